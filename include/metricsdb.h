@@ -0,0 +1,61 @@
+#ifndef METRICSDB_H
+#define METRICSDB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define METRICSDB_OK 0
+#define METRICSDB_INVALID_ARGUMENT -1
+#define METRICSDB_ENGINE_ERROR -2
+#define METRICSDB_BUFFER_TOO_SMALL -3
+/* An internal error (a panic) occurred, the engine should not be used afterwards. */
+#define METRICSDB_PANIC -4
+
+#define METRICSDB_GAUGE 0
+#define METRICSDB_COUNT 1
+#define METRICSDB_RATIO 2
+
+typedef struct metricsdb_engine metricsdb_engine;
+
+/* Opens (or creates) the engine at the given path. Returns NULL on failure. */
+metricsdb_engine* metricsdb_open(const char* path);
+void metricsdb_close(metricsdb_engine* engine);
+
+int metricsdb_add_metric(const metricsdb_engine* engine, const char* name, int metric_type);
+
+/* Tags are given on the format key:value. */
+int metricsdb_add_gauge(const metricsdb_engine* engine,
+                        const char* name,
+                        double time,
+                        double value,
+                        const char* const* tags,
+                        size_t num_tags);
+
+int metricsdb_add_count(const metricsdb_engine* engine,
+                        const char* name,
+                        double time,
+                        uint32_t count,
+                        const char* const* tags,
+                        size_t num_tags);
+
+/* Missing values are written as NaN. If the buffers are too small, METRICSDB_BUFFER_TOO_SMALL is returned
+   and num_windows contains the required capacity. */
+int metricsdb_average_in_window(const metricsdb_engine* engine,
+                                const char* name,
+                                double start,
+                                double end,
+                                double duration,
+                                double* times,
+                                double* values,
+                                size_t capacity,
+                                size_t* num_windows);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,394 @@
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::{ClientConfig, ClientResult, MetricsClient};
+use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    pub base_url: String,
+    /// The number of samples per second.
+    pub sample_rate: f64,
+    /// The host tag of the values, the hostname of the machine if not set.
+    pub hostname: Option<String>,
+    /// The directory where writes are kept while the server is unreachable.
+    pub spool_path: PathBuf,
+    /// The oldest writes are dropped once the spool would exceed this size.
+    pub max_spool_bytes: u64,
+    pub max_retries: usize
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            base_url: "http://localhost:9090".to_owned(),
+            sample_rate: 1.0,
+            hostname: None,
+            spool_path: PathBuf::from("agent_spool"),
+            max_spool_bytes: 64 * 1024 * 1024,
+            max_retries: 3
+        }
+    }
+}
+
+impl AgentConfig {
+    pub fn load(path: &Path) -> Result<AgentConfig, AgentConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|err| AgentConfigError::FailedToRead(path.to_owned(), err))?;
+        serde_json::from_str(&content).map_err(|err| AgentConfigError::FailedToParse(path.to_owned(), err))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgentConfigError {
+    #[error("failed to read config {0:?}: {1}")]
+    FailedToRead(PathBuf, std::io::Error),
+    #[error("failed to parse config {0:?}: {1}")]
+    FailedToParse(PathBuf, serde_json::Error)
+}
+
+/// The values written to a metric in a single request.
+#[derive(Serialize, Deserialize)]
+pub enum WriteBatch {
+    Gauge { metric: String, values: Vec<AddGaugeValue> },
+    Count { metric: String, values: Vec<AddCountValue> },
+    Ratio { metric: String, values: Vec<AddRatioValue> }
+}
+
+impl WriteBatch {
+    pub fn metric(&self) -> &str {
+        match self {
+            WriteBatch::Gauge { metric, .. } => metric,
+            WriteBatch::Count { metric, .. } => metric,
+            WriteBatch::Ratio { metric, .. } => metric
+        }
+    }
+}
+
+/// The writes that could not be sent, stored as JSON lines in the order they were made.
+/// New writes are appended and the sent writes are removed from the start once acknowledged by the server.
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64
+}
+
+impl Spool {
+    pub fn new(base_path: &Path, max_bytes: u64) -> std::io::Result<Spool> {
+        std::fs::create_dir_all(base_path)?;
+
+        Ok(
+            Spool {
+                path: base_path.join("spool.jsonl"),
+                max_bytes
+            }
+        )
+    }
+
+    /// The size (in bytes) of the spooled writes.
+    pub fn num_bytes(&self) -> std::io::Result<u64> {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err)
+        }
+    }
+
+    pub fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.num_bytes()? == 0)
+    }
+
+    pub fn load(&self) -> std::io::Result<Vec<WriteBatch>> {
+        self.iter()?.map(|entry| entry.map(|(_, batch)| batch)).collect()
+    }
+
+    /// Reads the spooled writes in order, together with the offset after each write that acknowledges it.
+    pub fn iter(&self) -> std::io::Result<SpoolIterator> {
+        let reader = match std::fs::File::open(&self.path) {
+            Ok(file) => Some(BufReader::new(file)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => { return Err(err); }
+        };
+
+        Ok(
+            SpoolIterator {
+                path: self.path.clone(),
+                reader,
+                offset: 0
+            }
+        )
+    }
+
+    /// Appends the writes, where the oldest are dropped if the size is exceeded. Returns the number of dropped writes.
+    pub fn append(&self, batches: &[WriteBatch]) -> std::io::Result<usize> {
+        if batches.is_empty() {
+            return Ok(0);
+        }
+
+        let mut content = String::new();
+        for batch in batches {
+            content.push_str(&serde_json::to_string(batch)?);
+            content.push('\n');
+        }
+
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_data()?;
+
+        let num_bytes = file.metadata()?.len();
+        if num_bytes <= self.max_bytes {
+            return Ok(0);
+        }
+
+        // Skip the oldest writes until the rest fits
+        let mut num_dropped = 0;
+        let mut offset = 0;
+        for entry in self.iter()? {
+            if num_bytes - offset <= self.max_bytes {
+                break;
+            }
+
+            offset = entry?.0;
+            num_dropped += 1;
+        }
+
+        self.acknowledge(offset)?;
+        Ok(num_dropped)
+    }
+
+    /// Removes the writes before the given offset (as given by `iter`).
+    pub fn acknowledge(&self, offset: u64) -> std::io::Result<()> {
+        if offset == 0 {
+            return Ok(());
+        }
+
+        if offset >= self.num_bytes()? {
+            std::fs::OpenOptions::new().write(true).open(&self.path)?.set_len(0)?;
+            return Ok(());
+        }
+
+        let mut file = std::fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let temp_path = self.path.with_extension("jsonl.tmp");
+        let mut temp_file = std::fs::File::create(&temp_path)?;
+        std::io::copy(&mut file, &mut temp_file)?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+pub struct SpoolIterator {
+    path: PathBuf,
+    reader: Option<BufReader<std::fs::File>>,
+    offset: u64
+}
+
+impl Iterator for SpoolIterator {
+    type Item = std::io::Result<(u64, WriteBatch)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = self.reader.as_mut()?;
+
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => { return None; }
+                Ok(num_bytes) => { self.offset += num_bytes as u64; }
+                Err(err) => { return Some(Err(err)); }
+            }
+
+            match serde_json::from_str::<WriteBatch>(line.trim_end()) {
+                Ok(batch) => { return Some(Ok((self.offset, batch))); }
+                Err(err) => { tracing::warn!(path = ?self.path, error = %err, "skipping invalid spooled write"); }
+            }
+        }
+    }
+}
+
+/// Ships writes to the server, where writes that fail because the server is unreachable are kept on disk and sent
+/// (before any new writes) once it's reachable again.
+pub struct Agent {
+    client: MetricsClient,
+    spool: Spool
+}
+
+impl Agent {
+    pub fn new(config: &AgentConfig) -> std::io::Result<Agent> {
+        let client = MetricsClient::with_config(
+            ClientConfig {
+                base_url: config.base_url.clone(),
+                max_retries: config.max_retries,
+                ..Default::default()
+            }
+        );
+
+        Ok(
+            Agent {
+                client,
+                spool: Spool::new(&config.spool_path, config.max_spool_bytes)?
+            }
+        )
+    }
+
+    pub async fn ship(&self, batches: Vec<WriteBatch>) -> std::io::Result<()> {
+        // Send directly unless earlier writes are waiting to be sent
+        if self.spool.is_empty()? {
+            for (index, batch) in batches.iter().enumerate() {
+                if !self.try_send(batch).await {
+                    return self.spool_writes(&batches[index..]);
+                }
+            }
+
+            return Ok(());
+        }
+
+        self.spool_writes(&batches)?;
+
+        let mut acknowledged = 0;
+        for entry in self.spool.iter()? {
+            let (offset, batch) = entry?;
+            if !self.try_send(&batch).await {
+                break;
+            }
+
+            acknowledged = offset;
+        }
+
+        self.spool.acknowledge(acknowledged)
+    }
+
+    /// Sends the write, where false is returned if the server is unreachable. Writes rejected by the server are dropped.
+    async fn try_send(&self, batch: &WriteBatch) -> bool {
+        match self.send(batch).await {
+            Ok(_) => true,
+            Err(err) if err.is_retriable() => {
+                tracing::warn!(error = %err, "Server unreachable, keeping writes on disk.");
+                false
+            }
+            Err(err) => {
+                tracing::warn!(metric = batch.metric(), error = %err, "Dropping write rejected by the server.");
+                true
+            }
+        }
+    }
+
+    fn spool_writes(&self, batches: &[WriteBatch]) -> std::io::Result<()> {
+        let num_dropped = self.spool.append(batches)?;
+        if num_dropped > 0 {
+            tracing::warn!(num_dropped, "Dropped the oldest spooled writes as the spool is full.");
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, batch: &WriteBatch) -> ClientResult<usize> {
+        match batch {
+            WriteBatch::Gauge { metric, values } => self.client.add_gauge_values(metric, values).await,
+            WriteBatch::Count { metric, values } => self.client.add_count_values(metric, values).await,
+            WriteBatch::Ratio { metric, values } => self.client.add_ratio_values(metric, values).await
+        }
+    }
+}
+
+#[test]
+fn test_spool1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let spool = Spool::new(&temp_dir.path().join("spool"), 1024 * 1024).unwrap();
+    assert!(spool.load().unwrap().is_empty());
+
+    let batches = test_batches();
+    assert_eq!(0, spool.append(&batches).unwrap());
+    assert_eq!(vec!["cpu", "requests"], spool_metrics(&spool));
+
+    let num_bytes = spool.num_bytes().unwrap();
+    spool.acknowledge(num_bytes).unwrap();
+    assert!(spool.is_empty().unwrap());
+    assert!(spool.load().unwrap().is_empty());
+
+    // Only the latest write fits
+    let spool = Spool::new(&temp_dir.path().join("spool"), 100).unwrap();
+    assert_eq!(1, spool.append(&batches).unwrap());
+    assert_eq!(vec!["requests"], spool_metrics(&spool));
+}
+
+#[test]
+fn test_spool2() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let spool = Spool::new(&temp_dir.path().join("spool"), 1024 * 1024).unwrap();
+    spool.append(&test_batches()).unwrap();
+    spool.append(&test_batches()[..1]).unwrap();
+    assert_eq!(vec!["cpu", "requests", "cpu"], spool_metrics(&spool));
+
+    // Only the acknowledged writes are removed
+    let offsets = spool.iter().unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(spool.num_bytes().unwrap(), offsets[2]);
+    spool.acknowledge(offsets[0]).unwrap();
+    assert_eq!(vec!["requests", "cpu"], spool_metrics(&spool));
+    assert_eq!(offsets[2] - offsets[0], spool.num_bytes().unwrap());
+
+    // Invalid writes are skipped
+    std::fs::OpenOptions::new().append(true).open(temp_dir.path().join("spool").join("spool.jsonl")).unwrap().write_all(b"invalid\n").unwrap();
+    spool.append(&test_batches()[1..]).unwrap();
+    assert_eq!(vec!["requests", "cpu", "requests"], spool_metrics(&spool));
+}
+
+#[tokio::test]
+async fn test_agent1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let agent = Agent::new(
+        &AgentConfig {
+            base_url: "http://127.0.0.1:1".to_owned(),
+            spool_path: temp_dir.path().join("spool"),
+            max_retries: 0,
+            ..Default::default()
+        }
+    ).unwrap();
+
+    // The server is unreachable, so the writes are kept in the order they were made
+    agent.ship(test_batches()).await.unwrap();
+    assert_eq!(vec!["cpu", "requests"], spool_metrics(&agent.spool));
+
+    let mut batches = test_batches();
+    batches.truncate(1);
+    agent.ship(batches).await.unwrap();
+    assert_eq!(vec!["cpu", "requests", "cpu"], spool_metrics(&agent.spool));
+}
+
+#[test]
+fn test_config1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("agent.json");
+
+    assert!(matches!(AgentConfig::load(&path), Err(AgentConfigError::FailedToRead(_, _))));
+
+    std::fs::write(&path, "{\"sample_rate\": ").unwrap();
+    assert!(matches!(AgentConfig::load(&path), Err(AgentConfigError::FailedToParse(_, _))));
+
+    std::fs::write(&path, "{\"sample_rate\": 2.0}").unwrap();
+    let config = AgentConfig::load(&path).unwrap();
+    assert_eq!(2.0, config.sample_rate);
+    assert_eq!(3, config.max_retries);
+}
+
+#[cfg(test)]
+fn test_batches() -> Vec<WriteBatch> {
+    use crate::metric::common::CountInput;
+    use crate::metric::tags::Tag;
+
+    vec![
+        WriteBatch::Gauge { metric: "cpu".to_owned(), values: vec![AddGaugeValue::new(1654077600.0, 0.5, vec![Tag::from_ref("host", "a")])] },
+        WriteBatch::Count { metric: "requests".to_owned(), values: vec![AddCountValue::new(1654077600.0, CountInput(3), Vec::new())] }
+    ]
+}
+
+#[cfg(test)]
+fn spool_metrics(spool: &Spool) -> Vec<String> {
+    spool.load().unwrap().iter().map(|batch| batch.metric().to_owned()).collect()
+}
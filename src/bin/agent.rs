@@ -1,138 +1,94 @@
+use std::path::Path;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use fnv::FnvHashMap;
 
-use reqwest::StatusCode;
-use serde_json::json;
-
+use metricsdb::agent::{Agent, AgentConfig, WriteBatch};
 use metricsdb::metric::common::CountInput;
 use metricsdb::metric::tags::Tag;
 use metricsdb::engine::io::{AddGaugeValue, AddCountValue};
 
-struct AgentConfig {
-    base_url: String,
-    sample_rate: f64
-}
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt().init();
 
-impl Default for AgentConfig {
-    fn default() -> Self {
-        AgentConfig {
-            base_url: "http://localhost:9090".to_string(),
-            sample_rate: 1.0
+    let arguments = std::env::args().collect::<Vec<_>>();
+    let config = if arguments.len() >= 2 {
+        match AgentConfig::load(Path::new(&arguments[1])) {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to load the config.");
+                std::process::exit(1);
+            }
         }
-    }
-}
+    } else {
+        AgentConfig::default()
+    };
+
+    let hostname = config.hostname.clone().unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned());
 
-#[tokio::main]
-async fn main() {
     let mut cpu_usage_collector = CpuUsageCollector::new();
     let mut context_switches_collector = ContextSwitchesCollector::new();
     let mut memory_usage_collector = MemoryUsageCollector::new();
 
-    let config = AgentConfig::default();
-
-    let arguments = std::env::args().collect::<Vec<_>>();
-    let hostname = if arguments.len() >= 2 {
-        arguments[1].clone()
-    } else {
-        gethostname::gethostname().to_str().unwrap().to_owned()
+    let agent = match Agent::new(&config) {
+        Ok(agent) => agent,
+        Err(err) => {
+            tracing::error!(error = %err, path = ?config.spool_path, "Failed to create the spool.");
+            std::process::exit(1);
+        }
     };
 
-    let client = reqwest::Client::new();
     loop {
         let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        let mut batches = Vec::new();
 
         let cpu_usage = cpu_usage_collector.collect().unwrap();
         if !cpu_usage.is_empty() {
-            let cpu_usage_json = json!(
-                cpu_usage
-                    .iter()
-                    .map(|(core_name, cpu_usage)|
-                        AddGaugeValue::new(
-                            time_now,
-                            *cpu_usage,
-                            vec![Tag::from_ref("host", &hostname), Tag::from_ref("core", core_name)]
-                        )
+            let cpu_usage_values = cpu_usage
+                .iter()
+                .map(|(core_name, cpu_usage)|
+                    AddGaugeValue::new(
+                        time_now,
+                        *cpu_usage,
+                        vec![Tag::from_ref("host", &hostname), Tag::from_ref("core", core_name)]
                     )
-                    .collect::<Vec<_>>()
-            );
+                )
+                .collect::<Vec<_>>();
 
-            send_metric_data(
-                &config,
-                &client,
-                "cpu_usage",
-                "gauge",
-                &cpu_usage_json
-            ).await;
+            batches.push(WriteBatch::Gauge { metric: "cpu_usage".to_owned(), values: cpu_usage_values });
         }
 
         let memory_usage = memory_usage_collector.collect().unwrap();
-
-        send_metric_data(
-            &config,
-            &client,
-            "used_memory",
-            "gauge",
-            &json!(vec![AddGaugeValue::new(time_now, memory_usage.1, vec![Tag::from_ref("host", &hostname)])])
-        ).await;
-
-        send_metric_data(
-            &config,
-            &client,
-            "total_memory",
-            "gauge",
-            &json!(vec![AddGaugeValue::new(time_now, memory_usage.0, vec![Tag::from_ref("host", &hostname)])])
-        ).await;
+        batches.push(
+            WriteBatch::Gauge {
+                metric: "used_memory".to_owned(),
+                values: vec![AddGaugeValue::new(time_now, memory_usage.1, vec![Tag::from_ref("host", &hostname)])]
+            }
+        );
+        batches.push(
+            WriteBatch::Gauge {
+                metric: "total_memory".to_owned(),
+                values: vec![AddGaugeValue::new(time_now, memory_usage.0, vec![Tag::from_ref("host", &hostname)])]
+            }
+        );
 
         if let Some(context_switches) = context_switches_collector.collect().unwrap() {
-            send_metric_data(
-                &config,
-                &client,
-                "context_switches",
-                "count",
-                &json!(vec![AddCountValue::new(time_now, CountInput(context_switches as u32), vec![Tag::from_ref("host", &hostname)])])
-            ).await;
+            batches.push(
+                WriteBatch::Count {
+                    metric: "context_switches".to_owned(),
+                    values: vec![AddCountValue::new(time_now, CountInput(context_switches as u32), vec![Tag::from_ref("host", &hostname)])]
+                }
+            );
         }
 
-        std::thread::sleep(std::time::Duration::from_secs_f64(1.0 / config.sample_rate));
-    }
-}
-
-async fn send_metric_data(config: &AgentConfig,
-                          client: &reqwest::Client,
-                          name: &str,
-                          metric_type: &str,
-                          metric_data: &serde_json::Value) {
-    match post_result(&config, &client, name, metric_type, metric_data).await {
-        Ok((status, content)) => {
-            if !status.is_success() {
-                println!("Failed to post result due to (status code: {}): {}", status, content)
-            }
-        }
-        Err(err) => {
-            println!("Failed to post result due to: {}", err);
+        if let Err(err) = agent.ship(batches).await {
+            tracing::error!(error = %err, "Failed to spool writes.");
         }
-    }
-}
 
-async fn post_result(config: &AgentConfig,
-                     client: &reqwest::Client,
-                     name: &str,
-                     metric_type: &str,
-                     metric_data: &serde_json::Value) -> reqwest::Result<(StatusCode, String)> {
-    println!("{}", metric_data);
-    let response = client.put(format!("{}/metrics/{}/{}", config.base_url, metric_type, name))
-        .json(&metric_data)
-        .send()
-        .await?;
-
-    let response_status = response.status();
-
-    let response_data = response.bytes().await?;
-    let response_data = std::str::from_utf8(response_data.as_ref()).unwrap().to_owned();
-
-    Ok((response_status, response_data))
+        tokio::time::sleep(Duration::from_secs_f64(1.0 / config.sample_rate)).await;
+    }
 }
 
 struct CpuUsageCollector {
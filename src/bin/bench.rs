@@ -0,0 +1,89 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use metricsdb::engine::MetricsEngine;
+use metricsdb::generate::{GaugeWorkload, Generator, TagProfile};
+use metricsdb::metric::common::MetricType;
+use metricsdb::model::{GroupKey, Query, TimeRange};
+
+const TAG_CARDINALITIES: [usize; 3] = [1, 10, 100];
+const QUERY_ITERATIONS: usize = 20;
+
+struct BenchResult {
+    name: String,
+    value: f64,
+    unit: &'static str
+}
+
+fn main() {
+    let arguments = std::env::args().collect::<Vec<_>>();
+    let as_json = arguments.iter().any(|argument| argument == "--json");
+
+    let workload = GaugeWorkload { duration: 24.0 * 3600.0, ..Default::default() };
+    let time_range = TimeRange::new(workload.start_time, workload.start_time + workload.duration);
+
+    let mut results = Vec::new();
+    for cardinality in TAG_CARDINALITIES {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let metrics_engine = MetricsEngine::new(Path::new(temp_dir.path())).unwrap();
+        metrics_engine.add_metric("bench", MetricType::Gauge).unwrap();
+
+        let tag_profile = TagProfile::Uniform { key: "tag".to_owned(), cardinality };
+        let values = Generator::new(1337).gauge_values(&workload, &tag_profile);
+        let num_values = values.len();
+
+        let start = Instant::now();
+        metrics_engine.gauge("bench", values.into_iter()).unwrap();
+        results.push(BenchResult {
+            name: format!("ingest.cardinality_{}", cardinality),
+            value: num_values as f64 / start.elapsed().as_secs_f64(),
+            unit: "points/s"
+        });
+
+        results.push(measure_query(&format!("query.scalar.cardinality_{}", cardinality), || {
+            metrics_engine.average("bench", Query::new(time_range)).unwrap();
+        }));
+
+        results.push(measure_query(&format!("query.windowed.cardinality_{}", cardinality), || {
+            metrics_engine.average_in_window("bench", Query::new(time_range), Duration::from_secs_f64(60.0)).unwrap();
+        }));
+
+        results.push(measure_query(&format!("query.grouped.cardinality_{}", cardinality), || {
+            metrics_engine.average("bench", Query::new(time_range).with_group_by(GroupKey::from_ref("tag"))).unwrap();
+        }));
+    }
+
+    if as_json {
+        let report = results
+            .iter()
+            .map(|result| (result.name.clone(), json!({ "value": result.value, "unit": result.unit })))
+            .collect::<serde_json::Map<_, _>>();
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        for result in &results {
+            println!("{:<40} {:>14.2} {}", result.name, result.value, result.unit);
+        }
+    }
+}
+
+fn measure_query(name: &str, mut query: impl FnMut()) -> BenchResult {
+    // Warm up the caches before measuring
+    query();
+
+    let mut durations = (0..QUERY_ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            query();
+            start.elapsed().as_secs_f64() * 1000.0
+        })
+        .collect::<Vec<_>>();
+    durations.sort_by(|x, y| x.partial_cmp(y).unwrap());
+
+    BenchResult {
+        name: name.to_owned(),
+        value: durations[durations.len() / 2],
+        unit: "ms (median)"
+    }
+}
@@ -1,11 +1,10 @@
 use std::path::Path;
 use std::time::Duration;
 
-use serde::Deserialize;
-
 use metricsdb::engine::engine::MetricsEngine;
 use metricsdb::engine::io::{AddCountValue, AddGaugeValue};
 use metricsdb::engine::querying::{MetricQuery, MetricQueryExpression};
+use metricsdb::generate::{GaugeWorkload, Generator};
 use metricsdb::helpers::{TimeMeasurement, TimeMeasurementUnit};
 use metricsdb::metric::common::{CountInput, GenericMetric, MetricType};
 use metricsdb::metric::count::DefaultCountMetric;
@@ -24,15 +23,8 @@ fn main() {
     // main_engine_existing2();
 }
 
-#[derive(Deserialize)]
-struct SampleData {
-    times: Vec<f64>,
-    values: Vec<f32>
-}
-
 fn main_gauge() {
-    let data = std::fs::read_to_string("output.json").unwrap();
-    let data: SampleData = serde_json::from_str(&data).unwrap();
+    let data = Generator::new(1337).gauge(&GaugeWorkload::default());
     let tags_list = vec![Tag::from_ref("tag", "T1"), Tag::from_ref("tag", "T2")];
 
     println!("n: {}", data.times.len());
@@ -125,8 +117,7 @@ fn main_gauge() {
 }
 
 fn main_count() {
-    let data = std::fs::read_to_string("output.json").unwrap();
-    let data: SampleData = serde_json::from_str(&data).unwrap();
+    let data = Generator::new(1337).gauge(&GaugeWorkload::default());
     let tags_list = vec![Tag::from_ref("tag", "T1"), Tag::from_ref("tag", "T2")];
 
     println!("n: {}", data.times.len());
@@ -209,8 +200,7 @@ fn main_count() {
 }
 
 fn main_ratio() {
-    let data = std::fs::read_to_string("output.json").unwrap();
-    let data: SampleData = serde_json::from_str(&data).unwrap();
+    let data = Generator::new(1337).gauge(&GaugeWorkload::default());
     let tags_list = vec![Tag::from_ref("tag", "T1"), Tag::from_ref("tag", "T2")];
 
     println!("n: {}", data.times.len());
@@ -250,8 +240,7 @@ fn main_ratio() {
 }
 
 fn main_engine() {
-    let data = std::fs::read_to_string("output.json").unwrap();
-    let data: SampleData = serde_json::from_str(&data).unwrap();
+    let data = Generator::new(1337).gauge(&GaugeWorkload::default());
     let tags_list = vec![Tag::from_ref("tag", "T1"), Tag::from_ref("tag", "T2")];
 
     println!("n: {}", data.times.len());
@@ -0,0 +1,32 @@
+use std::str::FromStr;
+
+use metricsdb::generate::{CountWorkload, GaugeWorkload, Generator, TagProfile};
+
+fn main() {
+    let arguments = std::env::args().collect::<Vec<_>>();
+    if arguments.len() < 2 {
+        println!("Usage: generate <gauge|gauge-values|count-values> [output] [seed] [tag cardinality]");
+        std::process::exit(1);
+    }
+
+    let output = arguments.get(2).cloned().unwrap_or_else(|| "output.json".to_owned());
+    let seed = arguments.get(3).map(|seed| u64::from_str(seed).unwrap()).unwrap_or(1337);
+    let tag_profile = match arguments.get(4).map(|cardinality| usize::from_str(cardinality).unwrap()) {
+        Some(cardinality) => TagProfile::Skewed { key: "tag".to_owned(), cardinality },
+        None => TagProfile::None
+    };
+
+    let mut generator = Generator::new(seed);
+    let content = match arguments[1].as_str() {
+        "gauge" => serde_json::to_string(&generator.gauge(&GaugeWorkload::default())).unwrap(),
+        "gauge-values" => serde_json::to_string(&generator.gauge_values(&GaugeWorkload::default(), &tag_profile)).unwrap(),
+        "count-values" => serde_json::to_string(&generator.count_values(&CountWorkload::default(), &tag_profile)).unwrap(),
+        workload => {
+            println!("Unknown workload: {}", workload);
+            std::process::exit(1);
+        }
+    };
+
+    std::fs::write(&output, content).unwrap();
+    println!("Wrote {}", output);
+}
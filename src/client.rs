@@ -0,0 +1,348 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+
+use reqwest::StatusCode;
+
+use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue};
+use crate::engine::querying::MetricQueryExpression;
+use crate::metric::common::MetricType;
+use crate::metric::{GroupTimeValues, GroupValues, OperationResult, TimeValues};
+use crate::model::{GroupValue, Query, TimeRange};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("server responded with {0}: {1}")]
+    Server(StatusCode, String),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String)
+}
+
+impl ClientError {
+    /// If the request might succeed if sent again later, such as when the server is unreachable.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            ClientError::Request(_) => true,
+            ClientError::Server(status, _) => status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+            ClientError::InvalidResponse(_) => false
+        }
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub base_url: String,
+    pub max_retries: usize,
+    pub retry_delay: Duration,
+    pub max_buffered_values: usize
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            base_url: "http://localhost:9090".to_owned(),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(500),
+            max_buffered_values: 1000
+        }
+    }
+}
+
+#[derive(Default)]
+struct WriteBuffer {
+    gauge: FnvHashMap<String, Vec<AddGaugeValue>>,
+    count: FnvHashMap<String, Vec<AddCountValue>>,
+    ratio: FnvHashMap<String, Vec<AddRatioValue>>,
+    num_values: usize
+}
+
+pub struct MetricsClient {
+    client: reqwest::Client,
+    config: ClientConfig,
+    buffer: Mutex<WriteBuffer>
+}
+
+impl MetricsClient {
+    pub fn new(base_url: &str) -> MetricsClient {
+        MetricsClient::with_config(ClientConfig { base_url: base_url.to_owned(), ..Default::default() })
+    }
+
+    pub fn with_config(config: ClientConfig) -> MetricsClient {
+        MetricsClient {
+            client: reqwest::Client::new(),
+            config,
+            buffer: Mutex::new(WriteBuffer::default())
+        }
+    }
+
+    pub async fn create_metric(&self, name: &str, metric_type: MetricType) -> ClientResult<()> {
+        self.send::<serde_json::Value>(
+            reqwest::Method::POST,
+            &format!("/metrics/{}", metric_type_path(&metric_type)),
+            &json!({ "name": name })
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn add_gauge_values(&self, name: &str, values: &[AddGaugeValue]) -> ClientResult<usize> {
+        self.add_values(MetricType::Gauge, name, values).await
+    }
+
+    pub async fn add_count_values(&self, name: &str, values: &[AddCountValue]) -> ClientResult<usize> {
+        self.add_values(MetricType::Count, name, values).await
+    }
+
+    pub async fn add_ratio_values(&self, name: &str, values: &[AddRatioValue]) -> ClientResult<usize> {
+        self.add_values(MetricType::Ratio, name, values).await
+    }
+
+    /// Buffers the value, the buffer is flushed when it's full or when `flush` is called.
+    pub async fn buffer_gauge(&self, name: &str, value: AddGaugeValue) -> ClientResult<()> {
+        let is_full = self.buffered(|buffer| buffer.gauge.entry(name.to_owned()).or_default().push(value));
+        self.flush_if(is_full).await
+    }
+
+    pub async fn buffer_count(&self, name: &str, value: AddCountValue) -> ClientResult<()> {
+        let is_full = self.buffered(|buffer| buffer.count.entry(name.to_owned()).or_default().push(value));
+        self.flush_if(is_full).await
+    }
+
+    pub async fn buffer_ratio(&self, name: &str, value: AddRatioValue) -> ClientResult<()> {
+        let is_full = self.buffered(|buffer| buffer.ratio.entry(name.to_owned()).or_default().push(value));
+        self.flush_if(is_full).await
+    }
+
+    pub async fn flush(&self) -> ClientResult<()> {
+        let buffer = std::mem::take(&mut *self.buffer.lock().unwrap());
+
+        for (name, values) in buffer.gauge {
+            self.add_gauge_values(&name, &values).await?;
+        }
+
+        for (name, values) in buffer.count {
+            self.add_count_values(&name, &values).await?;
+        }
+
+        for (name, values) in buffer.ratio {
+            self.add_ratio_values(&name, &values).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn query(&self,
+                       expression: &MetricQueryExpression,
+                       time_range: TimeRange,
+                       duration: Option<Duration>) -> ClientResult<OperationResult> {
+        let response: serde_json::Value = self.send(
+            reqwest::Method::POST,
+            "/metrics/query",
+            &json!({
+                "time_range": time_range,
+                "duration": duration.map(|duration| duration.as_secs_f64()),
+                "expression": expression
+            })
+        ).await?;
+
+        let value = response.get("value").ok_or_else(|| ClientError::InvalidResponse("missing value".to_owned()))?;
+        parse_operation_result(value, duration.is_some())
+    }
+
+    pub async fn average(&self, metric: &str, time_range: TimeRange, duration: Option<Duration>) -> ClientResult<OperationResult> {
+        self.query(&MetricQueryExpression::Average { metric: metric.to_owned(), query: Query::placeholder() }, time_range, duration).await
+    }
+
+    pub async fn sum(&self, metric: &str, time_range: TimeRange, duration: Option<Duration>) -> ClientResult<OperationResult> {
+        self.query(&MetricQueryExpression::Sum { metric: metric.to_owned(), query: Query::placeholder() }, time_range, duration).await
+    }
+
+    pub async fn max(&self, metric: &str, time_range: TimeRange, duration: Option<Duration>) -> ClientResult<OperationResult> {
+        self.query(&MetricQueryExpression::Max { metric: metric.to_owned(), query: Query::placeholder() }, time_range, duration).await
+    }
+
+    pub async fn min(&self, metric: &str, time_range: TimeRange, duration: Option<Duration>) -> ClientResult<OperationResult> {
+        self.query(&MetricQueryExpression::Min { metric: metric.to_owned(), query: Query::placeholder() }, time_range, duration).await
+    }
+
+    pub async fn percentile(&self,
+                            metric: &str,
+                            time_range: TimeRange,
+                            duration: Option<Duration>,
+                            percentile: i32) -> ClientResult<OperationResult> {
+        self.query(
+            &MetricQueryExpression::Percentile { metric: metric.to_owned(), query: Query::placeholder(), percentile },
+            time_range,
+            duration
+        ).await
+    }
+
+    async fn add_values<T: Serialize>(&self, metric_type: MetricType, name: &str, values: &[T]) -> ClientResult<usize> {
+        let response: serde_json::Value = self.send(
+            reqwest::Method::PUT,
+            &format!("/metrics/{}/{}", metric_type_path(&metric_type), name),
+            &values
+        ).await?;
+
+        response
+            .get("num_inserted")
+            .and_then(|num_inserted| num_inserted.as_u64())
+            .map(|num_inserted| num_inserted as usize)
+            .ok_or_else(|| ClientError::InvalidResponse("missing num_inserted".to_owned()))
+    }
+
+    async fn send<T: DeserializeOwned>(&self, method: reqwest::Method, path: &str, body: &impl Serialize) -> ClientResult<T> {
+        let mut attempt = 0;
+        loop {
+            let result = self.client.request(method.clone(), format!("{}{}", self.config.base_url, path))
+                .json(body)
+                .send()
+                .await;
+
+            let response = match result {
+                Ok(response) if !response.status().is_server_error() || attempt >= self.config.max_retries => response,
+                Err(err) if attempt >= self.config.max_retries => return Err(err.into()),
+                result => {
+                    if let Err(err) = result {
+                        tracing::warn!(error = %err, attempt, "Request failed, retrying.");
+                    }
+
+                    attempt += 1;
+                    tokio::time::sleep(self.config.retry_delay * attempt as u32).await;
+                    continue;
+                }
+            };
+
+            let status = response.status();
+            if !status.is_success() {
+                let content = response.text().await.unwrap_or_default();
+                let message = serde_json::from_str::<serde_json::Value>(&content)
+                    .ok()
+                    .and_then(|content| content.get("message").and_then(|message| message.as_str()).map(|message| message.to_owned()))
+                    .unwrap_or(content);
+                return Err(ClientError::Server(status, message));
+            }
+
+            return Ok(response.json().await?);
+        }
+    }
+
+    fn buffered(&self, add: impl FnOnce(&mut WriteBuffer)) -> bool {
+        let mut buffer = self.buffer.lock().unwrap();
+        add(&mut buffer);
+        buffer.num_values += 1;
+        buffer.num_values >= self.config.max_buffered_values
+    }
+
+    async fn flush_if(&self, is_full: bool) -> ClientResult<()> {
+        if is_full {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn metric_type_path(metric_type: &MetricType) -> &'static str {
+    match metric_type {
+        MetricType::Gauge => "gauge",
+        MetricType::Count => "count",
+        MetricType::Ratio => "ratio"
+    }
+}
+
+fn parse_operation_result(value: &serde_json::Value, windowed: bool) -> ClientResult<OperationResult> {
+    let invalid = || ClientError::InvalidResponse(value.to_string());
+
+    if !value.is_array() {
+        return if value.is_null() || value.is_number() {
+            Ok(OperationResult::Value(value.as_f64()))
+        } else {
+            Err(invalid())
+        };
+    }
+
+    let entries = value.as_array().unwrap();
+    // Time values have numeric first elements while groups are strings or lists of strings
+    let is_grouped = entries.first().map(|entry| !entry[0].is_number()).unwrap_or(false);
+
+    match (windowed, is_grouped) {
+        (true, false) => Ok(OperationResult::TimeValues(parse_time_values(value).ok_or_else(invalid)?)),
+        (false, _) => {
+            let values = entries
+                .iter()
+                .map(|entry| Some((parse_group_value(&entry[0])?, optional_f64(&entry[1])?)))
+                .collect::<Option<GroupValues>>()
+                .ok_or_else(invalid)?;
+            Ok(OperationResult::GroupValues(values))
+        }
+        (true, true) => {
+            let values = entries
+                .iter()
+                .map(|entry| Some((parse_group_value(&entry[0])?, parse_time_values(&entry[1])?)))
+                .collect::<Option<GroupTimeValues>>()
+                .ok_or_else(invalid)?;
+            Ok(OperationResult::GroupTimeValues(values))
+        }
+    }
+}
+
+fn parse_time_values(value: &serde_json::Value) -> Option<TimeValues> {
+    value
+        .as_array()?
+        .iter()
+        .map(|entry| Some((entry[0].as_f64()?, optional_f64(&entry[1])?)))
+        .collect()
+}
+
+fn parse_group_value(value: &serde_json::Value) -> Option<GroupValue> {
+    match value {
+        serde_json::Value::String(value) => Some(GroupValue(vec![value.clone()])),
+        serde_json::Value::Array(parts) => {
+            parts
+                .iter()
+                .map(|part| part.as_str().map(|part| part.to_owned()))
+                .collect::<Option<Vec<_>>>()
+                .map(GroupValue)
+        }
+        _ => None
+    }
+}
+
+fn optional_f64(value: &serde_json::Value) -> Option<Option<f64>> {
+    if value.is_null() {
+        Some(None)
+    } else {
+        value.as_f64().map(Some)
+    }
+}
+
+#[test]
+fn test_parse_operation_result1() {
+    assert_eq!(OperationResult::Value(Some(1.5)), parse_operation_result(&json!(1.5), false).unwrap());
+    assert_eq!(OperationResult::Value(None), parse_operation_result(&json!(null), false).unwrap());
+
+    assert_eq!(
+        OperationResult::TimeValues(vec![(1654077600.0, Some(1.0)), (1654077601.0, None)]),
+        parse_operation_result(&json!([[1654077600.0, 1.0], [1654077601.0, null]]), true).unwrap()
+    );
+
+    assert_eq!(
+        OperationResult::GroupValues(vec![(GroupValue::from_ref("T1"), Some(2.0))]),
+        parse_operation_result(&json!([["T1", 2.0]]), false).unwrap()
+    );
+
+    assert_eq!(
+        OperationResult::GroupTimeValues(vec![
+            (GroupValue(vec!["T1".to_owned(), "h1".to_owned()]), vec![(1654077600.0, Some(3.0))])
+        ]),
+        parse_operation_result(&json!([[["T1", "h1"], [[1654077600.0, 3.0]]]]), true).unwrap()
+    );
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::hash::Hasher;
+
+use fnv::FnvHasher;
+use serde::Deserialize;
+
+use crate::engine::io::{MetricsEngineError, MetricsEngineResult};
+use crate::engine::templates::matches_pattern;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricAccessRule {
+    /// The metrics the rule applies to, where `*` matches any sequence of characters (such as `teamA_*`).
+    pub metrics: String,
+    #[serde(default)]
+    pub read: bool,
+    #[serde(default)]
+    pub write: bool
+}
+
+impl MetricAccessRule {
+    pub fn new(metrics: &str, read: bool, write: bool) -> MetricAccessRule {
+        MetricAccessRule {
+            metrics: metrics.to_owned(),
+            read,
+            write
+        }
+    }
+
+    fn allows(&self, metric: &str, access: Access) -> bool {
+        let allowed = match access {
+            Access::Read => self.read,
+            Access::Write => self.write
+        };
+
+        allowed && matches_pattern(&self.metrics, metric)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AccessPolicies {
+    /// The rules of each API key (sent in the `X-Api-Key` header). The access is not restricted if there are no keys.
+    pub api_keys: HashMap<String, Vec<MetricAccessRule>>,
+    /// The rules for requests without a known API key.
+    pub anonymous: Vec<MetricAccessRule>
+}
+
+impl AccessPolicies {
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty()
+    }
+
+    pub fn check(&self, api_key: Option<&str>, metric: &str, access: Access) -> MetricsEngineResult<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let rules = api_key
+            .and_then(|api_key| self.api_keys.get(api_key))
+            .unwrap_or(&self.anonymous);
+
+        if rules.iter().any(|rule| rule.allows(metric, access)) {
+            Ok(())
+        } else {
+            Err(MetricsEngineError::AccessDenied(metric.to_owned()))
+        }
+    }
+
+    /// Identifies the API key by its fingerprint (such that the key itself is not stored), none if it is not a known key.
+    pub fn principal(&self, api_key: Option<&str>) -> Option<String> {
+        let api_key = api_key.filter(|api_key| self.api_keys.contains_key(*api_key))?;
+        let mut hasher = FnvHasher::default();
+        hasher.write(api_key.as_bytes());
+        Some(format!("{:016x}", hasher.finish()))
+    }
+}
+
+#[test]
+fn test_check1() {
+    let mut policies = AccessPolicies::default();
+    assert!(policies.check(None, "teamB_cpu", Access::Write).is_ok());
+
+    policies.api_keys.insert(
+        "teamA".to_owned(),
+        vec![MetricAccessRule::new("teamA_*", true, true), MetricAccessRule::new("*", true, false)]
+    );
+    policies.anonymous.push(MetricAccessRule::new("public_*", true, false));
+
+    assert!(policies.check(Some("teamA"), "teamA_cpu", Access::Write).is_ok());
+    assert!(policies.check(Some("teamA"), "teamB_cpu", Access::Read).is_ok());
+    assert!(matches!(policies.check(Some("teamA"), "teamB_cpu", Access::Write), Err(MetricsEngineError::AccessDenied(_))));
+
+    assert!(policies.check(None, "public_cpu", Access::Read).is_ok());
+    assert!(policies.check(Some("unknown"), "public_cpu", Access::Write).is_err());
+    assert!(policies.check(None, "teamA_cpu", Access::Read).is_err());
+
+    assert!(policies.principal(Some("teamA")).is_some());
+    assert_eq!(None, policies.principal(Some("unknown")));
+    assert_eq!(None, policies.principal(None));
+}
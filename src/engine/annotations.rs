@@ -0,0 +1,115 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::engine::io::{MetricsEngineError, MetricsEngineResult};
+use crate::metric::tags::Tag;
+use crate::model::TimeRange;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Annotation {
+    pub time: f64,
+    pub text: String,
+    #[serde(default)]
+    pub tags: Vec<Tag>
+}
+
+impl Annotation {
+    pub fn new(time: f64, text: &str, tags: Vec<Tag>) -> Annotation {
+        Annotation {
+            time,
+            text: text.to_owned(),
+            tags
+        }
+    }
+}
+
+pub struct AnnotationsStore {
+    path: PathBuf,
+    annotations: Vec<Annotation>
+}
+
+impl AnnotationsStore {
+    pub fn new(base_path: &Path) -> AnnotationsStore {
+        AnnotationsStore {
+            path: base_path.join("annotations.json"),
+            annotations: Vec::new()
+        }
+    }
+
+    pub fn from_existing(base_path: &Path) -> MetricsEngineResult<AnnotationsStore> {
+        let mut store = AnnotationsStore::new(base_path);
+        if !store.path.exists() {
+            return Ok(store);
+        }
+
+        let load = || -> std::io::Result<Vec<Annotation>> {
+            let content = std::fs::read_to_string(&store.path)?;
+            let annotations: Vec<Annotation> = serde_json::from_str(&content)?;
+            Ok(annotations)
+        };
+
+        let mut annotations = load().map_err(MetricsEngineError::FailedToLoadAnnotations)?;
+        annotations.sort_by(|x, y| x.time.total_cmp(&y.time));
+        store.annotations = annotations;
+        Ok(store)
+    }
+
+    pub fn add(&mut self, annotation: Annotation) -> MetricsEngineResult<()> {
+        let index = self.annotations.partition_point(|current| current.time <= annotation.time);
+        self.annotations.insert(index, annotation);
+
+        if let Err(err) = self.save() {
+            self.annotations.remove(index);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    pub fn query(&self, time_range: TimeRange, tags: &[Tag]) -> Vec<Annotation> {
+        let start_index = self.annotations.partition_point(|annotation| annotation.time < time_range.start);
+        self.annotations[start_index..]
+            .iter()
+            .take_while(|annotation| annotation.time <= time_range.end)
+            .filter(|annotation| tags.iter().all(|tag| annotation.tags.contains(tag)))
+            .cloned()
+            .collect()
+    }
+
+    fn save(&self) -> MetricsEngineResult<()> {
+        let save = || -> std::io::Result<()> {
+            let content = serde_json::to_string(&self.annotations)?;
+            std::fs::write(&self.path, &content)?;
+            Ok(())
+        };
+
+        save().map_err(MetricsEngineError::FailedToSaveAnnotations)
+    }
+}
+
+#[test]
+fn test_query_annotations1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let mut store = AnnotationsStore::new(temp_dir.path());
+    store.add(Annotation::new(30.0, "incident", vec![Tag::from_ref("service", "api")])).unwrap();
+    store.add(Annotation::new(10.0, "deploy", vec![Tag::from_ref("service", "api")])).unwrap();
+    store.add(Annotation::new(20.0, "deploy", vec![Tag::from_ref("service", "web")])).unwrap();
+
+    assert_eq!(
+        vec![10.0, 20.0],
+        store.query(TimeRange::new(0.0, 25.0), &[]).iter().map(|annotation| annotation.time).collect::<Vec<_>>()
+    );
+
+    assert_eq!(
+        vec![10.0, 30.0],
+        store.query(TimeRange::new(0.0, 40.0), &[Tag::from_ref("service", "api")]).iter().map(|annotation| annotation.time).collect::<Vec<_>>()
+    );
+
+    let store = AnnotationsStore::from_existing(temp_dir.path()).unwrap();
+    assert_eq!(3, store.query(TimeRange::new(0.0, 40.0), &[]).len());
+}
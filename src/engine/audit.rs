@@ -0,0 +1,158 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    CreateMetric,
+    DeleteMetric,
+    UndeleteMetric,
+    AddPrimaryTag,
+    RemovePrimaryTag,
+    AddAutoPrimaryTag,
+    RemoveAutoPrimaryTag,
+    Compact,
+    PutDashboard,
+    RemoveDashboard,
+    ApplySchema
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct AuditEntry {
+    pub time: f64,
+    pub action: AuditAction,
+    /// The API key that performed the action (identified by its fingerprint), none for anonymous requests.
+    pub principal: Option<String>,
+    pub request_id: Option<String>,
+    pub metric: Option<String>,
+    pub details: serde_json::Value
+}
+
+impl AuditEntry {
+    pub fn new(action: AuditAction, metric: Option<&str>, details: serde_json::Value) -> AuditEntry {
+        AuditEntry {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
+            action,
+            principal: None,
+            request_id: None,
+            metric: metric.map(|metric| metric.to_owned()),
+            details
+        }
+    }
+
+    pub fn with_principal(mut self, principal: Option<String>) -> AuditEntry {
+        self.principal = principal;
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: Option<String>) -> AuditEntry {
+        self.request_id = request_id;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[cfg_attr(feature = "server", derive(IntoParams))]
+#[serde(default)]
+#[cfg_attr(feature = "server", into_params(parameter_in = Query))]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub metric: Option<String>,
+    pub principal: Option<String>,
+    /// Only entries at or after this time are included.
+    pub since: Option<f64>,
+    pub limit: Option<usize>
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.action.map(|action| action == entry.action).unwrap_or(true)
+            && self.metric.as_ref().map(|metric| Some(metric) == entry.metric.as_ref()).unwrap_or(true)
+            && self.principal.as_ref().map(|principal| Some(principal) == entry.principal.as_ref()).unwrap_or(true)
+            && self.since.map(|since| entry.time >= since).unwrap_or(true)
+    }
+}
+
+/// The administrative actions performed on the storage, stored as JSON lines that are only appended to.
+pub struct AuditLog {
+    path: PathBuf,
+    entries: Mutex<Vec<AuditEntry>>
+}
+
+impl AuditLog {
+    pub fn new(base_path: &Path) -> AuditLog {
+        let path = base_path.join("audit.jsonl");
+
+        let mut entries = Vec::new();
+        if let Ok(content) = std::fs::read_to_string(&path) {
+            for line in content.lines() {
+                match serde_json::from_str::<AuditEntry>(line) {
+                    Ok(entry) => { entries.push(entry); }
+                    Err(err) => { tracing::warn!(path = ?path, error = %err, "skipping invalid audit entry"); }
+                }
+            }
+        }
+
+        AuditLog {
+            path,
+            entries: Mutex::new(entries)
+        }
+    }
+
+    pub fn add(&self, entry: AuditEntry) -> std::io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+
+        entries.push(entry);
+        Ok(())
+    }
+
+    /// The entries matching the query, latest first.
+    pub fn entries(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap()
+            .iter()
+            .rev()
+            .filter(|entry| query.matches(entry))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+}
+
+#[test]
+fn test_audit_log1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let log = AuditLog::new(temp_dir.path());
+    log.add(AuditEntry::new(AuditAction::CreateMetric, Some("cpu"), serde_json::json!({ "type": "Gauge" })).with_principal(Some("a".to_owned()))).unwrap();
+    log.add(AuditEntry::new(AuditAction::AddPrimaryTag, Some("cpu"), serde_json::json!({ "tag": "host:a" }))).unwrap();
+    log.add(AuditEntry::new(AuditAction::CreateMetric, Some("memory"), serde_json::json!({ "type": "Gauge" }))).unwrap();
+
+    let log = AuditLog::new(temp_dir.path());
+    let entries = log.entries(&AuditQuery::default());
+    assert_eq!(3, entries.len());
+    assert_eq!(Some("memory".to_owned()), entries[0].metric);
+
+    let entries = log.entries(&AuditQuery { action: Some(AuditAction::CreateMetric), ..Default::default() });
+    assert_eq!(vec![Some("memory".to_owned()), Some("cpu".to_owned())], entries.iter().map(|entry| entry.metric.clone()).collect::<Vec<_>>());
+
+    let entries = log.entries(&AuditQuery { metric: Some("cpu".to_owned()), limit: Some(1), ..Default::default() });
+    assert_eq!(1, entries.len());
+    assert_eq!(AuditAction::AddPrimaryTag, entries[0].action);
+
+    let entries = log.entries(&AuditQuery { principal: Some("a".to_owned()), ..Default::default() });
+    assert_eq!(1, entries.len());
+}
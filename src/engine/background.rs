@@ -0,0 +1,111 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Tells a background thread to stop, which also wakes it up if it is sleeping.
+#[derive(Clone, Default)]
+pub struct StopSignal {
+    state: Arc<(Mutex<bool>, Condvar)>
+}
+
+impl StopSignal {
+    pub fn stop(&self) {
+        let (stopped, condition) = &*self.state;
+        *stopped.lock().unwrap() = true;
+        condition.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Sleeps for the given duration, returns false if stopped before (or while) sleeping.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        self.sleep_until(Instant::now() + duration)
+    }
+
+    /// Sleeps until the given deadline, returns false if stopped before (or while) sleeping.
+    pub fn sleep_until(&self, deadline: Instant) -> bool {
+        let (stopped, condition) = &*self.state;
+        let mut stopped = stopped.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            if *stopped || now >= deadline {
+                return !*stopped;
+            }
+
+            stopped = condition.wait_timeout(stopped, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// A thread running periodic work in the background. The thread is stopped (and joined) when the handle is dropped,
+/// which releases everything it holds, such as its reference to the engine.
+pub struct BackgroundThread {
+    stop_signal: StopSignal,
+    handle: Option<JoinHandle<()>>
+}
+
+impl BackgroundThread {
+    pub fn spawn(run: impl FnOnce(StopSignal) + Send + 'static) -> BackgroundThread {
+        let stop_signal = StopSignal::default();
+        let thread_stop_signal = stop_signal.clone();
+        BackgroundThread {
+            stop_signal,
+            handle: Some(std::thread::spawn(move || run(thread_stop_signal)))
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.as_ref().map(|handle| handle.is_finished()).unwrap_or(true)
+    }
+
+    /// Stops the thread and waits for its current work to complete.
+    pub fn stop(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        self.stop_signal.stop();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                tracing::error!("background thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for BackgroundThread {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+#[test]
+fn test_stop1() {
+    let engine = Arc::new(());
+    let thread_engine = engine.clone();
+    let thread = BackgroundThread::spawn(move |stop_signal| {
+        let _engine = thread_engine;
+        while stop_signal.sleep(Duration::from_secs(3600)) {}
+    });
+
+    assert!(!thread.is_finished());
+    assert_eq!(2, Arc::strong_count(&engine));
+
+    let stop_start = Instant::now();
+    thread.stop();
+    assert!(stop_start.elapsed() < Duration::from_secs(10));
+    assert_eq!(1, Arc::strong_count(&engine));
+}
+
+#[test]
+fn test_sleep1() {
+    let stop_signal = StopSignal::default();
+    assert!(stop_signal.sleep(Duration::from_millis(10)));
+    assert!(!stop_signal.is_stopped());
+
+    stop_signal.stop();
+    assert!(!stop_signal.sleep(Duration::from_secs(3600)));
+    assert!(stop_signal.is_stopped());
+}
@@ -0,0 +1,56 @@
+use serde::Deserialize;
+
+/// How far (in seconds) the time of written values may be off before they are rejected, which is zero by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClockSkewTolerance {
+    /// Values at most this far into the future are clamped to the current time.
+    pub future: f64,
+    /// Values at most this far before the latest datapoint are moved into the latest datapoint.
+    pub past: f64
+}
+
+impl ClockSkewTolerance {
+    pub fn new(future: f64, past: f64) -> ClockSkewTolerance {
+        ClockSkewTolerance {
+            future,
+            past
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.future > 0.0 || self.past > 0.0
+    }
+
+    /// Returns the adjusted time if the time is within the tolerance.
+    pub fn adjust(&self, time: f64, now: f64, latest_time: Option<f64>) -> Option<f64> {
+        if time > now && time - now <= self.future {
+            return Some(now.max(latest_time.unwrap_or(now)));
+        }
+
+        if let Some(latest_time) = latest_time {
+            if time < latest_time && latest_time - time <= self.past {
+                return Some(latest_time);
+            }
+        }
+
+        None
+    }
+}
+
+#[test]
+fn test_adjust1() {
+    let tolerance = ClockSkewTolerance::new(2.0, 5.0);
+    let now = 1654077600.0;
+
+    assert_eq!(Some(now), tolerance.adjust(now + 1.5, now, None));
+    assert_eq!(None, tolerance.adjust(now + 3.0, now, None));
+    assert_eq!(None, tolerance.adjust(now - 10.0, now, None));
+
+    assert_eq!(Some(now - 1.0), tolerance.adjust(now - 4.0, now, Some(now - 1.0)));
+    assert_eq!(None, tolerance.adjust(now - 7.0, now, Some(now - 1.0)));
+    assert_eq!(None, tolerance.adjust(now - 0.5, now, Some(now - 1.0)));
+
+    // Clamping never moves a value before the latest datapoint
+    assert_eq!(Some(now + 0.5), tolerance.adjust(now + 1.0, now, Some(now + 0.5)));
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::engine::io::{MetricsEngineError, MetricsEngineResult};
+use crate::engine::querying::MetricQueryExpression;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Dashboard {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub panels: Vec<DashboardPanel>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct DashboardPanel {
+    pub title: String,
+    pub expression: MetricQueryExpression,
+    /// The window duration in seconds, if the panel should be shown as a time series.
+    #[serde(default)]
+    pub duration: Option<f64>,
+    /// Free-form hints for the frontend (position, size, chart type etc.).
+    #[serde(default)]
+    pub layout: serde_json::Value
+}
+
+pub struct DashboardsStore {
+    path: PathBuf,
+    dashboards: BTreeMap<String, Dashboard>
+}
+
+impl DashboardsStore {
+    pub fn new(base_path: &Path) -> DashboardsStore {
+        DashboardsStore {
+            path: base_path.join("dashboards.json"),
+            dashboards: BTreeMap::new()
+        }
+    }
+
+    pub fn from_existing(base_path: &Path) -> MetricsEngineResult<DashboardsStore> {
+        let mut store = DashboardsStore::new(base_path);
+        if !store.path.exists() {
+            return Ok(store);
+        }
+
+        let load = || -> std::io::Result<BTreeMap<String, Dashboard>> {
+            let content = std::fs::read_to_string(&store.path)?;
+            let dashboards: BTreeMap<String, Dashboard> = serde_json::from_str(&content)?;
+            Ok(dashboards)
+        };
+
+        store.dashboards = load().map_err(MetricsEngineError::FailedToLoadDashboards)?;
+        Ok(store)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.dashboards.keys().cloned().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Dashboard> {
+        self.dashboards.get(name)
+    }
+
+    pub fn put(&mut self, name: &str, dashboard: Dashboard) -> MetricsEngineResult<()> {
+        let previous = self.dashboards.insert(name.to_owned(), dashboard);
+
+        if let Err(err) = self.save() {
+            match previous {
+                Some(previous) => { self.dashboards.insert(name.to_owned(), previous); }
+                None => { self.dashboards.remove(name); }
+            }
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> MetricsEngineResult<()> {
+        let dashboard = self.dashboards.remove(name).ok_or_else(|| MetricsEngineError::DashboardNotFound(name.to_owned()))?;
+
+        if let Err(err) = self.save() {
+            self.dashboards.insert(name.to_owned(), dashboard);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    fn save(&self) -> MetricsEngineResult<()> {
+        let save = || -> std::io::Result<()> {
+            let content = serde_json::to_string(&self.dashboards)?;
+            std::fs::write(&self.path, &content)?;
+            Ok(())
+        };
+
+        save().map_err(MetricsEngineError::FailedToSaveDashboards)
+    }
+}
+
+#[test]
+fn test_dashboards1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let dashboard: Dashboard = serde_json::from_str(r#"{
+        "title": "Servers",
+        "panels": [
+            {
+                "title": "CPU",
+                "expression": { "Average": { "metric": "cpu", "query": { "group_by": "host" } } },
+                "duration": 60.0,
+                "layout": { "x": 0, "y": 0, "width": 6 }
+            }
+        ]
+    }"#).unwrap();
+
+    let mut store = DashboardsStore::new(temp_dir.path());
+    store.put("servers", dashboard.clone()).unwrap();
+    store.put("empty", Dashboard { title: String::new(), panels: Vec::new() }).unwrap();
+    assert_eq!(vec!["empty".to_owned(), "servers".to_owned()], store.names());
+
+    store.remove("empty").unwrap();
+    assert!(matches!(store.remove("empty"), Err(MetricsEngineError::DashboardNotFound(_))));
+
+    let store = DashboardsStore::from_existing(temp_dir.path()).unwrap();
+    assert_eq!(vec!["servers".to_owned()], store.names());
+
+    let loaded = store.get("servers").unwrap();
+    assert_eq!("CPU", loaded.panels[0].title);
+    assert_eq!(Some(60.0), loaded.panels[0].duration);
+    assert_eq!(serde_json::to_value(&dashboard).unwrap(), serde_json::to_value(loaded).unwrap());
+}
@@ -0,0 +1,148 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metric::common::MetricType;
+
+/// An entry in the log, where the definition of an added metric is stored as `[name, type]` (as in the legacy format).
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DefinitionEntry {
+    Added(String, MetricType),
+    Removed { removed: String }
+}
+
+/// The metric definitions, stored as JSON lines such that creating a metric only appends to the file.
+pub struct DefinitionsLog {
+    path: PathBuf,
+    legacy_path: PathBuf,
+    /// If the whole file needs to be written on the next change, such as when stored in the legacy format or when a write was interrupted.
+    rewrite: AtomicBool
+}
+
+impl DefinitionsLog {
+    pub fn new(base_path: &Path) -> DefinitionsLog {
+        DefinitionsLog {
+            path: base_path.join("metrics.jsonl"),
+            legacy_path: base_path.join("metrics.json"),
+            rewrite: AtomicBool::new(false)
+        }
+    }
+
+    pub fn exists(base_path: &Path) -> bool {
+        let log = DefinitionsLog::new(base_path);
+        log.path.exists() || log.legacy_path.exists()
+    }
+
+    pub fn load(base_path: &Path) -> std::io::Result<(DefinitionsLog, Vec<(String, MetricType)>)> {
+        let log = DefinitionsLog::new(base_path);
+
+        if !log.path.exists() && log.legacy_path.exists() {
+            let content = std::fs::read_to_string(&log.legacy_path)?;
+            let definitions: Vec<(String, MetricType)> = serde_json::from_str(&content)?;
+            log.rewrite.store(true, Ordering::SeqCst);
+            return Ok((log, definitions));
+        }
+
+        let content = std::fs::read_to_string(&log.path)?;
+        let mut definitions: Vec<(String, MetricType)> = Vec::new();
+        for line in content.lines() {
+            match serde_json::from_str::<DefinitionEntry>(line) {
+                Ok(DefinitionEntry::Added(name, metric_type)) => {
+                    definitions.retain(|(existing, _)| existing != &name);
+                    definitions.push((name, metric_type));
+                }
+                Ok(DefinitionEntry::Removed { removed }) => {
+                    definitions.retain(|(existing, _)| existing != &removed);
+                }
+                Err(err) => {
+                    tracing::warn!(path = ?log.path, error = %err, "skipping invalid metric definition");
+                    log.rewrite.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+
+        if !content.is_empty() && !content.ends_with('\n') {
+            log.rewrite.store(true, Ordering::SeqCst);
+        }
+
+        Ok((log, definitions))
+    }
+
+    /// Adds the definition of the metric, where the definitions include all metrics and are only used if the whole file needs to be written.
+    pub fn add(&self,
+               name: &str,
+               metric_type: &MetricType,
+               definitions: impl Iterator<Item=(String, MetricType)>) -> std::io::Result<()> {
+        self.append(&DefinitionEntry::Added(name.to_owned(), metric_type.clone()), definitions)
+    }
+
+    /// Removes the definition of the metric, where the definitions are the remaining metrics.
+    pub fn remove(&self, name: &str, definitions: impl Iterator<Item=(String, MetricType)>) -> std::io::Result<()> {
+        self.append(&DefinitionEntry::Removed { removed: name.to_owned() }, definitions)
+    }
+
+    fn append(&self, entry: &DefinitionEntry, definitions: impl Iterator<Item=(String, MetricType)>) -> std::io::Result<()> {
+        if self.rewrite.load(Ordering::SeqCst) {
+            let mut content = String::new();
+            for definition in definitions {
+                content += &serde_json::to_string(&definition)?;
+                content.push('\n');
+            }
+
+            let temp_path = self.path.with_extension("jsonl.tmp");
+            std::fs::write(&temp_path, content)?;
+            std::fs::rename(&temp_path, &self.path)?;
+            if self.legacy_path.exists() {
+                std::fs::remove_file(&self.legacy_path)?;
+            }
+
+            self.rewrite.store(false, Ordering::SeqCst);
+        } else {
+            let mut line = serde_json::to_string(entry)?;
+            line.push('\n');
+
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+            if let Err(err) = file.write_all(line.as_bytes()) {
+                self.rewrite.store(true, Ordering::SeqCst);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_definitions_log1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    std::fs::write(temp_dir.path().join("metrics.json"), r#"[["cpu","Gauge"]]"#).unwrap();
+    assert!(DefinitionsLog::exists(temp_dir.path()));
+
+    let (log, mut definitions) = DefinitionsLog::load(temp_dir.path()).unwrap();
+    assert_eq!(vec![("cpu".to_owned(), MetricType::Gauge)], definitions);
+
+    definitions.push(("requests".to_owned(), MetricType::Count));
+    log.add("requests", &MetricType::Count, definitions.iter().cloned()).unwrap();
+    assert!(!temp_dir.path().join("metrics.json").exists());
+
+    definitions.push(("errors".to_owned(), MetricType::Ratio));
+    log.add("errors", &MetricType::Ratio, definitions.iter().cloned()).unwrap();
+
+    // An interrupted write leaves a partial line
+    let mut file = std::fs::OpenOptions::new().append(true).open(temp_dir.path().join("metrics.jsonl")).unwrap();
+    file.write_all(br#"["lat"#).unwrap();
+
+    let (log, loaded) = DefinitionsLog::load(temp_dir.path()).unwrap();
+    assert_eq!(definitions, loaded);
+
+    definitions.remove(0);
+    log.remove("cpu", definitions.iter().cloned()).unwrap();
+    log.remove("requests", definitions.iter().skip(1).cloned()).unwrap();
+
+    let (_, loaded) = DefinitionsLog::load(temp_dir.path()).unwrap();
+    assert_eq!(vec![("errors".to_owned(), MetricType::Ratio)], loaded);
+}
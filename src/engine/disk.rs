@@ -0,0 +1,155 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct DiskSpace {
+    pub free_bytes: u64,
+    pub total_bytes: u64
+}
+
+impl DiskSpace {
+    pub fn of(path: &Path) -> std::io::Result<DiskSpace> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let fragment_size = stats.f_frsize as u64;
+        Ok(
+            DiskSpace {
+                free_bytes: stats.f_bavail as u64 * fragment_size,
+                total_bytes: stats.f_blocks as u64 * fragment_size
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DiskWatchdogConfig {
+    pub min_free_bytes: Option<u64>,
+    /// The minimum fraction (between 0 and 1) of the volume that must be free.
+    pub min_free_ratio: Option<f64>
+}
+
+impl DiskWatchdogConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.min_free_bytes.is_some() || self.min_free_ratio.is_some()
+    }
+
+    pub fn is_low(&self, disk_space: &DiskSpace) -> bool {
+        if let Some(min_free_bytes) = self.min_free_bytes {
+            if disk_space.free_bytes < min_free_bytes {
+                return true;
+            }
+        }
+
+        if let Some(min_free_ratio) = self.min_free_ratio {
+            if disk_space.total_bytes > 0 && (disk_space.free_bytes as f64 / disk_space.total_bytes as f64) < min_free_ratio {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Rejects writes once the free space of the storage volume drops below the configured limits,
+/// before the memory mapped files start failing.
+pub struct DiskWatchdog {
+    config: DiskWatchdogConfig,
+    low: AtomicBool,
+    free_bytes: AtomicU64,
+    total_bytes: AtomicU64
+}
+
+impl DiskWatchdog {
+    pub fn new(config: DiskWatchdogConfig) -> DiskWatchdog {
+        DiskWatchdog {
+            config,
+            low: AtomicBool::new(false),
+            free_bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0)
+        }
+    }
+
+    pub fn check(&self, path: &Path) {
+        if !self.config.is_enabled() {
+            return;
+        }
+
+        match DiskSpace::of(path) {
+            Ok(disk_space) => self.update(&disk_space),
+            Err(err) => {
+                tracing::warn!(error = %err, "Failed to determine free disk space.");
+            }
+        }
+    }
+
+    fn update(&self, disk_space: &DiskSpace) {
+        self.free_bytes.store(disk_space.free_bytes, Ordering::SeqCst);
+        self.total_bytes.store(disk_space.total_bytes, Ordering::SeqCst);
+
+        let low = self.config.is_low(disk_space);
+        let was_low = self.low.swap(low, Ordering::SeqCst);
+        if low && !was_low {
+            tracing::error!(free_bytes = disk_space.free_bytes, "Low disk space, rejecting writes.");
+        } else if !low && was_low {
+            tracing::info!(free_bytes = disk_space.free_bytes, "Disk space recovered, accepting writes.");
+        }
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::SeqCst)
+    }
+
+    /// The disk space at the last check, if the watchdog is enabled.
+    pub fn disk_space(&self) -> Option<DiskSpace> {
+        if !self.config.is_enabled() {
+            return None;
+        }
+
+        Some(
+            DiskSpace {
+                free_bytes: self.free_bytes.load(Ordering::SeqCst),
+                total_bytes: self.total_bytes.load(Ordering::SeqCst)
+            }
+        )
+    }
+}
+
+#[test]
+fn test_disk_space1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let disk_space = DiskSpace::of(temp_dir.path()).unwrap();
+    assert!(disk_space.total_bytes > 0);
+    assert!(disk_space.free_bytes <= disk_space.total_bytes);
+}
+
+#[test]
+fn test_watchdog1() {
+    let watchdog = DiskWatchdog::new(DiskWatchdogConfig { min_free_bytes: Some(1000), min_free_ratio: Some(0.1) });
+
+    watchdog.update(&DiskSpace { free_bytes: 5000, total_bytes: 10000 });
+    assert!(!watchdog.is_low());
+
+    watchdog.update(&DiskSpace { free_bytes: 900, total_bytes: 5000 });
+    assert!(watchdog.is_low());
+
+    watchdog.update(&DiskSpace { free_bytes: 1500, total_bytes: 20000 });
+    assert!(watchdog.is_low());
+
+    watchdog.update(&DiskSpace { free_bytes: 3000, total_bytes: 20000 });
+    assert!(!watchdog.is_low());
+    assert_eq!(Some(DiskSpace { free_bytes: 3000, total_bytes: 20000 }), watchdog.disk_space());
+}
@@ -1,322 +1,1993 @@
-use std::ops::{Deref, DerefMut};
+use std::ops::{ControlFlow, Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use dashmap::DashMap;
-use fnv::FnvBuildHasher;
+use fnv::{FnvBuildHasher, FnvHashMap};
+use serde_json::json;
 
-use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue, MetricsEngineError, MetricsEngineResult};
+use crate::engine::annotations::{Annotation, AnnotationsStore};
+use crate::engine::audit::{AuditEntry, AuditLog, AuditQuery};
+use crate::engine::clock_skew::ClockSkewTolerance;
+use crate::engine::dashboards::{Dashboard, DashboardsStore};
+use crate::engine::definitions::DefinitionsLog;
+use crate::engine::disk::{DiskSpace, DiskWatchdog, DiskWatchdogConfig};
+use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue, LoadingStatus, MetricsEngineError, MetricsEngineResult};
+use crate::engine::limits::{ActiveQueries, QueryLimits};
+use crate::engine::lock_watchdog::{LockStats, LockWatchdog, LockWatchdogConfig};
+use crate::engine::preaggregation::{BufferedValues, PreAggregation, PreAggregationBuffer, PreAggregationConfig};
+use crate::engine::sampling::{SamplingRule, SamplingRules};
+use crate::engine::schema::{self, MetricSchema, Schema, SchemaChanges};
+use crate::engine::quotas::{QuotaTracker, QuotaUsage, WriteQuotas};
 use crate::engine::querying;
 use crate::engine::querying::MetricQuery;
-use crate::metric::common::{GenericMetric, MetricConfig, MetricType};
+use crate::engine::scheduler::SchedulerConfig;
+use crate::engine::snapshots;
+use crate::engine::slow_queries::{SlowQuery, SlowQueryConfig, SlowQueryLog};
+use crate::engine::window_cache::{self, WindowCache, WindowCacheConfig, WindowCacheKey};
+use crate::engine::snapshots::{WritePause, WritePauseGuard};
+use crate::engine::tiering::TieringConfig;
+use crate::engine::templates::{MetricTemplate, UnknownMetricMode};
+use crate::engine::trash::{DeletedMetric, Trash, TrashConfig};
+use crate::engine::tenants::{TenantTracker, TenantsConfig, TenantUsage};
+use crate::engine::verification::VerificationStatus;
+use crate::metric::common::{GenericMetric, MetricConfig, MetricType, RollupConfig, RollupOperation};
 use crate::metric::count::DefaultCountMetric;
 use crate::metric::gauge::DefaultGaugeMetric;
-use crate::metric::OperationResult;
+use crate::metric::{blocks_scanned, CombineGroups, OperationResult};
 use crate::metric::ratio::{DefaultRatioMetric};
-use crate::metric::tags::{PrimaryTag};
-use crate::model::Query;
+use crate::metric::tags::{PrimaryTag, Tag};
+use crate::model::{GroupKey, GroupLimit, MetricResult, Query, ReadConsistency, Time, TimeRange, TIME_SCALE};
 
 pub struct MetricsEngine {
     base_path: PathBuf,
+    definitions: DashMap<String, MetricType, FnvBuildHasher>,
     metrics: DashMap<String, ArcMetric, FnvBuildHasher>,
-    create_lock: Mutex<()>
+    load_locks: DashMap<String, Arc<Mutex<()>>, FnvBuildHasher>,
+    create_lock: Mutex<()>,
+    definitions_log: DefinitionsLog,
+    trash: Trash,
+    loading_progress: LoadingProgress,
+    annotations: RwLock<AnnotationsStore>,
+    dashboards: RwLock<DashboardsStore>,
+    default_configs: FnvHashMap<MetricType, MetricConfig>,
+    scheduler_config: SchedulerConfig,
+    max_loaded_metrics: Option<usize>,
+    read_only: bool,
+    query_limits: QueryLimits,
+    active_queries: ActiveQueries,
+    write_quotas: WriteQuotas,
+    quota_tracker: QuotaTracker,
+    tenants: TenantTracker,
+    disk_watchdog: DiskWatchdog,
+    verification: Mutex<VerificationStatus>,
+    write_pause: WritePause,
+    tiering: TieringConfig,
+    templates: Vec<MetricTemplate>,
+    unknown_metrics: UnknownMetricMode,
+    dropped_values: AtomicU64,
+    rejected_values: AtomicU64,
+    write_sequences: DashMap<String, u64, FnvBuildHasher>,
+    clock_skew: ClockSkewTolerance,
+    clock_skew_adjustments: AtomicU64,
+    pre_aggregation: PreAggregationBuffer,
+    sampling_rules: SamplingRules,
+    slow_queries: SlowQueryLog,
+    audit_log: AuditLog,
+    window_cache: WindowCache,
+    lock_watchdog: Arc<LockWatchdog>
 }
 
 impl MetricsEngine {
     pub fn new(base_path: &Path) -> MetricsEngineResult<MetricsEngine> {
+        MetricsEngine::new_with_config(MetricsEngineBuilder::new(base_path))
+    }
+
+    pub fn from_existing(base_path: &Path) -> MetricsEngineResult<MetricsEngine> {
+        MetricsEngine::from_existing_with_config(MetricsEngineBuilder::new(base_path))
+    }
+
+    pub fn new_or_from_existing(base_path: &Path) -> MetricsEngineResult<MetricsEngine> {
+        if DefinitionsLog::exists(base_path) {
+            MetricsEngine::from_existing(base_path)
+        } else {
+            MetricsEngine::new(base_path)
+        }
+    }
+
+    fn new_with_config(config: MetricsEngineBuilder) -> MetricsEngineResult<MetricsEngine> {
+        let base_path = config.base_path.clone();
         if !base_path.exists() {
-            std::fs::create_dir_all(base_path).map_err(|err| MetricsEngineError::FailedToCreateBaseDir(err))?;
+            std::fs::create_dir_all(&base_path).map_err(|err| MetricsEngineError::FailedToCreateBaseDir(base_path.clone(), err))?;
         }
 
         Ok(
-            MetricsEngine {
-                base_path: base_path.to_owned(),
-                metrics: DashMap::default(),
-                create_lock: Mutex::new(())
-            }
+            MetricsEngine::with_config(
+                config,
+                DefinitionsLog::new(&base_path),
+                DashMap::default(),
+                AnnotationsStore::new(&base_path),
+                DashboardsStore::new(&base_path)
+            )
+        )
+    }
+
+    fn from_existing_with_config(config: MetricsEngineBuilder) -> MetricsEngineResult<MetricsEngine> {
+        let base_path = config.base_path.clone();
+        let (definitions_log, loaded_definitions) = DefinitionsLog::load(&base_path)
+            .map_err(MetricsEngineError::FailedToLoadMetricDefinitions)?;
+
+        let definitions = DashMap::default();
+        for (metric_name, metric_type) in loaded_definitions {
+            definitions.insert(metric_name, metric_type);
+        }
+
+        Ok(
+            MetricsEngine::with_config(
+                config,
+                definitions_log,
+                definitions,
+                AnnotationsStore::from_existing(&base_path)?,
+                DashboardsStore::from_existing(&base_path)?
+            )
         )
     }
 
-    pub fn from_existing(base_path: &Path) -> MetricsEngineResult<MetricsEngine> {
-        let load = || -> std::io::Result<Vec<(String, MetricType)>> {
-            let content = std::fs::read_to_string(&base_path.join("metrics.json"))?;
-            let metrics: Vec<_> = serde_json::from_str(&content)?;
-            Ok(metrics)
+    fn with_config(config: MetricsEngineBuilder,
+                   definitions_log: DefinitionsLog,
+                   definitions: DashMap<String, MetricType, FnvBuildHasher>,
+                   annotations: AnnotationsStore,
+                   dashboards: DashboardsStore) -> MetricsEngine {
+        let base_path = config.base_path;
+        let metrics_engine = MetricsEngine {
+            definitions,
+            metrics: DashMap::default(),
+            load_locks: DashMap::default(),
+            create_lock: Mutex::new(()),
+            definitions_log,
+            trash: Trash::new(&base_path, config.trash),
+            loading_progress: LoadingProgress::default(),
+            annotations: RwLock::new(annotations),
+            dashboards: RwLock::new(dashboards),
+            default_configs: config.default_configs,
+            scheduler_config: config.scheduler_config,
+            max_loaded_metrics: config.max_loaded_metrics,
+            read_only: config.read_only,
+            query_limits: config.query_limits,
+            active_queries: ActiveQueries::new(),
+            write_quotas: config.write_quotas,
+            quota_tracker: QuotaTracker::new(),
+            tenants: TenantTracker::new(config.tenants),
+            disk_watchdog: DiskWatchdog::new(config.disk_watchdog),
+            verification: Mutex::new(VerificationStatus::default()),
+            write_pause: WritePause::default(),
+            tiering: config.tiering,
+            templates: config.templates,
+            unknown_metrics: config.unknown_metrics,
+            dropped_values: AtomicU64::new(0),
+            rejected_values: AtomicU64::new(0),
+            write_sequences: DashMap::default(),
+            clock_skew: config.clock_skew,
+            clock_skew_adjustments: AtomicU64::new(0),
+            pre_aggregation: PreAggregationBuffer::new(&config.pre_aggregation),
+            sampling_rules: SamplingRules::new(config.sampling_rules),
+            slow_queries: SlowQueryLog::new(&base_path, config.slow_queries),
+            audit_log: AuditLog::new(&base_path),
+            window_cache: WindowCache::new(config.window_cache),
+            lock_watchdog: Arc::new(LockWatchdog::new(config.lock_watchdog)),
+            base_path
+        };
+
+        metrics_engine.move_tenant_metrics();
+        for metric in metrics_engine.metric_names() {
+            metrics_engine.tenants.update_bytes(&metric, &metrics_engine.metric_path(&metric));
+        }
+        metrics_engine.check_disk_space();
+        metrics_engine
+    }
+
+    pub fn add_metric(&self, name: &str, metric_type: MetricType) -> MetricsEngineResult<()> {
+        let config = self.default_config(name, &metric_type);
+        self.add_metric_with_config(name, metric_type, config)
+    }
+
+    pub fn add_metric_with_config(&self,
+                                  name: &str,
+                                  metric_type: MetricType,
+                                  config: MetricConfig) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+
+        for rollup in &config.rollups {
+            if rollup.metric == name || !rollup.duration.is_finite() || rollup.duration <= 0.0 {
+                return Err(MetricsEngineError::InvalidInput(format!("The rollup into '{}' is not valid.", rollup.metric)));
+            }
+        }
+
+        self.create_metric(name, metric_type, config)
+    }
+
+    /// The config used for the metric when not given explicitly, which includes the retention of the tenant of the metric.
+    pub fn default_metric_config(&self, name: &str, metric_type: MetricType) -> MetricConfig {
+        self.default_config(name, &metric_type)
+    }
+
+    fn default_config(&self, name: &str, metric_type: &MetricType) -> MetricConfig {
+        let mut config = self.default_configs
+            .get(metric_type)
+            .cloned()
+            .unwrap_or_else(|| MetricConfig::new(metric_type.clone()));
+
+        if let Some(data_keep_time) = self.tenants.config().for_metric(name).and_then(|tenant| tenant.data_keep_time) {
+            for duration in &mut config.durations {
+                duration.set_max_segments(data_keep_time);
+            }
+        }
+
+        config
+    }
+
+    /// Metrics belonging to a tenant are stored in the directory of the tenant, and other metrics directly in the base directory.
+    fn metric_path(&self, name: &str) -> PathBuf {
+        match self.tenants.config().for_metric(name) {
+            Some(tenant) => tenant.path(&self.base_path).join(name),
+            None => self.base_path.join(name)
+        }
+    }
+
+    /// Moves metrics created before they belonged to a tenant into the directory of the tenant.
+    fn move_tenant_metrics(&self) {
+        if self.read_only {
+            return;
+        }
+
+        for metric in self.metric_names() {
+            let old_path = self.base_path.join(&metric);
+            let new_path = self.metric_path(&metric);
+            if old_path == new_path || !old_path.exists() || new_path.exists() {
+                continue;
+            }
+
+            let result = std::fs::create_dir_all(new_path.parent().unwrap_or(&self.base_path))
+                .and_then(|_| std::fs::rename(&old_path, &new_path));
+
+            match result {
+                Ok(()) => { tracing::info!(metric, path = ?new_path, "moved metric to the directory of its tenant"); }
+                Err(err) => { tracing::error!(metric, error = %err, "failed to move metric to the directory of its tenant"); }
+            }
+        }
+    }
+
+    fn create_metric(&self, name: &str, metric_type: MetricType, config: MetricConfig) -> MetricsEngineResult<()> {
+        let _guard = self.create_lock.lock().unwrap();
+        if self.definitions.contains_key(name) {
+            return Err(MetricsEngineError::MetricAlreadyExists(name.to_owned()));
+        }
+
+        let metric_path = self.metric_path(name);
+        let metric = match metric_type {
+            MetricType::Gauge => Metric::gauge(DefaultGaugeMetric::with_config(&metric_path, config)?),
+            MetricType::Count => Metric::count(DefaultCountMetric::with_config(&metric_path, config)?),
+            MetricType::Ratio => Metric::ratio(DefaultRatioMetric::with_config(&metric_path, config)?)
+        };
+        metric.write().unwrap().set_lock_watchdog(name, self.lock_watchdog.clone());
+        self.metrics.insert(name.to_string(), metric);
+        self.definitions.insert(name.to_owned(), metric_type.clone());
+
+        self.definitions_log.add(
+            name,
+            &metric_type,
+            self.definitions.iter().map(|item| (item.key().to_owned(), item.value().clone()))
+        ).map_err(MetricsEngineError::FailedToSaveMetricDefinitions)?;
+        Ok(())
+    }
+
+    /// Deletes the metric by moving it to the trash, where it is kept for the grace period and can be undeleted.
+    pub fn delete_metric(&self, name: &str) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+        self.write_buffered()?;
+
+        let _create_guard = self.create_lock.lock().unwrap();
+        let metric_type = self.definitions.get(name).ok_or_else(|| MetricsEngineError::MetricNotFound(name.to_owned()))?.value().clone();
+
+        self.with_load_lock(name, || {
+            if let Some((_, metric)) = self.metrics.remove(name) {
+                // Waits for the operations using the metric to complete
+                let metric = self.lock_watchdog.write(name, &metric);
+                metric.flush()?;
+            }
+
+            self.trash.add(&self.metric_path(name), name, metric_type)
+                .map_err(|err| MetricsEngineError::FailedToDeleteMetric(name.to_owned(), err))
+        })?;
+
+        self.definitions.remove(name);
+        self.tenants.remove_metric(name);
+        self.definitions_log.remove(
+            name,
+            self.definitions.iter().map(|item| (item.key().to_owned(), item.value().clone()))
+        ).map_err(MetricsEngineError::FailedToSaveMetricDefinitions)?;
+
+        self.window_cache.invalidate(name);
+        tracing::info!(metric = name, "deleted metric");
+        Ok(())
+    }
+
+    /// Restores the latest deleted version of the metric from the trash.
+    pub fn undelete_metric(&self, name: &str) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+
+        let _create_guard = self.create_lock.lock().unwrap();
+        if self.definitions.contains_key(name) {
+            return Err(MetricsEngineError::MetricAlreadyExists(name.to_owned()));
+        }
+
+        let deleted_metric = self.trash.restore(name, &self.metric_path(name))
+            .map_err(|err| MetricsEngineError::FailedToUndeleteMetric(name.to_owned(), err))?
+            .ok_or_else(|| MetricsEngineError::MetricNotFound(name.to_owned()))?;
+
+        self.definitions.insert(name.to_owned(), deleted_metric.metric_type.clone());
+        self.definitions_log.add(
+            name,
+            &deleted_metric.metric_type,
+            self.definitions.iter().map(|item| (item.key().to_owned(), item.value().clone()))
+        ).map_err(MetricsEngineError::FailedToSaveMetricDefinitions)?;
+        self.tenants.update_bytes(name, &self.metric_path(name));
+
+        tracing::info!(metric = name, "undeleted metric");
+        Ok(())
+    }
+
+    /// The metrics in the trash, latest deleted first.
+    pub fn deleted_metrics(&self) -> Vec<DeletedMetric> {
+        self.trash.deleted_metrics()
+    }
+
+    /// Permanently removes the metrics that were deleted longer than the grace period ago.
+    pub fn purge_deleted_metrics(&self) {
+        if self.read_only {
+            return;
+        }
+
+        match self.trash.purge() {
+            Ok(num_purged) if num_purged > 0 => {
+                tracing::info!(num_purged, "purged deleted metrics");
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to purge deleted metrics");
+            }
+        }
+    }
+
+    pub fn warm(&self, num_threads: usize) -> MetricsEngineResult<()> {
+        let metric_names = self.metric_names();
+        self.loading_progress.start(metric_names.len());
+
+        let next_index = AtomicUsize::new(0);
+        let first_error = Mutex::new(None);
+
+        std::thread::scope(|scope| {
+            for _ in 0..num_threads.max(1) {
+                scope.spawn(|| {
+                    loop {
+                        let index = next_index.fetch_add(1, Ordering::SeqCst);
+                        if index >= metric_names.len() {
+                            break;
+                        }
+
+                        match self.get_metric(&metric_names[index]) {
+                            Ok(_) => {
+                                self.loading_progress.loaded.fetch_add(1, Ordering::SeqCst);
+                            }
+                            Err(err) => {
+                                tracing::error!(metric = %metric_names[index], error = %err, "failed to load metric");
+                                self.loading_progress.failed.fetch_add(1, Ordering::SeqCst);
+                                first_error.lock().unwrap().get_or_insert(err);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        match first_error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    pub fn loading_status(&self) -> LoadingStatus {
+        self.loading_progress.status()
+    }
+
+    fn get_metric(&self, name: &str) -> MetricsEngineResult<ArcMetric> {
+        if let Some(metric) = self.metrics.get(name) {
+            return Ok(metric.value().clone());
+        }
+
+        let metric_type = self.definitions.get(name).ok_or_else(|| MetricsEngineError::MetricNotFound(name.to_owned()))?.value().clone();
+
+        self.with_load_lock(name, || {
+            if let Some(metric) = self.metrics.get(name) {
+                return Ok(metric.value().clone());
+            }
+
+            tracing::debug!(metric = name, "loading metric");
+            let metric_path = self.metric_path(name);
+            let metric = if self.read_only {
+                match metric_type {
+                    MetricType::Gauge => Metric::gauge(DefaultGaugeMetric::from_existing_read_only(&metric_path)?),
+                    MetricType::Count => Metric::count(DefaultCountMetric::from_existing_read_only(&metric_path)?),
+                    MetricType::Ratio => Metric::ratio(DefaultRatioMetric::from_existing_read_only(&metric_path)?)
+                }
+            } else {
+                match metric_type {
+                    MetricType::Gauge => Metric::gauge(DefaultGaugeMetric::from_existing(&metric_path)?),
+                    MetricType::Count => Metric::count(DefaultCountMetric::from_existing(&metric_path)?),
+                    MetricType::Ratio => Metric::ratio(DefaultRatioMetric::from_existing(&metric_path)?)
+                }
+            };
+            metric.write().unwrap().set_lock_watchdog(name, self.lock_watchdog.clone());
+
+            self.evict_loaded_metrics(name);
+            self.metrics.insert(name.to_owned(), metric.clone());
+            Ok(metric)
+        })
+    }
+
+    /// Returns the metric to write to, or none if the values should be dropped.
+    fn get_metric_for_write(&self,
+                            name: &str,
+                            metric_type: MetricType,
+                            unknown_metrics: UnknownMetricMode) -> MetricsEngineResult<Option<ArcMetric>> {
+        match (self.get_metric(name), unknown_metrics) {
+            (Err(MetricsEngineError::MetricNotFound(_)), UnknownMetricMode::Drop) => return Ok(None),
+            (Err(MetricsEngineError::MetricNotFound(_)), UnknownMetricMode::AutoCreate) => {}
+            (result, _) => return result.map(Some)
+        }
+
+        let template = self.templates
+            .iter()
+            .find(|template| template.metric_type == metric_type && template.matches(name))
+            .ok_or_else(|| MetricsEngineError::MetricNotFound(name.to_owned()))?;
+
+        // The metric might have been created by a concurrent write
+        match self.create_metric(name, metric_type.clone(), template.metric_config(self.default_config(name, &metric_type))) {
+            Ok(()) => {
+                tracing::info!(metric = name, pattern = %template.pattern, "created metric from template");
+            }
+            Err(MetricsEngineError::MetricAlreadyExists(_)) => {}
+            Err(err) => {
+                return Err(err);
+            }
+        }
+
+        self.get_metric(name).map(Some)
+    }
+
+    fn drop_values(&self, name: &str, num_values: usize) -> usize {
+        tracing::debug!(metric = name, num_values, "dropped values for unknown metric");
+        self.dropped_values.fetch_add(num_values as u64, Ordering::SeqCst);
+        0
+    }
+
+    fn written(&self, name: &str, num_values: usize, num_inserted: usize) -> usize {
+        let num_rejected = num_values.saturating_sub(num_inserted);
+        if num_rejected > 0 {
+            tracing::debug!(metric = name, num_rejected, "rejected values older than the active block");
+            self.rejected_values.fetch_add(num_rejected as u64, Ordering::SeqCst);
+        }
+
+        match self.write_sequences.get_mut(name) {
+            Some(mut write_sequence) => { *write_sequence += 1; }
+            None => { *self.write_sequences.entry(name.to_owned()).or_insert(0) += 1; }
+        }
+
+        num_inserted
+    }
+
+    /// Increases every time values are written to the storage of the metric, such that waiting for new data only needs
+    /// to query again once changed.
+    pub fn write_sequence(&self, metric: &str) -> u64 {
+        self.write_sequences.get(metric).map(|write_sequence| *write_sequence).unwrap_or(0)
+    }
+
+    /// The number of values dropped because they were written to unknown metrics.
+    pub fn dropped_values(&self) -> u64 {
+        self.dropped_values.load(Ordering::SeqCst)
+    }
+
+    /// The number of values in partially written batches that were rejected, such as values older than the active block.
+    pub fn rejected_values(&self) -> u64 {
+        self.rejected_values.load(Ordering::SeqCst)
+    }
+
+    /// Values without a time get the current time, but never earlier than the latest datapoint (to avoid `InvalidTimeOrder`).
+    /// Values with a time within the clock skew tolerance are adjusted in the same way.
+    fn assign_times<T>(&self, metric: &ArcMetric, values: Vec<(Option<f64>, T, Vec<Tag>)>) -> Vec<(f64, T, Vec<Tag>)> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let mut latest_time = if self.clock_skew.is_enabled() || values.iter().any(|(time, _, _)| time.is_none()) {
+            metric.read().unwrap().latest_time()
+        } else {
+            None
+        };
+
+        let mut num_adjusted = 0;
+        let values = values
+            .into_iter()
+            .map(|(time, value, tags)| {
+                let time = match time {
+                    Some(time) => {
+                        match self.clock_skew.adjust(time, now, latest_time) {
+                            Some(adjusted_time) => {
+                                num_adjusted += 1;
+                                adjusted_time
+                            }
+                            None => time
+                        }
+                    }
+                    None => latest_time.map(|latest_time| now.max(latest_time)).unwrap_or(now)
+                };
+
+                latest_time = Some(latest_time.map(|latest_time| latest_time.max(time)).unwrap_or(time));
+                (time, value, tags)
+            })
+            .collect();
+
+        if num_adjusted > 0 {
+            tracing::debug!(num_adjusted, "adjusted time of values within clock skew tolerance");
+            self.clock_skew_adjustments.fetch_add(num_adjusted, Ordering::SeqCst);
+        }
+
+        values
+    }
+
+    /// Buffered values are not counted as inserted, as they are only inserted once written. The writer that fills the
+    /// buffer writes it, such that writers can't outpace the writing of the buffer.
+    fn buffer_values(&self, name: &str, values: BufferedValues, aggregation: PreAggregation) -> MetricsEngineResult<usize> {
+        if self.pre_aggregation.add(name, values, aggregation) {
+            self.write_buffered()?;
+        }
+
+        Ok(0)
+    }
+
+    /// Writes the values buffered by the pre-aggregation to storage.
+    pub fn flush_buffered(&self) -> MetricsEngineResult<()> {
+        let _write_guard = self.write_pause.enter();
+        self.write_buffered()
+    }
+
+    /// Writes the values of all metrics, even if some fail, and returns the first error.
+    fn write_buffered(&self) -> MetricsEngineResult<()> {
+        let mut write_result = Ok(());
+        for (name, values) in self.pre_aggregation.take() {
+            write_result = write_result.and(self.write_buffered_values(&name, values));
+        }
+
+        write_result
+    }
+
+    fn write_buffered_values(&self, name: &str, values: BufferedValues) -> MetricsEngineResult<()> {
+        let result = self.get_metric(name).and_then(|metric| {
+            let num_values = values.len();
+            let result = match (self.lock_watchdog.read(name, &metric).deref(), values) {
+                (Metric::Gauge(metric), BufferedValues::Gauge(values)) => metric.add_batch(&values),
+                (Metric::Count(metric), BufferedValues::Count(values)) => metric.add_batch(&values),
+                (Metric::Ratio(metric), BufferedValues::Ratio(values)) => metric.add_batch(&values),
+                _ => Ok(0)
+            };
+
+            let num_inserted = result?;
+            self.written(name, num_values, num_inserted);
+            Ok(())
+        });
+
+        if let Err(err) = &result {
+            tracing::warn!(metric = name, error = %err, "failed to write buffered values");
+        }
+
+        result
+    }
+
+    /// The number of values that have been removed by the sampling rules.
+    pub fn sampled_out_values(&self) -> u64 {
+        self.sampling_rules.num_sampled_out()
+    }
+
+    /// The number of values that are buffered by the pre-aggregation.
+    pub fn num_buffered_values(&self) -> usize {
+        self.pre_aggregation.num_buffered()
+    }
+
+    /// The number of values whose time was adjusted because of clock skew.
+    pub fn clock_skew_adjustments(&self) -> u64 {
+        self.clock_skew_adjustments.load(Ordering::SeqCst)
+    }
+
+    fn evict_loaded_metrics(&self, loading: &str) {
+        if let Some(max_loaded_metrics) = self.max_loaded_metrics {
+            let candidates = self.metrics
+                .iter()
+                .filter(|item| item.key() != loading && Arc::strong_count(item.value()) == 1)
+                .map(|item| item.key().to_owned())
+                .collect::<Vec<_>>();
+
+            for name in candidates {
+                if self.metrics.len() < max_loaded_metrics.max(1) {
+                    break;
+                }
+
+                // Holding the load lock stops the metric from being loaded again before it has been flushed.
+                // A busy lock means that the metric is in use, and waiting for it could deadlock.
+                self.try_with_load_lock(&name, || {
+                    // Only metrics that are not in use can be unloaded, which is checked again under the shard lock
+                    if let Some((_, metric)) = self.metrics.remove_if(&name, |_, metric| Arc::strong_count(metric) == 1) {
+                        if !self.read_only {
+                            metric.read().unwrap().scheduled();
+                        }
+
+                        tracing::debug!(metric = %name, "unloaded metric");
+                    }
+                });
+            }
+        }
+    }
+
+    /// Runs the function while holding the load lock of the metric, which serializes loading and unloading it.
+    fn with_load_lock<T>(&self, name: &str, apply: impl FnOnce() -> T) -> T {
+        let load_lock = self.load_locks.entry(name.to_owned()).or_insert_with(|| Arc::new(Mutex::new(()))).value().clone();
+        let result = {
+            let _load_guard = load_lock.lock().unwrap();
+            apply()
+        };
+
+        self.release_load_lock(name, load_lock);
+        result
+    }
+
+    /// Like `with_load_lock`, but returns none instead of waiting if the load lock is held.
+    fn try_with_load_lock<T>(&self, name: &str, apply: impl FnOnce() -> T) -> Option<T> {
+        let load_lock = self.load_locks.entry(name.to_owned()).or_insert_with(|| Arc::new(Mutex::new(()))).value().clone();
+        let result = match load_lock.try_lock() {
+            Ok(_load_guard) => Some(apply()),
+            Err(_) => None
+        };
+
+        self.release_load_lock(name, load_lock);
+        result
+    }
+
+    fn release_load_lock(&self, name: &str, load_lock: Arc<Mutex<()>>) {
+        // The lock can only be acquired through the map, so it is unused if the map holds the only reference
+        drop(load_lock);
+        self.load_locks.remove_if(name, |_, load_lock| Arc::strong_count(load_lock) == 1);
+    }
+
+    /// The returned guard must be held during the write, which delays snapshots until the write is done.
+    fn check_writable(&self) -> MetricsEngineResult<WritePauseGuard<'_>> {
+        if self.read_only {
+            return Err(MetricsEngineError::ReadOnly);
+        }
+
+        if self.disk_watchdog.is_low() {
+            return Err(MetricsEngineError::LowDiskSpace);
+        }
+
+        Ok(self.write_pause.enter())
+    }
+
+    pub fn scheduler_config(&self) -> &SchedulerConfig {
+        &self.scheduler_config
+    }
+
+    pub fn add_auto_primary_tag(&self, metric: &str, key: &str) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+
+        let metric_lock = self.get_metric(metric)?;
+        match self.lock_watchdog.write(metric, &metric_lock).deref_mut() {
+            Metric::Gauge(metric) => metric.add_auto_primary_tag(key)?,
+            Metric::Count(metric) => metric.add_auto_primary_tag(key)?,
+            Metric::Ratio(metric) => metric.add_auto_primary_tag(key)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn add_primary_tag(&self, metric: &str, tag: PrimaryTag) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+
+        let metric_lock = self.get_metric(metric)?;
+        match self.lock_watchdog.write(metric, &metric_lock).deref_mut() {
+            Metric::Gauge(metric) => metric.add_primary_tag(tag)?,
+            Metric::Count(metric) => metric.add_primary_tag(tag)?,
+            Metric::Ratio(metric) => metric.add_primary_tag(tag)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_primary_tag(&self, metric: &str, tag: &Tag) -> MetricsEngineResult<bool> {
+        let _write_guard = self.check_writable()?;
+
+        let metric_lock = self.get_metric(metric)?;
+        let removed = match self.lock_watchdog.write(metric, &metric_lock).deref_mut() {
+            Metric::Gauge(metric) => metric.remove_primary_tag(tag)?,
+            Metric::Count(metric) => metric.remove_primary_tag(tag)?,
+            Metric::Ratio(metric) => metric.remove_primary_tag(tag)?,
+        };
+
+        if removed {
+            self.window_cache.invalidate(metric);
+        }
+
+        Ok(removed)
+    }
+
+    pub fn remove_auto_primary_tag(&self, metric: &str, key: &str) -> MetricsEngineResult<bool> {
+        let _write_guard = self.check_writable()?;
+
+        let metric_lock = self.get_metric(metric)?;
+        let removed = match self.lock_watchdog.write(metric, &metric_lock).deref_mut() {
+            Metric::Gauge(metric) => metric.remove_auto_primary_tag(key)?,
+            Metric::Count(metric) => metric.remove_auto_primary_tag(key)?,
+            Metric::Ratio(metric) => metric.remove_auto_primary_tag(key)?,
+        };
+
+        Ok(removed)
+    }
+
+    /// The named primary tags of the metric, sorted.
+    pub fn primary_tags(&self, metric: &str) -> MetricsEngineResult<Vec<Tag>> {
+        let metric_lock = self.get_metric(metric)?;
+        let metric = self.lock_watchdog.read(metric, &metric_lock);
+        let mut tags = match metric.deref() {
+            Metric::Gauge(metric) => metric.primary_tags().flat_map(|tag| tag.named()).cloned().collect::<Vec<_>>(),
+            Metric::Count(metric) => metric.primary_tags().flat_map(|tag| tag.named()).cloned().collect::<Vec<_>>(),
+            Metric::Ratio(metric) => metric.primary_tags().flat_map(|tag| tag.named()).cloned().collect::<Vec<_>>()
+        };
+
+        tags.sort();
+        Ok(tags)
+    }
+
+    pub fn auto_primary_tags(&self, metric: &str) -> MetricsEngineResult<Vec<String>> {
+        let metric = self.get_metric(metric)?;
+        let keys = match metric.read().unwrap().deref() {
+            Metric::Gauge(metric) => metric.auto_primary_tags(),
+            Metric::Count(metric) => metric.auto_primary_tags(),
+            Metric::Ratio(metric) => metric.auto_primary_tags()
+        };
+
+        Ok(keys)
+    }
+
+    pub fn gauge(&self, name: &str, values: impl Iterator<Item=AddGaugeValue>) -> MetricsEngineResult<usize> {
+        self.gauge_with_mode(name, values, self.unknown_metrics)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, values))]
+    pub fn gauge_with_mode(&self,
+                           name: &str,
+                           values: impl Iterator<Item=AddGaugeValue>,
+                           unknown_metrics: UnknownMetricMode) -> MetricsEngineResult<usize> {
+        let _write_guard = self.check_writable()?;
+
+        let values = values.map(|value| (value.time, value.value, value.tags)).collect::<Vec<_>>();
+        let metric = match self.get_metric_for_write(name, MetricType::Gauge, unknown_metrics)? {
+            Some(metric) => metric,
+            None => return Ok(self.drop_values(name, values.len()))
+        };
+        let values = self.sampling_rules.apply(name, values);
+        let values = self.assign_times(&metric, values);
+        self.tenants.admit(name)?;
+        let values = self.quota_tracker.admit(&self.write_quotas, name, values)?;
+        try_create_auto_primary_tags(&metric, values.iter().map(|value| &value.2))?;
+        tracing::debug!(num_values = values.len(), "adding values");
+
+        let metric = self.lock_watchdog.read(name, &metric);
+        if self.pre_aggregation.is_buffered(name) && matches!(metric.deref(), Metric::Gauge(_)) {
+            let aggregation = PreAggregation::new(metric.config());
+            drop(metric);
+            return self.buffer_values(name, BufferedValues::Gauge(values), aggregation);
+        }
+
+        match metric.deref() {
+            Metric::Gauge(metric) => Ok(self.written(name, values.len(), metric.add_batch(&values)?)),
+            _ => Err(MetricsEngineError::WrongMetricType(name.to_owned()))
+        }
+    }
+
+    pub fn count(&self, name: &str, values: impl Iterator<Item=AddCountValue>) -> MetricsEngineResult<usize> {
+        self.count_with_mode(name, values, self.unknown_metrics)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, values))]
+    pub fn count_with_mode(&self,
+                           name: &str,
+                           values: impl Iterator<Item=AddCountValue>,
+                           unknown_metrics: UnknownMetricMode) -> MetricsEngineResult<usize> {
+        let _write_guard = self.check_writable()?;
+
+        let values = values.map(|value| (value.time, value.count, value.tags)).collect::<Vec<_>>();
+        let metric = match self.get_metric_for_write(name, MetricType::Count, unknown_metrics)? {
+            Some(metric) => metric,
+            None => return Ok(self.drop_values(name, values.len()))
+        };
+        let values = self.sampling_rules.apply(name, values);
+        let values = self.assign_times(&metric, values);
+        self.tenants.admit(name)?;
+        let values = self.quota_tracker.admit(&self.write_quotas, name, values)?;
+        try_create_auto_primary_tags(&metric, values.iter().map(|value| &value.2))?;
+        tracing::debug!(num_values = values.len(), "adding values");
+
+        let metric = self.lock_watchdog.read(name, &metric);
+        if self.pre_aggregation.is_buffered(name) && matches!(metric.deref(), Metric::Count(_)) {
+            let aggregation = PreAggregation::new(metric.config());
+            drop(metric);
+            return self.buffer_values(name, BufferedValues::Count(values), aggregation);
+        }
+
+        match metric.deref() {
+            Metric::Count(metric) => Ok(self.written(name, values.len(), metric.add_batch(&values)?)),
+            _ => Err(MetricsEngineError::WrongMetricType(name.to_owned()))
+        }
+    }
+
+    pub fn ratio(&self, name: &str, values: impl Iterator<Item=AddRatioValue>) -> MetricsEngineResult<usize> {
+        self.ratio_with_mode(name, values, self.unknown_metrics)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, values))]
+    pub fn ratio_with_mode(&self,
+                           name: &str,
+                           values: impl Iterator<Item=AddRatioValue>,
+                           unknown_metrics: UnknownMetricMode) -> MetricsEngineResult<usize> {
+        let _write_guard = self.check_writable()?;
+
+        let values = values.map(|value| (value.time, value.ratio, value.tags)).collect::<Vec<_>>();
+        let metric = match self.get_metric_for_write(name, MetricType::Ratio, unknown_metrics)? {
+            Some(metric) => metric,
+            None => return Ok(self.drop_values(name, values.len()))
+        };
+        let values = self.sampling_rules.apply(name, values);
+        let values = self.assign_times(&metric, values);
+        self.tenants.admit(name)?;
+        let values = self.quota_tracker.admit(&self.write_quotas, name, values)?;
+        try_create_auto_primary_tags(&metric, values.iter().map(|value| &value.2))?;
+        tracing::debug!(num_values = values.len(), "adding values");
+
+        let metric = self.lock_watchdog.read(name, &metric);
+        if self.pre_aggregation.is_buffered(name) && matches!(metric.deref(), Metric::Ratio(_)) {
+            let aggregation = PreAggregation::new(metric.config());
+            drop(metric);
+            return self.buffer_values(name, BufferedValues::Ratio(values), aggregation);
+        }
+
+        match metric.deref() {
+            Metric::Ratio(metric) => Ok(self.written(name, values.len(), metric.add_batch(&values)?)),
+            _ => Err(MetricsEngineError::WrongMetricType(name.to_owned()))
+        }
+    }
+
+    pub fn add_annotation(&self, annotation: Annotation) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+        self.annotations.write().unwrap().add(annotation)
+    }
+
+    pub fn annotations(&self, time_range: TimeRange, tags: &[Tag]) -> Vec<Annotation> {
+        self.annotations.read().unwrap().query(time_range, tags)
+    }
+
+    pub fn dashboard_names(&self) -> Vec<String> {
+        self.dashboards.read().unwrap().names()
+    }
+
+    pub fn dashboard(&self, name: &str) -> MetricsEngineResult<Dashboard> {
+        self.dashboards.read().unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| MetricsEngineError::DashboardNotFound(name.to_owned()))
+    }
+
+    pub fn put_dashboard(&self, name: &str, dashboard: Dashboard) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+        self.dashboards.write().unwrap().put(name, dashboard)
+    }
+
+    pub fn remove_dashboard(&self, name: &str) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+        self.dashboards.write().unwrap().remove(name)
+    }
+
+    /// Exports the definitions of all metrics and dashboards, which loads all metrics.
+    pub fn export_schema(&self) -> MetricsEngineResult<Schema> {
+        let mut schema = Schema::default();
+        for name in self.metric_names() {
+            let metric_lock = self.get_metric(&name)?;
+            let metric = self.lock_watchdog.read(&name, &metric_lock);
+            schema.metrics.insert(
+                name.clone(),
+                MetricSchema {
+                    metric_type: metric.metric_type(),
+                    config: Some(metric.config().clone()),
+                    primary_tags: Vec::new(),
+                    auto_primary_tags: Vec::new()
+                }
+            );
+        }
+
+        for (name, metric_schema) in schema.metrics.iter_mut() {
+            metric_schema.primary_tags = self.primary_tags(name)?;
+            metric_schema.auto_primary_tags = self.auto_primary_tags(name)?;
+            metric_schema.auto_primary_tags.sort();
+        }
+
+        let dashboards = self.dashboards.read().unwrap();
+        for name in dashboards.names() {
+            if let Some(dashboard) = dashboards.get(&name) {
+                schema.dashboards.insert(name, dashboard.clone());
+            }
+        }
+
+        Ok(schema)
+    }
+
+    /// The changes that applying the schema would make, where metrics, primary tags and dashboards that are not in the
+    /// schema are only removed if pruning.
+    pub fn diff_schema(&self, schema: &Schema, prune: bool) -> MetricsEngineResult<SchemaChanges> {
+        let mut changes = SchemaChanges::default();
+        for (name, metric_schema) in &schema.metrics {
+            let metric_type = self.definitions.get(name).map(|item| item.value().clone());
+            let (primary_tags, auto_primary_tags) = match metric_type {
+                Some(metric_type) if metric_type != metric_schema.metric_type => {
+                    return Err(MetricsEngineError::InvalidInput(format!("The metric '{}' already exists with a different type.", name)));
+                }
+                Some(_) => {
+                    let metric_lock = self.get_metric(name)?;
+                    let config = self.lock_watchdog.read(name, &metric_lock).config().clone();
+                    if let Some(schema_config) = metric_schema.config.as_ref() {
+                        if schema::config_value(&config) != schema::config_value(schema_config) {
+                            changes.changed_configs.push(name.clone());
+                        }
+                    }
+
+                    (self.primary_tags(name)?, self.auto_primary_tags(name)?)
+                }
+                None => {
+                    changes.created_metrics.push(name.clone());
+                    (Vec::new(), Vec::new())
+                }
+            };
+
+            schema::diff_items(name, &metric_schema.primary_tags, &primary_tags, prune, &mut changes.added_primary_tags, &mut changes.removed_primary_tags);
+            schema::diff_items(name, &metric_schema.auto_primary_tags, &auto_primary_tags, prune, &mut changes.added_auto_primary_tags, &mut changes.removed_auto_primary_tags);
+        }
+
+        if prune {
+            changes.removed_metrics = self.metric_names().into_iter().filter(|name| !schema.metrics.contains_key(name)).collect();
+            changes.removed_metrics.sort();
+        }
+
+        let dashboards = self.dashboards.read().unwrap();
+        for (name, dashboard) in &schema.dashboards {
+            let existing = dashboards.get(name).map(|existing| serde_json::to_value(existing).ok());
+            if existing != Some(serde_json::to_value(dashboard).ok()) {
+                changes.updated_dashboards.push(name.clone());
+            }
+        }
+
+        if prune {
+            changes.removed_dashboards = dashboards.names().into_iter().filter(|name| !schema.dashboards.contains_key(name)).collect();
+        }
+
+        Ok(changes)
+    }
+
+    /// Applies the changes given by [`MetricsEngine::diff_schema`]. The configs of existing metrics are not changed, and
+    /// removed metrics are moved to the trash.
+    pub fn apply_schema(&self, schema: &Schema, prune: bool) -> MetricsEngineResult<SchemaChanges> {
+        if self.read_only {
+            return Err(MetricsEngineError::ReadOnly);
+        }
+
+        let changes = self.diff_schema(schema, prune)?;
+        for name in &changes.created_metrics {
+            let metric_schema = &schema.metrics[name];
+            let config = metric_schema.config.clone().unwrap_or_else(|| self.default_config(name, &metric_schema.metric_type));
+            self.add_metric_with_config(name, metric_schema.metric_type.clone(), config)?;
+        }
+
+        for name in &changes.removed_metrics {
+            self.delete_metric(name)?;
+        }
+
+        for (name, tags) in &changes.added_primary_tags {
+            for tag in tags {
+                self.add_primary_tag(name, PrimaryTag::Named(tag.clone()))?;
+            }
+        }
+
+        for (name, tags) in &changes.removed_primary_tags {
+            for tag in tags {
+                self.remove_primary_tag(name, tag)?;
+            }
+        }
+
+        for (name, keys) in &changes.added_auto_primary_tags {
+            for key in keys {
+                self.add_auto_primary_tag(name, key)?;
+            }
+        }
+
+        for (name, keys) in &changes.removed_auto_primary_tags {
+            for key in keys {
+                self.remove_auto_primary_tag(name, key)?;
+            }
+        }
+
+        for name in &changes.updated_dashboards {
+            self.put_dashboard(name, schema.dashboards[name].clone())?;
+        }
+
+        for name in &changes.removed_dashboards {
+            self.remove_dashboard(name)?;
+        }
+
+        Ok(changes)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(start = query.time_range.start, end = query.time_range.end, request_id = query.request_id.as_deref()))]
+    pub fn query(&self, query: MetricQuery) -> MetricsEngineResult<OperationResult> {
+        self.log_slow_query(query, None, |query| querying::query(self, query))
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query), fields(start = query.time_range.start, end = query.time_range.end, request_id = query.request_id.as_deref()))]
+    pub fn query_in_window(&self, query: MetricQuery, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        self.log_slow_query(query, Some(duration), |query| querying::query_in_window(self, query, duration))
+    }
+
+    fn log_slow_query(&self,
+                      query: MetricQuery,
+                      duration: Option<Duration>,
+                      apply: impl FnOnce(MetricQuery) -> MetricsEngineResult<OperationResult>) -> MetricsEngineResult<OperationResult> {
+        if !self.slow_queries.is_enabled() {
+            return apply(query);
+        }
+
+        let request_id = query.request_id.clone();
+        let query_json = json!({
+            "time_range": query.time_range,
+            "duration": duration.map(|duration| duration.as_secs_f64()),
+            "expression": query.expression,
+            "output_filter": query.output_filter
+        });
+
+        let query_start = Instant::now();
+        let blocks_scanned_start = blocks_scanned();
+        let result = apply(query);
+
+        let elapsed = query_start.elapsed();
+        if self.slow_queries.is_slow(elapsed) {
+            let num_blocks_scanned = blocks_scanned() - blocks_scanned_start;
+            tracing::warn!(request_id, elapsed = elapsed.as_secs_f64(), blocks_scanned = num_blocks_scanned, "slow query");
+            if let Err(err) = self.slow_queries.add(SlowQuery::new(request_id, query_json, elapsed, num_blocks_scanned)) {
+                tracing::warn!(error = %err, "failed to log slow query");
+            }
+        }
+
+        result
+    }
+
+    /// The logged slow queries, slowest first.
+    pub fn slow_queries(&self, limit: Option<usize>) -> Vec<SlowQuery> {
+        self.slow_queries.entries(limit)
+    }
+
+    /// Records an administrative action in the audit log.
+    pub fn audit(&self, entry: AuditEntry) {
+        if self.read_only {
+            return;
+        }
+
+        if let Err(err) = self.audit_log.add(entry) {
+            tracing::warn!(error = %err, "failed to add audit entry");
+        }
+    }
+
+    /// The audit entries matching the query, latest first.
+    pub fn audit_entries(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        self.audit_log.entries(query)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn average(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.run_query(metric, query, None, |_| None, |metric, query| {
+            Ok(
+                match metric {
+                    Metric::Gauge(metric) => metric.average(query),
+                    Metric::Count(metric) => metric.average(query),
+                    Metric::Ratio(metric) => metric.average(query)
+                }
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn sum(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.run_query(metric, query, None, sum_groups, |metric, query| {
+            Ok(
+                match metric {
+                    Metric::Gauge(metric) => metric.sum(query),
+                    Metric::Count(metric) => metric.sum(query),
+                    Metric::Ratio(metric) => metric.sum(query)
+                }
+            )
+        })
+    }
+
+    /// The number of datapoints of a ratio metric, such as to know the volume behind a ratio.
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn datapoints(&self, name: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.run_query(name, query, None, |_| Some(|x, y| x + y), |metric, query| {
+            match metric {
+                Metric::Ratio(metric) => Ok(metric.count(query)),
+                _ => Err(MetricsEngineError::WrongMetricType(name.to_owned()))
+            }
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn max(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.run_query(metric, query, None, |_| Some(f64::max), |metric, query| {
+            Ok(
+                match metric {
+                    Metric::Gauge(metric) => metric.max(query),
+                    Metric::Count(metric) => metric.max(query),
+                    Metric::Ratio(metric) => metric.max(query)
+                }
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn min(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.run_query(metric, query, None, |_| Some(f64::min), |metric, query| {
+            Ok(
+                match metric {
+                    Metric::Gauge(metric) => metric.min(query),
+                    Metric::Count(metric) => metric.min(query),
+                    Metric::Ratio(metric) => metric.min(query)
+                }
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn percentile(&self, metric: &str, query: Query, percentile: i32) -> MetricsEngineResult<OperationResult> {
+        querying::validate_percentile(percentile)?;
+
+        self.run_query(metric, query, None, |_| None, |metric, query| {
+            Ok(
+                match metric {
+                    Metric::Gauge(metric) => metric.percentile(query, percentile),
+                    Metric::Count(metric) => metric.percentile(query, percentile),
+                    Metric::Ratio(metric) => metric.percentile(query, percentile)
+                }
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn last(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.run_query(metric, query, None, |_| None, |metric, query| {
+            Ok(
+                match metric {
+                    Metric::Gauge(metric) => metric.last(query),
+                    Metric::Count(metric) => metric.last(query),
+                    Metric::Ratio(metric) => metric.last(query)
+                }
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn average_in_window(&self, name: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        querying::validate_duration(duration)?;
+
+        self.run_query(name, query, Some(duration), |_| None, |metric, query| {
+            Ok(
+                self.cached_in_window(name, metric, "average", query, duration, |query| {
+                    match metric {
+                        Metric::Gauge(metric) => metric.average_in_window(query, duration),
+                        Metric::Count(metric) => metric.average_in_window(query, duration),
+                        Metric::Ratio(metric) => metric.average_in_window(query, duration)
+                    }
+                })
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn sum_in_window(&self, name: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        querying::validate_duration(duration)?;
+
+        self.run_query(name, query, Some(duration), sum_groups, |metric, query| {
+            Ok(
+                self.cached_in_window(name, metric, "sum", query, duration, |query| {
+                    match metric {
+                        Metric::Gauge(metric) => metric.sum_in_window(query, duration),
+                        Metric::Count(metric) => metric.sum_in_window(query, duration),
+                        Metric::Ratio(metric) => metric.sum_in_window(query, duration)
+                    }
+                })
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn datapoints_in_window(&self, name: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        querying::validate_duration(duration)?;
+
+        self.run_query(name, query, Some(duration), |_| Some(|x, y| x + y), |metric, query| {
+            let ratio_metric = match metric {
+                Metric::Ratio(metric) => metric,
+                _ => { return Err(MetricsEngineError::WrongMetricType(name.to_owned())); }
+            };
+
+            Ok(
+                self.cached_in_window(name, metric, "datapoints", query, duration, |query| {
+                    ratio_metric.count_in_window(query, duration)
+                })
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn max_in_window(&self, name: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        querying::validate_duration(duration)?;
+
+        self.run_query(name, query, Some(duration), |_| Some(f64::max), |metric, query| {
+            Ok(
+                self.cached_in_window(name, metric, "max", query, duration, |query| {
+                    match metric {
+                        Metric::Gauge(metric) => metric.max_in_window(query, duration),
+                        Metric::Count(metric) => metric.max_in_window(query, duration),
+                        Metric::Ratio(metric) => metric.max_in_window(query, duration)
+                    }
+                })
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn min_in_window(&self, name: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        querying::validate_duration(duration)?;
+
+        self.run_query(name, query, Some(duration), |_| Some(f64::min), |metric, query| {
+            Ok(
+                self.cached_in_window(name, metric, "min", query, duration, |query| {
+                    match metric {
+                        Metric::Gauge(metric) => metric.min_in_window(query, duration),
+                        Metric::Count(metric) => metric.min_in_window(query, duration),
+                        Metric::Ratio(metric) => metric.min_in_window(query, duration)
+                    }
+                })
+            )
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, query))]
+    pub fn percentile_in_window(&self, name: &str, query: Query, duration: Duration, percentile: i32) -> MetricsEngineResult<OperationResult> {
+        querying::validate_duration(duration)?;
+        querying::validate_percentile(percentile)?;
+
+        self.run_query(name, query, Some(duration), |_| None, |metric, query| {
+            Ok(
+                self.cached_in_window(name, metric, &format!("percentile_{}", percentile), query, duration, |query| {
+                    match metric {
+                        Metric::Gauge(metric) => metric.percentile_in_window(query, duration, percentile),
+                        Metric::Count(metric) => metric.percentile_in_window(query, duration, percentile),
+                        Metric::Ratio(metric) => metric.percentile_in_window(query, duration, percentile)
+                    }
+                })
+            )
+        })
+    }
+
+    /// Applies the query limits and the read consistency of the query, and validates the query before running it on the metric.
+    /// A duration is given for queries in windows, and the groups removed by a group limit are combined by `combine_other`.
+    fn run_query(&self,
+                 name: &str,
+                 query: Query,
+                 duration: Option<Duration>,
+                 combine_other: impl FnOnce(&Metric) -> Option<CombineGroups>,
+                 apply: impl FnOnce(&Metric, Query) -> MetricsEngineResult<OperationResult>) -> MetricsEngineResult<OperationResult> {
+        let _permit = self.active_queries.acquire(&self.query_limits)?;
+        self.sync_for_query(name, &query)?;
+        let metric_lock = self.get_metric(name)?;
+        let metric = self.lock_watchdog.read(name, &metric_lock);
+        query.validate_for(&metric.metric_type())?;
+        let query = match sealed_query(&metric, query, duration.is_some()) {
+            ControlFlow::Continue(query) => query,
+            ControlFlow::Break(result) => { return Ok(result); }
+        };
+        self.check_query_size(&metric, &query, duration)?;
+
+        let group_limit = query.group_limit.clone();
+        let combine_other = combine_other(&metric);
+        let result = apply(&metric, query)?;
+        Ok(apply_group_limit(result, group_limit, combine_other))
+    }
+
+    /// Takes the leading windows that only cover sealed blocks from the window cache, and computes the remaining windows.
+    fn cached_in_window(&self,
+                        name: &str,
+                        metric: &Metric,
+                        operation: &str,
+                        query: Query,
+                        duration: Duration,
+                        apply: impl Fn(Query) -> OperationResult) -> OperationResult {
+        // Removing the empty windows would make the windows of the result unknown
+        if !self.window_cache.is_enabled() || query.remove_empty_datapoints || query.depends_on_previous_windows() {
+            return apply(query);
+        }
+
+        let sealed_time = match metric.sealed_time() {
+            Some(sealed_time) => sealed_time,
+            None => { return apply(query); }
+        };
+
+        let (start_time, end_time) = query.time_range.int_range();
+        let window_duration = (duration.as_secs_f64() * TIME_SCALE as f64) as Time;
+        if end_time <= start_time || window_duration == 0 {
+            return apply(query);
+        }
+
+        let num_windows = ((end_time - start_time) / window_duration) as usize;
+        let num_sealed_windows = (sealed_time.saturating_sub(start_time) / window_duration).min(num_windows as Time) as usize;
+
+        let mut key_query = query.clone();
+        key_query.time_range = TimeRange::new(0.0, 1.0);
+        key_query.group_limit = None;
+        let key = WindowCacheKey {
+            metric: name.to_owned(),
+            operation: operation.to_owned(),
+            query: serde_json::to_string(&key_query).unwrap_or_default(),
+            duration: window_duration,
+            num_primary_tags: metric.num_primary_tags_with_data()
+        };
+
+        let grouped = query.group_by.is_some();
+        let mut windows = self.window_cache.get(&key, start_time, num_sealed_windows);
+        let num_cached = windows.len();
+
+        let remaining_start_time = start_time + num_cached as Time * window_duration;
+        let remaining = if num_cached < num_windows {
+            let mut remaining_query = query.clone();
+            remaining_query.time_range = TimeRange::new(remaining_start_time as f64 / TIME_SCALE as f64, query.time_range.end);
+            let result = apply(remaining_query);
+
+            match window_cache::split_windows(&result, remaining_start_time, window_duration, num_windows - num_cached) {
+                Some(remaining) => remaining,
+                None if num_cached == 0 => { return result; }
+                None => { return apply(query); }
+            }
+        } else {
+            Vec::new()
+        };
+
+        if num_cached < num_sealed_windows {
+            let new_windows = remaining.iter()
+                .take(num_sealed_windows - num_cached)
+                .enumerate()
+                .map(|(index, window)| (remaining_start_time + index as Time * window_duration, window.clone()))
+                .collect::<Vec<_>>();
+            self.window_cache.insert(key, new_windows);
+        }
+
+        windows.extend(remaining);
+        window_cache::join_windows(windows, grouped)
+    }
+
+    /// The number of windows in the window cache.
+    pub fn num_cached_windows(&self) -> usize {
+        self.window_cache.num_windows()
+    }
+
+    pub fn group_values(&self, metric: &str, query: &Query, key: &GroupKey) -> MetricsEngineResult<Vec<Vec<Tag>>> {
+        let metric_lock = self.get_metric(metric)?;
+        let metric = self.lock_watchdog.read(metric, &metric_lock);
+        Ok(metric.group_values(query, key))
+    }
+
+    /// The written values are visible to queries as soon as the write returns, except for the values buffered by the pre-aggregation.
+    fn sync_for_query(&self, name: &str, query: &Query) -> MetricsEngineResult<()> {
+        if query.consistency != ReadConsistency::Synced || self.read_only {
+            return Ok(());
+        }
+
+        let _write_guard = self.write_pause.enter();
+        match self.pre_aggregation.take_metric(name) {
+            Some(values) => self.write_buffered_values(name, values),
+            None => Ok(())
+        }
+    }
+
+    fn check_query_size(&self, metric: &Metric, query: &Query, duration: Option<Duration>) -> MetricsEngineResult<()> {
+        if !self.query_limits.has_size_limits() {
+            return Ok(());
+        }
+
+        let num_groups = match &query.group_by {
+            Some(key) => metric.num_groups(query, key),
+            None => 1
         };
 
-        let metrics = DashMap::default();
-        for (metric_name, metric_type) in load().map_err(|err| MetricsEngineError::FailedToLoadMetricDefinitions(err))? {
-            let metric = match metric_type {
-                MetricType::Gauge => Metric::Gauge(DefaultGaugeMetric::from_existing(&base_path.join(&metric_name))?),
-                MetricType::Count => Metric::Count(DefaultCountMetric::from_existing(&base_path.join(&metric_name))?),
-                MetricType::Ratio => Metric::Ratio(DefaultRatioMetric::from_existing(&base_path.join(&metric_name))?)
-            };
+        self.query_limits.check_num_groups(num_groups)?;
+        self.query_limits.check_num_values(&query.time_range, duration, num_groups)
+    }
 
-            metrics.insert(metric_name, Arc::new(RwLock::new(metric)));
+    pub fn check_disk_space(&self) {
+        if !self.read_only {
+            self.disk_watchdog.check(&self.base_path);
         }
+    }
 
-        Ok(
-            MetricsEngine {
-                base_path: base_path.to_owned(),
-                metrics,
-                create_lock: Mutex::new(())
-            }
-        )
+    pub fn disk_space(&self) -> Option<DiskSpace> {
+        self.disk_watchdog.disk_space()
     }
 
-    pub fn new_or_from_existing(base_path: &Path) -> MetricsEngineResult<MetricsEngine> {
-        if base_path.join("metrics.json").exists() {
-            MetricsEngine::from_existing(base_path)
-        } else {
-            MetricsEngine::new(base_path)
-        }
+    /// Logs the metric locks that have been held longer than the lock watchdog threshold.
+    pub fn check_locks(&self) {
+        self.lock_watchdog.check();
     }
 
-    pub fn add_metric(&self, name: &str, metric_type: MetricType) -> MetricsEngineResult<()> {
-        self.add_metric_with_config(name, metric_type.clone(), MetricConfig::new(metric_type))
+    pub fn lock_stats(&self) -> LockStats {
+        self.lock_watchdog.stats()
     }
 
-    pub fn add_metric_with_config(&self,
-                                  name: &str,
-                                  metric_type: MetricType,
-                                  config: MetricConfig) -> MetricsEngineResult<()> {
-        let _guard = self.create_lock.lock().unwrap();
-        if self.metrics.contains_key(name) {
-            return Err(MetricsEngineError::MetricAlreadyExists);
+    /// Verifies the sealed blocks of the metric, which is loaded if it is not already.
+    pub fn verify_metric(&self, metric: &str) -> MetricsEngineResult<Vec<String>> {
+        let problems = self.get_metric(metric)?.read().unwrap().verify();
+        self.verification.lock().unwrap().update(metric, &problems);
+        Ok(problems)
+    }
+
+    pub fn verification_completed(&self) {
+        self.verification.lock().unwrap().complete_pass();
+    }
+
+    pub fn verification_status(&self) -> VerificationStatus {
+        self.verification.lock().unwrap().clone()
+    }
+
+    /// Copies the storage to the given path, which can be opened as a regular storage.
+    /// Writes to a metric are blocked while that metric is being flushed and copied.
+    pub fn snapshot(&self, path: &Path) -> MetricsEngineResult<()> {
+        let failed = |err: std::io::Error| MetricsEngineError::FailedToCreateSnapshot(path.to_owned(), err);
+
+        // No metrics can be created while the snapshot is taken, which keeps the definitions consistent
+        let _create_guard = self.create_lock.lock().unwrap();
+        std::fs::create_dir_all(path).map_err(failed)?;
+        if !self.read_only {
+            self.write_buffered()?;
+        }
+
+        for metric_name in self.metric_names() {
+            let source = self.metric_path(&metric_name);
+            let destination = path.join(source.strip_prefix(&self.base_path).unwrap_or(Path::new(&metric_name)));
+
+            match self.metrics.get(&metric_name).map(|item| item.value().clone()) {
+                Some(metric) => {
+                    // Writers only take the metric lock in read mode, so the write lock is required to exclude them
+                    let metric_guard = self.lock_watchdog.write(&metric_name, &metric);
+                    if !self.read_only {
+                        metric_guard.flush()?;
+                    }
+
+                    snapshots::copy_directory(&source, &destination, &["lock"]).map_err(failed)?;
+                }
+                None => {
+                    self.with_load_lock(&metric_name, || snapshots::copy_directory(&source, &destination, &["lock"])).map_err(failed)?;
+                }
+            }
         }
 
-        self.metrics.insert(
-            name.to_string(),
-            match metric_type {
-                MetricType::Gauge => Metric::gauge(DefaultGaugeMetric::with_config(&self.base_path.join(name), config)?),
-                MetricType::Count => Metric::count(DefaultCountMetric::with_config(&self.base_path.join(name), config)?),
-                MetricType::Ratio => Metric::ratio(DefaultRatioMetric::with_config(&self.base_path.join(name), config)?)
+        let _annotations_guard = self.annotations.read().unwrap();
+        let _dashboards_guard = self.dashboards.read().unwrap();
+        for entry in std::fs::read_dir(&self.base_path).map_err(failed)? {
+            let entry = entry.map_err(failed)?;
+            if entry.file_type().map_err(failed)?.is_file() && entry.file_name() != "lock" {
+                std::fs::copy(entry.path(), path.join(entry.file_name())).map_err(failed)?;
             }
-        );
+        }
 
-        self.save_defined_metrics()?;
         Ok(())
     }
 
-    fn save_defined_metrics(&self) -> MetricsEngineResult<()> {
-        let save = || -> std::io::Result<()> {
-            let content = serde_json::to_string(
-                &self.metrics
-                    .iter()
-                    .map(|item| (item.key().to_owned(), item.value().read().unwrap().metric_type()))
-                    .collect::<Vec<_>>()
-            )?;
-            std::fs::write(&self.base_path.join("metrics.json"), &content)?;
-            Ok(())
-        };
+    /// Flushes the loaded metrics to disk and pauses writes until `post_snapshot` is called (or `max_pause` has passed),
+    /// such that a filesystem level snapshot of the storage is consistent.
+    pub fn pre_snapshot(&self, max_pause: Duration) -> MetricsEngineResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        self.write_pause.pause(max_pause);
+        if let Err(err) = self.write_buffered() {
+            self.write_pause.resume();
+            return Err(err);
+        }
+
+        let metrics = self.metrics.iter().map(|item| item.value().clone()).collect::<Vec<_>>();
+        for metric in metrics {
+            if let Err(err) = metric.read().unwrap().flush() {
+                self.write_pause.resume();
+                return Err(err.into());
+            }
+        }
 
-        save().map_err(|err| MetricsEngineError::FailedToSaveMetricDefinitions(err))?;
         Ok(())
     }
 
-    pub fn add_auto_primary_tag(&self, metric: &str, key: &str) -> MetricsEngineResult<()> {
-        match self.metrics.get_metric(metric)?.write().unwrap().deref_mut() {
-            Metric::Gauge(metric) => metric.add_auto_primary_tag(key)?,
-            Metric::Count(metric) => metric.add_auto_primary_tag(key)?,
-            Metric::Ratio(metric) => metric.add_auto_primary_tag(key)?,
-        }
+    /// Writes the buffered values and synchronously flushes the storage of the metric to disk, including its active blocks.
+    /// The metric is loaded if it is not already.
+    pub fn flush_metric(&self, metric: &str) -> MetricsEngineResult<()> {
+        let _write_guard = self.check_writable()?;
+        self.write_buffered()?;
 
+        let metric_lock = self.get_metric(metric)?;
+        let metric = self.lock_watchdog.read(metric, &metric_lock);
+        metric.scheduled();
+        metric.flush()?;
         Ok(())
     }
 
-    pub fn add_primary_tag(&self, metric: &str, tag: PrimaryTag) -> MetricsEngineResult<()> {
-        match self.metrics.get_metric(metric)?.write().unwrap().deref_mut() {
-            Metric::Gauge(metric) => metric.add_primary_tag(tag)?,
-            Metric::Count(metric) => metric.add_primary_tag(tag)?,
-            Metric::Ratio(metric) => metric.add_primary_tag(tag)?,
+    /// Flushes the loaded metrics to disk, should be called before the process exits.
+    pub fn shutdown(&self) -> MetricsEngineResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let _write_guard = self.write_pause.enter();
+        self.write_buffered()?;
+
+        let metrics = self.metrics.iter().map(|item| (item.key().to_owned(), item.value().clone())).collect::<Vec<_>>();
+        for (name, metric) in metrics {
+            self.lock_watchdog.read(&name, &metric).flush()?;
         }
 
         Ok(())
     }
 
-    pub fn gauge(&self, metric: &str, values: impl Iterator<Item=AddGaugeValue>) -> MetricsEngineResult<usize> {
-        match self.metrics.get_metric(metric)?.write().unwrap().deref_mut() {
-            Metric::Gauge(metric) => {
-                let mut num_success = 0;
-                let mut error = None;
+    /// Resumes writes after `pre_snapshot`, returns false if the pause had already expired.
+    pub fn post_snapshot(&self) -> bool {
+        self.write_pause.resume()
+    }
 
-                for value in values {
-                    match metric.add(value.time, value.value, value.tags) {
-                        Ok(_) => { num_success += 1; }
-                        Err(err) => { error = Some(err); }
-                    }
-                }
+    /// Moves the old sealed segments of the metric to the cold directory, if configured. The metric is loaded if it is not already.
+    pub fn move_to_cold_tier(&self, metric: &str) -> MetricsEngineResult<usize> {
+        let cold_directory = match self.tiering.cold_directory.as_ref() {
+            Some(cold_directory) if !self.read_only => Path::new(cold_directory),
+            _ => return Ok(0)
+        };
 
-                if num_success == 0 {
-                    if let Some(err) = error {
-                        return Err(err.into());
-                    }
-                }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let older_than = ((now - self.tiering.move_after).max(0.0) * TIME_SCALE as f64) as Time;
+
+        // Moving segments while a filesystem snapshot is taken would make the snapshot inconsistent
+        let _write_guard = self.write_pause.enter();
+        let metric = self.get_metric(metric)?;
+        let num_moved = metric.read().unwrap().move_to_cold_tier(&self.base_path, cold_directory, older_than)?;
+        Ok(num_moved)
+    }
 
-                Ok(num_success)
+    /// Runs the scheduled maintenance of the metric (or all loaded metrics) immediately, and removes the segments that are older
+    /// than the retention even if no new blocks have been created. Returns the number of removed segments.
+    pub fn compact(&self, metric: Option<&str>) -> MetricsEngineResult<usize> {
+        let _write_guard = self.check_writable()?;
+
+        let now = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64() * TIME_SCALE as f64) as Time;
+        let metrics = match metric {
+            Some(metric) => vec![(metric.to_owned(), self.get_metric(metric)?)],
+            None => self.metrics.iter().map(|item| (item.key().to_owned(), item.value().clone())).collect()
+        };
+
+        let mut num_removed = 0;
+        for (name, metric) in metrics {
+            let metric = self.lock_watchdog.read(&name, &metric);
+            let num_metric_removed = metric.remove_expired_segments(now)?;
+            if num_metric_removed > 0 {
+                self.window_cache.invalidate(&name);
+                self.tenants.changed(&name);
             }
-            _ => Err(MetricsEngineError::WrongMetricType)
+
+            num_removed += num_metric_removed;
+            metric.scheduled();
+            self.update_rollups(&name, &metric);
+            self.quota_tracker.update_bytes(&self.write_quotas, &name, &self.metric_path(&name));
+            self.tenants.update_bytes(&name, &self.metric_path(&name));
         }
+
+        Ok(num_removed)
     }
 
-    pub fn count(&self, metric: &str, values: impl Iterator<Item=AddCountValue>) -> MetricsEngineResult<usize> {
-        match self.metrics.get_metric(metric)?.write().unwrap().deref_mut() {
-            Metric::Count(metric) => {
-                let mut num_success = 0;
-                let mut error = None;
+    pub fn quota_usage(&self) -> Vec<QuotaUsage> {
+        self.quota_tracker.usage(&self.write_quotas)
+    }
 
-                for value in values {
-                    match metric.add(value.time, value.count, value.tags) {
-                        Ok(_) => { num_success += 1; }
-                        Err(err) => { error = Some(err); }
-                    }
-                }
+    pub fn tenant_usage(&self) -> Vec<TenantUsage> {
+        self.tenants.usage()
+    }
 
-                if num_success == 0 {
-                    if let Some(err) = error {
-                        return Err(err.into());
-                    }
-                }
+    pub fn metric_names(&self) -> Vec<String> {
+        self.definitions.iter().map(|item| item.key().to_owned()).collect()
+    }
+
+    pub fn metric_type(&self, name: &str) -> MetricsEngineResult<MetricType> {
+        self.definitions.get(name).map(|item| item.value().clone()).ok_or_else(|| MetricsEngineError::MetricNotFound(name.to_owned()))
+    }
+
+    pub fn scheduled(&self) {
+        if self.read_only {
+            return;
+        }
+
+        let _write_guard = self.write_pause.enter();
+
+        // Collected first, as updating the rollups can load metrics
+        let metrics = self.metrics.iter().map(|item| (item.key().to_owned(), item.value().clone())).collect::<Vec<_>>();
+        for (name, metric) in metrics {
+            let metric = self.lock_watchdog.read(&name, &metric);
+            metric.scheduled();
+            self.update_rollups(&name, &metric);
+            self.quota_tracker.update_bytes(&self.write_quotas, &name, &self.metric_path(&name));
+            self.tenants.update_bytes(&name, &self.metric_path(&name));
+        }
+    }
+
+    pub fn scheduled_metric(&self, metric: &str) -> MetricsEngineResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let _write_guard = self.write_pause.enter();
+
+        // Metrics that have not been loaded yet have nothing to maintain
+        if let Some(loaded_metric) = self.metrics.get(metric).map(|item| item.value().clone()) {
+            let loaded_metric = self.lock_watchdog.read(metric, &loaded_metric);
+            loaded_metric.scheduled();
+            self.update_rollups(metric, &loaded_metric);
+        }
+
+        self.quota_tracker.update_bytes(&self.write_quotas, metric, &self.metric_path(metric));
+        self.tenants.update_bytes(metric, &self.metric_path(metric));
+
+        Ok(())
+    }
+}
+
+impl MetricsEngine {
+    /// Writes the windows of the rollups of the metric that only cover sealed blocks, continuing after the latest written window.
+    fn update_rollups(&self, name: &str, metric: &Metric) {
+        let (start_time, sealed_time) = match (metric.start_time(), metric.sealed_time()) {
+            (Some(start_time), Some(sealed_time)) => (start_time, sealed_time),
+            _ => { return; }
+        };
 
-                Ok(num_success)
+        for rollup in &metric.config().rollups {
+            if let Err(err) = self.update_rollup(metric, rollup, start_time, sealed_time) {
+                tracing::warn!(metric = name, rollup = rollup.metric, error = %err, "failed to update rollup");
             }
-            _ => Err(MetricsEngineError::WrongMetricType)
         }
     }
 
-    pub fn ratio(&self, metric: &str, values: impl Iterator<Item=AddRatioValue>) -> MetricsEngineResult<usize> {
-        match self.metrics.get_metric(metric)?.write().unwrap().deref_mut() {
-            Metric::Ratio(metric) => {
-                let mut num_success = 0;
-                let mut error = None;
+    fn update_rollup(&self, metric: &Metric, rollup: &RollupConfig, start_time: Time, sealed_time: Time) -> MetricsEngineResult<usize> {
+        let rollup_metric = match self.get_metric(&rollup.metric) {
+            Ok(rollup_metric) => rollup_metric,
+            Err(MetricsEngineError::MetricNotFound(_)) => {
+                self.create_metric(&rollup.metric, MetricType::Gauge, self.default_config(&rollup.metric, &MetricType::Gauge))?;
+                self.get_metric(&rollup.metric)?
+            }
+            Err(err) => { return Err(err); }
+        };
+
+        let rollup_metric = rollup_metric.read().unwrap();
+        let rollup_metric = match rollup_metric.deref() {
+            Metric::Gauge(rollup_metric) => rollup_metric,
+            _ => { return Err(MetricsEngineError::WrongMetricType(rollup.metric.clone())); }
+        };
 
-                for value in values {
-                    match metric.add(value.time, value.ratio, value.tags) {
-                        Ok(_) => { num_success += 1; }
-                        Err(err) => { error = Some(err); }
-                    }
-                }
+        // The windows are aligned to the duration, as the time of a window is only stored in whole seconds
+        let duration = (rollup.duration * TIME_SCALE as f64) as Time;
+        let windows_start_time = match rollup_metric.latest_time() {
+            Some(latest_time) => ((latest_time * TIME_SCALE as f64) as Time / duration + 1) * duration,
+            None => start_time / duration * duration
+        };
+        let windows_end_time = sealed_time / duration * duration;
+        if windows_end_time <= windows_start_time {
+            return Ok(0);
+        }
 
-                if num_success == 0 {
-                    if let Some(err) = error {
-                        return Err(err.into());
-                    }
-                }
+        let mut query = Query::new(TimeRange::new(windows_start_time as f64 / TIME_SCALE as f64, windows_end_time as f64 / TIME_SCALE as f64));
+        query.group_by = rollup.group_by.clone();
+        let result = metric.rollup_in_window(rollup.operation, query, Duration::from_secs_f64(rollup.duration));
 
-                Ok(num_success)
+        let mut values = Vec::new();
+        match result {
+            OperationResult::TimeValues(time_values) => {
+                values.extend(time_values.into_iter().flat_map(|(time, value)| Some((time, value?, Vec::new()))));
             }
-            _ => Err(MetricsEngineError::WrongMetricType)
+            OperationResult::GroupTimeValues(groups) => {
+                let keys = rollup.group_by.as_ref().map(|key| key.0.clone()).unwrap_or_default();
+                for (group, time_values) in groups {
+                    let tags = keys.iter().zip(group.0.iter()).map(|(key, value)| Tag::from_ref(key, value)).collect::<Vec<_>>();
+                    values.extend(time_values.into_iter().flat_map(|(time, value)| Some((time, value?, tags.clone()))));
+                }
+            }
+            _ => {}
         }
+
+        values.sort_by(|x, y| x.0.total_cmp(&y.0));
+        Ok(rollup_metric.add_batch(&values)?)
     }
+}
 
-    pub fn query(&self, query: MetricQuery) -> MetricsEngineResult<OperationResult> {
-        querying::query(self, query)
+pub struct MetricsEngineBuilder {
+    base_path: PathBuf,
+    default_configs: FnvHashMap<MetricType, MetricConfig>,
+    scheduler_config: SchedulerConfig,
+    max_loaded_metrics: Option<usize>,
+    read_only: bool,
+    query_limits: QueryLimits,
+    write_quotas: WriteQuotas,
+    disk_watchdog: DiskWatchdogConfig,
+    tiering: TieringConfig,
+    templates: Vec<MetricTemplate>,
+    unknown_metrics: UnknownMetricMode,
+    clock_skew: ClockSkewTolerance,
+    pre_aggregation: PreAggregationConfig,
+    sampling_rules: Vec<SamplingRule>,
+    slow_queries: SlowQueryConfig,
+    window_cache: WindowCacheConfig,
+    lock_watchdog: LockWatchdogConfig,
+    trash: TrashConfig,
+    tenants: TenantsConfig
+}
+
+impl MetricsEngineBuilder {
+    pub fn new(base_path: &Path) -> MetricsEngineBuilder {
+        MetricsEngineBuilder {
+            base_path: base_path.to_owned(),
+            default_configs: FnvHashMap::default(),
+            scheduler_config: SchedulerConfig::default(),
+            max_loaded_metrics: None,
+            read_only: false,
+            query_limits: QueryLimits::default(),
+            write_quotas: WriteQuotas::default(),
+            disk_watchdog: DiskWatchdogConfig::default(),
+            tiering: TieringConfig::default(),
+            templates: Vec::new(),
+            unknown_metrics: UnknownMetricMode::default(),
+            clock_skew: ClockSkewTolerance::default(),
+            pre_aggregation: PreAggregationConfig::default(),
+            sampling_rules: Vec::new(),
+            slow_queries: SlowQueryConfig::default(),
+            window_cache: WindowCacheConfig::default(),
+            lock_watchdog: LockWatchdogConfig::default(),
+            trash: TrashConfig::default(),
+            tenants: TenantsConfig::default()
+        }
     }
 
-    pub fn query_in_window(&self, query: MetricQuery, duration: Duration) -> MetricsEngineResult<OperationResult> {
-        querying::query_in_window(self, query, duration)
+    pub fn with_default_config(mut self, metric_type: MetricType, config: MetricConfig) -> MetricsEngineBuilder {
+        self.default_configs.insert(metric_type, config);
+        self
     }
 
-    pub fn average(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.average(query)),
-            Metric::Count(metric) => Ok(metric.average(query)),
-            Metric::Ratio(metric) => Ok(metric.average(query))
-        }
+    pub fn with_scheduler(mut self, config: SchedulerConfig) -> MetricsEngineBuilder {
+        self.scheduler_config = config;
+        self
     }
 
-    pub fn sum(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.sum(query)),
-            Metric::Count(metric) => Ok(metric.sum(query)),
-            Metric::Ratio(metric) => Ok(metric.sum(query))
-        }
+    pub fn with_max_loaded_metrics(mut self, max_loaded_metrics: usize) -> MetricsEngineBuilder {
+        self.max_loaded_metrics = Some(max_loaded_metrics);
+        self
     }
 
-    pub fn max(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.max(query)),
-            Metric::Count(metric) => Ok(metric.max(query)),
-            Metric::Ratio(metric) => Ok(metric.max(query)),
-        }
+    pub fn read_only(mut self, read_only: bool) -> MetricsEngineBuilder {
+        self.read_only = read_only;
+        self
     }
 
-    pub fn min(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.min(query)),
-            Metric::Count(metric) => Ok(metric.min(query)),
-            Metric::Ratio(metric) => Ok(metric.min(query)),
-        }
+    pub fn with_query_limits(mut self, query_limits: QueryLimits) -> MetricsEngineBuilder {
+        self.query_limits = query_limits;
+        self
     }
 
-    pub fn percentile(&self, metric: &str, query: Query, percentile: i32) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.percentile(query, percentile)),
-            Metric::Count(metric) => Ok(metric.percentile(query, percentile)),
-            Metric::Ratio(metric) => Ok(metric.percentile(query, percentile)),
-        }
+    pub fn with_write_quotas(mut self, write_quotas: WriteQuotas) -> MetricsEngineBuilder {
+        self.write_quotas = write_quotas;
+        self
     }
 
-    pub fn average_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.average_in_window(query, duration)),
-            Metric::Count(metric) => Ok(metric.average_in_window(query, duration)),
-            Metric::Ratio(metric) => Ok(metric.average_in_window(query, duration))
-        }
+    pub fn with_disk_watchdog(mut self, config: DiskWatchdogConfig) -> MetricsEngineBuilder {
+        self.disk_watchdog = config;
+        self
     }
 
-    pub fn sum_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.sum_in_window(query, duration)),
-            Metric::Count(metric) => Ok(metric.sum_in_window(query, duration)),
-            Metric::Ratio(metric) => Ok(metric.sum_in_window(query, duration))
-        }
+    pub fn with_tiering(mut self, config: TieringConfig) -> MetricsEngineBuilder {
+        self.tiering = config;
+        self
     }
 
-    pub fn max_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.max_in_window(query, duration)),
-            Metric::Count(metric) => Ok(metric.max_in_window(query, duration)),
-            Metric::Ratio(metric) => Ok(metric.max_in_window(query, duration))
-        }
+    pub fn with_template(mut self, template: MetricTemplate) -> MetricsEngineBuilder {
+        self.templates.push(template);
+        self
+    }
+
+    pub fn with_templates(mut self, templates: Vec<MetricTemplate>) -> MetricsEngineBuilder {
+        self.templates.extend(templates);
+        self
+    }
+
+    /// What happens when writing to a metric that does not exist, unless specified for the write.
+    pub fn with_unknown_metrics(mut self, mode: UnknownMetricMode) -> MetricsEngineBuilder {
+        self.unknown_metrics = mode;
+        self
+    }
+
+    /// Writes to metrics that do not exist always fail, even if a template matches. Same as `UnknownMetricMode::Fail`.
+    pub fn with_strict(mut self, strict: bool) -> MetricsEngineBuilder {
+        self.unknown_metrics = if strict { UnknownMetricMode::Fail } else { UnknownMetricMode::AutoCreate };
+        self
+    }
+
+    pub fn with_clock_skew_tolerance(mut self, tolerance: ClockSkewTolerance) -> MetricsEngineBuilder {
+        self.clock_skew = tolerance;
+        self
+    }
+
+    pub fn with_pre_aggregation(mut self, config: PreAggregationConfig) -> MetricsEngineBuilder {
+        self.pre_aggregation = config;
+        self
+    }
+
+    pub fn with_sampling_rule(mut self, rule: SamplingRule) -> MetricsEngineBuilder {
+        self.sampling_rules.push(rule);
+        self
+    }
+
+    pub fn with_sampling_rules(mut self, rules: Vec<SamplingRule>) -> MetricsEngineBuilder {
+        self.sampling_rules.extend(rules);
+        self
+    }
+
+    pub fn with_slow_query_log(mut self, config: SlowQueryConfig) -> MetricsEngineBuilder {
+        self.slow_queries = config;
+        self
+    }
+
+    pub fn with_window_cache(mut self, config: WindowCacheConfig) -> MetricsEngineBuilder {
+        self.window_cache = config;
+        self
+    }
+
+    pub fn with_lock_watchdog(mut self, config: LockWatchdogConfig) -> MetricsEngineBuilder {
+        self.lock_watchdog = config;
+        self
+    }
+
+    pub fn with_trash(mut self, config: TrashConfig) -> MetricsEngineBuilder {
+        self.trash = config;
+        self
+    }
+
+    pub fn with_tenants(mut self, config: TenantsConfig) -> MetricsEngineBuilder {
+        self.tenants = config;
+        self
     }
 
-    pub fn min_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.min_in_window(query, duration)),
-            Metric::Count(metric) => Ok(metric.min_in_window(query, duration)),
-            Metric::Ratio(metric) => Ok(metric.min_in_window(query, duration))
+    pub fn build(self) -> MetricsEngineResult<MetricsEngine> {
+        self.scheduler_config.validate().map_err(MetricsEngineError::InvalidConfig)?;
+
+        if self.read_only || DefinitionsLog::exists(&self.base_path) {
+            MetricsEngine::from_existing_with_config(self)
+        } else {
+            MetricsEngine::new_with_config(self)
         }
     }
+}
+
+#[derive(Default)]
+struct LoadingProgress {
+    total: AtomicUsize,
+    loaded: AtomicUsize,
+    failed: AtomicUsize
+}
+
+impl LoadingProgress {
+    fn start(&self, total: usize) {
+        self.total.store(total, Ordering::SeqCst);
+        self.loaded.store(0, Ordering::SeqCst);
+        self.failed.store(0, Ordering::SeqCst);
+    }
 
-    pub fn percentile_in_window(&self, metric: &str, query: Query, duration: Duration, percentile: i32) -> MetricsEngineResult<OperationResult> {
-        match self.metrics.get_metric(metric)?.read().unwrap().deref() {
-            Metric::Gauge(metric) => Ok(metric.percentile_in_window(query, duration, percentile)),
-            Metric::Count(metric) => Ok(metric.percentile_in_window(query, duration, percentile)),
-            Metric::Ratio(metric) => Ok(metric.percentile_in_window(query, duration, percentile))
+    fn status(&self) -> LoadingStatus {
+        LoadingStatus {
+            total: self.total.load(Ordering::SeqCst),
+            loaded: self.loaded.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst)
         }
     }
+}
 
-    pub fn scheduled(&self) {
-        for entry in self.metrics.iter() {
-            match entry.value().write().unwrap().deref_mut() {
-                Metric::Gauge(metric) => metric.scheduled(),
-                Metric::Count(metric) => metric.scheduled(),
-                Metric::Ratio(metric) => metric.scheduled()
+/// Restricts the time range of the query to the sealed blocks of the metric if requested.
+/// Breaks with an empty result if none of the time range is sealed.
+fn sealed_query(metric: &Metric, mut query: Query, windowed: bool) -> ControlFlow<OperationResult, Query> {
+    if query.consistency != ReadConsistency::Sealed {
+        return ControlFlow::Continue(query);
+    }
+
+    // A primary tag without datapoints can receive datapoints at any time, so nothing is sealed until all primary tags have datapoints
+    let sealed_time = if metric.num_primary_tags_with_data() == metric.num_primary_tags() {
+        metric.sealed_time()
+    } else {
+        None
+    };
+
+    let (start_time, end_time) = query.time_range.int_range();
+    let sealed_time = sealed_time.unwrap_or(0);
+    if sealed_time <= start_time {
+        return ControlFlow::Break(
+            match (query.group_by.is_some(), windowed) {
+                (false, false) => OperationResult::Value(None),
+                (true, false) => OperationResult::GroupValues(Vec::new()),
+                (false, true) => OperationResult::TimeValues(Vec::new()),
+                (true, true) => OperationResult::GroupTimeValues(Vec::new())
             }
-        }
+        );
+    }
+
+    // The end of the time range is inclusive, while windows do not include their end
+    if sealed_time <= end_time {
+        let end_time = if windowed { sealed_time } else { sealed_time - 1 };
+        query.time_range.end = end_time as f64 / TIME_SCALE as f64;
+    }
+
+    ControlFlow::Continue(query)
+}
+
+/// The values of ratio metrics are ratios of sums, which cannot be summed.
+fn sum_groups(metric: &Metric) -> Option<CombineGroups> {
+    match metric {
+        Metric::Ratio(_) => None,
+        _ => Some(|x, y| x + y)
     }
 }
 
-trait MetricsHashMapExt {
-    fn get_metric(&self, name: &str) -> MetricsEngineResult<ArcMetric>;
+fn apply_group_limit(result: OperationResult, group_limit: Option<GroupLimit>, combine_other: Option<CombineGroups>) -> OperationResult {
+    match group_limit {
+        Some(group_limit) => result.limit_groups(&group_limit, combine_other),
+        None => result
+    }
 }
 
-impl MetricsHashMapExt for DashMap<String, ArcMetric, FnvBuildHasher> {
-    fn get_metric(&self, name: &str) -> MetricsEngineResult<ArcMetric> {
-        self.get(name).ok_or_else(|| MetricsEngineError::MetricNotFound).map(|item| item.value().clone())
+fn try_create_auto_primary_tags<'a>(metric: &ArcMetric, tags: impl Iterator<Item=&'a Vec<Tag>>) -> MetricsEngineResult<()> {
+    let new_tags = {
+        let metric = metric.read().unwrap();
+        tags.filter(|tags| metric.requires_new_primary_tags(tags)).collect::<Vec<_>>()
+    };
+
+    if !new_tags.is_empty() {
+        let mut metric = metric.write().unwrap();
+        for tags in new_tags {
+            metric.create_auto_primary_tags(tags)?;
+        }
     }
+
+    Ok(())
 }
 
 pub type ArcMetric = Arc<RwLock<Metric>>;
@@ -347,4 +2018,149 @@ impl Metric {
             Metric::Ratio(_) => MetricType::Ratio
         }
     }
+
+    pub fn scheduled(&self) {
+        match self {
+            Metric::Gauge(metric) => metric.scheduled(),
+            Metric::Count(metric) => metric.scheduled(),
+            Metric::Ratio(metric) => metric.scheduled()
+        }
+    }
+
+    pub fn latest_time(&self) -> Option<f64> {
+        match self {
+            Metric::Gauge(metric) => metric.latest_time(),
+            Metric::Count(metric) => metric.latest_time(),
+            Metric::Ratio(metric) => metric.latest_time()
+        }
+    }
+
+    pub fn config(&self) -> &MetricConfig {
+        match self {
+            Metric::Gauge(metric) => metric.config(),
+            Metric::Count(metric) => metric.config(),
+            Metric::Ratio(metric) => metric.config()
+        }
+    }
+
+    pub fn start_time(&self) -> Option<Time> {
+        match self {
+            Metric::Gauge(metric) => metric.start_time(),
+            Metric::Count(metric) => metric.start_time(),
+            Metric::Ratio(metric) => metric.start_time()
+        }
+    }
+
+    pub fn rollup_in_window(&self, operation: RollupOperation, query: Query, duration: Duration) -> OperationResult {
+        fn apply<T: GenericMetric>(metric: &T, operation: RollupOperation, query: Query, duration: Duration) -> OperationResult {
+            match operation {
+                RollupOperation::Average => metric.average_in_window(query, duration),
+                RollupOperation::Sum => metric.sum_in_window(query, duration),
+                RollupOperation::Max => metric.max_in_window(query, duration),
+                RollupOperation::Min => metric.min_in_window(query, duration)
+            }
+        }
+
+        match self {
+            Metric::Gauge(metric) => apply(metric, operation, query, duration),
+            Metric::Count(metric) => apply(metric, operation, query, duration),
+            Metric::Ratio(metric) => apply(metric, operation, query, duration)
+        }
+    }
+
+    pub fn sealed_time(&self) -> Option<Time> {
+        match self {
+            Metric::Gauge(metric) => metric.sealed_time(),
+            Metric::Count(metric) => metric.sealed_time(),
+            Metric::Ratio(metric) => metric.sealed_time()
+        }
+    }
+
+    pub fn num_primary_tags(&self) -> usize {
+        match self {
+            Metric::Gauge(metric) => metric.primary_tags().count(),
+            Metric::Count(metric) => metric.primary_tags().count(),
+            Metric::Ratio(metric) => metric.primary_tags().count()
+        }
+    }
+
+    pub fn num_primary_tags_with_data(&self) -> usize {
+        match self {
+            Metric::Gauge(metric) => metric.num_primary_tags_with_data(),
+            Metric::Count(metric) => metric.num_primary_tags_with_data(),
+            Metric::Ratio(metric) => metric.num_primary_tags_with_data()
+        }
+    }
+
+    pub fn flush(&self) -> MetricResult<()> {
+        match self {
+            Metric::Gauge(metric) => metric.flush(),
+            Metric::Count(metric) => metric.flush(),
+            Metric::Ratio(metric) => metric.flush()
+        }
+    }
+
+    pub fn move_to_cold_tier(&self, hot_root: &Path, cold_root: &Path, older_than: Time) -> MetricResult<usize> {
+        match self {
+            Metric::Gauge(metric) => metric.move_to_cold_tier(hot_root, cold_root, older_than),
+            Metric::Count(metric) => metric.move_to_cold_tier(hot_root, cold_root, older_than),
+            Metric::Ratio(metric) => metric.move_to_cold_tier(hot_root, cold_root, older_than)
+        }
+    }
+
+    pub fn remove_expired_segments(&self, now: Time) -> MetricResult<usize> {
+        match self {
+            Metric::Gauge(metric) => metric.remove_expired_segments(now),
+            Metric::Count(metric) => metric.remove_expired_segments(now),
+            Metric::Ratio(metric) => metric.remove_expired_segments(now)
+        }
+    }
+
+    pub fn verify(&self) -> Vec<String> {
+        match self {
+            Metric::Gauge(metric) => metric.verify(),
+            Metric::Count(metric) => metric.verify(),
+            Metric::Ratio(metric) => metric.verify()
+        }
+    }
+
+    pub fn set_lock_watchdog(&mut self, name: &str, lock_watchdog: Arc<LockWatchdog>) {
+        match self {
+            Metric::Gauge(metric) => metric.set_lock_watchdog(name, lock_watchdog),
+            Metric::Count(metric) => metric.set_lock_watchdog(name, lock_watchdog),
+            Metric::Ratio(metric) => metric.set_lock_watchdog(name, lock_watchdog)
+        }
+    }
+
+    pub fn requires_new_primary_tags(&self, tags: &[Tag]) -> bool {
+        match self {
+            Metric::Gauge(metric) => metric.requires_new_primary_tags(tags),
+            Metric::Count(metric) => metric.requires_new_primary_tags(tags),
+            Metric::Ratio(metric) => metric.requires_new_primary_tags(tags)
+        }
+    }
+
+    pub fn create_auto_primary_tags(&mut self, tags: &[Tag]) -> MetricResult<()> {
+        match self {
+            Metric::Gauge(metric) => metric.create_auto_primary_tags(tags),
+            Metric::Count(metric) => metric.create_auto_primary_tags(tags),
+            Metric::Ratio(metric) => metric.create_auto_primary_tags(tags)
+        }
+    }
+
+    pub fn num_groups(&self, query: &Query, key: &GroupKey) -> usize {
+        match self {
+            Metric::Gauge(metric) => metric.num_groups(query, key),
+            Metric::Count(metric) => metric.num_groups(query, key),
+            Metric::Ratio(metric) => metric.num_groups(query, key)
+        }
+    }
+
+    pub fn group_values(&self, query: &Query, key: &GroupKey) -> Vec<Vec<Tag>> {
+        match self {
+            Metric::Gauge(metric) => metric.group_values(query, key),
+            Metric::Count(metric) => metric.group_values(query, key),
+            Metric::Ratio(metric) => metric.group_values(query, key)
+        }
+    }
 }
\ No newline at end of file
@@ -1,33 +1,82 @@
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
 
 use crate::metric::common::CountInput;
 use crate::metric::ratio::RatioInput;
 use crate::metric::tags::Tag;
-use crate::model::MetricError;
+use crate::model::{MetricError, QueryError};
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum MetricsEngineError {
-    FailedToCreateBaseDir(std::io::Error),
+    #[error("failed to create base dir {0:?}: {1}")]
+    FailedToCreateBaseDir(PathBuf, std::io::Error),
+    #[error("failed to load metric definitions: {0}")]
     FailedToLoadMetricDefinitions(std::io::Error),
+    #[error("failed to save metric definitions: {0}")]
     FailedToSaveMetricDefinitions(std::io::Error),
-    MetricAlreadyExists,
-    MetricNotFound,
-    WrongMetricType,
+    #[error("failed to load annotations: {0}")]
+    FailedToLoadAnnotations(std::io::Error),
+    #[error("failed to save annotations: {0}")]
+    FailedToSaveAnnotations(std::io::Error),
+    #[error("failed to load dashboards: {0}")]
+    FailedToLoadDashboards(std::io::Error),
+    #[error("failed to save dashboards: {0}")]
+    FailedToSaveDashboards(std::io::Error),
+    #[error("failed to create snapshot {0:?}: {1}")]
+    FailedToCreateSnapshot(PathBuf, std::io::Error),
+    #[error("failed to delete metric '{0}': {1}")]
+    FailedToDeleteMetric(String, std::io::Error),
+    #[error("failed to undelete metric '{0}': {1}")]
+    FailedToUndeleteMetric(String, std::io::Error),
+    #[error("dashboard '{0}' not found")]
+    DashboardNotFound(String),
+    #[error("metric '{0}' already exists")]
+    MetricAlreadyExists(String),
+    #[error("metric '{0}' not found")]
+    MetricNotFound(String),
+    #[error("metric '{0}' has the wrong type")]
+    WrongMetricType(String),
+    #[error("unexpected result")]
     UnexpectedResult,
-    Metric(MetricError)
-}
-
-impl From<MetricError> for MetricsEngineError {
-    fn from(other: MetricError) -> Self {
-        MetricsEngineError::Metric(other)
-    }
+    #[error("invalid query input: {0}")]
+    InvalidQueryInput(String),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("invalid query: {0}")]
+    InvalidQuery(#[from] QueryError),
+    #[error("too many concurrent queries")]
+    TooManyConcurrentQueries,
+    #[error("the query would produce {num_values} values, the maximum is {max_query_values}")]
+    QueryTooLarge { num_values: usize, max_query_values: usize },
+    #[error("the group by would produce {num_groups} groups, the maximum is {max_groups}")]
+    TooManyGroups { num_groups: usize, max_groups: usize },
+    #[error("write quota exceeded for metric '{0}'")]
+    QuotaExceeded(String),
+    #[error("disk quota exceeded for tenant '{0}'")]
+    TenantQuotaExceeded(String),
+    #[error("the storage volume is running out of disk space")]
+    LowDiskSpace,
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("metrics engine is opened read-only")]
+    ReadOnly,
+    #[error("access denied to metric '{0}'")]
+    AccessDenied(String),
+    #[error("metric error: {0}")]
+    Metric(#[from] MetricError)
 }
 
 pub type MetricsEngineResult<T> = Result<T, MetricsEngineError>;
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct AddGaugeValue {
-    pub time: f64,
+    /// The time the server receives the value is used if not set.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub time: Option<f64>,
     pub value: f64,
     pub tags: Vec<Tag>
 }
@@ -35,7 +84,15 @@ pub struct AddGaugeValue {
 impl AddGaugeValue {
     pub fn new(time: f64, value: f64, tags: Vec<Tag>) -> AddGaugeValue {
         AddGaugeValue {
-            time,
+            time: Some(time),
+            value,
+            tags
+        }
+    }
+
+    pub fn without_time(value: f64, tags: Vec<Tag>) -> AddGaugeValue {
+        AddGaugeValue {
+            time: None,
             value,
             tags
         }
@@ -43,8 +100,11 @@ impl AddGaugeValue {
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct AddCountValue {
-    pub time: f64,
+    /// The time the server receives the value is used if not set.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub time: Option<f64>,
     pub count: CountInput,
     pub tags: Vec<Tag>
 }
@@ -52,7 +112,15 @@ pub struct AddCountValue {
 impl AddCountValue {
     pub fn new(time: f64, count: CountInput, tags: Vec<Tag>) -> AddCountValue {
         AddCountValue {
-            time,
+            time: Some(time),
+            count,
+            tags
+        }
+    }
+
+    pub fn without_time(count: CountInput, tags: Vec<Tag>) -> AddCountValue {
+        AddCountValue {
+            time: None,
             count,
             tags
         }
@@ -60,8 +128,11 @@ impl AddCountValue {
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct AddRatioValue {
-    pub time: f64,
+    /// The time the server receives the value is used if not set.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub time: Option<f64>,
     pub ratio: RatioInput,
     pub tags: Vec<Tag>
 }
@@ -69,9 +140,30 @@ pub struct AddRatioValue {
 impl AddRatioValue {
     pub fn new(time: f64, ratio: RatioInput, tags: Vec<Tag>) -> AddRatioValue {
         AddRatioValue {
-            time,
+            time: Some(time),
             ratio,
             tags
         }
     }
-}
\ No newline at end of file
+
+    pub fn without_time(ratio: RatioInput, tags: Vec<Tag>) -> AddRatioValue {
+        AddRatioValue {
+            time: None,
+            ratio,
+            tags
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct LoadingStatus {
+    pub total: usize,
+    pub loaded: usize,
+    pub failed: usize
+}
+
+impl LoadingStatus {
+    pub fn is_done(&self) -> bool {
+        self.loaded + self.failed >= self.total
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::engine::io::{MetricsEngineError, MetricsEngineResult};
+use crate::model::TimeRange;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QueryLimits {
+    pub max_concurrent_queries: Option<usize>,
+    /// The maximum number of values (windows × groups) a single query operation may produce.
+    pub max_query_values: Option<usize>,
+    /// The maximum number of groups a group by may produce.
+    pub max_groups: Option<usize>
+}
+
+impl QueryLimits {
+    pub fn has_size_limits(&self) -> bool {
+        self.max_query_values.is_some() || self.max_groups.is_some()
+    }
+
+    pub fn check_num_groups(&self, num_groups: usize) -> MetricsEngineResult<()> {
+        if let Some(max_groups) = self.max_groups {
+            if num_groups > max_groups {
+                return Err(MetricsEngineError::TooManyGroups { num_groups, max_groups });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_num_values(&self, time_range: &TimeRange, duration: Option<Duration>, num_groups: usize) -> MetricsEngineResult<()> {
+        let max_query_values = match self.max_query_values {
+            Some(max_query_values) => max_query_values,
+            None => return Ok(())
+        };
+
+        let num_windows = match duration {
+            Some(duration) => ((time_range.end - time_range.start) / duration.as_secs_f64()).ceil().max(1.0) as usize,
+            None => 1
+        };
+
+        let num_values = num_windows.saturating_mul(num_groups.max(1));
+        if num_values > max_query_values {
+            return Err(MetricsEngineError::QueryTooLarge { num_values, max_query_values });
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ActiveQueries {
+    count: AtomicUsize
+}
+
+impl ActiveQueries {
+    pub fn new() -> ActiveQueries {
+        ActiveQueries {
+            count: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn acquire(&self, limits: &QueryLimits) -> MetricsEngineResult<QueryPermit<'_>> {
+        let previous = self.count.fetch_add(1, Ordering::SeqCst);
+        let permit = QueryPermit { active_queries: self };
+
+        if let Some(max_concurrent_queries) = limits.max_concurrent_queries {
+            if previous >= max_concurrent_queries {
+                return Err(MetricsEngineError::TooManyConcurrentQueries);
+            }
+        }
+
+        Ok(permit)
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for ActiveQueries {
+    fn default() -> Self {
+        ActiveQueries::new()
+    }
+}
+
+pub struct QueryPermit<'a> {
+    active_queries: &'a ActiveQueries
+}
+
+impl<'a> Drop for QueryPermit<'a> {
+    fn drop(&mut self) {
+        self.active_queries.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_active_queries1() {
+    let limits = QueryLimits { max_concurrent_queries: Some(2), ..Default::default() };
+    let active_queries = ActiveQueries::new();
+
+    let permit1 = active_queries.acquire(&limits).unwrap();
+    let _permit2 = active_queries.acquire(&limits).unwrap();
+    assert!(active_queries.acquire(&limits).is_err());
+    assert_eq!(2, active_queries.count());
+
+    drop(permit1);
+    assert!(active_queries.acquire(&limits).is_ok());
+}
+
+#[test]
+fn test_check_num_values1() {
+    let limits = QueryLimits { max_query_values: Some(100), ..Default::default() };
+    let time_range = TimeRange::new(0.0, 3600.0);
+
+    assert!(limits.check_num_values(&time_range, None, 100).is_ok());
+    assert!(limits.check_num_values(&time_range, Some(Duration::from_secs(60)), 1).is_ok());
+    assert!(limits.check_num_values(&time_range, Some(Duration::from_secs(60)), 2).is_err());
+    assert!(limits.check_num_values(&time_range, Some(Duration::from_secs(1)), 1).is_err());
+}
+
+#[test]
+fn test_check_num_groups1() {
+    let limits = QueryLimits { max_groups: Some(10), ..Default::default() };
+    assert!(limits.check_num_groups(10).is_ok());
+    assert!(limits.check_num_groups(11).is_err());
+    assert!(QueryLimits::default().check_num_groups(usize::MAX).is_ok());
+}
@@ -0,0 +1,242 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::metric::tags::PrimaryTag;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LockWatchdogConfig {
+    /// Metric locks held longer than this (in seconds) are logged, held locks are not tracked if not set.
+    pub threshold: Option<f64>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LockAccess {
+    Read,
+    Write
+}
+
+struct HeldLock {
+    metric: String,
+    /// Set for the locks of the primary tags within a metric.
+    primary_tag: Option<PrimaryTag>,
+    access: LockAccess,
+    acquired: Instant,
+    /// The span of the operation holding the lock.
+    span: tracing::Span,
+    reported: bool
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct LockStats {
+    pub num_acquired: u64,
+    /// The total time (in seconds) spent waiting for metric locks.
+    pub total_wait_time: f64,
+    /// The longest time (in seconds) spent waiting for a metric lock.
+    pub max_wait_time: f64,
+    /// The number of metric locks currently held longer than the threshold.
+    pub num_blocked: usize
+}
+
+/// Tracks the time spent waiting for metric (and primary tag) locks, and logs locks that are held for too long (such as by long queries stalling writes).
+pub struct LockWatchdog {
+    config: LockWatchdogConfig,
+    held: Mutex<FnvHashMap<u64, HeldLock>>,
+    next_id: AtomicU64,
+    num_acquired: AtomicU64,
+    total_wait_time: AtomicU64,
+    max_wait_time: AtomicU64
+}
+
+impl LockWatchdog {
+    pub fn new(config: LockWatchdogConfig) -> LockWatchdog {
+        LockWatchdog {
+            config,
+            held: Mutex::new(FnvHashMap::default()),
+            next_id: AtomicU64::new(0),
+            num_acquired: AtomicU64::new(0),
+            total_wait_time: AtomicU64::new(0),
+            max_wait_time: AtomicU64::new(0)
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.threshold.is_some()
+    }
+
+    pub fn read<'a, T>(&'a self, metric: &str, lock: &'a RwLock<T>) -> WatchedGuard<'a, RwLockReadGuard<'a, T>> {
+        let wait_start = Instant::now();
+        let guard = lock.read().unwrap();
+        let id = self.acquired(metric, None, LockAccess::Read, wait_start.elapsed());
+        WatchedGuard { guard, watchdog: self, id }
+    }
+
+    pub fn write<'a, T>(&'a self, metric: &str, lock: &'a RwLock<T>) -> WatchedGuard<'a, RwLockWriteGuard<'a, T>> {
+        let wait_start = Instant::now();
+        let guard = lock.write().unwrap();
+        let id = self.acquired(metric, None, LockAccess::Write, wait_start.elapsed());
+        WatchedGuard { guard, watchdog: self, id }
+    }
+
+    pub fn read_primary_tag<'a, T>(&'a self,
+                                   metric: &str,
+                                   primary_tag: &PrimaryTag,
+                                   lock: &'a RwLock<T>) -> WatchedGuard<'a, RwLockReadGuard<'a, T>> {
+        let wait_start = Instant::now();
+        let guard = lock.read().unwrap();
+        let id = self.acquired(metric, Some(primary_tag), LockAccess::Read, wait_start.elapsed());
+        WatchedGuard { guard, watchdog: self, id }
+    }
+
+    pub fn write_primary_tag<'a, T>(&'a self,
+                                    metric: &str,
+                                    primary_tag: &PrimaryTag,
+                                    lock: &'a RwLock<T>) -> WatchedGuard<'a, RwLockWriteGuard<'a, T>> {
+        let wait_start = Instant::now();
+        let guard = lock.write().unwrap();
+        let id = self.acquired(metric, Some(primary_tag), LockAccess::Write, wait_start.elapsed());
+        WatchedGuard { guard, watchdog: self, id }
+    }
+
+    fn acquired(&self, metric: &str, primary_tag: Option<&PrimaryTag>, access: LockAccess, wait_time: Duration) -> Option<u64> {
+        let wait_time = wait_time.as_nanos() as u64;
+        self.num_acquired.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_time.fetch_add(wait_time, Ordering::Relaxed);
+        self.max_wait_time.fetch_max(wait_time, Ordering::Relaxed);
+
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.held.lock().unwrap().insert(
+            id,
+            HeldLock {
+                metric: metric.to_owned(),
+                primary_tag: primary_tag.cloned(),
+                access,
+                acquired: Instant::now(),
+                span: tracing::Span::current(),
+                reported: false
+            }
+        );
+
+        Some(id)
+    }
+
+    fn released(&self, id: u64) {
+        if let Some(held_lock) = self.held.lock().unwrap().remove(&id) {
+            if held_lock.reported {
+                tracing::warn!(
+                    parent: &held_lock.span,
+                    metric = held_lock.metric,
+                    primary_tag = ?held_lock.primary_tag,
+                    access = ?held_lock.access,
+                    held_for = held_lock.acquired.elapsed().as_secs_f64(),
+                    "released metric lock held longer than the threshold"
+                );
+            }
+        }
+    }
+
+    /// Logs the locks that have been held longer than the threshold, each lock is only logged once.
+    pub fn check(&self) -> usize {
+        let threshold = match self.config.threshold {
+            Some(threshold) => Duration::from_secs_f64(threshold),
+            None => { return 0; }
+        };
+
+        let mut num_blocked = 0;
+        for held_lock in self.held.lock().unwrap().values_mut() {
+            let held_for = held_lock.acquired.elapsed();
+            if held_for < threshold {
+                continue;
+            }
+
+            num_blocked += 1;
+            if !held_lock.reported {
+                held_lock.reported = true;
+                tracing::warn!(
+                    parent: &held_lock.span,
+                    metric = held_lock.metric,
+                    primary_tag = ?held_lock.primary_tag,
+                    access = ?held_lock.access,
+                    held_for = held_for.as_secs_f64(),
+                    "metric lock held longer than the threshold"
+                );
+            }
+        }
+
+        num_blocked
+    }
+
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            num_acquired: self.num_acquired.load(Ordering::Relaxed),
+            total_wait_time: Duration::from_nanos(self.total_wait_time.load(Ordering::Relaxed)).as_secs_f64(),
+            max_wait_time: Duration::from_nanos(self.max_wait_time.load(Ordering::Relaxed)).as_secs_f64(),
+            num_blocked: self.check()
+        }
+    }
+}
+
+/// A lock guard that reports to the watchdog when it is released.
+pub struct WatchedGuard<'a, G> {
+    guard: G,
+    watchdog: &'a LockWatchdog,
+    id: Option<u64>
+}
+
+impl<'a, G: Deref> Deref for WatchedGuard<'a, G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.deref()
+    }
+}
+
+impl<'a, G: DerefMut> DerefMut for WatchedGuard<'a, G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.deref_mut()
+    }
+}
+
+impl<'a, G> Drop for WatchedGuard<'a, G> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.watchdog.released(id);
+        }
+    }
+}
+
+#[test]
+fn test_watchdog1() {
+    let watchdog = LockWatchdog::new(LockWatchdogConfig { threshold: Some(0.0) });
+    let lock = RwLock::new(1);
+
+    {
+        let mut guard = watchdog.write("cpu", &lock);
+        *guard += 1;
+        assert_eq!(1, watchdog.check());
+    }
+
+    assert_eq!(2, *watchdog.read("cpu", &lock));
+    assert_eq!(0, watchdog.check());
+
+    {
+        let _guard = watchdog.read_primary_tag("cpu", &PrimaryTag::Default, &lock);
+        assert_eq!(1, watchdog.check());
+    }
+
+    let stats = watchdog.stats();
+    assert_eq!(3, stats.num_acquired);
+    assert_eq!(0, stats.num_blocked);
+}
@@ -1,5 +1,32 @@
 pub mod io;
+pub mod background;
+pub mod access;
 pub mod engine;
+pub(crate) mod definitions;
+pub mod trash;
+pub mod schema;
 pub mod querying;
+pub mod annotations;
+pub mod dashboards;
+pub mod scheduler;
+pub mod limits;
+pub mod quotas;
+pub mod disk;
+pub mod verification;
+pub mod snapshots;
+pub mod tiering;
+pub mod templates;
+pub mod clock_skew;
+pub mod preaggregation;
+pub mod sampling;
+pub mod slow_queries;
+pub mod audit;
+pub mod lock_watchdog;
+pub mod tenants;
+pub mod window_cache;
+pub mod prometheus;
 
-pub use engine::MetricsEngine;
\ No newline at end of file
+#[cfg(feature = "system-metrics")]
+pub mod system_metrics;
+
+pub use engine::{MetricsEngine, MetricsEngineBuilder};
\ No newline at end of file
@@ -0,0 +1,299 @@
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+use serde::Deserialize;
+
+use crate::engine::MetricsEngine;
+use crate::engine::background::BackgroundThread;
+use crate::engine::templates::matches_pattern;
+use crate::metric::common::{CountInput, GaugeCollision, MetricConfig};
+use crate::metric::ratio::RatioInput;
+use crate::metric::tags::Tag;
+
+/// Buffered values are lost if the server is killed before they are written, they are written on a clean shutdown.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PreAggregationConfig {
+    /// The metrics whose writes are buffered, where `*` matches any sequence of characters.
+    pub metrics: Vec<String>,
+    /// The time (in milliseconds) between writing the buffered values to storage.
+    pub flush_interval: u64,
+    /// The buffered values are written by the writer that fills the buffer to this size, which slows down the writers.
+    pub max_buffered_values: usize
+}
+
+impl Default for PreAggregationConfig {
+    fn default() -> Self {
+        PreAggregationConfig {
+            metrics: Vec::new(),
+            flush_interval: 100,
+            max_buffered_values: 100000
+        }
+    }
+}
+
+impl PreAggregationConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.metrics.is_empty()
+    }
+}
+
+pub enum BufferedValues {
+    Gauge(Vec<(f64, f64, Vec<Tag>)>),
+    Count(Vec<(f64, CountInput, Vec<Tag>)>),
+    Ratio(Vec<(f64, RatioInput, Vec<Tag>)>)
+}
+
+impl BufferedValues {
+    pub fn len(&self) -> usize {
+        match self {
+            BufferedValues::Gauge(values) => values.len(),
+            BufferedValues::Count(values) => values.len(),
+            BufferedValues::Ratio(values) => values.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn empty_like(&self) -> BufferedValues {
+        match self {
+            BufferedValues::Gauge(_) => BufferedValues::Gauge(Vec::new()),
+            BufferedValues::Count(_) => BufferedValues::Count(Vec::new()),
+            BufferedValues::Ratio(_) => BufferedValues::Ratio(Vec::new())
+        }
+    }
+}
+
+/// How the values of a metric are combined, the same way as the storage combines values within the same datapoint.
+#[derive(Debug, Clone, Copy)]
+pub struct PreAggregation {
+    datapoint_duration: f64,
+    gauge_collision: GaugeCollision
+}
+
+impl PreAggregation {
+    pub fn new(config: &MetricConfig) -> PreAggregation {
+        PreAggregation {
+            datapoint_duration: config.durations.iter().map(|duration| duration.datapoint_duration).fold(f64::INFINITY, f64::min),
+            gauge_collision: config.gauge_collision
+        }
+    }
+}
+
+/// The buffered values of a metric, where the last value of each tags is the datapoint that new values are combined with.
+struct MetricBuffer {
+    values: BufferedValues,
+    last: FnvHashMap<Vec<Tag>, (usize, u32)>
+}
+
+impl MetricBuffer {
+    fn add(&mut self, values: BufferedValues, aggregation: PreAggregation) {
+        let is_same_datapoint = |time: f64, last_time: f64| time >= last_time && time - last_time < aggregation.datapoint_duration;
+
+        if std::mem::discriminant(&self.values) != std::mem::discriminant(&values) {
+            // The metric has been recreated with another type, the old values can't be written anymore
+            self.values = values.empty_like();
+            self.last.clear();
+        }
+
+        match (&mut self.values, values) {
+            (BufferedValues::Gauge(buffered), BufferedValues::Gauge(values)) => {
+                for (time, value, tags) in values {
+                    match self.last.get_mut(&tags) {
+                        // The average of combined values can't be merged correctly with the datapoint in the storage
+                        Some((index, num_values)) if aggregation.gauge_collision != GaugeCollision::Average && is_same_datapoint(time, buffered[*index].0) => {
+                            let current = &mut buffered[*index].1;
+                            *current = aggregation.gauge_collision.combine(*current as f32, value as f32, *num_values) as f64;
+                            *num_values += 1;
+                        }
+                        _ => {
+                            self.last.insert(tags.clone(), (buffered.len(), 1));
+                            buffered.push((time, value, tags));
+                        }
+                    }
+                }
+            }
+            (BufferedValues::Count(buffered), BufferedValues::Count(values)) => {
+                for (time, value, tags) in values {
+                    match self.last.get(&tags) {
+                        Some((index, _)) if is_same_datapoint(time, buffered[*index].0) && add_counts(&mut buffered[*index].1, value) => {}
+                        _ => {
+                            self.last.insert(tags.clone(), (buffered.len(), 1));
+                            buffered.push((time, value, tags));
+                        }
+                    }
+                }
+            }
+            (BufferedValues::Ratio(buffered), BufferedValues::Ratio(values)) => {
+                for (time, value, tags) in values {
+                    match self.last.get(&tags) {
+                        Some((index, _)) if is_same_datapoint(time, buffered[*index].0) && add_ratios(&mut buffered[*index].1, value) => {}
+                        _ => {
+                            self.last.insert(tags.clone(), (buffered.len(), 1));
+                            buffered.push((time, value, tags));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Combines the counts if the sum is still a valid count.
+fn add_counts(current: &mut CountInput, value: CountInput) -> bool {
+    let sum = CountInput(current.0.saturating_add(value.0));
+    if sum.value().is_ok() {
+        *current = sum;
+        true
+    } else {
+        false
+    }
+}
+
+fn add_ratios(current: &mut RatioInput, value: RatioInput) -> bool {
+    let mut sum = *current;
+    if add_counts(&mut sum.0, value.0) && add_counts(&mut sum.1, value.1) {
+        *current = sum;
+        true
+    } else {
+        false
+    }
+}
+
+struct Buffers {
+    metrics: FnvHashMap<String, MetricBuffer>,
+    num_values: usize
+}
+
+/// Buffers the values of chatty metrics in memory, such that bursts are written to storage as one batch.
+/// Values arriving faster than the datapoint duration are combined in the buffer, and written with a single write lock.
+pub struct PreAggregationBuffer {
+    metrics: Vec<String>,
+    max_buffered_values: usize,
+    buffers: Mutex<Buffers>
+}
+
+impl PreAggregationBuffer {
+    pub fn new(config: &PreAggregationConfig) -> PreAggregationBuffer {
+        PreAggregationBuffer {
+            metrics: config.metrics.clone(),
+            max_buffered_values: config.max_buffered_values,
+            buffers: Mutex::new(Buffers { metrics: FnvHashMap::default(), num_values: 0 })
+        }
+    }
+
+    pub fn is_buffered(&self, metric: &str) -> bool {
+        self.metrics.iter().any(|pattern| matches_pattern(pattern, metric))
+    }
+
+    /// Adds the values to the buffer, returns true if the buffer is full and must be written before adding more values.
+    pub fn add(&self, metric: &str, values: BufferedValues, aggregation: PreAggregation) -> bool {
+        let mut buffers = self.buffers.lock().unwrap();
+        let buffers = buffers.deref_mut();
+        let buffer = buffers.metrics
+            .entry(metric.to_owned())
+            .or_insert_with(|| MetricBuffer { values: values.empty_like(), last: FnvHashMap::default() });
+
+        let num_values_before = buffer.values.len();
+        buffer.add(values, aggregation);
+        buffers.num_values = buffers.num_values - num_values_before + buffer.values.len();
+        buffers.num_values >= self.max_buffered_values
+    }
+
+    /// Removes all buffered values.
+    pub fn take(&self) -> Vec<(String, BufferedValues)> {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.num_values = 0;
+        std::mem::take(&mut buffers.metrics).into_iter().map(|(metric, buffer)| (metric, buffer.values)).collect()
+    }
+
+    /// Removes the buffered values of the metric.
+    pub fn take_metric(&self, metric: &str) -> Option<BufferedValues> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let buffer = buffers.metrics.remove(metric)?;
+        buffers.num_values -= buffer.values.len();
+        Some(buffer.values)
+    }
+
+    pub fn num_buffered(&self) -> usize {
+        self.buffers.lock().unwrap().num_values
+    }
+}
+
+/// Writes the buffered values to storage at a fixed interval.
+pub fn spawn_pre_aggregation_thread(metrics_engine: Arc<MetricsEngine>, config: PreAggregationConfig) -> BackgroundThread {
+    BackgroundThread::spawn(move |stop_signal| {
+        let interval = Duration::from_millis(config.flush_interval.max(1));
+
+        while stop_signal.sleep(interval) {
+            if let Err(err) = metrics_engine.flush_buffered() {
+                tracing::error!(error = %err, "failed to write buffered values");
+            }
+        }
+    })
+}
+
+#[test]
+fn test_buffer1() {
+    use crate::metric::common::MetricType;
+
+    let config = PreAggregationConfig { metrics: vec!["cpu_*".to_owned()], flush_interval: 100, max_buffered_values: 3 };
+    let buffer = PreAggregationBuffer::new(&config);
+    assert!(buffer.is_buffered("cpu_usage"));
+    assert!(!buffer.is_buffered("memory_usage"));
+
+    let gauge = PreAggregation::new(&MetricConfig::new(MetricType::Gauge));
+    let count = PreAggregation::new(&MetricConfig::new(MetricType::Count));
+    assert!(!buffer.add("cpu_usage", BufferedValues::Gauge(vec![(1654077600.0, 1.0, Vec::new())]), gauge));
+    assert!(!buffer.add("cpu_usage", BufferedValues::Gauge(vec![(1654077600.1, 2.0, Vec::new()), (1654077600.3, 3.0, Vec::new())]), gauge));
+    assert_eq!(2, buffer.num_buffered());
+    assert!(buffer.add("cpu_count", BufferedValues::Count(vec![(1654077600.0, CountInput(1), Vec::new())]), count));
+    assert_eq!(3, buffer.num_buffered());
+
+    let mut buffered = buffer.take();
+    buffered.sort_by(|x, y| x.0.cmp(&y.0));
+    assert_eq!(
+        vec![("cpu_count".to_owned(), 1), ("cpu_usage".to_owned(), 2)],
+        buffered.iter().map(|(metric, values)| (metric.clone(), values.len())).collect::<Vec<_>>()
+    );
+    match &buffered[1].1 {
+        BufferedValues::Gauge(values) => assert_eq!(vec![2.0, 3.0], values.iter().map(|value| value.1).collect::<Vec<_>>()),
+        _ => panic!("expected gauge values")
+    }
+    assert_eq!(0, buffer.num_buffered());
+}
+
+#[test]
+fn test_buffer2() {
+    use crate::metric::common::MetricType;
+
+    let buffer = PreAggregationBuffer::new(&PreAggregationConfig { metrics: vec!["requests".to_owned()], ..Default::default() });
+    let count = PreAggregation::new(&MetricConfig::new(MetricType::Count));
+    let tags = vec![Tag::from_ref("host", "a")];
+    buffer.add(
+        "requests",
+        BufferedValues::Count(vec![
+            (1654077600.0, CountInput(1), tags.clone()),
+            (1654077600.0, CountInput(5), Vec::new()),
+            (1654077600.5, CountInput(2), tags.clone()),
+            (1654077601.5, CountInput(4), tags.clone()),
+            (1654077601.6, CountInput(1 << 24), tags.clone())
+        ]),
+        count
+    );
+
+    match buffer.take().remove(0).1 {
+        BufferedValues::Count(values) => {
+            assert_eq!(
+                vec![(1654077600.0, 3, tags.clone()), (1654077600.0, 5, Vec::new()), (1654077601.5, 4, tags.clone()), (1654077601.6, 1 << 24, tags)],
+                values.into_iter().map(|(time, count, tags)| (time, count.0, tags)).collect::<Vec<_>>()
+            );
+        }
+        _ => panic!("expected count values")
+    }
+}
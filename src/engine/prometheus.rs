@@ -0,0 +1,309 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fnv::FnvHashMap;
+use serde::Deserialize;
+
+use crate::engine::io::{AddCountValue, AddGaugeValue, MetricsEngineError, MetricsEngineResult};
+use crate::engine::MetricsEngine;
+use crate::engine::background::BackgroundThread;
+use crate::metric::common::{CountInput, MetricType};
+use crate::metric::tags::Tag;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PrometheusAdapterConfig {
+    /// The time (in seconds) between scrapes of the registry.
+    pub interval: f64,
+    /// Prepended to the names of the metrics.
+    pub prefix: String
+}
+
+impl Default for PrometheusAdapterConfig {
+    fn default() -> Self {
+        PrometheusAdapterConfig {
+            interval: 15.0,
+            prefix: String::new()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleKind {
+    Gauge,
+    Counter,
+    Histogram,
+    Summary,
+    Untyped
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrometheusSample {
+    pub name: String,
+    pub kind: SampleKind,
+    pub labels: Vec<Tag>,
+    pub value: f64
+}
+
+/// Parses the text exposition format, which all Prometheus client registries can be encoded as
+/// (such as `prometheus::TextEncoder` or `prometheus_client::encoding::text::encode`).
+pub fn parse_exposition(text: &str) -> Result<Vec<PrometheusSample>, String> {
+    let mut kinds = FnvHashMap::default();
+    let mut samples = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.split_whitespace();
+            if let (Some("TYPE"), Some(name), Some(kind)) = (parts.next(), parts.next(), parts.next()) {
+                let kind = match kind {
+                    "gauge" => SampleKind::Gauge,
+                    "counter" => SampleKind::Counter,
+                    "histogram" => SampleKind::Histogram,
+                    "summary" => SampleKind::Summary,
+                    _ => SampleKind::Untyped
+                };
+                kinds.insert(name.to_owned(), kind);
+            }
+
+            continue;
+        }
+
+        let (name, labels, rest) = parse_series(line).ok_or_else(|| format!("Invalid sample: {}", line))?;
+        let value = rest
+            .split_whitespace()
+            .next()
+            .and_then(parse_value)
+            .ok_or_else(|| format!("Invalid value: {}", line))?;
+
+        let family = [name.as_str(), name.trim_end_matches("_total")]
+            .into_iter()
+            .chain(["_bucket", "_sum", "_count", "_created"].into_iter().filter_map(|suffix| name.strip_suffix(suffix)))
+            .find(|family| kinds.contains_key(*family));
+
+        samples.push(
+            PrometheusSample {
+                kind: family.map(|family| kinds[family]).unwrap_or(SampleKind::Untyped),
+                name,
+                labels,
+                value
+            }
+        );
+    }
+
+    Ok(samples)
+}
+
+fn parse_series(line: &str) -> Option<(String, Vec<Tag>, &str)> {
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let name = line[..name_end].to_owned();
+    let mut rest = &line[name_end..];
+
+    let mut labels = Vec::new();
+    if let Some(mut remaining) = rest.strip_prefix('{') {
+        loop {
+            remaining = remaining.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if let Some(after) = remaining.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+
+            let (key, after_key) = remaining.split_once('=')?;
+            let mut chars = after_key.trim_start().strip_prefix('"')?.char_indices();
+            let mut value = String::new();
+            let end = loop {
+                match chars.next()? {
+                    (index, '"') => break index,
+                    (_, '\\') => {
+                        match chars.next()?.1 {
+                            'n' => value.push('\n'),
+                            escaped => value.push(escaped)
+                        }
+                    }
+                    (_, c) => value.push(c)
+                }
+            };
+
+            labels.push(Tag(key.trim().to_owned(), value));
+            remaining = &after_key.trim_start()[1 + end + 1..];
+        }
+    }
+
+    Some((name, labels, rest))
+}
+
+fn parse_value(value: &str) -> Option<f64> {
+    match value {
+        "+Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        value => f64::from_str(value).ok()
+    }
+}
+
+/// Writes the samples of a registry into metrics (created if needed). Gauges (and summary quantiles) are written as gauges.
+/// Counters (and the counts of histograms and summaries) are written as counts of the increase since the previous scrape,
+/// and the sums of histograms and summaries as gauges of the increase. Histogram buckets are not written.
+pub struct PrometheusAdapter {
+    config: PrometheusAdapterConfig,
+    prev_values: FnvHashMap<(String, Vec<Tag>), f64>
+}
+
+impl PrometheusAdapter {
+    pub fn new(config: PrometheusAdapterConfig) -> PrometheusAdapter {
+        PrometheusAdapter {
+            config,
+            prev_values: FnvHashMap::default()
+        }
+    }
+
+    /// Writes the samples of the encoded registry, returning the number of values written.
+    pub fn scrape(&mut self, metrics_engine: &MetricsEngine, text: &str, time: f64) -> MetricsEngineResult<usize> {
+        let samples = parse_exposition(text).map_err(MetricsEngineError::InvalidInput)?;
+
+        let mut gauge_values = FnvHashMap::<String, Vec<AddGaugeValue>>::default();
+        let mut count_values = FnvHashMap::<String, Vec<AddCountValue>>::default();
+        for sample in samples {
+            let is_sum = sample.name.ends_with("_sum");
+            let is_count = sample.name.ends_with("_count");
+            let name = format!("{}{}", self.config.prefix, sample.name);
+
+            match sample.kind {
+                SampleKind::Gauge | SampleKind::Untyped => {
+                    gauge_values.entry(name).or_default().push(AddGaugeValue::new(time, sample.value, sample.labels));
+                }
+                SampleKind::Summary if !is_sum && !is_count => {
+                    gauge_values.entry(name).or_default().push(AddGaugeValue::new(time, sample.value, sample.labels));
+                }
+                SampleKind::Histogram if !is_sum && !is_count => {}
+                _ if sample.name.ends_with("_created") => {}
+                _ => {
+                    let increase = match self.increase(&name, &sample.labels, sample.value) {
+                        Some(increase) => increase,
+                        None => continue
+                    };
+
+                    if is_sum {
+                        gauge_values.entry(name).or_default().push(AddGaugeValue::new(time, increase, sample.labels));
+                    } else if increase >= 1.0 {
+                        let count = CountInput(increase.round().min(u32::MAX as f64) as u32);
+                        count_values.entry(name).or_default().push(AddCountValue::new(time, count, sample.labels));
+                    }
+                }
+            }
+        }
+
+        let mut num_written = 0;
+        for (name, values) in gauge_values {
+            create_metric(metrics_engine, &name, MetricType::Gauge)?;
+            num_written += metrics_engine.gauge(&name, values.into_iter())?;
+        }
+
+        for (name, values) in count_values {
+            create_metric(metrics_engine, &name, MetricType::Count)?;
+            num_written += metrics_engine.count(&name, values.into_iter())?;
+        }
+
+        Ok(num_written)
+    }
+
+    /// The increase of a cumulative value since the previous scrape, where a decrease means that the value was reset.
+    fn increase(&mut self, name: &str, labels: &[Tag], value: f64) -> Option<f64> {
+        let prev_value = self.prev_values.insert((name.to_owned(), labels.to_vec()), value)?;
+        if value >= prev_value {
+            Some(value - prev_value)
+        } else {
+            Some(value)
+        }
+    }
+}
+
+fn create_metric(metrics_engine: &MetricsEngine, name: &str, metric_type: MetricType) -> MetricsEngineResult<()> {
+    match metrics_engine.add_metric(name, metric_type) {
+        Ok(()) | Err(MetricsEngineError::MetricAlreadyExists(_)) => Ok(()),
+        Err(err) => Err(err)
+    }
+}
+
+/// Scrapes the registry (given as a function that encodes it in the text format) at the configured interval.
+pub fn spawn_prometheus_adapter_thread<F>(metrics_engine: Arc<MetricsEngine>,
+                                          config: PrometheusAdapterConfig,
+                                          encode: F) -> BackgroundThread
+    where F: Fn() -> String + Send + 'static {
+    BackgroundThread::spawn(move |stop_signal| {
+        let interval = Duration::from_secs_f64(config.interval.max(0.1));
+        let mut adapter = PrometheusAdapter::new(config);
+
+        while !stop_signal.is_stopped() {
+            let scrape_start = Instant::now();
+
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+            if let Err(err) = adapter.scrape(&metrics_engine, &encode(), time) {
+                tracing::warn!(error = %err, "failed to write scraped metrics");
+            }
+
+            stop_signal.sleep_until(scrape_start + interval);
+        }
+    })
+}
+
+#[test]
+fn test_parse_exposition1() {
+    let text = r#"
+# HELP http_requests_total The number of requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",path="/a\"b"} 1027 1395066363000
+http_requests_total{method="get"} 3
+# TYPE temperature gauge
+temperature -2.5
+# TYPE latency histogram
+latency_bucket{le="+Inf"} 10
+latency_sum 4.5
+latency_count 10
+build_info{version="1.0",} 1
+"#;
+
+    let samples = parse_exposition(text).unwrap();
+    assert_eq!(7, samples.len());
+    assert_eq!(
+        PrometheusSample {
+            name: "http_requests_total".to_owned(),
+            kind: SampleKind::Counter,
+            labels: vec![Tag::from_ref("method", "post"), Tag::from_ref("path", "/a\"b")],
+            value: 1027.0
+        },
+        samples[0]
+    );
+    assert_eq!((SampleKind::Gauge, -2.5), (samples[2].kind, samples[2].value));
+    assert_eq!((SampleKind::Histogram, vec![Tag::from_ref("le", "+Inf")]), (samples[3].kind, samples[3].labels.clone()));
+    assert_eq!(SampleKind::Histogram, samples[5].kind);
+    assert_eq!((SampleKind::Untyped, vec![Tag::from_ref("version", "1.0")]), (samples[6].kind, samples[6].labels.clone()));
+
+    assert!(parse_exposition("requests{method=\"get} 1").is_err());
+}
+
+#[test]
+fn test_scrape1() {
+    use crate::model::{Query, TimeRange};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let metrics_engine = MetricsEngine::new(temp_dir.path()).unwrap();
+
+    let mut adapter = PrometheusAdapter::new(PrometheusAdapterConfig { prefix: "app_".to_owned(), ..Default::default() });
+    let time = 1654077600.0;
+    let scrape = |requests: u32, temperature: f64| {
+        format!("# TYPE requests_total counter\nrequests_total {}\n# TYPE temperature gauge\ntemperature {}\n", requests, temperature)
+    };
+
+    assert_eq!(1, adapter.scrape(&metrics_engine, &scrape(10, 20.0), time).unwrap());
+    assert_eq!(2, adapter.scrape(&metrics_engine, &scrape(15, 22.0), time + 15.0).unwrap());
+    assert_eq!(2, adapter.scrape(&metrics_engine, &scrape(4, 24.0), time + 30.0).unwrap());
+
+    let query = Query::new(TimeRange::new(time, time + 60.0));
+    assert_eq!(Some(22.0), metrics_engine.average("app_temperature", query.clone()).unwrap().value());
+    assert_eq!(Some(9.0), metrics_engine.sum("app_requests_total", query).unwrap().value());
+}
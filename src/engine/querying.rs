@@ -1,22 +1,241 @@
 use std::collections::HashMap;
+use std::rc::Rc;
 use std::time::Duration;
 use fnv::{FnvHashMap, FnvHashSet};
+use regex::Regex;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
 
 use crate::engine::engine::MetricsEngine;
 use crate::engine::io::{MetricsEngineError, MetricsEngineResult};
-use crate::metric::{GroupTimeValues, GroupValues, OperationResult, TimeValues};
-use crate::metric::expression::{ArithmeticOperation, ExpressionValue, FilterExpression, Function};
-use crate::model::{GroupValue, Query, TimeRange};
+use crate::metric::{downsampling, ratio, GroupTimeValues, GroupValues, OperationResult, TimeValues};
+use crate::metric::common::MetricType;
+use crate::metric::expression::{ArithmeticOperation, ExpressionValue, FilterExpression, Function, TransformExpression};
+use crate::metric::tags::{Tag, TagsFilter};
+use crate::model::{GroupKey, GroupValue, Query, QueryError, ReadConsistency, Time, TIME_SCALE, TimeRange};
 
 #[cfg(test)]
 use crate::metric::expression::CompareOperation;
+#[cfg(test)]
+use crate::model::GroupLimit;
 
+#[derive(Clone)]
 pub struct MetricQuery {
     pub time_range: TimeRange,
     pub expression: MetricQueryExpression,
-    pub output_filter: Option<FilterExpression>
+    pub output_filter: Option<FilterExpression>,
+    /// The maximum number of points returned per series for windowed queries.
+    pub max_points: Option<usize>,
+    pub downsampling: Downsampling,
+    pub alignment: Alignment,
+    pub join: GroupJoin,
+    pub relabel: Option<Relabeling>,
+    /// The request the query was made in, which is included in the slow query log.
+    pub request_id: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum Downsampling {
+    /// Widens the window duration so that at most max points windows are computed.
+    #[default]
+    WidenWindow,
+    /// Computes the windows as requested and then reduces each series using Largest-Triangle-Three-Buckets.
+    Lttb
+}
+
+/// How the windows of windowed operands are matched when they are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum Alignment {
+    /// The windows of the operands must have the same timestamps.
+    #[default]
+    Exact,
+    /// Takes the value of the window with the nearest timestamp.
+    Nearest,
+    /// Interpolates linearly between the surrounding windows that have values, without extrapolating.
+    Linear
+}
+
+/// How the groups of two grouped operands are combined, where missing groups use the fill value (or have no value).
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum GroupJoin {
+    /// Only the groups present in both operands are kept.
+    #[default]
+    Inner,
+    /// The groups of the left operand are kept.
+    Left { fill: Option<f64> },
+    /// The groups of both operands are kept.
+    Outer { fill: Option<f64> }
+}
+
+impl GroupJoin {
+    fn fill(&self) -> Option<f64> {
+        match self {
+            GroupJoin::Inner => None,
+            GroupJoin::Left { fill } | GroupJoin::Outer { fill } => *fill
+        }
+    }
+
+    fn groups<L, R>(&self, left: &FnvHashMap<GroupValue, L>, right: &FnvHashMap<GroupValue, R>) -> Vec<GroupValue> {
+        match self {
+            GroupJoin::Inner => left.keys().filter(|group| right.contains_key(*group)).cloned().collect(),
+            GroupJoin::Left { .. } => left.keys().cloned().collect(),
+            GroupJoin::Outer { .. } => left.keys().chain(right.keys().filter(|group| !left.contains_key(*group))).cloned().collect()
+        }
+    }
+}
+
+/// How the values of the groups are aggregated into a single value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum AggregateFunction {
+    #[default]
+    Sum,
+    Average,
+    Max,
+    Min,
+    /// The number of groups with a value.
+    Count
+}
+
+impl AggregateFunction {
+    pub fn apply(&self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return match self {
+                AggregateFunction::Count => Some(0.0),
+                _ => None
+            };
+        }
+
+        match self {
+            AggregateFunction::Sum => Some(values.iter().sum()),
+            AggregateFunction::Average => Some(values.iter().sum::<f64>() / values.len() as f64),
+            AggregateFunction::Max => values.iter().cloned().reduce(f64::max),
+            AggregateFunction::Min => values.iter().cloned().reduce(f64::min),
+            AggregateFunction::Count => Some(values.len() as f64)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum RelabelRule {
+    /// Renames the group name equal to `from`.
+    Map { from: String, to: String },
+    /// Renames group names that fully match the pattern, where the replacement can refer to captures (such as `socket$1`).
+    Regex { pattern: String, replacement: String }
+}
+
+/// Renames the groups of the result, where the first matching rule is used for each group name.
+/// Groups that end up with the same name are merged using the aggregate function.
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Relabeling {
+    pub rules: Vec<RelabelRule>,
+    #[serde(default)]
+    pub aggregate: AggregateFunction
+}
+
+impl Relabeling {
+    pub fn new(rules: Vec<RelabelRule>, aggregate: AggregateFunction) -> Relabeling {
+        Relabeling {
+            rules,
+            aggregate
+        }
+    }
+
+    fn compile(&self) -> MetricsEngineResult<Vec<Option<Regex>>> {
+        transform_with_result(
+            self.rules.iter(),
+            |rule| {
+                match rule {
+                    RelabelRule::Map { .. } => Ok(None),
+                    RelabelRule::Regex { pattern, .. } => {
+                        Regex::new(&format!("^(?:{})$", pattern))
+                            .map(Some)
+                            .map_err(|_| MetricsEngineError::InvalidQueryInput(format!("The relabel pattern '{}' is not valid.", pattern)))
+                    }
+                }
+            }
+        )
+    }
+
+    fn relabel(&self, regexes: &[Option<Regex>], group: &GroupValue) -> GroupValue {
+        let relabel_name = |name: &String| {
+            for (rule, regex) in self.rules.iter().zip(regexes) {
+                match (rule, regex) {
+                    (RelabelRule::Map { from, to }, _) if from == name => { return to.clone(); }
+                    (RelabelRule::Regex { replacement, .. }, Some(regex)) if regex.is_match(name) => {
+                        return regex.replace(name, replacement.as_str()).into_owned();
+                    }
+                    _ => {}
+                }
+            }
+
+            name.clone()
+        };
+
+        GroupValue(group.0.iter().map(relabel_name).collect())
+    }
+
+    fn merge_groups<T>(&self, values: Vec<(GroupValue, T)>) -> MetricsEngineResult<Vec<(GroupValue, Vec<T>)>> {
+        let regexes = self.compile()?;
+
+        let mut group_indices = FnvHashMap::default();
+        let mut groups: Vec<(GroupValue, Vec<T>)> = Vec::new();
+        for (group, value) in values {
+            let group = self.relabel(&regexes, &group);
+            let group_index = *group_indices.entry(group.clone()).or_insert_with(|| {
+                groups.push((group, Vec::new()));
+                groups.len() - 1
+            });
+
+            groups[group_index].1.push(value);
+        }
+
+        groups.sort_by(|x, y| x.0.cmp(&y.0));
+        Ok(groups)
+    }
+
+    pub fn apply(&self, result: OperationResult, alignment: Alignment) -> MetricsEngineResult<OperationResult> {
+        match result {
+            OperationResult::GroupValues(values) => {
+                let groups = self.merge_groups(values)?
+                    .into_iter()
+                    .map(|(group, values)| {
+                        let values = values.into_iter().flatten().collect::<Vec<_>>();
+                        (group, self.aggregate.apply(&values))
+                    })
+                    .collect();
+                Ok(OperationResult::GroupValues(groups))
+            }
+            OperationResult::GroupTimeValues(values) => {
+                let mut groups = Vec::new();
+                for (group, values) in self.merge_groups(values)? {
+                    let reference = &values[0];
+                    let values = transform_with_result(
+                        values.iter(),
+                        |values| align_time_values(reference, values, alignment)
+                    )?;
+
+                    let mut results = Vec::new();
+                    for (window_index, (time, _)) in reference.iter().enumerate() {
+                        let window_values = values.iter().filter_map(|values| values[window_index].1).collect::<Vec<_>>();
+                        results.push((*time, self.aggregate.apply(&window_values)));
+                    }
+
+                    groups.push((group, results));
+                }
+
+                Ok(OperationResult::GroupTimeValues(groups))
+            }
+            result => Ok(result)
+        }
+    }
 }
 
 impl MetricQuery {
@@ -24,7 +243,13 @@ impl MetricQuery {
         MetricQuery {
             time_range,
             expression,
-            output_filter: None
+            output_filter: None,
+            max_points: None,
+            downsampling: Downsampling::WidenWindow,
+            alignment: Alignment::Exact,
+            join: GroupJoin::Inner,
+            relabel: None,
+            request_id: None
         }
     }
 
@@ -42,20 +267,139 @@ impl MetricQuery {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+pub fn validate_time_range(time_range: &TimeRange) -> MetricsEngineResult<()> {
+    time_range.validate()?;
+    Ok(())
+}
+
+pub fn validate_duration(duration: Duration) -> MetricsEngineResult<()> {
+    if (duration.as_secs_f64() * TIME_SCALE as f64) as Time == 0 {
+        return Err(MetricsEngineError::InvalidQueryInput("The window duration must be positive.".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn offset_time_range(time_range: &TimeRange, offset: f64) -> MetricsEngineResult<TimeRange> {
+    if !offset.is_finite() {
+        return Err(MetricsEngineError::InvalidQueryInput("The offset must be finite.".to_owned()));
+    }
+
+    let time_range = TimeRange { start: time_range.start - offset, end: time_range.end - offset };
+    validate_time_range(&time_range)?;
+    Ok(time_range)
+}
+
+pub fn widen_duration(time_range: &TimeRange, duration: Duration, max_points: Option<usize>) -> MetricsEngineResult<Duration> {
+    let max_points = match max_points {
+        Some(0) => { return Err(MetricsEngineError::InvalidQueryInput("The max points must be positive.".to_owned())); }
+        Some(max_points) => max_points,
+        None => { return Ok(duration); }
+    };
+
+    let num_points = (time_range.end - time_range.start) / duration.as_secs_f64();
+    if num_points <= max_points as f64 {
+        return Ok(duration);
+    }
+
+    Ok(Duration::from_secs_f64(((time_range.end - time_range.start) / max_points as f64).ceil()))
+}
+
+pub fn validate_percentile(percentile: i32) -> MetricsEngineResult<()> {
+    if !(0..=100).contains(&percentile) {
+        return Err(MetricsEngineError::InvalidQueryInput("The percentile must be between 0 and 100.".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum MetricQueryExpression {
     Average { metric: String, query: Query },
     Sum { metric: String, query: Query },
     Max { metric: String, query: Query },
     Min { metric: String, query: Query },
     Percentile { metric: String, query: Query, percentile: i32 },
+    Last { metric: String, query: Query },
+    /// The number of events of a count metric.
+    Count { metric: String, query: Query },
+    /// The number of events per second of a count metric.
+    Rate { metric: String, query: Query },
+    /// The summed numerator of a ratio metric.
+    Numerator { metric: String, query: Query },
+    /// The summed denominator of a ratio metric.
+    Denominator { metric: String, query: Query },
+    /// The number of datapoints of a ratio metric, where values added at the same time are a single datapoint.
+    Datapoints { metric: String, query: Query },
+    /// The ratio of a ratio metric with its Wilson score interval at the confidence (between 0 and 1), computed from the
+    /// summed numerator and denominator. Grouped by the part (`ratio`, `lower` or `upper`).
+    RatioInterval {
+        metric: String,
+        query: Query,
+        #[serde(default = "default_confidence")]
+        confidence: f64
+    },
+    /// Computes multiple quantiles (between 0 and 1) of the same metric, grouped by the quantile.
+    Quantiles { metric: String, query: Query, quantiles: Vec<f64> },
     Value(f64),
-    Arithmetic { operation: ArithmeticOperation, left: Box<MetricQueryExpression>, right: Box<MetricQueryExpression> },
-    Function { function: Function, arguments: Vec<MetricQueryExpression> }
+    /// Evaluates the value once and makes it available as a variable with the given name in the body.
+    Let {
+        name: String,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        value: Box<MetricQueryExpression>,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        body: Box<MetricQueryExpression>
+    },
+    Variable(String),
+    /// Removes the groups (or values for windows) whose computed value does not pass the filter.
+    Filter {
+        filter: FilterExpression,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        expression: Box<MetricQueryExpression>
+    },
+    Arithmetic {
+        operation: ArithmeticOperation,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        left: Box<MetricQueryExpression>,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        right: Box<MetricQueryExpression>
+    },
+    Function {
+        function: Function,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        arguments: Vec<MetricQueryExpression>
+    },
+    /// For each value (per group and window) of `value`, takes the corresponding value of `then` if the condition holds
+    /// and otherwise of `otherwise`, where a missing branch has no value. Constant branches are broadcasted.
+    Conditional {
+        condition: FilterExpression,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        value: Box<MetricQueryExpression>,
+        #[serde(default)]
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        then: Option<Box<MetricQueryExpression>>,
+        #[serde(default)]
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        otherwise: Option<Box<MetricQueryExpression>>
+    },
+    /// Aggregates the values of the groups (per window) into a single value, where groups without value are ignored.
+    AggregateGroups {
+        function: AggregateFunction,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        expression: Box<MetricQueryExpression>
+    },
+    /// Evaluates the expression over the time range shifted back by the offset (in seconds), such as the previous period.
+    /// Windows are shifted forward by the offset to line up with the windows of the time range.
+    Offset {
+        offset: f64,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        expression: Box<MetricQueryExpression>
+    }
 }
 
 pub fn query<T: MetricQueryable>(engine: &T, query: MetricQuery) -> MetricsEngineResult<OperationResult> {
-    fn evaluate<T: MetricQueryable>(engine: &T, time_range: TimeRange, expression: MetricQueryExpression) -> MetricsEngineResult<OperationResult> {
+    fn evaluate<T: MetricQueryable>(engine: &T, time_range: TimeRange, join: GroupJoin, bindings: &Bindings, expression: MetricQueryExpression) -> MetricsEngineResult<OperationResult> {
         match expression {
             MetricQueryExpression::Average { metric, mut query } => {
                 query.time_range = time_range;
@@ -77,12 +421,75 @@ pub fn query<T: MetricQueryable>(engine: &T, query: MetricQuery) -> MetricsEngin
                 query.time_range = time_range;
                 engine.percentile(&metric, query, percentile)
             }
+            MetricQueryExpression::Last { metric, mut query } => {
+                query.time_range = time_range;
+                engine.last(&metric, query)
+            }
+            MetricQueryExpression::Count { metric, mut query } => {
+                query.time_range = time_range;
+                engine.sum(&metric, query)
+            }
+            MetricQueryExpression::Rate { metric, mut query } => {
+                query.time_range = time_range;
+                let seconds = time_range.end - time_range.start;
+                Ok(engine.sum(&metric, query)?.map_values(|value| value / seconds))
+            }
+            MetricQueryExpression::Numerator { metric, mut query } => {
+                query.time_range = time_range;
+                query.output_transform = Some(TransformExpression::InputNumerator);
+                engine.sum(&metric, query)
+            }
+            MetricQueryExpression::Denominator { metric, mut query } => {
+                query.time_range = time_range;
+                query.output_transform = Some(TransformExpression::InputDenominator);
+                engine.sum(&metric, query)
+            }
+            MetricQueryExpression::Datapoints { metric, mut query } => {
+                query.time_range = time_range;
+                engine.datapoints(&metric, query)
+            }
+            MetricQueryExpression::RatioInterval { metric, mut query, confidence } => {
+                query.time_range = time_range;
+                let z = confidence_z(confidence)?;
+                let (numerator, denominator) = ratio_parts(query, |query| engine.sum(&metric, query))?;
+                ratio_interval(numerator, denominator, z)
+            }
+            MetricQueryExpression::Quantiles { metric, mut query, quantiles } => {
+                query.time_range = time_range;
+
+                let mut results = Vec::new();
+                for quantile in quantiles {
+                    match engine.percentile(&metric, query.clone(), quantile_percentile(quantile)?)? {
+                        OperationResult::Value(value) => {
+                            results.push((quantile_group(None, quantile), value));
+                        }
+                        OperationResult::GroupValues(values) => {
+                            results.extend(values.into_iter().map(|(group, value)| (quantile_group(Some(group), quantile), value)));
+                        }
+                        _ => { return Err(MetricsEngineError::UnexpectedResult); }
+                    }
+                }
+
+                Ok(OperationResult::GroupValues(results))
+            }
             MetricQueryExpression::Value(value) => {
                 Ok(OperationResult::Value(Some(value)))
             }
+            MetricQueryExpression::Let { name, value, body } => {
+                let value = evaluate(engine, time_range, join, bindings, *value)?;
+                let mut bindings = bindings.clone();
+                bindings.insert(name, Rc::new(value));
+                evaluate(engine, time_range, join, &bindings, *body)
+            }
+            MetricQueryExpression::Variable(name) => {
+                lookup_variable(bindings, &name)
+            }
+            MetricQueryExpression::Filter { filter, expression } => {
+                Ok(filter_operation_result(&filter, evaluate(engine, time_range, join, bindings, *expression)?))
+            }
             MetricQueryExpression::Arithmetic { operation, left, right } => {
-                let left = evaluate(engine, time_range, *left)?;
-                let right = evaluate(engine, time_range, *right)?;
+                let left = evaluate(engine, time_range, join, bindings, *left)?;
+                let right = evaluate(engine, time_range, join, bindings, *right)?;
 
                 match (left, right) {
                     (OperationResult::Value(left), OperationResult::GroupValues(right)) => {
@@ -101,15 +508,15 @@ pub fn query<T: MetricQueryable>(engine: &T, query: MetricQuery) -> MetricsEngin
                     }
                     (OperationResult::GroupValues(left), OperationResult::GroupValues(right)) => {
                         let left = group_map(left);
-                        let left_groups = group_keys(&left);
                         let right = group_map(right);
-                        let right_groups = group_keys(&right);
 
-                        let transformed_values = left_groups.intersection(&right_groups)
-                            .map(|&group| (
-                                group.clone(),
-                                option_op((&left[group]).clone(), (&right[group]).clone(), |x, y| operation.apply(x, y))
-                            ))
+                        let transformed_values = join.groups(&left, &right)
+                            .into_iter()
+                            .map(|group| {
+                                let left_value = left.get(&group).copied().unwrap_or(join.fill());
+                                let right_value = right.get(&group).copied().unwrap_or(join.fill());
+                                (group, option_op(left_value, right_value, |x, y| operation.apply(x, y)))
+                            })
                             .collect();
                         Ok(OperationResult::GroupValues(sorted_group_values(transformed_values)))
                     }
@@ -120,9 +527,10 @@ pub fn query<T: MetricQueryable>(engine: &T, query: MetricQuery) -> MetricsEngin
                 }
             }
             MetricQueryExpression::Function { function, arguments } => {
+                function.validate_arguments(arguments.len())?;
                 let transformed_arguments = transform_with_result(
                     arguments.into_iter(),
-                    |argument| evaluate(engine, time_range, argument)
+                    |argument| evaluate(engine, time_range, join, bindings, argument)
                 )?;
 
                 if transformed_arguments.is_empty() {
@@ -171,6 +579,30 @@ pub fn query<T: MetricQueryable>(engine: &T, query: MetricQuery) -> MetricsEngin
                     Ok(OperationResult::Value(function.apply(&transformed_arguments)))
                 }
             }
+            MetricQueryExpression::Conditional { condition, value, then, otherwise } => {
+                let mut results = vec![evaluate(engine, time_range, join, bindings, *value)?];
+                for branch in [then, otherwise] {
+                    results.push(match branch {
+                        Some(branch) => evaluate(engine, time_range, join, bindings, *branch)?,
+                        None => OperationResult::Value(None)
+                    });
+                }
+
+                combine_results(results, Alignment::Exact, |values| select_branch(&condition, values))
+            }
+            MetricQueryExpression::AggregateGroups { function, expression } => {
+                match evaluate(engine, time_range, join, bindings, *expression)? {
+                    OperationResult::Value(value) => Ok(OperationResult::Value(function.apply(&value.into_iter().collect::<Vec<_>>()))),
+                    OperationResult::GroupValues(values) => {
+                        let values = values.into_iter().flat_map(|(_, value)| value).collect::<Vec<_>>();
+                        Ok(OperationResult::Value(function.apply(&values)))
+                    }
+                    _ => Err(MetricsEngineError::UnexpectedResult)
+                }
+            }
+            MetricQueryExpression::Offset { offset, expression } => {
+                evaluate(engine, offset_time_range(&time_range, offset)?, join, bindings, *expression)
+            }
         }
     }
 
@@ -179,16 +611,31 @@ pub fn query<T: MetricQueryable>(engine: &T, query: MetricQuery) -> MetricsEngin
         values
     }
 
+    validate_time_range(&query.time_range)?;
+    query.expression.validate(&|metric| engine.metric_type(metric))?;
+
     let output_filter = query.output_filter;
-    match evaluate(engine, query.time_range, query.expression)? {
+    let join = query.join;
+    let mut result = evaluate(engine, query.time_range, join, &Bindings::default(), query.expression)?;
+    if let Some(relabel) = query.relabel.as_ref() {
+        result = relabel.apply(result, Alignment::Exact)?;
+    }
+
+    match result {
         OperationResult::Value(value) => Ok(OperationResult::Value(MetricQuery::apply_filter(output_filter.as_ref(), value))),
         OperationResult::GroupValues(values) => {
+            // Groups without value are only kept when joined, where they are missing in one of the operands
             Ok(
                 OperationResult::GroupValues(
                     values
                         .into_iter()
-                        .map(|(group, value)| (group, MetricQuery::apply_filter(output_filter.as_ref(), value)))
-                        .filter(|(_, value)| value.is_some())
+                        .filter_map(|(group, value)| {
+                            match value {
+                                Some(_) => MetricQuery::apply_filter(output_filter.as_ref(), value).map(|value| (group, Some(value))),
+                                None if join != GroupJoin::Inner => Some((group, None)),
+                                None => None
+                            }
+                        })
                         .collect()
                 )
             )
@@ -198,7 +645,7 @@ pub fn query<T: MetricQueryable>(engine: &T, query: MetricQuery) -> MetricsEngin
 }
 
 pub fn query_in_window<T: MetricQueryable>(engine: &T, query: MetricQuery, duration: Duration) -> MetricsEngineResult<OperationResult> {
-    fn evaluate<T: MetricQueryable>(engine: &T, time_range: TimeRange, duration: Duration, expression: MetricQueryExpression) -> MetricsEngineResult<OperationResult> {
+    fn evaluate<T: MetricQueryable>(engine: &T, time_range: TimeRange, duration: Duration, alignment: Alignment, join: GroupJoin, bindings: &Bindings, expression: MetricQueryExpression) -> MetricsEngineResult<OperationResult> {
         match expression {
             MetricQueryExpression::Average { metric, mut query } => {
                 query.time_range = time_range;
@@ -225,74 +672,185 @@ pub fn query_in_window<T: MetricQueryable>(engine: &T, query: MetricQuery, durat
                 query.remove_empty_datapoints = false;
                 engine.percentile_in_window(&metric, query, duration, percentile)
             }
+            MetricQueryExpression::Last { metric, mut query } => {
+                // The latest value at the end of the time range, broadcasted like a constant
+                query.time_range = time_range;
+                engine.last(&metric, query)
+            }
+            MetricQueryExpression::Count { metric, mut query } => {
+                query.time_range = time_range;
+                query.remove_empty_datapoints = false;
+                engine.sum_in_window(&metric, query, duration)
+            }
+            MetricQueryExpression::Rate { metric, mut query } => {
+                query.time_range = time_range;
+                query.remove_empty_datapoints = false;
+                let seconds = duration.as_secs_f64();
+                Ok(engine.sum_in_window(&metric, query, duration)?.map_values(|value| value / seconds))
+            }
+            MetricQueryExpression::Numerator { metric, mut query } => {
+                query.time_range = time_range;
+                query.remove_empty_datapoints = false;
+                query.output_transform = Some(TransformExpression::InputNumerator);
+                engine.sum_in_window(&metric, query, duration)
+            }
+            MetricQueryExpression::Denominator { metric, mut query } => {
+                query.time_range = time_range;
+                query.remove_empty_datapoints = false;
+                query.output_transform = Some(TransformExpression::InputDenominator);
+                engine.sum_in_window(&metric, query, duration)
+            }
+            MetricQueryExpression::Datapoints { metric, mut query } => {
+                query.time_range = time_range;
+                query.remove_empty_datapoints = false;
+                engine.datapoints_in_window(&metric, query, duration)
+            }
+            MetricQueryExpression::RatioInterval { metric, mut query, confidence } => {
+                query.time_range = time_range;
+                query.remove_empty_datapoints = false;
+                let z = confidence_z(confidence)?;
+                let (numerator, denominator) = ratio_parts(query, |query| engine.sum_in_window(&metric, query, duration))?;
+                ratio_interval(numerator, denominator, z)
+            }
+            MetricQueryExpression::Quantiles { metric, mut query, quantiles } => {
+                query.time_range = time_range;
+                query.remove_empty_datapoints = false;
+
+                let mut results = Vec::new();
+                for quantile in quantiles {
+                    match engine.percentile_in_window(&metric, query.clone(), duration, quantile_percentile(quantile)?)? {
+                        OperationResult::TimeValues(values) => {
+                            results.push((quantile_group(None, quantile), values));
+                        }
+                        OperationResult::GroupTimeValues(values) => {
+                            results.extend(values.into_iter().map(|(group, values)| (quantile_group(Some(group), quantile), values)));
+                        }
+                        _ => { return Err(MetricsEngineError::UnexpectedResult); }
+                    }
+                }
+
+                Ok(OperationResult::GroupTimeValues(results))
+            }
             MetricQueryExpression::Value(value) => {
                 Ok(OperationResult::Value(Some(value)))
             }
+            MetricQueryExpression::Let { name, value, body } => {
+                let value = evaluate(engine, time_range, duration, alignment, join, bindings, *value)?;
+                let mut bindings = bindings.clone();
+                bindings.insert(name, Rc::new(value));
+                evaluate(engine, time_range, duration, alignment, join, &bindings, *body)
+            }
+            MetricQueryExpression::Variable(name) => {
+                lookup_variable(bindings, &name)
+            }
+            MetricQueryExpression::Filter { filter, expression } => {
+                Ok(filter_operation_result(&filter, evaluate(engine, time_range, duration, alignment, join, bindings, *expression)?))
+            }
             MetricQueryExpression::Arithmetic { operation, left, right } => {
-                let left = evaluate(engine, time_range, duration, *left)?;
-                let right = evaluate(engine, time_range, duration, *right)?;
+                let left = evaluate(engine, time_range, duration, alignment, join, bindings, *left)?;
+                let right = evaluate(engine, time_range, duration, alignment, join, bindings, *right)?;
 
                 match (left, right) {
                     (OperationResult::TimeValues(left), OperationResult::TimeValues(right)) => {
-                        Ok(OperationResult::TimeValues(transform_time_values(&left, &right, |x, y| operation.apply(x, y))))
+                        Ok(OperationResult::TimeValues(transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
                     },
                     (OperationResult::TimeValues(left), OperationResult::Value(right)) => {
                         let right = constant_time_values(&left, right);
-                        Ok(OperationResult::TimeValues(transform_time_values(&left, &right, |x, y| operation.apply(x, y))))
+                        Ok(OperationResult::TimeValues(transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
                     }
                     (OperationResult::Value(left), OperationResult::TimeValues(right)) => {
                         let left = constant_time_values(&right, left);
-                        Ok(OperationResult::TimeValues(transform_time_values(&left, &right, |x, y| operation.apply(x, y))))
+                        Ok(OperationResult::TimeValues(transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
                     }
                     (OperationResult::Value(left), OperationResult::Value(right)) => {
                         Ok(OperationResult::Value(option_op(left, right, |x, y| operation.apply(x, y))))
                     }
                     (OperationResult::GroupTimeValues(left), OperationResult::GroupTimeValues(right)) => {
                         let left = group_map(left);
-                        let left_groups = group_keys(&left);
                         let right = group_map(right);
-                        let right_groups = group_keys(&right);
-
-                        let transformed_values = left_groups.intersection(&right_groups)
-                            .map(|&group| {
-                                if let (Some(left), Some(right)) = (left.get(group), right.get(group)) {
-                                    Some((group.to_owned(), transform_time_values(left, right, |x, y| operation.apply(x, y))))
-                                } else {
-                                    None
-                                }
-                            })
-                            .flatten()
-                            .collect();
+
+                        let transformed_values = transform_with_result::<_, _, MetricsEngineError>(
+                            join.groups(&left, &right).into_iter(),
+                            |group| {
+                                let (left, right) = match (left.get(&group), right.get(&group)) {
+                                    (Some(left), Some(right)) => (left.clone(), right.clone()),
+                                    (Some(left), None) => (left.clone(), constant_time_values(left, join.fill())),
+                                    (None, Some(right)) => (constant_time_values(right, join.fill()), right.clone()),
+                                    (None, None) => (Vec::new(), Vec::new())
+                                };
+
+                                Ok((group, transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
+                            }
+                        )?;
                         Ok(OperationResult::GroupTimeValues(sorted_time_group_values(transformed_values)))
                     }
                     (OperationResult::GroupTimeValues(left), OperationResult::Value(right)) => {
-                        let transformed_values = left
-                            .into_iter()
-                            .map(|(group, left)| {
+                        let transformed_values = transform_with_result::<_, _, MetricsEngineError>(
+                            left.into_iter(),
+                            |(group, left)| {
                                 let right = constant_time_values(&left, right);
-                                (group, transform_time_values(&left, &right, |x, y| operation.apply(x, y)))
-                            })
-                            .collect();
+                                Ok((group, transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
+                            }
+                        )?;
                         Ok(OperationResult::GroupTimeValues(sorted_time_group_values(transformed_values)))
                     }
                     (OperationResult::Value(left), OperationResult::GroupTimeValues(right)) => {
-                        let transformed_values = right
-                            .into_iter()
-                            .map(|(group, right)| {
+                        let transformed_values = transform_with_result::<_, _, MetricsEngineError>(
+                            right.into_iter(),
+                            |(group, right)| {
                                 let left = constant_time_values(&right, left);
-                                (group, transform_time_values(&left, &right, |x, y| operation.apply(x, y)))
-                            })
-                            .collect();
+                                Ok((group, transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
+                            }
+                        )?;
+                        Ok(OperationResult::GroupTimeValues(sorted_time_group_values(transformed_values)))
+                    }
+                    (OperationResult::GroupTimeValues(left), OperationResult::TimeValues(right)) => {
+                        // The series is used for each group
+                        let transformed_values = transform_with_result::<_, _, MetricsEngineError>(
+                            left.into_iter(),
+                            |(group, left)| Ok((group, transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
+                        )?;
+                        Ok(OperationResult::GroupTimeValues(sorted_time_group_values(transformed_values)))
+                    }
+                    (OperationResult::TimeValues(left), OperationResult::GroupTimeValues(right)) => {
+                        let transformed_values = transform_with_result::<_, _, MetricsEngineError>(
+                            right.into_iter(),
+                            |(group, right)| Ok((group, transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
+                        )?;
+                        Ok(OperationResult::GroupTimeValues(sorted_time_group_values(transformed_values)))
+                    }
+                    (OperationResult::GroupTimeValues(left), OperationResult::GroupValues(right)) => {
+                        // The value of each group is broadcasted over the windows of the group
+                        let right = group_map(right);
+                        let transformed_values = transform_with_result::<_, _, MetricsEngineError>(
+                            left.into_iter().filter(|(group, _)| join != GroupJoin::Inner || right.contains_key(group)),
+                            |(group, left)| {
+                                let right = constant_time_values(&left, right.get(&group).copied().unwrap_or(join.fill()));
+                                Ok((group, transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
+                            }
+                        )?;
+                        Ok(OperationResult::GroupTimeValues(sorted_time_group_values(transformed_values)))
+                    }
+                    (OperationResult::GroupValues(left), OperationResult::GroupTimeValues(right)) => {
+                        let left = group_map(left);
+                        let transformed_values = transform_with_result::<_, _, MetricsEngineError>(
+                            right.into_iter().filter(|(group, _)| join != GroupJoin::Inner || left.contains_key(group)),
+                            |(group, right)| {
+                                let left = constant_time_values(&right, left.get(&group).copied().unwrap_or(join.fill()));
+                                Ok((group, transform_time_values(&left, &right, alignment, |x, y| operation.apply(x, y))?))
+                            }
+                        )?;
                         Ok(OperationResult::GroupTimeValues(sorted_time_group_values(transformed_values)))
                     }
                     _ => { return Err(MetricsEngineError::UnexpectedResult); }
                 }
             }
             MetricQueryExpression::Function { function, arguments } => {
+                function.validate_arguments(arguments.len())?;
                 let num_arguments = arguments.len();
                 let transformed_arguments = transform_with_result(
                     arguments.into_iter(),
-                    |argument| evaluate(engine, time_range, duration, argument)
+                    |argument| evaluate(engine, time_range, duration, alignment, join, bindings, argument)
                 )?;
 
                 let num_windows = transformed_arguments
@@ -317,12 +875,18 @@ pub fn query_in_window<T: MetricQueryable>(engine: &T, query: MetricQuery, durat
 
                     let mut results = Vec::new();
                     for group in overlapping_groups {
+                        let reference = &transformed_arguments[0][&group];
+                        let group_arguments = transform_with_result(
+                            transformed_arguments.iter(),
+                            |argument| align_time_values(reference, &argument[&group], alignment)
+                        )?;
+
                         let mut group_results = Vec::new();
-                        for window_index in 0..num_windows {
-                            let time = transformed_arguments[0][&group][window_index].0;
-                            let this_window_transformed_arguments = transformed_arguments
+                        for window_index in 0..reference.len() {
+                            let time = reference[window_index].0;
+                            let this_window_transformed_arguments = group_arguments
                                 .iter()
-                                .map(|windows_argument| windows_argument[&group][window_index].1)
+                                .map(|windows_argument| windows_argument[window_index].1)
                                 .flatten()
                                 .collect::<Vec<_>>();
 
@@ -341,6 +905,11 @@ pub fn query_in_window<T: MetricQueryable>(engine: &T, query: MetricQuery, durat
                         |argument| argument.time_values().ok_or_else(|| MetricsEngineError::UnexpectedResult)
                     )?;
 
+                    let transformed_arguments = transform_with_result(
+                        transformed_arguments.iter(),
+                        |argument| align_time_values(&transformed_arguments[0], argument, alignment)
+                    )?;
+
                     let mut results = Vec::new();
                     for window_index in 0..num_windows {
                         let time = transformed_arguments[0][window_index].0;
@@ -358,24 +927,67 @@ pub fn query_in_window<T: MetricQueryable>(engine: &T, query: MetricQuery, durat
                     Ok(OperationResult::TimeValues(results))
                 }
             }
+            MetricQueryExpression::Conditional { condition, value, then, otherwise } => {
+                let mut results = vec![evaluate(engine, time_range, duration, alignment, join, bindings, *value)?];
+                for branch in [then, otherwise] {
+                    results.push(match branch {
+                        Some(branch) => evaluate(engine, time_range, duration, alignment, join, bindings, *branch)?,
+                        None => OperationResult::Value(None)
+                    });
+                }
+
+                combine_results(results, alignment, |values| select_branch(&condition, values))
+            }
+            MetricQueryExpression::AggregateGroups { function, expression } => {
+                let groups = match evaluate(engine, time_range, duration, alignment, join, bindings, *expression)? {
+                    OperationResult::TimeValues(values) => vec![values],
+                    OperationResult::GroupTimeValues(values) => values.into_iter().map(|(_, values)| values).collect(),
+                    _ => { return Err(MetricsEngineError::UnexpectedResult); }
+                };
+
+                if groups.is_empty() {
+                    return Ok(OperationResult::TimeValues(Vec::new()));
+                }
+
+                let reference = &groups[0];
+                let groups = transform_with_result(
+                    groups.iter(),
+                    |values| align_time_values(reference, values, alignment)
+                )?;
+
+                let mut results = Vec::new();
+                for (window_index, (time, _)) in reference.iter().enumerate() {
+                    let values = groups
+                        .iter()
+                        .filter_map(|values| values[window_index].1)
+                        .collect::<Vec<_>>();
+                    results.push((*time, function.apply(&values)));
+                }
+
+                Ok(OperationResult::TimeValues(results))
+            }
+            MetricQueryExpression::Offset { offset, expression } => {
+                let shift = |values: TimeValues| values.into_iter().map(|(time, value)| (time + offset, value)).collect::<Vec<_>>();
+                match evaluate(engine, offset_time_range(&time_range, offset)?, duration, alignment, join, bindings, *expression)? {
+                    OperationResult::TimeValues(values) => Ok(OperationResult::TimeValues(shift(values))),
+                    OperationResult::GroupTimeValues(values) => {
+                        Ok(OperationResult::GroupTimeValues(values.into_iter().map(|(group, values)| (group, shift(values))).collect()))
+                    }
+                    result => Ok(result)
+                }
+            }
         }
     }
 
-    fn transform_time_values(left: &TimeValues, right: &TimeValues, op: impl Fn(f64, f64) -> f64) -> TimeValues {
-        let mut results = Vec::new();
-        for ((left_time, left_value), (right_time, right_value)) in left.iter().zip(right.iter()) {
-            assert_eq!(left_time, right_time);
-
-            let result = if let (Some(left), Some(right)) = (left_value, right_value) {
-                Some(op(*left, *right))
-            } else {
-                None
-            };
+    fn transform_time_values(left: &TimeValues, right: &TimeValues, alignment: Alignment, op: impl Fn(f64, f64) -> f64) -> MetricsEngineResult<TimeValues> {
+        let right = align_time_values(left, right, alignment)?;
 
-            results.push((*left_time, result));
+        let mut results = Vec::new();
+        for ((left_time, left_value), (_, right_value)) in left.iter().zip(right.iter()) {
+            results.push((*left_time, option_op(*left_value, *right_value, &op)));
         }
 
-        results
+        Ok(results)
     }
 
     fn constant_time_values(time_values: &TimeValues, constant: Option<f64>) -> TimeValues {
@@ -395,15 +1007,36 @@ pub fn query_in_window<T: MetricQueryable>(engine: &T, query: MetricQuery, durat
         values
     }
 
+    validate_time_range(&query.time_range)?;
+    validate_duration(duration)?;
+    query.expression.validate(&|metric| engine.metric_type(metric))?;
+    let (max_points, mode) = (query.max_points, query.downsampling);
+    let duration = match mode {
+        Downsampling::WidenWindow => widen_duration(&query.time_range, duration, max_points)?,
+        Downsampling::Lttb => duration
+    };
+
+    let downsample = |time_values: TimeValues| {
+        match (mode, max_points) {
+            (Downsampling::Lttb, Some(max_points)) => downsampling::lttb(time_values, max_points),
+            _ => time_values
+        }
+    };
+
     let output_filter = query.output_filter;
-    match evaluate(engine, query.time_range, duration, query.expression)? {
-        OperationResult::TimeValues(time_values) => Ok(OperationResult::TimeValues(filter_time_values(output_filter.as_ref(), time_values))),
+    let mut result = evaluate(engine, query.time_range, duration, query.alignment, query.join, &Bindings::default(), query.expression)?;
+    if let Some(relabel) = query.relabel.as_ref() {
+        result = relabel.apply(result, query.alignment)?;
+    }
+
+    match result {
+        OperationResult::TimeValues(time_values) => Ok(OperationResult::TimeValues(downsample(filter_time_values(output_filter.as_ref(), time_values)))),
         OperationResult::GroupTimeValues(group_time_values) => {
             Ok(
                 OperationResult::GroupTimeValues(
                     group_time_values
                         .into_iter()
-                        .map(|(group, time_values)| (group, filter_time_values(output_filter.as_ref(), time_values)))
+                        .map(|(group, time_values)| (group, downsample(filter_time_values(output_filter.as_ref(), time_values))))
                         .filter(|(_, values)| !values.is_empty())
                         .collect()
                 )
@@ -413,44 +1046,329 @@ pub fn query_in_window<T: MetricQueryable>(engine: &T, query: MetricQuery, durat
     }
 }
 
-pub trait MetricQueryable {
-    fn average(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
-    fn sum(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
-    fn max(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
-    fn min(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
-    fn percentile(&self, metric: &str, query: Query, percentile: i32) -> MetricsEngineResult<OperationResult>;
-
-    fn average_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
-    fn sum_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
-    fn max_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
-    fn min_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
-    fn percentile_in_window(&self, metric: &str, query: Query, duration: Duration, percentile: i32) -> MetricsEngineResult<OperationResult>;
-}
-
-impl MetricQueryable for MetricsEngine {
-    fn average(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        self.average(metric, query)
+impl MetricQueryExpression {
+    /// The ratio between the two expressions.
+    pub fn ratio(numerator: MetricQueryExpression, denominator: MetricQueryExpression) -> MetricQueryExpression {
+        MetricQueryExpression::Arithmetic {
+            operation: ArithmeticOperation::Divide,
+            left: Box::new(numerator),
+            right: Box::new(denominator)
+        }
     }
 
-    fn sum(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        self.sum(metric, query)
+    /// The percentage of each group of the total over all groups.
+    pub fn percent_of_total(expression: MetricQueryExpression) -> MetricQueryExpression {
+        MetricQueryExpression::Arithmetic {
+            operation: ArithmeticOperation::Multiply,
+            left: Box::new(MetricQueryExpression::Value(100.0)),
+            right: Box::new(
+                MetricQueryExpression::ratio(
+                    expression.clone(),
+                    MetricQueryExpression::AggregateGroups { function: AggregateFunction::Sum, expression: Box::new(expression) }
+                )
+            )
+        }
     }
 
-    fn max(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        self.max(metric, query)
+    /// The change of the expression compared to the period (in seconds) before.
+    pub fn delta(expression: MetricQueryExpression, period: f64) -> MetricQueryExpression {
+        MetricQueryExpression::Arithmetic {
+            operation: ArithmeticOperation::Subtract,
+            left: Box::new(expression.clone()),
+            right: Box::new(MetricQueryExpression::Offset { offset: period, expression: Box::new(expression) })
+        }
     }
 
-    fn min(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
-        self.min(metric, query)
+    /// The metrics used in the expression.
+    pub fn metrics(&self) -> Vec<String> {
+        let mut metrics = Vec::new();
+        self.for_each_query(&mut |metric, _| {
+            if !metrics.iter().any(|current| current == metric) {
+                metrics.push(metric.to_owned());
+            }
+        });
+        metrics
     }
 
-    fn percentile(&self, metric: &str, query: Query, percentile: i32) -> MetricsEngineResult<OperationResult> {
-        self.percentile(metric, query, percentile)
-    }
+    /// Validates the whole expression before anything is evaluated, where the metric queries are validated for the type
+    /// of their metric.
+    pub fn validate(&self, metric_type: &impl Fn(&str) -> MetricsEngineResult<MetricType>) -> MetricsEngineResult<()> {
+        let validate_ratio_part = |metric: &str, query: &Query| {
+            let metric_type = metric_type(metric)?;
+            if metric_type != MetricType::Ratio {
+                return Err(MetricsEngineError::WrongMetricType(metric.to_owned()));
+            }
 
-    fn average_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
-        self.average_in_window(metric, query, duration)
-    }
+            if query.output_transform.is_some() {
+                return Err(QueryError::OutputTransformNotSupported.into());
+            }
+
+            Ok(query.validate_for(&metric_type)?)
+        };
+
+        match self {
+            MetricQueryExpression::Average { metric, query }
+            | MetricQueryExpression::Sum { metric, query }
+            | MetricQueryExpression::Max { metric, query }
+            | MetricQueryExpression::Min { metric, query }
+            | MetricQueryExpression::Percentile { metric, query, .. }
+            | MetricQueryExpression::Last { metric, query }
+            | MetricQueryExpression::Count { metric, query }
+            | MetricQueryExpression::Rate { metric, query }
+            | MetricQueryExpression::Datapoints { metric, query } => {
+                query.validate_for(&metric_type(metric)?)?;
+            }
+            MetricQueryExpression::Numerator { metric, query } | MetricQueryExpression::Denominator { metric, query } => {
+                validate_ratio_part(metric, query)?;
+            }
+            MetricQueryExpression::RatioInterval { metric, query, confidence } => {
+                confidence_z(*confidence)?;
+                validate_ratio_part(metric, query)?;
+            }
+            MetricQueryExpression::Quantiles { metric, query, quantiles } => {
+                for quantile in quantiles {
+                    quantile_percentile(*quantile)?;
+                }
+
+                query.validate_for(&metric_type(metric)?)?;
+            }
+            MetricQueryExpression::Value(_) | MetricQueryExpression::Variable(_) => {}
+            MetricQueryExpression::Let { value, body, .. } => {
+                value.validate(metric_type)?;
+                body.validate(metric_type)?;
+            }
+            MetricQueryExpression::Filter { expression, .. }
+            | MetricQueryExpression::AggregateGroups { expression, .. }
+            | MetricQueryExpression::Offset { expression, .. } => {
+                expression.validate(metric_type)?;
+            }
+            MetricQueryExpression::Arithmetic { left, right, .. } => {
+                left.validate(metric_type)?;
+                right.validate(metric_type)?;
+            }
+            MetricQueryExpression::Function { function, arguments } => {
+                function.validate_arguments(arguments.len())?;
+                for argument in arguments {
+                    argument.validate(metric_type)?;
+                }
+            }
+            MetricQueryExpression::Conditional { value, then, otherwise, .. } => {
+                value.validate(metric_type)?;
+                for branch in [then, otherwise].into_iter().flatten() {
+                    branch.validate(metric_type)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the read consistency of all metric queries in the expression.
+    pub fn set_consistency(&mut self, consistency: ReadConsistency) {
+        self.for_each_query_mut(&mut |query| query.consistency = consistency);
+    }
+
+    fn for_each_query(&self, apply: &mut impl FnMut(&str, &Query)) {
+        match self {
+            MetricQueryExpression::Average { metric, query }
+            | MetricQueryExpression::Sum { metric, query }
+            | MetricQueryExpression::Max { metric, query }
+            | MetricQueryExpression::Min { metric, query }
+            | MetricQueryExpression::Percentile { metric, query, .. }
+            | MetricQueryExpression::Last { metric, query }
+            | MetricQueryExpression::Count { metric, query }
+            | MetricQueryExpression::Rate { metric, query }
+            | MetricQueryExpression::Numerator { metric, query }
+            | MetricQueryExpression::Denominator { metric, query }
+            | MetricQueryExpression::Datapoints { metric, query }
+            | MetricQueryExpression::RatioInterval { metric, query, .. }
+            | MetricQueryExpression::Quantiles { metric, query, .. } => {
+                apply(metric, query);
+            }
+            MetricQueryExpression::Value(_) | MetricQueryExpression::Variable(_) => {}
+            MetricQueryExpression::Let { value, body, .. } => {
+                value.for_each_query(apply);
+                body.for_each_query(apply);
+            }
+            MetricQueryExpression::Filter { expression, .. } => {
+                expression.for_each_query(apply);
+            }
+            MetricQueryExpression::Arithmetic { left, right, .. } => {
+                left.for_each_query(apply);
+                right.for_each_query(apply);
+            }
+            MetricQueryExpression::Function { arguments, .. } => {
+                for argument in arguments {
+                    argument.for_each_query(apply);
+                }
+            }
+            MetricQueryExpression::Conditional { value, then, otherwise, .. } => {
+                value.for_each_query(apply);
+                for branch in [then, otherwise].into_iter().flatten() {
+                    branch.for_each_query(apply);
+                }
+            }
+            MetricQueryExpression::AggregateGroups { expression, .. } | MetricQueryExpression::Offset { expression, .. } => {
+                expression.for_each_query(apply);
+            }
+        }
+    }
+
+    fn for_each_query_mut(&mut self, apply: &mut impl FnMut(&mut Query)) {
+        match self {
+            MetricQueryExpression::Average { query, .. }
+            | MetricQueryExpression::Sum { query, .. }
+            | MetricQueryExpression::Max { query, .. }
+            | MetricQueryExpression::Min { query, .. }
+            | MetricQueryExpression::Percentile { query, .. }
+            | MetricQueryExpression::Last { query, .. }
+            | MetricQueryExpression::Count { query, .. }
+            | MetricQueryExpression::Rate { query, .. }
+            | MetricQueryExpression::Numerator { query, .. }
+            | MetricQueryExpression::Denominator { query, .. }
+            | MetricQueryExpression::Datapoints { query, .. }
+            | MetricQueryExpression::RatioInterval { query, .. }
+            | MetricQueryExpression::Quantiles { query, .. } => {
+                apply(query);
+            }
+            MetricQueryExpression::Value(_) | MetricQueryExpression::Variable(_) => {}
+            MetricQueryExpression::Let { value, body, .. } => {
+                value.for_each_query_mut(apply);
+                body.for_each_query_mut(apply);
+            }
+            MetricQueryExpression::Filter { expression, .. } => {
+                expression.for_each_query_mut(apply);
+            }
+            MetricQueryExpression::Arithmetic { left, right, .. } => {
+                left.for_each_query_mut(apply);
+                right.for_each_query_mut(apply);
+            }
+            MetricQueryExpression::Function { arguments, .. } => {
+                for argument in arguments {
+                    argument.for_each_query_mut(apply);
+                }
+            }
+            MetricQueryExpression::Conditional { value, then, otherwise, .. } => {
+                value.for_each_query_mut(apply);
+                for branch in [then, otherwise].into_iter().flatten() {
+                    branch.for_each_query_mut(apply);
+                }
+            }
+            MetricQueryExpression::AggregateGroups { expression, .. } | MetricQueryExpression::Offset { expression, .. } => {
+                expression.for_each_query_mut(apply);
+            }
+        }
+    }
+}
+
+/// Splits the expression into one expression per group, such that the result of each group can be computed separately.
+/// Returns None if the metrics of the expression are not all grouped by the same key, or if the result of a group
+/// depends on the other groups.
+pub fn split_by_group(expression: &MetricQueryExpression,
+                      group_values: impl Fn(&str, &Query, &GroupKey) -> MetricsEngineResult<Vec<Vec<Tag>>>) -> MetricsEngineResult<Option<Vec<MetricQueryExpression>>> {
+    fn aggregates_groups(expression: &MetricQueryExpression) -> bool {
+        match expression {
+            MetricQueryExpression::AggregateGroups { .. } => true,
+            MetricQueryExpression::Let { value, body, .. } => aggregates_groups(value) || aggregates_groups(body),
+            MetricQueryExpression::Filter { expression, .. } | MetricQueryExpression::Offset { expression, .. } => aggregates_groups(expression),
+            MetricQueryExpression::Arithmetic { left, right, .. } => aggregates_groups(left) || aggregates_groups(right),
+            MetricQueryExpression::Function { arguments, .. } => arguments.iter().any(aggregates_groups),
+            MetricQueryExpression::Conditional { value, then, otherwise, .. } => {
+                aggregates_groups(value) || [then, otherwise].into_iter().flatten().any(|branch| aggregates_groups(branch))
+            }
+            _ => false
+        }
+    }
+
+    let mut queries = Vec::new();
+    expression.for_each_query(&mut |metric, query| queries.push((metric.to_owned(), query.clone())));
+
+    let group_key = match queries.first().and_then(|(_, query)| query.group_by.clone()) {
+        Some(group_key) => group_key,
+        None => { return Ok(None); }
+    };
+
+    // An and clause with the group can't be added to these filters, and group limits rank the groups against each other
+    let splittable = queries.iter().all(|(_, query)| {
+        query.group_by.as_ref() == Some(&group_key) && !matches!(query.tags_filter, TagsFilter::OrAnd(_, _)) && query.group_limit.is_none()
+    });
+
+    if !splittable || aggregates_groups(expression) {
+        return Ok(None);
+    }
+
+    let mut groups = Vec::new();
+    for (metric, query) in &queries {
+        groups.extend(group_values(metric, query, &group_key)?.into_iter().map(|tags| (GroupValue::from_tags(&tags), tags)));
+    }
+    groups.sort_by(|x, y| x.0.cmp(&y.0));
+    groups.dedup_by(|x, y| x.0 == y.0);
+
+    let expressions = groups
+        .into_iter()
+        .map(|(_, tags)| {
+            let mut expression = expression.clone();
+            expression.for_each_query_mut(&mut |query| {
+                let tags_filter = std::mem::replace(&mut query.tags_filter, TagsFilter::None);
+                query.tags_filter = tags_filter.add_and_clause(tags.clone());
+            });
+            expression
+        })
+        .collect();
+    Ok(Some(expressions))
+}
+
+pub trait MetricQueryable {
+    fn average(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
+    fn sum(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
+    fn max(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
+    fn min(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
+    fn percentile(&self, metric: &str, query: Query, percentile: i32) -> MetricsEngineResult<OperationResult>;
+    fn last(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
+    fn datapoints(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult>;
+
+    fn average_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
+    fn sum_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
+    fn max_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
+    fn min_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
+    fn percentile_in_window(&self, metric: &str, query: Query, duration: Duration, percentile: i32) -> MetricsEngineResult<OperationResult>;
+    fn datapoints_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult>;
+
+    fn metric_type(&self, metric: &str) -> MetricsEngineResult<MetricType>;
+}
+
+impl MetricQueryable for MetricsEngine {
+    fn average(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.average(metric, query)
+    }
+
+    fn sum(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.sum(metric, query)
+    }
+
+    fn max(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.max(metric, query)
+    }
+
+    fn min(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.min(metric, query)
+    }
+
+    fn percentile(&self, metric: &str, query: Query, percentile: i32) -> MetricsEngineResult<OperationResult> {
+        self.percentile(metric, query, percentile)
+    }
+
+    fn last(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.last(metric, query)
+    }
+
+    fn datapoints(&self, metric: &str, query: Query) -> MetricsEngineResult<OperationResult> {
+        self.datapoints(metric, query)
+    }
+
+    fn average_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        self.average_in_window(metric, query, duration)
+    }
 
     fn sum_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
         self.sum_in_window(metric, query, duration)
@@ -467,14 +1385,335 @@ impl MetricQueryable for MetricsEngine {
     fn percentile_in_window(&self, metric: &str, query: Query, duration: Duration, percentile: i32) -> MetricsEngineResult<OperationResult> {
         self.percentile_in_window(metric, query, duration, percentile)
     }
+
+    fn datapoints_in_window(&self, metric: &str, query: Query, duration: Duration) -> MetricsEngineResult<OperationResult> {
+        self.datapoints_in_window(metric, query, duration)
+    }
+
+    fn metric_type(&self, metric: &str) -> MetricsEngineResult<MetricType> {
+        self.metric_type(metric)
+    }
 }
 
-fn group_map<T>(values: Vec<(GroupValue, T)>) -> FnvHashMap<GroupValue, T> {
-    FnvHashMap::from_iter(values.into_iter())
+/// Windows are kept as missing values so that the result still lines up with other operands.
+fn filter_operation_result(filter: &FilterExpression, result: OperationResult) -> OperationResult {
+    let filter_time_values = |values: TimeValues| {
+        values
+            .into_iter()
+            .map(|(time, value)| (time, MetricQuery::apply_filter(Some(filter), value)))
+            .collect::<Vec<_>>()
+    };
+
+    match result {
+        OperationResult::Value(value) => OperationResult::Value(MetricQuery::apply_filter(Some(filter), value)),
+        OperationResult::TimeValues(values) => OperationResult::TimeValues(filter_time_values(values)),
+        OperationResult::GroupValues(values) => {
+            OperationResult::GroupValues(
+                values
+                    .into_iter()
+                    .map(|(group, value)| (group, MetricQuery::apply_filter(Some(filter), value)))
+                    .filter(|(_, value)| value.is_some())
+                    .collect()
+            )
+        }
+        OperationResult::GroupTimeValues(values) => {
+            OperationResult::GroupTimeValues(
+                values
+                    .into_iter()
+                    .map(|(group, values)| (group, filter_time_values(values)))
+                    .collect()
+            )
+        }
+        result => result
+    }
+}
+
+type Bindings = FnvHashMap<String, Rc<OperationResult>>;
+
+/// Aligns the values to the windows of the reference.
+fn align_time_values(reference: &TimeValues, values: &TimeValues, alignment: Alignment) -> MetricsEngineResult<TimeValues> {
+    let lines_up = reference.len() == values.len() && reference.iter().zip(values.iter()).all(|((x, _), (y, _))| x == y);
+    if lines_up {
+        return Ok(values.clone());
+    }
+
+    match alignment {
+        Alignment::Exact => {
+            Err(MetricsEngineError::InvalidQueryInput("The windows of the operands do not line up.".to_owned()))
+        }
+        Alignment::Nearest => {
+            Ok(
+                reference
+                    .iter()
+                    .map(|&(time, _)| {
+                        let index = values.partition_point(|(value_time, _)| *value_time < time);
+                        let nearest = match (index.checked_sub(1).map(|index| &values[index]), values.get(index)) {
+                            (Some(before), Some(after)) => if time - before.0 <= after.0 - time { before } else { after },
+                            (Some(before), None) => before,
+                            (None, Some(after)) => after,
+                            (None, None) => { return (time, None); }
+                        };
+
+                        (time, nearest.1)
+                    })
+                    .collect()
+            )
+        }
+        Alignment::Linear => {
+            let points = values.iter().flat_map(|&(time, value)| Some((time, value?))).collect::<Vec<_>>();
+            Ok(
+                reference
+                    .iter()
+                    .map(|&(time, _)| {
+                        let index = points.partition_point(|(point_time, _)| *point_time < time);
+                        let value = match (index.checked_sub(1).map(|index| points[index]), points.get(index)) {
+                            (_, Some(&(after_time, after_value))) if after_time == time => Some(after_value),
+                            (Some((before_time, before_value)), Some(&(after_time, after_value))) => {
+                                let fraction = (time - before_time) / (after_time - before_time);
+                                Some(before_value + fraction * (after_value - before_value))
+                            }
+                            _ => None
+                        };
+
+                        (time, value)
+                    })
+                    .collect()
+            )
+        }
+    }
+}
+
+fn select_branch(condition: &FilterExpression, values: &[Option<f64>]) -> Option<f64> {
+    if condition.evaluate(&ExpressionValue::Float(values[0]?))? {
+        values[1]
+    } else {
+        values[2]
+    }
+}
+
+enum CombinedSeries {
+    Constant(Option<f64>),
+    Single(TimeValues),
+    Grouped(FnvHashMap<GroupValue, TimeValues>)
+}
+
+/// Combines the results value by value, where constants are broadcasted over the windows and groups of the other results.
+/// Only the groups present in all grouped results are kept.
+fn combine_results(results: Vec<OperationResult>,
+                   alignment: Alignment,
+                   apply: impl Fn(&[Option<f64>]) -> Option<f64>) -> MetricsEngineResult<OperationResult> {
+    let windowed = results.iter().any(|result| result.num_windows().is_some());
+    let grouped = results.iter().any(|result| result.is_group_values() || result.is_group_time_values());
+
+    let mut all_series = Vec::new();
+    for result in results {
+        all_series.push(match result {
+            OperationResult::Value(value) => CombinedSeries::Constant(value),
+            OperationResult::TimeValues(values) => CombinedSeries::Single(values),
+            OperationResult::GroupValues(values) if !windowed => {
+                CombinedSeries::Grouped(values.into_iter().map(|(group, value)| (group, vec![(0.0, value)])).collect())
+            }
+            OperationResult::GroupTimeValues(values) => CombinedSeries::Grouped(group_map(values)),
+            _ => { return Err(MetricsEngineError::UnexpectedResult); }
+        });
+    }
+
+    let grouped_series = all_series
+        .iter()
+        .flat_map(|series| match series {
+            CombinedSeries::Grouped(groups) => Some(groups.clone()),
+            _ => None
+        })
+        .collect::<Vec<_>>();
+    let mut groups = if grouped_series.is_empty() { Vec::new() } else { Vec::from_iter(get_overlapping_groups(&grouped_series)) };
+    groups.sort();
+
+    let combine_group = |group: Option<&GroupValue>| -> MetricsEngineResult<TimeValues> {
+        let group_series = all_series
+            .iter()
+            .map(|series| match series {
+                CombinedSeries::Constant(_) => None,
+                CombinedSeries::Single(values) => Some(values),
+                CombinedSeries::Grouped(groups) => group.and_then(|group| groups.get(group))
+            })
+            .collect::<Vec<_>>();
+
+        // The windows of the first windowed result are used
+        let reference = group_series.iter().flatten().next().map(|values| (*values).clone());
+        let windows = match &reference {
+            Some(values) => values.iter().map(|(time, _)| *time).collect::<Vec<_>>(),
+            None => vec![0.0]
+        };
+
+        let group_series = transform_with_result(
+            group_series.into_iter(),
+            |series| match (series, &reference) {
+                (Some(values), Some(reference)) => align_time_values(reference, values, alignment).map(Some),
+                _ => Ok(None)
+            }
+        )?;
+
+        let mut combined = Vec::new();
+        for (window_index, time) in windows.iter().enumerate() {
+            let values = all_series
+                .iter()
+                .zip(group_series.iter())
+                .map(|(series, values)| match (series, values) {
+                    (CombinedSeries::Constant(value), _) => *value,
+                    (_, Some(values)) => values[window_index].1,
+                    (_, None) => None
+                })
+                .collect::<Vec<_>>();
+
+            combined.push((*time, apply(&values)));
+        }
+
+        Ok(combined)
+    };
+
+    match (grouped, windowed) {
+        (false, false) => Ok(OperationResult::Value(combine_group(None)?[0].1)),
+        (false, true) => Ok(OperationResult::TimeValues(combine_group(None)?)),
+        (true, false) => {
+            let values = transform_with_result(groups.into_iter(), |group| Ok::<_, MetricsEngineError>((group.clone(), combine_group(Some(&group))?[0].1)))?;
+            Ok(OperationResult::GroupValues(values))
+        }
+        (true, true) => {
+            let values = transform_with_result(groups.into_iter(), |group| Ok::<_, MetricsEngineError>((group.clone(), combine_group(Some(&group))?)))?;
+            Ok(OperationResult::GroupTimeValues(values))
+        }
+    }
+}
+
+fn lookup_variable(bindings: &Bindings, name: &str) -> MetricsEngineResult<OperationResult> {
+    bindings
+        .get(name)
+        .map(|value| value.as_ref().clone())
+        .ok_or_else(|| MetricsEngineError::InvalidQueryInput(format!("The variable '{}' is not defined.", name)))
+}
+
+fn quantile_percentile(quantile: f64) -> MetricsEngineResult<i32> {
+    if !(0.0..=1.0).contains(&quantile) {
+        return Err(MetricsEngineError::InvalidQueryInput("The quantiles must be between 0 and 1.".to_owned()));
+    }
+
+    Ok((quantile * 100.0).round() as i32)
+}
+
+/// The quantile is added as the last part of the group, after the group by of the query (if any).
+fn quantile_group(group: Option<GroupValue>, quantile: f64) -> GroupValue {
+    let mut group = group.unwrap_or_else(|| GroupValue(Vec::new()));
+    group.0.push(quantile.to_string());
+    group
+}
+
+fn default_confidence() -> f64 {
+    0.95
+}
+
+/// The standard normal quantile of a two-sided interval with the confidence.
+fn confidence_z(confidence: f64) -> MetricsEngineResult<f64> {
+    if !(confidence > 0.0 && confidence < 1.0) {
+        return Err(MetricsEngineError::InvalidQueryInput("The confidence must be between 0 and 1.".to_owned()));
+    }
+
+    Ok(ratio::normal_quantile(0.5 + confidence / 2.0))
+}
+
+/// Evaluates the summed numerator and denominator of a ratio metric.
+fn ratio_parts(query: Query,
+               apply: impl Fn(Query) -> MetricsEngineResult<OperationResult>) -> MetricsEngineResult<(OperationResult, OperationResult)> {
+    let mut numerator_query = query.clone();
+    numerator_query.output_transform = Some(TransformExpression::InputNumerator);
+    let mut denominator_query = query;
+    denominator_query.output_transform = Some(TransformExpression::InputDenominator);
+    Ok((apply(numerator_query)?, apply(denominator_query)?))
+}
+
+const RATIO_INTERVAL_PARTS: [&str; 3] = ["ratio", "lower", "upper"];
+
+/// Combines the numerators and denominators into the parts of the interval, where the part is added as the last part of
+/// the group, after the group by of the query (if any).
+fn ratio_interval(numerator: OperationResult, denominator: OperationResult, z: f64) -> MetricsEngineResult<OperationResult> {
+    let interval_values = |numerator: Option<f64>, denominator: Option<f64>| {
+        let interval = numerator.zip(denominator).and_then(|(numerator, denominator)| ratio::wilson_interval(numerator, denominator, z));
+        [interval.map(|interval| interval.0), interval.map(|interval| interval.1), interval.map(|interval| interval.2)]
+    };
+
+    let interval_time_values = |numerator: TimeValues, denominator: Option<&TimeValues>| {
+        let mut parts = [Vec::new(), Vec::new(), Vec::new()];
+        for (index, (time, numerator)) in numerator.into_iter().enumerate() {
+            let denominator = denominator.and_then(|values| values.get(index)).and_then(|(_, value)| *value);
+            for (part, value) in parts.iter_mut().zip(interval_values(numerator, denominator)) {
+                part.push((time, value));
+            }
+        }
+
+        parts
+    };
+
+    let interval_group = |group: Option<GroupValue>, part: &str| {
+        let mut group = group.unwrap_or_else(|| GroupValue(Vec::new()));
+        group.0.push(part.to_owned());
+        group
+    };
+
+    match (numerator, denominator) {
+        (OperationResult::Value(numerator), OperationResult::Value(denominator)) => {
+            Ok(
+                OperationResult::GroupValues(
+                    RATIO_INTERVAL_PARTS
+                        .into_iter()
+                        .zip(interval_values(numerator, denominator))
+                        .map(|(part, value)| (interval_group(None, part), value))
+                        .collect()
+                )
+            )
+        }
+        (OperationResult::GroupValues(numerator), OperationResult::GroupValues(denominator)) => {
+            let denominator = group_map(denominator);
+
+            let mut results = Vec::new();
+            for (group, numerator) in numerator {
+                let values = interval_values(numerator, denominator.get(&group).copied().flatten());
+                for (part, value) in RATIO_INTERVAL_PARTS.into_iter().zip(values) {
+                    results.push((interval_group(Some(group.clone()), part), value));
+                }
+            }
+
+            Ok(OperationResult::GroupValues(results))
+        }
+        (OperationResult::TimeValues(numerator), OperationResult::TimeValues(denominator)) => {
+            Ok(
+                OperationResult::GroupTimeValues(
+                    RATIO_INTERVAL_PARTS
+                        .into_iter()
+                        .zip(interval_time_values(numerator, Some(&denominator)))
+                        .map(|(part, values)| (interval_group(None, part), values))
+                        .collect()
+                )
+            )
+        }
+        (OperationResult::GroupTimeValues(numerator), OperationResult::GroupTimeValues(denominator)) => {
+            let denominator = group_map(denominator);
+
+            let mut results = Vec::new();
+            for (group, numerator) in numerator {
+                let parts = interval_time_values(numerator, denominator.get(&group));
+                for (part, values) in RATIO_INTERVAL_PARTS.into_iter().zip(parts) {
+                    results.push((interval_group(Some(group.clone()), part), values));
+                }
+            }
+
+            Ok(OperationResult::GroupTimeValues(results))
+        }
+        (OperationResult::NotSupported, _) | (_, OperationResult::NotSupported) => Ok(OperationResult::NotSupported),
+        _ => Err(MetricsEngineError::UnexpectedResult)
+    }
 }
 
-fn group_keys<T>(map: &FnvHashMap<GroupValue, T>) -> FnvHashSet<&GroupValue> {
-    FnvHashSet::from_iter(map.keys())
+fn group_map<T>(values: Vec<(GroupValue, T)>) -> FnvHashMap<GroupValue, T> {
+    FnvHashMap::from_iter(values.into_iter())
 }
 
 fn get_overlapping_groups<T>(groups: &[FnvHashMap<GroupValue, T>]) -> FnvHashSet<GroupValue> {
@@ -539,6 +1778,14 @@ impl MetricQueryable for TestMetricsEngine {
         self.metric_values.get(metric).cloned().ok_or_else(|| MetricsEngineError::UnexpectedResult)
     }
 
+    fn last(&self, metric: &str, _query: Query) -> MetricsEngineResult<OperationResult> {
+        self.metric_values.get(metric).cloned().ok_or_else(|| MetricsEngineError::UnexpectedResult)
+    }
+
+    fn datapoints(&self, metric: &str, _query: Query) -> MetricsEngineResult<OperationResult> {
+        self.metric_values.get(metric).cloned().ok_or_else(|| MetricsEngineError::UnexpectedResult)
+    }
+
     fn average_in_window(&self, metric: &str, _query: Query, _duration: Duration) -> MetricsEngineResult<OperationResult> {
         self.metric_values.get(metric).cloned().ok_or_else(|| MetricsEngineError::UnexpectedResult)
     }
@@ -558,6 +1805,14 @@ impl MetricQueryable for TestMetricsEngine {
     fn percentile_in_window(&self, metric: &str, _query: Query, _duration: Duration, _percentile: i32) -> MetricsEngineResult<OperationResult> {
         self.metric_values.get(metric).cloned().ok_or_else(|| MetricsEngineError::UnexpectedResult)
     }
+
+    fn datapoints_in_window(&self, metric: &str, _query: Query, _duration: Duration) -> MetricsEngineResult<OperationResult> {
+        self.metric_values.get(metric).cloned().ok_or_else(|| MetricsEngineError::UnexpectedResult)
+    }
+
+    fn metric_type(&self, metric: &str) -> MetricsEngineResult<MetricType> {
+        self.metric_values.get(metric).map(|_| MetricType::Gauge).ok_or_else(|| MetricsEngineError::MetricNotFound(metric.to_owned()))
+    }
 }
 
 #[test]
@@ -578,7 +1833,13 @@ fn test_query1() {
                     left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
                     right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     )
@@ -604,7 +1865,13 @@ fn test_query2() {
                         MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() }
                     ]
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     )
@@ -636,7 +1903,13 @@ fn test_query3() {
                         left: Box::new(FilterExpression::input_value()),
                         right: Box::new(FilterExpression::value(5.0))
                     }
-                )
+                ),
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     );
@@ -660,7 +1933,13 @@ fn test_query3() {
                         left: Box::new(FilterExpression::input_value()),
                         right: Box::new(FilterExpression::value(3.0))
                     }
-                )
+                ),
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     );
@@ -684,7 +1963,13 @@ fn test_query_group1() {
                     left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
                     right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     )
@@ -707,7 +1992,13 @@ fn test_query_group2() {
                     left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
                     right: Box::new(MetricQueryExpression::Value(2.0))
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     )
@@ -733,7 +2024,13 @@ fn test_query_group3() {
                         MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() }
                     ]
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     )
@@ -763,12 +2060,53 @@ fn test_query_group4() {
                         left: Box::new(FilterExpression::input_value()),
                         right: Box::new(FilterExpression::value(5.0))
                     }
-                )
+                ),
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             }
         ).ok()
     );
 }
 
+#[test]
+fn test_query_group5() {
+    let engine = TestMetricsEngine::new(vec![
+        ("m1".to_owned(), OperationResult::GroupValues(vec![(GroupValue::from_ref("v1"), Some(2.0)), (GroupValue::from_ref("v2"), Some(3.0))])),
+        ("m2".to_owned(), OperationResult::GroupValues(vec![(GroupValue::from_ref("v2"), Some(4.0)), (GroupValue::from_ref("v3"), Some(5.0))])),
+    ]);
+
+    let create_query = |join: GroupJoin| {
+        let mut metric_query = MetricQuery::new(
+            TimeRange::new(0.0, 1.0),
+            MetricQueryExpression::Arithmetic {
+                operation: ArithmeticOperation::Add,
+                left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
+                right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
+            }
+        );
+        metric_query.join = join;
+        metric_query
+    };
+
+    assert_eq!(
+        Some(OperationResult::GroupValues(vec![
+            (GroupValue::from_ref("v1"), None),
+            (GroupValue::from_ref("v2"), Some(7.0)),
+            (GroupValue::from_ref("v3"), None)
+        ])),
+        query(&engine, create_query(GroupJoin::Outer { fill: None })).ok()
+    );
+
+    assert_eq!(
+        Some(OperationResult::GroupValues(vec![(GroupValue::from_ref("v1"), Some(2.0)), (GroupValue::from_ref("v2"), Some(7.0))])),
+        query(&engine, create_query(GroupJoin::Left { fill: Some(0.0) })).ok()
+    );
+}
+
 #[test]
 fn test_query_in_window1() {
     let engine = TestMetricsEngine::new(vec![
@@ -787,7 +2125,13 @@ fn test_query_in_window1() {
                     left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
                     right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
@@ -811,7 +2155,13 @@ fn test_query_in_window2() {
                     left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
                     right: Box::new(MetricQueryExpression::Value(2.0))
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
@@ -838,7 +2188,13 @@ fn test_query_in_window3() {
                         MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() }
                     ]
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
@@ -869,7 +2225,13 @@ fn test_query_in_window4() {
                         left: Box::new(FilterExpression::input_value()),
                         right: Box::new(FilterExpression::value(7.0))
                     }
-                )
+                ),
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
@@ -910,7 +2272,13 @@ fn test_query_in_window_group1() {
                     left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
                     right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
@@ -943,7 +2311,13 @@ fn test_query_in_window_group2() {
                     left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
                     right: Box::new(MetricQueryExpression::Value(10.0))
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
@@ -985,7 +2359,13 @@ fn test_query_in_window_group3() {
                         MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() }
                     ]
                 },
-                output_filter: None
+                output_filter: None,
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
@@ -1031,9 +2411,634 @@ fn test_query_in_window_group4() {
                         left: Box::new(FilterExpression::input_value()),
                         right: Box::new(FilterExpression::value(7.0))
                     }
-                )
+                ),
+                max_points: None,
+                downsampling: Downsampling::WidenWindow,
+                alignment: Alignment::Exact,
+                join: GroupJoin::Inner,
+                relabel: None,
+                request_id: None
             },
             Duration::from_secs_f64(1.0)
         ).ok()
     )
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_query_in_window_group5() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "capacity".to_owned(),
+            OperationResult::GroupValues(vec![(GroupValue::from_ref("a"), Some(10.0)), (GroupValue::from_ref("b"), Some(20.0))])
+        ),
+        (
+            "usage".to_owned(),
+            OperationResult::GroupTimeValues(vec![
+                (GroupValue::from_ref("a"), vec![(0.0, Some(1.0)), (1.0, Some(2.0))]),
+                (GroupValue::from_ref("b"), vec![(0.0, Some(4.0)), (1.0, None)]),
+                (GroupValue::from_ref("c"), vec![(0.0, Some(5.0)), (1.0, Some(6.0))])
+            ])
+        )
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::GroupTimeValues(vec![
+            (GroupValue::from_ref("a"), vec![(0.0, Some(0.1)), (1.0, Some(0.2))]),
+            (GroupValue::from_ref("b"), vec![(0.0, Some(0.2))])
+        ])),
+        query_in_window(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 2.0),
+                MetricQueryExpression::Arithmetic {
+                    operation: ArithmeticOperation::Divide,
+                    left: Box::new(MetricQueryExpression::Average { metric: "usage".to_string(), query: Query::placeholder() }),
+                    right: Box::new(MetricQueryExpression::Last { metric: "capacity".to_string(), query: Query::placeholder() })
+                }
+            ),
+            Duration::from_secs_f64(1.0)
+        ).ok()
+    );
+}
+
+#[test]
+fn test_query_in_window_group6() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::GroupTimeValues(vec![(GroupValue::from_ref("a"), vec![(0.0, Some(1.0)), (1.0, Some(2.0))])])
+        ),
+        (
+            "m2".to_owned(),
+            OperationResult::GroupTimeValues(vec![(GroupValue::from_ref("b"), vec![(0.0, Some(3.0)), (1.0, Some(4.0))])])
+        )
+    ]);
+
+    let create_query = |join: GroupJoin| {
+        let mut metric_query = MetricQuery::new(
+            TimeRange::new(0.0, 2.0),
+            MetricQueryExpression::Arithmetic {
+                operation: ArithmeticOperation::Add,
+                left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
+                right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
+            }
+        );
+        metric_query.join = join;
+        metric_query
+    };
+
+    assert_eq!(
+        Some(OperationResult::GroupTimeValues(Vec::new())),
+        query_in_window(&engine, create_query(GroupJoin::Inner), Duration::from_secs_f64(1.0)).ok()
+    );
+
+    assert_eq!(
+        Some(OperationResult::GroupTimeValues(vec![
+            (GroupValue::from_ref("a"), vec![(0.0, Some(1.0)), (1.0, Some(2.0))]),
+            (GroupValue::from_ref("b"), vec![(0.0, Some(3.0)), (1.0, Some(4.0))])
+        ])),
+        query_in_window(&engine, create_query(GroupJoin::Outer { fill: Some(0.0) }), Duration::from_secs_f64(1.0)).ok()
+    );
+}
+
+#[test]
+fn test_query_in_window_misaligned1() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::TimeValues(vec![(0.0, Some(1.0)), (1.0, Some(2.0)), (2.0, Some(3.0))])
+        ),
+        (
+            "m2".to_owned(),
+            OperationResult::TimeValues(vec![(0.5, Some(4.0)), (1.5, Some(5.0)), (2.5, Some(6.0))])
+        ),
+    ]);
+
+    let result = query_in_window(
+        &engine,
+        MetricQuery {
+            time_range: TimeRange::new(0.0, 1.0),
+            expression: MetricQueryExpression::Arithmetic {
+                operation: ArithmeticOperation::Add,
+                left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
+                right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
+            },
+            output_filter: None,
+            max_points: None,
+            downsampling: Downsampling::WidenWindow,
+            alignment: Alignment::Exact,
+            join: GroupJoin::Inner,
+            relabel: None,
+            request_id: None
+        },
+        Duration::from_secs_f64(1.0)
+    );
+
+    assert!(matches!(result, Err(MetricsEngineError::InvalidQueryInput(_))));
+}
+
+#[test]
+fn test_query_in_window_misaligned2() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::TimeValues(vec![(0.0, Some(1.0)), (1.0, Some(2.0)), (2.0, Some(3.0)), (3.0, Some(4.0))])
+        ),
+        (
+            "m2".to_owned(),
+            OperationResult::TimeValues(vec![(0.5, Some(4.0)), (1.5, None), (2.5, Some(8.0))])
+        ),
+    ]);
+
+    let query = |alignment: Alignment| {
+        let mut query = MetricQuery::new(
+            TimeRange::new(0.0, 4.0),
+            MetricQueryExpression::Arithmetic {
+                operation: ArithmeticOperation::Add,
+                left: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
+                right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
+            }
+        );
+        query.alignment = alignment;
+        query_in_window(&engine, query, Duration::from_secs_f64(1.0)).ok()
+    };
+
+    // Ties are resolved using the earlier window
+    assert_eq!(
+        Some(OperationResult::TimeValues(vec![(0.0, Some(5.0)), (1.0, Some(6.0)), (3.0, Some(12.0))])),
+        query(Alignment::Nearest)
+    );
+
+    assert_eq!(
+        Some(OperationResult::TimeValues(vec![(1.0, Some(2.0 + 5.0)), (2.0, Some(3.0 + 7.0))])),
+        query(Alignment::Linear)
+    );
+}
+
+#[test]
+fn test_query_quantiles1() {
+    let engine = TestMetricsEngine::new(vec![
+        ("m1".to_owned(), OperationResult::Value(Some(2.0)))
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::GroupValues(vec![
+            (GroupValue::from_ref("0.5"), Some(2.0)),
+            (GroupValue::from_ref("0.99"), Some(2.0))
+        ])),
+        query(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 1.0),
+                MetricQueryExpression::Quantiles { metric: "m1".to_string(), query: Query::placeholder(), quantiles: vec![0.5, 0.99] }
+            )
+        ).ok()
+    );
+
+    let result = query(
+        &engine,
+        MetricQuery::new(
+            TimeRange::new(0.0, 1.0),
+            MetricQueryExpression::Quantiles { metric: "m1".to_string(), query: Query::placeholder(), quantiles: vec![1.5] }
+        )
+    );
+    assert!(matches!(result, Err(MetricsEngineError::InvalidQueryInput(_))));
+}
+
+#[test]
+fn test_query_rate1() {
+    let engine = TestMetricsEngine::new(vec![
+        ("errors".to_owned(), OperationResult::TimeValues(vec![(0.0, Some(6.0)), (60.0, Some(3.0)), (120.0, None)])),
+        ("requests".to_owned(), OperationResult::TimeValues(vec![(0.0, Some(60.0)), (60.0, Some(120.0)), (120.0, Some(60.0))]))
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::TimeValues(vec![(0.0, Some(0.1)), (60.0, Some(0.025))])),
+        query_in_window(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 180.0),
+                MetricQueryExpression::Arithmetic {
+                    operation: ArithmeticOperation::Divide,
+                    left: Box::new(MetricQueryExpression::Rate { metric: "errors".to_string(), query: Query::placeholder() }),
+                    right: Box::new(MetricQueryExpression::Rate { metric: "requests".to_string(), query: Query::placeholder() })
+                }
+            ),
+            Duration::from_secs_f64(60.0)
+        ).ok()
+    );
+
+    assert_eq!(
+        Some(OperationResult::TimeValues(vec![(0.0, Some(1.0)), (60.0, Some(2.0)), (120.0, Some(1.0))])),
+        query_in_window(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 180.0),
+                MetricQueryExpression::Rate { metric: "requests".to_string(), query: Query::placeholder() }
+            ),
+            Duration::from_secs_f64(60.0)
+        ).ok()
+    );
+}
+
+#[test]
+fn test_query_let1() {
+    let engine = TestMetricsEngine::new(vec![
+        ("m1".to_owned(), OperationResult::Value(Some(2.0))),
+        ("m2".to_owned(), OperationResult::Value(Some(4.0)))
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::Value(Some(12.0))),
+        query(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 1.0),
+                MetricQueryExpression::Let {
+                    name: "total".to_owned(),
+                    value: Box::new(MetricQueryExpression::Arithmetic {
+                        operation: ArithmeticOperation::Add,
+                        left: Box::new(MetricQueryExpression::Sum { metric: "m1".to_string(), query: Query::placeholder() }),
+                        right: Box::new(MetricQueryExpression::Sum { metric: "m2".to_string(), query: Query::placeholder() })
+                    }),
+                    body: Box::new(MetricQueryExpression::Arithmetic {
+                        operation: ArithmeticOperation::Add,
+                        left: Box::new(MetricQueryExpression::Variable("total".to_owned())),
+                        right: Box::new(MetricQueryExpression::Variable("total".to_owned()))
+                    })
+                }
+            )
+        ).ok()
+    );
+
+    let result = query(
+        &engine,
+        MetricQuery::new(TimeRange::new(0.0, 1.0), MetricQueryExpression::Variable("total".to_owned()))
+    );
+    assert!(matches!(result, Err(MetricsEngineError::InvalidQueryInput(_))));
+}
+
+#[test]
+fn test_query_in_window_filter1() {
+    let engine = TestMetricsEngine::new(vec![
+        ("m1".to_owned(), OperationResult::TimeValues(vec![(0.0, Some(0.5)), (1.0, Some(2.0)), (2.0, Some(3.0))])),
+        ("m2".to_owned(), OperationResult::TimeValues(vec![(0.0, Some(4.0)), (1.0, Some(5.0)), (2.0, Some(6.0))]))
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::TimeValues(vec![(1.0, Some(7.0)), (2.0, Some(9.0))])),
+        query_in_window(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 3.0),
+                MetricQueryExpression::Arithmetic {
+                    operation: ArithmeticOperation::Add,
+                    left: Box::new(MetricQueryExpression::Filter {
+                        filter: FilterExpression::Compare {
+                            operation: CompareOperation::GreaterThanOrEqual,
+                            left: Box::new(FilterExpression::input_value()),
+                            right: Box::new(FilterExpression::value(1.0))
+                        },
+                        expression: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() })
+                    }),
+                    right: Box::new(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
+                }
+            ),
+            Duration::from_secs_f64(1.0)
+        ).ok()
+    );
+}
+
+#[test]
+fn test_query_conditional1() {
+    let engine = TestMetricsEngine::new(vec![
+        ("m1".to_owned(), OperationResult::GroupValues(vec![(GroupValue::from_ref("a"), Some(500.0)), (GroupValue::from_ref("b"), Some(200.0))])),
+        ("m2".to_owned(), OperationResult::GroupValues(vec![(GroupValue::from_ref("a"), Some(1.0)), (GroupValue::from_ref("c"), Some(2.0))]))
+    ]);
+
+    let condition = FilterExpression::Compare {
+        operation: CompareOperation::Equal,
+        left: Box::new(FilterExpression::input_value()),
+        right: Box::new(FilterExpression::value(500.0))
+    };
+
+    assert_eq!(
+        Some(OperationResult::GroupValues(vec![(GroupValue::from_ref("a"), Some(1.0)), (GroupValue::from_ref("b"), Some(0.0))])),
+        query(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 1.0),
+                MetricQueryExpression::Conditional {
+                    condition: condition.clone(),
+                    value: Box::new(MetricQueryExpression::Last { metric: "m1".to_string(), query: Query::placeholder() }),
+                    then: Some(Box::new(MetricQueryExpression::Value(1.0))),
+                    otherwise: Some(Box::new(MetricQueryExpression::Value(0.0)))
+                }
+            )
+        ).ok()
+    );
+
+    // Only the groups in both results are kept
+    assert_eq!(
+        Some(OperationResult::GroupValues(vec![(GroupValue::from_ref("a"), Some(1.0))])),
+        query(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 1.0),
+                MetricQueryExpression::Conditional {
+                    condition,
+                    value: Box::new(MetricQueryExpression::Last { metric: "m1".to_string(), query: Query::placeholder() }),
+                    then: Some(Box::new(MetricQueryExpression::Last { metric: "m2".to_string(), query: Query::placeholder() })),
+                    otherwise: None
+                }
+            )
+        ).ok()
+    );
+}
+
+#[test]
+fn test_query_in_window_conditional1() {
+    let engine = TestMetricsEngine::new(vec![
+        ("m1".to_owned(), OperationResult::TimeValues(vec![(0.0, Some(1.0)), (1.0, Some(200.0)), (2.0, Some(3.0)), (3.0, None)]))
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::TimeValues(vec![(0.0, Some(1.0)), (2.0, Some(3.0))])),
+        query_in_window(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 4.0),
+                MetricQueryExpression::Conditional {
+                    condition: FilterExpression::Compare {
+                        operation: CompareOperation::GreaterThan,
+                        left: Box::new(FilterExpression::input_value()),
+                        right: Box::new(FilterExpression::value(100.0))
+                    },
+                    value: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }),
+                    then: None,
+                    otherwise: Some(Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }))
+                }
+            ),
+            Duration::from_secs_f64(1.0)
+        ).ok()
+    );
+}
+
+#[test]
+fn test_expression_serialize1() {
+    use crate::model::GroupKey;
+
+    let content = r#"{
+        "Arithmetic": {
+            "operation": "Divide",
+            "left": { "Sum": { "metric": "errors", "query": { "tags_filter": { "And": ["host:h1"] }, "group_by": "dc" } } },
+            "right": { "Sum": { "metric": "requests", "query": { "group_by": ["dc", "host"], "staleness": 60.0 } } }
+        }
+    }"#;
+
+    let expression: MetricQueryExpression = serde_json::from_str(content).unwrap();
+    let serialized = serde_json::to_value(&expression).unwrap();
+    let reparsed: MetricQueryExpression = serde_json::from_value(serialized.clone()).unwrap();
+    assert_eq!(serialized, serde_json::to_value(&reparsed).unwrap());
+
+    let left_query = &serialized["Arithmetic"]["left"]["Sum"]["query"];
+    assert_eq!(serde_json::json!({ "And": ["host:h1"] }), left_query["tags_filter"]);
+    assert_eq!(serde_json::json!("dc"), left_query["group_by"]);
+    assert_eq!(serde_json::json!(["dc", "host"]), serialized["Arithmetic"]["right"]["Sum"]["query"]["group_by"]);
+
+    assert_eq!(
+        r#"{"Or":["host:h1","host:h2"]}"#,
+        serde_json::to_string(&TagsFilter::Or(vec![Tag::from_ref("host", "h1"), Tag::from_ref("host", "h2")])).unwrap()
+    );
+    assert_eq!(r#"["a","b"]"#, serde_json::to_string(&GroupKey::from_multi_ref(&["a", "b"])).unwrap());
+}
+
+#[test]
+fn test_widen_duration1() {
+    let time_range = TimeRange::new(0.0, 3600.0);
+    assert_eq!(Duration::from_secs(60), widen_duration(&time_range, Duration::from_secs(60), None).unwrap());
+    assert_eq!(Duration::from_secs(60), widen_duration(&time_range, Duration::from_secs(60), Some(60)).unwrap());
+    assert_eq!(Duration::from_secs(360), widen_duration(&time_range, Duration::from_secs(60), Some(10)).unwrap());
+    assert_eq!(Duration::from_secs(515), widen_duration(&time_range, Duration::from_secs(1), Some(7)).unwrap());
+    assert!(widen_duration(&time_range, Duration::from_secs(60), Some(0)).is_err());
+}
+
+#[test]
+fn test_split_by_group1() {
+    let group_values = |metric: &str, _query: &Query, _key: &GroupKey| {
+        Ok(
+            match metric {
+                "m1" => vec![vec![Tag::from_ref("host", "h2")], vec![Tag::from_ref("host", "h1")]],
+                _ => vec![vec![Tag::from_ref("host", "h2")], vec![Tag::from_ref("host", "h3")]]
+            }
+        )
+    };
+
+    let expression = MetricQueryExpression::Arithmetic {
+        operation: ArithmeticOperation::Divide,
+        left: Box::new(MetricQueryExpression::Sum { metric: "m1".to_owned(), query: Query::placeholder().with_group_by(GroupKey::from_ref("host")) }),
+        right: Box::new(MetricQueryExpression::Sum { metric: "m2".to_owned(), query: Query::placeholder().with_group_by(GroupKey::from_ref("host")) })
+    };
+
+    let expressions = split_by_group(&expression, group_values).unwrap().unwrap();
+    assert_eq!(3, expressions.len());
+    for (expression, host) in expressions.iter().zip(["h1", "h2", "h3"]) {
+        expression.for_each_query(&mut |_, query| {
+            assert!(matches!(&query.tags_filter, TagsFilter::And(tags) if tags == &vec![Tag::from_ref("host", host)]));
+        });
+    }
+
+    let expression = MetricQueryExpression::Arithmetic {
+        operation: ArithmeticOperation::Divide,
+        left: Box::new(MetricQueryExpression::Sum { metric: "m1".to_owned(), query: Query::placeholder().with_group_by(GroupKey::from_ref("host")) }),
+        right: Box::new(MetricQueryExpression::Sum { metric: "m2".to_owned(), query: Query::placeholder() })
+    };
+    assert!(split_by_group(&expression, group_values).unwrap().is_none());
+
+    let expression = MetricQueryExpression::percent_of_total(
+        MetricQueryExpression::Sum { metric: "m1".to_owned(), query: Query::placeholder().with_group_by(GroupKey::from_ref("host")) }
+    );
+    assert!(split_by_group(&expression, group_values).unwrap().is_none());
+
+    let expression = MetricQueryExpression::Sum {
+        metric: "m1".to_owned(),
+        query: Query::placeholder().with_group_by(GroupKey::from_ref("host")).with_group_limit(GroupLimit::new(1, false))
+    };
+    assert!(split_by_group(&expression, group_values).unwrap().is_none());
+}
+
+#[test]
+fn test_query_aggregate_groups1() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::GroupValues(vec![
+                (GroupValue::from_ref("v1"), Some(2.0)),
+                (GroupValue::from_ref("v2"), Some(3.0)),
+                (GroupValue::from_ref("v3"), None)
+            ])
+        )
+    ]);
+
+    let create_query = |function: AggregateFunction| {
+        MetricQuery::new(
+            TimeRange::new(0.0, 1.0),
+            MetricQueryExpression::AggregateGroups {
+                function,
+                expression: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() })
+            }
+        )
+    };
+
+    assert_eq!(Some(OperationResult::Value(Some(5.0))), query(&engine, create_query(AggregateFunction::Sum)).ok());
+    assert_eq!(Some(OperationResult::Value(Some(2.5))), query(&engine, create_query(AggregateFunction::Average)).ok());
+    assert_eq!(Some(OperationResult::Value(Some(3.0))), query(&engine, create_query(AggregateFunction::Max)).ok());
+    assert_eq!(Some(OperationResult::Value(Some(2.0))), query(&engine, create_query(AggregateFunction::Min)).ok());
+    assert_eq!(Some(OperationResult::Value(Some(2.0))), query(&engine, create_query(AggregateFunction::Count)).ok());
+}
+
+#[test]
+fn test_query_in_window_aggregate_groups1() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::GroupTimeValues(vec![
+                (GroupValue::from_ref("a"), vec![(0.0, Some(1.0)), (1.0, Some(2.0)), (2.0, None)]),
+                (GroupValue::from_ref("b"), vec![(0.0, Some(3.0)), (1.0, None), (2.0, None)])
+            ])
+        )
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::TimeValues(vec![(0.0, Some(4.0)), (1.0, Some(2.0))])),
+        query_in_window(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 3.0),
+                MetricQueryExpression::AggregateGroups {
+                    function: AggregateFunction::Sum,
+                    expression: Box::new(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() })
+                }
+            ),
+            Duration::from_secs_f64(1.0)
+        ).ok()
+    );
+}
+
+#[test]
+fn test_query_relabel1() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::GroupValues(vec![
+                (GroupValue::from_ref("cpu0"), Some(1.0)),
+                (GroupValue::from_ref("cpu1"), Some(2.0)),
+                (GroupValue::from_ref("cpu2"), Some(3.0)),
+                (GroupValue::from_ref("gpu"), Some(4.0))
+            ])
+        )
+    ]);
+
+    let mut metric_query = MetricQuery::new(
+        TimeRange::new(0.0, 1.0),
+        MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }
+    );
+    metric_query.relabel = Some(Relabeling::new(
+        vec![
+            RelabelRule::Map { from: "cpu2".to_owned(), to: "socket1".to_owned() },
+            RelabelRule::Regex { pattern: "cpu([0-9])".to_owned(), replacement: "socket0".to_owned() }
+        ],
+        AggregateFunction::Sum
+    ));
+
+    assert_eq!(
+        Some(OperationResult::GroupValues(vec![
+            (GroupValue::from_ref("gpu"), Some(4.0)),
+            (GroupValue::from_ref("socket0"), Some(3.0)),
+            (GroupValue::from_ref("socket1"), Some(3.0))
+        ])),
+        query(&engine, metric_query).ok()
+    );
+}
+
+#[test]
+fn test_query_in_window_relabel1() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::GroupTimeValues(vec![
+                (GroupValue::from_ref("a-cpu0"), vec![(0.0, Some(1.0)), (1.0, Some(2.0))]),
+                (GroupValue::from_ref("a-cpu1"), vec![(0.0, Some(3.0)), (1.0, None)]),
+                (GroupValue::from_ref("b-cpu0"), vec![(0.0, Some(5.0)), (1.0, Some(6.0))])
+            ])
+        )
+    ]);
+
+    let create_query = |pattern: &str| {
+        let mut metric_query = MetricQuery::new(
+            TimeRange::new(0.0, 2.0),
+            MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() }
+        );
+        metric_query.relabel = Some(Relabeling::new(
+            vec![RelabelRule::Regex { pattern: pattern.to_owned(), replacement: "host_${1}".to_owned() }],
+            AggregateFunction::Max
+        ));
+        metric_query
+    };
+
+    assert_eq!(
+        Some(OperationResult::GroupTimeValues(vec![
+            (GroupValue::from_ref("host_a"), vec![(0.0, Some(3.0)), (1.0, Some(2.0))]),
+            (GroupValue::from_ref("host_b"), vec![(0.0, Some(5.0)), (1.0, Some(6.0))])
+        ])),
+        query_in_window(&engine, create_query("([a-z]+)-cpu[0-9]+"), Duration::from_secs_f64(1.0)).ok()
+    );
+
+    assert!(matches!(
+        query_in_window(&engine, create_query("cpu("), Duration::from_secs_f64(1.0)),
+        Err(MetricsEngineError::InvalidQueryInput(_))
+    ));
+}
+
+#[test]
+fn test_query_percent_of_total1() {
+    let engine = TestMetricsEngine::new(vec![
+        (
+            "m1".to_owned(),
+            OperationResult::GroupValues(vec![(GroupValue::from_ref("a"), Some(1.0)), (GroupValue::from_ref("b"), Some(3.0))])
+        ),
+        (
+            "m2".to_owned(),
+            OperationResult::GroupTimeValues(vec![
+                (GroupValue::from_ref("a"), vec![(0.0, Some(1.0)), (1.0, Some(2.0))]),
+                (GroupValue::from_ref("b"), vec![(0.0, Some(1.0)), (1.0, Some(6.0))])
+            ])
+        )
+    ]);
+
+    assert_eq!(
+        Some(OperationResult::GroupValues(vec![(GroupValue::from_ref("a"), Some(25.0)), (GroupValue::from_ref("b"), Some(75.0))])),
+        query(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 1.0),
+                MetricQueryExpression::percent_of_total(MetricQueryExpression::Average { metric: "m1".to_string(), query: Query::placeholder() })
+            )
+        ).ok()
+    );
+
+    assert_eq!(
+        Some(OperationResult::GroupTimeValues(vec![
+            (GroupValue::from_ref("a"), vec![(0.0, Some(50.0)), (1.0, Some(25.0))]),
+            (GroupValue::from_ref("b"), vec![(0.0, Some(50.0)), (1.0, Some(75.0))])
+        ])),
+        query_in_window(
+            &engine,
+            MetricQuery::new(
+                TimeRange::new(0.0, 2.0),
+                MetricQueryExpression::percent_of_total(MetricQueryExpression::Average { metric: "m2".to_string(), query: Query::placeholder() })
+            ),
+            Duration::from_secs_f64(1.0)
+        ).ok()
+    );
+}
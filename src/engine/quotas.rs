@@ -0,0 +1,263 @@
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use fnv::{FnvBuildHasher, FnvHashMap};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::engine::io::{MetricsEngineError, MetricsEngineResult};
+
+const BYTES_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum QuotaEnforcement {
+    #[default]
+    Reject,
+    /// Keeps every n:th value (given by the sample rate) once the daily quota has been used.
+    Sample
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricQuota {
+    pub max_datapoints_per_day: Option<u64>,
+    /// Always enforced by rejecting writes, as sampling would not stop the disk from filling up.
+    pub max_bytes: Option<u64>,
+    pub enforcement: QuotaEnforcement,
+    pub sample_rate: f64
+}
+
+impl Default for MetricQuota {
+    fn default() -> Self {
+        MetricQuota {
+            max_datapoints_per_day: None,
+            max_bytes: None,
+            enforcement: QuotaEnforcement::Reject,
+            sample_rate: 0.1
+        }
+    }
+}
+
+impl MetricQuota {
+    fn is_limited(&self) -> bool {
+        self.max_datapoints_per_day.is_some() || self.max_bytes.is_some()
+    }
+
+    fn sample_stride(&self) -> usize {
+        if self.sample_rate > 0.0 {
+            (1.0 / self.sample_rate).round().max(1.0) as usize
+        } else {
+            usize::MAX
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WriteQuotas {
+    /// Applies to all metrics without their own quota.
+    pub default: MetricQuota,
+    pub metrics: FnvHashMap<String, MetricQuota>
+}
+
+impl WriteQuotas {
+    pub fn for_metric(&self, metric: &str) -> &MetricQuota {
+        self.metrics.get(metric).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct QuotaUsage {
+    pub metric: String,
+    pub datapoints_today: u64,
+    pub max_datapoints_per_day: Option<u64>,
+    pub bytes: u64,
+    pub max_bytes: Option<u64>
+}
+
+#[derive(Default)]
+struct MetricUsage {
+    day: u64,
+    datapoints: u64,
+    bytes: u64,
+    bytes_checked: Option<Instant>
+}
+
+pub struct QuotaTracker {
+    usage: DashMap<String, MetricUsage, FnvBuildHasher>
+}
+
+impl QuotaTracker {
+    pub fn new() -> QuotaTracker {
+        QuotaTracker {
+            usage: DashMap::default()
+        }
+    }
+
+    pub fn admit<T>(&self, quotas: &WriteQuotas, metric: &str, values: Vec<T>) -> MetricsEngineResult<Vec<T>> {
+        self.admit_on_day(quotas, metric, values, current_day())
+    }
+
+    fn admit_on_day<T>(&self, quotas: &WriteQuotas, metric: &str, values: Vec<T>, day: u64) -> MetricsEngineResult<Vec<T>> {
+        let quota = quotas.for_metric(metric);
+        if !quota.is_limited() {
+            return Ok(values);
+        }
+
+        let mut usage = self.usage.entry(metric.to_owned()).or_default();
+        if usage.day != day {
+            usage.day = day;
+            usage.datapoints = 0;
+        }
+
+        if let Some(max_bytes) = quota.max_bytes {
+            if usage.bytes >= max_bytes {
+                return Err(MetricsEngineError::QuotaExceeded(metric.to_owned()));
+            }
+        }
+
+        let values = match quota.max_datapoints_per_day {
+            Some(max_datapoints) if usage.datapoints + values.len() as u64 > max_datapoints => {
+                match quota.enforcement {
+                    QuotaEnforcement::Reject => {
+                        return Err(MetricsEngineError::QuotaExceeded(metric.to_owned()));
+                    }
+                    QuotaEnforcement::Sample => {
+                        let remaining = max_datapoints.saturating_sub(usage.datapoints) as usize;
+                        let stride = quota.sample_stride();
+                        values
+                            .into_iter()
+                            .enumerate()
+                            .filter(|(index, _)| *index < remaining || (index - remaining).is_multiple_of(stride))
+                            .map(|(_, value)| value)
+                            .collect()
+                    }
+                }
+            }
+            _ => values
+        };
+
+        usage.datapoints += values.len() as u64;
+        Ok(values)
+    }
+
+    /// Refreshes the disk usage of the metric if it has a byte quota, at most every few seconds.
+    pub fn update_bytes(&self, quotas: &WriteQuotas, metric: &str, metric_path: &Path) {
+        if quotas.for_metric(metric).max_bytes.is_none() {
+            return;
+        }
+
+        let mut usage = self.usage.entry(metric.to_owned()).or_default();
+        if usage.bytes_checked.map(|checked| checked.elapsed() < BYTES_CHECK_INTERVAL).unwrap_or(false) {
+            return;
+        }
+
+        match directory_size(metric_path) {
+            Ok(bytes) => {
+                usage.bytes = bytes;
+                usage.bytes_checked = Some(Instant::now());
+            }
+            Err(err) => {
+                tracing::warn!(metric, error = %err, "Failed to determine disk usage.");
+            }
+        }
+    }
+
+    pub fn usage(&self, quotas: &WriteQuotas) -> Vec<QuotaUsage> {
+        let day = current_day();
+        let mut usage = self.usage
+            .iter()
+            .map(|item| {
+                let quota = quotas.for_metric(item.key());
+                QuotaUsage {
+                    metric: item.key().clone(),
+                    datapoints_today: if item.day == day { item.datapoints } else { 0 },
+                    max_datapoints_per_day: quota.max_datapoints_per_day,
+                    bytes: item.bytes,
+                    max_bytes: quota.max_bytes
+                }
+            })
+            .collect::<Vec<_>>();
+        usage.sort_by(|x, y| x.metric.cmp(&y.metric));
+        usage
+    }
+}
+
+impl Default for QuotaTracker {
+    fn default() -> Self {
+        QuotaTracker::new()
+    }
+}
+
+fn current_day() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / (24 * 3600)
+}
+
+pub(crate) fn directory_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+#[test]
+fn test_admit_reject1() {
+    let quotas = WriteQuotas {
+        default: MetricQuota { max_datapoints_per_day: Some(10), ..Default::default() },
+        ..Default::default()
+    };
+    let tracker = QuotaTracker::new();
+
+    assert_eq!(8, tracker.admit_on_day(&quotas, "cpu", vec![0; 8], 1).unwrap().len());
+    assert!(matches!(tracker.admit_on_day(&quotas, "cpu", vec![0; 3], 1), Err(MetricsEngineError::QuotaExceeded(_))));
+    assert_eq!(2, tracker.admit_on_day(&quotas, "cpu", vec![0; 2], 1).unwrap().len());
+    assert_eq!(5, tracker.admit_on_day(&quotas, "memory", vec![0; 5], 1).unwrap().len());
+
+    // A new day resets the quota
+    assert_eq!(10, tracker.admit_on_day(&quotas, "cpu", vec![0; 10], 2).unwrap().len());
+}
+
+#[test]
+fn test_admit_sample1() {
+    let mut quotas = WriteQuotas::default();
+    quotas.metrics.insert(
+        "cpu".to_owned(),
+        MetricQuota { max_datapoints_per_day: Some(5), enforcement: QuotaEnforcement::Sample, sample_rate: 0.25, ..Default::default() }
+    );
+    let tracker = QuotaTracker::new();
+
+    assert_eq!(
+        vec![0, 1, 2, 3, 4, 5, 9, 13],
+        tracker.admit_on_day(&quotas, "cpu", (0..16).collect(), 1).unwrap()
+    );
+    assert_eq!(vec![0, 4], tracker.admit_on_day(&quotas, "cpu", (0..8).collect(), 1).unwrap());
+    assert_eq!(16, tracker.admit_on_day(&quotas, "memory", (0..16).collect::<Vec<_>>(), 1).unwrap().len());
+}
+
+#[test]
+fn test_admit_bytes1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    std::fs::write(temp_dir.path().join("data"), vec![0u8; 1000]).unwrap();
+
+    let quotas = WriteQuotas {
+        default: MetricQuota { max_bytes: Some(500), ..Default::default() },
+        ..Default::default()
+    };
+    let tracker = QuotaTracker::new();
+
+    assert!(tracker.admit_on_day(&quotas, "cpu", vec![0; 1], 1).is_ok());
+    tracker.update_bytes(&quotas, "cpu", temp_dir.path());
+    assert!(matches!(tracker.admit_on_day(&quotas, "cpu", vec![0; 1], 1), Err(MetricsEngineError::QuotaExceeded(_))));
+    assert_eq!(1000, tracker.usage(&quotas)[0].bytes);
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Deserialize;
+
+use crate::engine::templates::matches_pattern;
+use crate::metric::tags::Tag;
+
+/// Keeps only a fraction of the values written to a metric, e.g. to limit the cost of verbose sources.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SamplingRule {
+    /// The metrics the rule applies to, where `*` matches any sequence of characters.
+    pub metric: String,
+    /// The rule only applies to values that have all of these tags.
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+    /// Keeps one out of this many values.
+    pub keep_one_in: u64
+}
+
+impl SamplingRule {
+    pub fn new(metric: &str, keep_one_in: u64) -> SamplingRule {
+        SamplingRule {
+            metric: metric.to_owned(),
+            tags: Vec::new(),
+            keep_one_in
+        }
+    }
+
+    pub fn with_tag(mut self, tag: Tag) -> SamplingRule {
+        self.tags.push(tag);
+        self
+    }
+
+    fn applies_to(&self, metric: &str, tags: &[Tag]) -> bool {
+        matches_pattern(&self.metric, metric) && self.tags.iter().all(|tag| tags.contains(tag))
+    }
+}
+
+/// The first matching rule is used for each value, values without a matching rule are always kept.
+#[derive(Default)]
+pub struct SamplingRules {
+    rules: Vec<SamplingRule>,
+    counters: Vec<AtomicU64>,
+    num_sampled_out: AtomicU64
+}
+
+impl SamplingRules {
+    pub fn new(rules: Vec<SamplingRule>) -> SamplingRules {
+        SamplingRules {
+            counters: rules.iter().map(|_| AtomicU64::new(0)).collect(),
+            rules,
+            num_sampled_out: AtomicU64::new(0)
+        }
+    }
+
+    pub fn apply<A, B>(&self, metric: &str, values: Vec<(A, B, Vec<Tag>)>) -> Vec<(A, B, Vec<Tag>)> {
+        if !self.rules.iter().any(|rule| matches_pattern(&rule.metric, metric)) {
+            return values;
+        }
+
+        let num_values = values.len();
+        let values = values
+            .into_iter()
+            .filter(|(_, _, tags)| {
+                match self.rules.iter().position(|rule| rule.applies_to(metric, tags)) {
+                    Some(rule_index) => {
+                        let keep_one_in = self.rules[rule_index].keep_one_in.max(1);
+                        self.counters[rule_index].fetch_add(1, Ordering::Relaxed).is_multiple_of(keep_one_in)
+                    }
+                    None => true
+                }
+            })
+            .collect::<Vec<_>>();
+
+        self.num_sampled_out.fetch_add((num_values - values.len()) as u64, Ordering::SeqCst);
+        values
+    }
+
+    /// The number of values that have been removed by the rules.
+    pub fn num_sampled_out(&self) -> u64 {
+        self.num_sampled_out.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_apply1() {
+    let rules = SamplingRules::new(vec![
+        SamplingRule::new("http_*", 10).with_tag(Tag::from_ref("debug", "true")),
+        SamplingRule::new("http_*", 1)
+    ]);
+
+    let values = (0..100)
+        .map(|index| {
+            let tags = if index % 2 == 0 { vec![Tag::from_ref("debug", "true")] } else { vec![Tag::from_ref("debug", "false")] };
+            (index as f64, 1.0, tags)
+        })
+        .collect::<Vec<_>>();
+
+    let sampled = rules.apply("http_requests", values.clone());
+    assert_eq!(55, sampled.len());
+    assert_eq!(5, sampled.iter().filter(|(_, _, tags)| tags[0].1 == "true").count());
+    assert_eq!(45, rules.num_sampled_out());
+
+    assert_eq!(100, rules.apply("cpu", values).len());
+    assert_eq!(45, rules.num_sampled_out());
+}
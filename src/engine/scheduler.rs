@@ -0,0 +1,173 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::engine::MetricsEngine;
+use crate::engine::background::BackgroundThread;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    pub interval: f64,
+    pub jitter: f64,
+    pub stagger: bool
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            interval: 0.25,
+            jitter: 0.0,
+            stagger: true
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.interval.is_finite() && self.interval > 0.0) {
+            return Err(format!("the scheduler interval must be positive, got {}", self.interval));
+        }
+
+        if !(self.jitter.is_finite() && self.jitter >= 0.0) {
+            return Err(format!("the scheduler jitter must not be negative, got {}", self.jitter));
+        }
+
+        Ok(())
+    }
+
+    fn slot_duration(&self, num_metrics: usize) -> Duration {
+        if self.stagger && num_metrics > 0 {
+            Duration::from_secs_f64(self.interval) / num_metrics as u32
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    fn cycle_duration(&self) -> Duration {
+        let jitter = if self.jitter > 0.0 {
+            Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..self.jitter))
+        } else {
+            Duration::ZERO
+        };
+
+        Duration::from_secs_f64(self.interval) + jitter
+    }
+}
+
+#[cfg(feature = "scheduler")]
+pub fn spawn_scheduler(metrics_engine: Arc<MetricsEngine>, config: SchedulerConfig) -> tokio::task::JoinHandle<()> {
+    use tokio::time;
+
+    // The maintenance blocks on locks and disk IO, so it runs on the blocking threads instead of the runtime
+    tokio::spawn(async move {
+        loop {
+            let cycle_start = time::Instant::now();
+
+            let engine = metrics_engine.clone();
+            let metrics = tokio::task::spawn_blocking(move || {
+                engine.check_disk_space();
+                engine.check_locks();
+                engine.purge_deleted_metrics();
+                engine.metric_names()
+            }).await.unwrap_or_default();
+
+            let slot_duration = config.slot_duration(metrics.len());
+
+            for (index, metric) in metrics.into_iter().enumerate() {
+                if !slot_duration.is_zero() {
+                    time::sleep_until(cycle_start + slot_duration * index as u32).await;
+                }
+
+                // The metric might have been removed since the names were gathered
+                let engine = metrics_engine.clone();
+                tokio::task::spawn_blocking(move || engine.scheduled_metric(&metric).ok()).await.ok();
+            }
+
+            time::sleep_until(cycle_start + config.cycle_duration()).await;
+        }
+    })
+}
+
+pub fn spawn_scheduler_thread(metrics_engine: Arc<MetricsEngine>, config: SchedulerConfig) -> BackgroundThread {
+    BackgroundThread::spawn(move |stop_signal| {
+        while !stop_signal.is_stopped() {
+            let cycle_start = Instant::now();
+
+            metrics_engine.check_disk_space();
+            metrics_engine.check_locks();
+            metrics_engine.purge_deleted_metrics();
+
+            let metrics = metrics_engine.metric_names();
+            let slot_duration = config.slot_duration(metrics.len());
+
+            for (index, metric) in metrics.iter().enumerate() {
+                if !slot_duration.is_zero() && !stop_signal.sleep_until(cycle_start + slot_duration * index as u32) {
+                    return;
+                }
+
+                metrics_engine.scheduled_metric(metric).ok();
+            }
+
+            stop_signal.sleep_until(cycle_start + config.cycle_duration());
+        }
+    })
+}
+
+#[test]
+fn test_validate1() {
+    assert!(SchedulerConfig::default().validate().is_ok());
+    assert!(SchedulerConfig { interval: -1.0, ..Default::default() }.validate().is_err());
+    assert!(SchedulerConfig { interval: 0.0, ..Default::default() }.validate().is_err());
+    assert!(SchedulerConfig { interval: f64::NAN, ..Default::default() }.validate().is_err());
+    assert!(SchedulerConfig { jitter: -0.5, ..Default::default() }.validate().is_err());
+}
+
+#[cfg(feature = "scheduler")]
+#[tokio::test]
+async fn test_spawn_scheduler1() {
+    use crate::engine::MetricsEngineBuilder;
+    use crate::engine::trash::TrashConfig;
+    use crate::metric::common::MetricType;
+
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let metrics_engine = Arc::new(
+        MetricsEngineBuilder::new(temp_metric_data.path())
+            .with_trash(TrashConfig { grace_period: 0.0 })
+            .build()
+            .unwrap()
+    );
+
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_metric("memory", MetricType::Gauge).unwrap();
+    metrics_engine.delete_metric("cpu").unwrap();
+    assert_eq!(1, metrics_engine.deleted_metrics().len());
+
+    let scheduler = spawn_scheduler(metrics_engine.clone(), SchedulerConfig { interval: 0.01, ..Default::default() });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    scheduler.abort();
+
+    assert_eq!(0, metrics_engine.deleted_metrics().len());
+}
+
+#[test]
+fn test_spawn_scheduler_thread1() {
+    use crate::engine::MetricsEngineBuilder;
+    use crate::metric::common::MetricType;
+
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let metrics_engine = Arc::new(MetricsEngineBuilder::new(temp_metric_data.path()).build().unwrap());
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let scheduler = spawn_scheduler_thread(metrics_engine.clone(), SchedulerConfig { interval: 3600.0, ..Default::default() });
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!scheduler.is_finished());
+
+    // Stopping does not wait for the interval to pass, and releases the engine
+    let stop_start = Instant::now();
+    scheduler.stop();
+    assert!(stop_start.elapsed() < Duration::from_secs(60));
+    assert_eq!(1, Arc::strong_count(&metrics_engine));
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::engine::dashboards::Dashboard;
+use crate::metric::common::{MetricConfig, MetricType};
+use crate::metric::tags::Tag;
+
+/// The definitions of an instance (metrics and dashboards) as a single document, which can be exported and applied to another instance.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Schema {
+    #[serde(default)]
+    pub metrics: BTreeMap<String, MetricSchema>,
+    #[serde(default)]
+    pub dashboards: BTreeMap<String, Dashboard>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct MetricSchema {
+    #[serde(rename="type")]
+    pub metric_type: MetricType,
+    /// Only used when creating the metric, the default config of the type is used if not set.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub config: Option<MetricConfig>,
+    /// The named primary tags.
+    #[serde(default)]
+    pub primary_tags: Vec<Tag>,
+    #[serde(default)]
+    pub auto_primary_tags: Vec<String>
+}
+
+/// The changes made when applying a schema.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct SchemaChanges {
+    pub created_metrics: Vec<String>,
+    pub removed_metrics: Vec<String>,
+    /// The existing metrics with a different config than in the schema, which is not changed when applying.
+    pub changed_configs: Vec<String>,
+    pub added_primary_tags: BTreeMap<String, Vec<Tag>>,
+    pub removed_primary_tags: BTreeMap<String, Vec<Tag>>,
+    pub added_auto_primary_tags: BTreeMap<String, Vec<String>>,
+    pub removed_auto_primary_tags: BTreeMap<String, Vec<String>>,
+    pub updated_dashboards: Vec<String>,
+    pub removed_dashboards: Vec<String>
+}
+
+/// The config as compared between the schema and the instance, where the auto primary tags are compared separately.
+pub fn config_value(config: &MetricConfig) -> Option<serde_json::Value> {
+    let mut value = serde_json::to_value(config).ok()?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("auto_primary_tags");
+    }
+
+    Some(value)
+}
+
+pub fn diff_items<T: Clone + PartialEq>(name: &str,
+                                        schema_items: &[T],
+                                        existing_items: &[T],
+                                        prune: bool,
+                                        added: &mut BTreeMap<String, Vec<T>>,
+                                        removed: &mut BTreeMap<String, Vec<T>>) {
+    let added_items = schema_items.iter().filter(|item| !existing_items.contains(item)).cloned().collect::<Vec<_>>();
+    if !added_items.is_empty() {
+        added.insert(name.to_owned(), added_items);
+    }
+
+    if prune {
+        let removed_items = existing_items.iter().filter(|item| !schema_items.contains(item)).cloned().collect::<Vec<_>>();
+        if !removed_items.is_empty() {
+            removed.insert(name.to_owned(), removed_items);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SlowQueryConfig {
+    /// Queries that take longer than this (in seconds) are logged, no queries are logged if not set.
+    pub threshold: Option<f64>,
+    /// The number of logged queries to keep, older queries are removed.
+    pub max_entries: usize
+}
+
+impl Default for SlowQueryConfig {
+    fn default() -> Self {
+        SlowQueryConfig {
+            threshold: None,
+            max_entries: 1000
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct SlowQuery {
+    /// The time the query completed.
+    pub time: f64,
+    pub request_id: Option<String>,
+    pub query: serde_json::Value,
+    /// The time (in seconds) the query took.
+    pub duration: f64,
+    pub blocks_scanned: u64
+}
+
+impl SlowQuery {
+    pub fn new(request_id: Option<String>, query: serde_json::Value, duration: Duration, blocks_scanned: u64) -> SlowQuery {
+        SlowQuery {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
+            request_id,
+            query,
+            duration: duration.as_secs_f64(),
+            blocks_scanned
+        }
+    }
+}
+
+struct SlowQueryEntries {
+    entries: VecDeque<SlowQuery>,
+    /// The number of lines in the file, which is rewritten when it contains too many removed entries.
+    num_lines: usize
+}
+
+/// The queries that exceeded the threshold, stored as JSON lines such that logging a query only appends to the file.
+pub struct SlowQueryLog {
+    path: PathBuf,
+    config: SlowQueryConfig,
+    entries: Mutex<SlowQueryEntries>
+}
+
+impl SlowQueryLog {
+    pub fn new(base_path: &Path, config: SlowQueryConfig) -> SlowQueryLog {
+        let path = base_path.join("slow_queries.jsonl");
+
+        let mut entries = VecDeque::new();
+        let mut num_lines = 0;
+        if let Ok(content) = std::fs::read_to_string(&path) {
+            for line in content.lines() {
+                num_lines += 1;
+                match serde_json::from_str::<SlowQuery>(line) {
+                    Ok(entry) => { entries.push_back(entry); }
+                    Err(err) => { tracing::warn!(path = ?path, error = %err, "skipping invalid slow query entry"); }
+                }
+            }
+        }
+
+        while entries.len() > config.max_entries {
+            entries.pop_front();
+        }
+
+        SlowQueryLog {
+            path,
+            config,
+            entries: Mutex::new(SlowQueryEntries { entries, num_lines })
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.threshold.is_some()
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        self.config.threshold.map(|threshold| duration.as_secs_f64() >= threshold).unwrap_or(false)
+    }
+
+    pub fn add(&self, entry: SlowQuery) -> std::io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        entries.entries.push_back(entry);
+        while entries.entries.len() > self.config.max_entries {
+            entries.entries.pop_front();
+        }
+
+        if entries.num_lines >= 2 * self.config.max_entries.max(1) {
+            let mut content = String::new();
+            for entry in &entries.entries {
+                content += &serde_json::to_string(entry)?;
+                content.push('\n');
+            }
+
+            std::fs::write(&self.path, content)?;
+            entries.num_lines = entries.entries.len();
+        } else {
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+            file.write_all(line.as_bytes())?;
+            entries.num_lines += 1;
+        }
+
+        Ok(())
+    }
+
+    /// The logged queries, slowest first.
+    pub fn entries(&self, limit: Option<usize>) -> Vec<SlowQuery> {
+        let mut entries = self.entries.lock().unwrap().entries.iter().cloned().collect::<Vec<_>>();
+        entries.sort_by(|x, y| y.duration.total_cmp(&x.duration));
+        entries.truncate(limit.unwrap_or(usize::MAX));
+        entries
+    }
+}
+
+#[test]
+fn test_slow_query_log1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let log = SlowQueryLog::new(temp_dir.path(), SlowQueryConfig { threshold: Some(1.0), max_entries: 2 });
+    assert!(!log.is_slow(Duration::from_millis(500)));
+    assert!(log.is_slow(Duration::from_secs(2)));
+
+    for (index, duration) in [2.0, 5.0, 3.0, 4.0, 1.5].into_iter().enumerate() {
+        let query = serde_json::json!({ "index": index });
+        log.add(SlowQuery::new(None, query, Duration::from_secs_f64(duration), 10)).unwrap();
+    }
+
+    assert_eq!(vec![4.0, 1.5], log.entries(None).iter().map(|entry| entry.duration).collect::<Vec<_>>());
+
+    let log = SlowQueryLog::new(temp_dir.path(), SlowQueryConfig { threshold: Some(1.0), max_entries: 2 });
+    assert_eq!(vec![4.0], log.entries(Some(1)).iter().map(|entry| entry.duration).collect::<Vec<_>>());
+}
@@ -0,0 +1,305 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::engine::io::MetricsEngineResult;
+use crate::engine::MetricsEngine;
+use crate::engine::background::BackgroundThread;
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    pub enabled: bool,
+    /// The directory the snapshots are created in, which should not be inside the storage folder.
+    pub directory: String,
+    /// The time (in seconds) between snapshots.
+    pub interval: f64,
+    /// The number of snapshots to keep, older snapshots are removed.
+    pub keep: usize,
+    /// A command that is run with the path of each new snapshot as its only argument.
+    pub upload_command: Option<String>,
+    /// The maximum time (in seconds) writes are paused for filesystem snapshots, in case `post_snapshot` is never called.
+    pub max_pause: f64
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            enabled: false,
+            directory: "snapshots".to_owned(),
+            interval: 6.0 * 3600.0,
+            keep: 7,
+            upload_command: None,
+            max_pause: 10.0
+        }
+    }
+}
+
+#[derive(Default)]
+struct WritePauseState {
+    paused_until: Option<Instant>,
+    active_writers: usize
+}
+
+impl WritePauseState {
+    fn is_paused(&self) -> bool {
+        self.remaining().is_some()
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        self.paused_until
+            .map(|paused_until| paused_until.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/// Allows writes to be paused while a filesystem level snapshot is taken.
+/// The pause ends after a deadline, such that writes are not blocked forever if the snapshot is never completed.
+#[derive(Default)]
+pub struct WritePause {
+    state: Mutex<WritePauseState>,
+    changed: Condvar
+}
+
+impl WritePause {
+    /// Waits until writes are not paused, and then blocks pausing until the guard is dropped.
+    pub fn enter(&self) -> WritePauseGuard<'_> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(remaining) = state.remaining() {
+            state = self.changed.wait_timeout(state, remaining).unwrap().0;
+        }
+
+        state.active_writers += 1;
+        WritePauseGuard { pause: self }
+    }
+
+    /// Pauses new writes and waits for the in-progress writes to complete.
+    pub fn pause(&self, max_pause: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.paused_until = Some(Instant::now() + max_pause);
+        while state.active_writers > 0 {
+            match state.remaining() {
+                Some(remaining) => {
+                    state = self.changed.wait_timeout(state, remaining).unwrap().0;
+                }
+                None => break
+            }
+        }
+    }
+
+    /// Resumes writes, returns false if the pause had already expired.
+    pub fn resume(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let was_paused = state.is_paused();
+        state.paused_until = None;
+        self.changed.notify_all();
+        was_paused
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().is_paused()
+    }
+}
+
+pub struct WritePauseGuard<'a> {
+    pause: &'a WritePause
+}
+
+impl Drop for WritePauseGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pause.state.lock().unwrap();
+        state.active_writers -= 1;
+        self.pause.changed.notify_all();
+    }
+}
+
+/// Copies the regular files of the directory (recursively), skipping files with the given names.
+pub fn copy_directory(source: &Path, destination: &Path, skip: &[&str]) -> std::io::Result<()> {
+    std::fs::create_dir_all(destination)?;
+
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if skip.iter().any(|skip| file_name == *skip) {
+            continue;
+        }
+
+        // Segments in the cold tier are symlinks, which are copied as the file they point to.
+        // The name of the target is kept (as it can denote compression), with a symlink to it.
+        let path = entry.path();
+        if let Ok(target) = std::fs::read_link(&path) {
+            let target_name = target.file_name().unwrap_or(&file_name);
+            std::fs::copy(&path, destination.join(target_name))?;
+            if target_name != file_name {
+                std::os::unix::fs::symlink(target_name, destination.join(&file_name))?;
+            }
+        } else if path.is_dir() {
+            copy_directory(&path, &destination.join(&file_name), skip)?;
+        } else if path.is_file() {
+            std::fs::copy(&path, destination.join(&file_name))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn snapshot_name(time: SystemTime) -> String {
+    format!("{}{}", SNAPSHOT_PREFIX, time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+}
+
+/// The snapshots in the directory, oldest first.
+pub fn list_snapshots(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let timestamp = entry.file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(SNAPSHOT_PREFIX))
+            .and_then(|timestamp| timestamp.parse::<u64>().ok());
+
+        if let Some(timestamp) = timestamp {
+            if entry.file_type()?.is_dir() {
+                snapshots.push((timestamp, entry.path()));
+            }
+        }
+    }
+
+    snapshots.sort();
+    Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Removes the oldest snapshots such that at most `keep` remain, returning the removed snapshots.
+pub fn apply_retention(directory: &Path, keep: usize) -> std::io::Result<Vec<PathBuf>> {
+    let snapshots = list_snapshots(directory)?;
+    let num_remove = snapshots.len().saturating_sub(keep);
+
+    let mut removed = Vec::new();
+    for snapshot in snapshots.into_iter().take(num_remove) {
+        std::fs::remove_dir_all(&snapshot)?;
+        removed.push(snapshot);
+    }
+
+    Ok(removed)
+}
+
+fn run_upload_command(command: &str, snapshot: &Path) {
+    match std::process::Command::new(command).arg(snapshot).status() {
+        Ok(status) if status.success() => {
+            tracing::info!(snapshot = ?snapshot, "uploaded snapshot");
+        }
+        Ok(status) => {
+            tracing::error!(snapshot = ?snapshot, status = %status, "snapshot upload command failed");
+        }
+        Err(err) => {
+            tracing::error!(snapshot = ?snapshot, error = %err, "failed to run snapshot upload command");
+        }
+    }
+}
+
+/// Creates a new snapshot in the configured directory, then uploads it and removes the oldest snapshots.
+pub fn create_snapshot(metrics_engine: &MetricsEngine, config: &SnapshotConfig) -> MetricsEngineResult<PathBuf> {
+    let directory = PathBuf::from(&config.directory);
+    let snapshot_start = Instant::now();
+    let snapshot = directory.join(snapshot_name(SystemTime::now()));
+    if let Err(err) = metrics_engine.snapshot(&snapshot) {
+        // Don't leave a partial snapshot that would count towards the retention
+        let _ = std::fs::remove_dir_all(&snapshot);
+        return Err(err);
+    }
+    tracing::info!(snapshot = ?snapshot, elapsed = snapshot_start.elapsed().as_secs_f64(), "created snapshot");
+
+    if let Some(upload_command) = config.upload_command.as_ref() {
+        run_upload_command(upload_command, &snapshot);
+    }
+
+    match apply_retention(&directory, config.keep.max(1)) {
+        Ok(removed) => {
+            for snapshot in removed {
+                tracing::info!(snapshot = ?snapshot, "removed old snapshot");
+            }
+        }
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to remove old snapshots");
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Creates a snapshot of the engine at a fixed interval, keeping only the latest snapshots.
+pub fn spawn_snapshot_thread(metrics_engine: Arc<MetricsEngine>, config: SnapshotConfig) -> BackgroundThread {
+    BackgroundThread::spawn(move |stop_signal| {
+        let interval = Duration::from_secs_f64(config.interval.max(1.0));
+
+        while stop_signal.sleep(interval) {
+            if let Err(err) = create_snapshot(&metrics_engine, &config) {
+                tracing::error!(error = %err, "failed to create snapshot");
+            }
+        }
+    })
+}
+
+#[test]
+fn test_write_pause1() {
+    let pause = Arc::new(WritePause::default());
+    let guard = pause.enter();
+
+    let pause_clone = pause.clone();
+    let pause_thread = std::thread::spawn(move || {
+        pause_clone.pause(Duration::from_secs(10));
+    });
+
+    // Pausing waits for the in-progress write
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!pause_thread.is_finished());
+    drop(guard);
+    pause_thread.join().unwrap();
+    assert!(pause.is_paused());
+
+    let pause_clone = pause.clone();
+    let write_thread = std::thread::spawn(move || {
+        let _guard = pause_clone.enter();
+    });
+
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!write_thread.is_finished());
+    assert!(pause.resume());
+    write_thread.join().unwrap();
+    assert!(!pause.is_paused());
+}
+
+#[test]
+fn test_write_pause2() {
+    let pause = WritePause::default();
+    pause.pause(Duration::from_millis(10));
+    std::thread::sleep(Duration::from_millis(20));
+
+    // The pause has expired
+    let _guard = pause.enter();
+    assert!(!pause.resume());
+}
+
+#[test]
+fn test_apply_retention1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    for timestamp in [1654077600, 1654099200, 1654088400, 1654110000] {
+        std::fs::create_dir_all(temp_dir.path().join(format!("snapshot-{}", timestamp))).unwrap();
+    }
+    std::fs::create_dir_all(temp_dir.path().join("other")).unwrap();
+
+    let removed = apply_retention(temp_dir.path(), 2).unwrap();
+    assert_eq!(
+        vec![temp_dir.path().join("snapshot-1654077600"), temp_dir.path().join("snapshot-1654088400")],
+        removed
+    );
+
+    assert_eq!(
+        vec![temp_dir.path().join("snapshot-1654099200"), temp_dir.path().join("snapshot-1654110000")],
+        list_snapshots(temp_dir.path()).unwrap()
+    );
+    assert!(temp_dir.path().join("other").exists());
+}
@@ -0,0 +1,278 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fnv::FnvHashMap;
+use serde::Deserialize;
+
+use crate::engine::disk::DiskSpace;
+use crate::engine::io::{AddGaugeValue, MetricsEngineError, MetricsEngineResult};
+use crate::engine::MetricsEngine;
+use crate::engine::background::BackgroundThread;
+use crate::metric::common::MetricType;
+use crate::metric::tags::Tag;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SystemMetricsConfig {
+    pub enabled: bool,
+    /// The time (in seconds) between samples.
+    pub interval: f64,
+    /// Prepended to the names of the metrics (such as `system_cpu_usage`).
+    pub prefix: String,
+    /// The host tag of the values, the hostname of the machine if not set.
+    pub hostname: Option<String>,
+    /// The mount points whose disk usage is sampled.
+    pub disk_paths: Vec<String>
+}
+
+impl Default for SystemMetricsConfig {
+    fn default() -> Self {
+        SystemMetricsConfig {
+            enabled: false,
+            interval: 10.0,
+            prefix: "system_".to_owned(),
+            hostname: None,
+            disk_paths: vec!["/".to_owned()]
+        }
+    }
+}
+
+/// The values of a sample, as (metric, value, tags) where the metric is without prefix.
+pub type SystemSample = Vec<(&'static str, f64, Vec<Tag>)>;
+
+/// Samples the CPU, memory, disk and network usage of the host, where the usages that are based on counters are
+/// computed relative to the previous sample.
+pub struct SystemCollector {
+    disk_paths: Vec<String>,
+    prev_cpu: FnvHashMap<String, (u64, u64)>,
+    prev_network: FnvHashMap<String, (u64, u64)>,
+    prev_time: Option<f64>
+}
+
+impl SystemCollector {
+    pub fn new(disk_paths: Vec<String>) -> SystemCollector {
+        SystemCollector {
+            disk_paths,
+            prev_cpu: FnvHashMap::default(),
+            prev_network: FnvHashMap::default(),
+            prev_time: None
+        }
+    }
+
+    pub fn collect(&mut self, time: f64) -> SystemSample {
+        let mut sample = Vec::new();
+
+        match std::fs::read_to_string("/proc/stat") {
+            Ok(stat) => { self.add_cpu_usage(&stat, &mut sample); }
+            Err(err) => { tracing::debug!(error = %err, "failed to read CPU usage"); }
+        }
+
+        match std::fs::read_to_string("/proc/meminfo") {
+            Ok(meminfo) => { add_memory_usage(&meminfo, &mut sample); }
+            Err(err) => { tracing::debug!(error = %err, "failed to read memory usage"); }
+        }
+
+        for path in &self.disk_paths {
+            match DiskSpace::of(Path::new(path)) {
+                Ok(disk_space) => {
+                    let tags = vec![Tag::from_ref("path", path)];
+                    sample.push(("disk_used_bytes", (disk_space.total_bytes - disk_space.free_bytes) as f64, tags.clone()));
+                    sample.push(("disk_total_bytes", disk_space.total_bytes as f64, tags));
+                }
+                Err(err) => { tracing::debug!(path, error = %err, "failed to read disk usage"); }
+            }
+        }
+
+        match std::fs::read_to_string("/proc/net/dev") {
+            Ok(net_dev) => { self.add_network_usage(&net_dev, time, &mut sample); }
+            Err(err) => { tracing::debug!(error = %err, "failed to read network usage"); }
+        }
+
+        self.prev_time = Some(time);
+        sample
+    }
+
+    fn add_cpu_usage(&mut self, stat: &str, sample: &mut SystemSample) {
+        for line in stat.lines() {
+            let mut parts = line.split_whitespace();
+            let core = match parts.next() {
+                Some(core) if core.starts_with("cpu") && core != "cpu" => core,
+                _ => continue
+            };
+
+            let times = parts.flat_map(u64::from_str).collect::<Vec<_>>();
+            if times.len() < 4 {
+                continue;
+            }
+
+            // Both idle and iowait are counted as idle
+            let idle = times[3] + times.get(4).copied().unwrap_or(0);
+            let total = times.iter().sum::<u64>();
+            if let Some((prev_total, prev_idle)) = self.prev_cpu.insert(core.to_owned(), (total, idle)) {
+                let diff_total = total.saturating_sub(prev_total);
+                if diff_total > 0 {
+                    let usage = 1.0 - idle.saturating_sub(prev_idle) as f64 / diff_total as f64;
+                    sample.push(("cpu_usage", usage, vec![Tag::from_ref("core", core)]));
+                }
+            }
+        }
+    }
+
+    fn add_network_usage(&mut self, net_dev: &str, time: f64, sample: &mut SystemSample) {
+        let elapsed = self.prev_time.map(|prev_time| time - prev_time);
+
+        // The first two lines are headers
+        for line in net_dev.lines().skip(2) {
+            let (interface, counters) = match line.split_once(':') {
+                Some((interface, counters)) => (interface.trim(), counters),
+                None => continue
+            };
+
+            let counters = counters.split_whitespace().flat_map(u64::from_str).collect::<Vec<_>>();
+            if counters.len() < 9 || interface == "lo" {
+                continue;
+            }
+
+            let (received, transmitted) = (counters[0], counters[8]);
+            let prev = self.prev_network.insert(interface.to_owned(), (received, transmitted));
+            if let (Some((prev_received, prev_transmitted)), Some(elapsed)) = (prev, elapsed) {
+                if elapsed > 0.0 {
+                    let tags = vec![Tag::from_ref("interface", interface)];
+                    sample.push(("network_received_bytes_per_second", received.saturating_sub(prev_received) as f64 / elapsed, tags.clone()));
+                    sample.push(("network_transmitted_bytes_per_second", transmitted.saturating_sub(prev_transmitted) as f64 / elapsed, tags));
+                }
+            }
+        }
+    }
+}
+
+fn add_memory_usage(meminfo: &str, sample: &mut SystemSample) {
+    let value = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| f64::from_str(value).ok())
+            .map(|value| value * 1024.0)
+    };
+
+    if let (Some(total), Some(available)) = (value("MemTotal"), value("MemAvailable")) {
+        sample.push(("memory_used_bytes", total - available, Vec::new()));
+        sample.push(("memory_total_bytes", total, Vec::new()));
+    }
+}
+
+/// Writes the sample to the metrics (created if needed), where all values are tagged with the host.
+pub fn write_sample(metrics_engine: &MetricsEngine,
+                    config: &SystemMetricsConfig,
+                    hostname: &str,
+                    time: f64,
+                    sample: SystemSample) -> MetricsEngineResult<()> {
+    let mut values = FnvHashMap::<&str, Vec<AddGaugeValue>>::default();
+    for (metric, value, mut tags) in sample {
+        tags.push(Tag::from_ref("host", hostname));
+        values.entry(metric).or_default().push(AddGaugeValue::new(time, value, tags));
+    }
+
+    for (metric, values) in values {
+        let name = format!("{}{}", config.prefix, metric);
+        match metrics_engine.add_metric(&name, MetricType::Gauge) {
+            Ok(()) | Err(MetricsEngineError::MetricAlreadyExists(_)) => {}
+            Err(err) => { return Err(err); }
+        }
+
+        metrics_engine.gauge(&name, values.into_iter())?;
+    }
+
+    Ok(())
+}
+
+/// Samples the system metrics of the host at the configured interval.
+pub fn spawn_system_metrics_thread(metrics_engine: Arc<MetricsEngine>, config: SystemMetricsConfig) -> BackgroundThread {
+    BackgroundThread::spawn(move |stop_signal| {
+        let hostname = config.hostname.clone().unwrap_or_else(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|hostname| hostname.trim().to_owned())
+                .unwrap_or_else(|_| "localhost".to_owned())
+        });
+
+        let mut collector = SystemCollector::new(config.disk_paths.clone());
+        while !stop_signal.is_stopped() {
+            let sample_start = Instant::now();
+
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+            let sample = collector.collect(time);
+            if let Err(err) = write_sample(&metrics_engine, &config, &hostname, time, sample) {
+                tracing::warn!(error = %err, "failed to write system metrics");
+            }
+
+            stop_signal.sleep_until(sample_start + Duration::from_secs_f64(config.interval.max(0.1)));
+        }
+    })
+}
+
+#[test]
+fn test_cpu_usage1() {
+    let mut collector = SystemCollector::new(Vec::new());
+
+    let mut sample = Vec::new();
+    collector.add_cpu_usage("cpu  300 0 100 600 0 0 0 0 0 0\ncpu0 150 0 50 300 0 0 0 0 0 0\ncpu1 150 0 50 300 0 0 0 0 0 0\n", &mut sample);
+    assert!(sample.is_empty());
+
+    collector.add_cpu_usage("cpu  400 0 100 700 0 0 0 0 0 0\ncpu0 250 0 50 300 0 0 0 0 0 0\ncpu1 150 0 50 400 0 0 0 0 0 0\n", &mut sample);
+    assert_eq!(
+        vec![("cpu_usage", 1.0, vec![Tag::from_ref("core", "cpu0")]), ("cpu_usage", 0.0, vec![Tag::from_ref("core", "cpu1")])],
+        sample
+    );
+}
+
+#[test]
+fn test_memory_usage1() {
+    let mut sample = Vec::new();
+    add_memory_usage("MemTotal:       16000 kB\nMemFree:         2000 kB\nMemAvailable:    4000 kB\n", &mut sample);
+    assert_eq!(
+        vec![("memory_used_bytes", 12000.0 * 1024.0, Vec::new()), ("memory_total_bytes", 16000.0 * 1024.0, Vec::new())],
+        sample
+    );
+}
+
+#[test]
+fn test_network_usage1() {
+    let header = "Inter-|   Receive                                                |  Transmit\n face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n";
+    let mut collector = SystemCollector::new(Vec::new());
+
+    let mut sample = Vec::new();
+    collector.add_network_usage(&format!("{}    lo: 100 1 0 0 0 0 0 0 100 1 0 0 0 0 0 0\n  eth0: 1000 10 0 0 0 0 0 0 500 5 0 0 0 0 0 0\n", header), 1654077600.0, &mut sample);
+    collector.prev_time = Some(1654077600.0);
+    assert!(sample.is_empty());
+
+    collector.add_network_usage(&format!("{}    lo: 200 2 0 0 0 0 0 0 200 2 0 0 0 0 0 0\n  eth0: 3000 30 0 0 0 0 0 0 1500 15 0 0 0 0 0 0\n", header), 1654077610.0, &mut sample);
+    assert_eq!(
+        vec![
+            ("network_received_bytes_per_second", 200.0, vec![Tag::from_ref("interface", "eth0")]),
+            ("network_transmitted_bytes_per_second", 100.0, vec![Tag::from_ref("interface", "eth0")])
+        ],
+        sample
+    );
+}
+
+#[test]
+fn test_write_sample1() {
+    use crate::model::{Query, TimeRange};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let metrics_engine = MetricsEngine::new(temp_dir.path()).unwrap();
+
+    let config = SystemMetricsConfig::default();
+    let time = 1654077600.0;
+    write_sample(&metrics_engine, &config, "host1", time, vec![("memory_used_bytes", 1000.0, Vec::new())]).unwrap();
+    write_sample(&metrics_engine, &config, "host1", time + 10.0, vec![("memory_used_bytes", 2000.0, Vec::new())]).unwrap();
+
+    assert_eq!(vec!["system_memory_used_bytes".to_owned()], metrics_engine.metric_names());
+    assert_eq!(
+        Some(1500.0),
+        metrics_engine.average("system_memory_used_bytes", Query::new(TimeRange::new(time, time + 20.0))).unwrap().value()
+    );
+}
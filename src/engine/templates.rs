@@ -0,0 +1,100 @@
+use serde::Deserialize;
+
+use crate::metric::common::{MetricConfig, MetricType};
+
+/// What happens when writing to a metric that does not exist.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum UnknownMetricMode {
+    /// The write fails with `MetricNotFound`.
+    Fail,
+    /// The write is accepted but the values are dropped (and counted).
+    Drop,
+    /// The metric is created from the first matching template, fails if there is none.
+    #[default]
+    AutoCreate
+}
+
+/// Describes how a metric that does not exist is created when it is written to.
+#[derive(Clone, Deserialize)]
+pub struct MetricTemplate {
+    /// The pattern the name of the metric must match, where `*` matches any sequence of characters.
+    pub pattern: String,
+    pub metric_type: MetricType,
+    /// The default config of the metric type is used if not set.
+    #[serde(default)]
+    pub config: Option<MetricConfig>,
+    #[serde(default)]
+    pub auto_primary_tags: Vec<String>
+}
+
+impl MetricTemplate {
+    pub fn new(pattern: &str, metric_type: MetricType) -> MetricTemplate {
+        MetricTemplate {
+            pattern: pattern.to_owned(),
+            metric_type,
+            config: None,
+            auto_primary_tags: Vec::new()
+        }
+    }
+
+    pub fn with_config(mut self, config: MetricConfig) -> MetricTemplate {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn with_auto_primary_tag(mut self, key: &str) -> MetricTemplate {
+        self.auto_primary_tags.push(key.to_owned());
+        self
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        matches_pattern(&self.pattern, name)
+    }
+
+    pub fn metric_config(&self, default_config: MetricConfig) -> MetricConfig {
+        let mut config = self.config.clone().unwrap_or(default_config);
+        for key in &self.auto_primary_tags {
+            config.add_auto_primary_tag(key);
+        }
+
+        config
+    }
+}
+
+pub(crate) fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut remaining = match name.strip_prefix(first) {
+        Some(remaining) => remaining,
+        None => return false
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some((last, middle)) => (*last, middle),
+        None => return remaining.is_empty()
+    };
+
+    for part in middle {
+        match remaining.find(part) {
+            Some(index) => remaining = &remaining[index + part.len()..],
+            None => return false
+        }
+    }
+
+    remaining.ends_with(last)
+}
+
+#[test]
+fn test_matches_pattern1() {
+    assert!(matches_pattern("cpu", "cpu"));
+    assert!(!matches_pattern("cpu", "cpu_usage"));
+    assert!(matches_pattern("cpu_*", "cpu_usage"));
+    assert!(!matches_pattern("cpu_*", "memory_usage"));
+    assert!(matches_pattern("*_bytes", "memory_bytes"));
+    assert!(matches_pattern("http.*.requests", "http.api.requests"));
+    assert!(!matches_pattern("http.*.requests", "http.api.errors"));
+    assert!(matches_pattern("*", "anything"));
+    assert!(matches_pattern("a*b*c", "abc"));
+    assert!(!matches_pattern("ab*ba", "aba"));
+}
@@ -0,0 +1,218 @@
+use std::path::{Path, PathBuf};
+
+use dashmap::DashMap;
+use fnv::FnvBuildHasher;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::engine::io::{MetricsEngineError, MetricsEngineResult};
+use crate::engine::quotas;
+use crate::engine::templates::matches_pattern;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tenant {
+    pub name: String,
+    /// The metrics of the tenant, where `*` matches any sequence of characters (such as `teamA_*`).
+    pub metrics: String,
+    /// The retention (in seconds) of metrics created for the tenant without an explicit retention.
+    #[serde(default)]
+    pub data_keep_time: Option<f64>,
+    /// The maximum disk usage (in bytes) of all metrics of the tenant, writes are rejected once exceeded.
+    #[serde(default)]
+    pub max_bytes: Option<u64>
+}
+
+impl Tenant {
+    /// The directory that the metrics of the tenant are stored in.
+    pub fn path(&self, base_path: &Path) -> PathBuf {
+        base_path.join("tenants").join(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TenantsConfig {
+    /// A metric belongs to the first tenant that matches it, metrics without a tenant are stored directly in the base directory.
+    pub tenants: Vec<Tenant>
+}
+
+impl TenantsConfig {
+    pub fn for_metric(&self, metric: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| matches_pattern(&tenant.metrics, metric))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct TenantUsage {
+    pub tenant: String,
+    pub bytes: u64,
+    pub max_bytes: Option<u64>
+}
+
+struct MetricBytes {
+    tenant: String,
+    bytes: u64,
+    changed: bool
+}
+
+/// Tracks the disk usage of the tenants as the sum of the usage of their metrics,
+/// where the usage of a metric is only determined again once it has changed.
+pub struct TenantTracker {
+    config: TenantsConfig,
+    usage: DashMap<String, u64, FnvBuildHasher>,
+    metrics: DashMap<String, MetricBytes, FnvBuildHasher>
+}
+
+impl TenantTracker {
+    pub fn new(config: TenantsConfig) -> TenantTracker {
+        TenantTracker {
+            config,
+            usage: DashMap::default(),
+            metrics: DashMap::default()
+        }
+    }
+
+    pub fn config(&self) -> &TenantsConfig {
+        &self.config
+    }
+
+    /// Checks the quota of the tenant of the metric before writing to it, which marks the usage of the metric as changed.
+    pub fn admit(&self, metric: &str) -> MetricsEngineResult<()> {
+        let tenant = match self.config.for_metric(metric) {
+            Some(tenant) => tenant,
+            None => { return Ok(()); }
+        };
+
+        if let Some(max_bytes) = tenant.max_bytes {
+            let bytes = self.usage.get(&tenant.name).map(|usage| *usage).unwrap_or(0);
+            if bytes >= max_bytes {
+                return Err(MetricsEngineError::TenantQuotaExceeded(tenant.name.clone()));
+            }
+        }
+
+        self.changed(metric);
+        Ok(())
+    }
+
+    /// Marks the usage of the metric as changed, such that it's determined again by the next update.
+    pub fn changed(&self, metric: &str) {
+        if let Some(mut metric_bytes) = self.metrics.get_mut(metric) {
+            metric_bytes.changed = true;
+        }
+    }
+
+    /// Determines the disk usage of the metric if it belongs to a tenant and has changed since it was last determined.
+    pub fn update_bytes(&self, metric: &str, metric_path: &Path) {
+        let tenant = match self.config.for_metric(metric) {
+            Some(tenant) => tenant,
+            None => { return; }
+        };
+
+        let mut metric_bytes = self.metrics.entry(metric.to_owned()).or_insert_with(|| {
+            MetricBytes {
+                tenant: tenant.name.clone(),
+                bytes: 0,
+                changed: true
+            }
+        });
+
+        if !metric_bytes.changed {
+            return;
+        }
+
+        let bytes = if metric_path.exists() { quotas::directory_size(metric_path) } else { Ok(0) };
+        match bytes {
+            Ok(bytes) => {
+                let mut usage = self.usage.entry(tenant.name.clone()).or_default();
+                *usage = (*usage + bytes).saturating_sub(metric_bytes.bytes);
+                metric_bytes.bytes = bytes;
+                metric_bytes.changed = false;
+            }
+            Err(err) => {
+                tracing::warn!(tenant = tenant.name, metric, error = %err, "Failed to determine disk usage.");
+            }
+        }
+    }
+
+    /// Removes the usage of a deleted metric from its tenant.
+    pub fn remove_metric(&self, metric: &str) {
+        if let Some((_, metric_bytes)) = self.metrics.remove(metric) {
+            if let Some(mut usage) = self.usage.get_mut(&metric_bytes.tenant) {
+                *usage = usage.saturating_sub(metric_bytes.bytes);
+            }
+        }
+    }
+
+    pub fn usage(&self) -> Vec<TenantUsage> {
+        self.config.tenants
+            .iter()
+            .map(|tenant| {
+                TenantUsage {
+                    tenant: tenant.name.clone(),
+                    bytes: self.usage.get(&tenant.name).map(|usage| *usage).unwrap_or(0),
+                    max_bytes: tenant.max_bytes
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_admit1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let tracker = TenantTracker::new(
+        TenantsConfig {
+            tenants: vec![
+                Tenant { name: "a".to_owned(), metrics: "teamA_*".to_owned(), data_keep_time: None, max_bytes: Some(10) },
+                Tenant { name: "b".to_owned(), metrics: "teamB_*".to_owned(), data_keep_time: None, max_bytes: Some(1000) }
+            ]
+        }
+    );
+
+    let metric_path = tracker.config().tenants[0].path(temp_dir.path()).join("teamA_cpu");
+    std::fs::create_dir_all(&metric_path).unwrap();
+    std::fs::write(metric_path.join("data"), vec![0; 100]).unwrap();
+    tracker.update_bytes("teamA_cpu", &metric_path);
+    tracker.update_bytes("cpu", &temp_dir.path().join("cpu"));
+
+    assert!(matches!(tracker.admit("teamA_cpu"), Err(MetricsEngineError::TenantQuotaExceeded(_))));
+    assert!(tracker.admit("teamB_cpu").is_ok());
+    assert!(tracker.admit("cpu").is_ok());
+    assert_eq!(vec![100, 0], tracker.usage().into_iter().map(|usage| usage.bytes).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_update_bytes1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let tracker = TenantTracker::new(
+        TenantsConfig {
+            tenants: vec![
+                Tenant { name: "a".to_owned(), metrics: "teamA_*".to_owned(), data_keep_time: None, max_bytes: None }
+            ]
+        }
+    );
+
+    let tenant_path = tracker.config().tenants[0].path(temp_dir.path());
+    for metric in ["teamA_cpu", "teamA_memory"] {
+        std::fs::create_dir_all(tenant_path.join(metric)).unwrap();
+        std::fs::write(tenant_path.join(metric).join("data"), vec![0; 100]).unwrap();
+        tracker.update_bytes(metric, &tenant_path.join(metric));
+    }
+    assert_eq!(200, tracker.usage()[0].bytes);
+
+    // Only determined again once changed
+    std::fs::write(tenant_path.join("teamA_cpu").join("data"), vec![0; 150]).unwrap();
+    tracker.update_bytes("teamA_cpu", &tenant_path.join("teamA_cpu"));
+    assert_eq!(200, tracker.usage()[0].bytes);
+
+    tracker.admit("teamA_cpu").unwrap();
+    tracker.update_bytes("teamA_cpu", &tenant_path.join("teamA_cpu"));
+    assert_eq!(250, tracker.usage()[0].bytes);
+
+    tracker.remove_metric("teamA_memory");
+    assert_eq!(150, tracker.usage()[0].bytes);
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::engine::MetricsEngine;
+use crate::engine::background::BackgroundThread;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TieringConfig {
+    /// The directory (e.g. on a slower disk) that old sealed segments are moved to, tiering is disabled if not set.
+    pub cold_directory: Option<String>,
+    /// The age (in seconds) of the newest datapoint of a sealed segment before it is moved.
+    pub move_after: f64,
+    /// The time (in seconds) between checking the metrics for segments to move.
+    pub interval: f64
+}
+
+impl Default for TieringConfig {
+    fn default() -> Self {
+        TieringConfig {
+            cold_directory: None,
+            move_after: 7.0 * 24.0 * 3600.0,
+            interval: 3600.0
+        }
+    }
+}
+
+impl TieringConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cold_directory.is_some()
+    }
+}
+
+/// Periodically moves the old sealed segments of all metrics to the cold directory.
+pub fn spawn_tiering_thread(metrics_engine: Arc<MetricsEngine>, config: TieringConfig) -> BackgroundThread {
+    BackgroundThread::spawn(move |stop_signal| {
+        while !stop_signal.is_stopped() {
+            let pass_start = Instant::now();
+
+            for metric in metrics_engine.metric_names() {
+                if stop_signal.is_stopped() {
+                    return;
+                }
+
+                match metrics_engine.move_to_cold_tier(&metric) {
+                    Ok(num_moved) if num_moved > 0 => {
+                        tracing::info!(metric, num_moved, "moved segments to the cold tier");
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!(metric, error = %err, "failed to move segments to the cold tier");
+                    }
+                }
+            }
+
+            stop_signal.sleep_until(pass_start + Duration::from_secs_f64(config.interval.max(0.0)));
+        }
+    })
+}
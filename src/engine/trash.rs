@@ -0,0 +1,164 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::metric::common::MetricType;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TrashConfig {
+    /// The time (in seconds) a deleted metric is kept in the trash, during which it can be undeleted.
+    pub grace_period: f64
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig {
+            grace_period: 7.0 * 24.0 * 3600.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct DeletedMetric {
+    pub name: String,
+    pub metric_type: MetricType,
+    /// The time the metric was deleted.
+    pub deleted_at: f64
+}
+
+impl DeletedMetric {
+    fn id(&self) -> String {
+        format!("{}.{}", self.name, (self.deleted_at * 1000.0) as u64)
+    }
+}
+
+/// The deleted metrics, where the directory of each metric is kept together with a file describing it.
+pub struct Trash {
+    path: PathBuf,
+    config: TrashConfig
+}
+
+impl Trash {
+    pub fn new(base_path: &Path, config: TrashConfig) -> Trash {
+        Trash {
+            path: base_path.join("trash"),
+            config
+        }
+    }
+
+    /// Moves the directory of the metric to the trash.
+    pub fn add(&self, metric_path: &Path, name: &str, metric_type: MetricType) -> std::io::Result<DeletedMetric> {
+        let deleted_metric = DeletedMetric {
+            name: name.to_owned(),
+            metric_type,
+            deleted_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
+        };
+
+        std::fs::create_dir_all(&self.path)?;
+        std::fs::write(self.info_path(&deleted_metric), serde_json::to_string(&deleted_metric)?)?;
+        if metric_path.exists() {
+            std::fs::rename(metric_path, self.metric_path(&deleted_metric))?;
+        }
+
+        Ok(deleted_metric)
+    }
+
+    /// The deleted metrics, latest first.
+    pub fn deleted_metrics(&self) -> Vec<DeletedMetric> {
+        let mut deleted_metrics = Vec::new();
+        if let Ok(entries) = std::fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().map(|extension| extension == "json").unwrap_or(false) {
+                    let deleted_metric = std::fs::read_to_string(&path)
+                        .map_err(|err| err.to_string())
+                        .and_then(|content| serde_json::from_str::<DeletedMetric>(&content).map_err(|err| err.to_string()));
+
+                    match deleted_metric {
+                        Ok(deleted_metric) => { deleted_metrics.push(deleted_metric); }
+                        Err(err) => { tracing::warn!(path = ?path, error = %err, "skipping invalid deleted metric"); }
+                    }
+                }
+            }
+        }
+
+        deleted_metrics.sort_by(|x, y| y.deleted_at.total_cmp(&x.deleted_at));
+        deleted_metrics
+    }
+
+    /// Moves the latest deleted version of the metric back to the given path.
+    pub fn restore(&self, name: &str, metric_path: &Path) -> std::io::Result<Option<DeletedMetric>> {
+        let deleted_metric = match self.deleted_metrics().into_iter().find(|deleted_metric| deleted_metric.name == name) {
+            Some(deleted_metric) => deleted_metric,
+            None => { return Ok(None); }
+        };
+
+        let trashed_path = self.metric_path(&deleted_metric);
+        if trashed_path.exists() {
+            std::fs::rename(trashed_path, metric_path)?;
+        }
+
+        std::fs::remove_file(self.info_path(&deleted_metric))?;
+        Ok(Some(deleted_metric))
+    }
+
+    /// Permanently removes the metrics that were deleted longer than the grace period ago.
+    pub fn purge(&self) -> std::io::Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+
+        let mut num_purged = 0;
+        for deleted_metric in self.deleted_metrics() {
+            if now - deleted_metric.deleted_at < self.config.grace_period {
+                continue;
+            }
+
+            let trashed_path = self.metric_path(&deleted_metric);
+            if trashed_path.exists() {
+                std::fs::remove_dir_all(trashed_path)?;
+            }
+
+            std::fs::remove_file(self.info_path(&deleted_metric))?;
+            num_purged += 1;
+        }
+
+        Ok(num_purged)
+    }
+
+    fn metric_path(&self, deleted_metric: &DeletedMetric) -> PathBuf {
+        self.path.join(deleted_metric.id())
+    }
+
+    fn info_path(&self, deleted_metric: &DeletedMetric) -> PathBuf {
+        self.path.join(format!("{}.json", deleted_metric.id()))
+    }
+}
+
+#[test]
+fn test_trash1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let metric_path = temp_dir.path().join("cpu");
+    std::fs::create_dir_all(&metric_path).unwrap();
+    std::fs::write(metric_path.join("config.json"), "{}").unwrap();
+
+    let trash = Trash::new(temp_dir.path(), TrashConfig::default());
+    trash.add(&metric_path, "cpu", MetricType::Gauge).unwrap();
+    assert!(!metric_path.exists());
+    assert_eq!(vec!["cpu".to_owned()], trash.deleted_metrics().into_iter().map(|deleted_metric| deleted_metric.name).collect::<Vec<_>>());
+
+    assert_eq!(0, trash.purge().unwrap());
+    assert_eq!(None, trash.restore("memory", &temp_dir.path().join("memory")).unwrap());
+    assert_eq!(Some(MetricType::Gauge), trash.restore("cpu", &metric_path).unwrap().map(|deleted_metric| deleted_metric.metric_type));
+    assert!(metric_path.join("config.json").exists());
+    assert!(trash.deleted_metrics().is_empty());
+
+    let trash = Trash::new(temp_dir.path(), TrashConfig { grace_period: 0.0 });
+    trash.add(&metric_path, "cpu", MetricType::Gauge).unwrap();
+    assert_eq!(1, trash.purge().unwrap());
+    assert!(trash.deleted_metrics().is_empty());
+    assert_eq!(0, std::fs::read_dir(temp_dir.path().join("trash")).unwrap().count());
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::engine::MetricsEngine;
+use crate::engine::background::BackgroundThread;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VerificationConfig {
+    pub enabled: bool,
+    /// The time (in seconds) between the start of each pass over all metrics.
+    pub interval: f64,
+    /// The pause (in seconds) after each metric, which spreads out the reads of a pass.
+    pub pause: f64
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        VerificationConfig {
+            enabled: false,
+            interval: 24.0 * 3600.0,
+            pause: 1.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct VerificationProblem {
+    pub metric: String,
+    pub description: String
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct VerificationStatus {
+    pub num_verified: u64,
+    pub last_pass_completed: Option<f64>,
+    pub problems: Vec<VerificationProblem>
+}
+
+impl VerificationStatus {
+    pub fn update(&mut self, metric: &str, problems: &[String]) {
+        self.num_verified += 1;
+        self.problems.retain(|problem| problem.metric != metric);
+        self.problems.extend(
+            problems.iter().map(|description| VerificationProblem { metric: metric.to_owned(), description: description.clone() })
+        );
+    }
+
+    pub fn complete_pass(&mut self) {
+        self.last_pass_completed = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64());
+    }
+}
+
+/// Periodically re-reads the sealed blocks of all metrics on a low priority thread, calling `on_problems` for each metric with problems.
+pub fn spawn_verification_thread(metrics_engine: Arc<MetricsEngine>,
+                                 config: VerificationConfig,
+                                 on_problems: impl Fn(&str, &[String]) + Send + 'static) -> BackgroundThread {
+    BackgroundThread::spawn(move |stop_signal| {
+        // On Linux, this only changes the priority of the calling thread
+        unsafe {
+            libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+        }
+
+        while !stop_signal.is_stopped() {
+            let pass_start = Instant::now();
+
+            for metric in metrics_engine.metric_names() {
+                match metrics_engine.verify_metric(&metric) {
+                    Ok(problems) if !problems.is_empty() => {
+                        tracing::error!(metric, num_problems = problems.len(), "verification found problems");
+                        on_problems(&metric, &problems);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!(metric, error = %err, "failed to verify metric");
+                    }
+                }
+
+                if !stop_signal.sleep(Duration::from_secs_f64(config.pause.max(0.0))) {
+                    return;
+                }
+            }
+
+            metrics_engine.verification_completed();
+            stop_signal.sleep_until(pass_start + Duration::from_secs_f64(config.interval.max(0.0)));
+        }
+    })
+}
+
+#[test]
+fn test_update_status1() {
+    let mut status = VerificationStatus::default();
+    status.update("cpu", &["block 1: invalid sub-block 0".to_owned()]);
+    status.update("memory", &["block 2: invalid sub-block 1".to_owned()]);
+    assert_eq!(2, status.problems.len());
+
+    status.update("cpu", &[]);
+    assert_eq!(3, status.num_verified);
+    assert_eq!(
+        vec![VerificationProblem { metric: "memory".to_owned(), description: "block 2: invalid sub-block 1".to_owned() }],
+        status.problems
+    );
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use fnv::FnvHashMap;
+use serde::Deserialize;
+
+use crate::metric::{GroupTimeValues, OperationResult};
+use crate::model::{GroupValue, Time, TIME_SCALE};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WindowCacheConfig {
+    /// The maximum number of cached windows over all queries, nothing is cached if zero.
+    pub max_windows: usize
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WindowCacheKey {
+    pub metric: String,
+    pub operation: String,
+    /// The query (without the time range) serialized as JSON.
+    pub query: String,
+    pub duration: Time,
+    /// The windows before the sealed time change when a primary tag receives its first datapoints.
+    pub num_primary_tags: usize
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedWindow {
+    pub timestamp: f64,
+    /// The value of each group, where ungrouped results have a single value without group.
+    pub values: Vec<(Option<GroupValue>, Option<f64>)>
+}
+
+struct CachedQuery {
+    windows: BTreeMap<Time, CachedWindow>,
+    last_used: u64
+}
+
+struct WindowCacheEntries {
+    queries: FnvHashMap<WindowCacheKey, CachedQuery>,
+    num_windows: usize,
+    counter: u64
+}
+
+/// Caches the results of windowed queries for windows that only cover sealed blocks, which cannot change.
+/// Periodically refreshed queries then only compute the trailing windows.
+pub struct WindowCache {
+    config: WindowCacheConfig,
+    entries: Mutex<WindowCacheEntries>
+}
+
+impl WindowCache {
+    pub fn new(config: WindowCacheConfig) -> WindowCache {
+        WindowCache {
+            config,
+            entries: Mutex::new(
+                WindowCacheEntries {
+                    queries: FnvHashMap::default(),
+                    num_windows: 0,
+                    counter: 0
+                }
+            )
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.max_windows > 0
+    }
+
+    pub fn num_windows(&self) -> usize {
+        self.entries.lock().unwrap().num_windows
+    }
+
+    /// The consecutive cached windows starting at the given time, at most `max_windows` of them.
+    pub fn get(&self, key: &WindowCacheKey, start_time: Time, max_windows: usize) -> Vec<CachedWindow> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.counter += 1;
+        let counter = entries.counter;
+
+        let mut windows = Vec::new();
+        if let Some(query) = entries.queries.get_mut(key) {
+            query.last_used = counter;
+            while windows.len() < max_windows {
+                match query.windows.get(&(start_time + windows.len() as Time * key.duration)) {
+                    Some(window) => windows.push(window.clone()),
+                    None => break
+                }
+            }
+        }
+
+        windows
+    }
+
+    pub fn insert(&self, key: WindowCacheKey, windows: Vec<(Time, CachedWindow)>) {
+        if windows.is_empty() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.counter += 1;
+        let counter = entries.counter;
+
+        let query = entries.queries.entry(key).or_insert_with(|| CachedQuery { windows: BTreeMap::new(), last_used: 0 });
+        query.last_used = counter;
+
+        let mut num_added = 0;
+        for (start_time, window) in windows {
+            if query.windows.insert(start_time, window).is_none() {
+                num_added += 1;
+            }
+        }
+
+        entries.num_windows += num_added;
+        while entries.num_windows > self.config.max_windows {
+            let least_recently_used = entries.queries
+                .iter()
+                .min_by_key(|(_, query)| query.last_used)
+                .map(|(key, _)| key.clone());
+
+            match least_recently_used.and_then(|key| entries.queries.remove(&key)) {
+                Some(query) => { entries.num_windows -= query.windows.len(); }
+                None => break
+            }
+        }
+    }
+
+    /// Removes the cached windows of the metric, used when its stored data is removed.
+    pub fn invalidate(&self, metric: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut num_removed = 0;
+        entries.queries.retain(|key, query| {
+            if key.metric == metric {
+                num_removed += query.windows.len();
+                false
+            } else {
+                true
+            }
+        });
+
+        entries.num_windows -= num_removed;
+    }
+}
+
+/// Splits the result of a windowed query into its windows, None if it is not a windowed result with the given number of windows.
+pub fn split_windows(result: &OperationResult, start_time: Time, duration: Time, num_windows: usize) -> Option<Vec<CachedWindow>> {
+    let timestamp = |index: usize| ((start_time + index as Time * duration) / TIME_SCALE) as f64;
+
+    match result {
+        OperationResult::TimeValues(values) if values.is_empty() => {
+            Some((0..num_windows).map(|index| CachedWindow { timestamp: timestamp(index), values: vec![(None, None)] }).collect())
+        }
+        OperationResult::TimeValues(values) if values.len() == num_windows => {
+            Some(
+                values.iter()
+                    .map(|&(timestamp, value)| CachedWindow { timestamp, values: vec![(None, value)] })
+                    .collect()
+            )
+        }
+        OperationResult::GroupTimeValues(groups) => {
+            if groups.iter().any(|(_, values)| values.len() != num_windows) {
+                return None;
+            }
+
+            Some(
+                (0..num_windows)
+                    .map(|index| {
+                        CachedWindow {
+                            timestamp: timestamp(index),
+                            values: groups.iter().map(|(group, values)| (Some(group.clone()), values[index].1)).collect()
+                        }
+                    })
+                    .collect()
+            )
+        }
+        _ => None
+    }
+}
+
+/// Joins consecutive windows into a windowed result, groups missing in a window have no value.
+pub fn join_windows(windows: Vec<CachedWindow>, grouped: bool) -> OperationResult {
+    if !grouped {
+        return OperationResult::TimeValues(
+            windows.into_iter()
+                .map(|window| (window.timestamp, window.values.into_iter().next().and_then(|(_, value)| value)))
+                .collect()
+        );
+    }
+
+    let mut group_indices = FnvHashMap::default();
+    let mut groups: GroupTimeValues = Vec::new();
+    for (window_index, window) in windows.iter().enumerate() {
+        for (group, value) in &window.values {
+            let group = match group {
+                Some(group) => group,
+                None => continue
+            };
+
+            let group_index = *group_indices.entry(group.clone()).or_insert_with(|| {
+                groups.push((group.clone(), windows.iter().map(|window| (window.timestamp, None)).collect()));
+                groups.len() - 1
+            });
+
+            groups[group_index].1[window_index].1 = *value;
+        }
+    }
+
+    OperationResult::GroupTimeValues(groups)
+}
+
+#[test]
+fn test_cache1() {
+    let cache = WindowCache::new(WindowCacheConfig { max_windows: 3 });
+    let key = |metric: &str| {
+        WindowCacheKey { metric: metric.to_owned(), operation: "average".to_owned(), query: "{}".to_owned(), duration: 10, num_primary_tags: 1 }
+    };
+    let window = |value: f64| CachedWindow { timestamp: 0.0, values: vec![(None, Some(value))] };
+
+    cache.insert(key("cpu"), vec![(0, window(1.0)), (10, window(2.0))]);
+    assert_eq!(vec![window(1.0), window(2.0)], cache.get(&key("cpu"), 0, 5));
+    assert_eq!(vec![window(2.0)], cache.get(&key("cpu"), 10, 5));
+    assert_eq!(vec![window(1.0)], cache.get(&key("cpu"), 0, 1));
+    assert!(cache.get(&key("cpu"), 5, 5).is_empty());
+
+    // The least recently used query is removed when there are too many windows
+    cache.insert(key("memory"), vec![(0, window(3.0)), (10, window(4.0))]);
+    assert_eq!(2, cache.num_windows());
+    assert!(cache.get(&key("cpu"), 0, 5).is_empty());
+
+    cache.invalidate("memory");
+    assert_eq!(0, cache.num_windows());
+}
+
+#[test]
+fn test_split_join1() {
+    let result = OperationResult::GroupTimeValues(vec![
+        (GroupValue::from_ref("a"), vec![(0.0, Some(1.0)), (10.0, None)]),
+        (GroupValue::from_ref("b"), vec![(0.0, None), (10.0, Some(2.0))])
+    ]);
+
+    let windows = split_windows(&result, 0, 10 * TIME_SCALE, 2).unwrap();
+    assert_eq!(2, windows.len());
+    assert_eq!(10.0, windows[1].timestamp);
+    assert_eq!(result, join_windows(windows, true));
+
+    let result = OperationResult::TimeValues(vec![(0.0, Some(1.0)), (10.0, None)]);
+    assert_eq!(result, join_windows(split_windows(&result, 0, 10 * TIME_SCALE, 2).unwrap(), false));
+    assert_eq!(None, split_windows(&result, 0, 10 * TIME_SCALE, 3));
+    assert_eq!(
+        OperationResult::TimeValues(vec![(0.0, None), (10.0, None)]),
+        join_windows(split_windows(&OperationResult::TimeValues(Vec::new()), 0, 10 * TIME_SCALE, 2).unwrap(), false)
+    );
+}
@@ -0,0 +1,298 @@
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::engine::io::{AddCountValue, AddGaugeValue};
+use crate::engine::{MetricsEngine, MetricsEngineBuilder};
+use crate::metric::common::{CountInput, MetricType};
+use crate::metric::tags::Tag;
+use crate::model::{Query, TimeRange};
+
+pub const METRICSDB_OK: c_int = 0;
+pub const METRICSDB_INVALID_ARGUMENT: c_int = -1;
+pub const METRICSDB_ENGINE_ERROR: c_int = -2;
+pub const METRICSDB_BUFFER_TOO_SMALL: c_int = -3;
+pub const METRICSDB_PANIC: c_int = -4;
+
+pub const METRICSDB_GAUGE: c_int = 0;
+pub const METRICSDB_COUNT: c_int = 1;
+pub const METRICSDB_RATIO: c_int = 2;
+
+/// Opens (or creates) the engine at the given path. Returns null on failure.
+///
+/// # Safety
+/// `path` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn metricsdb_open(path: *const c_char) -> *mut MetricsEngine {
+    catch_panic(std::ptr::null_mut(), || {
+        let path = match c_str(path) {
+            Some(path) => path,
+            None => return std::ptr::null_mut()
+        };
+
+        match MetricsEngineBuilder::new(Path::new(path)).build() {
+            Ok(metrics_engine) => Box::into_raw(Box::new(metrics_engine)),
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to open metrics engine.");
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Closes the engine, null is ignored.
+///
+/// # Safety
+/// `metrics_engine` must be null or a handle obtained from `metricsdb_open` that has not been closed.
+/// The handle must not be used after it has been closed.
+#[no_mangle]
+pub unsafe extern "C" fn metricsdb_close(metrics_engine: *mut MetricsEngine) {
+    catch_panic((), || {
+        if !metrics_engine.is_null() {
+            drop(Box::from_raw(metrics_engine));
+        }
+    })
+}
+
+/// Adds a metric of the given type (`METRICSDB_GAUGE`, `METRICSDB_COUNT` or `METRICSDB_RATIO`).
+///
+/// # Safety
+/// `metrics_engine` must be null or a handle obtained from `metricsdb_open` that has not been closed.
+/// `name` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn metricsdb_add_metric(metrics_engine: *const MetricsEngine,
+                                              name: *const c_char,
+                                              metric_type: c_int) -> c_int {
+    catch_panic(METRICSDB_PANIC, || {
+        let (metrics_engine, name) = match (metrics_engine.as_ref(), c_str(name)) {
+            (Some(metrics_engine), Some(name)) => (metrics_engine, name),
+            _ => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        let metric_type = match metric_type {
+            METRICSDB_GAUGE => MetricType::Gauge,
+            METRICSDB_COUNT => MetricType::Count,
+            METRICSDB_RATIO => MetricType::Ratio,
+            _ => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        to_status(metrics_engine.add_metric(name, metric_type).map(|_| ()))
+    })
+}
+
+/// Adds a value to the gauge metric.
+///
+/// # Safety
+/// `metrics_engine` must be null or a handle obtained from `metricsdb_open` that has not been closed.
+/// `name` must be null or a valid NUL-terminated string.
+/// Unless `num_tags` is zero, `tags` must point to `num_tags` valid NUL-terminated strings of the form `key:value`.
+#[no_mangle]
+pub unsafe extern "C" fn metricsdb_add_gauge(metrics_engine: *const MetricsEngine,
+                                             name: *const c_char,
+                                             time: f64,
+                                             value: f64,
+                                             tags: *const *const c_char,
+                                             num_tags: usize) -> c_int {
+    catch_panic(METRICSDB_PANIC, || {
+        let (metrics_engine, name) = match (metrics_engine.as_ref(), c_str(name)) {
+            (Some(metrics_engine), Some(name)) => (metrics_engine, name),
+            _ => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        let tags = match c_tags(tags, num_tags) {
+            Some(tags) => tags,
+            None => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        to_status(metrics_engine.gauge(name, std::iter::once(AddGaugeValue::new(time, value, tags))).map(|_| ()))
+    })
+}
+
+/// Adds a count to the count metric.
+///
+/// # Safety
+/// `metrics_engine` must be null or a handle obtained from `metricsdb_open` that has not been closed.
+/// `name` must be null or a valid NUL-terminated string.
+/// Unless `num_tags` is zero, `tags` must point to `num_tags` valid NUL-terminated strings of the form `key:value`.
+#[no_mangle]
+pub unsafe extern "C" fn metricsdb_add_count(metrics_engine: *const MetricsEngine,
+                                             name: *const c_char,
+                                             time: f64,
+                                             count: u32,
+                                             tags: *const *const c_char,
+                                             num_tags: usize) -> c_int {
+    catch_panic(METRICSDB_PANIC, || {
+        let (metrics_engine, name) = match (metrics_engine.as_ref(), c_str(name)) {
+            (Some(metrics_engine), Some(name)) => (metrics_engine, name),
+            _ => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        let tags = match c_tags(tags, num_tags) {
+            Some(tags) => tags,
+            None => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        to_status(metrics_engine.count(name, std::iter::once(AddCountValue::new(time, CountInput(count), tags))).map(|_| ()))
+    })
+}
+
+/// Computes the average in windows of the given duration. The window times and values are written to the output
+/// buffers (missing values as NaN) and the number of windows to `num_windows`.
+///
+/// # Safety
+/// `metrics_engine` must be null or a handle obtained from `metricsdb_open` that has not been closed.
+/// `name` must be null or a valid NUL-terminated string.
+/// `times` and `values` must be null or valid for writes of `capacity` values, and `num_windows` must be null or valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn metricsdb_average_in_window(metrics_engine: *const MetricsEngine,
+                                                     name: *const c_char,
+                                                     start: f64,
+                                                     end: f64,
+                                                     duration: f64,
+                                                     times: *mut f64,
+                                                     values: *mut f64,
+                                                     capacity: usize,
+                                                     num_windows: *mut usize) -> c_int {
+    catch_panic(METRICSDB_PANIC, || {
+        let (metrics_engine, name) = match (metrics_engine.as_ref(), c_str(name)) {
+            (Some(metrics_engine), Some(name)) => (metrics_engine, name),
+            _ => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        if times.is_null() || values.is_null() || num_windows.is_null() {
+            return METRICSDB_INVALID_ARGUMENT;
+        }
+
+        let (time_range, duration) = match (TimeRange::try_new(start, end), Duration::try_from_secs_f64(duration)) {
+            (Ok(time_range), Ok(duration)) => (time_range, duration),
+            _ => return METRICSDB_INVALID_ARGUMENT
+        };
+
+        let result = match metrics_engine.average_in_window(name, Query::new(time_range), duration) {
+            Ok(result) => result,
+            Err(err) => return to_status(Err(err))
+        };
+
+        let windows = result.time_values().unwrap_or_default();
+        *num_windows = windows.len();
+        if windows.len() > capacity {
+            return METRICSDB_BUFFER_TOO_SMALL;
+        }
+
+        let times = std::slice::from_raw_parts_mut(times, capacity);
+        let values = std::slice::from_raw_parts_mut(values, capacity);
+        for (index, (time, value)) in windows.into_iter().enumerate() {
+            times[index] = time;
+            values[index] = value.unwrap_or(f64::NAN);
+        }
+
+        METRICSDB_OK
+    })
+}
+
+unsafe fn c_str<'a>(value: *const c_char) -> Option<&'a str> {
+    if value.is_null() {
+        return None;
+    }
+
+    CStr::from_ptr(value).to_str().ok()
+}
+
+unsafe fn c_tags(tags: *const *const c_char, num_tags: usize) -> Option<Vec<Tag>> {
+    if num_tags == 0 {
+        return Some(Vec::new());
+    }
+
+    if tags.is_null() {
+        return None;
+    }
+
+    std::slice::from_raw_parts(tags, num_tags)
+        .iter()
+        .map(|&tag| {
+            let (key, value) = c_str(tag)?.split_once(':')?;
+            Some(Tag::from_ref(key, value))
+        })
+        .collect()
+}
+
+/// Unwinding across the FFI boundary is undefined behavior, so panics are caught and reported as the given value.
+fn catch_panic<T>(on_panic: T, run: impl FnOnce() -> T) -> T {
+    match std::panic::catch_unwind(AssertUnwindSafe(run)) {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::error!("Metrics engine operation panicked.");
+            on_panic
+        }
+    }
+}
+
+fn to_status<E: std::fmt::Display>(result: Result<(), E>) -> c_int {
+    match result {
+        Ok(()) => METRICSDB_OK,
+        Err(err) => {
+            tracing::error!(error = %err, "Metrics engine operation failed.");
+            METRICSDB_ENGINE_ERROR
+        }
+    }
+}
+
+#[test]
+fn test_ffi1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = std::ffi::CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+    let name = std::ffi::CString::new("cpu").unwrap();
+    let tag = std::ffi::CString::new("host:h1").unwrap();
+    let tags = [tag.as_ptr()];
+
+    let start_time = 1654077600.0;
+
+    unsafe {
+        let metrics_engine = metricsdb_open(path.as_ptr());
+        assert!(!metrics_engine.is_null());
+
+        assert_eq!(METRICSDB_OK, metricsdb_add_metric(metrics_engine, name.as_ptr(), METRICSDB_GAUGE));
+        for index in 0..10 {
+            let value = if index < 5 { 1.0 } else { 3.0 };
+            assert_eq!(
+                METRICSDB_OK,
+                metricsdb_add_gauge(metrics_engine, name.as_ptr(), start_time + index as f64, value, tags.as_ptr(), tags.len())
+            );
+        }
+
+        let mut times = [0.0; 1];
+        let mut values = [0.0; 1];
+        let mut num_windows = 0;
+        assert_eq!(
+            METRICSDB_BUFFER_TOO_SMALL,
+            metricsdb_average_in_window(
+                metrics_engine, name.as_ptr(),
+                start_time, start_time + 10.0, 5.0,
+                times.as_mut_ptr(), values.as_mut_ptr(), times.len(), &mut num_windows
+            )
+        );
+        assert_eq!(2, num_windows);
+
+        let mut times = [0.0; 2];
+        let mut values = [0.0; 2];
+        assert_eq!(
+            METRICSDB_OK,
+            metricsdb_average_in_window(
+                metrics_engine, name.as_ptr(),
+                start_time, start_time + 10.0, 5.0,
+                times.as_mut_ptr(), values.as_mut_ptr(), times.len(), &mut num_windows
+            )
+        );
+        assert_eq!([1.0, 3.0], values);
+
+        metricsdb_close(metrics_engine);
+    }
+}
+
+#[test]
+fn test_catch_panic1() {
+    assert_eq!(METRICSDB_OK, catch_panic(METRICSDB_PANIC, || METRICSDB_OK));
+    assert_eq!(METRICSDB_PANIC, catch_panic(METRICSDB_PANIC, || -> c_int { panic!("failure") }));
+    assert!(catch_panic(std::ptr::null_mut(), || -> *mut MetricsEngine { panic!("failure") }).is_null());
+}
@@ -0,0 +1,188 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::engine::io::{AddCountValue, AddGaugeValue};
+use crate::metric::common::CountInput;
+use crate::metric::tags::Tag;
+
+/// The times and values of a gauge workload, also used as the sample data of the integration tests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleData {
+    pub times: Vec<f64>,
+    pub values: Vec<f32>
+}
+
+#[derive(Debug, Clone)]
+pub struct GaugeWorkload {
+    pub start_time: f64,
+    pub duration: f64,
+    pub event_frequency: f64,
+    pub base: f64,
+    pub amplitude: f64,
+    pub frequency: f64,
+    pub noise: f64
+}
+
+impl Default for GaugeWorkload {
+    fn default() -> Self {
+        GaugeWorkload {
+            start_time: 1654077600.0,
+            duration: 7.0 * 24.0 * 3600.0,
+            event_frequency: 20.0,
+            base: 0.5,
+            amplitude: 0.15,
+            frequency: 0.001,
+            noise: 0.005
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CountWorkload {
+    pub start_time: f64,
+    pub duration: f64,
+    pub event_frequency: f64,
+    pub burst_probability: f64,
+    pub max_burst_size: u32
+}
+
+impl Default for CountWorkload {
+    fn default() -> Self {
+        CountWorkload {
+            start_time: 1654077600.0,
+            duration: 24.0 * 3600.0,
+            event_frequency: 5.0,
+            burst_probability: 0.01,
+            max_burst_size: 100
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TagProfile {
+    None,
+    /// Each value gets one of the tag values with equal probability.
+    Uniform { key: String, cardinality: usize },
+    /// Lower tag values are more likely, following a Zipf-like distribution.
+    Skewed { key: String, cardinality: usize }
+}
+
+pub struct Generator {
+    rng: StdRng
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {
+            rng: StdRng::seed_from_u64(seed)
+        }
+    }
+
+    pub fn gauge(&mut self, workload: &GaugeWorkload) -> SampleData {
+        let mut data = SampleData { times: Vec::new(), values: Vec::new() };
+
+        let mut time = workload.start_time;
+        while time < workload.start_time + workload.duration {
+            let mut value = workload.base + workload.amplitude * (1.0 + (workload.frequency * time).sin());
+            value += self.normal(workload.noise);
+
+            data.times.push(time);
+            data.values.push(value.clamp(0.0, 1.0) as f32);
+            time += self.exponential(workload.event_frequency);
+        }
+
+        data
+    }
+
+    pub fn gauge_values(&mut self, workload: &GaugeWorkload, tag_profile: &TagProfile) -> Vec<AddGaugeValue> {
+        let data = self.gauge(workload);
+        data.times
+            .into_iter()
+            .zip(data.values)
+            .map(|(time, value)| AddGaugeValue::new(time, value as f64, self.tags(tag_profile)))
+            .collect()
+    }
+
+    pub fn count_values(&mut self, workload: &CountWorkload, tag_profile: &TagProfile) -> Vec<AddCountValue> {
+        let mut values = Vec::new();
+
+        let mut time = workload.start_time;
+        while time < workload.start_time + workload.duration {
+            let count = if self.rng.gen_bool(workload.burst_probability.clamp(0.0, 1.0)) {
+                self.rng.gen_range(1..=workload.max_burst_size.max(1))
+            } else {
+                1
+            };
+
+            values.push(AddCountValue::new(time, CountInput(count), self.tags(tag_profile)));
+            time += self.exponential(workload.event_frequency);
+        }
+
+        values
+    }
+
+    pub fn tags(&mut self, tag_profile: &TagProfile) -> Vec<Tag> {
+        match tag_profile {
+            TagProfile::None => Vec::new(),
+            TagProfile::Uniform { key, cardinality } => {
+                let index = self.rng.gen_range(0..(*cardinality).max(1));
+                vec![Tag(key.clone(), format!("T{}", index + 1))]
+            }
+            TagProfile::Skewed { key, cardinality } => {
+                let index = self.zipf((*cardinality).max(1));
+                vec![Tag(key.clone(), format!("T{}", index + 1))]
+            }
+        }
+    }
+
+    fn normal(&mut self, scale: f64) -> f64 {
+        // Box-Muller transform
+        let u1 = 1.0 - self.rng.gen::<f64>();
+        let u2 = self.rng.gen::<f64>();
+        scale * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    fn exponential(&mut self, rate: f64) -> f64 {
+        -(1.0 - self.rng.gen::<f64>()).ln() / rate
+    }
+
+    fn zipf(&mut self, cardinality: usize) -> usize {
+        let total = (1..=cardinality).map(|rank| 1.0 / rank as f64).sum::<f64>();
+        let mut target = self.rng.gen::<f64>() * total;
+        for rank in 1..=cardinality {
+            target -= 1.0 / rank as f64;
+            if target <= 0.0 {
+                return rank - 1;
+            }
+        }
+
+        cardinality - 1
+    }
+}
+
+#[test]
+fn test_generate_gauge1() {
+    let workload = GaugeWorkload { duration: 3600.0, ..Default::default() };
+
+    let data1 = Generator::new(1337).gauge(&workload);
+    let data2 = Generator::new(1337).gauge(&workload);
+    assert_eq!(data1.times, data2.times);
+    assert_eq!(data1.values, data2.values);
+
+    let rate = data1.times.len() as f64 / workload.duration;
+    assert!((rate - workload.event_frequency).abs() < 1.0);
+    assert!(data1.values.iter().all(|&value| (0.0..=1.0).contains(&value)));
+    assert!(data1.times.windows(2).all(|times| times[0] <= times[1]));
+}
+
+#[test]
+fn test_generate_tags1() {
+    let mut generator = Generator::new(4711);
+    let tag_profile = TagProfile::Skewed { key: "host".to_owned(), cardinality: 10 };
+
+    let values = generator.count_values(&CountWorkload::default(), &tag_profile);
+    let num_first = values.iter().filter(|value| value.tags[0].1 == "T1").count();
+    let num_last = values.iter().filter(|value| value.tags[0].1 == "T10").count();
+    assert!(num_first > num_last);
+}
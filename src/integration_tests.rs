@@ -1,38 +1,57 @@
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
 use approx::assert_abs_diff_eq;
 use lazy_static::lazy_static;
-use serde::Deserialize;
 use tempfile::tempdir;
 
-use crate::engine::MetricsEngine;
-use crate::engine::io::{AddCountValue, AddGaugeValue};
+use crate::engine::{MetricsEngine, MetricsEngineBuilder};
+use crate::engine::limits::QueryLimits;
+use crate::engine::lock_watchdog::{LockWatchdog, LockWatchdogConfig};
+use crate::engine::preaggregation::PreAggregationConfig;
+use crate::engine::quotas::{MetricQuota, WriteQuotas};
+use crate::engine::schema::{MetricSchema, SchemaChanges};
+use crate::engine::slow_queries::SlowQueryConfig;
+use crate::engine::tenants::{Tenant, TenantsConfig};
+use crate::engine::window_cache::WindowCacheConfig;
+use crate::engine::templates::{MetricTemplate, UnknownMetricMode};
+use crate::engine::clock_skew::ClockSkewTolerance;
+use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue, MetricsEngineError};
 use crate::engine::querying::{MetricQuery, MetricQueryExpression};
-use crate::metric::common::{GenericMetric, MetricType, MetricConfig, MetricStorageDurationConfig};
+use crate::generate::{GaugeWorkload, Generator, SampleData};
+use crate::metric::common::{GaugeCollision, GenericMetric, MetricType, MetricConfig, MetricStorageDurationConfig, RollupConfig, RollupOperation};
 use crate::metric::common::CountInput;
 use crate::metric::count::DefaultCountMetric;
 use crate::metric::expression::{ArithmeticOperation, CompareOperation, FilterExpression, Function, TransformExpression};
 use crate::metric::gauge::DefaultGaugeMetric;
-use crate::metric::OperationResult;
-use crate::metric::ratio::{DefaultRatioMetric, RatioInput};
+use crate::metric::{OperationResult, OTHER_GROUP};
+use crate::metric::operations::{AverageWeighting, PercentileAlgorithm};
+use crate::metric::ratio::{DefaultRatioMetric, RatioInput, wilson_interval};
 use crate::metric::tags::{PrimaryTag, Tag, TagsFilter};
-use crate::model::{GroupKey, GroupValue, Query, TimeRange};
-
-#[derive(Deserialize)]
-struct SampleData {
-    times: Vec<f64>,
-    values: Vec<f32>
-}
+use crate::model::{GroupKey, GroupLimit, GroupValue, MetricError, Query, QueryError, ReadConsistency, TimeRange};
 
 lazy_static! {
-    static ref SAMPLE_DATA: SampleData = load_sample_data();
+    static ref SAMPLE_DATA: SampleData = Generator::new(1337).gauge(&GaugeWorkload { event_frequency: 5.0, ..Default::default() });
 }
 
-fn load_sample_data() -> SampleData {
-    let data = std::fs::read_to_string("output.json").unwrap();
-    let data: SampleData = serde_json::from_str(&data).unwrap();
-    data
+/// The relative path and content of every file in the directory.
+fn directory_contents(path: &Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut contents = Vec::new();
+    let mut directories = vec![path.to_owned()];
+    while let Some(directory) = directories.pop() {
+        for entry in std::fs::read_dir(&directory).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                directories.push(entry.path());
+            } else {
+                contents.push((entry.path().strip_prefix(path).unwrap().to_owned(), std::fs::read(entry.path()).unwrap()));
+            }
+        }
+    }
+
+    contents.sort();
+    contents
 }
 
 #[test]
@@ -53,7 +72,7 @@ fn test_gauge_average1() {
     }
 
     assert_eq!(
-        Some(0.667791913241474),
+        Some(0.6669117897016185),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -78,7 +97,7 @@ fn test_gauge_average2() {
     }
 
     assert_eq!(
-        Some(0.6676946286179699),
+        Some(0.667261069097595),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -103,7 +122,7 @@ fn test_gauge_average3() {
     }
 
     assert_eq!(
-        Some(0.6676941904100635),
+        Some(0.6672762664901963),
         metric.average(
             Query::new(TimeRange::new(start_time, end_time))
                 .with_tags_filter(TagsFilter::And(vec![tags_list[0].clone()]))
@@ -129,7 +148,7 @@ fn test_gauge_average4() {
     }
 
     assert_eq!(
-        Some(0.8143444467992389),
+        Some(0.8138019333606837),
         metric.average(
             Query::new(TimeRange::new(start_time, end_time))
                 .with_input_transform(TransformExpression::Function { function: Function::Sqrt, arguments: vec![TransformExpression::InputValue] })
@@ -156,20 +175,20 @@ fn test_gauge_average5() {
 
     assert_eq!(
         Some(vec![
-            (1654596000.0, Some(0.7810616929429516)),
-            (1654596500.0, Some(0.7984125255297858)),
-            (1654597000.0, Some(0.7794862563100798)),
-            (1654597500.0, Some(0.7288043881887649)),
-            (1654598000.0, Some(0.6590226288448248)),
-            (1654598500.0, Some(0.5867395178973674)),
-            (1654599000.0, Some(0.5302283972157132)),
-            (1654599500.0, Some(0.5026470303535462)),
-            (1654600000.0, Some(0.5116417237243929)),
-            (1654600500.0, Some(0.554182909562916)),
-            (1654601000.0, Some(0.6203749242122579)),
-            (1654601500.0, Some(0.693674767847678)),
-            (1654602000.0, Some(0.7563903743624687)),
-            (1654602500.0, Some(0.7929651886075586))
+            (1654596000.0, Some(0.7807955817811808)),
+            (1654596500.0, Some(0.7984972200257991)),
+            (1654597000.0, Some(0.7795500827905459)),
+            (1654597500.0, Some(0.7286961196687227)),
+            (1654598000.0, Some(0.6582816255064079)),
+            (1654598500.0, Some(0.586558422267625)),
+            (1654599000.0, Some(0.5301435223368348)),
+            (1654599500.0, Some(0.5029175874522874)),
+            (1654600000.0, Some(0.5116638106843218)),
+            (1654600500.0, Some(0.5541849966746081)),
+            (1654601000.0, Some(0.6201167229149077)),
+            (1654601500.0, Some(0.6934793267215368)),
+            (1654602000.0, Some(0.7563091281716863)),
+            (1654602500.0, Some(0.7928456305113776))
         ]),
         metric.average_in_window(
             Query::new(TimeRange::new(start_time, end_time)),
@@ -196,7 +215,7 @@ fn test_gauge_max1() {
     }
 
     assert_eq!(
-        Some(0.8197603225708008),
+        Some(0.817139208316803),
         metric.max(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -219,7 +238,7 @@ fn test_gauge_95th1() {
     }
 
     assert_eq!(
-        Some(0.8006096125819039),
+        Some(0.800312015264881),
         metric.percentile(Query::new(TimeRange::new(start_time, end_time)), 95).value()
     );
 }
@@ -245,8 +264,8 @@ fn test_gauge_group_by_average1() {
 
     assert_eq!(
         OperationResult::GroupValues(vec![
-            (GroupValue::from_ref("T1"), Some(0.6676941904100635)),
-            (GroupValue::from_ref("T2"), Some(0.6676950667588899))
+            (GroupValue::from_ref("T1"), Some(0.6672762664901963)),
+            (GroupValue::from_ref("T2"), Some(0.6672458620100309))
         ]),
         metric.average(
             Query::new(TimeRange::new(start_time, end_time))
@@ -278,8 +297,8 @@ fn test_gauge_group_by_average2() {
 
     assert_eq!(
         OperationResult::GroupValues(vec![
-            (GroupValue::from_ref("T1"), Some(0.6677078367421156)),
-            (GroupValue::from_ref("T2"), Some(0.6676991150199966))
+            (GroupValue::from_ref("T1"), Some(0.6672762664901963)),
+            (GroupValue::from_ref("T2"), Some(0.6672402504144168))
         ]),
         metric.average(
             Query::new(TimeRange::new(start_time, end_time))
@@ -288,6 +307,40 @@ fn test_gauge_group_by_average2() {
     );
 }
 
+#[test]
+fn test_gauge_group_by_primary_tag1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0 + 6.0 * 24.0 * 3600.0;
+    let end_time = start_time + 2.0 * 3600.0;
+    let tags_list = vec![Tag::from_ref("tag", "T1"), Tag::from_ref("tag", "T2"), Tag::from_ref("tag", "T3")];
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    metric.add_primary_tag(PrimaryTag::Named(tags_list[0].clone())).unwrap();
+    metric.add_primary_tag(PrimaryTag::Named(tags_list[1].clone())).unwrap();
+
+    for index in 0..SAMPLE_DATA.times.len() {
+        let tags = vec![tags_list[(index % 3)].to_owned()];
+        metric.add(SAMPLE_DATA.times[index], SAMPLE_DATA.values[index] as f64, tags).unwrap();
+
+        if SAMPLE_DATA.times[index] >= end_time + 3600.0 {
+            break;
+        }
+    }
+
+    let query = Query::new(TimeRange::new(start_time, end_time));
+    let all_groups = metric.average(query.clone().with_group_by(GroupKey::from_ref("tag"))).group_values().unwrap();
+    assert_eq!(3, all_groups.len());
+
+    let primary_tag_groups = metric.average(query.clone().group_by_primary_tag("tag")).group_values().unwrap();
+    assert_eq!(&all_groups[..2], &primary_tag_groups[..]);
+
+    let primary_tag_groups = metric.average(
+        query.with_tags_filter(TagsFilter::Or(vec![tags_list[1].clone()])).group_by_primary_tag("tag")
+    ).group_values().unwrap();
+    assert_eq!(&all_groups[1..2], &primary_tag_groups[..]);
+}
+
 #[test]
 fn test_gauge_reload1() {
     let temp_metric_data = tempdir().unwrap();
@@ -312,7 +365,7 @@ fn test_gauge_reload1() {
     }
 
     assert_eq!(
-        Some(0.667791913241474),
+        Some(0.6669117897016185),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -339,7 +392,7 @@ fn test_gauge_primary_tag_average1() {
     }
 
     assert_eq!(
-        Some(0.6677034751310084),
+        Some(0.6672582648327054),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -366,7 +419,7 @@ fn test_gauge_primary_tag_average2() {
     }
 
     assert_eq!(
-        Some(0.6677034751310084),
+        Some(0.6672582648327054),
         metric.average(
             Query::new(TimeRange::new(start_time, end_time))
                 .with_tags_filter(TagsFilter::Or(vec![tags_list[0].clone(), tags_list[1].clone()]))
@@ -402,7 +455,7 @@ fn test_gauge_auto_primary_tag_average1() {
     );
 
     assert_eq!(
-        Some(0.6677034751310084),
+        Some(0.6672582648327054),
         metric.average(
             Query::new(TimeRange::new(start_time, end_time))
                 .with_tags_filter(TagsFilter::Or(vec![tags_list[0].clone(), tags_list[1].clone()]))
@@ -432,7 +485,7 @@ fn test_gauge_primary_tag_95th1() {
     }
 
     assert_abs_diff_eq!(
-        0.8004917040615059,
+        0.800380998861695,
         metric.percentile(Query::new(TimeRange::new(start_time, end_time)), 95).value().unwrap_or(0.0),
         epsilon = 1e-5
     );
@@ -464,7 +517,7 @@ fn test_gauge_segments_reload1() {
     }
 
     assert_eq!(
-        Some(0.667791913241474),
+        Some(0.6669117897016185),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -489,7 +542,7 @@ fn test_gauge_segments_remove1() {
     }
 
     assert_eq!(
-        Some(0.667791913241474),
+        Some(0.6669117897016185),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -514,7 +567,7 @@ fn test_gauge_segments_remove2() {
     }
 
     assert_eq!(
-        Some(0.667791913241474),
+        Some(0.6669117897016185),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -540,7 +593,7 @@ fn test_gauge_multiple_durations1() {
     }
 
     assert_eq!(
-        Some(0.667791913241474),
+        Some(0.6669117897016185),
         metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -565,22 +618,22 @@ fn test_gauge_multiple_durations2() {
 
     assert_eq!(
         Some(vec![
-            (1654596000.0, Some(0.7623410224914551)),
-            (1654596015.0, Some(0.7596292793750763)),
-            (1654596030.0, Some(0.7643710076808929)),
-            (1654596045.0, Some(0.7709805965423584))
+            (1654596000.0, Some(0.7655343413352966)),
+            (1654596015.0, Some(0.7614072561264038)),
+            (1654596030.0, Some(0.7562398314476013)),
+            (1654596045.0, Some(0.7671620845794678))
         ]),
         metric.average_in_window(Query::new(TimeRange::new(start_time, start_time + 60.0)), Duration::from_secs_f64(15.0)).time_values()
     );
 
     assert_eq!(
         Some(vec![
-            (1654596006.0, Some(0.7623410224914551)),
-            (1654596016.0, Some(0.7514280676841736)),
-            (1654596026.0, Some(0.767830491065979)),
-            (1654596036.0, Some(0.7641525864601135)),
-            (1654596044.0, Some(0.7645894289016724)),
-            (1654596054.0, Some(0.7709805965423584))
+            (1654596006.0, Some(0.7655343413352966)),
+            (1654596016.0, Some(0.7564069628715515)),
+            (1654596026.0, Some(0.7664075493812561)),
+            (1654596036.0, Some(0.7562398314476013)),
+            (1654596046.0, Some(0.7642343640327454)),
+            (1654596056.0, Some(0.7700898051261902))
         ]),
         metric.average_in_window(Query::new(TimeRange::new(start_time, start_time + 60.0)), Duration::from_secs_f64(2.0)).time_values()
     );
@@ -609,51 +662,128 @@ fn test_gauge_multiple_durations3() {
 
     assert_eq!(
         Some(vec![
-            (1654596000.0, Some(0.7623410224914551)),
-            (1654596015.0, Some(0.7596292793750763)),
-            (1654596030.0, Some(0.7643710076808929)),
-            (1654596045.0, Some(0.7709805965423584))
+            (1654596000.0, Some(0.7655343413352966)),
+            (1654596015.0, Some(0.7614072561264038)),
+            (1654596030.0, Some(0.7562398314476013)),
+            (1654596045.0, Some(0.7671620845794678))
         ]),
         metric.average_in_window(Query::new(TimeRange::new(start_time, start_time + 60.0)), Duration::from_secs_f64(15.0)).time_values()
     );
 
     assert_eq!(
         Some(vec![
-            (1654596000.0, Some(0.7637167572975159)),
-            (1654596002.0, Some(0.7580450773239136)),
-            (1654596004.0, Some(0.7613156139850616)),
-            (1654596006.0, Some(0.7570991516113281)),
-            (1654596008.0, Some(0.7641935348510742)),
-            (1654596010.0, Some(0.7648496627807617)),
-            (1654596012.0, Some(0.7661760151386261)),
-            (1654596014.0, Some(0.7678832113742828)),
-            (1654596016.0, Some(0.760563462972641)),
-            (1654596018.0, Some(0.7685678601264954)),
-            (1654596020.0, Some(0.7670826613903046)),
-            (1654596022.0, Some(0.7649800479412079)),
-            (1654596024.0, Some(0.7651969194412231)),
-            (1654596026.0, Some(0.7659425735473633)),
-            (1654596028.0, Some(0.7556097507476807)),
-            (1654596030.0, Some(0.761206328868866)),
-            (1654596032.0, Some(0.7634029388427734)),
-            (1654596034.0, Some(0.7706423401832581)),
-            (1654596036.0, Some(0.7674989998340607)),
-            (1654596038.0, Some(0.758998692035675)),
-            (1654596040.0, Some(0.7695607244968414)),
-            (1654596042.0, Some(0.7605744898319244)),
-            (1654596044.0, Some(0.7630680203437805)),
-            (1654596046.0, Some(0.7692327797412872)),
-            (1654596048.0, Some(0.7624682188034058)),
-            (1654596050.0, Some(0.7596468925476074)),
-            (1654596052.0, Some(0.7719657719135284)),
-            (1654596054.0, Some(0.7644509077072144)),
-            (1654596056.0, Some(0.76540207862854)),
-            (1654596058.0, Some(0.7614836096763611))
+            (1654596000.0, Some(0.7613887190818787)),
+            (1654596002.0, Some(0.7544766068458557)),
+            (1654596004.0, Some(0.7614923119544983)),
+            (1654596006.0, Some(0.7618088126182556)),
+            (1654596008.0, Some(0.755911111831665)),
+            (1654596010.0, Some(0.7585034072399139)),
+            (1654596012.0, Some(0.7600761651992798)),
+            (1654596014.0, Some(0.7667132019996643)),
+            (1654596016.0, Some(0.7582692503929138)),
+            (1654596018.0, Some(0.7714272439479828)),
+            (1654596020.0, Some(0.7605118751525879)),
+            (1654596022.0, Some(0.7667253017425537)),
+            (1654596024.0, Some(0.7611173093318939)),
+            (1654596026.0, Some(0.7605282068252563)),
+            (1654596028.0, Some(0.7666554152965546)),
+            (1654596030.0, Some(0.7650625109672546)),
+            (1654596032.0, Some(0.7613300085067749)),
+            (1654596034.0, Some(0.765879213809967)),
+            (1654596036.0, Some(0.764632910490036)),
+            (1654596038.0, Some(0.7595379948616028)),
+            (1654596040.0, Some(0.7657462954521179)),
+            (1654596042.0, Some(0.7650035917758942)),
+            (1654596044.0, Some(0.7653763294219971)),
+            (1654596046.0, Some(0.7617731690406799)),
+            (1654596048.0, Some(0.7712474763393402)),
+            (1654596050.0, Some(0.7596482038497925)),
+            (1654596052.0, Some(0.7653418183326721)),
+            (1654596054.0, Some(0.7675566077232361)),
+            (1654596056.0, Some(0.7575685977935791)),
+            (1654596058.0, Some(0.7732893228530884))
         ]),
         metric.average_in_window(Query::new(TimeRange::new(start_time, start_time + 60.0)), Duration::from_secs_f64(2.0)).time_values()
     );
 }
 
+#[test]
+fn test_gauge_multiple_durations4() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0 + 6.0 * 24.0 * 3600.0;
+    let end_time = start_time + 2.0 * 3600.0;
+
+    let mut coarse_config = MetricConfig::new(MetricType::Gauge);
+    coarse_config.durations[0].datapoint_duration = 10.0;
+
+    let mut fine_duration = MetricStorageDurationConfig::default_for(MetricType::Gauge);
+    fine_duration.datapoint_duration = 1.0;
+    let mut fine_config = MetricConfig::new(MetricType::Gauge);
+    fine_config.durations[0] = fine_duration.clone();
+
+    // The fine duration only keeps the most recent data
+    let mut config = coarse_config.clone();
+    fine_duration.set_max_segments(3600.0);
+    config.durations.push(fine_duration);
+
+    let mut metric = DefaultGaugeMetric::with_config(&temp_metric_data.path().join("stitched"), config).unwrap();
+    let mut coarse_metric = DefaultGaugeMetric::with_config(&temp_metric_data.path().join("coarse"), coarse_config).unwrap();
+    let mut fine_metric = DefaultGaugeMetric::with_config(&temp_metric_data.path().join("fine"), fine_config).unwrap();
+
+    for index in 0..SAMPLE_DATA.times.len() {
+        metric.add(SAMPLE_DATA.times[index], SAMPLE_DATA.values[index] as f64, Vec::new()).unwrap();
+        coarse_metric.add(SAMPLE_DATA.times[index], SAMPLE_DATA.values[index] as f64, Vec::new()).unwrap();
+        fine_metric.add(SAMPLE_DATA.times[index], SAMPLE_DATA.values[index] as f64, Vec::new()).unwrap();
+        if SAMPLE_DATA.times[index] >= end_time + 3600.0 {
+            break;
+        }
+    }
+
+    metric.scheduled();
+
+    let query = Query::new(TimeRange::new(start_time, end_time + 3600.0));
+    let duration = Duration::from_secs_f64(2.0);
+    let values = metric.average_in_window(query.clone(), duration).time_values().unwrap();
+    let coarse_values = coarse_metric.average_in_window(query.clone(), duration).time_values().unwrap();
+    let fine_values = fine_metric.average_in_window(query, duration).time_values().unwrap();
+
+    // Older windows come from the coarse duration and recent ones from the fine duration
+    let split_index = values.iter().position(|value| !coarse_values.contains(value)).unwrap();
+    assert!(split_index > 0);
+    assert!(values[..split_index].iter().all(|value| coarse_values.contains(value)));
+    assert!(values[split_index..].iter().all(|value| fine_values.contains(value)));
+}
+
+#[test]
+fn test_gauge_collision1() {
+    let start_time = 1654077600.0;
+    let query = Query::new(TimeRange::new(start_time, start_time + 10.0));
+
+    for (gauge_collision, expected) in [(GaugeCollision::KeepLast, 5.0), (GaugeCollision::Average, 3.0), (GaugeCollision::Min, 1.0), (GaugeCollision::Max, 5.0)] {
+        let temp_metric_data = tempdir().unwrap();
+
+        let mut config = MetricConfig::new(MetricType::Gauge);
+        config.durations[0].datapoint_duration = 5.0;
+        config.gauge_collision = gauge_collision;
+        let mut metric = DefaultGaugeMetric::with_config(temp_metric_data.path(), config).unwrap();
+
+        // The values within the same 5 seconds are combined into one datapoint
+        for index in 0..5 {
+            metric.add(start_time + index as f64, (index + 1) as f64, vec![Tag::from_ref("source", "single")]).unwrap();
+        }
+
+        let values = (0..5)
+            .map(|index| (start_time + index as f64, (index + 1) as f64, vec![Tag::from_ref("source", "batch")]))
+            .collect::<Vec<_>>();
+        assert_eq!(5, metric.add_batch(&values).unwrap());
+
+        let tags_filter = |source| TagsFilter::And(vec![Tag::from_ref("source", source)]);
+        assert_eq!(Some(expected), metric.max(query.clone().with_tags_filter(tags_filter("single"))).value(), "{:?}", gauge_collision);
+        assert_eq!(Some(expected), metric.max(query.clone().with_tags_filter(tags_filter("batch"))).value(), "{:?}", gauge_collision);
+    }
+}
+
 #[test]
 fn test_count_sum1() {
     let temp_metric_data = tempdir().unwrap();
@@ -672,7 +802,7 @@ fn test_count_sum1() {
     }
 
     assert_eq!(
-        Some(144322.0),
+        Some(36227.0),
         metric.sum(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -699,7 +829,7 @@ fn test_count_primary_tag_sum1() {
     }
 
     assert_eq!(
-        Some(144338.0),
+        Some(36234.0),
         metric.sum(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -726,7 +856,7 @@ fn test_count_primary_tag_sum2() {
     }
 
     assert_eq!(
-        Some(144338.0),
+        Some(36234.0),
         metric.sum(
             Query::new(TimeRange::new(start_time, end_time))
                 .with_tags_filter(TagsFilter::Or(vec![tags_list[0].clone(), tags_list[1].clone()]))
@@ -756,7 +886,7 @@ fn test_ratio_sum1() {
     }
 
     assert_eq!(
-        Some(0.46893058577347874),
+        Some(0.4657851878433213),
         metric.sum(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -785,14 +915,14 @@ fn test_ratio_sum2() {
     assert_eq!(
         Some(
             vec![
-                (1654596500.0, Some(1.0)),
                 (1654597000.0, Some(1.0)),
-                (1654598500.0, Some(0.0)),
+                (1654598000.0, Some(0.00777000777000777)),
+                (1654599000.0, Some(0.0)),
                 (1654599500.0, Some(0.0)),
-                (1654600000.0, Some(0.0)),
                 (1654600500.0, Some(0.0)),
-                (1654601500.0, Some(0.41670732897433393)),
-                (1654602500.0, Some(1.0))
+                (1654601000.0, Some(0.0)),
+                (1654601500.0, Some(0.40751098681582104)),
+                (1654602000.0, Some(1.0))
             ]
         ),
         metric.sum_in_window(
@@ -801,7 +931,7 @@ fn test_ratio_sum2() {
                     FilterExpression::Compare {
                         operation: CompareOperation::GreaterThan,
                         left: Box::new(FilterExpression::Value(TransformExpression::InputDenominator)),
-                        right: Box::new(FilterExpression::Value(TransformExpression::Value(10000.0)))
+                        right: Box::new(FilterExpression::Value(TransformExpression::Value(2500.0)))
                     }
                 )
             ,
@@ -837,7 +967,7 @@ fn test_ratio_primary_tag_sum1() {
     }
 
     assert_eq!(
-        Some(0.46898945530629493),
+        Some(0.46588839211790034),
         metric.sum(Query::new(TimeRange::new(start_time, end_time))).value()
     );
 }
@@ -865,12 +995,12 @@ fn test_metrics_engine1() {
     }
 
     assert_eq!(
-        Some(0.6676946286179699),
+        Some(0.667261069097595),
         metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
     );
 
     assert_eq!(
-        Some(144338.0),
+        Some(36234.0),
         metrics_engine.sum("perf_events", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
     );
 }
@@ -896,7 +1026,7 @@ fn test_metrics_engine_query1() {
     }
 
     assert_eq!(
-        Some(100.0 * 0.6676946286179699),
+        Some(100.0 * 0.667261069097595),
         metrics_engine.query(
             MetricQuery::new(
                 TimeRange::new(start_time, end_time),
@@ -938,7 +1068,7 @@ fn test_metrics_engine_query2() {
     }
 
     assert_eq!(
-        Some(1.4583610857319427),
+        Some(1.4592734898147008),
         metrics_engine.query(
             MetricQuery::new(
                 TimeRange::new(start_time, end_time),
@@ -985,7 +1115,7 @@ fn test_metrics_engine_query3() {
     }
 
     assert_eq!(
-        Some(vec![(GroupValue::from_ref("1"), Some(1.4584017863792649)), (GroupValue::from_ref("2"), Some(1.458320393630842))]),
+        Some(vec![(GroupValue::from_ref("1"), Some(1.4592055474756478)), (GroupValue::from_ref("2"), Some(1.4593414849282602))]),
         metrics_engine.query(
             MetricQuery::new(
                 TimeRange::new(start_time, end_time),
@@ -1032,7 +1162,7 @@ fn test_metrics_engine_query4() {
     }
 
     assert_eq!(
-        Some(vec![(GroupValue::from_ref("1"), Some(0.6676941904100635)), (GroupValue::from_ref("2"), Some(0.6676950667588899))]),
+        Some(vec![(GroupValue::from_ref("1"), Some(0.6672762664901963)), (GroupValue::from_ref("2"), Some(0.6672458620100309))]),
         metrics_engine.query(
             MetricQuery::new(
                 TimeRange::new(start_time, end_time),
@@ -1052,4 +1182,1804 @@ fn test_metrics_engine_query4() {
             )
         ).unwrap().group_values()
     );
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_gauge_last1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    for index in 0..10 {
+        metric.add(start_time + index as f64, index as f64, Vec::new()).unwrap();
+    }
+
+    assert_eq!(
+        Some(9.0),
+        metric.last(Query::new(TimeRange::new(start_time, end_time))).value()
+    );
+
+    assert_eq!(
+        None,
+        metric.last(Query::new(TimeRange::new(start_time, end_time)).with_staleness(5.0)).value()
+    );
+
+    assert_eq!(
+        Some(4.0),
+        metric.last(Query::new(TimeRange::new(start_time, start_time + 4.5)).with_staleness(5.0)).value()
+    );
+}
+
+#[test]
+fn test_count_last1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let mut config = MetricConfig::new(MetricType::Count);
+    config.staleness = 5.0;
+    let mut metric = DefaultCountMetric::with_config(temp_metric_data.path(), config).unwrap();
+    for index in 0..10 {
+        metric.add(start_time + index as f64, CountInput(index + 1), Vec::new()).unwrap();
+    }
+
+    assert_eq!(
+        None,
+        metric.last(Query::new(TimeRange::new(start_time, end_time))).value()
+    );
+
+    assert_eq!(
+        Some(10.0),
+        metric.last(Query::new(TimeRange::new(start_time, end_time)).with_staleness(15.0)).value()
+    );
+}
+
+#[test]
+fn test_gauge_percentile_digests1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    for index in 0..360 {
+        metric.add(start_time + index as f64 * 10.0, index as f64, Vec::new()).unwrap();
+    }
+
+    let query = Query::new(TimeRange::new(start_time, end_time));
+    let partial_query = Query::new(TimeRange::new(start_time + 900.0, start_time + 2700.0));
+
+    let expected_value = metric.percentile(query.clone(), 50).value().unwrap();
+    let expected_partial_value = metric.percentile(partial_query.clone(), 50).value().unwrap();
+
+    metric.scheduled();
+    assert!(temp_metric_data.path().join("default").join("digests.jsonl").exists());
+
+    assert_abs_diff_eq!(expected_value, metric.percentile(query, 50).value().unwrap(), epsilon = 2.0);
+    assert_abs_diff_eq!(expected_partial_value, metric.percentile(partial_query, 50).value().unwrap(), epsilon = 2.0);
+}
+
+#[test]
+fn test_gauge_percentile_histogram1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    for index in 0..360 {
+        metric.add(start_time + index as f64 * 10.0, index as f64, Vec::new()).unwrap();
+    }
+
+    let query = Query::new(TimeRange::new(start_time, end_time)).with_percentile_algorithm(PercentileAlgorithm::Histogram);
+    assert_abs_diff_eq!(
+        metric.percentile(query.clone().with_percentile_algorithm(PercentileAlgorithm::TDigest), 90).value().unwrap(),
+        metric.percentile(query.clone(), 90).value().unwrap(),
+        epsilon = 20.0
+    );
+
+    let values = metric.percentile_in_window(query, Duration::from_secs_f64(600.0), 50).time_values().unwrap();
+    assert_eq!(6, values.len());
+    for (index, (_, value)) in values.into_iter().enumerate() {
+        assert_abs_diff_eq!(index as f64 * 60.0 + 30.0, value.unwrap(), epsilon = 10.0);
+    }
+}
+
+#[test]
+fn test_gauge_average_weighting1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+    let tags_list = vec![Tag::from_ref("host", "a"), Tag::from_ref("host", "b")];
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    metric.add_primary_tag(PrimaryTag::Named(tags_list[0].clone())).unwrap();
+    metric.add_primary_tag(PrimaryTag::Named(tags_list[1].clone())).unwrap();
+
+    for index in 0..300 {
+        metric.add(start_time + index as f64 * 10.0, 1.0, vec![tags_list[0].clone()]).unwrap();
+    }
+
+    for index in 0..30 {
+        metric.add(start_time + 5.0 + index as f64 * 100.0, 12.0, vec![tags_list[1].clone()]).unwrap();
+    }
+
+    let query = Query::new(TimeRange::new(start_time, end_time));
+    assert_abs_diff_eq!(2.0, metric.average(query.clone()).value().unwrap(), epsilon = 1E-9);
+    assert_abs_diff_eq!(
+        6.5,
+        metric.average(query.clone().with_average_weighting(AverageWeighting::PrimaryTags)).value().unwrap(),
+        epsilon = 1E-9
+    );
+
+    let values = metric.average_in_window(query.with_average_weighting(AverageWeighting::PrimaryTags), Duration::from_secs_f64(1800.0)).time_values().unwrap();
+    assert_eq!(2, values.len());
+    for (_, value) in values {
+        assert_abs_diff_eq!(6.5, value.unwrap(), epsilon = 1E-9);
+    }
+}
+
+#[test]
+fn test_gauge_time_weighted_average1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    for index in 0..60 {
+        let time = start_time + index as f64 * 60.0;
+        metric.add(time, 1.0, Vec::new()).unwrap();
+
+        // A burst of dense samples
+        if index == 30 {
+            for burst_index in 1..21 {
+                metric.add(time + burst_index as f64 * 0.5, 10.0, Vec::new()).unwrap();
+            }
+        }
+    }
+
+    let query = Query::new(TimeRange::new(start_time, end_time));
+    assert_abs_diff_eq!(3.25, metric.average(query.clone()).value().unwrap(), epsilon = 1E-6);
+
+    let query = query.with_average_weighting(AverageWeighting::Time);
+    assert_abs_diff_eq!(4135.5 / 3600.0, metric.average(query.clone()).value().unwrap(), epsilon = 1E-6);
+
+    let values = metric.average_in_window(query, Duration::from_secs_f64(1800.0)).time_values().unwrap();
+    assert_eq!(2, values.len());
+    assert_abs_diff_eq!(1.0, values[0].1.unwrap(), epsilon = 1E-6);
+    assert_abs_diff_eq!(2335.5 / 1800.0, values[1].1.unwrap(), epsilon = 1E-6);
+}
+
+#[test]
+fn test_gauge_add_batch1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    let mut values = Vec::new();
+    for index in 0..1000 {
+        let tags = vec![Tag::from_ref("host", if index % 2 == 0 { "a" } else { "b" })];
+        values.push((start_time + index as f64 * 3.0, index as f64, tags));
+    }
+    values.reverse();
+
+    let metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    assert_eq!(1000, metric.add_batch(&values).unwrap());
+
+    assert_eq!(
+        Some(499.5),
+        metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
+    );
+
+    assert_eq!(
+        Some(500.0),
+        metric.average(Query::new(TimeRange::new(start_time, end_time)).with_tags_filter(TagsFilter::And(vec![Tag::from_ref("host", "b")]))).value()
+    );
+}
+
+#[test]
+fn test_gauge_add_batch2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let values = (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, 1.0, Vec::new()));
+    assert_eq!(10, metrics_engine.gauge("cpu", values).unwrap());
+    assert_eq!(0, metrics_engine.rejected_values());
+
+    // The values older than the latest datapoint are rejected
+    let values = (0..10).map(|index| AddGaugeValue::new(start_time + 5.0 + index as f64 * 2.0, 2.0, Vec::new()));
+    assert_eq!(8, metrics_engine.gauge("cpu", values).unwrap());
+    assert_eq!(2, metrics_engine.rejected_values());
+
+    assert_eq!(
+        Some(2.0),
+        metrics_engine.max("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+    assert!(matches!(
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time, 3.0, Vec::new())].into_iter()),
+        Err(MetricsEngineError::Metric(MetricError::InvalidTimeOrder))
+    ));
+}
+
+#[test]
+fn test_count_add_batch1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metric = DefaultCountMetric::new(temp_metric_data.path()).unwrap();
+    let values = vec![
+        (start_time + 2.0, CountInput(1), Vec::new()),
+        (start_time, CountInput(1), Vec::new()),
+        (start_time, CountInput(1), Vec::new()),
+        (start_time + 1.0, CountInput(1), Vec::new())
+    ];
+    assert_eq!(4, metric.add_batch(&values).unwrap());
+
+    assert_eq!(
+        Some(4.0),
+        metric.sum(Query::new(TimeRange::new(start_time, end_time))).value()
+    );
+}
+
+#[test]
+fn test_metrics_engine_lazy_load1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    {
+        let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+        metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+        metrics_engine.gauge("cpu", (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()))).unwrap();
+        metrics_engine.scheduled();
+    }
+
+    let metrics_engine = MetricsEngine::from_existing(&Path::new(temp_metric_data.path())).unwrap();
+    assert_eq!(vec!["cpu".to_owned()], metrics_engine.metric_names());
+
+    assert_eq!(
+        Some(4.5),
+        metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+
+    assert!(metrics_engine.average("memory", Query::new(TimeRange::new(start_time, end_time))).is_err());
+}
+
+#[test]
+fn test_metrics_engine_warm1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    {
+        let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+        for index in 0..8 {
+            metrics_engine.add_metric(&format!("cpu{}", index), MetricType::Gauge).unwrap();
+        }
+    }
+
+    let metrics_engine = MetricsEngine::from_existing(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.warm(4).unwrap();
+
+    let status = metrics_engine.loading_status();
+    assert_eq!(8, status.total);
+    assert_eq!(8, status.loaded);
+    assert_eq!(0, status.failed);
+    assert!(status.is_done());
+}
+
+#[test]
+fn test_gauge_read_only1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    for index in 0..100 {
+        metric.add(start_time + index as f64 * 10.0, index as f64, Vec::new()).unwrap();
+    }
+
+    let mut read_only_metric = DefaultGaugeMetric::from_existing_read_only(temp_metric_data.path()).unwrap();
+    assert_eq!(
+        Some(49.5),
+        read_only_metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
+    );
+
+    assert!(read_only_metric.add(start_time + 2000.0, 1.0, Vec::new()).is_err());
+}
+
+#[test]
+fn test_gauge_read_only2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    {
+        let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+        metric.add_auto_primary_tag("host").unwrap();
+        for index in 0..100 {
+            metric.add(start_time + index as f64 * 10.0, index as f64, vec![Tag::from_ref("host", "a"), Tag::from_ref("core", "1")]).unwrap();
+        }
+    }
+
+    let contents = directory_contents(temp_metric_data.path());
+
+    let mut read_only_metric = DefaultGaugeMetric::from_existing_read_only(temp_metric_data.path()).unwrap();
+    assert_eq!(
+        Some(49.5),
+        read_only_metric.average(Query::new(TimeRange::new(start_time, end_time))).value()
+    );
+
+    assert!(matches!(
+        read_only_metric.add(start_time + 2000.0, 1.0, vec![Tag::from_ref("host", "b")]),
+        Err(MetricError::ReadOnly)
+    ));
+    assert!(matches!(
+        read_only_metric.add_concurrent(start_time + 2000.0, 1.0, vec![Tag::from_ref("core", "2")]),
+        Err(MetricError::ReadOnly)
+    ));
+    assert!(matches!(
+        read_only_metric.add_batch(&[(start_time + 2000.0, 1.0, vec![Tag::from_ref("host", "a"), Tag::from_ref("core", "2")])]),
+        Err(MetricError::ReadOnly)
+    ));
+    assert!(matches!(read_only_metric.add_auto_primary_tag("core"), Err(MetricError::ReadOnly)));
+    read_only_metric.scheduled();
+    drop(read_only_metric);
+
+    assert_eq!(contents, directory_contents(temp_metric_data.path()));
+}
+
+#[test]
+fn test_metrics_engine_errors1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let err = metrics_engine.average("memory", Query::new(TimeRange::new(start_time, end_time))).unwrap_err();
+    assert_eq!("metric 'memory' not found", err.to_string());
+
+    let err = metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap_err();
+    assert_eq!("metric 'cpu' already exists", err.to_string());
+
+    let err = metrics_engine.count("cpu", vec![AddCountValue::new(start_time, CountInput(1), Vec::new())].into_iter()).unwrap_err();
+    assert_eq!("metric 'cpu' has the wrong type", err.to_string());
+}
+
+#[test]
+fn test_metrics_engine_query_ratio_parts1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0 + 6.0 * 24.0 * 3600.0;
+    let end_time = start_time + 2.0 * 3600.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("errors", MetricType::Ratio).unwrap();
+
+    let values = (0..SAMPLE_DATA.times.len())
+        .take_while(|&index| SAMPLE_DATA.times[index] < end_time + 3600.0)
+        .map(|index| {
+            AddRatioValue::new(
+                SAMPLE_DATA.times[index],
+                RatioInput(CountInput(if SAMPLE_DATA.values[index] > 0.7 {1} else {0}), CountInput(1)),
+                Vec::new()
+            )
+        })
+        .collect::<Vec<_>>();
+    metrics_engine.ratio("errors", values.into_iter()).unwrap();
+
+    let numerator = MetricQueryExpression::Numerator { metric: "errors".to_owned(), query: Query::placeholder() };
+    let denominator = MetricQueryExpression::Denominator { metric: "errors".to_owned(), query: Query::placeholder() };
+
+    let time_range = TimeRange::new(start_time, end_time);
+    let numerator_value = metrics_engine.query(MetricQuery::new(time_range, numerator.clone())).unwrap().value().unwrap();
+    let denominator_value = metrics_engine.query(MetricQuery::new(time_range, denominator.clone())).unwrap().value().unwrap();
+    assert!(numerator_value > 0.0 && numerator_value < denominator_value);
+    assert_eq!(denominator_value.fract(), 0.0);
+
+    assert_eq!(
+        Some(0.4657851878433213),
+        metrics_engine.query(
+            MetricQuery::new(
+                time_range,
+                MetricQueryExpression::Arithmetic {
+                    operation: ArithmeticOperation::Divide,
+                    left: Box::new(numerator),
+                    right: Box::new(denominator)
+                }
+            )
+        ).unwrap().value()
+    );
+}
+
+#[test]
+fn test_metrics_engine_query_ratio_datapoints1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 10.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("errors", MetricType::Ratio).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.ratio(
+        "errors",
+        (0..10).map(|index| AddRatioValue::new(start_time + index as f64, RatioInput(CountInput(index % 2), CountInput(2)), Vec::new()))
+    ).unwrap();
+
+    let time_range = TimeRange::new(start_time, end_time);
+    let value = |expression| metrics_engine.query(MetricQuery::new(time_range, expression)).unwrap().value();
+    let metric = "errors".to_owned();
+    assert_eq!(Some(5.0), value(MetricQueryExpression::Numerator { metric: metric.clone(), query: Query::placeholder() }));
+    assert_eq!(Some(20.0), value(MetricQueryExpression::Denominator { metric: metric.clone(), query: Query::placeholder() }));
+    assert_eq!(Some(10.0), value(MetricQueryExpression::Datapoints { metric, query: Query::placeholder() }));
+
+    assert_eq!(
+        Some(vec![(start_time, Some(5.0)), (start_time + 5.0, Some(5.0))]),
+        metrics_engine.datapoints_in_window("errors", Query::new(time_range), Duration::from_secs_f64(5.0)).unwrap().time_values()
+    );
+
+    assert!(matches!(
+        metrics_engine.datapoints("cpu", Query::new(time_range)),
+        Err(MetricsEngineError::WrongMetricType(_))
+    ));
+}
+
+#[test]
+fn test_metrics_engine_query_ratio_interval1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 10.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("errors", MetricType::Ratio).unwrap();
+    metrics_engine.ratio(
+        "errors",
+        (0..10).map(|index| AddRatioValue::new(start_time + index as f64, RatioInput(CountInput(index % 2), CountInput(2)), Vec::new()))
+    ).unwrap();
+
+    let time_range = TimeRange::new(start_time, end_time);
+    let expression = |confidence| MetricQueryExpression::RatioInterval { metric: "errors".to_owned(), query: Query::placeholder(), confidence };
+
+    let values = metrics_engine.query(MetricQuery::new(time_range, expression(0.95))).unwrap().group_values().unwrap();
+    let (_, lower, upper) = wilson_interval(5.0, 20.0, 1.959964).unwrap();
+    assert_eq!(
+        vec![GroupValue::from_ref("ratio"), GroupValue::from_ref("lower"), GroupValue::from_ref("upper")],
+        values.iter().map(|value| value.0.clone()).collect::<Vec<_>>()
+    );
+    assert_eq!(Some(0.25), values[0].1);
+    assert_abs_diff_eq!(lower, values[1].1.unwrap(), epsilon = 1E-6);
+    assert_abs_diff_eq!(upper, values[2].1.unwrap(), epsilon = 1E-6);
+
+    // Wider interval for a higher confidence
+    let values = metrics_engine.query(MetricQuery::new(time_range, expression(0.99))).unwrap().group_values().unwrap();
+    assert!(values[1].1.unwrap() < lower && values[2].1.unwrap() > upper);
+
+    let values = metrics_engine.query_in_window(MetricQuery::new(time_range, expression(0.95)), Duration::from_secs_f64(5.0))
+        .unwrap()
+        .group_time_values()
+        .unwrap();
+    assert_eq!(3, values.len());
+    assert_eq!(vec![(start_time, Some(0.2)), (start_time + 5.0, Some(0.3))], values[0].1);
+
+    assert!(matches!(
+        metrics_engine.query(MetricQuery::new(time_range, expression(1.0))),
+        Err(MetricsEngineError::InvalidQueryInput(_))
+    ));
+}
+
+#[test]
+fn test_metrics_engine_builder1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let mut config = MetricConfig::new(MetricType::Gauge);
+    config.durations[0].datapoint_duration = 5.0;
+
+    {
+        let metrics_engine = MetricsEngineBuilder::new(&Path::new(temp_metric_data.path()))
+            .with_default_config(MetricType::Gauge, config)
+            .with_max_loaded_metrics(1)
+            .build()
+            .unwrap();
+
+        metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+        metrics_engine.add_metric("memory", MetricType::Gauge).unwrap();
+        metrics_engine.gauge("cpu", (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()))).unwrap();
+        metrics_engine.gauge("memory", (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, 1.0, Vec::new()))).unwrap();
+
+        // Datapoints within the same 5 seconds are merged
+        assert_eq!(
+            Some(6.5),
+            metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+        );
+    }
+
+    let metrics_engine = MetricsEngineBuilder::new(&Path::new(temp_metric_data.path()))
+        .read_only(true)
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        Some(1.0),
+        metrics_engine.average("memory", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+
+    assert!(metrics_engine.gauge("cpu", vec![AddGaugeValue::new(end_time, 1.0, Vec::new())].into_iter()).is_err());
+    assert!(metrics_engine.add_metric("disk", MetricType::Gauge).is_err());
+}
+
+#[test]
+fn test_metrics_engine_read_only1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    {
+        let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+        metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+        metrics_engine.add_auto_primary_tag("cpu", "host").unwrap();
+        metrics_engine.gauge("cpu", (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, vec![Tag::from_ref("host", "a")]))).unwrap();
+        metrics_engine.shutdown().unwrap();
+    }
+
+    let contents = directory_contents(temp_metric_data.path());
+
+    {
+        let metrics_engine = MetricsEngineBuilder::new(&Path::new(temp_metric_data.path()))
+            .read_only(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            Some(4.5),
+            metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+        );
+
+        let values = vec![AddGaugeValue::new(end_time, 1.0, vec![Tag::from_ref("host", "b")])];
+        assert!(metrics_engine.gauge("cpu", values.into_iter()).is_err());
+        metrics_engine.scheduled_metric("cpu").unwrap();
+        metrics_engine.purge_deleted_metrics();
+        metrics_engine.shutdown().unwrap();
+    }
+
+    assert_eq!(contents, directory_contents(temp_metric_data.path()));
+}
+
+#[test]
+fn test_metrics_engine_builder2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let num_values = 1000;
+    let end_time = start_time + num_values as f64;
+
+    let metrics_engine = std::sync::Arc::new(
+        MetricsEngineBuilder::new(&Path::new(temp_metric_data.path()))
+            .with_max_loaded_metrics(1)
+            .build()
+            .unwrap()
+    );
+
+    let metric_names = vec!["cpu", "memory", "disk", "network"];
+    for metric_name in &metric_names {
+        metrics_engine.add_metric(metric_name, MetricType::Gauge).unwrap();
+    }
+
+    // Each thread loads a different metric, which unloads the metrics of the other threads
+    let threads = metric_names
+        .iter()
+        .map(|metric_name| {
+            let metrics_engine = metrics_engine.clone();
+            let metric_name = metric_name.to_string();
+            std::thread::spawn(move || {
+                for batch_index in 0..(num_values / 10) {
+                    let values = (0..10).map(|index| AddGaugeValue::new(start_time + (batch_index * 10 + index) as f64, 1.0, Vec::new()));
+                    assert_eq!(10, metrics_engine.gauge(&metric_name, values).unwrap());
+                    metrics_engine.sum(&metric_name, Query::new(TimeRange::new(start_time, end_time))).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    for metric_name in &metric_names {
+        assert_eq!(
+            Some(num_values as f64),
+            metrics_engine.sum(metric_name, Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+        );
+    }
+}
+
+#[test]
+fn test_query_validate1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_metric("requests", MetricType::Count).unwrap();
+
+    let err = metrics_engine.sum(
+        "requests",
+        Query::new(TimeRange::new(start_time, end_time))
+            .with_input_filter(FilterExpression::Compare {
+                operation: CompareOperation::GreaterThan,
+                left: Box::new(FilterExpression::Value(TransformExpression::InputValue)),
+                right: Box::new(FilterExpression::Value(TransformExpression::Value(1.0)))
+            })
+    ).unwrap_err();
+    assert_eq!("invalid query: Count metrics do not support input filters or transforms", err.to_string());
+
+    let err = metrics_engine.average(
+        "cpu",
+        Query::new(TimeRange::new(start_time, end_time))
+            .with_tags_filter(TagsFilter::OrAnd(vec![Tag::from_ref("tag", "T1")], vec![Tag::from_ref("tag", "T2")]))
+            .with_group_by(GroupKey::from_ref("tag"))
+    ).unwrap_err();
+    assert_eq!("invalid query: grouping is not supported together with an or-and tags filter", err.to_string());
+
+    assert!(TimeRange::try_new(end_time, start_time).is_err());
+    assert!(TimeRange::try_new(start_time, f64::NAN).is_err());
+    assert!(Query::new(TimeRange::new(start_time, end_time)).with_staleness(-1.0).validate().is_err());
+}
+
+#[test]
+fn test_query_validate2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_metric("requests", MetricType::Count).unwrap();
+    metrics_engine.add_metric("errors", MetricType::Ratio).unwrap();
+
+    let time_range = TimeRange::new(start_time, end_time);
+    let invalid_count_query = Query::placeholder().with_input_transform(TransformExpression::InputValue);
+    let expression = MetricQueryExpression::Arithmetic {
+        operation: ArithmeticOperation::Add,
+        left: Box::new(MetricQueryExpression::Average { metric: "cpu".to_owned(), query: Query::placeholder() }),
+        right: Box::new(MetricQueryExpression::Sum { metric: "requests".to_owned(), query: invalid_count_query })
+    };
+    let err = metrics_engine.query(MetricQuery::new(time_range, expression.clone())).unwrap_err();
+    assert_eq!("invalid query: Count metrics do not support input filters or transforms", err.to_string());
+    let err = metrics_engine.query_in_window(MetricQuery::new(time_range, expression), Duration::from_secs_f64(5.0)).unwrap_err();
+    assert_eq!("invalid query: Count metrics do not support input filters or transforms", err.to_string());
+
+    let expression = MetricQueryExpression::Numerator {
+        metric: "errors".to_owned(),
+        query: Query::placeholder().with_output_transform(TransformExpression::InputDenominator)
+    };
+    let err = metrics_engine.query(MetricQuery::new(time_range, expression)).unwrap_err();
+    assert_eq!("invalid query: output transforms are not supported for the parts of a ratio", err.to_string());
+
+    let expression = MetricQueryExpression::Denominator { metric: "cpu".to_owned(), query: Query::placeholder() };
+    let err = metrics_engine.query(MetricQuery::new(time_range, expression)).unwrap_err();
+    assert_eq!("metric 'cpu' has the wrong type", err.to_string());
+
+    let expression = MetricQueryExpression::Let {
+        name: "x".to_owned(),
+        value: Box::new(MetricQueryExpression::Average { metric: "memory".to_owned(), query: Query::placeholder() }),
+        body: Box::new(MetricQueryExpression::Variable("x".to_owned()))
+    };
+    let err = metrics_engine.query(MetricQuery::new(time_range, expression)).unwrap_err();
+    assert_eq!("metric 'memory' not found", err.to_string());
+}
+
+#[test]
+fn test_query_limits1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 3600.0;
+
+    let metrics_engine = MetricsEngineBuilder::new(&Path::new(temp_metric_data.path()))
+        .with_query_limits(QueryLimits { max_query_values: Some(100), ..Default::default() })
+        .build()
+        .unwrap();
+
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.gauge(
+        "cpu",
+        (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, 1.0, vec![Tag::from_ref("core", &index.to_string())]))
+    ).unwrap();
+
+    let query = Query::new(TimeRange::new(start_time, end_time)).with_group_by(GroupKey::from_ref("core"));
+    assert!(metrics_engine.average("cpu", query.clone()).is_ok());
+    assert!(metrics_engine.average_in_window("cpu", query.clone(), Duration::from_secs_f64(600.0)).is_ok());
+
+    let err = metrics_engine.average_in_window("cpu", query, Duration::from_secs_f64(60.0)).unwrap_err();
+    assert_eq!("the query would produce 600 values, the maximum is 100", err.to_string());
+}
+
+#[test]
+fn test_write_quotas1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let mut write_quotas = WriteQuotas::default();
+    write_quotas.metrics.insert("cpu".to_owned(), MetricQuota { max_datapoints_per_day: Some(100), ..Default::default() });
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_write_quotas(write_quotas)
+        .build()
+        .unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_metric("memory", MetricType::Gauge).unwrap();
+
+    let values = |count: usize| (0..count).map(|index| AddGaugeValue::new(1654077600.0 + index as f64, 1.0, Vec::new())).collect::<Vec<_>>();
+    assert_eq!(80, metrics_engine.gauge("cpu", values(80).into_iter()).unwrap());
+    assert!(matches!(metrics_engine.gauge("cpu", values(30).into_iter()), Err(MetricsEngineError::QuotaExceeded(_))));
+    assert_eq!(200, metrics_engine.gauge("memory", values(200).into_iter()).unwrap());
+
+    let usage = metrics_engine.quota_usage();
+    assert_eq!(1, usage.len());
+    assert_eq!(80, usage[0].datapoints_today);
+    assert_eq!(Some(100), usage[0].max_datapoints_per_day);
+}
+
+#[test]
+fn test_tenants1() {
+    let temp_metric_data = tempdir().unwrap();
+    let tenants = TenantsConfig {
+        tenants: vec![
+            Tenant { name: "a".to_owned(), metrics: "teamA_*".to_owned(), data_keep_time: Some(24.0 * 3600.0), max_bytes: None }
+        ]
+    };
+
+    let start_time = 1654077600.0;
+    {
+        let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+            .with_tenants(tenants.clone())
+            .build()
+            .unwrap();
+        metrics_engine.add_metric("teamA_cpu", MetricType::Gauge).unwrap();
+        metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+        metrics_engine.gauge("teamA_cpu", vec![AddGaugeValue::new(start_time, 1.0, Vec::new())].into_iter()).unwrap();
+        metrics_engine.scheduled();
+
+        assert!(metrics_engine.default_metric_config("teamA_memory", MetricType::Gauge).durations.iter().all(|duration| duration.max_segments.is_some()));
+        assert!(metrics_engine.default_metric_config("memory", MetricType::Gauge).durations[0].max_segments.is_none());
+    }
+
+    assert!(temp_metric_data.path().join("tenants").join("a").join("teamA_cpu").exists());
+    assert!(temp_metric_data.path().join("cpu").exists());
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_tenants(tenants)
+        .build()
+        .unwrap();
+    let value = metrics_engine.average("teamA_cpu", Query::new(TimeRange::new(start_time, start_time + 60.0))).unwrap().value();
+    assert_eq!(Some(1.0), value);
+
+    let usage = metrics_engine.tenant_usage();
+    assert_eq!(1, usage.len());
+    assert!(usage[0].bytes > 0);
+}
+
+#[test]
+fn test_tenants2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    {
+        let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path()).build().unwrap();
+        metrics_engine.add_metric("teamA_cpu", MetricType::Gauge).unwrap();
+        metrics_engine.gauge("teamA_cpu", vec![AddGaugeValue::new(start_time, 1.0, Vec::new())].into_iter()).unwrap();
+        metrics_engine.scheduled();
+    }
+
+    assert!(temp_metric_data.path().join("teamA_cpu").exists());
+
+    // Metrics created before the tenant existed are moved into its directory
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_tenants(
+            TenantsConfig {
+                tenants: vec![
+                    Tenant { name: "a".to_owned(), metrics: "teamA_*".to_owned(), data_keep_time: None, max_bytes: None }
+                ]
+            }
+        )
+        .build()
+        .unwrap();
+    assert!(!temp_metric_data.path().join("teamA_cpu").exists());
+    assert!(temp_metric_data.path().join("tenants").join("a").join("teamA_cpu").exists());
+
+    let value = metrics_engine.average("teamA_cpu", Query::new(TimeRange::new(start_time, start_time + 60.0))).unwrap().value();
+    assert_eq!(Some(1.0), value);
+    assert!(metrics_engine.tenant_usage()[0].bytes > 0);
+
+    metrics_engine.delete_metric("teamA_cpu").unwrap();
+    assert_eq!(0, metrics_engine.tenant_usage()[0].bytes);
+}
+
+#[test]
+fn test_snapshot1() {
+    let temp_metric_data = tempdir().unwrap();
+    let temp_snapshot = tempdir().unwrap();
+    let snapshot_path = temp_snapshot.path().join("snapshot-1654077600");
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_metric("memory", MetricType::Gauge).unwrap();
+    metrics_engine.gauge("cpu", (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()))).unwrap();
+    metrics_engine.snapshot(&snapshot_path).unwrap();
+
+    // Values added after the snapshot are not part of it
+    metrics_engine.gauge("cpu", (10..20).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()))).unwrap();
+
+    let snapshot_engine = MetricsEngine::from_existing(&snapshot_path).unwrap();
+    let mut metric_names = snapshot_engine.metric_names();
+    metric_names.sort();
+    assert_eq!(vec!["cpu".to_owned(), "memory".to_owned()], metric_names);
+
+    assert_eq!(
+        Some(4.5),
+        snapshot_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+    assert_eq!(
+        Some(9.5),
+        metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+}
+
+#[test]
+fn test_snapshot2() {
+    let temp_metric_data = tempdir().unwrap();
+    let temp_snapshot = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let num_values = 5000;
+    let end_time = start_time + num_values as f64;
+
+    let metrics_engine = std::sync::Arc::new(MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap());
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let write_engine = metrics_engine.clone();
+    let write_thread = std::thread::spawn(move || {
+        for index in 0..num_values {
+            write_engine.gauge("cpu", std::iter::once(AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()))).unwrap();
+        }
+    });
+
+    let mut snapshot_paths = Vec::new();
+    while !write_thread.is_finished() {
+        let snapshot_path = temp_snapshot.path().join(format!("snapshot-{}", snapshot_paths.len()));
+        metrics_engine.snapshot(&snapshot_path).unwrap();
+        snapshot_paths.push(snapshot_path);
+    }
+    write_thread.join().unwrap();
+
+    // Each snapshot must contain a prefix of the written values
+    for snapshot_path in snapshot_paths {
+        let snapshot_engine = MetricsEngine::from_existing(&snapshot_path).unwrap();
+        let query = Query::new(TimeRange::new(start_time, end_time));
+        if let Some(max) = snapshot_engine.max("cpu", query.clone()).unwrap().value() {
+            assert_eq!(Some(max / 2.0), snapshot_engine.average("cpu", query).unwrap().value());
+        }
+    }
+}
+
+#[test]
+fn test_pre_post_snapshot1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = std::sync::Arc::new(MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap());
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.gauge("cpu", (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()))).unwrap();
+
+    metrics_engine.pre_snapshot(Duration::from_secs(10)).unwrap();
+
+    let write_engine = metrics_engine.clone();
+    let write_thread = std::thread::spawn(move || {
+        write_engine.gauge("cpu", (10..20).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()))).unwrap();
+    });
+
+    // Writes are paused until the snapshot is done
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!write_thread.is_finished());
+    assert_eq!(
+        Some(4.5),
+        metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+
+    assert!(metrics_engine.post_snapshot());
+    write_thread.join().unwrap();
+    assert_eq!(
+        Some(9.5),
+        metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+}
+
+#[test]
+fn test_concurrent_queries1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let num_values = 20000;
+    let end_time = start_time + num_values as f64;
+
+    let metrics_engine = std::sync::Arc::new(MetricsEngine::new(&Path::new(temp_metric_data.path())).unwrap());
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let write_engine = metrics_engine.clone();
+    let write_thread = std::thread::spawn(move || {
+        for batch_index in 0..(num_values / 100) {
+            let values = (0..100).map(|index| AddGaugeValue::new(start_time + (batch_index * 100 + index) as f64, 1.0, Vec::new()));
+            assert_eq!(100, write_engine.gauge("cpu", values).unwrap());
+        }
+    });
+
+    let read_threads = (0..4)
+        .map(|_| {
+            let read_engine = metrics_engine.clone();
+            std::thread::spawn(move || {
+                let mut previous_sum = 0.0;
+                for _ in 0..200 {
+                    let sum = read_engine.sum("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value().unwrap_or(0.0);
+                    assert!(sum >= previous_sum);
+                    previous_sum = sum;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    write_thread.join().unwrap();
+    for read_thread in read_threads {
+        read_thread.join().unwrap();
+    }
+
+    assert_eq!(
+        Some(num_values as f64),
+        metrics_engine.sum("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+}
+
+#[test]
+fn test_metric_templates1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_template(MetricTemplate::new("cpu_*", MetricType::Gauge).with_auto_primary_tag("host"))
+        .with_template(MetricTemplate::new("requests_*", MetricType::Count))
+        .build()
+        .unwrap();
+
+    let values = (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, vec![Tag::from_ref("host", "a")]));
+    assert_eq!(10, metrics_engine.gauge("cpu_usage", values).unwrap());
+    assert_eq!(vec!["cpu_usage".to_owned()], metrics_engine.metric_names());
+    assert_eq!(
+        Some(4.5),
+        metrics_engine.average("cpu_usage", Query::new(TimeRange::new(start_time, end_time))).unwrap().value()
+    );
+
+    // The template must be for the same type of metric
+    let values = (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()));
+    assert!(matches!(metrics_engine.gauge("requests_total", values), Err(MetricsEngineError::MetricNotFound(_))));
+
+    let values = (0..10).map(|index| AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new()));
+    assert!(matches!(metrics_engine.gauge("memory_usage", values), Err(MetricsEngineError::MetricNotFound(_))));
+}
+
+#[test]
+fn test_metric_templates2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_template(MetricTemplate::new("cpu_*", MetricType::Gauge))
+        .with_strict(true)
+        .build()
+        .unwrap();
+
+    let values = (0..10).map(|index| AddGaugeValue::new(1654077600.0 + index as f64, index as f64, Vec::new()));
+    assert!(matches!(metrics_engine.gauge("cpu_usage", values), Err(MetricsEngineError::MetricNotFound(_))));
+    assert!(metrics_engine.metric_names().is_empty());
+}
+
+#[test]
+fn test_unknown_metrics1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_template(MetricTemplate::new("cpu_*", MetricType::Gauge))
+        .with_unknown_metrics(UnknownMetricMode::Fail)
+        .build()
+        .unwrap();
+
+    let values = || (0..10).map(|index| AddGaugeValue::new(1654077600.0 + index as f64, index as f64, Vec::new()));
+    assert!(matches!(metrics_engine.gauge("cpu_usage", values()), Err(MetricsEngineError::MetricNotFound(_))));
+    assert!(metrics_engine.metric_names().is_empty());
+
+    assert_eq!(0, metrics_engine.gauge_with_mode("cpu_usage", values(), UnknownMetricMode::Drop).unwrap());
+    assert_eq!(0, metrics_engine.gauge_with_mode("memory_usage", values(), UnknownMetricMode::Drop).unwrap());
+    assert_eq!(20, metrics_engine.dropped_values());
+    assert!(metrics_engine.metric_names().is_empty());
+
+    assert_eq!(10, metrics_engine.gauge_with_mode("cpu_usage", values(), UnknownMetricMode::AutoCreate).unwrap());
+    assert_eq!(vec!["cpu_usage".to_owned()], metrics_engine.metric_names());
+
+    // Existing metrics are always written to
+    let values = (10..20).map(|index| AddGaugeValue::new(1654077600.0 + index as f64, index as f64, Vec::new()));
+    assert_eq!(10, metrics_engine.gauge_with_mode("cpu_usage", values, UnknownMetricMode::Drop).unwrap());
+}
+
+#[test]
+fn test_unknown_metrics2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_template(MetricTemplate::new("requests_*", MetricType::Count))
+        .with_unknown_metrics(UnknownMetricMode::Drop)
+        .build()
+        .unwrap();
+
+    let values = || (0..10).map(|index| AddCountValue::new(1654077600.0 + index as f64, CountInput(1), Vec::new()));
+    assert_eq!(0, metrics_engine.count("requests_total", values()).unwrap());
+    assert_eq!(10, metrics_engine.dropped_values());
+
+    // Auto-creating still requires a matching template
+    assert!(matches!(
+        metrics_engine.count_with_mode("errors_total", values(), UnknownMetricMode::AutoCreate),
+        Err(MetricsEngineError::MetricNotFound(_))
+    ));
+    assert_eq!(10, metrics_engine.count_with_mode("requests_total", values(), UnknownMetricMode::AutoCreate).unwrap());
+
+    // Existing metrics are always written to
+    let values = (10..20).map(|index| AddCountValue::new(1654077600.0 + index as f64, CountInput(1), Vec::new()));
+    assert_eq!(10, metrics_engine.count("requests_total", values).unwrap());
+    assert_eq!(10, metrics_engine.dropped_values());
+}
+
+#[test]
+fn test_server_time1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+    assert_eq!(1, metrics_engine.gauge("cpu", vec![AddGaugeValue::without_time(1.0, Vec::new())].into_iter()).unwrap());
+
+    // A client with a clock ahead of the server
+    assert_eq!(1, metrics_engine.gauge("cpu", vec![AddGaugeValue::new(now + 60.0, 2.0, Vec::new())].into_iter()).unwrap());
+    assert_eq!(1, metrics_engine.gauge("cpu", vec![AddGaugeValue::without_time(3.0, Vec::new())].into_iter()).unwrap());
+
+    assert_eq!(
+        Some(1.0),
+        metrics_engine.average("cpu", Query::new(TimeRange::new(now - 60.0, now + 30.0))).unwrap().value()
+    );
+    assert!(metrics_engine.max("cpu", Query::new(TimeRange::new(now + 30.0, now + 120.0))).unwrap().value().is_some());
+}
+
+#[test]
+fn test_clock_skew1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_clock_skew_tolerance(ClockSkewTolerance::new(5.0, 5.0))
+        .build()
+        .unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+    assert_eq!(1, metrics_engine.gauge("cpu", vec![AddGaugeValue::new(now - 10.0, 1.0, Vec::new())].into_iter()).unwrap());
+    assert_eq!(0, metrics_engine.clock_skew_adjustments());
+
+    // Slightly in the past
+    assert_eq!(1, metrics_engine.gauge("cpu", vec![AddGaugeValue::new(now - 12.0, 2.0, Vec::new())].into_iter()).unwrap());
+    assert_eq!(1, metrics_engine.clock_skew_adjustments());
+
+    // Slightly in the future
+    assert_eq!(1, metrics_engine.gauge("cpu", vec![AddGaugeValue::new(now + 60.0 + 3.0, 3.0, Vec::new())].into_iter()).unwrap());
+    assert_eq!(1, metrics_engine.clock_skew_adjustments());
+    assert_eq!(1, metrics_engine.gauge("cpu", vec![AddGaugeValue::new(now + 3.0, 4.0, Vec::new())].into_iter()).unwrap());
+    assert_eq!(2, metrics_engine.clock_skew_adjustments());
+
+    assert_eq!(
+        None,
+        metrics_engine.average("cpu", Query::new(TimeRange::new(now - 15.0, now - 11.0))).unwrap().value()
+    );
+    assert!(matches!(
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(now - 20.0, 5.0, Vec::new())].into_iter()),
+        Err(MetricsEngineError::Metric(MetricError::InvalidTimeOrder))
+    ));
+}
+
+#[test]
+fn test_pre_aggregation1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_pre_aggregation(PreAggregationConfig { metrics: vec!["cpu".to_owned()], ..Default::default() })
+        .build()
+        .unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_metric("memory", MetricType::Gauge).unwrap();
+
+    for index in 0..10 {
+        let values = || vec![AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new())].into_iter();
+        assert_eq!(0, metrics_engine.gauge("cpu", values()).unwrap());
+        assert_eq!(1, metrics_engine.gauge("memory", values()).unwrap());
+    }
+
+    assert_eq!(10, metrics_engine.num_buffered_values());
+    assert_eq!(0, metrics_engine.write_sequence("cpu"));
+    assert_eq!(10, metrics_engine.write_sequence("memory"));
+    assert_eq!(None, metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+    assert_eq!(Some(4.5), metrics_engine.average("memory", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+
+    metrics_engine.flush_buffered().unwrap();
+    assert_eq!(0, metrics_engine.num_buffered_values());
+    assert_eq!(1, metrics_engine.write_sequence("cpu"));
+    assert_eq!(Some(4.5), metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+}
+
+#[test]
+fn test_pre_aggregation2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_pre_aggregation(PreAggregationConfig { metrics: vec!["cpu".to_owned(), "memory".to_owned()], ..Default::default() })
+        .build()
+        .unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_metric("memory", MetricType::Gauge).unwrap();
+
+    for index in 0..10 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new())].into_iter()).unwrap();
+        metrics_engine.gauge("memory", vec![AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    // The buffered values of the queried metric are written before a synced read
+    let query = Query::new(TimeRange::new(start_time, end_time)).with_consistency(ReadConsistency::Synced);
+    assert_eq!(Some(4.5), metrics_engine.average("cpu", query).unwrap().value());
+    assert_eq!(10, metrics_engine.num_buffered_values());
+    assert_eq!(None, metrics_engine.average("memory", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+}
+
+#[test]
+fn test_pre_aggregation3() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    {
+        let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+            .with_pre_aggregation(PreAggregationConfig { metrics: vec!["cpu".to_owned()], flush_interval: 100, max_buffered_values: 4 })
+            .build()
+            .unwrap();
+        metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+        // Values within the same datapoint are combined in the buffer, and a full buffer is written by the writer
+        for index in 0..10 {
+            let values = vec![
+                AddGaugeValue::new(start_time + index as f64, 0.0, Vec::new()),
+                AddGaugeValue::new(start_time + index as f64 + 0.1, index as f64, Vec::new())
+            ];
+            metrics_engine.gauge("cpu", values.into_iter()).unwrap();
+        }
+
+        assert_eq!(2, metrics_engine.num_buffered_values());
+        assert_eq!(2, metrics_engine.write_sequence("cpu"));
+        assert_eq!(Some(3.5), metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+
+        // The remaining buffered values are written on shutdown
+        metrics_engine.shutdown().unwrap();
+        assert_eq!(0, metrics_engine.num_buffered_values());
+    }
+
+    let metrics_engine = MetricsEngine::from_existing(temp_metric_data.path()).unwrap();
+    assert_eq!(Some(4.5), metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+}
+
+#[test]
+fn test_slow_query_log1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_slow_query_log(SlowQueryConfig { threshold: Some(0.0), max_entries: 10 })
+        .build()
+        .unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for index in 0..10 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    let mut query = MetricQuery::new(
+        TimeRange::new(start_time, end_time),
+        MetricQueryExpression::Average { metric: "cpu".to_owned(), query: Query::placeholder() }
+    );
+    query.request_id = Some("1337".to_owned());
+    assert_eq!(Some(4.5), metrics_engine.query(query).unwrap().value());
+
+    let slow_queries = metrics_engine.slow_queries(None);
+    assert_eq!(1, slow_queries.len());
+    assert_eq!(Some("1337".to_owned()), slow_queries[0].request_id);
+    assert_eq!(1, slow_queries[0].blocks_scanned);
+    assert_eq!(serde_json::json!(start_time), slow_queries[0].query["time_range"]["start"]);
+    drop(metrics_engine);
+
+    let metrics_engine = MetricsEngine::from_existing(temp_metric_data.path()).unwrap();
+    assert_eq!(1, metrics_engine.slow_queries(None).len());
+}
+
+#[test]
+fn test_flush_metric1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for index in 0..10 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    metrics_engine.flush_metric("cpu").unwrap();
+    assert!(matches!(metrics_engine.flush_metric("memory"), Err(MetricsEngineError::MetricNotFound(_))));
+    drop(metrics_engine);
+
+    let metrics_engine = MetricsEngine::from_existing(temp_metric_data.path()).unwrap();
+    assert_eq!(Some(4.5), metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+}
+
+#[test]
+fn test_compact1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    let mut config = MetricConfig::new(MetricType::Gauge);
+    config.durations[0].max_segments = Some(2);
+    config.durations[0].segment_duration = 10.0 * 600.0;
+    metrics_engine.add_metric_with_config("cpu", MetricType::Gauge, config).unwrap();
+    for index in 0..25 {
+        let time = start_time + index as f64 * 600.0;
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(time, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    // The data is much older than the retention, so only the active segment is kept
+    assert_eq!(1, metrics_engine.compact(Some("cpu")).unwrap());
+    assert_eq!(0, metrics_engine.compact(None).unwrap());
+
+    let time = start_time + 24.0 * 600.0;
+    assert_eq!(Some(24.0), metrics_engine.average("cpu", Query::new(TimeRange::new(time, time + 1.0))).unwrap().value());
+
+    let time = start_time + 10.0 * 600.0;
+    assert_eq!(None, metrics_engine.average("cpu", Query::new(TimeRange::new(time, time + 1.0))).unwrap().value());
+}
+
+#[test]
+fn test_primary_tags_management1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_auto_primary_tag("cpu", "host").unwrap();
+    metrics_engine.add_primary_tag("cpu", PrimaryTag::Named(Tag::from_ref("dc", "north"))).unwrap();
+    metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time, 1.0, vec![Tag::from_ref("host", "a")])].into_iter()).unwrap();
+    metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time, 3.0, vec![Tag::from_ref("host", "b")])].into_iter()).unwrap();
+
+    assert_eq!(vec!["host".to_owned()], metrics_engine.auto_primary_tags("cpu").unwrap());
+    assert_eq!(
+        vec![Tag::from_ref("dc", "north"), Tag::from_ref("host", "a"), Tag::from_ref("host", "b")],
+        metrics_engine.primary_tags("cpu").unwrap()
+    );
+
+    assert!(metrics_engine.remove_auto_primary_tag("cpu", "host").unwrap());
+    assert!(!metrics_engine.remove_auto_primary_tag("cpu", "host").unwrap());
+    assert!(metrics_engine.remove_primary_tag("cpu", &Tag::from_ref("host", "a")).unwrap());
+    assert!(!metrics_engine.remove_primary_tag("cpu", &Tag::from_ref("host", "a")).unwrap());
+    assert_eq!(Some(3.0), metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+    drop(metrics_engine);
+
+    let metrics_engine = MetricsEngine::from_existing(temp_metric_data.path()).unwrap();
+    assert!(metrics_engine.auto_primary_tags("cpu").unwrap().is_empty());
+    assert_eq!(vec![Tag::from_ref("dc", "north"), Tag::from_ref("host", "b")], metrics_engine.primary_tags("cpu").unwrap());
+    assert!(!temp_metric_data.path().join("cpu").join("host:a").exists());
+}
+
+#[test]
+fn test_window_cache1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 1200.0;
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_window_cache(WindowCacheConfig { max_windows: 100 })
+        .build()
+        .unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for index in 0..20 {
+        let tags = vec![Tag::from_ref("host", if index % 2 == 0 { "a" } else { "b" })];
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, index as f64, tags)].into_iter()).unwrap();
+    }
+
+    let query = || {
+        MetricQuery::new(
+            TimeRange::new(start_time, end_time),
+            MetricQueryExpression::Average { metric: "cpu".to_owned(), query: Query::placeholder().with_group_by(GroupKey::from_ref("host")) }
+        )
+    };
+
+    let expected = metrics_engine.query_in_window(query(), Duration::from_secs(600)).unwrap();
+    assert_eq!(1, metrics_engine.num_cached_windows());
+
+    // Only the window of the active block is computed, which is scanned once per group
+    let blocks_scanned_start = crate::metric::blocks_scanned();
+    assert_eq!(expected, metrics_engine.query_in_window(query(), Duration::from_secs(600)).unwrap());
+    assert_eq!(2, crate::metric::blocks_scanned() - blocks_scanned_start);
+
+    assert_eq!(
+        vec![
+            (GroupValue::from_ref("a"), vec![(start_time, Some(4.0)), (start_time + 600.0, Some(14.0))]),
+            (GroupValue::from_ref("b"), vec![(start_time, Some(5.0)), (start_time + 600.0, Some(15.0))])
+        ],
+        {
+            let mut groups = expected.group_time_values().unwrap();
+            groups.sort_by(|x, y| x.0.cmp(&y.0));
+            groups
+        }
+    );
+}
+
+#[test]
+fn test_window_cache2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 1200.0;
+
+    let metrics_engine = MetricsEngineBuilder::new(temp_metric_data.path())
+        .with_window_cache(WindowCacheConfig { max_windows: 100 })
+        .build()
+        .unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_auto_primary_tag("cpu", "host").unwrap();
+    for index in 0..20 {
+        let tags = vec![Tag::from_ref("host", "a")];
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, index as f64, tags)].into_iter()).unwrap();
+    }
+
+    let query = || {
+        MetricQuery::new(
+            TimeRange::new(start_time, end_time),
+            MetricQueryExpression::Sum { metric: "cpu".to_owned(), query: Query::placeholder() }
+        )
+    };
+
+    assert_eq!(
+        vec![(start_time, Some(45.0)), (start_time + 600.0, Some(145.0))],
+        metrics_engine.query_in_window(query(), Duration::from_secs(600)).unwrap().time_values().unwrap()
+    );
+    assert_eq!(1, metrics_engine.num_cached_windows());
+
+    // The new primary tag adds datapoints before the sealed time of the existing primary tags, which are sealed by the next block
+    let tags = vec![Tag::from_ref("host", "b")];
+    metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time, 100.0, tags.clone())].into_iter()).unwrap();
+    metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + 1140.0, 0.0, tags)].into_iter()).unwrap();
+    assert_eq!(
+        vec![(start_time, Some(145.0)), (start_time + 600.0, Some(145.0))],
+        metrics_engine.query_in_window(query(), Duration::from_secs(600)).unwrap().time_values().unwrap()
+    );
+}
+
+#[test]
+fn test_rollups1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    let mut config = MetricConfig::new(MetricType::Gauge);
+    config.rollups.push(RollupConfig::new("cpu_10m", 600.0, RollupOperation::Average));
+    metrics_engine.add_metric_with_config("cpu", MetricType::Gauge, config).unwrap();
+    for index in 0..11 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    // Only the window of the sealed block is written, and only once
+    metrics_engine.scheduled();
+    metrics_engine.scheduled();
+
+    let query = |expression: fn(String, Query) -> MetricQueryExpression| {
+        MetricQuery::new(TimeRange::new(start_time, start_time + 1200.0), expression("cpu_10m".to_owned(), Query::placeholder()))
+    };
+
+    assert_eq!(
+        Some(4.5),
+        metrics_engine.query(query(|metric, query| MetricQueryExpression::Average { metric, query })).unwrap().value()
+    );
+    assert_eq!(
+        Some(4.5),
+        metrics_engine.query(query(|metric, query| MetricQueryExpression::Sum { metric, query })).unwrap().value()
+    );
+
+    let mut config = MetricConfig::new(MetricType::Gauge);
+    config.rollups.push(RollupConfig::new("memory", 600.0, RollupOperation::Max));
+    assert!(matches!(metrics_engine.add_metric_with_config("memory", MetricType::Gauge, config), Err(MetricsEngineError::InvalidInput(_))));
+}
+
+#[test]
+fn test_holt_winters1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 600.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for (index, value) in [1.0, 3.0, 3.0].into_iter().enumerate() {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, value, Vec::new())].into_iter()).unwrap();
+    }
+
+    let smoothing = TransformExpression::HoltWinters {
+        value: Box::new(TransformExpression::InputValue),
+        alpha: 0.5,
+        beta: 0.0,
+        gamma: 0.0,
+        season_length: 0
+    };
+
+    let query = MetricQuery::new(
+        TimeRange::new(start_time, end_time),
+        MetricQueryExpression::Average { metric: "cpu".to_owned(), query: Query::placeholder().with_output_transform(smoothing) }
+    );
+    assert_eq!(
+        Some(vec![(start_time, Some(1.0)), (start_time + 60.0, Some(2.0)), (start_time + 120.0, Some(2.5))]),
+        metrics_engine.query_in_window(query, Duration::from_secs(60)).unwrap().time_values()
+    );
+
+    let smoothing = TransformExpression::HoltWinters {
+        value: Box::new(TransformExpression::InputValue),
+        alpha: 1.5,
+        beta: 0.0,
+        gamma: 0.0,
+        season_length: 0
+    };
+
+    let query = MetricQuery::new(
+        TimeRange::new(start_time, end_time),
+        MetricQueryExpression::Average { metric: "cpu".to_owned(), query: Query::placeholder().with_output_transform(smoothing) }
+    );
+    assert!(matches!(
+        metrics_engine.query_in_window(query, Duration::from_secs(60)),
+        Err(MetricsEngineError::InvalidQuery(QueryError::InvalidSmoothingFactor))
+    ));
+}
+
+#[test]
+fn test_gauge_input_time1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 600.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for index in 0..10 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    let query = Query::new(TimeRange::new(start_time, end_time))
+        .with_input_filter(FilterExpression::Compare {
+            operation: CompareOperation::GreaterThanOrEqual,
+            left: Box::new(FilterExpression::Value(TransformExpression::InputTime)),
+            right: Box::new(FilterExpression::value(start_time + 300.0))
+        });
+    assert_eq!(Some(7.0), metrics_engine.average("cpu", query).unwrap().value());
+
+    // The time within each window
+    let query = Query::new(TimeRange::new(start_time, end_time))
+        .with_input_transform(TransformExpression::Arithmetic {
+            operation: ArithmeticOperation::Subtract,
+            left: Box::new(TransformExpression::InputTime),
+            right: Box::new(TransformExpression::InputWindowStart)
+        });
+    assert_eq!(
+        Some(vec![(start_time, Some(120.0)), (start_time + 300.0, Some(120.0))]),
+        metrics_engine.average_in_window("cpu", query, Duration::from_secs(300)).unwrap().time_values()
+    );
+}
+
+#[test]
+fn test_query_delta1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for index in 0..20 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    let time_range = TimeRange::new(start_time + 600.0, start_time + 1190.0);
+    let expression = MetricQueryExpression::delta(
+        MetricQueryExpression::Average { metric: "cpu".to_owned(), query: Query::placeholder() },
+        600.0
+    );
+
+    assert_eq!(
+        Some(10.0),
+        metrics_engine.query(MetricQuery::new(time_range, expression.clone())).unwrap().value()
+    );
+
+    assert_eq!(
+        Some(vec![(start_time + 600.0, Some(10.0)), (start_time + 900.0, Some(10.0))]),
+        metrics_engine.query_in_window(
+            MetricQuery::new(TimeRange::new(start_time + 600.0, start_time + 1200.0), expression),
+            Duration::from_secs(300)
+        ).unwrap().time_values()
+    );
+}
+
+#[test]
+fn test_sealed_consistency1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 900.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for index in 0..15 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    let query = || Query::new(TimeRange::new(start_time, end_time));
+    assert_eq!(Some(7.0), metrics_engine.average("cpu", query()).unwrap().value());
+
+    // Only the first block is sealed
+    assert_eq!(Some(4.5), metrics_engine.average("cpu", query().with_consistency(ReadConsistency::Sealed)).unwrap().value());
+    assert_eq!(
+        Some(vec![(start_time, Some(2.0)), (start_time + 300.0, Some(7.0))]),
+        metrics_engine.average_in_window("cpu", query().with_consistency(ReadConsistency::Sealed), Duration::from_secs(300)).unwrap().time_values()
+    );
+
+    let query = Query::new(TimeRange::new(start_time + 600.0, end_time)).with_consistency(ReadConsistency::Sealed);
+    assert_eq!(None, metrics_engine.average("cpu", query).unwrap().value());
+}
+
+#[test]
+fn test_sealed_consistency2() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 900.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_primary_tag("cpu", PrimaryTag::Named(Tag::from_ref("host", "a"))).unwrap();
+    for index in 0..15 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64 * 60.0, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    // The primary tag without datapoints can still receive datapoints within the first block
+    let query = || Query::new(TimeRange::new(start_time, end_time)).with_consistency(ReadConsistency::Sealed);
+    assert_eq!(None, metrics_engine.average("cpu", query()).unwrap().value());
+
+    let values = vec![AddGaugeValue::new(start_time + 840.0, 14.0, vec![Tag::from_ref("host", "a")])];
+    metrics_engine.gauge("cpu", values.into_iter()).unwrap();
+    assert_eq!(Some(4.5), metrics_engine.average("cpu", query()).unwrap().value());
+}
+
+#[test]
+fn test_group_limit1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for (index, host) in ["a", "b", "c"].iter().enumerate() {
+        let values = vec![AddGaugeValue::new(start_time, (index + 1) as f64, vec![Tag::from_ref("host", host)])];
+        metrics_engine.gauge("cpu", values.into_iter()).unwrap();
+    }
+
+    let query = || {
+        Query::new(TimeRange::new(start_time, end_time))
+            .with_group_by(GroupKey::from_ref("host"))
+            .with_group_limit(GroupLimit::new(1, true))
+    };
+
+    assert_eq!(
+        Some(vec![(GroupValue::from_ref("c"), Some(3.0)), (GroupValue::from_ref(OTHER_GROUP), Some(3.0))]),
+        metrics_engine.sum("cpu", query()).unwrap().group_values()
+    );
+
+    assert_eq!(
+        Some(vec![(GroupValue::from_ref("c"), Some(3.0)), (GroupValue::from_ref(OTHER_GROUP), Some(2.0))]),
+        metrics_engine.max("cpu", query()).unwrap().group_values()
+    );
+
+    // Averages of different groups cannot be combined
+    assert_eq!(
+        Some(vec![(GroupValue::from_ref("c"), Some(3.0))]),
+        metrics_engine.average("cpu", query()).unwrap().group_values()
+    );
+}
+
+#[test]
+fn test_delete_metric1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let start_time = 1654077600.0;
+    let end_time = start_time + 20.0;
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    for index in 0..10 {
+        metrics_engine.gauge("cpu", vec![AddGaugeValue::new(start_time + index as f64, index as f64, Vec::new())].into_iter()).unwrap();
+    }
+
+    metrics_engine.delete_metric("cpu").unwrap();
+    assert!(matches!(metrics_engine.delete_metric("cpu"), Err(MetricsEngineError::MetricNotFound(_))));
+    assert!(matches!(metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))), Err(MetricsEngineError::MetricNotFound(_))));
+    assert_eq!(vec!["cpu".to_owned()], metrics_engine.deleted_metrics().into_iter().map(|deleted_metric| deleted_metric.name).collect::<Vec<_>>());
+    drop(metrics_engine);
+
+    let metrics_engine = MetricsEngine::from_existing(temp_metric_data.path()).unwrap();
+    assert!(metrics_engine.metric_names().is_empty());
+
+    metrics_engine.undelete_metric("cpu").unwrap();
+    assert!(matches!(metrics_engine.undelete_metric("cpu"), Err(MetricsEngineError::MetricAlreadyExists(_))));
+    assert!(matches!(metrics_engine.undelete_metric("memory"), Err(MetricsEngineError::MetricNotFound(_))));
+    assert!(metrics_engine.deleted_metrics().is_empty());
+    assert_eq!(Some(4.5), metrics_engine.average("cpu", Query::new(TimeRange::new(start_time, end_time))).unwrap().value());
+    drop(metrics_engine);
+
+    let metrics_engine = MetricsEngine::from_existing(temp_metric_data.path()).unwrap();
+    assert_eq!(vec!["cpu".to_owned()], metrics_engine.metric_names());
+}
+
+#[test]
+fn test_schema1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_primary_tag("cpu", PrimaryTag::Named(Tag::from_ref("host", "a"))).unwrap();
+    metrics_engine.add_auto_primary_tag("cpu", "region").unwrap();
+    metrics_engine.add_metric("requests", MetricType::Count).unwrap();
+
+    let schema = metrics_engine.export_schema().unwrap();
+    assert_eq!(vec!["cpu", "requests"], schema.metrics.keys().collect::<Vec<_>>());
+    assert_eq!(vec![Tag::from_ref("host", "a")], schema.metrics["cpu"].primary_tags);
+    assert_eq!(vec!["region".to_owned()], schema.metrics["cpu"].auto_primary_tags);
+
+    let temp_other_metric_data = tempdir().unwrap();
+    let other_metrics_engine = MetricsEngine::new(temp_other_metric_data.path()).unwrap();
+    other_metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let changes = other_metrics_engine.apply_schema(&schema, false).unwrap();
+    assert_eq!(vec!["requests".to_owned()], changes.created_metrics);
+    assert_eq!(Some(&vec![Tag::from_ref("host", "a")]), changes.added_primary_tags.get("cpu"));
+    assert_eq!(Some(&vec!["region".to_owned()]), changes.added_auto_primary_tags.get("cpu"));
+
+    // Applying the same schema again does not change anything
+    assert_eq!(SchemaChanges::default(), other_metrics_engine.apply_schema(&schema, false).unwrap());
+    assert_eq!(vec![Tag::from_ref("host", "a")], other_metrics_engine.primary_tags("cpu").unwrap());
+
+    let mut schema = schema;
+    schema.metrics.get_mut("requests").unwrap().metric_type = MetricType::Gauge;
+    assert!(matches!(other_metrics_engine.apply_schema(&schema, false), Err(MetricsEngineError::InvalidInput(_))));
+}
+
+#[test]
+fn test_schema_diff1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let metrics_engine = MetricsEngine::new(temp_metric_data.path()).unwrap();
+    metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    metrics_engine.add_primary_tag("cpu", PrimaryTag::Named(Tag::from_ref("host", "a"))).unwrap();
+    metrics_engine.add_metric("requests", MetricType::Count).unwrap();
+    let mut schema = metrics_engine.export_schema().unwrap();
+
+    schema.metrics.remove("requests");
+    let cpu_schema = schema.metrics.get_mut("cpu").unwrap();
+    cpu_schema.primary_tags = vec![Tag::from_ref("host", "b")];
+    cpu_schema.config.as_mut().unwrap().staleness += 1.0;
+    schema.metrics.insert(
+        "memory".to_owned(),
+        MetricSchema { metric_type: MetricType::Gauge, config: None, primary_tags: Vec::new(), auto_primary_tags: vec!["host".to_owned()] }
+    );
+
+    let changes = metrics_engine.diff_schema(&schema, false).unwrap();
+    assert_eq!(vec!["memory".to_owned()], changes.created_metrics);
+    assert!(changes.removed_metrics.is_empty());
+    assert_eq!(vec!["cpu".to_owned()], changes.changed_configs);
+    assert_eq!(Some(&vec![Tag::from_ref("host", "b")]), changes.added_primary_tags.get("cpu"));
+    assert!(changes.removed_primary_tags.is_empty());
+    assert_eq!(Some(&vec!["host".to_owned()]), changes.added_auto_primary_tags.get("memory"));
+
+    // Nothing is applied by the diff
+    let mut metric_names = metrics_engine.metric_names();
+    metric_names.sort();
+    assert_eq!(vec!["cpu".to_owned(), "requests".to_owned()], metric_names);
+
+    let changes = metrics_engine.diff_schema(&schema, true).unwrap();
+    assert_eq!(vec!["requests".to_owned()], changes.removed_metrics);
+    assert_eq!(Some(&vec![Tag::from_ref("host", "a")]), changes.removed_primary_tags.get("cpu"));
+
+    assert_eq!(changes, metrics_engine.apply_schema(&schema, true).unwrap());
+    let mut metric_names = metrics_engine.metric_names();
+    metric_names.sort();
+    assert_eq!(vec!["cpu".to_owned(), "memory".to_owned()], metric_names);
+    assert_eq!(vec![Tag::from_ref("host", "b")], metrics_engine.primary_tags("cpu").unwrap());
+    assert_eq!(vec!["requests".to_owned()], metrics_engine.deleted_metrics().into_iter().map(|deleted_metric| deleted_metric.name).collect::<Vec<_>>());
+
+    let changes = metrics_engine.diff_schema(&schema, true).unwrap();
+    assert_eq!(vec!["cpu".to_owned()], changes.changed_configs);
+    assert_eq!(SchemaChanges { changed_configs: vec!["cpu".to_owned()], ..Default::default() }, changes);
+}
+
+#[test]
+fn test_lock_watchdog1() {
+    let temp_metric_data = tempdir().unwrap();
+
+    let mut metric = DefaultGaugeMetric::new(temp_metric_data.path()).unwrap();
+    let lock_watchdog = Arc::new(LockWatchdog::new(LockWatchdogConfig { threshold: Some(3600.0) }));
+    metric.set_lock_watchdog("cpu", lock_watchdog.clone());
+
+    metric.add(1654077600.0, 1.0, vec![Tag::from_ref("host", "a")]).unwrap();
+    assert_eq!(1, lock_watchdog.stats().num_acquired);
+
+    assert_eq!(Some(1.0), metric.average(Query::new(TimeRange::new(1654077600.0, 1654077600.0 + 60.0))).value());
+    assert_eq!(2, lock_watchdog.stats().num_acquired);
+    assert_eq!(0, lock_watchdog.stats().num_blocked);
+}
+
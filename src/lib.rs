@@ -1,7 +1,46 @@
+#![recursion_limit = "256"]
+
 pub mod helpers;
-pub mod traits;
+pub(crate) mod traits;
 pub mod storage;
 pub mod model;
 pub mod metric;
 pub mod engine;
-pub mod server;
\ No newline at end of file
+pub mod generate;
+
+#[cfg(feature = "server")]
+pub mod server;
+
+#[cfg(feature = "server")]
+pub(crate) mod protobuf;
+
+#[cfg(feature = "client")]
+pub mod client;
+
+#[cfg(feature = "agent")]
+pub mod agent;
+
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
+
+#[cfg(feature = "python")]
+mod python;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(test)]
+mod integration_tests;
+
+pub use crate::engine::{MetricsEngine, MetricsEngineBuilder};
+pub use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue, LoadingStatus, MetricsEngineError, MetricsEngineResult};
+pub use crate::engine::annotations::Annotation;
+pub use crate::engine::querying::{MetricQuery, MetricQueryExpression};
+pub use crate::metric::{JsonOptions, OperationResult};
+pub use crate::metric::common::{CountInput, GenericMetric, MetricConfig, MetricStorageDurationConfig, MetricType};
+pub use crate::metric::count::DefaultCountMetric;
+pub use crate::metric::expression::{ArithmeticOperation, CompareOperation, FilterExpression, Function, TransformExpression};
+pub use crate::metric::gauge::DefaultGaugeMetric;
+pub use crate::metric::ratio::{DefaultRatioMetric, RatioInput};
+pub use crate::metric::tags::{PrimaryTag, Tag, TagsFilter};
+pub use crate::model::{GroupKey, GroupLimit, GroupValue, MetricError, MetricResult, Query, QueryError, TimeRange};
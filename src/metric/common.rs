@@ -1,13 +1,22 @@
 use std::collections::HashSet;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::Duration;
 
 use fnv::{FnvHashMap, FnvHashSet};
 
-use serde::{Serialize, Deserialize};
+use tdigest::TDigest;
 
-use crate::metric::OperationResult;
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+use crate::engine::lock_watchdog::{LockWatchdog, LockWatchdogConfig, WatchedGuard};
+use crate::metric::{helpers, OperationResult};
+use crate::metric::digests::BlockDigests;
+use crate::metric::expression::ExpressionValue;
+use crate::metric::operations::{DigestConfig, PercentileAlgorithm};
 use crate::metric::tags::{PrimaryTag, SecondaryTagsFilter, SecondaryTagsIndex, Tag, TagsFilter};
 use crate::model::{Datapoint, GroupKey, GroupValue, MetricError, MetricResult, Query, Tags, Time, TIME_SCALE};
 use crate::storage::{MetricStorage, MetricStorageConfig};
@@ -20,7 +29,10 @@ pub const DEFAULT_GAUGE_DATAPOINT_DURATION: f64 = 0.2;
 pub const DEFAULT_COUNT_DATAPOINT_DURATION: f64 = 1.0;
 pub const DEFAULT_RATIO_DATAPOINT_DURATION: f64 = 1.0;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+pub const DEFAULT_STALENESS: f64 = 5.0 * 60.0;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum MetricType {
     Gauge,
     Count,
@@ -28,6 +40,7 @@ pub enum MetricType {
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct CountInput(pub u32);
 
 impl CountInput {
@@ -46,14 +59,34 @@ pub trait GenericMetric {
     fn add_primary_tag(&mut self, tag: PrimaryTag) -> MetricResult<()>;
     fn add_auto_primary_tag(&mut self, key: &str) -> MetricResult<()>;
 
+    /// Removes the primary tag including its data, returns false if it does not exist.
+    fn remove_primary_tag(&mut self, tag: &Tag) -> MetricResult<bool>;
+    /// Stops creating primary tags for the key, the already created primary tags are kept.
+    fn remove_auto_primary_tag(&mut self, key: &str) -> MetricResult<bool>;
+    fn auto_primary_tags(&self) -> Vec<String>;
+
+    fn requires_new_primary_tags(&self, tags: &[Tag]) -> bool;
+    fn create_auto_primary_tags(&mut self, tags: &[Tag]) -> MetricResult<()>;
+
+    fn num_groups(&self, query: &Query, key: &GroupKey) -> usize;
+    /// The tags of each group that the query would return.
+    fn group_values(&self, query: &Query, key: &GroupKey) -> Vec<Vec<Tag>>;
+
     type Input;
-    fn add(&mut self, time: f64, value: Self::Input, tags: Vec<Tag>) -> MetricResult<()>;
+    fn add(&mut self, time: f64, value: Self::Input, tags: Vec<Tag>) -> MetricResult<()> {
+        self.create_auto_primary_tags(&tags)?;
+        self.add_concurrent(time, value, tags)
+    }
+
+    fn add_concurrent(&self, time: f64, value: Self::Input, tags: Vec<Tag>) -> MetricResult<()>;
+    fn add_batch(&self, values: &[(f64, Self::Input, Vec<Tag>)]) -> MetricResult<usize>;
 
     fn average(&self, query: Query) -> OperationResult;
     fn sum(&self, query: Query) -> OperationResult;
     fn max(&self, query: Query) -> OperationResult;
     fn min(&self, query: Query) -> OperationResult;
     fn percentile(&self, query: Query, percentile: i32) -> OperationResult;
+    fn last(&self, query: Query) -> OperationResult;
 
     fn average_in_window(&self, query: Query, duration: Duration) -> OperationResult;
     fn sum_in_window(&self, query: Query, duration: Duration) -> OperationResult;
@@ -61,15 +94,51 @@ pub trait GenericMetric {
     fn min_in_window(&self, query: Query, duration: Duration) -> OperationResult;
     fn percentile_in_window(&self, query: Query, duration: Duration, percentile: i32) -> OperationResult;
 
-    fn scheduled(&mut self);
+    fn scheduled(&self);
+
+    fn config(&self) -> &MetricConfig;
+
+    /// The start time of the earliest block over all primary tags.
+    fn start_time(&self) -> Option<Time>;
+
+    /// The time (in seconds) of the latest datapoint over all primary tags.
+    fn latest_time(&self) -> Option<f64>;
+
+    /// The time before which all blocks of all storages are sealed, such that no datapoints can be added before it
+    /// by the primary tags with datapoints.
+    fn sealed_time(&self) -> Option<Time>;
+
+    /// The number of primary tags with datapoints. A primary tag without datapoints can receive datapoints before the sealed time.
+    fn num_primary_tags_with_data(&self) -> usize;
+
+    /// Synchronously writes all changes of all storages to disk.
+    fn flush(&self) -> MetricResult<()>;
+
+    /// Moves the sealed segments that end before the given time from `hot_root` to `cold_root`.
+    fn move_to_cold_tier(&self, hot_root: &Path, cold_root: &Path, older_than: Time) -> MetricResult<usize>;
+
+    /// Removes the sealed segments of all storages that are older than their retention.
+    fn remove_expired_segments(&self, now: Time) -> MetricResult<usize>;
+
+    /// Checks the invariants of the sealed blocks of all storages.
+    fn verify(&self) -> Vec<String>;
+
+    /// Reports the locks of the primary tags to the given watchdog, under the given metric name.
+    fn set_lock_watchdog(&mut self, metric: &str, lock_watchdog: Arc<LockWatchdog>);
 }
 
-pub type PrimaryTags<TStorage, E> = FnvHashMap<PrimaryTag, PrimaryTagMetric<TStorage, E>>;
+pub type PrimaryTags<TStorage, E> = FnvHashMap<PrimaryTag, RwLock<PrimaryTagMetric<TStorage, E>>>;
+
+pub type PrimaryTagReadGuard<'a, TStorage, E> = WatchedGuard<'a, RwLockReadGuard<'a, PrimaryTagMetric<TStorage, E>>>;
+pub type PrimaryTagWriteGuard<'a, TStorage, E> = WatchedGuard<'a, RwLockWriteGuard<'a, PrimaryTagMetric<TStorage, E>>>;
 
 pub struct PrimaryTagsStorage<TStorage: MetricStorage<E>, E: Copy> {
     base_path: PathBuf,
     tags: PrimaryTags<TStorage, E>,
-    config: MetricConfig
+    config: MetricConfig,
+    read_only: bool,
+    metric_name: String,
+    lock_watchdog: Arc<LockWatchdog>
 }
 
 impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
@@ -79,16 +148,19 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
 
     pub fn with_config(base_path: &Path, config: MetricConfig) -> MetricResult<PrimaryTagsStorage<TStorage, E>> {
         if !base_path.exists() {
-            std::fs::create_dir_all(base_path).map_err(|err| MetricError::FailedToCreateBaseDir(err))?;
+            std::fs::create_dir_all(base_path).map_err(|err| MetricError::FailedToCreateBaseDir(base_path.to_owned(), err))?;
         }
-        clean_dir(base_path).map_err(|err| MetricError::FailedToCreateMetric(err))?;
+        clean_dir(base_path).map_err(|err| MetricError::FailedToCreateMetric(base_path.to_owned(), err))?;
 
         config.save(&base_path.join("config.json"))?;
 
         let mut primary_tags_storage = PrimaryTagsStorage {
             base_path: base_path.to_owned(),
             tags: FnvHashMap::default(),
-            config
+            config,
+            read_only: false,
+            metric_name: default_metric_name(base_path),
+            lock_watchdog: Arc::new(LockWatchdog::new(LockWatchdogConfig::default()))
         };
         primary_tags_storage.add_primary_tag(PrimaryTag::Default)?;
 
@@ -96,17 +168,45 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
     }
 
     pub fn from_existing(base_path: &Path) -> MetricResult<PrimaryTagsStorage<TStorage, E>> {
+        PrimaryTagsStorage::load(base_path, false)
+    }
+
+    pub fn from_existing_read_only(base_path: &Path) -> MetricResult<PrimaryTagsStorage<TStorage, E>> {
+        PrimaryTagsStorage::load(base_path, true)
+    }
+
+    fn load(base_path: &Path, read_only: bool) -> MetricResult<PrimaryTagsStorage<TStorage, E>> {
         Ok(
             PrimaryTagsStorage {
                 base_path: base_path.to_owned(),
-                tags: PrimaryTagsSerialization::new(base_path).load()?,
-                config: MetricConfig::load(&base_path.join("config.json"))?
+                tags: PrimaryTagsSerialization::new(base_path).load(read_only)?,
+                config: MetricConfig::load(&base_path.join("config.json"))?,
+                read_only,
+                metric_name: default_metric_name(base_path),
+                lock_watchdog: Arc::new(LockWatchdog::new(LockWatchdogConfig::default()))
             }
         )
     }
 
+    pub fn set_lock_watchdog(&mut self, metric: &str, lock_watchdog: Arc<LockWatchdog>) {
+        self.metric_name = metric.to_owned();
+        self.lock_watchdog = lock_watchdog;
+    }
+
+    fn read_primary_tag<'a>(&'a self,
+                            primary_tag_key: &PrimaryTag,
+                            primary_tag: &'a RwLock<PrimaryTagMetric<TStorage, E>>) -> PrimaryTagReadGuard<'a, TStorage, E> {
+        self.lock_watchdog.read_primary_tag(&self.metric_name, primary_tag_key, primary_tag)
+    }
+
+    fn write_primary_tag<'a>(&'a self,
+                             primary_tag_key: &PrimaryTag,
+                             primary_tag: &'a RwLock<PrimaryTagMetric<TStorage, E>>) -> PrimaryTagWriteGuard<'a, TStorage, E> {
+        self.lock_watchdog.write_primary_tag(&self.metric_name, primary_tag_key, primary_tag)
+    }
+
     pub fn stats(&self) {
-        for (tag, primary_tag) in self.tags.iter() {
+        for (tag, primary_tag) in self.iter() {
             let storage = primary_tag.storage(None);
             println!("Tag: {:?}", tag);
             println!("Num blocks: {}", storage.len());
@@ -125,17 +225,35 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
         }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item=(&PrimaryTag, &PrimaryTagMetric<TStorage, E>)> {
-        self.tags.iter()
+    pub fn iter(&self) -> impl Iterator<Item=(&PrimaryTag, PrimaryTagReadGuard<'_, TStorage, E>)> {
+        self.tags.iter().map(|(primary_tag_key, primary_tag)| (primary_tag_key, self.read_primary_tag(primary_tag_key, primary_tag)))
     }
 
-    pub fn iter_for_query<'a>(&'a self, tags_filter: &'a TagsFilter) -> impl Iterator<Item=(&PrimaryTagMetric<TStorage, E>, SecondaryTagsFilter)> + '_ {
+    /// The primary tags matching the filter. Each primary tag is only locked while taking a snapshot of it, so the query
+    /// itself never blocks writes.
+    pub fn iter_for_query<'a>(&'a self, tags_filter: &'a TagsFilter) -> impl Iterator<Item=(PrimaryTagSnapshot<TStorage, E>, SecondaryTagsFilter)> + 'a {
         let named_primary_tags = HashSet::from_iter(self.named_primary_tags());
-        self.tags
-            .iter()
-            .map(move |(primary_tag_key, primary_tag)| (primary_tag, tags_filter.apply(&named_primary_tags, primary_tag_key, &primary_tag.tags_index)))
-            .filter(|(_, tags_filter)| tags_filter.is_some())
-            .map(|(primary_tag, tags_filter)| (primary_tag, tags_filter.unwrap()))
+        self.tags.iter()
+            .filter(move |(primary_tag_key, _)| {
+                match tags_filter.primary_tag() {
+                    Some(tag) => primary_tag_key.named() == Some(tag),
+                    None => true
+                }
+            })
+            .map(|(primary_tag_key, primary_tag)| (primary_tag_key, self.read_primary_tag(primary_tag_key, primary_tag)))
+            .map(move |(primary_tag_key, primary_tag)| {
+                let tags_filter = tags_filter.apply(&named_primary_tags, primary_tag_key, &primary_tag.tags_index);
+                (primary_tag_key, primary_tag, tags_filter)
+            })
+            .filter(|(_, _, tags_filter)| tags_filter.is_some())
+            .map(|(primary_tag_key, primary_tag, tags_filter)| {
+                tracing::trace!(primary_tag = ?primary_tag_key, "querying primary tag");
+                (primary_tag.snapshot(), tags_filter.unwrap())
+            })
+    }
+
+    pub fn config(&self) -> &MetricConfig {
+        &self.config
     }
 
     pub fn primary_tags(&self) -> impl Iterator<Item=&PrimaryTag> {
@@ -147,10 +265,11 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
     }
 
     pub fn add_primary_tag(&mut self, tag: PrimaryTag) -> MetricResult<()> {
+        self.check_writable()?;
         if !self.tags.contains_key(&tag) {
             let primary_tag = PrimaryTagMetric::new(&tag.path(&self.base_path), &self.config)?;
             primary_tag.tags_index.save()?;
-            self.tags.insert(tag, primary_tag);
+            self.tags.insert(tag, RwLock::new(primary_tag));
             PrimaryTagsSerialization::new(&self.base_path).save(&self.tags)?;
         }
 
@@ -158,27 +277,61 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
     }
 
     pub fn add_auto_primary_tag(&mut self, key: &str) -> MetricResult<()> {
+        self.check_writable()?;
         self.config.auto_primary_tags.insert(key.to_owned());
         self.config.save(&self.base_path.join("config.json"))?;
         Ok(())
     }
 
-    pub fn insert_tags(&mut self, tags: &mut Vec<Tag>) -> MetricResult<(PrimaryTag, PrimaryTagMetric<TStorage, E>, Tags)> {
-        self.try_create_primary_tag(tags)?;
-
-        let (primary_tag_key, mut primary_tag) = self.extract_primary_tag(tags);
-        let secondary_tags = match primary_tag.tags_index.try_add_tags(&tags) {
-            Ok(secondary_tags) => secondary_tags,
-            Err(err) => {
-                self.tags.insert(primary_tag_key, primary_tag);
-                return Err(err);
-            }
+    pub fn remove_primary_tag(&mut self, tag: &Tag) -> MetricResult<bool> {
+        self.check_writable()?;
+        let primary_tag = PrimaryTag::Named(tag.to_owned());
+        let storage = match self.tags.remove(&primary_tag) {
+            Some(storage) => storage,
+            None => { return Ok(false); }
         };
 
-        Ok((primary_tag_key, primary_tag, secondary_tags))
+        PrimaryTagsSerialization::new(&self.base_path).save(&self.tags)?;
+
+        // Releases the lock of the storage before its files are removed
+        drop(storage);
+        let path = primary_tag.path(&self.base_path);
+        std::fs::remove_dir_all(&path).map_err(|err| MetricError::FailedToRemoveMetric(path, err))?;
+        Ok(true)
+    }
+
+    pub fn remove_auto_primary_tag(&mut self, key: &str) -> MetricResult<bool> {
+        self.check_writable()?;
+        if !self.config.auto_primary_tags.remove(key) {
+            return Ok(false);
+        }
+
+        self.config.save(&self.base_path.join("config.json"))?;
+        Ok(true)
     }
 
-    fn try_create_primary_tag(&mut self, tags: &Vec<Tag>) -> MetricResult<()> {
+    pub fn auto_primary_tags(&self) -> Vec<String> {
+        let mut keys = self.config.auto_primary_tags.iter().cloned().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    pub fn insert_tags(&self, tags: &mut Vec<Tag>) -> MetricResult<(PrimaryTagWriteGuard<'_, TStorage, E>, Tags)> {
+        self.check_writable()?;
+        let (primary_tag_key, primary_tag) = self.extract_primary_tag(tags);
+        let mut primary_tag = self.write_primary_tag(primary_tag_key, primary_tag);
+        let secondary_tags = primary_tag.tags_index.try_add_tags(tags)?;
+        Ok((primary_tag, secondary_tags))
+    }
+
+    pub fn requires_new_primary_tags(&self, tags: &[Tag]) -> bool {
+        tags.iter().any(|tag| {
+            self.config.auto_primary_tags.contains(&tag.0) && !self.tags.contains_key(&PrimaryTag::Named(tag.to_owned()))
+        })
+    }
+
+    pub fn create_auto_primary_tags(&mut self, tags: &[Tag]) -> MetricResult<()> {
+        self.check_writable()?;
         for tag in tags.iter() {
             let new_primary_tag = PrimaryTag::Named(tag.to_owned());
             if self.config.auto_primary_tags.contains(&tag.0) && !self.tags.contains_key(&new_primary_tag) {
@@ -189,28 +342,92 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
         Ok(())
     }
 
-    fn extract_primary_tag(&mut self, tags: &mut Vec<Tag>) -> (PrimaryTag, PrimaryTagMetric<TStorage, E>) {
+    fn check_writable(&self) -> MetricResult<()> {
+        if self.read_only {
+            return Err(MetricError::ReadOnly);
+        }
+
+        Ok(())
+    }
+
+    fn extract_primary_tag(&self, tags: &mut Vec<Tag>) -> (&PrimaryTag, &RwLock<PrimaryTagMetric<TStorage, E>>) {
         for (index, tag) in tags.iter().enumerate() {
             let tag = PrimaryTag::Named((*tag).to_owned());
-            if let Some(primary_tag) = self.tags.remove(&tag) {
+            if let Some(primary_tag) = self.tags.get_key_value(&tag) {
                 tags.remove(index);
-                return (tag, primary_tag);
+                return primary_tag;
             }
         }
 
-        (PrimaryTag::Default, self.tags.remove(&PrimaryTag::Default).unwrap())
+        self.tags.get_key_value(&PrimaryTag::Default).unwrap()
     }
 
-    pub fn return_tags(&mut self, primary_tag_key: PrimaryTag, primary_tag: PrimaryTagMetric<TStorage, E>) {
-        self.tags.insert(primary_tag_key, primary_tag);
+    pub fn add_batch<T: Copy>(&self,
+                              values: &[(f64, T, Vec<Tag>)],
+                              to_value: impl Fn(T) -> MetricResult<E>,
+                              handle_same_datapoint: impl Fn(&mut Datapoint<E>, E, u32)) -> MetricResult<usize> {
+        self.check_writable()?;
+
+        let mut values_by_tags: FnvHashMap<&Vec<Tag>, Vec<(f64, T)>> = FnvHashMap::default();
+        for (time, value, tags) in values {
+            values_by_tags.entry(tags).or_default().push((*time, *value));
+        }
+
+        let mut values_by_primary_tag: FnvHashMap<&PrimaryTag, Vec<_>> = FnvHashMap::default();
+        for (tags, tag_values) in values_by_tags {
+            let mut secondary_tags = tags.clone();
+            let (primary_tag_key, _) = self.extract_primary_tag(&mut secondary_tags);
+            values_by_primary_tag.entry(primary_tag_key).or_default().push((secondary_tags, tag_values));
+        }
+
+        let mut num_added = 0;
+        let mut error = None;
+        for (primary_tag_key, primary_tag_values) in values_by_primary_tag {
+            let mut primary_tag = self.write_primary_tag(primary_tag_key, &self.tags[primary_tag_key]);
+
+            let mut datapoints = Vec::new();
+            for (secondary_tags, tag_values) in primary_tag_values {
+                let secondary_tags = match primary_tag.tags_index.try_add_tags(&secondary_tags) {
+                    Ok(secondary_tags) => secondary_tags,
+                    Err(err) => {
+                        error = Some(err);
+                        continue;
+                    }
+                };
+
+                for (time, value) in tag_values {
+                    match to_value(value) {
+                        Ok(value) => { datapoints.push(((time * TIME_SCALE as f64).round() as Time, value, secondary_tags)); }
+                        Err(err) => { error = Some(err); }
+                    }
+                }
+            }
+
+            datapoints.sort_by_key(|(time, _, _)| *time);
+            match primary_tag.add_batch(&datapoints, &handle_same_datapoint) {
+                Ok(num_datapoints_added) => { num_added += num_datapoints_added; }
+                Err(err) => { error = Some(err); }
+            }
+        }
+
+        if num_added == 0 && !values.is_empty() {
+            return Err(error.unwrap_or(MetricError::InvalidTimeOrder));
+        }
+
+        Ok(num_added)
     }
 
     pub fn apply_group_by<F: Fn(&TagsFilter) -> T, T>(&self, query: &Query, key: &GroupKey, apply: F) -> Vec<(GroupValue, T)> {
+        let primary_tag_group_by = self.is_primary_tag_group_by(query, key);
         let mut groups = self.gather_group_values(&query, key)
             .into_iter()
-            .map(|group_key_value| {
+            .map(|mut group_key_value| {
                 let group_value = GroupValue::from_tags(&group_key_value);
-                let tags_filter = query.tags_filter.clone().add_and_clause(group_key_value);
+                let tags_filter = if primary_tag_group_by {
+                    TagsFilter::Primary(group_key_value.remove(0), Box::new(query.tags_filter.clone()))
+                } else {
+                    query.tags_filter.clone().add_and_clause(group_key_value)
+                };
                 (group_value, apply(&tags_filter))
             })
             .collect::<Vec<_>>();
@@ -219,8 +436,43 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
         groups
     }
 
-    fn gather_group_values(&self, query: &Query, key: &GroupKey) -> Vec<Vec<Tag>> {
+    pub fn num_groups(&self, query: &Query, key: &GroupKey) -> usize {
+        self.gather_group_dimensions(query, key)
+            .iter()
+            .fold(1usize, |num_groups, dimension| num_groups.saturating_mul(dimension.len()))
+    }
+
+    pub fn gather_group_values(&self, query: &Query, key: &GroupKey) -> Vec<Vec<Tag>> {
+        cartesian_product_groups(&key, self.gather_group_dimensions(query, key))
+    }
+
+    /// A group by on a primary tag can be evaluated directly on each primary tag storage, as long as no values for the key are stored as secondary tags.
+    fn is_primary_tag_group_by(&self, query: &Query, key: &GroupKey) -> bool {
+        if key.0.len() != 1 {
+            return false;
+        }
+
+        if query.primary_tag_group_by {
+            return true;
+        }
+
+        let key = &key.0[0];
+        self.named_primary_tags().any(|tag| &tag.0 == key)
+            && self.iter().all(|(_, primary_tag)| !primary_tag.tags_index.contains_key(key))
+    }
+
+    fn gather_group_dimensions(&self, query: &Query, key: &GroupKey) -> Vec<Vec<String>> {
         let named_primary_tags = HashSet::from_iter(self.named_primary_tags());
+        if self.is_primary_tag_group_by(query, key) {
+            let group_values = self.iter()
+                .filter(|(primary_tag_key, primary_tag)| query.tags_filter.apply(&named_primary_tags, primary_tag_key, &primary_tag.tags_index).is_some())
+                .flat_map(|(primary_tag_key, _)| primary_tag_key.named())
+                .filter(|tag| tag.0 == key.0[0])
+                .map(|tag| tag.1.clone())
+                .collect::<Vec<_>>();
+            return vec![group_values];
+        }
+
         let mut group_dimensions = key.0.iter().map(|_| FnvHashSet::default()).collect::<Vec<_>>();
 
         let mut try_add_tag = |tag: &Tag| {
@@ -253,29 +505,169 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagsStorage<TStorage, E> {
             }
         }
 
-        let group_dimensions = group_dimensions.into_iter().map(|dimension| Vec::from_iter(dimension.into_iter())).collect::<Vec<_>>();
-        let group_values = cartesian_product_groups(&key, group_dimensions);
+        group_dimensions.into_iter().map(|dimension| Vec::from_iter(dimension.into_iter())).collect::<Vec<_>>()
+    }
+
+    pub fn last<F: Fn(E) -> ExpressionValue>(&self, query: &Query, to_value: F) -> OperationResult {
+        let (_, end_time) = query.time_range.int_range();
+        let staleness = (query.staleness.unwrap_or(self.config.staleness) * TIME_SCALE as f64) as Time;
+        let start_time = end_time.saturating_sub(staleness);
+
+        let apply = |tags_filter: &TagsFilter| {
+            let mut last_datapoint: Option<(Time, E)> = None;
+            for (primary_tag, tags_filter) in self.iter_for_query(tags_filter) {
+                let storage = primary_tag.storage(None);
+                if let Some(start_block_index) = helpers::find_block_index(storage, start_time) {
+                    helpers::visit_datapoints_in_time_range(
+                        storage,
+                        start_time,
+                        end_time,
+                        tags_filter,
+                        start_block_index,
+                        false,
+                        |_, datapoint_time, datapoint| {
+                            let is_newer = match &last_datapoint {
+                                Some((last_time, _)) => datapoint_time >= *last_time,
+                                None => true
+                            };
+
+                            if is_newer {
+                                last_datapoint = Some((datapoint_time, datapoint.value));
+                            }
+                        }
+                    );
+                }
+            }
 
-        group_values
+            let (_, value) = last_datapoint?;
+            query.apply_output_transform(to_value(value))
+        };
+
+        match &query.group_by {
+            None => {
+                OperationResult::Value(apply(&query.tags_filter))
+            }
+            Some(key) => {
+                OperationResult::GroupValues(self.apply_group_by(query, key, apply))
+            }
+        }
     }
 
-    pub fn scheduled(&mut self) {
-        for primary_tag in self.tags.values_mut() {
+    pub fn latest_time(&self) -> Option<f64> {
+        self.iter()
+            .flat_map(|(_, primary_tag)| primary_tag.storage_for_durations[0].active_block_time_range())
+            .map(|(_, end_time)| end_time)
+            .max()
+            .map(|end_time| end_time as f64 / TIME_SCALE as f64)
+    }
+
+    pub fn start_time(&self) -> Option<Time> {
+        self.iter()
+            .flat_map(|(_, primary_tag)| primary_tag.storage_for_durations[0].time_range())
+            .map(|(start_time, _)| start_time)
+            .min()
+    }
+
+    pub fn sealed_time(&self) -> Option<Time> {
+        self.iter()
+            .flat_map(|(_, primary_tag)| {
+                primary_tag.storage_for_durations
+                    .iter()
+                    .flat_map(|storage| storage.active_block_time_range())
+                    .map(|(start_time, _)| start_time)
+                    .collect::<Vec<_>>()
+            })
+            .min()
+    }
+
+    pub fn num_primary_tags_with_data(&self) -> usize {
+        self.iter()
+            .filter(|(_, primary_tag)| primary_tag.storage_for_durations.iter().any(|storage| storage.active_block_time_range().is_some()))
+            .count()
+    }
+
+    pub fn flush(&self) -> MetricResult<()> {
+        for (primary_tag_key, primary_tag) in self.tags.iter() {
+            self.write_primary_tag(primary_tag_key, primary_tag).flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn move_to_cold_tier(&self, hot_root: &Path, cold_root: &Path, older_than: Time) -> MetricResult<usize> {
+        let mut num_moved = 0;
+        for (primary_tag_key, primary_tag) in self.tags.iter() {
+            num_moved += self.write_primary_tag(primary_tag_key, primary_tag).move_to_cold_tier(hot_root, cold_root, older_than, &self.config.durations)?;
+        }
+
+        Ok(num_moved)
+    }
+
+    pub fn remove_expired_segments(&self, now: Time) -> MetricResult<usize> {
+        let mut num_removed = 0;
+        for (primary_tag_key, primary_tag) in self.tags.iter() {
+            num_removed += self.write_primary_tag(primary_tag_key, primary_tag).remove_expired_segments(now)?;
+        }
+
+        Ok(num_removed)
+    }
+
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (tag, primary_tag) in self.iter() {
+            for problem in primary_tag.verify() {
+                problems.push(format!("primary tag {:?}, {}", tag, problem));
+            }
+        }
+
+        problems
+    }
+
+    pub fn scheduled(&self) {
+        if self.read_only {
+            return;
+        }
+
+        for (primary_tag_key, primary_tag) in self.tags.iter() {
+            let mut primary_tag = self.write_primary_tag(primary_tag_key, primary_tag);
             primary_tag.scheduled();
+
+            if self.config.pinned {
+                if let Err(err) = primary_tag.pin_active_segments() {
+                    tracing::warn!(path = ?self.base_path, error = %err, "failed to pin active segment");
+                }
+            }
+        }
+    }
+}
+
+impl<TStorage: MetricStorage<E>, E: Copy + Into<f64>> PrimaryTagsStorage<TStorage, E> {
+    pub fn update_block_digests(&self) -> MetricResult<()> {
+        if self.read_only {
+            return Ok(());
         }
+
+        for (primary_tag_key, primary_tag) in self.tags.iter() {
+            self.write_primary_tag(primary_tag_key, primary_tag).update_block_digests(self.config.digest.size)?;
+        }
+
+        Ok(())
     }
 }
 
 pub struct PrimaryTagMetric<TStorage: MetricStorage<E>, E: Copy> {
     storage_for_durations: Vec<TStorage>,
     tags_index: SecondaryTagsIndex,
+    block_digests: Arc<BlockDigests>,
+    /// The number of values in the last datapoint of each secondary tags (per storage), only known for datapoints written since loaded.
+    merged_values: Vec<FnvHashMap<Tags, u32>>,
     _phantom: PhantomData<E>
 }
 
 impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagMetric<TStorage, E> {
     pub fn new(base_path: &Path, config: &MetricConfig) -> MetricResult<PrimaryTagMetric<TStorage, E>> {
         if !base_path.exists() {
-            std::fs::create_dir_all(base_path).map_err(|err| MetricError::FailedToCreateMetric(err))?;
+            std::fs::create_dir_all(base_path).map_err(|err| MetricError::FailedToCreateMetric(base_path.to_owned(), err))?;
         }
 
         let mut storage_for_durations = Vec::new();
@@ -286,7 +678,7 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagMetric<TStorage, E> {
             let storage_name = format!("{}", storage_config.datapoint_duration);
             let storage_folder = base_path.join(&storage_name);
             if !storage_folder.exists() {
-                std::fs::create_dir_all(&storage_folder).map_err(|err| MetricError::FailedToCreateMetric(err))?;
+                std::fs::create_dir_all(&storage_folder).map_err(|err| MetricError::FailedToCreateMetric(storage_folder.clone(), err))?;
             }
 
             storage_for_durations.push(TStorage::new(&storage_folder, storage_config)?);
@@ -299,55 +691,59 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagMetric<TStorage, E> {
             Ok(())
         };
 
-        save().map_err(|err| MetricError::FailedToCreateMetric(err))?;
+        save().map_err(|err| MetricError::FailedToCreateMetric(base_path.to_owned(), err))?;
 
+        let num_storages = storage_for_durations.len();
         Ok(
             PrimaryTagMetric {
                 storage_for_durations,
                 tags_index: SecondaryTagsIndex::new(base_path),
+                block_digests: Arc::new(BlockDigests::new(base_path)),
+                merged_values: (0..num_storages).map(|_| FnvHashMap::default()).collect(),
                 _phantom: PhantomData::default()
             }
         )
     }
 
-    pub fn from_existing(base_path: &Path) -> MetricResult<PrimaryTagMetric<TStorage, E>> {
+    pub fn from_existing(base_path: &Path, read_only: bool) -> MetricResult<PrimaryTagMetric<TStorage, E>> {
         let load = || {
             let content = std::fs::read_to_string(base_path.join("config.json"))?;
             let storage_names: Vec<String> = serde_json::from_str(&content)?;
             Ok(storage_names)
         };
 
-        let storage_names = load().map_err(|err| MetricError::FailedToLoadMetric(err))?;
+        let storage_names = load().map_err(|err| MetricError::FailedToLoadMetric(base_path.to_owned(), err))?;
         let mut storage_for_durations = Vec::new();
         for storage_name in storage_names {
-            storage_for_durations.push(TStorage::from_existing(&base_path.join(storage_name))?);
+            let storage_path = base_path.join(storage_name);
+            if read_only {
+                storage_for_durations.push(TStorage::from_existing_read_only(&storage_path)?);
+            } else {
+                storage_for_durations.push(TStorage::from_existing(&storage_path)?);
+            }
         }
 
+        let num_storages = storage_for_durations.len();
         Ok(
             PrimaryTagMetric {
                 storage_for_durations,
                 tags_index: SecondaryTagsIndex::load(&base_path.join("tags.json"))?,
+                block_digests: Arc::new(BlockDigests::load(base_path)?),
+                merged_values: (0..num_storages).map(|_| FnvHashMap::default()).collect(),
                 _phantom: PhantomData::default()
             }
         )
     }
 
     pub fn storage(&self, time_range: Option<(Time, Time, Time)>) -> &TStorage {
-        // We assume that each storage duration is ordered in decreasing datapoint duration
-        if let Some((start_time, end_time, duration)) = time_range {
-            if duration < self.storage_for_durations[0].datapoint_duration() {
-                for storage_duration in self.storage_for_durations.iter().skip(1).rev() {
-                    if let Some((storage_start_time, storage_end_time)) = storage_duration.time_range() {
-                        if start_time >= storage_start_time && end_time <= storage_end_time {
-                            return storage_duration;
-                        }
-                    }
-                }
-            }
+        select_storage(&self.storage_for_durations, time_range)
+    }
 
-            &self.storage_for_durations[0]
-        } else {
-            &self.storage_for_durations[0]
+    pub fn snapshot(&self) -> PrimaryTagSnapshot<TStorage, E> {
+        PrimaryTagSnapshot {
+            storage_for_durations: self.storage_for_durations.iter().map(|storage| storage.snapshot()).collect(),
+            block_digests: self.block_digests.clone(),
+            _phantom: PhantomData::default()
         }
     }
 
@@ -355,72 +751,223 @@ impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagMetric<TStorage, E> {
                time: f64,
                value: E,
                secondary_tags: Tags,
-               handle_same_datapoint: impl Fn(&mut Datapoint<E>, E)) -> MetricResult<()> {
-        let add = |storage: &mut TStorage| {
-            let time = (time * TIME_SCALE as f64).round() as Time;
-
-            let mut datapoint = Datapoint {
-                time_offset: 0,
-                value
-            };
-
-            if let Some((block_start_time, block_end_time)) = storage.active_block_time_range() {
-                if time < block_end_time {
-                    return Err(MetricError::InvalidTimeOrder);
-                }
+               handle_same_datapoint: impl Fn(&mut Datapoint<E>, E, u32)) -> MetricResult<()> {
+        let time = (time * TIME_SCALE as f64).round() as Time;
 
-                let time_offset = time - block_start_time;
-                if time_offset < storage.block_duration() {
-                    assert!(time_offset < u32::MAX as u64);
-                    datapoint.time_offset = time_offset as u32;
-
-                    let datapoint_duration = storage.datapoint_duration();
-                    if let Some(last_datapoint) = storage.last_datapoint_mut(secondary_tags) {
-                        if (time - (block_start_time + last_datapoint.time_offset as u64)) < datapoint_duration {
-                            handle_same_datapoint(last_datapoint, value);
-                            return Ok(());
-                        }
-                    }
+        for (storage, merged_values) in self.storage_for_durations.iter_mut().zip(self.merged_values.iter_mut()) {
+            add_to_storage(storage, merged_values, time, value, secondary_tags, &handle_same_datapoint)?;
+        }
 
-                    storage.add_datapoint(secondary_tags, datapoint)?;
-                } else {
-                    storage.create_block_with_datapoint(time, secondary_tags, datapoint)?;
-                }
-            } else {
-                storage.create_block_with_datapoint(time, secondary_tags, datapoint)?;
-            }
+        Ok(())
+    }
 
-            Ok(())
-        };
+    pub fn add_batch(&mut self,
+                     datapoints: &[(Time, E, Tags)],
+                     handle_same_datapoint: impl Fn(&mut Datapoint<E>, E, u32)) -> MetricResult<usize> {
+        let mut num_added = 0;
+        for (storage, merged_values) in self.storage_for_durations.iter_mut().zip(self.merged_values.iter_mut()) {
+            num_added = add_batch_to_storage(storage, merged_values, datapoints, &handle_same_datapoint)?;
+        }
 
+        Ok(num_added)
+    }
+
+    pub fn scheduled(&mut self) {
         for storage in &mut self.storage_for_durations {
-            add(storage)?;
+            storage.scheduled();
+        }
+    }
+
+    pub fn flush(&mut self) -> MetricResult<()> {
+        for storage in &mut self.storage_for_durations {
+            storage.flush()?;
         }
 
         Ok(())
     }
 
-    pub fn scheduled(&mut self) {
+    pub fn move_to_cold_tier(&mut self,
+                             hot_root: &Path,
+                             cold_root: &Path,
+                             older_than: Time,
+                             durations: &[MetricStorageDurationConfig]) -> MetricResult<usize> {
+        let mut num_moved = 0;
+        for (index, storage) in self.storage_for_durations.iter_mut().enumerate() {
+            let compression_level = durations.get(index).and_then(|duration| duration.cold_compression_level);
+            num_moved += storage.move_to_cold_tier(hot_root, cold_root, older_than, compression_level)?;
+        }
+
+        Ok(num_moved)
+    }
+
+    pub fn remove_expired_segments(&mut self, now: Time) -> MetricResult<usize> {
+        let mut num_removed = 0;
         for storage in &mut self.storage_for_durations {
-            storage.scheduled();
+            num_removed += storage.remove_expired_segments(now)?;
+        }
+
+        Ok(num_removed)
+    }
+
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for storage in &self.storage_for_durations {
+            let datapoint_duration = storage.datapoint_duration() as f64 / TIME_SCALE as f64;
+            for problem in storage.verify() {
+                problems.push(format!("datapoint duration {}: {}", datapoint_duration, problem));
+            }
+        }
+
+        problems
+    }
+
+    pub fn pin_active_segments(&mut self) -> MetricResult<()> {
+        for storage in &mut self.storage_for_durations {
+            storage.pin_active_segment()?;
         }
+
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize)]
+impl<TStorage: MetricStorage<E>, E: Copy + Into<f64>> PrimaryTagMetric<TStorage, E> {
+    pub fn update_block_digests(&mut self, digest_size: usize) -> MetricResult<()> {
+        let storage = &self.storage_for_durations[0];
+        if let Some((start_time, _)) = storage.time_range() {
+            if self.block_digests.contains_before(start_time) {
+                Arc::make_mut(&mut self.block_digests).remove_before(start_time)?;
+            }
+        }
+
+        // Only sealed blocks are digested, the active block is always scanned
+        let mut new_block_indices = Vec::new();
+        for block_index in (0..storage.len().saturating_sub(1)).rev() {
+            let (block_start_time, _) = storage.block_time_range(block_index).unwrap();
+            if self.block_digests.contains(block_start_time) {
+                break;
+            }
+
+            new_block_indices.push(block_index);
+        }
+
+        for block_index in new_block_indices.into_iter().rev() {
+            let (block_start_time, _) = storage.block_time_range(block_index).unwrap();
+
+            let mut sub_blocks = Vec::new();
+            if let Some(iterator) = storage.block_datapoints(block_index) {
+                for (tags, datapoints) in iterator {
+                    let values = datapoints.iter().map(|datapoint| datapoint.value.into()).collect::<Vec<f64>>();
+                    sub_blocks.push((tags, TDigest::new_with_size(digest_size).merge_unsorted(values)));
+                }
+            }
+
+            Arc::make_mut(&mut self.block_digests).add(block_start_time, sub_blocks)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct MetricConfig {
+    #[cfg_attr(feature = "server", schema(value_type = Vec<String>))]
     auto_primary_tags: FnvHashSet<String>,
-    pub durations: Vec<MetricStorageDurationConfig>
+    pub durations: Vec<MetricStorageDurationConfig>,
+    #[serde(default="default_staleness")]
+    pub staleness: f64,
+    #[serde(default)]
+    pub digest: DigestConfig,
+    /// Only used by gauge metrics, ratio metrics always use t-digest.
+    #[serde(default)]
+    pub percentile_algorithm: PercentileAlgorithm,
+    /// Keeps the most recent data locked in memory, for metrics that must stay fast to query.
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub rollups: Vec<RollupConfig>,
+    /// Only used by gauge metrics, count and ratio metrics always sum the values.
+    #[serde(default)]
+    pub gauge_collision: GaugeCollision
+}
+
+/// How values of a gauge metric that fall within the same datapoint (given by the datapoint duration) are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum GaugeCollision {
+    #[default]
+    KeepLast,
+    /// The average of the values, where datapoints written before the metric was loaded count as a single value.
+    Average,
+    Min,
+    Max
+}
+
+impl GaugeCollision {
+    /// Combines the value with the current value of the datapoint, which consists of the given number of values.
+    pub fn combine(&self, current: f32, value: f32, num_values: u32) -> f32 {
+        match self {
+            GaugeCollision::KeepLast => value,
+            GaugeCollision::Average => current + (value - current) / (num_values + 1) as f32,
+            GaugeCollision::Min => current.min(value),
+            GaugeCollision::Max => current.max(value)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum RollupOperation {
+    Average,
+    Sum,
+    Max,
+    Min
+}
+
+/// A gauge metric that is maintained with the aggregated windows of the metric, such that queries at the resolution of
+/// the rollup never scan the raw data. Windows are written once they only cover sealed blocks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct RollupConfig {
+    /// The metric the windows are written to, created if it does not exist.
+    pub metric: String,
+    /// The duration (in seconds) of each window.
+    pub duration: f64,
+    pub operation: RollupOperation,
+    /// Each combination of values of the keys is rolled up separately, and written with the values as tags.
+    #[serde(default)]
+    pub group_by: Option<GroupKey>
+}
+
+impl RollupConfig {
+    pub fn new(metric: &str, duration: f64, operation: RollupOperation) -> RollupConfig {
+        RollupConfig {
+            metric: metric.to_owned(),
+            duration,
+            operation,
+            group_by: None
+        }
+    }
 }
 
 impl MetricConfig {
     pub fn new(metric_type: MetricType) -> MetricConfig {
         MetricConfig {
             auto_primary_tags: FnvHashSet::default(),
-            durations: vec![MetricStorageDurationConfig::default_for(metric_type)]
+            durations: vec![MetricStorageDurationConfig::default_for(metric_type)],
+            staleness: DEFAULT_STALENESS,
+            digest: DigestConfig::default(),
+            percentile_algorithm: PercentileAlgorithm::default(),
+            pinned: false,
+            rollups: Vec::new(),
+            gauge_collision: GaugeCollision::default()
         }
     }
 
+    pub fn add_auto_primary_tag(&mut self, key: &str) {
+        self.auto_primary_tags.insert(key.to_owned());
+    }
+
     pub fn save(&self, path: &Path) -> MetricResult<()> {
         let save = || {
             let content = serde_json::to_string(self)?;
@@ -442,12 +989,20 @@ impl MetricConfig {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+fn default_staleness() -> f64 {
+    DEFAULT_STALENESS
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct MetricStorageDurationConfig {
     pub max_segments: Option<usize>,
     pub segment_duration: f64,
     pub block_duration: f64,
-    pub datapoint_duration: f64
+    pub datapoint_duration: f64,
+    /// The zstd level used to compress the segments moved to the cold tier, which are not compressed if not set.
+    #[serde(default)]
+    pub cold_compression_level: Option<i32>
 }
 
 impl MetricStorageDurationConfig {
@@ -460,7 +1015,8 @@ impl MetricStorageDurationConfig {
                 MetricType::Gauge => DEFAULT_GAUGE_DATAPOINT_DURATION,
                 MetricType::Count => DEFAULT_COUNT_DATAPOINT_DURATION,
                 MetricType::Ratio => DEFAULT_RATIO_DATAPOINT_DURATION
-            }
+            },
+            cold_compression_level: None
         }
     }
 
@@ -503,7 +1059,7 @@ impl PrimaryTagsSerialization {
         Ok(())
     }
 
-    pub fn load<TStorage: MetricStorage<E>, E: Copy>(&self) -> MetricResult<PrimaryTags<TStorage, E>> {
+    pub fn load<TStorage: MetricStorage<E>, E: Copy>(&self, read_only: bool) -> MetricResult<PrimaryTags<TStorage, E>> {
         let mut primary_tags = FnvHashMap::default();
 
         let load = || -> std::io::Result<Vec<PrimaryTag>> {
@@ -517,7 +1073,7 @@ impl PrimaryTagsSerialization {
             let primary_tag_base_path = primary_tag_value.path(&self.base_path);
             primary_tags.insert(
                 primary_tag_value,
-                PrimaryTagMetric::from_existing(&primary_tag_base_path)?
+                RwLock::new(PrimaryTagMetric::from_existing(&primary_tag_base_path, read_only)?)
             );
         }
 
@@ -525,6 +1081,203 @@ impl PrimaryTagsSerialization {
     }
 }
 
+/// The storages of a primary tag as they were when the snapshot was taken.
+pub struct PrimaryTagSnapshot<TStorage: MetricStorage<E>, E: Copy> {
+    storage_for_durations: Vec<TStorage>,
+    block_digests: Arc<BlockDigests>,
+    _phantom: PhantomData<E>
+}
+
+impl<TStorage: MetricStorage<E>, E: Copy> PrimaryTagSnapshot<TStorage, E> {
+    pub fn storage(&self, time_range: Option<(Time, Time, Time)>) -> &TStorage {
+        select_storage(&self.storage_for_durations, time_range)
+    }
+
+    pub fn storages_for_window(&self, start_time: Time, end_time: Time, duration: Time) -> Vec<(&TStorage, Time, Time)> {
+        select_storages_for_window(&self.storage_for_durations, start_time, end_time, duration)
+    }
+
+    pub fn block_digests(&self) -> &BlockDigests {
+        &self.block_digests
+    }
+}
+
+fn select_storage<TStorage: MetricStorage<E>, E: Copy>(storage_for_durations: &[TStorage], time_range: Option<(Time, Time, Time)>) -> &TStorage {
+    // We assume that each storage duration is ordered in decreasing datapoint duration
+    if let Some((start_time, end_time, duration)) = time_range {
+        if duration < storage_for_durations[0].datapoint_duration() {
+            for storage_duration in storage_for_durations.iter().skip(1).rev() {
+                if let Some((storage_start_time, storage_end_time)) = storage_duration.time_range() {
+                    if start_time >= storage_start_time && end_time <= storage_end_time {
+                        return storage_duration;
+                    }
+                }
+            }
+        }
+
+        &storage_for_durations[0]
+    } else {
+        &storage_for_durations[0]
+    }
+}
+
+/// Returns the storage to use for each part of the time range of a windowed query. A finer storage that only covers the
+/// recent part of the range is used for the windows that it covers, and the coarsest storage for the older windows.
+fn select_storages_for_window<TStorage: MetricStorage<E>, E: Copy>(storage_for_durations: &[TStorage],
+                                                                   start_time: Time,
+                                                                   end_time: Time,
+                                                                   duration: Time) -> Vec<(&TStorage, Time, Time)> {
+    let coarsest_storage = &storage_for_durations[0];
+    if duration < coarsest_storage.datapoint_duration() {
+        for storage_duration in storage_for_durations.iter().skip(1).rev() {
+            if let Some((storage_start_time, _)) = storage_duration.time_range() {
+                // The range can extend past the most recent datapoint, which is the same for all durations
+                if start_time >= storage_start_time {
+                    return vec![(storage_duration, start_time, end_time)];
+                }
+
+                if storage_start_time <= end_time {
+                    // Windows are never split between storages
+                    let num_coarse_windows = (storage_start_time - start_time).div_ceil(duration);
+                    let split_time = start_time + num_coarse_windows * duration;
+                    if split_time <= end_time {
+                        return vec![
+                            (coarsest_storage, start_time, split_time - 1),
+                            (storage_duration, split_time, end_time)
+                        ];
+                    }
+                }
+            }
+        }
+    }
+
+    vec![(coarsest_storage, start_time, end_time)]
+}
+
+fn add_to_storage<TStorage: MetricStorage<E>, E: Copy>(storage: &mut TStorage,
+                                                       merged_values: &mut FnvHashMap<Tags, u32>,
+                                                       time: Time,
+                                                       value: E,
+                                                       secondary_tags: Tags,
+                                                       handle_same_datapoint: &impl Fn(&mut Datapoint<E>, E, u32)) -> MetricResult<()> {
+    let mut datapoint = Datapoint {
+        time_offset: 0,
+        value
+    };
+
+    if let Some((block_start_time, block_end_time)) = storage.active_block_time_range() {
+        if time < block_end_time {
+            return Err(MetricError::InvalidTimeOrder);
+        }
+
+        let time_offset = time - block_start_time;
+        if time_offset < storage.block_duration() {
+            assert!(time_offset < u32::MAX as u64);
+            datapoint.time_offset = time_offset as u32;
+
+            let datapoint_duration = storage.datapoint_duration();
+            if let Some(last_datapoint) = storage.last_datapoint_mut(secondary_tags) {
+                if (time - (block_start_time + last_datapoint.time_offset as u64)) < datapoint_duration {
+                    merge_datapoint(merged_values, last_datapoint, value, secondary_tags, handle_same_datapoint);
+                    return Ok(());
+                }
+            }
+
+            storage.add_datapoint(secondary_tags, datapoint)?;
+        } else {
+            storage.create_block_with_datapoint(time, secondary_tags, datapoint)?;
+        }
+    } else {
+        storage.create_block_with_datapoint(time, secondary_tags, datapoint)?;
+    }
+
+    merged_values.remove(&secondary_tags);
+    Ok(())
+}
+
+fn add_batch_to_storage<TStorage: MetricStorage<E>, E: Copy>(storage: &mut TStorage,
+                                                             merged_values: &mut FnvHashMap<Tags, u32>,
+                                                             datapoints: &[(Time, E, Tags)],
+                                                             handle_same_datapoint: &impl Fn(&mut Datapoint<E>, E, u32)) -> MetricResult<usize> {
+    let mut num_added = 0;
+    let mut runs: FnvHashMap<Tags, Vec<Datapoint<E>>> = FnvHashMap::default();
+    let mut active_block_time_range = storage.active_block_time_range();
+
+    for &(time, value, secondary_tags) in datapoints {
+        let mut datapoint = Datapoint {
+            time_offset: 0,
+            value
+        };
+
+        if let Some((_, block_end_time)) = active_block_time_range {
+            if time < block_end_time {
+                continue;
+            }
+        }
+
+        match active_block_time_range {
+            Some((block_start_time, block_end_time)) if time - block_start_time < storage.block_duration() => {
+                let time_offset = time - block_start_time;
+                assert!(time_offset < u32::MAX as u64);
+                datapoint.time_offset = time_offset as u32;
+
+                let datapoint_duration = storage.datapoint_duration();
+                let run = runs.entry(secondary_tags).or_default();
+                let is_same_datapoint = {
+                    let last_datapoint = if run.is_empty() {
+                        storage.last_datapoint_mut(secondary_tags)
+                    } else {
+                        run.last_mut()
+                    };
+
+                    match last_datapoint {
+                        Some(last_datapoint) if (time - (block_start_time + last_datapoint.time_offset as u64)) < datapoint_duration => {
+                            merge_datapoint(merged_values, last_datapoint, value, secondary_tags, handle_same_datapoint);
+                            true
+                        }
+                        _ => false
+                    }
+                };
+
+                if !is_same_datapoint {
+                    merged_values.remove(&secondary_tags);
+                    run.push(datapoint);
+                    active_block_time_range = Some((block_start_time, block_end_time.max(time)));
+                }
+            }
+            _ => {
+                flush_runs(storage, &mut runs)?;
+                storage.create_block_with_datapoint(time, secondary_tags, datapoint)?;
+                merged_values.remove(&secondary_tags);
+                active_block_time_range = Some((time, time));
+            }
+        }
+
+        num_added += 1;
+    }
+
+    flush_runs(storage, &mut runs)?;
+    Ok(num_added)
+}
+
+fn merge_datapoint<E: Copy>(merged_values: &mut FnvHashMap<Tags, u32>,
+                            last_datapoint: &mut Datapoint<E>,
+                            value: E,
+                            secondary_tags: Tags,
+                            handle_same_datapoint: &impl Fn(&mut Datapoint<E>, E, u32)) {
+    let num_values = merged_values.entry(secondary_tags).or_insert(1);
+    handle_same_datapoint(last_datapoint, value, *num_values);
+    *num_values += 1;
+}
+
+fn flush_runs<TStorage: MetricStorage<E>, E: Copy>(storage: &mut TStorage, runs: &mut FnvHashMap<Tags, Vec<Datapoint<E>>>) -> MetricResult<()> {
+    for (secondary_tags, run) in runs.drain() {
+        storage.add_datapoints(secondary_tags, &run)?;
+    }
+
+    Ok(())
+}
+
 fn cartesian_product_groups(group_key: &GroupKey, group_dimensions: Vec<Vec<String>>) -> Vec<Vec<Tag>> {
     for dimension in &group_dimensions {
         if dimension.is_empty() {
@@ -566,6 +1319,11 @@ fn cartesian_product_groups(group_key: &GroupKey, group_dimensions: Vec<Vec<Stri
     group_values
 }
 
+/// The name of the metric stored at the path, until set by the engine.
+fn default_metric_name(base_path: &Path) -> String {
+    base_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
 fn clean_dir(path: &Path) -> std::io::Result<()> {
     for entry in std::fs::read_dir(path)? {
         if let Ok(entry) = entry {
@@ -1,13 +1,15 @@
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
+use crate::engine::lock_watchdog::LockWatchdog;
 use crate::metric::common::{CountInput, GenericMetric, MetricType, PrimaryTagsStorage, MetricConfig};
 use crate::metric::helpers::{MetricWindowing};
 use crate::metric::operations::{StreamingConvert, StreamingOperation, StreamingSum, StreamingTimeAverage};
 use crate::metric::{helpers, OperationResult};
 use crate::metric::expression::ExpressionValue;
 use crate::metric::tags::{PrimaryTag, Tag, TagsFilter};
-use crate::model::{MetricResult, Query, Time, TIME_SCALE, TimeRange};
+use crate::model::{GroupKey, MetricResult, Query, Time, TIME_SCALE, TimeRange};
 use crate::storage::file::FileMetricStorage;
 use crate::storage::MetricStorage;
 
@@ -42,13 +44,23 @@ impl<TStorage: MetricStorage<u32>> CountMetric<TStorage> {
         )
     }
 
+    pub fn from_existing_read_only(base_path: &Path) -> MetricResult<CountMetric<TStorage>> {
+        Ok(
+            CountMetric {
+                primary_tags_storage: PrimaryTagsStorage::from_existing_read_only(base_path)?
+            }
+        )
+    }
+
     pub fn primary_tags(&self) -> impl Iterator<Item=&PrimaryTag> {
         self.primary_tags_storage.primary_tags()
     }
 
     fn operation<T: StreamingOperation<u64, f64>, F: Fn() -> T>(&self, query: Query, create_op: F) -> OperationResult {
         let (start_time, end_time) = query.time_range.int_range();
-        assert!(end_time > start_time);
+        if end_time <= start_time {
+            return OperationResult::empty(&query, false);
+        }
 
         let apply = |tags_filter: &TagsFilter| {
             let mut streaming_operations = Vec::new();
@@ -95,9 +107,10 @@ impl<TStorage: MetricStorage<u32>> CountMetric<TStorage> {
                                                                                   duration: Duration,
                                                                                   create_op: F) -> OperationResult {
         let (start_time, end_time) = query.time_range.int_range();
-        assert!(end_time > start_time);
-
         let duration = (duration.as_secs_f64() * TIME_SCALE as f64) as Time;
+        if end_time <= start_time || duration == 0 {
+            return OperationResult::empty(&query, true);
+        }
 
         let apply = |tags_filter: &TagsFilter| {
             let mut primary_tags_windowing = Vec::new();
@@ -136,10 +149,12 @@ impl<TStorage: MetricStorage<u32>> CountMetric<TStorage> {
                 return Vec::new();
             }
 
-            helpers::extract_operations_in_windows(
-                helpers::merge_windowing(primary_tags_windowing),
-                |value| query.apply_output_transform(ExpressionValue::Float(value?)),
-                query.remove_empty_datapoints
+            query.apply_output_transform_in_windows(
+                helpers::extract_operations_in_windows(
+                    helpers::merge_windowing(primary_tags_windowing),
+                    |value| query.apply_output_transform(ExpressionValue::Float(value?)),
+                    query.remove_empty_datapoints
+                )
             )
         };
 
@@ -167,21 +182,56 @@ impl<TStorage: MetricStorage<u32>> GenericMetric for CountMetric<TStorage> {
         self.primary_tags_storage.add_auto_primary_tag(key)
     }
 
+    fn remove_primary_tag(&mut self, tag: &Tag) -> MetricResult<bool> {
+        self.primary_tags_storage.remove_primary_tag(tag)
+    }
+
+    fn remove_auto_primary_tag(&mut self, key: &str) -> MetricResult<bool> {
+        self.primary_tags_storage.remove_auto_primary_tag(key)
+    }
+
+    fn auto_primary_tags(&self) -> Vec<String> {
+        self.primary_tags_storage.auto_primary_tags()
+    }
+
+    fn requires_new_primary_tags(&self, tags: &[Tag]) -> bool {
+        self.primary_tags_storage.requires_new_primary_tags(tags)
+    }
+
+    fn create_auto_primary_tags(&mut self, tags: &[Tag]) -> MetricResult<()> {
+        self.primary_tags_storage.create_auto_primary_tags(tags)
+    }
+
+    fn num_groups(&self, query: &Query, key: &GroupKey) -> usize {
+        self.primary_tags_storage.num_groups(query, key)
+    }
+
+    fn group_values(&self, query: &Query, key: &GroupKey) -> Vec<Vec<Tag>> {
+        self.primary_tags_storage.gather_group_values(query, key)
+    }
+
     type Input = CountInput;
-    fn add(&mut self, time: f64, count: CountInput, mut tags: Vec<Tag>) -> MetricResult<()> {
-        let (primary_tag_key, mut primary_tag, secondary_tags) = self.primary_tags_storage.insert_tags(&mut tags)?;
+    fn add_concurrent(&self, time: f64, count: CountInput, mut tags: Vec<Tag>) -> MetricResult<()> {
+        let (mut primary_tag, secondary_tags) = self.primary_tags_storage.insert_tags(&mut tags)?;
 
-        let result = primary_tag.add(
+        primary_tag.add(
             time,
             count.value()?,
             secondary_tags,
-            |last_datapoint, value| {
+            |last_datapoint, value, _| {
                 last_datapoint.value += value;
             }
-        );
+        )
+    }
 
-        self.primary_tags_storage.return_tags(primary_tag_key, primary_tag);
-        result
+    fn add_batch(&self, values: &[(f64, CountInput, Vec<Tag>)]) -> MetricResult<usize> {
+        self.primary_tags_storage.add_batch(
+            values,
+            |count: CountInput| count.value(),
+            |last_datapoint, value, _| {
+                last_datapoint.value += value;
+            }
+        )
     }
 
     fn sum(&self, query: Query) -> OperationResult {
@@ -212,6 +262,10 @@ impl<TStorage: MetricStorage<u32>> GenericMetric for CountMetric<TStorage> {
         OperationResult::NotSupported
     }
 
+    fn last(&self, query: Query) -> OperationResult {
+        self.primary_tags_storage.last(&query, |value| ExpressionValue::Float(value as f64))
+    }
+
     fn sum_in_window(&self, query: Query, duration: Duration) -> OperationResult {
         if query.input_filter.is_some() || query.input_transform.is_some() {
             return OperationResult::NotSupported;
@@ -240,7 +294,46 @@ impl<TStorage: MetricStorage<u32>> GenericMetric for CountMetric<TStorage> {
         OperationResult::NotSupported
     }
 
-    fn scheduled(&mut self) {
+    fn scheduled(&self) {
         self.primary_tags_storage.scheduled();
     }
+    fn config(&self) -> &MetricConfig {
+        self.primary_tags_storage.config()
+    }
+
+    fn start_time(&self) -> Option<Time> {
+        self.primary_tags_storage.start_time()
+    }
+
+    fn latest_time(&self) -> Option<f64> {
+        self.primary_tags_storage.latest_time()
+    }
+
+    fn sealed_time(&self) -> Option<Time> {
+        self.primary_tags_storage.sealed_time()
+    }
+
+    fn num_primary_tags_with_data(&self) -> usize {
+        self.primary_tags_storage.num_primary_tags_with_data()
+    }
+
+    fn flush(&self) -> MetricResult<()> {
+        self.primary_tags_storage.flush()
+    }
+
+    fn move_to_cold_tier(&self, hot_root: &Path, cold_root: &Path, older_than: Time) -> MetricResult<usize> {
+        self.primary_tags_storage.move_to_cold_tier(hot_root, cold_root, older_than)
+    }
+
+    fn remove_expired_segments(&self, now: Time) -> MetricResult<usize> {
+        self.primary_tags_storage.remove_expired_segments(now)
+    }
+
+    fn verify(&self) -> Vec<String> {
+        self.primary_tags_storage.verify()
+    }
+
+    fn set_lock_watchdog(&mut self, metric: &str, lock_watchdog: Arc<LockWatchdog>) {
+        self.primary_tags_storage.set_lock_watchdog(metric, lock_watchdog);
+    }
 }
\ No newline at end of file
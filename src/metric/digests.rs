@@ -0,0 +1,119 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use fnv::FnvHashMap;
+
+use tdigest::TDigest;
+
+use crate::model::{MetricError, MetricResult, Tags, Time};
+
+pub type SubBlockDigests = Vec<(Tags, TDigest)>;
+
+#[derive(Clone)]
+pub struct BlockDigests {
+    path: PathBuf,
+    digests: FnvHashMap<Time, SubBlockDigests>
+}
+
+impl BlockDigests {
+    pub fn new(base_path: &Path) -> BlockDigests {
+        BlockDigests {
+            path: base_path.join("digests.jsonl"),
+            digests: FnvHashMap::default()
+        }
+    }
+
+    pub fn load(base_path: &Path) -> MetricResult<BlockDigests> {
+        let mut block_digests = BlockDigests::new(base_path);
+        if !block_digests.path.exists() {
+            return Ok(block_digests);
+        }
+
+        let load = || -> std::io::Result<Vec<(Time, SubBlockDigests)>> {
+            let content = std::fs::read_to_string(&block_digests.path)?;
+
+            // A partially written entry is skipped, the digest is then recomputed or the block scanned instead
+            let entries = content
+                .lines()
+                .filter_map(|line| serde_json::from_str::<(Time, SubBlockDigests)>(line).ok())
+                .collect::<Vec<_>>();
+
+            Ok(entries)
+        };
+
+        let entries = load().map_err(MetricError::FailedToLoadBlockDigests)?;
+        for (block_start_time, sub_blocks) in entries {
+            block_digests.digests.insert(block_start_time, sub_blocks);
+        }
+
+        Ok(block_digests)
+    }
+
+    pub fn get(&self, block_start_time: Time) -> Option<&SubBlockDigests> {
+        self.digests.get(&block_start_time)
+    }
+
+    pub fn contains(&self, block_start_time: Time) -> bool {
+        self.digests.contains_key(&block_start_time)
+    }
+
+    pub fn contains_before(&self, time: Time) -> bool {
+        self.digests.keys().any(|&block_start_time| block_start_time < time)
+    }
+
+    pub fn add(&mut self, block_start_time: Time, sub_blocks: SubBlockDigests) -> MetricResult<()> {
+        let append = || -> std::io::Result<()> {
+            let mut content = serde_json::to_string(&(block_start_time, &sub_blocks))?;
+            content.push('\n');
+
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+            file.write_all(content.as_bytes())?;
+            Ok(())
+        };
+
+        append().map_err(MetricError::FailedToSaveBlockDigests)?;
+        self.digests.insert(block_start_time, sub_blocks);
+        Ok(())
+    }
+
+    pub fn remove_before(&mut self, time: Time) -> MetricResult<()> {
+        let num_digests = self.digests.len();
+        self.digests.retain(|&block_start_time, _| block_start_time >= time);
+        if self.digests.len() == num_digests {
+            return Ok(());
+        }
+
+        let save = || -> std::io::Result<()> {
+            let mut content = String::new();
+            for (&block_start_time, sub_blocks) in self.digests.iter() {
+                content += &serde_json::to_string(&(block_start_time, sub_blocks))?;
+                content.push('\n');
+            }
+
+            std::fs::write(&self.path, &content)?;
+            Ok(())
+        };
+
+        save().map_err(MetricError::FailedToSaveBlockDigests)
+    }
+}
+
+#[test]
+fn test_block_digests1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let mut block_digests = BlockDigests::new(temp_dir.path());
+    block_digests.add(1000, vec![(0, TDigest::new_with_size(100).merge_unsorted(vec![1.0, 2.0, 3.0]))]).unwrap();
+    block_digests.add(2000, vec![(1, TDigest::new_with_size(100).merge_unsorted(vec![4.0, 5.0]))]).unwrap();
+
+    let mut block_digests = BlockDigests::load(temp_dir.path()).unwrap();
+    assert!(block_digests.contains(1000));
+    assert!(block_digests.contains(2000));
+    assert_eq!(1, block_digests.get(2000).unwrap()[0].0);
+
+    block_digests.remove_before(1500).unwrap();
+
+    let block_digests = BlockDigests::load(temp_dir.path()).unwrap();
+    assert!(!block_digests.contains(1000));
+    assert!(block_digests.contains(2000));
+}
@@ -0,0 +1,73 @@
+use crate::metric::TimeValues;
+
+/// Downsamples the values using Largest-Triangle-Three-Buckets, which keeps the visual shape of the series.
+/// Values without a value are removed before downsampling.
+pub fn lttb(values: TimeValues, threshold: usize) -> TimeValues {
+    let points = values
+        .into_iter()
+        .filter_map(|(time, value)| value.map(|value| (time, value)))
+        .collect::<Vec<_>>();
+
+    if threshold >= points.len() || threshold == 0 {
+        return points.into_iter().map(|(time, value)| (time, Some(value))).collect();
+    }
+
+    if threshold < 3 {
+        return points.into_iter().take(threshold).map(|(time, value)| (time, Some(value))).collect();
+    }
+
+    let mut sampled = Vec::with_capacity(threshold);
+    sampled.push(points[0]);
+
+    // The first and last points are always kept, the rest are divided into buckets
+    let bucket_size = (points.len() - 2) as f64 / (threshold - 2) as f64;
+    let mut selected_index = 0;
+    for bucket_index in 0..(threshold - 2) {
+        let bucket_start = (bucket_index as f64 * bucket_size) as usize + 1;
+        let bucket_end = ((bucket_index + 1) as f64 * bucket_size) as usize + 1;
+
+        let next_bucket_start = bucket_end;
+        let next_bucket_end = (((bucket_index + 2) as f64 * bucket_size) as usize + 1).min(points.len());
+        let next_bucket = &points[next_bucket_start..next_bucket_end.max(next_bucket_start + 1).min(points.len())];
+        let next_average = (
+            next_bucket.iter().map(|(time, _)| time).sum::<f64>() / next_bucket.len() as f64,
+            next_bucket.iter().map(|(_, value)| value).sum::<f64>() / next_bucket.len() as f64
+        );
+
+        let (selected_time, selected_value) = points[selected_index];
+        let mut max_area = -1.0;
+        let mut max_area_index = bucket_start;
+        for (index, &(time, value)) in points.iter().enumerate().take(bucket_end).skip(bucket_start) {
+            let area = ((selected_time - next_average.0) * (value - selected_value) - (selected_time - time) * (next_average.1 - selected_value)).abs();
+            if area > max_area {
+                max_area = area;
+                max_area_index = index;
+            }
+        }
+
+        sampled.push(points[max_area_index]);
+        selected_index = max_area_index;
+    }
+
+    sampled.push(points[points.len() - 1]);
+    sampled.into_iter().map(|(time, value)| (time, Some(value))).collect()
+}
+
+#[test]
+fn test_lttb1() {
+    let values = (0..100).map(|time| (time as f64, Some(if time == 42 { 100.0 } else { 1.0 }))).collect::<Vec<_>>();
+    let sampled = lttb(values, 10);
+
+    assert_eq!(10, sampled.len());
+    assert_eq!((0.0, Some(1.0)), sampled[0]);
+    assert_eq!((99.0, Some(1.0)), sampled[9]);
+    assert!(sampled.contains(&(42.0, Some(100.0))));
+    assert!(sampled.windows(2).all(|pair| pair[0].0 < pair[1].0));
+}
+
+#[test]
+fn test_lttb2() {
+    let values = vec![(0.0, Some(1.0)), (1.0, None), (2.0, Some(3.0))];
+    assert_eq!(vec![(0.0, Some(1.0)), (2.0, Some(3.0))], lttb(values.clone(), 5));
+    assert_eq!(vec![(0.0, Some(1.0))], lttb(values, 1));
+}
@@ -1,5 +1,10 @@
+use std::ops::RangeInclusive;
+
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
 use crate::metric::ratio::Ratio;
+use crate::model::{QueryError, Time, TIME_SCALE};
 
 pub enum ExpressionValue {
     Float(f64),
@@ -29,46 +34,183 @@ impl ExpressionValue {
     }
 }
 
+/// The time of the datapoint that an input expression is evaluated for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DatapointTime {
+    pub time: f64,
+    /// The start of the window of the datapoint, or of the time range for queries without windows.
+    pub window_start: f64
+}
+
+impl DatapointTime {
+    pub fn new(time: Time, window_start: f64) -> DatapointTime {
+        DatapointTime {
+            time: time as f64 / TIME_SCALE as f64,
+            window_start
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum TransformExpression {
     InputValue,
     InputNumerator,
     InputDenominator,
+    /// The time (in seconds) of the datapoint, only available for input filters and transforms.
+    InputTime,
+    /// The start time (in seconds) of the window of the datapoint, only available for input filters and transforms.
+    InputWindowStart,
     Value(f64),
-    Arithmetic { operation: ArithmeticOperation, left: Box<TransformExpression>, right: Box<TransformExpression> },
-    Function { function: Function, arguments: Vec<TransformExpression> }
+    Arithmetic {
+        operation: ArithmeticOperation,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        left: Box<TransformExpression>,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        right: Box<TransformExpression>
+    },
+    Function {
+        function: Function,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        arguments: Vec<TransformExpression>
+    },
+    /// Evaluates to `then` if the condition holds for the input and otherwise to `otherwise`, where a missing branch has no value.
+    Conditional {
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        condition: Box<FilterExpression>,
+        #[serde(default)]
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        then: Option<Box<TransformExpression>>,
+        #[serde(default)]
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        otherwise: Option<Box<TransformExpression>>
+    },
+    /// Smooths the windows of windowed queries using additive Holt-Winters smoothing of the value of each window,
+    /// where the season is the given number of windows (none if zero). Non-windowed queries use the value as is.
+    HoltWinters {
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        value: Box<TransformExpression>,
+        alpha: f64,
+        beta: f64,
+        gamma: f64,
+        season_length: usize
+    }
 }
 
 impl TransformExpression {
     pub fn evaluate(&self, input: &ExpressionValue) -> Option<f64> {
+        self.evaluate_at(input, None)
+    }
+
+    pub fn evaluate_at(&self, input: &ExpressionValue, time: Option<&DatapointTime>) -> Option<f64> {
         match self {
             TransformExpression::InputValue => input.float(),
             TransformExpression::InputNumerator => input.numerator(),
             TransformExpression::InputDenominator => input.denominator(),
+            TransformExpression::InputTime => Some(time?.time),
+            TransformExpression::InputWindowStart => Some(time?.window_start),
             TransformExpression::Value(value) => Some(*value),
             TransformExpression::Arithmetic { operation, left, right } => {
-                let left = left.evaluate(input)?;
-                let right = right.evaluate(input)?;
+                let left = left.evaluate_at(input, time)?;
+                let right = right.evaluate_at(input, time)?;
                 Some(operation.apply(left, right))
             }
             TransformExpression::Function { function, arguments } => {
                 let mut transformed_arguments = Vec::new();
                 for argument in arguments {
-                    transformed_arguments.push(argument.evaluate(input)?);
+                    transformed_arguments.push(argument.evaluate_at(input, time)?);
                 }
 
                 function.apply(&transformed_arguments)
             }
+            TransformExpression::Conditional { condition, then, otherwise } => {
+                let branch = if condition.evaluate_at(input, time)? { then } else { otherwise };
+                branch.as_ref()?.evaluate_at(input, time)
+            }
+            TransformExpression::HoltWinters { value, .. } => value.evaluate_at(input, time)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), QueryError> {
+        match self {
+            TransformExpression::Arithmetic { left, right, .. } => {
+                left.validate()?;
+                right.validate()
+            }
+            TransformExpression::Function { function, arguments } => {
+                function.validate_arguments(arguments.len())?;
+                arguments.iter().try_for_each(|argument| argument.validate())
+            }
+            TransformExpression::Conditional { condition, then, otherwise } => {
+                condition.validate()?;
+                [then, otherwise].into_iter().flatten().try_for_each(|branch| branch.validate())
+            }
+            TransformExpression::HoltWinters { value, .. } => value.validate(),
+            _ => Ok(())
+        }
+    }
+
+    /// Applies the transforms that depend on the previous windows, after each window has been evaluated.
+    pub fn apply_in_windows(&self, values: &mut [(f64, Option<f64>)]) {
+        if let TransformExpression::HoltWinters { alpha, beta, gamma, season_length, .. } = self {
+            holt_winters(values, *alpha, *beta, *gamma, *season_length);
+        }
+    }
+}
+
+/// The level starts at the first value and the trend and seasonal components at zero.
+/// Windows without a value are skipped, but still advance the season.
+fn holt_winters(values: &mut [(f64, Option<f64>)], alpha: f64, beta: f64, gamma: f64, season_length: usize) {
+    let mut seasonal = vec![0.0; season_length.max(1)];
+    let mut state: Option<(f64, f64)> = None;
+    for (index, (_, value)) in values.iter_mut().enumerate() {
+        let current = match value {
+            Some(current) => *current,
+            None => { continue; }
+        };
+
+        let season_index = index % seasonal.len();
+        let season = seasonal[season_index];
+        let (level, trend) = match state {
+            Some((level, trend)) => {
+                let new_level = alpha * (current - season) + (1.0 - alpha) * (level + trend);
+                (new_level, beta * (new_level - level) + (1.0 - beta) * trend)
+            }
+            None => (current, 0.0)
+        };
+
+        if season_length > 0 {
+            seasonal[season_index] = gamma * (current - level) + (1.0 - gamma) * season;
         }
+
+        state = Some((level, trend));
+        *value = Some(level + seasonal[season_index]);
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum FilterExpression {
     Value(TransformExpression),
-    Compare { operation: CompareOperation, left: Box<FilterExpression>, right: Box<FilterExpression> },
-    And { left: Box<FilterExpression>, right: Box<FilterExpression> },
-    Or { left: Box<FilterExpression>, right: Box<FilterExpression> }
+    Compare {
+        operation: CompareOperation,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        left: Box<FilterExpression>,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        right: Box<FilterExpression>
+    },
+    And {
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        left: Box<FilterExpression>,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        right: Box<FilterExpression>
+    },
+    Or {
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        left: Box<FilterExpression>,
+        #[cfg_attr(feature = "server", schema(no_recursion))]
+        right: Box<FilterExpression>
+    }
 }
 
 impl FilterExpression {
@@ -81,17 +223,31 @@ impl FilterExpression {
     }
 
     pub fn evaluate(&self, input: &ExpressionValue) -> Option<bool> {
-        self.evaluate_internal(input)?.bool()
+        self.evaluate_at(input, None)
+    }
+
+    pub fn evaluate_at(&self, input: &ExpressionValue, time: Option<&DatapointTime>) -> Option<bool> {
+        self.evaluate_internal(input, time)?.bool()
+    }
+
+    pub fn validate(&self) -> Result<(), QueryError> {
+        match self {
+            FilterExpression::Value(expression) => expression.validate(),
+            FilterExpression::Compare { left, right, .. } | FilterExpression::And { left, right } | FilterExpression::Or { left, right } => {
+                left.validate()?;
+                right.validate()
+            }
+        }
     }
 
-    fn evaluate_internal(&self, input: &ExpressionValue) -> Option<FilterExpressionResult> {
+    fn evaluate_internal(&self, input: &ExpressionValue, time: Option<&DatapointTime>) -> Option<FilterExpressionResult> {
         match self {
             FilterExpression::Value(expression) => {
-                Some(FilterExpressionResult::Float(expression.evaluate(input)?))
+                Some(FilterExpressionResult::Float(expression.evaluate_at(input, time)?))
             }
             FilterExpression::Compare { operation, left, right } => {
-                let left = left.evaluate_internal(input)?.float()?;
-                let right = right.evaluate_internal(input)?.float()?;
+                let left = left.evaluate_internal(input, time)?.float()?;
+                let right = right.evaluate_internal(input, time)?.float()?;
 
                 match operation {
                     CompareOperation::Equal => Some(FilterExpressionResult::Bool(left == right)),
@@ -103,10 +259,10 @@ impl FilterExpression {
                 }
             }
             FilterExpression::And { left, right } => {
-                Some(FilterExpressionResult::Bool(left.evaluate_internal(input)?.bool()? && right.evaluate_internal(input)?.bool()?))
+                Some(FilterExpressionResult::Bool(left.evaluate_internal(input, time)?.bool()? && right.evaluate_internal(input, time)?.bool()?))
             }
             FilterExpression::Or { left, right } => {
-                Some(FilterExpressionResult::Bool(left.evaluate_internal(input)?.bool()? || right.evaluate_internal(input)?.bool()?))
+                Some(FilterExpressionResult::Bool(left.evaluate_internal(input, time)?.bool()? || right.evaluate_internal(input, time)?.bool()?))
             }
         }
     }
@@ -134,6 +290,7 @@ impl FilterExpressionResult {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum ArithmeticOperation {
     Add,
     Subtract,
@@ -153,18 +310,25 @@ impl ArithmeticOperation {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum Function {
     Abs,
     Max,
     Min,
+    /// Rounds to the given number of digits (second argument) or to an integer.
     Round,
     Ceil,
     Floor,
+    /// Limits the value to the range given by the second and third argument.
+    Clamp,
     Sqrt,
     Square,
     Power,
+    #[serde(alias = "Exp")]
     Exponential,
+    #[serde(alias = "Log")]
     LogE,
+    Log10,
     LogBase,
     Sin,
     Cos,
@@ -172,19 +336,42 @@ pub enum Function {
 }
 
 impl Function {
+    pub fn arity(&self) -> RangeInclusive<usize> {
+        match self {
+            Function::Max | Function::Min | Function::Power | Function::LogBase => 2..=2,
+            Function::Round => 1..=2,
+            Function::Clamp => 3..=3,
+            _ => 1..=1
+        }
+    }
+
+    pub fn validate_arguments(&self, num_arguments: usize) -> Result<(), QueryError> {
+        if self.arity().contains(&num_arguments) {
+            Ok(())
+        } else {
+            Err(QueryError::InvalidNumberOfArguments(self.clone(), num_arguments))
+        }
+    }
+
     pub fn apply(&self, arguments: &[f64]) -> Option<f64> {
         match self {
             Function::Abs if arguments.len() == 1 => Some(arguments[0].abs()),
             Function::Max if arguments.len() == 2 => Some(arguments[0].max(arguments[1])),
             Function::Min if arguments.len() == 2 => Some(arguments[0].min(arguments[1])),
             Function::Round if arguments.len() == 1 => Some(arguments[0].round()),
+            Function::Round if arguments.len() == 2 => {
+                let scale = 10.0f64.powi(arguments[1].round() as i32);
+                Some((arguments[0] * scale).round() / scale)
+            }
             Function::Ceil if arguments.len() == 1 => Some(arguments[0].ceil()),
             Function::Floor if arguments.len() == 1 => Some(arguments[0].floor()),
+            Function::Clamp if arguments.len() == 3 && arguments[1] <= arguments[2] => Some(arguments[0].clamp(arguments[1], arguments[2])),
             Function::Sqrt if arguments.len() == 1 && arguments[0] >= 0.0 => Some(arguments[0].sqrt()),
             Function::Square if arguments.len() == 1 => Some(arguments[0] * arguments[0]),
             Function::Power if arguments.len() == 2 => Some(arguments[0].powf(arguments[1])),
             Function::Exponential if arguments.len() == 1 => Some(arguments[0].exp()),
             Function::LogE if arguments.len() == 1 && arguments[0] > 0.0 => Some(arguments[0].ln()),
+            Function::Log10 if arguments.len() == 1 && arguments[0] > 0.0 => Some(arguments[0].log10()),
             Function::LogBase if arguments.len() == 2 && arguments[0] > 0.0 && arguments[1] > 0.0 => Some(arguments[0].log(arguments[1])),
             Function::Sin if arguments.len() == 1 => Some(arguments[0].sin()),
             Function::Cos if arguments.len() == 1 => Some(arguments[0].cos()),
@@ -195,6 +382,7 @@ impl Function {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum CompareOperation {
     Equal,
     NotEqual,
@@ -226,6 +414,138 @@ fn test_transform2() {
     assert_eq!(Some(4.0 + 4.0f64.sqrt()), expression.evaluate(&ExpressionValue::Float(4.0)));
 }
 
+#[test]
+fn test_holt_winters1() {
+    let expression = TransformExpression::HoltWinters {
+        value: Box::new(TransformExpression::InputValue),
+        alpha: 0.5,
+        beta: 0.0,
+        gamma: 0.0,
+        season_length: 0
+    };
+    assert_eq!(Some(4.0), expression.evaluate(&ExpressionValue::Float(4.0)));
+
+    let mut values = vec![(0.0, Some(1.0)), (1.0, None), (2.0, Some(3.0)), (3.0, Some(3.0))];
+    expression.apply_in_windows(&mut values);
+    assert_eq!(vec![(0.0, Some(1.0)), (1.0, None), (2.0, Some(2.0)), (3.0, Some(2.5))], values);
+
+    // A repeating season is followed more closely than by only smoothing the level
+    let seasonal_values = |season_length: usize| {
+        let expression = TransformExpression::HoltWinters {
+            value: Box::new(TransformExpression::InputValue),
+            alpha: 0.2,
+            beta: 0.0,
+            gamma: 0.5,
+            season_length
+        };
+
+        let mut values = (0..40).map(|index| (index as f64, Some(if index % 4 == 0 { 10.0 } else { 0.0 }))).collect::<Vec<_>>();
+        expression.apply_in_windows(&mut values);
+        values[36].1.unwrap()
+    };
+    assert!(seasonal_values(4) > 8.0);
+    assert!(seasonal_values(0) < 5.0);
+}
+
+#[test]
+fn test_transform_conditional1() {
+    let expression = TransformExpression::Conditional {
+        condition: Box::new(FilterExpression::Compare {
+            operation: CompareOperation::GreaterThan,
+            left: Box::new(FilterExpression::input_value()),
+            right: Box::new(FilterExpression::value(100.0))
+        }),
+        then: None,
+        otherwise: Some(Box::new(TransformExpression::Arithmetic {
+            operation: ArithmeticOperation::Multiply,
+            left: Box::new(TransformExpression::InputValue),
+            right: Box::new(TransformExpression::Value(2.0))
+        }))
+    };
+
+    assert_eq!(Some(8.0), expression.evaluate(&ExpressionValue::Float(4.0)));
+    assert_eq!(None, expression.evaluate(&ExpressionValue::Float(400.0)));
+}
+
+#[test]
+fn test_transform_time1() {
+    // Keeps the datapoints between 08:00 and 17:00 (UTC)
+    let hour = TransformExpression::Arithmetic {
+        operation: ArithmeticOperation::Multiply,
+        left: Box::new(TransformExpression::Arithmetic {
+            operation: ArithmeticOperation::Subtract,
+            left: Box::new(TransformExpression::Arithmetic {
+                operation: ArithmeticOperation::Divide,
+                left: Box::new(TransformExpression::InputTime),
+                right: Box::new(TransformExpression::Value(86400.0))
+            }),
+            right: Box::new(TransformExpression::Function {
+                function: Function::Floor,
+                arguments: vec![TransformExpression::Arithmetic {
+                    operation: ArithmeticOperation::Divide,
+                    left: Box::new(TransformExpression::InputTime),
+                    right: Box::new(TransformExpression::Value(86400.0))
+                }]
+            })
+        }),
+        right: Box::new(TransformExpression::Value(24.0))
+    };
+
+    let expression = FilterExpression::And {
+        left: Box::new(FilterExpression::Compare {
+            operation: CompareOperation::GreaterThanOrEqual,
+            left: Box::new(FilterExpression::Value(hour.clone())),
+            right: Box::new(FilterExpression::value(8.0))
+        }),
+        right: Box::new(FilterExpression::Compare {
+            operation: CompareOperation::LessThanOrEqual,
+            left: Box::new(FilterExpression::Value(hour)),
+            right: Box::new(FilterExpression::value(17.0))
+        })
+    };
+
+    // 2022-06-01 10:00 and 20:00
+    let time = |time: f64| DatapointTime::new((time * TIME_SCALE as f64) as Time, 1654034400.0);
+    assert_eq!(Some(true), expression.evaluate_at(&ExpressionValue::Float(1.0), Some(&time(1654077600.0))));
+    assert_eq!(Some(false), expression.evaluate_at(&ExpressionValue::Float(1.0), Some(&time(1654113600.0))));
+    assert_eq!(None, expression.evaluate(&ExpressionValue::Float(1.0)));
+
+    assert_eq!(
+        Some(1654034400.0),
+        TransformExpression::InputWindowStart.evaluate_at(&ExpressionValue::Float(1.0), Some(&time(1654077600.0)))
+    );
+}
+
+#[test]
+fn test_functions1() {
+    assert_eq!(Some(3.0), Function::Abs.apply(&[-3.0]));
+    assert_eq!(Some(2.0), Function::Log10.apply(&[100.0]));
+    assert_eq!(None, Function::Log10.apply(&[0.0]));
+    assert_eq!(Some(2.35), Function::Round.apply(&[2.34567, 2.0]));
+    assert_eq!(Some(2.0), Function::Round.apply(&[2.34567]));
+    assert_eq!(Some(1.0), Function::Clamp.apply(&[1.5, 0.0, 1.0]));
+    assert_eq!(Some(0.5), Function::Clamp.apply(&[0.5, 0.0, 1.0]));
+    assert_eq!(None, Function::Clamp.apply(&[0.5, 1.0, 0.0]));
+
+    assert_eq!(Function::Exponential, serde_json::from_str::<Function>("\"Exp\"").unwrap());
+    assert_eq!(Function::LogE, serde_json::from_str::<Function>("\"Log\"").unwrap());
+}
+
+#[test]
+fn test_validate1() {
+    let expression = TransformExpression::Function {
+        function: Function::Clamp,
+        arguments: vec![TransformExpression::InputValue, TransformExpression::Value(0.0)]
+    };
+    assert_eq!(Err(QueryError::InvalidNumberOfArguments(Function::Clamp, 2)), expression.validate());
+
+    let expression = FilterExpression::Compare {
+        operation: CompareOperation::GreaterThan,
+        left: Box::new(FilterExpression::Value(TransformExpression::Function { function: Function::Round, arguments: vec![TransformExpression::InputValue] })),
+        right: Box::new(FilterExpression::value(0.5))
+    };
+    assert_eq!(Ok(()), expression.validate());
+}
 
 #[test]
 fn test_filter1() {
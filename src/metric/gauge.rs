@@ -1,13 +1,15 @@
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
+use crate::engine::lock_watchdog::LockWatchdog;
 use crate::metric::common::{GenericMetric, MetricType, PrimaryTagsStorage, MetricConfig};
 use crate::metric::helpers::{MetricWindowing, TimeRangeStatistics};
-use crate::metric::operations::{StreamingApproxPercentileTDigest, StreamingAverage, StreamingMax, StreamingMin, StreamingOperation, StreamingSum, StreamingTransformOperation, StreamingFilterOperation};
+use crate::metric::operations::{AverageWeighting, PercentileAlgorithm, StreamingApproxPercentile, StreamingApproxPercentileTDigest, StreamingAverage, StreamingMax, StreamingMin, StreamingOperation, StreamingPrimaryTagsAverage, StreamingSum, StreamingTimeWeightedAverage, StreamingTransformOperation, StreamingFilterOperation};
 use crate::metric::{helpers, OperationResult};
-use crate::metric::expression::ExpressionValue;
+use crate::metric::expression::{DatapointTime, ExpressionValue};
 use crate::metric::tags::{PrimaryTag, Tag, TagsFilter};
-use crate::model::{MetricResult, Query, Time, TIME_SCALE};
+use crate::model::{GroupKey, MetricResult, Query, Time, TIME_SCALE};
 use crate::storage::file::FileMetricStorage;
 use crate::storage::MetricStorage;
 
@@ -101,6 +103,14 @@ impl<TStorage: MetricStorage<f32>> GaugeMetric<TStorage> {
         )
     }
 
+    pub fn from_existing_read_only(base_path: &Path) -> MetricResult<GaugeMetric<TStorage>> {
+        Ok(
+            GaugeMetric {
+                primary_tags_storage: PrimaryTagsStorage::from_existing_read_only(base_path)?
+            }
+        )
+    }
+
     pub fn primary_tags(&self) -> impl Iterator<Item=&PrimaryTag> {
         self.primary_tags_storage.primary_tags()
     }
@@ -114,7 +124,9 @@ impl<TStorage: MetricStorage<f32>> GaugeMetric<TStorage> {
                                                                                             create_op: F,
                                                                                             require_statistics: bool) -> OperationResult {
         let (start_time, end_time) = query.time_range.int_range();
-        assert!(end_time > start_time);
+        if end_time <= start_time {
+            return OperationResult::empty(&query, false);
+        }
 
         let apply = |tags_filter: &TagsFilter| {
             let mut streaming_operations = Vec::new();
@@ -143,8 +155,8 @@ impl<TStorage: MetricStorage<f32>> GaugeMetric<TStorage> {
                         tags_filter,
                         start_block_index,
                         false,
-                        |_, _, datapoint| {
-                            streaming_operation.add(datapoint.value as f64);
+                        |_, datapoint_time, datapoint| {
+                            streaming_operation.add_at(datapoint.value as f64, &DatapointTime::new(datapoint_time, query.time_range.start));
                         }
                     );
 
@@ -170,6 +182,76 @@ impl<TStorage: MetricStorage<f32>> GaugeMetric<TStorage> {
         }
     }
 
+    fn percentile_with_digests(&self, query: Query, percentile: i32) -> OperationResult {
+        let (start_time, end_time) = query.time_range.int_range();
+        if end_time <= start_time {
+            return OperationResult::empty(&query, false);
+        }
+
+        let apply = |tags_filter: &TagsFilter| {
+            let mut streaming_operations = Vec::new();
+            for (primary_tag, tags_filter) in self.primary_tags_storage.iter_for_query(tags_filter) {
+                let storage = primary_tag.storage(None);
+                if let Some(start_block_index) = helpers::find_block_index(storage, start_time) {
+                    let mut streaming_operation = StreamingApproxPercentileTDigest::with_config(percentile, self.primary_tags_storage.config().digest);
+                    let mut digests = Vec::new();
+
+                    for block_index in start_block_index..storage.len() {
+                        let (block_start_time, block_end_time) = storage.block_time_range(block_index).unwrap();
+                        if block_start_time > end_time {
+                            break;
+                        }
+
+                        let is_sealed = block_index + 1 < storage.len();
+                        let block_digests = if is_sealed && block_start_time >= start_time && block_end_time <= end_time {
+                            primary_tag.block_digests().get(block_start_time)
+                        } else {
+                            None
+                        };
+
+                        if let Some(block_digests) = block_digests {
+                            for (tags, digest) in block_digests {
+                                if tags_filter.accept(*tags) {
+                                    digests.push(digest.clone());
+                                }
+                            }
+                        } else {
+                            helpers::visit_datapoints_in_block(
+                                storage,
+                                block_index,
+                                start_time,
+                                end_time,
+                                tags_filter,
+                                |_, _, datapoint| {
+                                    streaming_operation.add(datapoint.value as f64);
+                                }
+                            );
+                        }
+                    }
+
+                    streaming_operation.merge_digests(digests);
+                    streaming_operations.push(streaming_operation);
+                }
+            }
+
+            if streaming_operations.is_empty() {
+                return None;
+            }
+
+            let streaming_operation = helpers::merge_operations(streaming_operations);
+            query.apply_output_transform(ExpressionValue::Float(streaming_operation.value()?))
+        };
+
+        match &query.group_by {
+            None => {
+                OperationResult::Value(apply(&query.tags_filter))
+            }
+            Some(key) => {
+                OperationResult::GroupValues(self.primary_tags_storage.apply_group_by(&query, key, apply))
+            }
+        }
+    }
+
     fn simple_operation_in_window<T: StreamingOperation<f64> + Default>(&self, query: Query, duration: Duration) -> OperationResult {
         apply_operation_in_window!(self, T, query, duration, |_| T::default(), false)
     }
@@ -180,77 +262,88 @@ impl<TStorage: MetricStorage<f32>> GaugeMetric<TStorage> {
                                                                                                       create_op: F,
                                                                                                       require_statistics: bool) -> OperationResult {
         let (start_time, end_time) = query.time_range.int_range();
-        assert!(end_time > start_time);
-
         let duration = (duration.as_secs_f64() * TIME_SCALE as f64) as Time;
+        if end_time <= start_time || duration == 0 {
+            return OperationResult::empty(&query, true);
+        }
 
         let apply = |tags_filter: &TagsFilter| {
             let mut primary_tags_windowing = Vec::new();
             for (primary_tag, tags_filter) in self.primary_tags_storage.iter_for_query(tags_filter) {
-                let storage = primary_tag.storage(Some((start_time, end_time, duration)));
-                if let Some(start_block_index) = helpers::find_block_index(storage, start_time) {
-                    let mut windowing = MetricWindowing::new(start_time, end_time, duration);
-
-                    let window_stats = if require_statistics {
-                        let mut window_stats = windowing.create_windows(|| None);
-
-                        helpers::visit_datapoints_in_time_range(
-                            storage,
-                            start_time,
-                            end_time,
-                            tags_filter,
-                            start_block_index,
-                            false,
-                            |_, datapoint_time, datapoint| {
-                                let window_index = windowing.get_window_index(datapoint_time);
-                                if window_index < windowing.len() {
-                                    window_stats[window_index]
-                                        .get_or_insert_with(|| TimeRangeStatistics::default())
-                                        .handle(datapoint.value as f64);
-                                }
-                            }
-                        );
+                let storage_ranges = primary_tag.storages_for_window(start_time, end_time, duration)
+                    .into_iter()
+                    .flat_map(|(storage, range_start_time, range_end_time)| {
+                        helpers::find_block_index(storage, range_start_time)
+                            .map(|start_block_index| (storage, range_start_time, range_end_time, start_block_index))
+                    })
+                    .collect::<Vec<_>>();
+
+                if storage_ranges.is_empty() {
+                    continue;
+                }
 
-                        Some(window_stats)
-                    } else {
-                        None
-                    };
+                let mut windowing = MetricWindowing::new(start_time, end_time, duration);
 
+                // Operations requiring statistics are created once the whole window has been seen, which buffers the values
+                // of the window but only walks the datapoints once
+                let mut window_stats: Vec<Option<TimeRangeStatistics<f64>>> = Vec::new();
+                let mut window_values: Vec<Vec<(Time, f64)>> = Vec::new();
+                if require_statistics {
+                    window_stats = windowing.create_windows(|| None);
+                    window_values = windowing.create_windows(Vec::new);
+                }
+
+                for &(storage, range_start_time, range_end_time, start_block_index) in &storage_ranges {
                     helpers::visit_datapoints_in_time_range(
                         storage,
-                        start_time,
-                        end_time,
+                        range_start_time,
+                        range_end_time,
                         tags_filter,
                         start_block_index,
                         false,
                         |_, datapoint_time, datapoint| {
                             let window_index = windowing.get_window_index(datapoint_time);
                             if window_index < windowing.len() {
-                                windowing.get(window_index)
-                                    .get_or_insert_with(|| {
-                                        if require_statistics {
-                                            create_op((&window_stats.as_ref().unwrap()[window_index]).as_ref())
-                                        } else {
-                                            create_op(None)
-                                        }
-                                    })
-                                    .add(datapoint.value as f64);
+                                let value = datapoint.value as f64;
+                                if require_statistics {
+                                    window_stats[window_index]
+                                        .get_or_insert_with(TimeRangeStatistics::default)
+                                        .handle(value);
+                                    window_values[window_index].push((datapoint_time, value));
+                                } else {
+                                    let time = DatapointTime::new(datapoint_time, windowing.get_timestamp(window_index));
+                                    windowing.get(window_index)
+                                        .get_or_insert_with(|| create_op(None))
+                                        .add_at(value, &time);
+                                }
                             }
                         }
                     );
+                }
 
-                    primary_tags_windowing.push(windowing);
+                for (window_index, (stats, values)) in window_stats.into_iter().zip(window_values).enumerate() {
+                    if let Some(stats) = stats {
+                        let window_start = windowing.get_timestamp(window_index);
+                        let operation = windowing.get(window_index).insert(create_op(Some(&stats)));
+                        for (time, value) in values {
+                            operation.add_at(value, &DatapointTime::new(time, window_start));
+                        }
+                    }
                 }
+
+                primary_tags_windowing.push(windowing);
             }
 
             if primary_tags_windowing.is_empty() {
                 return Vec::new();
             }
 
-            helpers::extract_operations_in_windows(
-                helpers::merge_windowing(primary_tags_windowing),
-                |value| query.apply_output_transform(ExpressionValue::Float(value?)),
-                query.remove_empty_datapoints
+            query.apply_output_transform_in_windows(
+                helpers::extract_operations_in_windows(
+                    helpers::merge_windowing(primary_tags_windowing),
+                    |value| query.apply_output_transform(ExpressionValue::Float(value?)),
+                    query.remove_empty_datapoints
+                )
             )
         };
 
@@ -263,6 +356,116 @@ impl<TStorage: MetricStorage<f32>> GaugeMetric<TStorage> {
             }
         }
     }
+
+    /// The values of all secondary tags within a primary tag are treated as a single series.
+    fn time_weighted_average(&self, query: Query, duration: Option<Duration>) -> OperationResult {
+        let (start_time, end_time) = query.time_range.int_range();
+        if end_time <= start_time {
+            return OperationResult::empty(&query, duration.is_some());
+        }
+
+        let window_duration = match duration {
+            Some(duration) => (duration.as_secs_f64() * TIME_SCALE as f64) as Time,
+            None => end_time - start_time
+        };
+        if window_duration == 0 {
+            return OperationResult::empty(&query, duration.is_some());
+        }
+
+        let to_seconds = |time: Time| time as f64 / TIME_SCALE as f64;
+
+        let apply = |tags_filter: &TagsFilter| {
+            let mut primary_tags_windowing = Vec::new();
+            for (primary_tag, tags_filter) in self.primary_tags_storage.iter_for_query(tags_filter) {
+                let mut windowing = MetricWindowing::new(start_time, end_time, window_duration);
+                let mut any_storage = false;
+
+                for (storage, range_start_time, range_end_time) in primary_tag.storages_for_window(start_time, end_time, window_duration) {
+                    let start_block_index = match helpers::find_block_index(storage, range_start_time) {
+                        Some(start_block_index) => start_block_index,
+                        None => continue
+                    };
+                    any_storage = true;
+
+                    helpers::visit_datapoints_in_time_range(
+                        storage,
+                        range_start_time,
+                        range_end_time,
+                        tags_filter,
+                        start_block_index,
+                        true,
+                        |_, datapoint_time, datapoint| {
+                            let window_index = windowing.get_window_index(datapoint_time);
+                            if window_index >= windowing.len() {
+                                return;
+                            }
+
+                            let value = datapoint.value as f64;
+                            let time = DatapointTime::new(datapoint_time, windowing.get_timestamp(window_index));
+                            if let Some(filter) = &query.input_filter {
+                                if !filter.evaluate_at(&ExpressionValue::Float(value), Some(&time)).unwrap_or(false) {
+                                    return;
+                                }
+                            }
+
+                            let value = match &query.input_transform {
+                                Some(transform) => match transform.evaluate_at(&ExpressionValue::Float(value), Some(&time)) {
+                                    Some(value) => value,
+                                    None => return
+                                },
+                                None => value
+                            };
+
+                            windowing.get(window_index)
+                                .get_or_insert_with(|| StreamingTimeWeightedAverage::new(to_seconds(start_time), to_seconds(window_duration)))
+                                .add((to_seconds(datapoint_time), value));
+                        }
+                    );
+                }
+
+                if any_storage {
+                    primary_tags_windowing.push(windowing);
+                }
+            }
+
+            if primary_tags_windowing.is_empty() {
+                None
+            } else {
+                Some(helpers::merge_windowing(primary_tags_windowing))
+            }
+        };
+
+        let transform_output = |value: Option<f64>| query.apply_output_transform(ExpressionValue::Float(value?));
+        let apply_value = |tags_filter: &TagsFilter| {
+            let operation = apply(tags_filter)?.into_windows().into_iter().next()??;
+            transform_output(operation.value())
+        };
+        let apply_windows = |tags_filter: &TagsFilter| {
+            match apply(tags_filter) {
+                Some(windowing) => {
+                    query.apply_output_transform_in_windows(
+                        helpers::extract_operations_in_windows(windowing, transform_output, query.remove_empty_datapoints)
+                    )
+                }
+                None => Vec::new()
+            }
+        };
+
+        match (&query.group_by, duration.is_some()) {
+            (None, false) => {
+                OperationResult::Value(apply_value(&query.tags_filter))
+            }
+            (None, true) => {
+                OperationResult::TimeValues(apply_windows(&query.tags_filter))
+            }
+            (Some(key), false) => {
+                OperationResult::GroupValues(self.primary_tags_storage.apply_group_by(&query, key, apply_value))
+            }
+            (Some(key), true) => {
+                OperationResult::GroupTimeValues(self.primary_tags_storage.apply_group_by(&query, key, apply_windows))
+            }
+        }
+    }
 }
 
 impl<TStorage: MetricStorage<f32>> GenericMetric for GaugeMetric<TStorage> {
@@ -278,25 +481,66 @@ impl<TStorage: MetricStorage<f32>> GenericMetric for GaugeMetric<TStorage> {
         self.primary_tags_storage.add_auto_primary_tag(key)
     }
 
+    fn remove_primary_tag(&mut self, tag: &Tag) -> MetricResult<bool> {
+        self.primary_tags_storage.remove_primary_tag(tag)
+    }
+
+    fn remove_auto_primary_tag(&mut self, key: &str) -> MetricResult<bool> {
+        self.primary_tags_storage.remove_auto_primary_tag(key)
+    }
+
+    fn auto_primary_tags(&self) -> Vec<String> {
+        self.primary_tags_storage.auto_primary_tags()
+    }
+
+    fn requires_new_primary_tags(&self, tags: &[Tag]) -> bool {
+        self.primary_tags_storage.requires_new_primary_tags(tags)
+    }
+
+    fn create_auto_primary_tags(&mut self, tags: &[Tag]) -> MetricResult<()> {
+        self.primary_tags_storage.create_auto_primary_tags(tags)
+    }
+
+    fn num_groups(&self, query: &Query, key: &GroupKey) -> usize {
+        self.primary_tags_storage.num_groups(query, key)
+    }
+
+    fn group_values(&self, query: &Query, key: &GroupKey) -> Vec<Vec<Tag>> {
+        self.primary_tags_storage.gather_group_values(query, key)
+    }
+
     type Input = f64;
-    fn add(&mut self, time: f64, value: f64, mut tags: Vec<Tag>) -> MetricResult<()> {
-        let (primary_tag_key, mut primary_tag, secondary_tags) = self.primary_tags_storage.insert_tags(&mut tags)?;
+    fn add_concurrent(&self, time: f64, value: f64, mut tags: Vec<Tag>) -> MetricResult<()> {
+        let gauge_collision = self.primary_tags_storage.config().gauge_collision;
+        let (mut primary_tag, secondary_tags) = self.primary_tags_storage.insert_tags(&mut tags)?;
 
-        let result = primary_tag.add(
+        primary_tag.add(
             time,
             value as f32,
             secondary_tags,
-            |last_datapoint, value| {
-                last_datapoint.value = value;
+            |last_datapoint, value, num_values| {
+                last_datapoint.value = gauge_collision.combine(last_datapoint.value, value, num_values);
             }
-        );
+        )
+    }
 
-        self.primary_tags_storage.return_tags(primary_tag_key, primary_tag);
-        result
+    fn add_batch(&self, values: &[(f64, f64, Vec<Tag>)]) -> MetricResult<usize> {
+        let gauge_collision = self.primary_tags_storage.config().gauge_collision;
+        self.primary_tags_storage.add_batch(
+            values,
+            |value: f64| Ok(value as f32),
+            |last_datapoint, value, num_values| {
+                last_datapoint.value = gauge_collision.combine(last_datapoint.value, value, num_values);
+            }
+        )
     }
 
     fn average(&self, query: Query) -> OperationResult {
-        self.simple_operation::<StreamingAverage<f64>>(query)
+        match query.average_weighting {
+            AverageWeighting::Samples => self.simple_operation::<StreamingAverage<f64>>(query),
+            AverageWeighting::PrimaryTags => self.simple_operation::<StreamingPrimaryTagsAverage>(query),
+            AverageWeighting::Time => self.time_weighted_average(query, None)
+        }
     }
 
     fn sum(&self, query: Query) -> OperationResult {
@@ -312,15 +556,32 @@ impl<TStorage: MetricStorage<f32>> GenericMetric for GaugeMetric<TStorage> {
     }
 
     fn percentile(&self, query: Query, percentile: i32) -> OperationResult {
-        let create = |_: Option<&TimeRangeStatistics<f32>>| {
-            StreamingApproxPercentileTDigest::new(percentile)
+        let config = self.primary_tags_storage.config();
+        let algorithm = query.percentile_algorithm.unwrap_or(config.percentile_algorithm);
+
+        // The block digests are built using the config of the metric
+        if algorithm == PercentileAlgorithm::TDigest && query.input_filter.is_none() && query.input_transform.is_none() && query.digest.is_none() {
+            return self.percentile_with_digests(query, percentile);
+        }
+
+        let digest_config = query.digest.unwrap_or(config.digest);
+        let create = |stats: Option<&TimeRangeStatistics<f32>>| {
+            StreamingApproxPercentile::new(algorithm, percentile, digest_config, stats.map(|stats| stats.to_f64()).as_ref())
         };
 
-        apply_operation!(self, StreamingApproxPercentileTDigest, query, create, false)
+        apply_operation!(self, StreamingApproxPercentile, query, create, algorithm == PercentileAlgorithm::Histogram)
+    }
+
+    fn last(&self, query: Query) -> OperationResult {
+        self.primary_tags_storage.last(&query, |value| ExpressionValue::Float(value as f64))
     }
 
     fn average_in_window(&self, query: Query, duration: Duration) -> OperationResult {
-        self.simple_operation_in_window::<StreamingAverage<f64>>(query, duration)
+        match query.average_weighting {
+            AverageWeighting::Samples => self.simple_operation_in_window::<StreamingAverage<f64>>(query, duration),
+            AverageWeighting::PrimaryTags => self.simple_operation_in_window::<StreamingPrimaryTagsAverage>(query, duration),
+            AverageWeighting::Time => self.time_weighted_average(query, Some(duration))
+        }
     }
 
     fn sum_in_window(&self, query: Query, duration: Duration) -> OperationResult {
@@ -336,14 +597,60 @@ impl<TStorage: MetricStorage<f32>> GenericMetric for GaugeMetric<TStorage> {
     }
 
     fn percentile_in_window(&self, query: Query, duration: Duration, percentile: i32) -> OperationResult {
-        let create = |_: Option<&TimeRangeStatistics<f64>>| {
-            StreamingApproxPercentileTDigest::new(percentile)
+        let config = self.primary_tags_storage.config();
+        let algorithm = query.percentile_algorithm.unwrap_or(config.percentile_algorithm);
+        let digest_config = query.digest.unwrap_or(config.digest);
+        let create = |stats: Option<&TimeRangeStatistics<f64>>| {
+            StreamingApproxPercentile::new(algorithm, percentile, digest_config, stats)
         };
 
-        apply_operation_in_window!(self, StreamingApproxPercentileTDigest, query, duration, create, false)
+        apply_operation_in_window!(self, StreamingApproxPercentile, query, duration, create, algorithm == PercentileAlgorithm::Histogram)
     }
 
-    fn scheduled(&mut self) {
+    fn scheduled(&self) {
         self.primary_tags_storage.scheduled();
+
+        if let Err(err) = self.primary_tags_storage.update_block_digests() {
+            tracing::warn!(error = %err, "failed to update block digests");
+        }
+    }
+    fn config(&self) -> &MetricConfig {
+        self.primary_tags_storage.config()
+    }
+
+    fn start_time(&self) -> Option<Time> {
+        self.primary_tags_storage.start_time()
+    }
+
+    fn latest_time(&self) -> Option<f64> {
+        self.primary_tags_storage.latest_time()
+    }
+
+    fn sealed_time(&self) -> Option<Time> {
+        self.primary_tags_storage.sealed_time()
+    }
+
+    fn num_primary_tags_with_data(&self) -> usize {
+        self.primary_tags_storage.num_primary_tags_with_data()
+    }
+
+    fn flush(&self) -> MetricResult<()> {
+        self.primary_tags_storage.flush()
+    }
+
+    fn move_to_cold_tier(&self, hot_root: &Path, cold_root: &Path, older_than: Time) -> MetricResult<usize> {
+        self.primary_tags_storage.move_to_cold_tier(hot_root, cold_root, older_than)
+    }
+
+    fn remove_expired_segments(&self, now: Time) -> MetricResult<usize> {
+        self.primary_tags_storage.remove_expired_segments(now)
+    }
+
+    fn verify(&self) -> Vec<String> {
+        self.primary_tags_storage.verify()
+    }
+
+    fn set_lock_watchdog(&mut self, metric: &str, lock_watchdog: Arc<LockWatchdog>) {
+        self.primary_tags_storage.set_lock_watchdog(metric, lock_watchdog);
     }
 }
\ No newline at end of file
@@ -1,9 +1,24 @@
+use std::cell::Cell;
+
 use crate::model::{Datapoint, Tags, Time, TIME_SCALE};
 use crate::metric::operations::StreamingOperation;
 use crate::metric::tags::SecondaryTagsFilter;
 use crate::storage::MetricStorage;
 use crate::traits::MinMax;
 
+thread_local! {
+    static BLOCKS_SCANNED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The total number of blocks scanned by queries on the current thread, the cost of a query is the difference before and after it.
+pub fn blocks_scanned() -> u64 {
+    BLOCKS_SCANNED.with(|blocks_scanned| blocks_scanned.get())
+}
+
+fn add_blocks_scanned(count: u64) {
+    BLOCKS_SCANNED.with(|blocks_scanned| blocks_scanned.set(blocks_scanned.get() + count));
+}
+
 pub fn find_block_index<TStorage: MetricStorage<E>, E: Copy>(storage: &TStorage, time: Time) -> Option<usize> {
     if storage.len() == 0 {
         return None;
@@ -37,9 +52,15 @@ pub fn visit_datapoints_in_time_range<TStorage: MetricStorage<E>, F: FnMut(&Tags
                                                                                                                  start_block_index: usize,
                                                                                                                  strict_ordering: bool,
                                                                                                                  mut apply: F) {
+    if let Some(end_block_index) = find_block_index(storage, end_time) {
+        storage.advise_scan(start_block_index, end_block_index);
+    }
+
+    let mut blocks_scanned = 0;
     for block_index in start_block_index..storage.len() {
         let (block_start_time, block_end_time) = storage.block_time_range(block_index).unwrap();
         if block_end_time >= start_time {
+            blocks_scanned += 1;
             let mut outside_time_range = false;
 
             if let Some(iterator) = storage.block_datapoints(block_index) {
@@ -102,6 +123,35 @@ pub fn visit_datapoints_in_time_range<TStorage: MetricStorage<E>, F: FnMut(&Tags
             }
         }
     }
+
+    tracing::trace!(blocks_scanned, "scanned blocks");
+    add_blocks_scanned(blocks_scanned);
+}
+
+pub fn visit_datapoints_in_block<TStorage: MetricStorage<E>, F: FnMut(&Tags, Time, &Datapoint<E>), E: Copy>(storage: &TStorage,
+                                                                                                           block_index: usize,
+                                                                                                           start_time: Time,
+                                                                                                           end_time: Time,
+                                                                                                           tags_filter: SecondaryTagsFilter,
+                                                                                                           mut apply: F) {
+    let (block_start_time, _) = storage.block_time_range(block_index).unwrap();
+    add_blocks_scanned(1);
+    if let Some(iterator) = storage.block_datapoints(block_index) {
+        for (tags, datapoints) in iterator {
+            if tags_filter.accept(tags) {
+                let iterator = DatapointIterator::new(
+                    start_time,
+                    end_time,
+                    block_start_time,
+                    datapoints.iter()
+                );
+
+                for datapoint in iterator {
+                    apply(&tags, block_start_time + datapoint.time_offset as Time, datapoint);
+                }
+            }
+        }
+    }
 }
 
 pub fn determine_statistics_for_time_range<TStorage: MetricStorage<E>, E: Copy + MinMax>(storage: &TStorage,
@@ -200,6 +250,16 @@ impl<T: MinMax + Copy> TimeRangeStatistics<T> {
     }
 }
 
+impl<T: MinMax + Copy + Into<f64>> TimeRangeStatistics<T> {
+    pub fn to_f64(&self) -> TimeRangeStatistics<f64> {
+        TimeRangeStatistics {
+            count: self.count,
+            min: self.min.map(|value| value.into()),
+            max: self.max.map(|value| value.into())
+        }
+    }
+}
+
 impl<T> Default for TimeRangeStatistics<T> {
     fn default() -> Self {
         TimeRangeStatistics {
@@ -7,16 +7,41 @@ pub mod tags;
 mod helpers;
 pub mod operations;
 pub mod expression;
+pub mod digests;
+pub mod downsampling;
 
+#[cfg(feature = "server")]
+use std::borrow::Cow;
 use std::fmt::{Display};
+
+use serde::{Deserialize, Serialize, Serializer};
+#[cfg(feature = "server")]
+use utoipa::{PartialSchema, ToSchema};
+#[cfg(feature = "server")]
+use utoipa::openapi::{ArrayBuilder, ObjectBuilder, OneOfBuilder, RefOr, Schema, Type};
+#[cfg(feature = "server")]
+use utoipa::openapi::schema::SchemaType;
 use serde_json::json;
 
-use crate::model::GroupValue;
+use crate::model::{GroupLimit, GroupValue, Query};
+
+pub use helpers::blocks_scanned;
 
 pub type TimeValues = Vec<(f64, Option<f64>)>;
 pub type GroupValues = Vec<(GroupValue, Option<f64>)>;
 pub type GroupTimeValues = Vec<(GroupValue, TimeValues)>;
 
+/// Combines the values of two groups, such as into the `__other__` group of a group limit.
+pub type CombineGroups = fn(f64, f64) -> f64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+#[serde(default)]
+pub struct JsonOptions {
+    pub rfc3339_timestamps: bool,
+    pub named_fields: bool
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum OperationResult {
     NotSupported,
@@ -27,6 +52,15 @@ pub enum OperationResult {
 }
 
 impl OperationResult {
+    pub fn empty(query: &Query, windowed: bool) -> OperationResult {
+        match (query.group_by.is_some(), windowed) {
+            (false, false) => OperationResult::Value(None),
+            (true, false) => OperationResult::GroupValues(Vec::new()),
+            (false, true) => OperationResult::TimeValues(Vec::new()),
+            (true, true) => OperationResult::GroupTimeValues(Vec::new())
+        }
+    }
+
     pub fn value(self) -> Option<f64> {
         match self {
             OperationResult::Value(value) => value,
@@ -55,6 +89,24 @@ impl OperationResult {
         }
     }
 
+    pub fn map_values(self, apply: impl Fn(f64) -> f64) -> OperationResult {
+        let map_time_values = |values: TimeValues| {
+            values.into_iter().map(|(time, value)| (time, value.map(&apply))).collect::<Vec<_>>()
+        };
+
+        match self {
+            OperationResult::NotSupported => OperationResult::NotSupported,
+            OperationResult::Value(value) => OperationResult::Value(value.map(&apply)),
+            OperationResult::TimeValues(values) => OperationResult::TimeValues(map_time_values(values)),
+            OperationResult::GroupValues(values) => {
+                OperationResult::GroupValues(values.into_iter().map(|(group, value)| (group, value.map(&apply))).collect())
+            }
+            OperationResult::GroupTimeValues(values) => {
+                OperationResult::GroupTimeValues(values.into_iter().map(|(group, values)| (group, map_time_values(values))).collect())
+            }
+        }
+    }
+
     pub fn error_message(&self) -> Option<String> {
         match self {
             OperationResult::NotSupported => Some("Not supported operation.".to_owned()),
@@ -84,6 +136,19 @@ impl OperationResult {
         }
     }
 
+    /// The time of the latest window that has a value, for windowed results.
+    pub fn latest_window_time(&self) -> Option<f64> {
+        let latest_time = |values: &TimeValues| {
+            values.iter().filter(|(_, value)| value.is_some()).map(|(time, _)| *time).reduce(f64::max)
+        };
+
+        match self {
+            OperationResult::TimeValues(values) => latest_time(values),
+            OperationResult::GroupTimeValues(values) => values.iter().filter_map(|(_, values)| latest_time(values)).reduce(f64::max),
+            _ => None
+        }
+    }
+
     pub fn as_json(&self) -> serde_json::Value {
         match self {
             OperationResult::NotSupported => json!({ "error_message": "not supported operation" }),
@@ -93,6 +158,243 @@ impl OperationResult {
             OperationResult::GroupTimeValues(values) => json!(values)
         }
     }
+
+    pub fn as_json_with(&self, options: &JsonOptions) -> serde_json::Value {
+        json!(self.with_options(options))
+    }
+
+    /// Serializes the same as `as_json_with` without creating the JSON value, such as for the binary formats.
+    pub fn with_options<'a>(&'a self, options: &'a JsonOptions) -> WithOptions<'a> {
+        WithOptions {
+            result: self,
+            options
+        }
+    }
+
+    /// The `__other__` group is only added if the values of the operation can be combined, which is not the case for e.g. averages.
+    pub fn limit_groups(self, group_limit: &GroupLimit, combine_other: Option<CombineGroups>) -> OperationResult {
+        let combine_other = combine_other.filter(|_| group_limit.other);
+        match self {
+            OperationResult::GroupValues(values) => {
+                let (mut kept, rest) = split_groups(values, group_limit.limit, |value| value.unwrap_or(f64::NEG_INFINITY));
+                if let Some(combine_other) = combine_other.filter(|_| !rest.is_empty()) {
+                    let other = rest.into_iter().filter_map(|(_, value)| value).reduce(combine_other);
+                    kept.push((GroupValue::from_ref(OTHER_GROUP), other));
+                }
+
+                OperationResult::GroupValues(kept)
+            }
+            OperationResult::GroupTimeValues(values) => {
+                let (mut kept, rest) = split_groups(
+                    values,
+                    group_limit.limit,
+                    |values| values.iter().filter_map(|(_, value)| *value).sum::<f64>()
+                );
+
+                if let Some(combine_other) = combine_other.filter(|_| !rest.is_empty()) {
+                    let mut other = rest[0].1.iter().map(|(time, _)| (*time, None)).collect::<TimeValues>();
+                    for (_, values) in rest {
+                        for ((_, other_value), (_, value)) in other.iter_mut().zip(values) {
+                            if let Some(value) = value {
+                                *other_value = Some(other_value.map(|other_value| combine_other(other_value, value)).unwrap_or(value));
+                            }
+                        }
+                    }
+
+                    kept.push((GroupValue::from_ref(OTHER_GROUP), other));
+                }
+
+                OperationResult::GroupTimeValues(kept)
+            }
+            result => result
+        }
+    }
+
+    pub fn columns(&self) -> Vec<&'static str> {
+        match self {
+            OperationResult::NotSupported => Vec::new(),
+            OperationResult::Value(_) => vec!["value"],
+            OperationResult::TimeValues(_) => vec!["time", "value"],
+            OperationResult::GroupValues(_) => vec!["group", "value"],
+            OperationResult::GroupTimeValues(_) => vec!["group", "time", "value"]
+        }
+    }
+
+    pub fn rows<'a>(&'a self, options: &'a JsonOptions) -> Box<dyn Iterator<Item=Vec<serde_json::Value>> + Send + 'a> {
+        match self {
+            OperationResult::NotSupported => Box::new(std::iter::empty()),
+            OperationResult::Value(value) => Box::new(std::iter::once(vec![json!(value)])),
+            OperationResult::TimeValues(values) => {
+                Box::new(values.iter().map(|(time, value)| vec![time_json(*time, options), json!(value)]))
+            }
+            OperationResult::GroupValues(values) => {
+                Box::new(values.iter().map(|(group, value)| vec![json!(group), json!(value)]))
+            }
+            OperationResult::GroupTimeValues(values) => {
+                Box::new(
+                    values
+                        .iter()
+                        .flat_map(move |(group, values)| {
+                            values.iter().map(move |(time, value)| vec![json!(group), time_json(*time, options), json!(value)])
+                        })
+                )
+            }
+        }
+    }
+}
+
+pub const OTHER_GROUP: &str = "__other__";
+
+type Groups<T> = Vec<(GroupValue, T)>;
+
+/// Splits into the groups with the largest ranks, kept in their original order, and the rest.
+fn split_groups<T>(groups: Groups<T>, limit: usize, rank: impl Fn(&T) -> f64) -> (Groups<T>, Groups<T>) {
+    if groups.len() <= limit {
+        return (groups, Vec::new());
+    }
+
+    let mut ranked = groups.iter().enumerate().map(|(index, (_, value))| (index, rank(value))).collect::<Vec<_>>();
+    ranked.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap_or(std::cmp::Ordering::Equal));
+    let kept_indices = ranked.into_iter().take(limit).map(|(index, _)| index).collect::<fnv::FnvHashSet<_>>();
+
+    let mut kept = Vec::new();
+    let mut rest = Vec::new();
+    for (index, group) in groups.into_iter().enumerate() {
+        if kept_indices.contains(&index) {
+            kept.push(group);
+        } else {
+            rest.push(group);
+        }
+    }
+
+    (kept, rest)
+}
+
+pub struct WithOptions<'a> {
+    result: &'a OperationResult,
+    options: &'a JsonOptions
+}
+
+impl Serialize for WithOptions<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let options = self.options;
+        match self.result {
+            OperationResult::TimeValues(values) => TimeValuesWithOptions { values, options }.serialize(serializer),
+            OperationResult::GroupValues(values) if options.named_fields => {
+                serializer.collect_seq(values.iter().map(|(group, value)| NamedGroupValue { group, value: *value }))
+            }
+            OperationResult::GroupTimeValues(values) => {
+                serializer.collect_seq(
+                    values
+                        .iter()
+                        .map(|(group, values)| {
+                            let values = TimeValuesWithOptions { values, options };
+                            if options.named_fields {
+                                GroupTimeValuesEntry::Named { group, values }
+                            } else {
+                                GroupTimeValuesEntry::Unnamed(group, values)
+                            }
+                        })
+                )
+            }
+            result => result.as_json().serialize(serializer)
+        }
+    }
+}
+
+/// The shape of the value depends on whether the query is windowed and grouped, and on the JSON options.
+#[cfg(feature = "server")]
+impl PartialSchema for WithOptions<'_> {
+    fn schema() -> RefOr<Schema> {
+        let entries = |description: &str| ArrayBuilder::new().items(ObjectBuilder::new()).description(Some(description));
+
+        OneOfBuilder::new()
+            .item(
+                ObjectBuilder::new()
+                    .schema_type(SchemaType::from_iter([Type::Number, Type::Null]))
+                    .description(Some("Not windowed or grouped."))
+            )
+            .item(entries("Windowed: (time, value) tuples, or objects with named fields."))
+            .item(entries("Grouped: (group, value) tuples, or objects with named fields."))
+            .item(entries("Windowed and grouped: (group, [(time, value)]) tuples, or objects with named fields."))
+            .into()
+    }
+}
+
+#[cfg(feature = "server")]
+impl ToSchema for WithOptions<'_> {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("QueryValue")
+    }
+}
+
+struct TimeValuesWithOptions<'a> {
+    values: &'a TimeValues,
+    options: &'a JsonOptions
+}
+
+impl Serialize for TimeValuesWithOptions<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            self.values
+                .iter()
+                .map(|(time, value)| {
+                    let time = TimeField::new(*time, self.options);
+                    if self.options.named_fields {
+                        TimeValueEntry::Named { time, value: *value }
+                    } else {
+                        TimeValueEntry::Unnamed(time, *value)
+                    }
+                })
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct NamedGroupValue<'a> {
+    group: &'a GroupValue,
+    value: Option<f64>
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum GroupTimeValuesEntry<'a> {
+    Named { group: &'a GroupValue, values: TimeValuesWithOptions<'a> },
+    Unnamed(&'a GroupValue, TimeValuesWithOptions<'a>)
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TimeValueEntry {
+    Named { time: TimeField, value: Option<f64> },
+    Unnamed(TimeField, Option<f64>)
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TimeField {
+    Timestamp(String),
+    Seconds(f64)
+}
+
+impl TimeField {
+    fn new(time: f64, options: &JsonOptions) -> TimeField {
+        match options.rfc3339_timestamps.then(|| rfc3339_timestamp(time)).flatten() {
+            Some(time) => TimeField::Timestamp(time),
+            None => TimeField::Seconds(time)
+        }
+    }
+}
+
+fn time_json(time: f64, options: &JsonOptions) -> serde_json::Value {
+    json!(TimeField::new(time, options))
+}
+
+fn rfc3339_timestamp(time: f64) -> Option<String> {
+    let seconds = time.floor();
+    let nanoseconds = ((time - seconds) * 1.0E9).round().min(999_999_999.0) as u32;
+    chrono::DateTime::from_timestamp(seconds as i64, nanoseconds)
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true))
 }
 
 impl Display for OperationResult {
@@ -106,4 +408,119 @@ impl Display for OperationResult {
             OperationResult::GroupTimeValues(values) => write!(f, "{:?}", values)
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_as_json_with1() {
+    let result = OperationResult::TimeValues(vec![(1654077600.0, Some(1.0)), (1654077600.5, None)]);
+
+    assert_eq!(
+        json!([[1654077600.0, 1.0], [1654077600.5, null]]),
+        result.as_json_with(&JsonOptions::default())
+    );
+
+    assert_eq!(
+        json!([
+            { "time": "2022-06-01T10:00:00Z", "value": 1.0 },
+            { "time": "2022-06-01T10:00:00.500Z", "value": null }
+        ]),
+        result.as_json_with(&JsonOptions { rfc3339_timestamps: true, named_fields: true })
+    );
+
+    let result = OperationResult::GroupValues(vec![(GroupValue::from_ref("T1"), Some(2.0))]);
+    assert_eq!(
+        json!([{ "group": "T1", "value": 2.0 }]),
+        result.as_json_with(&JsonOptions { rfc3339_timestamps: false, named_fields: true })
+    );
+}
+
+#[test]
+fn test_rows1() {
+    let result = OperationResult::GroupTimeValues(vec![
+        (GroupValue::from_ref("T1"), vec![(1654077600.0, Some(1.0)), (1654077601.0, None)]),
+        (GroupValue::from_ref("T2"), vec![(1654077600.0, Some(2.0))])
+    ]);
+
+    assert_eq!(vec!["group", "time", "value"], result.columns());
+    assert_eq!(
+        vec![
+            vec![json!("T1"), json!(1654077600.0), json!(1.0)],
+            vec![json!("T1"), json!(1654077601.0), json!(null)],
+            vec![json!("T2"), json!(1654077600.0), json!(2.0)]
+        ],
+        result.rows(&JsonOptions::default()).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_limit_groups1() {
+    let result = OperationResult::GroupValues(vec![
+        (GroupValue::from_ref("T1"), Some(1.0)),
+        (GroupValue::from_ref("T2"), Some(5.0)),
+        (GroupValue::from_ref("T3"), Some(3.0)),
+        (GroupValue::from_ref("T4"), None)
+    ]);
+
+    assert_eq!(
+        OperationResult::GroupValues(vec![
+            (GroupValue::from_ref("T2"), Some(5.0)),
+            (GroupValue::from_ref("T3"), Some(3.0))
+        ]),
+        result.clone().limit_groups(&GroupLimit::new(2, false), Some(|x, y| x + y))
+    );
+
+    assert_eq!(
+        OperationResult::GroupValues(vec![
+            (GroupValue::from_ref("T2"), Some(5.0)),
+            (GroupValue::from_ref("T3"), Some(3.0)),
+            (GroupValue::from_ref(OTHER_GROUP), Some(1.0))
+        ]),
+        result.clone().limit_groups(&GroupLimit::new(2, true), Some(|x, y| x + y))
+    );
+
+    // Averages cannot be combined
+    assert_eq!(
+        OperationResult::GroupValues(vec![
+            (GroupValue::from_ref("T2"), Some(5.0)),
+            (GroupValue::from_ref("T3"), Some(3.0))
+        ]),
+        result.limit_groups(&GroupLimit::new(2, true), None)
+    );
+}
+
+#[test]
+fn test_limit_groups2() {
+    let result = OperationResult::GroupTimeValues(vec![
+        (GroupValue::from_ref("T1"), vec![(0.0, Some(1.0)), (1.0, None)]),
+        (GroupValue::from_ref("T2"), vec![(0.0, Some(5.0)), (1.0, Some(5.0))]),
+        (GroupValue::from_ref("T3"), vec![(0.0, Some(2.0)), (1.0, Some(1.0))])
+    ]);
+
+    assert_eq!(
+        OperationResult::GroupTimeValues(vec![
+            (GroupValue::from_ref("T2"), vec![(0.0, Some(5.0)), (1.0, Some(5.0))]),
+            (GroupValue::from_ref(OTHER_GROUP), vec![(0.0, Some(3.0)), (1.0, Some(1.0))])
+        ]),
+        result.clone().limit_groups(&GroupLimit::new(1, true), Some(|x, y| x + y))
+    );
+
+    assert_eq!(
+        OperationResult::GroupTimeValues(vec![
+            (GroupValue::from_ref("T2"), vec![(0.0, Some(5.0)), (1.0, Some(5.0))]),
+            (GroupValue::from_ref(OTHER_GROUP), vec![(0.0, Some(2.0)), (1.0, Some(1.0))])
+        ]),
+        result.limit_groups(&GroupLimit::new(1, true), Some(f64::max))
+    );
+}
+
+#[test]
+fn test_latest_window_time1() {
+    let result = OperationResult::GroupTimeValues(vec![
+        (GroupValue::from_ref("T1"), vec![(1654077600.0, Some(1.0)), (1654077601.0, None)]),
+        (GroupValue::from_ref("T2"), vec![(1654077600.0, None), (1654077601.0, Some(2.0)), (1654077602.0, None)])
+    ]);
+    assert_eq!(Some(1654077601.0), result.latest_window_time());
+
+    assert_eq!(None, OperationResult::TimeValues(vec![(1654077600.0, None)]).latest_window_time());
+    assert_eq!(None, OperationResult::Value(Some(1.0)).latest_window_time());
+}
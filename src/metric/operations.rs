@@ -1,7 +1,10 @@
 use std::marker::PhantomData;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
 use tdigest::TDigest;
 
-use crate::metric::expression::{ExpressionValue, FilterExpression, TransformExpression};
+use crate::metric::expression::{DatapointTime, ExpressionValue, FilterExpression, TransformExpression};
 use crate::metric::helpers::TimeRangeStatistics;
 use crate::metric::ratio::{Ratio};
 use crate::model::TimeRange;
@@ -9,6 +12,12 @@ use crate::traits::{MinMax, ToExpressionValue};
 
 pub trait StreamingOperation<TInput, TOutput=TInput> {
     fn add(&mut self, value: TInput);
+
+    /// Adds the value of a datapoint, where the time is used by input filters and transforms.
+    fn add_at(&mut self, value: TInput, _time: &DatapointTime) {
+        self.add(value);
+    }
+
     fn value(&self) -> Option<TOutput>;
 
     fn merge(&mut self, other: Self);
@@ -78,6 +87,35 @@ impl<T: Clone + Default + std::ops::AddAssign> Default for StreamingSum<T> {
     }
 }
 
+/// Counts the values, such as the number of datapoints.
+pub struct StreamingCount<T> {
+    count: u64,
+    _phantom: PhantomData<T>
+}
+
+impl<T> StreamingOperation<T, ExpressionValue> for StreamingCount<T> {
+    fn add(&mut self, _value: T) {
+        self.count += 1;
+    }
+
+    fn value(&self) -> Option<ExpressionValue> {
+        Some(ExpressionValue::Float(self.count as f64))
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.count += other.count;
+    }
+}
+
+impl<T> Default for StreamingCount<T> {
+    fn default() -> Self {
+        StreamingCount {
+            count: 0,
+            _phantom: Default::default()
+        }
+    }
+}
+
 pub struct StreamingAverage<T> {
     sum: T,
     count: i32
@@ -118,6 +156,135 @@ impl<T: Clone + Default + std::ops::AddAssign + std::ops::Div<Output=T> + From<i
     }
 }
 
+/// Controls how the values of different primary tags are weighted when averaging.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum AverageWeighting {
+    /// Every datapoint has the same weight, so primary tags with more datapoints dominate the average.
+    #[default]
+    Samples,
+    /// Every primary tag has the same weight, regardless of the number of datapoints it has.
+    PrimaryTags,
+    /// Every value is weighted by the time until the next value, which avoids over-weighting bursts of samples.
+    Time
+}
+
+/// Averages the average of each merged operation, where each operation covers a single primary tag.
+pub struct StreamingPrimaryTagsAverage {
+    current: StreamingAverage<f64>,
+    sum: f64,
+    count: i32
+}
+
+impl StreamingPrimaryTagsAverage {
+    pub fn new() -> StreamingPrimaryTagsAverage {
+        StreamingPrimaryTagsAverage {
+            current: StreamingAverage::new(),
+            sum: 0.0,
+            count: 0
+        }
+    }
+}
+
+impl StreamingOperation<f64> for StreamingPrimaryTagsAverage {
+    fn add(&mut self, value: f64) {
+        self.current.add(value);
+    }
+
+    fn value(&self) -> Option<f64> {
+        let mut sum = self.sum;
+        let mut count = self.count;
+        if let Some(current) = self.current.value() {
+            sum += current;
+            count += 1;
+        }
+
+        if count > 0 {
+            Some(sum / count as f64)
+        } else {
+            None
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.sum += other.sum;
+        self.count += other.count;
+        if let Some(other_current) = other.current.value() {
+            self.sum += other_current;
+            self.count += 1;
+        }
+    }
+}
+
+impl Default for StreamingPrimaryTagsAverage {
+    fn default() -> Self {
+        StreamingPrimaryTagsAverage::new()
+    }
+}
+
+/// Weights each value by the time until the next value, where the last value is held until the end of its window.
+/// Expects the values to be added in time order.
+pub struct StreamingTimeWeightedAverage {
+    start: f64,
+    window_duration: f64,
+    integral: f64,
+    duration: f64,
+    last: Option<(f64, f64)>
+}
+
+impl StreamingTimeWeightedAverage {
+    pub fn new(start: f64, window_duration: f64) -> StreamingTimeWeightedAverage {
+        StreamingTimeWeightedAverage {
+            start,
+            window_duration,
+            integral: 0.0,
+            duration: 0.0,
+            last: None
+        }
+    }
+
+    fn window_end(&self, time: f64) -> f64 {
+        self.start + ((time - self.start) / self.window_duration).floor() * self.window_duration + self.window_duration
+    }
+
+    fn totals(&self) -> (f64, f64) {
+        match self.last {
+            Some((last_time, last_value)) => {
+                let elapsed = (self.window_end(last_time) - last_time).max(0.0);
+                (self.integral + last_value * elapsed, self.duration + elapsed)
+            }
+            None => (self.integral, self.duration)
+        }
+    }
+}
+
+impl StreamingOperation<(f64, f64), f64> for StreamingTimeWeightedAverage {
+    fn add(&mut self, (time, value): (f64, f64)) {
+        if let Some((last_time, last_value)) = self.last {
+            let elapsed = (time - last_time).max(0.0);
+            self.integral += last_value * elapsed;
+            self.duration += elapsed;
+        }
+
+        self.last = Some((time, value));
+    }
+
+    fn value(&self) -> Option<f64> {
+        let (integral, duration) = self.totals();
+        if duration > 0.0 {
+            Some(integral / duration)
+        } else {
+            self.last.map(|(_, value)| value)
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        let (integral, duration) = other.totals();
+        self.integral += integral;
+        self.duration += duration;
+    }
+}
+
 pub struct StreamingTimeAverage<T> {
     sum: T,
     start: f64,
@@ -322,6 +489,11 @@ impl StreamingHistogram {
         self.min + (index / (self.buckets.len()) as f64) * (self.max - self.min)
     }
 
+    /// The center of each bucket and the number of values in it.
+    fn bucket_centers(&self) -> impl Iterator<Item=(f64, usize)> + '_ {
+        self.buckets.iter().enumerate().map(|(bucket_index, &count)| (self.edge_from_float_index(bucket_index as f64 + 0.5), count))
+    }
+
     fn auto_num_buckets(count: usize) -> usize {
         (count as f64).sqrt().ceil() as usize
     }
@@ -357,8 +529,7 @@ impl StreamingOperation<f64> for StreamingHistogram {
         );
 
         let mut add_histogram = |histogram: &StreamingHistogram| {
-            for (window_index, &count) in histogram.buckets.iter().enumerate() {
-                let center = histogram.edge_from_float_index(window_index as f64 + 0.5);
+            for (center, count) in histogram.bucket_centers() {
                 new_histogram.add_with_count(center, count);
             }
         };
@@ -386,6 +557,18 @@ impl StreamingApproxPercentileHistogram {
     pub fn from_stats(stats: &TimeRangeStatistics<f64>, percentile: i32) -> StreamingApproxPercentileHistogram {
         StreamingApproxPercentileHistogram::new(stats.min(), stats.max(), StreamingHistogram::auto_num_buckets(stats.count), percentile)
     }
+
+    /// Approximates the values of the histogram by the centers of their buckets.
+    pub fn into_tdigest(self, config: DigestConfig) -> StreamingApproxPercentileTDigest {
+        let mut tdigest = StreamingApproxPercentileTDigest::with_config(self.percentile, config);
+        for (center, count) in self.histogram.bucket_centers() {
+            for _ in 0..count {
+                tdigest.add(center);
+            }
+        }
+
+        tdigest
+    }
 }
 
 impl StreamingOperation<f64> for StreamingApproxPercentileHistogram {
@@ -403,6 +586,34 @@ impl StreamingOperation<f64> for StreamingApproxPercentileHistogram {
     }
 }
 
+pub const DEFAULT_TDIGEST_SIZE: usize = 150;
+pub const DEFAULT_TDIGEST_BUFFER_SIZE: usize = 512;
+
+/// A larger size gives more accurate percentiles (especially the tails) at the cost of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+#[serde(default)]
+pub struct DigestConfig {
+    pub size: usize,
+    /// The number of values buffered before being merged into the digest.
+    pub buffer_size: usize
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        DigestConfig {
+            size: DEFAULT_TDIGEST_SIZE,
+            buffer_size: DEFAULT_TDIGEST_BUFFER_SIZE
+        }
+    }
+}
+
+impl DigestConfig {
+    pub fn is_valid(&self) -> bool {
+        self.size > 0 && self.buffer_size > 0
+    }
+}
+
 pub struct StreamingTDigest {
     digest: TDigest,
     buffer: Vec<f64>,
@@ -411,16 +622,36 @@ pub struct StreamingTDigest {
 
 impl StreamingTDigest {
     pub fn new(max_size: usize) -> StreamingTDigest {
+        StreamingTDigest::with_config(DigestConfig { size: max_size, ..Default::default() })
+    }
+
+    pub fn with_config(config: DigestConfig) -> StreamingTDigest {
         StreamingTDigest {
-            digest: TDigest::new_with_size(max_size),
+            digest: TDigest::new_with_size(config.size),
             buffer: Vec::new(),
-            max_buffer_before_merge: 512
+            max_buffer_before_merge: config.buffer_size
         }
     }
 
     fn digest(&self) -> TDigest {
         self.digest.merge_unsorted(self.buffer.clone())
     }
+
+    pub fn config(&self) -> DigestConfig {
+        DigestConfig {
+            size: self.digest.max_size(),
+            buffer_size: self.max_buffer_before_merge
+        }
+    }
+
+    pub fn merge_digests(&mut self, mut digests: Vec<TDigest>) {
+        if digests.is_empty() {
+            return;
+        }
+
+        digests.insert(0, self.digest.clone());
+        self.digest = TDigest::merge_digests(digests);
+    }
 }
 
 impl StreamingOperation<f64> for StreamingTDigest {
@@ -448,11 +679,19 @@ pub struct StreamingApproxPercentileTDigest {
 
 impl StreamingApproxPercentileTDigest {
     pub fn new(percentile: i32) -> StreamingApproxPercentileTDigest {
+        StreamingApproxPercentileTDigest::with_config(percentile, DigestConfig::default())
+    }
+
+    pub fn with_config(percentile: i32, config: DigestConfig) -> StreamingApproxPercentileTDigest {
         StreamingApproxPercentileTDigest {
-            digest: StreamingTDigest::new(150),
+            digest: StreamingTDigest::with_config(config),
             percentile
         }
     }
+
+    pub fn merge_digests(&mut self, digests: Vec<TDigest>) {
+        self.digest.merge_digests(digests);
+    }
 }
 
 impl StreamingOperation<f64> for StreamingApproxPercentileTDigest {
@@ -470,6 +709,80 @@ impl StreamingOperation<f64> for StreamingApproxPercentileTDigest {
     }
 }
 
+/// The histogram requires a pre-pass to determine the value range, but the errors are bounded by the bucket width.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum PercentileAlgorithm {
+    #[default]
+    TDigest,
+    Histogram
+}
+
+pub enum StreamingApproxPercentile {
+    TDigest(StreamingApproxPercentileTDigest),
+    Histogram(StreamingApproxPercentileHistogram)
+}
+
+impl StreamingApproxPercentile {
+    pub fn new(algorithm: PercentileAlgorithm,
+               percentile: i32,
+               digest_config: DigestConfig,
+               stats: Option<&TimeRangeStatistics<f64>>) -> StreamingApproxPercentile {
+        match algorithm {
+            PercentileAlgorithm::TDigest => {
+                StreamingApproxPercentile::TDigest(StreamingApproxPercentileTDigest::with_config(percentile, digest_config))
+            }
+            PercentileAlgorithm::Histogram => {
+                match stats {
+                    Some(stats) if stats.count > 0 => {
+                        StreamingApproxPercentile::Histogram(StreamingApproxPercentileHistogram::from_stats(stats, percentile))
+                    }
+                    _ => StreamingApproxPercentile::Histogram(StreamingApproxPercentileHistogram::new(0.0, 0.0, 0, percentile))
+                }
+            }
+        }
+    }
+}
+
+impl StreamingOperation<f64> for StreamingApproxPercentile {
+    fn add(&mut self, value: f64) {
+        match self {
+            StreamingApproxPercentile::TDigest(operation) => operation.add(value),
+            StreamingApproxPercentile::Histogram(operation) => operation.add(value)
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        match self {
+            StreamingApproxPercentile::TDigest(operation) => operation.value(),
+            StreamingApproxPercentile::Histogram(operation) => operation.value()
+        }
+    }
+
+    /// Percentiles computed using different algorithms are merged as t-digests, as the histogram requires the value range up front.
+    fn merge(&mut self, other: Self) {
+        let placeholder = StreamingApproxPercentile::Histogram(StreamingApproxPercentileHistogram::new(0.0, 0.0, 0, 0));
+        match (std::mem::replace(self, placeholder), other) {
+            (StreamingApproxPercentile::TDigest(mut operation), StreamingApproxPercentile::TDigest(other)) => {
+                operation.merge(other);
+                *self = StreamingApproxPercentile::TDigest(operation);
+            }
+            (StreamingApproxPercentile::Histogram(mut operation), StreamingApproxPercentile::Histogram(other)) => {
+                operation.merge(other);
+                *self = StreamingApproxPercentile::Histogram(operation);
+            }
+            (StreamingApproxPercentile::TDigest(mut operation), StreamingApproxPercentile::Histogram(other)) => {
+                operation.merge(other.into_tdigest(operation.digest.config()));
+                *self = StreamingApproxPercentile::TDigest(operation);
+            }
+            (StreamingApproxPercentile::Histogram(operation), StreamingApproxPercentile::TDigest(mut other)) => {
+                other.merge(operation.into_tdigest(other.digest.config()));
+                *self = StreamingApproxPercentile::TDigest(other);
+            }
+        }
+    }
+}
+
 pub struct StreamingTransformOperation<T> {
     operation: TransformExpression,
     inner: T
@@ -500,6 +813,12 @@ impl<T: StreamingOperation<f64>> StreamingOperation<f64> for StreamingTransformO
         }
     }
 
+    fn add_at(&mut self, value: f64, time: &DatapointTime) {
+        if let Some(value) = self.operation.evaluate_at(&ExpressionValue::Float(value), Some(time)) {
+            self.inner.add_at(value, time);
+        }
+    }
+
     fn value(&self) -> Option<f64> {
         self.inner.value()
     }
@@ -546,6 +865,12 @@ impl<TInput: ToExpressionValue, TOutput, TOp: StreamingOperation<TInput, TOutput
         }
     }
 
+    fn add_at(&mut self, value: TInput, time: &DatapointTime) {
+        if self.operation.evaluate_at(&value.to_value(), Some(time)).unwrap_or(false) {
+            self.inner.add_at(value, time);
+        }
+    }
+
     fn value(&self) -> Option<TOutput> {
         self.inner.value()
     }
@@ -648,3 +973,81 @@ fn test_streaming_approx_percentile2() {
 
     assert_eq!(Some(990.5), streaming.value());
 }
+
+#[test]
+fn test_streaming_approx_percentile3() {
+    let config = DigestConfig { size: 1000, buffer_size: 10 };
+    let mut streaming = StreamingApproxPercentileTDigest::with_config(99, config);
+    for value in 1..1001 {
+        streaming.add(value as f64);
+    }
+
+    let value = streaming.value().unwrap();
+    assert!((value - 990.0).abs() <= 1.0, "{}", value);
+    assert!(!DigestConfig { size: 0, buffer_size: 10 }.is_valid());
+}
+
+#[test]
+fn test_streaming_approx_percentile4() {
+    let config = DigestConfig { size: 1000, buffer_size: 10 };
+    let mut histogram = StreamingApproxPercentile::Histogram(StreamingApproxPercentileHistogram::new(1.0, 501.0, 500, 50));
+    let mut tdigest = StreamingApproxPercentile::TDigest(StreamingApproxPercentileTDigest::with_config(50, config));
+    for value in 1..501 {
+        histogram.add(value as f64);
+        tdigest.add((value + 500) as f64);
+    }
+
+    // Merged as t-digests regardless of the order
+    histogram.merge(tdigest);
+    assert!(matches!(histogram, StreamingApproxPercentile::TDigest(_)));
+    let value = histogram.value().unwrap();
+    assert!((value - 500.0).abs() <= 2.0, "{}", value);
+}
+
+#[test]
+fn test_streaming_primary_tags_average1() {
+    let mut streaming1 = StreamingPrimaryTagsAverage::new();
+    for value in [1.0, 1.0, 1.0, 1.0] {
+        streaming1.add(value);
+    }
+
+    let mut streaming2 = StreamingPrimaryTagsAverage::new();
+    streaming2.add(3.0);
+
+    let mut streaming3 = StreamingPrimaryTagsAverage::new();
+    let mut pooled = StreamingAverage::<f64>::new();
+    for value in [1.0, 1.0, 1.0, 1.0, 3.0] {
+        pooled.add(value);
+    }
+
+    streaming1.merge(streaming2);
+    streaming1.merge(StreamingPrimaryTagsAverage::new());
+    assert_eq!(Some(2.0), streaming1.value());
+    assert_eq!(Some(1.4), pooled.value());
+    assert_eq!(None, streaming3.value());
+    streaming3.merge(streaming1);
+    assert_eq!(Some(2.0), streaming3.value());
+}
+
+#[test]
+fn test_streaming_time_weighted_average1() {
+    let mut streaming = StreamingTimeWeightedAverage::new(0.0, 100.0);
+    streaming.add((0.0, 1.0));
+    for index in 0..10 {
+        streaming.add((50.0 + index as f64, 10.0));
+    }
+    streaming.add((60.0, 1.0));
+
+    // 1.0 for 50 seconds, 10.0 for 10 seconds and 1.0 for 40 seconds
+    assert_eq!(Some(1.9), streaming.value());
+
+    let mut other = StreamingTimeWeightedAverage::new(0.0, 100.0);
+    other.add((75.0, 5.0));
+    streaming.merge(other);
+    assert_eq!(Some(2.52), streaming.value());
+
+    let mut single = StreamingTimeWeightedAverage::new(0.0, 100.0);
+    assert_eq!(None, single.value());
+    single.add((100.0, 3.0));
+    assert_eq!(Some(3.0), single.value());
+}
@@ -1,16 +1,22 @@
 use std::ops::AddAssign;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
 use serde::{Serialize, Deserialize};
+#[cfg(feature = "server")]
+use utoipa::{PartialSchema, ToSchema};
+#[cfg(feature = "server")]
+use utoipa::openapi::{ArrayBuilder, ObjectBuilder, RefOr, Schema, Type};
 
+use crate::engine::lock_watchdog::LockWatchdog;
 use crate::metric::common::{CountInput, GenericMetric, MetricType, PrimaryTagsStorage, MetricConfig};
 use crate::metric::helpers::{MetricWindowing, TimeRangeStatistics};
-use crate::metric::operations::{StreamingAverage, StreamingConvert, StreamingMax, StreamingOperation, StreamingRatioValue, StreamingSum, StreamingFilterOperation, StreamingMin, StreamingApproxPercentileTDigest};
+use crate::metric::operations::{AverageWeighting, StreamingAverage, StreamingConvert, StreamingMax, StreamingOperation, StreamingPrimaryTagsAverage, StreamingRatioValue, StreamingSum, StreamingFilterOperation, StreamingMin, StreamingApproxPercentileTDigest, StreamingCount};
 use crate::metric::{helpers, OperationResult};
-use crate::metric::expression::ExpressionValue;
+use crate::metric::expression::{DatapointTime, ExpressionValue};
 use crate::metric::tags::{PrimaryTag, Tag, TagsFilter};
-use crate::model::{MetricResult, Query, Time, TIME_SCALE};
+use crate::model::{GroupKey, MetricResult, Query, Time, TIME_SCALE};
 use crate::storage::file::FileMetricStorage;
 use crate::storage::MetricStorage;
 use crate::traits::{MinMax, ToExpressionValue};
@@ -82,16 +88,37 @@ impl<TStorage: MetricStorage<RatioU32>> RatioMetric<TStorage> {
         )
     }
 
+    pub fn from_existing_read_only(base_path: &Path) -> MetricResult<RatioMetric<TStorage>> {
+        Ok(
+            RatioMetric {
+                primary_tags_storage: PrimaryTagsStorage::from_existing_read_only(base_path)?
+            }
+        )
+    }
+
     pub fn primary_tags(&self) -> impl Iterator<Item=&PrimaryTag> {
         self.primary_tags_storage.primary_tags()
     }
 
+    /// The number of datapoints the ratios are computed from, where values added at the same time are a single datapoint.
+    pub fn count(&self, query: Query) -> OperationResult {
+        type Op = StreamingCount<Ratio>;
+        apply_operation!(self, Op, query, |_| Op::default(), false)
+    }
+
+    pub fn count_in_window(&self, query: Query, duration: Duration) -> OperationResult {
+        type Op = StreamingCount<Ratio>;
+        apply_operation_in_window!(self, Op, query, duration, |_| Op::default(), false)
+    }
+
     fn operation<T: StreamingOperation<Ratio, ExpressionValue>, F: Fn(Option<&TimeRangeStatistics<RatioU32>>) -> T>(&self,
                                                                                                                     query: Query,
                                                                                                                     create_op: F,
                                                                                                                     require_statistics: bool) -> OperationResult {
         let (start_time, end_time) = query.time_range.int_range();
-        assert!(end_time > start_time);
+        if end_time <= start_time {
+            return OperationResult::empty(&query, false);
+        }
 
         let apply = |tags_filter: &TagsFilter| {
             let mut streaming_operations = Vec::new();
@@ -120,8 +147,8 @@ impl<TStorage: MetricStorage<RatioU32>> RatioMetric<TStorage> {
                         tags_filter,
                         start_block_index,
                         false,
-                        |_, _, datapoint| {
-                            streaming_operation.add(datapoint.value.to_u64());
+                        |_, datapoint_time, datapoint| {
+                            streaming_operation.add_at(datapoint.value.to_u64(), &DatapointTime::new(datapoint_time, query.time_range.start));
                         }
                     );
 
@@ -153,9 +180,10 @@ impl<TStorage: MetricStorage<RatioU32>> RatioMetric<TStorage> {
                                                                                                                            create_op: F,
                                                                                                                            require_statistics: bool) -> OperationResult {
         let (start_time, end_time) = query.time_range.int_range();
-        assert!(end_time > start_time);
-
         let duration = (duration.as_secs_f64() * TIME_SCALE as f64) as Time;
+        if end_time <= start_time || duration == 0 {
+            return OperationResult::empty(&query, true);
+        }
 
         let apply = |tags_filter: &TagsFilter| {
             let mut primary_tags_windowing = Vec::new();
@@ -164,30 +192,14 @@ impl<TStorage: MetricStorage<RatioU32>> RatioMetric<TStorage> {
                 if let Some(start_block_index) = helpers::find_block_index(storage, start_time) {
                     let mut windowing = MetricWindowing::new(start_time, end_time, duration);
 
-                    let window_stats = if require_statistics {
-                        let mut window_stats = windowing.create_windows(|| None);
-
-                        helpers::visit_datapoints_in_time_range(
-                            storage,
-                            start_time,
-                            end_time,
-                            tags_filter,
-                            start_block_index,
-                            false,
-                            |_, datapoint_time, datapoint| {
-                                let window_index = windowing.get_window_index(datapoint_time);
-                                if window_index < windowing.len() {
-                                    window_stats[window_index]
-                                        .get_or_insert_with(|| TimeRangeStatistics::default())
-                                        .handle(datapoint.value.to_u64());
-                                }
-                            }
-                        );
-
-                        Some(window_stats)
-                    } else {
-                        None
-                    };
+                    // Operations requiring statistics are created once the whole window has been seen, which buffers the values
+                    // of the window but only walks the datapoints once
+                    let mut window_stats: Vec<Option<TimeRangeStatistics<Ratio>>> = Vec::new();
+                    let mut window_values: Vec<Vec<(Time, Ratio)>> = Vec::new();
+                    if require_statistics {
+                        window_stats = windowing.create_windows(|| None);
+                        window_values = windowing.create_windows(Vec::new);
+                    }
 
                     helpers::visit_datapoints_in_time_range(
                         storage,
@@ -199,19 +211,32 @@ impl<TStorage: MetricStorage<RatioU32>> RatioMetric<TStorage> {
                         |_, datapoint_time, datapoint| {
                             let window_index = windowing.get_window_index(datapoint_time);
                             if window_index < windowing.len() {
-                                windowing.get(window_index)
-                                    .get_or_insert_with(|| {
-                                        if require_statistics {
-                                            create_op((&window_stats.as_ref().unwrap()[window_index]).as_ref())
-                                        } else {
-                                            create_op(None)
-                                        }
-                                    })
-                                    .add(datapoint.value.to_u64());
+                                let value = datapoint.value.to_u64();
+                                if require_statistics {
+                                    window_stats[window_index]
+                                        .get_or_insert_with(TimeRangeStatistics::default)
+                                        .handle(value);
+                                    window_values[window_index].push((datapoint_time, value));
+                                } else {
+                                    let time = DatapointTime::new(datapoint_time, windowing.get_timestamp(window_index));
+                                    windowing.get(window_index)
+                                        .get_or_insert_with(|| create_op(None))
+                                        .add_at(value, &time);
+                                }
                             }
                         }
                     );
 
+                    for (window_index, (stats, values)) in window_stats.into_iter().zip(window_values).enumerate() {
+                        if let Some(stats) = stats {
+                            let window_start = windowing.get_timestamp(window_index);
+                            let operation = windowing.get(window_index).insert(create_op(Some(&stats)));
+                            for (time, value) in values {
+                                operation.add_at(value, &DatapointTime::new(time, window_start));
+                            }
+                        }
+                    }
+
                     primary_tags_windowing.push(windowing);
                 }
             }
@@ -220,10 +245,12 @@ impl<TStorage: MetricStorage<RatioU32>> RatioMetric<TStorage> {
                 return Vec::new();
             }
 
-            helpers::extract_operations_in_windows(
-                helpers::merge_windowing(primary_tags_windowing),
-                |value| query.apply_output_transform(value?),
-                query.remove_empty_datapoints
+            query.apply_output_transform_in_windows(
+                helpers::extract_operations_in_windows(
+                    helpers::merge_windowing(primary_tags_windowing),
+                    |value| query.apply_output_transform(value?),
+                    query.remove_empty_datapoints
+                )
             )
         };
 
@@ -251,26 +278,70 @@ impl<TStorage: MetricStorage<RatioU32>> GenericMetric for RatioMetric<TStorage>
         self.primary_tags_storage.add_auto_primary_tag(key)
     }
 
+    fn remove_primary_tag(&mut self, tag: &Tag) -> MetricResult<bool> {
+        self.primary_tags_storage.remove_primary_tag(tag)
+    }
+
+    fn remove_auto_primary_tag(&mut self, key: &str) -> MetricResult<bool> {
+        self.primary_tags_storage.remove_auto_primary_tag(key)
+    }
+
+    fn auto_primary_tags(&self) -> Vec<String> {
+        self.primary_tags_storage.auto_primary_tags()
+    }
+
+    fn requires_new_primary_tags(&self, tags: &[Tag]) -> bool {
+        self.primary_tags_storage.requires_new_primary_tags(tags)
+    }
+
+    fn create_auto_primary_tags(&mut self, tags: &[Tag]) -> MetricResult<()> {
+        self.primary_tags_storage.create_auto_primary_tags(tags)
+    }
+
+    fn num_groups(&self, query: &Query, key: &GroupKey) -> usize {
+        self.primary_tags_storage.num_groups(query, key)
+    }
+
+    fn group_values(&self, query: &Query, key: &GroupKey) -> Vec<Vec<Tag>> {
+        self.primary_tags_storage.gather_group_values(query, key)
+    }
+
     type Input = RatioInput;
-    fn add(&mut self, time: f64, value: RatioInput, mut tags: Vec<Tag>) -> MetricResult<()> {
-        let (primary_tag_key, mut primary_tag, secondary_tags) = self.primary_tags_storage.insert_tags(&mut tags)?;
+    fn add_concurrent(&self, time: f64, value: RatioInput, mut tags: Vec<Tag>) -> MetricResult<()> {
+        let (mut primary_tag, secondary_tags) = self.primary_tags_storage.insert_tags(&mut tags)?;
 
-        let result = primary_tag.add(
+        primary_tag.add(
             time,
             value.value()?,
             secondary_tags,
-            |last_datapoint, value| {
+            |last_datapoint, value, _| {
                 last_datapoint.value += value;
             }
-        );
+        )
+    }
 
-        self.primary_tags_storage.return_tags(primary_tag_key, primary_tag);
-        result
+    fn add_batch(&self, values: &[(f64, RatioInput, Vec<Tag>)]) -> MetricResult<usize> {
+        self.primary_tags_storage.add_batch(
+            values,
+            |value: RatioInput| value.value(),
+            |last_datapoint, value, _| {
+                last_datapoint.value += value;
+            }
+        )
     }
 
     fn average(&self, query: Query) -> OperationResult {
-        type Op = StreamingRatioValue<StreamingAverage<f64>>;
-        apply_operation!(self, Op, query, |_| Op::from_default(), false)
+        match query.average_weighting {
+            // Ratios are not sampled values, so time weighting does not apply
+            AverageWeighting::Samples | AverageWeighting::Time => {
+                type Op = StreamingRatioValue<StreamingAverage<f64>>;
+                apply_operation!(self, Op, query, |_| Op::from_default(), false)
+            }
+            AverageWeighting::PrimaryTags => {
+                type Op = StreamingRatioValue<StreamingPrimaryTagsAverage>;
+                apply_operation!(self, Op, query, |_| Op::from_default(), false)
+            }
+        }
     }
 
     fn sum(&self, query: Query) -> OperationResult {
@@ -288,17 +359,31 @@ impl<TStorage: MetricStorage<RatioU32>> GenericMetric for RatioMetric<TStorage>
     }
 
     fn percentile(&self, query: Query, percentile: i32) -> OperationResult {
+        let digest_config = query.digest.unwrap_or(self.primary_tags_storage.config().digest);
         let create = |_: Option<&TimeRangeStatistics<RatioU32>>| {
-            StreamingRatioValue::new(StreamingApproxPercentileTDigest::new(percentile))
+            StreamingRatioValue::new(StreamingApproxPercentileTDigest::with_config(percentile, digest_config))
         };
 
         type Op = StreamingRatioValue<StreamingApproxPercentileTDigest>;
-        apply_operation!(self, Op, query, create, true)
+        apply_operation!(self, Op, query, create, false)
+    }
+
+    fn last(&self, query: Query) -> OperationResult {
+        self.primary_tags_storage.last(&query, |value| ExpressionValue::Ratio(value.to_u64()))
     }
 
     fn average_in_window(&self, query: Query, duration: Duration) -> OperationResult {
-        type Op = StreamingRatioValue<StreamingAverage<f64>>;
-        apply_operation_in_window!(self, Op, query, duration, |_| Op::from_default(), false)
+        match query.average_weighting {
+            // Ratios are not sampled values, so time weighting does not apply
+            AverageWeighting::Samples | AverageWeighting::Time => {
+                type Op = StreamingRatioValue<StreamingAverage<f64>>;
+                apply_operation_in_window!(self, Op, query, duration, |_| Op::from_default(), false)
+            }
+            AverageWeighting::PrimaryTags => {
+                type Op = StreamingRatioValue<StreamingPrimaryTagsAverage>;
+                apply_operation_in_window!(self, Op, query, duration, |_| Op::from_default(), false)
+            }
+        }
     }
 
     fn sum_in_window(&self, query: Query, duration: Duration) -> OperationResult {
@@ -316,17 +401,57 @@ impl<TStorage: MetricStorage<RatioU32>> GenericMetric for RatioMetric<TStorage>
     }
 
     fn percentile_in_window(&self, query: Query, duration: Duration, percentile: i32) -> OperationResult {
+        let digest_config = query.digest.unwrap_or(self.primary_tags_storage.config().digest);
         let create = |_: Option<&TimeRangeStatistics<Ratio>>| {
-            StreamingRatioValue::new(StreamingApproxPercentileTDigest::new(percentile))
+            StreamingRatioValue::new(StreamingApproxPercentileTDigest::with_config(percentile, digest_config))
         };
 
         type Op = StreamingRatioValue<StreamingApproxPercentileTDigest>;
-        apply_operation_in_window!(self, Op, query, duration, create, true)
+        apply_operation_in_window!(self, Op, query, duration, create, false)
     }
 
-    fn scheduled(&mut self) {
+    fn scheduled(&self) {
         self.primary_tags_storage.scheduled();
     }
+    fn config(&self) -> &MetricConfig {
+        self.primary_tags_storage.config()
+    }
+
+    fn start_time(&self) -> Option<Time> {
+        self.primary_tags_storage.start_time()
+    }
+
+    fn latest_time(&self) -> Option<f64> {
+        self.primary_tags_storage.latest_time()
+    }
+
+    fn sealed_time(&self) -> Option<Time> {
+        self.primary_tags_storage.sealed_time()
+    }
+
+    fn num_primary_tags_with_data(&self) -> usize {
+        self.primary_tags_storage.num_primary_tags_with_data()
+    }
+
+    fn flush(&self) -> MetricResult<()> {
+        self.primary_tags_storage.flush()
+    }
+
+    fn move_to_cold_tier(&self, hot_root: &Path, cold_root: &Path, older_than: Time) -> MetricResult<usize> {
+        self.primary_tags_storage.move_to_cold_tier(hot_root, cold_root, older_than)
+    }
+
+    fn remove_expired_segments(&self, now: Time) -> MetricResult<usize> {
+        self.primary_tags_storage.remove_expired_segments(now)
+    }
+
+    fn verify(&self) -> Vec<String> {
+        self.primary_tags_storage.verify()
+    }
+
+    fn set_lock_watchdog(&mut self, metric: &str, lock_watchdog: Arc<LockWatchdog>) {
+        self.primary_tags_storage.set_lock_watchdog(metric, lock_watchdog);
+    }
 }
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -426,8 +551,84 @@ impl MinMax for RatioU32 {
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct RatioInput(pub CountInput, pub CountInput);
 
+#[cfg(feature = "server")]
+impl PartialSchema for RatioInput {
+    fn schema() -> RefOr<Schema> {
+        ArrayBuilder::new()
+            .items(ObjectBuilder::new().schema_type(Type::Integer).minimum(Some(0)))
+            .min_items(Some(2))
+            .max_items(Some(2))
+            .description(Some("The numerator and denominator."))
+            .into()
+    }
+}
+
+#[cfg(feature = "server")]
+impl ToSchema for RatioInput {}
+
 impl RatioInput {
     pub fn value(&self) -> MetricResult<RatioU32> {
         Ok(RatioU32(self.0.value()?, self.1.value()?))
     }
-}
\ No newline at end of file
+}
+/// The ratio with its Wilson score interval as (ratio, lower, upper), where `z` is the standard normal quantile of the
+/// confidence (such as 1.96 for 95%). Unlike the normal approximation, the interval is meaningful for small denominators.
+pub fn wilson_interval(numerator: f64, denominator: f64, z: f64) -> Option<(f64, f64, f64)> {
+    if denominator <= 0.0 {
+        return None;
+    }
+
+    let ratio = numerator / denominator;
+    let z2 = z * z;
+    let scale = 1.0 + z2 / denominator;
+    let center = (ratio + z2 / (2.0 * denominator)) / scale;
+    let margin = z / scale * ((ratio * (1.0 - ratio)).max(0.0) / denominator + z2 / (4.0 * denominator * denominator)).sqrt();
+    Some((ratio, (center - margin).max(0.0), (center + margin).min(1.0)))
+}
+
+/// The inverse of the standard normal CDF, using the rational approximation by Acklam (relative error below 1.2e-9).
+pub fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [-3.969683028665376e+01, 2.209460984245205e+02, -2.759285104469687e+02, 1.38357751867269e+02, -3.066479806614716e+01, 2.506628277459239e+00];
+    const B: [f64; 5] = [-5.447609879822406e+01, 1.615858368580409e+02, -1.556989798598866e+02, 6.680131188771972e+01, -1.328068155288572e+01];
+    const C: [f64; 6] = [-7.784894002430293e-03, -3.223964580411365e-01, -2.400758277161838e+00, -2.549732539343734e+00, 4.374664141464968e+00, 2.938163982698783e+00];
+    const D: [f64; 4] = [7.784695709041462e-03, 3.224671290700398e-01, 2.445134137142996e+00, 3.754408661907416e+00];
+    const P_LOW: f64 = 0.02425;
+
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5]) / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+
+    if p < P_LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p <= 1.0 - P_LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    }
+}
+
+#[test]
+fn test_wilson_interval1() {
+    let (ratio, lower, upper) = wilson_interval(1.0, 10.0, 1.96).unwrap();
+    assert_eq!(0.1, ratio);
+    assert!((lower - 0.017875).abs() < 1e-5);
+    assert!((upper - 0.404156).abs() < 1e-5);
+
+    // Stays within 0 and 1 even when nothing or everything passes
+    let (_, lower, upper) = wilson_interval(0.0, 3.0, 1.96).unwrap();
+    assert_eq!(0.0, lower);
+    assert!(upper > 0.0 && upper < 1.0);
+    assert_eq!(1.0, wilson_interval(3.0, 3.0, 1.96).unwrap().2);
+
+    assert_eq!(None, wilson_interval(0.0, 0.0, 1.96));
+}
+
+#[test]
+fn test_normal_quantile1() {
+    assert!((normal_quantile(0.975) - 1.959964).abs() < 1e-6);
+    assert!((normal_quantile(0.995) - 2.575829).abs() < 1e-6);
+    assert!((normal_quantile(0.01) + 2.326348).abs() < 1e-6);
+    assert_eq!(0.0, normal_quantile(0.5));
+}
@@ -4,6 +4,10 @@ use std::path::{Path, PathBuf};
 use fnv::FnvHashSet;
 
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
+#[cfg(feature = "server")]
+use utoipa::{PartialSchema, ToSchema};
+#[cfg(feature = "server")]
+use utoipa::openapi::{ObjectBuilder, RefOr, Schema, Type};
 use serde::de::{Error, Visitor};
 
 use crate::model::{MetricError, MetricResult, Tags};
@@ -57,6 +61,20 @@ impl<'de> Deserialize<'de> for Tag {
     }
 }
 
+#[cfg(feature = "server")]
+impl PartialSchema for Tag {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some("On the format key:value."))
+            .examples(["host:server1"])
+            .into()
+    }
+}
+
+#[cfg(feature = "server")]
+impl ToSchema for Tag {}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum PrimaryTag {
     Default,
@@ -79,15 +97,26 @@ impl PrimaryTag {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub enum TagsFilter {
     None,
     And(Vec<Tag>),
     Or(Vec<Tag>),
-    OrAnd(Vec<Tag>, Vec<Tag>)
+    OrAnd(Vec<Tag>, Vec<Tag>),
+    /// Only matches the storage of the given primary tag, applying the inner filter to it.
+    #[serde(skip)]
+    Primary(Tag, Box<TagsFilter>)
 }
 
 impl TagsFilter {
+    pub fn primary_tag(&self) -> Option<&Tag> {
+        match self {
+            TagsFilter::Primary(tag, _) => Some(tag),
+            _ => None
+        }
+    }
+
     pub fn apply(&self,
                  named_primary_tags: &HashSet<&Tag>,
                  primary_tag: &PrimaryTag,
@@ -164,6 +193,12 @@ impl TagsFilter {
                     }
                 }
             }
+            TagsFilter::Primary(tag, tags_filter) => {
+                match primary_tag {
+                    PrimaryTag::Named(named_tag) if named_tag == tag => tags_filter.apply(named_primary_tags, primary_tag, tags_index),
+                    _ => None
+                }
+            }
         }
     }
 
@@ -180,6 +215,9 @@ impl TagsFilter {
             TagsFilter::OrAnd(_, _) => {
                 unimplemented!("Not supported.");
             }
+            TagsFilter::Primary(tag, tags_filter) => {
+                TagsFilter::Primary(tag, Box::new(tags_filter.add_and_clause(tags)))
+            }
         }
     }
 }
@@ -247,6 +285,10 @@ impl SecondaryTagsIndex {
         self.tags_pattern_to_string.get(tags)
     }
 
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.mapping.keys().any(|tag| tag.0 == key)
+    }
+
     pub fn all_patterns(&self) -> &FnvHashSet<Tags> {
         &self.all_patterns
     }
@@ -1,10 +1,19 @@
+use std::path::PathBuf;
+
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
+#[cfg(feature = "server")]
+use utoipa::{PartialSchema, ToSchema};
+#[cfg(feature = "server")]
+use utoipa::openapi::{ArrayBuilder, ObjectBuilder, OneOfBuilder, RefOr, Schema, Type};
 use serde::de::{Error, SeqAccess, Visitor};
 use serde::ser::SerializeSeq;
 
-use crate::metric::expression::{ExpressionValue, FilterExpression, TransformExpression};
+use crate::metric::common::MetricType;
+use crate::metric::expression::{ExpressionValue, FilterExpression, Function, TransformExpression};
+use crate::metric::TimeValues;
+use crate::metric::operations::{AverageWeighting, DigestConfig, PercentileAlgorithm};
 use crate::metric::tags::{Tag, TagsFilter};
-use crate::storage::memory_file::MemoryFileError;
+use crate::storage::MemoryFileError;
 
 pub type Time = u64;
 pub type Tags = u128;
@@ -17,7 +26,8 @@ pub struct Datapoint<T: Copy> {
     pub value: T
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 pub struct TimeRange {
     pub start: f64,
     pub end: f64
@@ -33,6 +43,29 @@ impl TimeRange {
         }
     }
 
+    pub fn try_new(start: f64, end: f64) -> Result<TimeRange, QueryError> {
+        let time_range = TimeRange {
+            start,
+            end
+        };
+
+        time_range.validate()?;
+        Ok(time_range)
+    }
+
+    pub fn validate(&self) -> Result<(), QueryError> {
+        if !self.start.is_finite() || !self.end.is_finite() {
+            return Err(QueryError::InvalidTimeRange);
+        }
+
+        let (start_time, end_time) = self.int_range();
+        if end_time <= start_time {
+            return Err(QueryError::InvalidTimeRange);
+        }
+
+        Ok(())
+    }
+
     pub fn int_range(&self) -> (Time, Time) {
         (
             (self.start * TIME_SCALE as f64).round() as Time,
@@ -54,6 +87,20 @@ impl GroupKey {
     }
 }
 
+impl Serialize for GroupKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        if self.0.len() == 1 {
+            serializer.serialize_str(&self.0[0])
+        } else {
+            let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+            for part in &self.0 {
+                seq.serialize_element(part)?;
+            }
+            seq.end()
+        }
+    }
+}
+
 struct GroupKeyVisitor;
 impl<'de> Visitor<'de> for GroupKeyVisitor {
     type Value = GroupKey;
@@ -86,6 +133,20 @@ impl<'de> Deserialize<'de> for GroupKey {
     }
 }
 
+#[cfg(feature = "server")]
+impl PartialSchema for GroupKey {
+    fn schema() -> RefOr<Schema> {
+        OneOfBuilder::new()
+            .item(ObjectBuilder::new().schema_type(Type::String))
+            .item(ArrayBuilder::new().items(ObjectBuilder::new().schema_type(Type::String)))
+            .description(Some("A single tag key or multiple tag keys."))
+            .into()
+    }
+}
+
+#[cfg(feature = "server")]
+impl ToSchema for GroupKey {}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct GroupValue(pub Vec<String>);
 
@@ -113,7 +174,22 @@ impl Serialize for GroupValue {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Which of the stored data a query reads.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub enum ReadConsistency {
+    /// Reads all written data, including the active blocks.
+    #[default]
+    Latest,
+    /// Only reads the sealed blocks, which cannot change, such that the result is reproducible.
+    /// Nothing is sealed while a primary tag of the metric has no datapoints.
+    Sealed,
+    /// Writes the values of the metric buffered by the pre-aggregation before reading, for the freshest data.
+    Synced
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
 #[serde(default)]
 pub struct Query {
     pub time_range: TimeRange,
@@ -123,7 +199,16 @@ pub struct Query {
     pub output_filter: Option<FilterExpression>,
     pub output_transform: Option<TransformExpression>,
     pub group_by: Option<GroupKey>,
-    pub remove_empty_datapoints: bool
+    pub remove_empty_datapoints: bool,
+    pub staleness: Option<f64>,
+    pub group_limit: Option<GroupLimit>,
+    /// Evaluates the group by directly on the primary tag storages, ignoring values of the key stored as secondary tags.
+    pub primary_tag_group_by: bool,
+    /// Overrides the digest config of the metric for percentile queries.
+    pub digest: Option<DigestConfig>,
+    pub percentile_algorithm: Option<PercentileAlgorithm>,
+    pub average_weighting: AverageWeighting,
+    pub consistency: ReadConsistency
 }
 
 impl Query {
@@ -136,7 +221,14 @@ impl Query {
             output_filter: None,
             output_transform: None,
             group_by: None,
-            remove_empty_datapoints: true
+            remove_empty_datapoints: true,
+            staleness: None,
+            group_limit: None,
+            primary_tag_group_by: false,
+            digest: None,
+            percentile_algorithm: None,
+            average_weighting: AverageWeighting::Samples,
+            consistency: ReadConsistency::Latest
         }
     }
 
@@ -180,6 +272,125 @@ impl Query {
         new
     }
 
+    pub fn group_by_primary_tag(self, key: &str) -> Query {
+        let mut new = self;
+        new.group_by = Some(GroupKey::from_ref(key));
+        new.primary_tag_group_by = true;
+        new
+    }
+
+    pub fn with_staleness(self, staleness: f64) -> Query {
+        let mut new = self;
+        new.staleness = Some(staleness);
+        new
+    }
+
+    pub fn with_group_limit(self, group_limit: GroupLimit) -> Query {
+        let mut new = self;
+        new.group_limit = Some(group_limit);
+        new
+    }
+
+    pub fn with_digest(self, digest: DigestConfig) -> Query {
+        let mut new = self;
+        new.digest = Some(digest);
+        new
+    }
+
+    pub fn with_percentile_algorithm(self, algorithm: PercentileAlgorithm) -> Query {
+        let mut new = self;
+        new.percentile_algorithm = Some(algorithm);
+        new
+    }
+
+    pub fn with_average_weighting(self, weighting: AverageWeighting) -> Query {
+        let mut new = self;
+        new.average_weighting = weighting;
+        new
+    }
+
+    pub fn with_consistency(self, consistency: ReadConsistency) -> Query {
+        let mut new = self;
+        new.consistency = consistency;
+        new
+    }
+
+    pub fn validate(&self) -> Result<(), QueryError> {
+        self.time_range.validate()?;
+
+        if let Some(staleness) = self.staleness {
+            if staleness.is_nan() || staleness < 0.0 {
+                return Err(QueryError::InvalidStaleness);
+            }
+        }
+
+        if let Some(group_by) = &self.group_by {
+            if group_by.0.is_empty() {
+                return Err(QueryError::EmptyGroupKey);
+            }
+
+            if let TagsFilter::OrAnd(_, _) = &self.tags_filter {
+                return Err(QueryError::GroupByWithOrAndFilter);
+            }
+        }
+
+        if self.primary_tag_group_by && self.group_by.as_ref().map(|group_by| group_by.0.len()) != Some(1) {
+            return Err(QueryError::InvalidPrimaryTagGroupBy);
+        }
+
+        if let Some(group_limit) = &self.group_limit {
+            if self.group_by.is_none() {
+                return Err(QueryError::GroupLimitWithoutGroupBy);
+            }
+
+            if group_limit.limit == 0 {
+                return Err(QueryError::InvalidGroupLimit);
+            }
+        }
+
+        if let Some(digest) = &self.digest {
+            if !digest.is_valid() {
+                return Err(QueryError::InvalidDigestConfig);
+            }
+        }
+
+        for filter in [&self.input_filter, &self.output_filter].into_iter().flatten() {
+            filter.validate()?;
+        }
+
+        for transform in [&self.input_transform, &self.output_transform].into_iter().flatten() {
+            transform.validate()?;
+        }
+
+        if let Some(TransformExpression::HoltWinters { alpha, beta, gamma, .. }) = &self.output_transform {
+            if ![alpha, beta, gamma].iter().all(|factor| (0.0..=1.0).contains(*factor)) {
+                return Err(QueryError::InvalidSmoothingFactor);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate_for(&self, metric_type: &MetricType) -> Result<(), QueryError> {
+        self.validate()?;
+
+        match metric_type {
+            MetricType::Gauge => {}
+            MetricType::Count => {
+                if self.input_filter.is_some() || self.input_transform.is_some() {
+                    return Err(QueryError::InputExpressionNotSupported(metric_type.clone()));
+                }
+            }
+            MetricType::Ratio => {
+                if self.input_transform.is_some() {
+                    return Err(QueryError::InputTransformNotSupported(metric_type.clone()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn apply_output_transform(&self, value: ExpressionValue) -> Option<f64> {
         if let Some(filter) = &self.output_filter {
             if !filter.evaluate(&value).unwrap_or(false) {
@@ -192,6 +403,39 @@ impl Query {
             None => value.float()
         }
     }
+
+    pub fn apply_output_transform_in_windows(&self, mut values: TimeValues) -> TimeValues {
+        if let Some(operation) = &self.output_transform {
+            operation.apply_in_windows(&mut values);
+        }
+
+        values
+    }
+
+    /// Indicates if the value of a window depends on the previous windows.
+    pub fn depends_on_previous_windows(&self) -> bool {
+        matches!(&self.output_transform, Some(TransformExpression::HoltWinters { .. }))
+    }
+}
+
+/// Keeps the groups with the largest values, where windowed values are ranked by their sum.
+/// The remaining groups are combined into an `__other__` group if `other` is set, for the operations whose values can be combined
+/// (sum, max, min and counts).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct GroupLimit {
+    pub limit: usize,
+    #[serde(default)]
+    pub other: bool
+}
+
+impl GroupLimit {
+    pub fn new(limit: usize, other: bool) -> GroupLimit {
+        GroupLimit {
+            limit,
+            other
+        }
+    }
 }
 
 impl Default for Query {
@@ -200,22 +444,79 @@ impl Default for Query {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum QueryError {
+    #[error("the time range must be finite and end after it starts")]
+    InvalidTimeRange,
+    #[error("the staleness must not be negative")]
+    InvalidStaleness,
+    #[error("the group by key must contain at least one tag key")]
+    EmptyGroupKey,
+    #[error("grouping is not supported together with an or-and tags filter")]
+    GroupByWithOrAndFilter,
+    #[error("grouping by primary tag requires a group by with a single tag key")]
+    InvalidPrimaryTagGroupBy,
+    #[error("a group limit requires a group by")]
+    GroupLimitWithoutGroupBy,
+    #[error("the group limit must be positive")]
+    InvalidGroupLimit,
+    #[error("the digest size and buffer size must be positive")]
+    InvalidDigestConfig,
+    #[error("the smoothing factors must be between 0 and 1")]
+    InvalidSmoothingFactor,
+    #[error("the function {0:?} does not take {1} arguments")]
+    InvalidNumberOfArguments(Function, usize),
+    #[error("{0:?} metrics do not support input filters or transforms")]
+    InputExpressionNotSupported(MetricType),
+    #[error("{0:?} metrics do not support input transforms")]
+    InputTransformNotSupported(MetricType),
+    #[error("output transforms are not supported for the parts of a ratio")]
+    OutputTransformNotSupported
+}
+
 pub type MetricResult<T> = Result<T, MetricError>;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum MetricError {
-    FailedToCreateBaseDir(std::io::Error),
-    FailedToLoadMetric(std::io::Error),
+    #[error("failed to create base dir {0:?}: {1}")]
+    FailedToCreateBaseDir(PathBuf, std::io::Error),
+    #[error("failed to load metric at {0:?}: {1}")]
+    FailedToLoadMetric(PathBuf, std::io::Error),
+    #[error("failed to load config: {0}")]
     FailedToLoadConfig(std::io::Error),
+    #[error("failed to save config: {0}")]
     FailedToSaveConfig(std::io::Error),
+    #[error("memory file error: {0}")]
     MemoryFileError(MemoryFileError),
+    #[error("exceeded the maximum number of secondary tags")]
     ExceededSecondaryTags,
+    #[error("failed to save primary tag: {0}")]
     FailedToSavePrimaryTag(std::io::Error),
+    #[error("failed to load primary tag: {0}")]
     FailedToLoadPrimaryTag(std::io::Error),
+    #[error("failed to load secondary tags: {0}")]
     FailedToLoadSecondaryTag(std::io::Error),
-    FailedToCreateMetric(std::io::Error),
-    FailedToRemoveMetric(std::io::Error),
+    #[error("failed to create metric at {0:?}: {1}")]
+    FailedToCreateMetric(PathBuf, std::io::Error),
+    #[error("failed to remove metric file {0:?}: {1}")]
+    FailedToRemoveMetric(PathBuf, std::io::Error),
+    #[error("failed to move segment {0:?} to the cold tier: {1}")]
+    FailedToMoveSegment(PathBuf, std::io::Error),
+    #[error("failed to load block digests: {0}")]
+    FailedToLoadBlockDigests(std::io::Error),
+    #[error("failed to save block digests: {0}")]
+    FailedToSaveBlockDigests(std::io::Error),
+    #[error("failed to lock storage: {0}")]
+    FailedToLock(std::io::Error),
+    #[error("storage {0:?} is locked by another process")]
+    AlreadyLocked(PathBuf),
+    #[error("storage is opened read-only")]
+    ReadOnly,
+    #[error("storage {0:?} is corrupted: {1}")]
+    CorruptedStorage(PathBuf, String),
+    #[error("datapoint is older than the latest datapoint")]
     InvalidTimeOrder,
+    #[error("count is too large")]
     TooLargeCount
 }
 
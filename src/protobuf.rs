@@ -0,0 +1,114 @@
+//! The write batches accepted at `/write/pb`, the schema is in `proto/write.proto`.
+
+use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue};
+use crate::metric::common::CountInput;
+use crate::metric::ratio::RatioInput;
+use crate::metric::tags::Tag;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub series: Vec<WriteSeries>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum MetricKind {
+    Gauge = 0,
+    Count = 1,
+    Ratio = 2
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteSeries {
+    #[prost(string, tag = "1")]
+    pub metric: String,
+    #[prost(enumeration = "MetricKind", tag = "2")]
+    pub metric_type: i32,
+    #[prost(message, repeated, tag = "3")]
+    pub points: Vec<WritePoint>,
+    #[prost(message, repeated, tag = "4")]
+    pub tags: Vec<WriteTag>
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WritePoint {
+    #[prost(double, optional, tag = "1")]
+    pub time: Option<f64>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+    #[prost(uint32, tag = "4")]
+    pub denominator: u32,
+    #[prost(message, repeated, tag = "5")]
+    pub tags: Vec<WriteTag>
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteTag {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String
+}
+
+impl WriteSeries {
+    fn point_tags(&self, point: &WritePoint) -> Vec<Tag> {
+        self.tags.iter()
+            .chain(point.tags.iter())
+            .map(|tag| Tag(tag.key.clone(), tag.value.clone()))
+            .collect()
+    }
+
+    pub fn gauge_values(&self) -> impl Iterator<Item=AddGaugeValue> + '_ {
+        self.points.iter().map(|point| {
+            AddGaugeValue { time: point.time, value: point.value, tags: self.point_tags(point) }
+        })
+    }
+
+    pub fn count_values(&self) -> impl Iterator<Item=AddCountValue> + '_ {
+        self.points.iter().map(|point| {
+            AddCountValue { time: point.time, count: CountInput(point.count), tags: self.point_tags(point) }
+        })
+    }
+
+    pub fn ratio_values(&self) -> impl Iterator<Item=AddRatioValue> + '_ {
+        self.points.iter().map(|point| {
+            AddRatioValue {
+                time: point.time,
+                ratio: RatioInput(CountInput(point.count), CountInput(point.denominator)),
+                tags: self.point_tags(point)
+            }
+        })
+    }
+}
+
+#[test]
+fn test_decode1() {
+    use prost::Message;
+
+    let batch = WriteBatch {
+        series: vec![
+            WriteSeries {
+                metric: "cpu".to_owned(),
+                metric_type: MetricKind::Gauge as i32,
+                points: vec![
+                    WritePoint { time: Some(1654077600.0), value: 1.5, count: 0, denominator: 0, tags: vec![WriteTag { key: "core".to_owned(), value: "1".to_owned() }] },
+                    WritePoint { time: None, value: 2.5, count: 0, denominator: 0, tags: Vec::new() }
+                ],
+                tags: vec![WriteTag { key: "host".to_owned(), value: "a".to_owned() }]
+            }
+        ]
+    };
+
+    let decoded = WriteBatch::decode(batch.encode_to_vec().as_slice()).unwrap();
+    assert_eq!(batch, decoded);
+    assert_eq!(MetricKind::Gauge, decoded.series[0].metric_type());
+
+    let values = decoded.series[0].gauge_values().collect::<Vec<_>>();
+    assert_eq!(Some(1654077600.0), values[0].time);
+    assert_eq!(vec![Tag::from_ref("host", "a"), Tag::from_ref("core", "1")], values[0].tags);
+    assert_eq!(None, values[1].time);
+    assert_eq!(2.5, values[1].value);
+}
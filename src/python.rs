@@ -0,0 +1,263 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use numpy::PyArray1;
+use pyo3::prelude::*;
+use pyo3::IntoPyObjectExt;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::types::{PyDict, PyTuple};
+
+use serde::de::DeserializeOwned;
+
+use crate::engine::{MetricsEngine, MetricsEngineBuilder};
+use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue, MetricsEngineError};
+use crate::engine::querying::{MetricQuery, MetricQueryExpression};
+use crate::metric::common::{CountInput, MetricType};
+use crate::metric::{OperationResult, TimeValues};
+use crate::metric::ratio::RatioInput;
+use crate::metric::tags::Tag;
+use crate::model::TimeRange;
+
+#[pyclass(name = "MetricsEngine")]
+struct PyMetricsEngine {
+    metrics_engine: Arc<MetricsEngine>
+}
+
+#[pymethods]
+impl PyMetricsEngine {
+    #[staticmethod]
+    fn create(path: &str) -> PyResult<PyMetricsEngine> {
+        let metrics_engine = MetricsEngine::new(Path::new(path)).map_err(engine_error)?;
+        Ok(PyMetricsEngine { metrics_engine: Arc::new(metrics_engine) })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (path, read_only=false))]
+    fn open(path: &str, read_only: bool) -> PyResult<PyMetricsEngine> {
+        let metrics_engine = MetricsEngineBuilder::new(Path::new(path))
+            .read_only(read_only)
+            .build()
+            .map_err(engine_error)?;
+        Ok(PyMetricsEngine { metrics_engine: Arc::new(metrics_engine) })
+    }
+
+    fn add_metric(&self, name: &str, metric_type: &str) -> PyResult<()> {
+        let metric_type: MetricType = from_str_value(metric_type)?;
+        self.metrics_engine.add_metric(name, metric_type).map_err(engine_error)
+    }
+
+    #[pyo3(signature = (name, times, values, tags=Vec::new()))]
+    fn add_gauge(&self, name: &str, times: Vec<f64>, values: Vec<f64>, tags: Vec<String>) -> PyResult<usize> {
+        check_lengths(times.len(), values.len())?;
+        let tags = parse_tags(tags)?;
+
+        let values = times
+            .into_iter()
+            .zip(values)
+            .map(|(time, value)| AddGaugeValue::new(time, value, tags.clone()));
+        self.metrics_engine.gauge(name, values).map_err(engine_error)
+    }
+
+    #[pyo3(signature = (name, times, counts, tags=Vec::new()))]
+    fn add_count(&self, name: &str, times: Vec<f64>, counts: Vec<u32>, tags: Vec<String>) -> PyResult<usize> {
+        check_lengths(times.len(), counts.len())?;
+        let tags = parse_tags(tags)?;
+
+        let values = times
+            .into_iter()
+            .zip(counts)
+            .map(|(time, count)| AddCountValue::new(time, CountInput(count), tags.clone()));
+        self.metrics_engine.count(name, values).map_err(engine_error)
+    }
+
+    #[pyo3(signature = (name, times, numerators, denominators, tags=Vec::new()))]
+    fn add_ratio(&self,
+                 name: &str,
+                 times: Vec<f64>,
+                 numerators: Vec<u32>,
+                 denominators: Vec<u32>,
+                 tags: Vec<String>) -> PyResult<usize> {
+        check_lengths(times.len(), numerators.len())?;
+        check_lengths(times.len(), denominators.len())?;
+        let tags = parse_tags(tags)?;
+
+        let values = times
+            .into_iter()
+            .zip(numerators.into_iter().zip(denominators))
+            .map(|(time, (numerator, denominator))| {
+                AddRatioValue::new(time, RatioInput(CountInput(numerator), CountInput(denominator)), tags.clone())
+            });
+        self.metrics_engine.ratio(name, values).map_err(engine_error)
+    }
+
+    /// The expression is given as JSON, in the same format as for the query endpoint of the server.
+    #[pyo3(signature = (expression, start, end, duration=None))]
+    fn query(&self, py: Python<'_>, expression: &str, start: f64, end: f64, duration: Option<f64>) -> PyResult<Py<PyAny>> {
+        let expression: MetricQueryExpression = serde_json::from_str(expression)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        let time_range = TimeRange::try_new(start, end).map_err(|err| PyValueError::new_err(err.to_string()))?;
+        let query = MetricQuery::new(time_range, expression);
+
+        let metrics_engine = self.metrics_engine.clone();
+        let result = py.detach(move || {
+            match duration {
+                Some(duration) => {
+                    let duration = Duration::try_from_secs_f64(duration)
+                        .map_err(|_| MetricsEngineError::InvalidQueryInput("The window duration is not valid.".to_owned()))?;
+                    metrics_engine.query_in_window(query, duration)
+                }
+                None => metrics_engine.query(query)
+            }
+        });
+
+        operation_result_to_python(py, result.map_err(engine_error)?)
+    }
+}
+
+/// Windowed results are returned as (times, values) numpy arrays where missing values are NaN.
+fn operation_result_to_python(py: Python<'_>, result: OperationResult) -> PyResult<Py<PyAny>> {
+    match result {
+        OperationResult::NotSupported => Err(PyValueError::new_err("not supported operation")),
+        OperationResult::Value(value) => value.into_py_any(py),
+        OperationResult::TimeValues(values) => Ok(time_values_to_python(py, values)?.into_any().unbind()),
+        OperationResult::GroupValues(values) => {
+            let groups = PyDict::new(py);
+            for (group, value) in values {
+                groups.set_item(group.0.join(","), value)?;
+            }
+
+            Ok(groups.into_any().unbind())
+        }
+        OperationResult::GroupTimeValues(values) => {
+            let groups = PyDict::new(py);
+            for (group, values) in values {
+                groups.set_item(group.0.join(","), time_values_to_python(py, values)?)?;
+            }
+
+            Ok(groups.into_any().unbind())
+        }
+    }
+}
+
+fn time_values_to_python(py: Python<'_>, values: TimeValues) -> PyResult<Bound<'_, PyTuple>> {
+    let (times, values): (Vec<f64>, Vec<f64>) = values
+        .into_iter()
+        .map(|(time, value)| (time, value.unwrap_or(f64::NAN)))
+        .unzip();
+
+    PyTuple::new(py, [PyArray1::from_vec(py, times), PyArray1::from_vec(py, values)])
+}
+
+fn check_lengths(num_times: usize, num_values: usize) -> PyResult<()> {
+    if num_times != num_values {
+        return Err(PyValueError::new_err("the number of times and values must be the same"));
+    }
+
+    Ok(())
+}
+
+fn parse_tags(tags: Vec<String>) -> PyResult<Vec<Tag>> {
+    tags.into_iter().map(|tag| from_str_value(&tag)).collect()
+}
+
+fn from_str_value<T: DeserializeOwned>(value: &str) -> PyResult<T> {
+    serde_json::from_value(serde_json::Value::String(value.to_owned()))
+        .map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+fn engine_error(err: MetricsEngineError) -> PyErr {
+    match err {
+        MetricsEngineError::InvalidQueryInput(_) | MetricsEngineError::InvalidQuery(_) | MetricsEngineError::InvalidInput(_) => {
+            PyValueError::new_err(err.to_string())
+        }
+        err => PyRuntimeError::new_err(err.to_string())
+    }
+}
+
+#[pymodule]
+fn metricsdb(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyMetricsEngine>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_engine() -> (tempfile::TempDir, PyMetricsEngine) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let engine = PyMetricsEngine::create(temp_dir.path().to_str().unwrap()).unwrap();
+    engine.add_metric("cpu", "Gauge").unwrap();
+
+    let start_time = 1654077600.0;
+    for offset in 0..10 {
+        for (index, host) in ["h1", "h2"].into_iter().enumerate() {
+            engine.add_gauge("cpu", vec![start_time + offset as f64], vec![1.0 + index as f64], vec![format!("host:{}", host)]).unwrap();
+        }
+    }
+
+    (temp_dir, engine)
+}
+
+#[test]
+fn test_python1() {
+    Python::initialize();
+    Python::attach(|py| {
+        let (_temp_dir, engine) = test_engine();
+
+        let result = engine.query(py, r#"{"Average": {"metric": "cpu", "query": {}}}"#, 1654077600.0, 1654077610.0, None).unwrap();
+        assert_eq!(1.5, result.extract::<f64>(py).unwrap());
+
+        let result = engine.query(
+            py,
+            r#"{"Max": {"metric": "cpu", "query": {"group_by": "host"}}}"#,
+            1654077600.0, 1654077610.0, None
+        ).unwrap();
+        let result = result.bind(py).cast::<PyDict>().unwrap();
+        assert_eq!(2, result.len());
+        assert_eq!(1.0, result.get_item("h1").unwrap().unwrap().extract::<f64>().unwrap());
+        assert_eq!(2.0, result.get_item("h2").unwrap().unwrap().extract::<f64>().unwrap());
+    });
+}
+
+#[test]
+fn test_python2() {
+    Python::initialize();
+    Python::attach(|py| {
+        let (_temp_dir, engine) = test_engine();
+
+        let err = engine.add_gauge("cpu", vec![1654077600.0], vec![1.0, 2.0], Vec::new()).unwrap_err();
+        assert!(err.is_instance_of::<PyValueError>(py));
+
+        let err = engine.add_gauge("memory", vec![1654077600.0], vec![1.0], Vec::new()).unwrap_err();
+        assert!(err.is_instance_of::<PyRuntimeError>(py));
+
+        let err = engine.add_metric("memory", "Histogram").unwrap_err();
+        assert!(err.is_instance_of::<PyValueError>(py));
+
+        let err = engine.query(py, r#"{"Average": {"metric": "cpu"}"#, 1654077600.0, 1654077610.0, None).unwrap_err();
+        assert!(err.is_instance_of::<PyValueError>(py));
+    });
+}
+
+#[test]
+fn test_python3() {
+    use numpy::PyArrayMethods;
+
+    Python::initialize();
+    Python::attach(|py| {
+        // The windowed results are numpy arrays, which requires numpy to be installed.
+        if py.import("numpy").is_err() {
+            return;
+        }
+
+        let (_temp_dir, engine) = test_engine();
+
+        let result = engine.query(
+            py,
+            r#"{"Average": {"metric": "cpu", "query": {}}}"#,
+            1654077600.0, 1654077610.0, Some(5.0)
+        ).unwrap();
+        let (times, values) = result.extract::<(Bound<'_, PyArray1<f64>>, Bound<'_, PyArray1<f64>>)>(py).unwrap();
+        assert_eq!(vec![1654077605.0, 1654077610.0], times.to_vec().unwrap());
+        assert_eq!(vec![1.5, 1.5], values.to_vec().unwrap());
+    });
+}
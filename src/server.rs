@@ -1,26 +1,80 @@
+use std::collections::{BTreeMap, HashMap};
 use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use serde_json::json;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use utoipa::{IntoParams, ToSchema};
+use prost::Message;
 
-use tokio::time;
-
-use axum::extract::{Path, State};
+use axum::body::{Body, Bytes};
+use axum::extract::{Extension, Path, Query, State};
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
 use axum::{Json, Router};
-use axum::http::StatusCode;
-use axum::routing::{post, put};
-
-use crate::engine::MetricsEngine;
-use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue, MetricsEngineError};
-use crate::engine::querying::{MetricQuery, MetricQueryExpression};
-use crate::metric::common::{MetricConfig, MetricType, MetricStorageDurationConfig};
-use crate::metric::OperationResult;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::routing::{delete, get, post, put};
+
+use tracing::Instrument;
+
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+
+use crate::engine::{MetricsEngine, MetricsEngineBuilder};
+use crate::engine::annotations::Annotation;
+use crate::engine::dashboards::Dashboard;
+use crate::engine::scheduler;
+use crate::engine::scheduler::SchedulerConfig;
+use crate::engine::limits::QueryLimits;
+use crate::engine::quotas::WriteQuotas;
+use crate::engine::disk::DiskWatchdogConfig;
+use crate::engine::verification;
+use crate::engine::snapshots;
+use crate::engine::snapshots::SnapshotConfig;
+use crate::engine::tiering;
+use crate::engine::tiering::TieringConfig;
+use crate::engine::templates::{MetricTemplate, UnknownMetricMode};
+use crate::engine::clock_skew::ClockSkewTolerance;
+use crate::engine::preaggregation;
+use crate::engine::preaggregation::PreAggregationConfig;
+use crate::engine::sampling::SamplingRule;
+use crate::engine::slow_queries::SlowQueryConfig;
+use crate::engine::lock_watchdog::LockWatchdogConfig;
+use crate::engine::trash::TrashConfig;
+use crate::engine::tenants::TenantsConfig;
+#[cfg(feature = "system-metrics")]
+use crate::engine::system_metrics::{self, SystemMetricsConfig};
+use crate::engine::schema::{Schema, SchemaChanges};
+use crate::engine::window_cache::WindowCacheConfig;
+use crate::engine::access::{Access, AccessPolicies};
+#[cfg(test)]
+use crate::engine::access::MetricAccessRule;
+use crate::engine::audit::{AuditAction, AuditEntry, AuditQuery};
+use crate::engine::disk::DiskSpace;
+use crate::engine::lock_watchdog::LockStats;
+use crate::engine::quotas::QuotaUsage;
+use crate::engine::slow_queries::SlowQuery;
+use crate::engine::tenants::TenantUsage;
+use crate::engine::trash::DeletedMetric;
+use crate::engine::verification::VerificationStatus;
+use crate::engine::verification::VerificationConfig;
+use crate::engine::io::{AddCountValue, AddGaugeValue, AddRatioValue, LoadingStatus, MetricsEngineError};
+use crate::engine::querying;
+use crate::engine::querying::{Alignment, Downsampling, GroupJoin, MetricQuery, MetricQueryExpression, Relabeling};
+use crate::metric::common::{GaugeCollision, MetricType, MetricStorageDurationConfig, RollupConfig};
+use crate::metric::operations::{DigestConfig, PercentileAlgorithm};
+use crate::metric::{JsonOptions, OperationResult, WithOptions};
+use crate::metric::expression::FilterExpression;
 use crate::metric::tags::{PrimaryTag, Tag};
-use crate::model::{TimeRange};
+use crate::model::{self, MetricError, ReadConsistency, TimeRange};
+use crate::protobuf;
+use crate::webhooks::{WebhookConfig, WebhookDispatcher, WebhookEvent};
+
+mod openapi;
 
 pub async fn main() {
     let arguments = std::env::args().collect::<Vec<_>>();
@@ -31,50 +85,164 @@ pub async fn main() {
         Config::default()
     };
 
+    setup_logging(&config.logging);
+
     let app_state = Arc::new(AppState::new(&config));
-    let app = Router::with_state(app_state.clone())
-        .route("/metrics/gauge", post(create_gauge_metric))
-        .route("/metrics/gauge/:name", put(add_gauge_metric_value))
+    let app = router(app_state.clone());
 
-        .route("/metrics/count", post(create_count_metric))
-        .route("/metrics/count/:name", put(add_count_metric_value))
+    if config.warm_metrics {
+        let metrics_engine = app_state.metrics_engine.clone();
+        let num_threads = config.warm_threads.unwrap_or_else(|| std::thread::available_parallelism().map(|num| num.get()).unwrap_or(1));
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = metrics_engine.warm(num_threads) {
+                tracing::error!(error = %err, "failed to load metrics");
+            }
+        });
+    }
 
-        .route("/metrics/ratio", post(create_ratio_metric))
-        .route("/metrics/ratio/:name", put(add_ratio_metric_value))
+    let scheduler = scheduler::spawn_scheduler(app_state.metrics_engine.clone(), app_state.metrics_engine.scheduler_config().clone());
 
-        .route("/metrics/query", post(metric_query))
+    let mut background_threads = Vec::new();
+    if config.verification.enabled {
+        let verification_state = app_state.clone();
+        background_threads.push(verification::spawn_verification_thread(
+            app_state.metrics_engine.clone(),
+            config.verification.clone(),
+            move |metric, problems| {
+                verification_state.webhooks.notify(WebhookEvent::new("verification_failed", metric, json!({ "problems": problems })));
+            }
+        ));
+    }
 
-        .route("/metrics/primary-tag/:name", post(add_primary_tag))
-        .route("/metrics/auto-primary-tag/:name", post(add_auto_primary_tag))
-    ;
+    if config.snapshots.enabled {
+        background_threads.push(snapshots::spawn_snapshot_thread(app_state.metrics_engine.clone(), config.snapshots.clone()));
+    }
 
-    tokio::spawn(async move {
-        let mut duration = time::interval(Duration::from_secs_f64(0.25));
-        loop {
-            duration.tick().await;
-            app_state.metrics_engine.scheduled();
-        }
-    });
+    if config.tiering.is_enabled() {
+        background_threads.push(tiering::spawn_tiering_thread(app_state.metrics_engine.clone(), config.tiering.clone()));
+    }
+
+    if config.ingestion.pre_aggregation.is_enabled() {
+        background_threads.push(preaggregation::spawn_pre_aggregation_thread(app_state.metrics_engine.clone(), config.ingestion.pre_aggregation.clone()));
+    }
+
+    #[cfg(feature = "system-metrics")]
+    if config.system_metrics.enabled {
+        background_threads.push(system_metrics::spawn_system_metrics_thread(app_state.metrics_engine.clone(), config.system_metrics.clone()));
+    }
 
     let address = SocketAddr::new(Ipv4Addr::from_str(&config.bind_url).unwrap().into(), config.bind_port);
-    println!("Listening on {}", address);
+    tracing::info!("Listening on {}", address);
     tokio::select! {
         result = axum::Server::bind(&address).serve(app.into_make_service()) => {
             result.unwrap();
         }
         _ = tokio::signal::ctrl_c() => {
-            println!("Shutting down...");
-            return;
+            tracing::info!("Shutting down...");
+            scheduler.abort();
+
+            // The background threads are stopped before the final flush, such that they can't write afterwards
+            let metrics_engine = app_state.metrics_engine.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                background_threads.clear();
+                metrics_engine.shutdown()
+            }).await;
+
+            if let Ok(Err(err)) = result {
+                tracing::error!(error = %err, "failed to flush metrics");
+            }
         }
     }
 }
 
+fn router(app_state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/metrics/gauge", post(create_gauge_metric))
+        .route("/metrics/gauge/:name", put(add_gauge_metric_value))
+
+        .route("/metrics/count", post(create_count_metric))
+        .route("/metrics/count/:name", put(add_count_metric_value))
+
+        .route("/metrics/ratio", post(create_ratio_metric))
+        .route("/metrics/ratio/:name", put(add_ratio_metric_value))
+
+        .route("/metrics/query", post(metric_query))
+        .route("/metrics/query/stream", post(metric_query_stream))
+        .route("/metrics/query/aggregate", post(metric_query_aggregate))
+        .route("/metrics/query/ratio", post(metric_query_ratio))
+        .route("/metrics/query/percent-of-total", post(metric_query_percent_of_total))
+        .route("/metrics/query/delta", post(metric_query_delta))
+        .route("/query/batch", post(batch_query))
+
+        .route("/metrics/primary-tag/:name", post(add_primary_tag_legacy))
+        .route("/metrics/auto-primary-tag/:name", post(add_auto_primary_tag_legacy))
+        .route("/metrics/:name/primary-tags", get(list_primary_tags).post(add_primary_tag))
+        .route("/metrics/:name/primary-tags/:tag", delete(remove_primary_tag))
+        .route("/metrics/:name/auto-primary-tags", get(list_auto_primary_tags).post(add_auto_primary_tag))
+        .route("/metrics/:name/auto-primary-tags/:key", delete(remove_auto_primary_tag))
+        .route("/metrics/:name/flush", post(flush_metric))
+        .route("/metrics/:name", delete(delete_metric))
+        .route("/metrics/:name/undelete", post(undelete_metric))
+        .route("/metrics/deleted", get(list_deleted_metrics))
+
+        .route("/write/pb", post(write_protobuf))
+
+        .route("/status", get(status))
+
+        .route("/annotations", put(add_annotation))
+        .route("/annotations/query", post(query_annotations))
+
+        .route("/webhooks/test", post(test_webhooks))
+
+        .route("/snapshots", post(create_snapshot))
+        .route("/snapshots/pre", post(pre_snapshot))
+        .route("/snapshots/post", post(post_snapshot))
+
+        .route("/dashboards", get(list_dashboards))
+        .route("/dashboards/:name", get(get_dashboard).put(put_dashboard).delete(delete_dashboard))
+
+        .route("/slow-queries", get(slow_queries))
+
+        .route("/admin/compact", post(compact))
+        .route("/admin/audit", get(audit_log))
+        .route("/admin/schema", get(export_schema).put(apply_schema))
+        .route("/admin/schema/diff", post(diff_schema))
+
+        .route("/api-docs", get(api_docs))
+
+        .layer(middleware::from_fn(request_tracing))
+        .with_state(app_state)
+}
+
 #[derive(Deserialize)]
 #[serde(default)]
 struct Config {
     bind_url: String,
     bind_port: u16,
-    storage_folder: String
+    storage_folder: String,
+    warm_metrics: bool,
+    warm_threads: Option<usize>,
+    scheduler: SchedulerConfig,
+    query_limits: QueryLimits,
+    write_quotas: WriteQuotas,
+    disk_watchdog: DiskWatchdogConfig,
+    verification: VerificationConfig,
+    snapshots: SnapshotConfig,
+    tiering: TieringConfig,
+    templates: Vec<MetricTemplate>,
+    /// Writes to metrics that do not exist always fail, same as an `unknown_metrics` of `Fail` for ingestion.
+    strict: bool,
+    ingestion: IngestionConfig,
+    slow_queries: SlowQueryConfig,
+    window_cache: WindowCacheConfig,
+    lock_watchdog: LockWatchdogConfig,
+    trash: TrashConfig,
+    tenants: TenantsConfig,
+    #[cfg(feature = "system-metrics")]
+    system_metrics: SystemMetricsConfig,
+    access: AccessPolicies,
+    webhooks: WebhookConfig,
+    logging: LoggingConfig
 }
 
 impl Default for Config {
@@ -82,77 +250,289 @@ impl Default for Config {
         Config {
             bind_url: "127.0.0.1".to_string(),
             bind_port: 9090,
-            storage_folder: "server_storage".to_string()
+            storage_folder: "server_storage".to_string(),
+            warm_metrics: false,
+            warm_threads: None,
+            scheduler: SchedulerConfig::default(),
+            query_limits: QueryLimits::default(),
+            write_quotas: WriteQuotas::default(),
+            disk_watchdog: DiskWatchdogConfig::default(),
+            verification: VerificationConfig::default(),
+            snapshots: SnapshotConfig::default(),
+            tiering: TieringConfig::default(),
+            templates: Vec::new(),
+            strict: false,
+            ingestion: IngestionConfig::default(),
+            slow_queries: SlowQueryConfig::default(),
+            window_cache: WindowCacheConfig::default(),
+            lock_watchdog: LockWatchdogConfig::default(),
+            trash: TrashConfig::default(),
+            tenants: TenantsConfig::default(),
+            #[cfg(feature = "system-metrics")]
+            system_metrics: SystemMetricsConfig::default(),
+            access: AccessPolicies::default(),
+            webhooks: WebhookConfig::default(),
+            logging: LoggingConfig::default()
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+struct IngestionConfig {
+    unknown_metrics: UnknownMetricMode,
+    /// Overrides the mode for writes with the given API key (sent in the `X-Api-Key` header).
+    api_keys: HashMap<String, UnknownMetricMode>,
+    clock_skew: ClockSkewTolerance,
+    pre_aggregation: PreAggregationConfig,
+    sampling_rules: Vec<SamplingRule>
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct LoggingConfig {
+    level: String,
+    json: bool,
+    log_span_durations: bool
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            json: false,
+            log_span_durations: false
         }
     }
 }
 
+fn setup_logging(config: &LoggingConfig) {
+    // RUST_LOG takes precedence over the configured level
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let span_events = if config.log_span_durations { FmtSpan::CLOSE } else { FmtSpan::NONE };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(span_events);
+
+    if config.json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Clone)]
+struct RequestId(String);
+
+/// Assigns each request an ID (or uses the one in the `X-Request-Id` header), which is included in the spans of the request and returned in the response.
+async fn request_tracing<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .map(|request_id| request_id.to_owned())
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    let span = tracing::info_span!("request", request_id = request_id.as_str(), method = %request.method(), path = request.uri().path());
+    request.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+
+    response
+}
+
 pub type ServerResult<T> = Result<T, MetricsEngineError>;
 
+#[derive(Serialize, ToSchema)]
+struct ErrorMessage {
+    message: String
+}
+
 impl IntoResponse for MetricsEngineError {
     fn into_response(self) -> Response {
-        let (status_code, error_message) = match self {
-            MetricsEngineError::FailedToCreateBaseDir(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create base dir due to: {}", err)),
-            MetricsEngineError::FailedToLoadMetricDefinitions(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load metrics definitions due to: {}", err)),
-            MetricsEngineError::FailedToSaveMetricDefinitions(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save metrics definitions due to: {}", err)),
-            MetricsEngineError::MetricAlreadyExists => (StatusCode::BAD_REQUEST, format!("Metrics already exist.")),
-            MetricsEngineError::MetricNotFound => (StatusCode::NOT_FOUND, format!("Metric not found.")),
-            MetricsEngineError::WrongMetricType => (StatusCode::BAD_REQUEST, format!("Wrong metric type.")),
-            MetricsEngineError::UnexpectedResult => (StatusCode::BAD_REQUEST, format!("Unexpected result.")),
-            MetricsEngineError::Metric(err) => (StatusCode::BAD_REQUEST, format!("Metric error: {:?}", err))
-        };
+        with_response_code(Json(ErrorMessage { message: self.to_string() }).into_response(), error_status_code(&self))
+    }
+}
 
-        with_response_code(
-            Json(
-                json!({
-                    "message": error_message
-                })
-            ).into_response(),
-            status_code
-        )
+fn error_status_code(error: &MetricsEngineError) -> StatusCode {
+    match error {
+        MetricsEngineError::MetricNotFound(_) => StatusCode::NOT_FOUND,
+        MetricsEngineError::DashboardNotFound(_) => StatusCode::NOT_FOUND,
+        MetricsEngineError::MetricAlreadyExists(_) => StatusCode::CONFLICT,
+        MetricsEngineError::WrongMetricType(_) => StatusCode::BAD_REQUEST,
+        MetricsEngineError::UnexpectedResult => StatusCode::BAD_REQUEST,
+        MetricsEngineError::InvalidQueryInput(_) => StatusCode::BAD_REQUEST,
+        MetricsEngineError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        MetricsEngineError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+        MetricsEngineError::ReadOnly => StatusCode::CONFLICT,
+        MetricsEngineError::AccessDenied(_) => StatusCode::FORBIDDEN,
+        MetricsEngineError::TooManyConcurrentQueries => StatusCode::SERVICE_UNAVAILABLE,
+        MetricsEngineError::QueryTooLarge { .. } => StatusCode::BAD_REQUEST,
+        MetricsEngineError::TooManyGroups { .. } => StatusCode::BAD_REQUEST,
+        MetricsEngineError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+        MetricsEngineError::TenantQuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+        MetricsEngineError::LowDiskSpace => StatusCode::INSUFFICIENT_STORAGE,
+        MetricsEngineError::Metric(err) => {
+            match err {
+                MetricError::ExceededSecondaryTags => StatusCode::BAD_REQUEST,
+                MetricError::InvalidTimeOrder => StatusCode::BAD_REQUEST,
+                MetricError::TooLargeCount => StatusCode::BAD_REQUEST,
+                MetricError::AlreadyLocked(_) => StatusCode::CONFLICT,
+                MetricError::ReadOnly => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+        MetricsEngineError::FailedToCreateBaseDir(_, _)
+        | MetricsEngineError::FailedToLoadMetricDefinitions(_)
+        | MetricsEngineError::FailedToSaveMetricDefinitions(_)
+        | MetricsEngineError::FailedToLoadAnnotations(_)
+        | MetricsEngineError::FailedToSaveAnnotations(_)
+        | MetricsEngineError::FailedToLoadDashboards(_)
+        | MetricsEngineError::FailedToSaveDashboards(_)
+        | MetricsEngineError::FailedToCreateSnapshot(_, _)
+        | MetricsEngineError::FailedToDeleteMetric(_, _)
+        | MetricsEngineError::FailedToUndeleteMetric(_, _)
+        | MetricsEngineError::InvalidConfig(_) => StatusCode::INTERNAL_SERVER_ERROR
     }
 }
 
 struct AppState {
-    metrics_engine: MetricsEngine
+    metrics_engine: Arc<MetricsEngine>,
+    webhooks: WebhookDispatcher,
+    snapshots: SnapshotConfig,
+    ingestion: IngestionConfig,
+    access: AccessPolicies
 }
 
 impl AppState {
     pub fn new(config: &Config) -> AppState {
+        let mut ingestion = config.ingestion.clone();
+        if config.strict {
+            ingestion.unknown_metrics = UnknownMetricMode::Fail;
+        }
+
         AppState {
-            metrics_engine: MetricsEngine::new_or_from_existing(std::path::Path::new(&config.storage_folder)).unwrap()
+            metrics_engine: Arc::new(
+                MetricsEngineBuilder::new(std::path::Path::new(&config.storage_folder))
+                    .with_scheduler(config.scheduler.clone())
+                    .with_query_limits(config.query_limits.clone())
+                    .with_write_quotas(config.write_quotas.clone())
+                    .with_disk_watchdog(config.disk_watchdog.clone())
+                    .with_tiering(config.tiering.clone())
+                    .with_templates(config.templates.clone())
+                    .with_unknown_metrics(ingestion.unknown_metrics)
+                    .with_clock_skew_tolerance(config.ingestion.clock_skew.clone())
+                    .with_pre_aggregation(config.ingestion.pre_aggregation.clone())
+                    .with_sampling_rules(config.ingestion.sampling_rules.clone())
+                    .with_slow_query_log(config.slow_queries.clone())
+                    .with_window_cache(config.window_cache.clone())
+                    .with_lock_watchdog(config.lock_watchdog.clone())
+                    .with_trash(config.trash.clone())
+                    .with_tenants(config.tenants.clone())
+                    .build()
+                    .unwrap()
+            ),
+            webhooks: WebhookDispatcher::spawn(config.webhooks.clone()),
+            snapshots: config.snapshots.clone(),
+            ingestion,
+            access: config.access.clone()
         }
     }
+
+    fn unknown_metric_mode(&self, headers: &HeaderMap) -> UnknownMetricMode {
+        api_key(headers)
+            .and_then(|api_key| self.ingestion.api_keys.get(api_key))
+            .copied()
+            .unwrap_or(self.ingestion.unknown_metrics)
+    }
+
+    fn authorize(&self, headers: &HeaderMap, metric: &str, access: Access) -> ServerResult<()> {
+        self.access.check(api_key(headers), metric, access)?;
+        Ok(())
+    }
+
+    fn audit(&self,
+             headers: &HeaderMap,
+             request_id: &RequestId,
+             action: AuditAction,
+             metric: Option<&str>,
+             details: serde_json::Value) {
+        self.metrics_engine.audit(
+            AuditEntry::new(action, metric, details)
+                .with_principal(self.access.principal(api_key(headers)))
+                .with_request_id(Some(request_id.0.clone()))
+        );
+    }
 }
 
-#[derive(Deserialize)]
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers.get("x-api-key").and_then(|api_key| api_key.to_str().ok())
+}
+
+#[derive(Deserialize, ToSchema)]
 struct CreateMetric {
     name: String,
     datapoint_duration: Option<f64>,
     data_keep_time: Option<f64>,
-    faster_duration: Option<FasterDuration>
+    faster_duration: Option<FasterDuration>,
+    staleness: Option<f64>,
+    digest: Option<DigestConfig>,
+    percentile_algorithm: Option<PercentileAlgorithm>,
+    #[serde(default)]
+    pinned: bool,
+    gauge_collision: Option<GaugeCollision>,
+    cold_compression_level: Option<i32>,
+    #[serde(default)]
+    rollups: Vec<RollupConfig>
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct FasterDuration {
     datapoint_duration: f64,
     data_keep_time: f64,
 }
 
-async fn create_gauge_metric(State(state): State<Arc<AppState>>, Json(input): Json<CreateMetric>) -> ServerResult<Response> {
-    create_metric(state, input, MetricType::Gauge)
+/// Creates a new gauge metric.
+#[utoipa::path(post, path = "/metrics/gauge", request_body = CreateMetric, responses((status = 200, description = "The metric was created.")))]
+async fn create_gauge_metric(State(state): State<Arc<AppState>>,
+                            headers: HeaderMap,
+                            Extension(request_id): Extension<RequestId>,
+                            Json(input): Json<CreateMetric>) -> ServerResult<Response> {
+    state.authorize(&headers, &input.name, Access::Write)?;
+    create_metric(state, &headers, &request_id, input, MetricType::Gauge)
 }
 
-async fn create_count_metric(State(state): State<Arc<AppState>>, Json(input): Json<CreateMetric>) -> ServerResult<Response> {
-    create_metric(state, input, MetricType::Count)
+/// Creates a new count metric.
+#[utoipa::path(post, path = "/metrics/count", request_body = CreateMetric, responses((status = 200, description = "The metric was created.")))]
+async fn create_count_metric(State(state): State<Arc<AppState>>,
+                            headers: HeaderMap,
+                            Extension(request_id): Extension<RequestId>,
+                            Json(input): Json<CreateMetric>) -> ServerResult<Response> {
+    state.authorize(&headers, &input.name, Access::Write)?;
+    create_metric(state, &headers, &request_id, input, MetricType::Count)
 }
 
-async fn create_ratio_metric(State(state): State<Arc<AppState>>, Json(input): Json<CreateMetric>) -> ServerResult<Response> {
-    create_metric(state, input, MetricType::Ratio)
+/// Creates a new ratio metric.
+#[utoipa::path(post, path = "/metrics/ratio", request_body = CreateMetric, responses((status = 200, description = "The metric was created.")))]
+async fn create_ratio_metric(State(state): State<Arc<AppState>>,
+                            headers: HeaderMap,
+                            Extension(request_id): Extension<RequestId>,
+                            Json(input): Json<CreateMetric>) -> ServerResult<Response> {
+    state.authorize(&headers, &input.name, Access::Write)?;
+    create_metric(state, &headers, &request_id, input, MetricType::Ratio)
 }
 
-fn create_metric(state: Arc<AppState>, input: CreateMetric, metric_type: MetricType) -> ServerResult<Response> {
-    let mut config = MetricConfig::new(metric_type.clone());
+fn create_metric(state: Arc<AppState>,
+                 headers: &HeaderMap,
+                 request_id: &RequestId,
+                 input: CreateMetric,
+                 metric_type: MetricType) -> ServerResult<Response> {
+    let mut config = state.metrics_engine.default_metric_config(&input.name, metric_type.clone());
     if let Some(datapoint_duration) = input.datapoint_duration {
         config.durations[0].datapoint_duration = datapoint_duration;
     }
@@ -168,117 +548,1823 @@ fn create_metric(state: Arc<AppState>, input: CreateMetric, metric_type: MetricT
         config.durations.push(duration);
     }
 
+    if let Some(staleness) = input.staleness {
+        config.staleness = staleness;
+    }
+
+    if let Some(digest) = input.digest {
+        if !digest.is_valid() {
+            return Err(MetricsEngineError::InvalidInput("The digest size and buffer size must be positive.".to_owned()));
+        }
+
+        config.digest = digest;
+    }
+
+    if let Some(percentile_algorithm) = input.percentile_algorithm {
+        config.percentile_algorithm = percentile_algorithm;
+    }
+
+    config.pinned = input.pinned;
+
+    if let Some(gauge_collision) = input.gauge_collision {
+        config.gauge_collision = gauge_collision;
+    }
+
+    if let Some(cold_compression_level) = input.cold_compression_level {
+        if !(1..=22).contains(&cold_compression_level) {
+            return Err(MetricsEngineError::InvalidInput("The cold compression level must be between 1 and 22.".to_owned()));
+        }
+
+        for duration in &mut config.durations {
+            duration.cold_compression_level = Some(cold_compression_level);
+        }
+    }
+
+    config.rollups = input.rollups;
+
+    let details = json!({ "type": metric_type, "config": config });
     state.metrics_engine.add_metric_with_config(&input.name, metric_type, config)?;
+    state.audit(headers, request_id, AuditAction::CreateMetric, Some(&input.name), details);
     Ok(Json(json!({})).into_response())
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct AddPrimaryTag {
     tag: Tag
 }
 
+/// Adds a primary tag to the metric.
+#[utoipa::path(post, path = "/metrics/{name}/primary-tags", params(("name" = String, Path, description = "The name of the metric.")), request_body = AddPrimaryTag, responses((status = 200, description = "The primary tag was added.")))]
 async fn add_primary_tag(State(state): State<Arc<AppState>>,
                          Path(name): Path<String>,
+                         headers: HeaderMap,
+                         Extension(request_id): Extension<RequestId>,
                          Json(primary_tag): Json<AddPrimaryTag>) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    let details = json!({ "tag": primary_tag.tag });
     state.metrics_engine.add_primary_tag(&name, PrimaryTag::Named(primary_tag.tag))?;
+    state.audit(&headers, &request_id, AuditAction::AddPrimaryTag, Some(&name), details);
     Ok(Json(json!({})).into_response())
 }
 
-#[derive(Deserialize)]
+/// Same as `POST /metrics/{name}/primary-tags`.
+#[utoipa::path(post, path = "/metrics/primary-tag/{name}", params(("name" = String, Path, description = "The name of the metric.")), request_body = AddPrimaryTag, responses((status = 200, description = "The primary tag was added.")))]
+async fn add_primary_tag_legacy(state: State<Arc<AppState>>,
+                                name: Path<String>,
+                                headers: HeaderMap,
+                                request_id: Extension<RequestId>,
+                                primary_tag: Json<AddPrimaryTag>) -> ServerResult<Response> {
+    add_primary_tag(state, name, headers, request_id, primary_tag).await
+}
+
+#[derive(Deserialize, ToSchema)]
 struct AddAutoPrimaryTag {
     key: String
 }
 
+/// Automatically creates primary tags for values of the tag key.
+#[utoipa::path(post, path = "/metrics/{name}/auto-primary-tags", params(("name" = String, Path, description = "The name of the metric.")), request_body = AddAutoPrimaryTag, responses((status = 200, description = "The tag key was added.")))]
 async fn add_auto_primary_tag(State(state): State<Arc<AppState>>,
                          Path(name): Path<String>,
+                         headers: HeaderMap,
+                         Extension(request_id): Extension<RequestId>,
                          Json(primary_tag): Json<AddAutoPrimaryTag>) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
     state.metrics_engine.add_auto_primary_tag(&name, &primary_tag.key)?;
+    state.audit(&headers, &request_id, AuditAction::AddAutoPrimaryTag, Some(&name), json!({ "key": primary_tag.key }));
+    Ok(Json(json!({})).into_response())
+}
+
+/// Same as `POST /metrics/{name}/auto-primary-tags`.
+#[utoipa::path(post, path = "/metrics/auto-primary-tag/{name}", params(("name" = String, Path, description = "The name of the metric.")), request_body = AddAutoPrimaryTag, responses((status = 200, description = "The tag key was added.")))]
+async fn add_auto_primary_tag_legacy(state: State<Arc<AppState>>,
+                                     name: Path<String>,
+                                     headers: HeaderMap,
+                                     request_id: Extension<RequestId>,
+                                     primary_tag: Json<AddAutoPrimaryTag>) -> ServerResult<Response> {
+    add_auto_primary_tag(state, name, headers, request_id, primary_tag).await
+}
+
+/// Returns the primary tags of the metric.
+#[utoipa::path(get, path = "/metrics/{name}/primary-tags", params(("name" = String, Path, description = "The name of the metric.")), responses((status = 200, description = "Success", body = PrimaryTagsResponse)))]
+async fn list_primary_tags(State(state): State<Arc<AppState>>,
+                           Path(name): Path<String>,
+                           headers: HeaderMap) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Read)?;
+    Ok(Json(PrimaryTagsResponse { primary_tags: state.metrics_engine.primary_tags(&name)? }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct PrimaryTagsResponse {
+    primary_tags: Vec<Tag>
+}
+
+/// Removes the primary tag of the metric, including its data.
+#[utoipa::path(delete, path = "/metrics/{name}/primary-tags/{tag}", params(("name" = String, Path, description = "The name of the metric."), ("tag" = Tag, Path)), responses((status = 200, description = "Success", body = RemovedResponse)))]
+async fn remove_primary_tag(State(state): State<Arc<AppState>>,
+                            Path((name, tag)): Path<(String, Tag)>,
+                            headers: HeaderMap,
+                            Extension(request_id): Extension<RequestId>) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    let removed = state.metrics_engine.remove_primary_tag(&name, &tag)?;
+    if removed {
+        state.audit(&headers, &request_id, AuditAction::RemovePrimaryTag, Some(&name), json!({ "tag": tag }));
+    }
+
+    Ok(Json(RemovedResponse { removed }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct RemovedResponse {
+    removed: bool
+}
+
+/// Returns the tag keys that primary tags are automatically created for.
+#[utoipa::path(get, path = "/metrics/{name}/auto-primary-tags", params(("name" = String, Path, description = "The name of the metric.")), responses((status = 200, description = "Success", body = AutoPrimaryTagsResponse)))]
+async fn list_auto_primary_tags(State(state): State<Arc<AppState>>,
+                                Path(name): Path<String>,
+                                headers: HeaderMap) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Read)?;
+    Ok(Json(AutoPrimaryTagsResponse { keys: state.metrics_engine.auto_primary_tags(&name)? }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct AutoPrimaryTagsResponse {
+    keys: Vec<String>
+}
+
+/// Stops automatically creating primary tags for the tag key, the existing primary tags are kept.
+#[utoipa::path(delete, path = "/metrics/{name}/auto-primary-tags/{key}", params(("name" = String, Path, description = "The name of the metric."), ("key" = String, Path)), responses((status = 200, description = "Success", body = RemovedResponse)))]
+async fn remove_auto_primary_tag(State(state): State<Arc<AppState>>,
+                                 Path((name, key)): Path<(String, String)>,
+                                 headers: HeaderMap,
+                                 Extension(request_id): Extension<RequestId>) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    let removed = state.metrics_engine.remove_auto_primary_tag(&name, &key)?;
+    if removed {
+        state.audit(&headers, &request_id, AuditAction::RemoveAutoPrimaryTag, Some(&name), json!({ "key": key }));
+    }
+
+    Ok(Json(RemovedResponse { removed }).into_response())
+}
+
+/// Writes the buffered values of the metric and synchronously flushes its storage to disk.
+#[utoipa::path(post, path = "/metrics/{name}/flush", params(("name" = String, Path, description = "The name of the metric.")), responses((status = 200, description = "The metric was flushed.")))]
+async fn flush_metric(State(state): State<Arc<AppState>>,
+                      Path(name): Path<String>,
+                      headers: HeaderMap) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    state.metrics_engine.flush_metric(&name)?;
+    Ok(Json(json!({})).into_response())
+}
+
+/// Deletes the metric by moving it to the trash, where it can be undeleted during the grace period.
+#[utoipa::path(delete, path = "/metrics/{name}", params(("name" = String, Path, description = "The name of the metric.")), responses((status = 200, description = "The metric was deleted.")))]
+async fn delete_metric(State(state): State<Arc<AppState>>,
+                       Path(name): Path<String>,
+                       headers: HeaderMap,
+                       Extension(request_id): Extension<RequestId>) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+
+    let metrics_engine = state.metrics_engine.clone();
+    let metric = name.clone();
+    tokio::task::spawn_blocking(move || metrics_engine.delete_metric(&metric)).await.unwrap()?;
+    state.audit(&headers, &request_id, AuditAction::DeleteMetric, Some(&name), json!({}));
+    Ok(Json(json!({})).into_response())
+}
+
+/// Restores the latest deleted version of the metric from the trash.
+#[utoipa::path(post, path = "/metrics/{name}/undelete", params(("name" = String, Path, description = "The name of the metric.")), responses((status = 200, description = "The metric was restored.")))]
+async fn undelete_metric(State(state): State<Arc<AppState>>,
+                         Path(name): Path<String>,
+                         headers: HeaderMap,
+                         Extension(request_id): Extension<RequestId>) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    state.metrics_engine.undelete_metric(&name)?;
+    state.audit(&headers, &request_id, AuditAction::UndeleteMetric, Some(&name), json!({}));
     Ok(Json(json!({})).into_response())
 }
 
+/// Returns the metrics in the trash, latest deleted first.
+#[utoipa::path(get, path = "/metrics/deleted", responses((status = 200, description = "Success", body = DeletedMetricsResponse)))]
+async fn list_deleted_metrics(State(state): State<Arc<AppState>>,
+                              headers: HeaderMap) -> ServerResult<Response> {
+    let deleted_metrics = state.metrics_engine.deleted_metrics()
+        .into_iter()
+        .filter(|deleted_metric| state.authorize(&headers, &deleted_metric.name, Access::Read).is_ok())
+        .collect::<Vec<_>>();
+    Ok(Json(DeletedMetricsResponse { metrics: deleted_metrics }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeletedMetricsResponse {
+    metrics: Vec<DeletedMetric>
+}
+
+/// Adds values to a gauge metric.
+#[utoipa::path(put, path = "/metrics/gauge/{name}", params(("name" = String, Path, description = "The name of the metric.")), request_body = Vec<AddGaugeValue>, responses((status = 200, description = "Success", body = InsertedResponse)))]
 async fn add_gauge_metric_value(State(state): State<Arc<AppState>>,
                                 Path(name): Path<String>,
-                                Json(metric_values): Json<Vec<AddGaugeValue>>) -> ServerResult<Response> {
-    let num_inserted = state.metrics_engine.gauge(&name, metric_values.into_iter())?;
-    Ok(
-        Json(
-            json!({
-                "num_inserted": num_inserted
-            })
-        ).into_response()
-    )
+                                headers: HeaderMap,
+                                body: Bytes) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    let metric_values: Vec<AddGaugeValue> = decode_body(&headers, &body)?;
+    let num_inserted = state.metrics_engine.gauge_with_mode(&name, metric_values.into_iter(), state.unknown_metric_mode(&headers))?;
+    Ok(encoded_response(&headers, InsertedResponse { num_inserted }))
 }
 
+/// Adds values to a count metric.
+#[utoipa::path(put, path = "/metrics/count/{name}", params(("name" = String, Path, description = "The name of the metric.")), request_body = Vec<AddCountValue>, responses((status = 200, description = "Success", body = InsertedResponse)))]
 async fn add_count_metric_value(State(state): State<Arc<AppState>>,
                                 Path(name): Path<String>,
-                                Json(metric_values): Json<Vec<AddCountValue>>) -> ServerResult<Response> {
-    let num_inserted = state.metrics_engine.count(&name, metric_values.into_iter())?;
-    Ok(
-        Json(
-            json!({
-                "num_inserted": num_inserted
-            })
-        ).into_response()
-    )
+                                headers: HeaderMap,
+                                body: Bytes) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    let metric_values: Vec<AddCountValue> = decode_body(&headers, &body)?;
+    let num_inserted = state.metrics_engine.count_with_mode(&name, metric_values.into_iter(), state.unknown_metric_mode(&headers))?;
+    Ok(encoded_response(&headers, InsertedResponse { num_inserted }))
 }
 
+/// Adds values to a ratio metric.
+#[utoipa::path(put, path = "/metrics/ratio/{name}", params(("name" = String, Path, description = "The name of the metric.")), request_body = Vec<AddRatioValue>, responses((status = 200, description = "Success", body = InsertedResponse)))]
 async fn add_ratio_metric_value(State(state): State<Arc<AppState>>,
                                 Path(name): Path<String>,
-                                Json(metric_values): Json<Vec<AddRatioValue>>) -> ServerResult<Response> {
-    let num_inserted = state.metrics_engine.ratio(&name, metric_values.into_iter())?;
+                                headers: HeaderMap,
+                                body: Bytes) -> ServerResult<Response> {
+    state.authorize(&headers, &name, Access::Write)?;
+    let metric_values: Vec<AddRatioValue> = decode_body(&headers, &body)?;
+    let num_inserted = state.metrics_engine.ratio_with_mode(&name, metric_values.into_iter(), state.unknown_metric_mode(&headers))?;
+    Ok(encoded_response(&headers, InsertedResponse { num_inserted }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct InsertedResponse {
+    num_inserted: usize
+}
+
+/// Adds values to multiple metrics, using the WriteBatch message of proto/write.proto as body.
+#[utoipa::path(post, path = "/write/pb", request_body(content = Vec<u8>, content_type = "application/x-protobuf"), responses((status = 200, description = "Success", body = InsertedResponse)))]
+async fn write_protobuf(State(state): State<Arc<AppState>>,
+                        headers: HeaderMap,
+                        body: Bytes) -> ServerResult<Response> {
+    let batch = protobuf::WriteBatch::decode(body.as_ref())
+        .map_err(|err| MetricsEngineError::InvalidInput(format!("failed to decode body: {}", err)))?;
+
+    for series in &batch.series {
+        state.authorize(&headers, &series.metric, Access::Write)?;
+    }
+
+    let unknown_metrics = state.unknown_metric_mode(&headers);
+    let mut num_inserted = 0;
+    for series in &batch.series {
+        num_inserted += match series.metric_type() {
+            protobuf::MetricKind::Gauge => state.metrics_engine.gauge_with_mode(&series.metric, series.gauge_values(), unknown_metrics)?,
+            protobuf::MetricKind::Count => state.metrics_engine.count_with_mode(&series.metric, series.count_values(), unknown_metrics)?,
+            protobuf::MetricKind::Ratio => state.metrics_engine.ratio_with_mode(&series.metric, series.ratio_values(), unknown_metrics)?
+        };
+    }
+
+    Ok(encoded_response(&headers, InsertedResponse { num_inserted }))
+}
+
+/// Returns the status of the server.
+#[utoipa::path(get, path = "/status", responses((status = 200, description = "Success", body = StatusResponse)))]
+async fn status(State(state): State<Arc<AppState>>,
+                headers: HeaderMap) -> ServerResult<Response> {
+    // The status includes the usage of all metrics
+    state.authorize(&headers, "*", Access::Read)?;
+
     Ok(
         Json(
-            json!({
-                "num_inserted": num_inserted
-            })
+            StatusResponse {
+                loading: state.metrics_engine.loading_status(),
+                quotas: state.metrics_engine.quota_usage(),
+                tenants: state.metrics_engine.tenant_usage(),
+                disk_space: state.metrics_engine.disk_space(),
+                verification: state.metrics_engine.verification_status(),
+                dropped_values: state.metrics_engine.dropped_values(),
+                rejected_values: state.metrics_engine.rejected_values(),
+                clock_skew_adjustments: state.metrics_engine.clock_skew_adjustments(),
+                buffered_values: state.metrics_engine.num_buffered_values(),
+                sampled_out_values: state.metrics_engine.sampled_out_values(),
+                cached_windows: state.metrics_engine.num_cached_windows(),
+                metric_locks: state.metrics_engine.lock_stats()
+            }
         ).into_response()
     )
 }
 
-#[derive(Deserialize)]
-struct InputMetricQuery {
-    time_range: TimeRange,
-    duration: Option<f64>,
-    expression: MetricQueryExpression
+#[derive(Serialize, ToSchema)]
+struct StatusResponse {
+    loading: LoadingStatus,
+    quotas: Vec<QuotaUsage>,
+    tenants: Vec<TenantUsage>,
+    disk_space: Option<DiskSpace>,
+    verification: VerificationStatus,
+    dropped_values: u64,
+    rejected_values: u64,
+    clock_skew_adjustments: u64,
+    buffered_values: usize,
+    sampled_out_values: u64,
+    cached_windows: usize,
+    metric_locks: LockStats
 }
 
-async fn metric_query(State(state): State<Arc<AppState>>,
-                      Json(input_query): Json<InputMetricQuery>) -> ServerResult<Response> {
-    let query = MetricQuery::new(input_query.time_range, input_query.expression);
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SlowQueriesParameters {
+    limit: Option<usize>
+}
 
-    let value = if let Some(duration) = input_query.duration {
-        state.metrics_engine.query_in_window(query, Duration::from_secs_f64(duration))?
-    } else {
-        state.metrics_engine.query(query)?
-    };
+/// Returns the queries that exceeded the slow query threshold, slowest first.
+#[utoipa::path(get, path = "/slow-queries", params(SlowQueriesParameters), responses((status = 200, description = "Success", body = SlowQueriesResponse)))]
+async fn slow_queries(State(state): State<Arc<AppState>>,
+                      Query(parameters): Query<SlowQueriesParameters>,
+                      headers: HeaderMap) -> ServerResult<Response> {
+    // The slow queries can be of any metric
+    state.authorize(&headers, "*", Access::Read)?;
+    Ok(Json(SlowQueriesResponse { queries: state.metrics_engine.slow_queries(parameters.limit) }).into_response())
+}
 
-    operation_result_response(value)
+#[derive(Serialize, ToSchema)]
+struct SlowQueriesResponse {
+    queries: Vec<SlowQuery>
 }
 
-fn operation_result_response(value: OperationResult) -> ServerResult<Response> {
-    if let Some(error_message) = value.error_message() {
-        return Ok(with_response_code(
-            Json(
-                json!({
-                    "message": error_message
-                })
-            ).into_response(),
-            StatusCode::BAD_REQUEST
-        ));
-    }
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CompactParameters {
+    metric: Option<String>
+}
 
-    let value = value.as_json();
+/// Runs the maintenance of the metric (or all loaded metrics) and removes the segments older than the retention.
+#[utoipa::path(post, path = "/admin/compact", params(CompactParameters), responses((status = 200, description = "Success", body = CompactResponse)))]
+async fn compact(State(state): State<Arc<AppState>>,
+                 Query(parameters): Query<CompactParameters>,
+                 headers: HeaderMap,
+                 Extension(request_id): Extension<RequestId>) -> ServerResult<Response> {
+    // Compacting all metrics requires a rule that gives write access to all metrics
+    state.authorize(&headers, parameters.metric.as_deref().unwrap_or("*"), Access::Write)?;
 
-    Ok(
-        Json(
-            json!({
-                "value": value
-            })
-        ).into_response()
-    )
+    let metrics_engine = state.metrics_engine.clone();
+    let metric = parameters.metric.clone();
+    let num_removed = tokio::task::spawn_blocking(move || metrics_engine.compact(metric.as_deref())).await.unwrap()?;
+    state.audit(
+        &headers,
+        &request_id,
+        AuditAction::Compact,
+        parameters.metric.as_deref(),
+        json!({ "num_removed_segments": num_removed })
+    );
+    Ok(Json(CompactResponse { num_removed_segments: num_removed }).into_response())
 }
 
-fn with_response_code(mut response: Response, code: StatusCode) -> Response {
-    *response.status_mut() = code;
-    response
-}
\ No newline at end of file
+#[derive(Serialize, ToSchema)]
+struct CompactResponse {
+    num_removed_segments: usize
+}
+
+/// Adds an annotation.
+#[utoipa::path(put, path = "/annotations", request_body = Annotation, responses((status = 200, description = "The annotation was added.")))]
+async fn add_annotation(State(state): State<Arc<AppState>>,
+                        headers: HeaderMap,
+                        Json(annotation): Json<Annotation>) -> ServerResult<Response> {
+    // Annotations are not bound to metrics, so they are shared by all metrics
+    state.authorize(&headers, "*", Access::Write)?;
+    state.metrics_engine.add_annotation(annotation)?;
+    Ok(Json(json!({})).into_response())
+}
+
+/// Sends a test event to the configured webhooks.
+#[utoipa::path(post, path = "/webhooks/test", responses((status = 200, description = "Success", body = WebhookTestResponse)))]
+async fn test_webhooks(State(state): State<Arc<AppState>>,
+                       headers: HeaderMap) -> ServerResult<Response> {
+    // Events are sent to the webhooks of all metrics
+    state.authorize(&headers, "*", Access::Write)?;
+    let queued = state.webhooks.notify(WebhookEvent::new("test", "test", json!({})));
+    Ok(Json(WebhookTestResponse { queued }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct WebhookTestResponse {
+    queued: bool
+}
+
+/// Creates a snapshot in the configured snapshot directory and removes the oldest snapshots.
+#[utoipa::path(post, path = "/snapshots", responses((status = 200, description = "Success", body = SnapshotResponse)))]
+async fn create_snapshot(State(state): State<Arc<AppState>>,
+                         headers: HeaderMap) -> ServerResult<Response> {
+    // Snapshots contain all metrics, and pause their writes
+    state.authorize(&headers, "*", Access::Write)?;
+    let metrics_engine = state.metrics_engine.clone();
+    let snapshot_config = state.snapshots.clone();
+    let snapshot = tokio::task::spawn_blocking(move || snapshots::create_snapshot(&metrics_engine, &snapshot_config)).await.unwrap()?;
+    Ok(Json(SnapshotResponse { path: snapshot }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct SnapshotResponse {
+    #[schema(value_type = String)]
+    path: PathBuf
+}
+
+/// Flushes the metrics to disk and pauses writes, such that a filesystem level snapshot is consistent.
+#[utoipa::path(post, path = "/snapshots/pre", responses((status = 200, description = "Success", body = PreSnapshotResponse)))]
+async fn pre_snapshot(State(state): State<Arc<AppState>>,
+                      headers: HeaderMap) -> ServerResult<Response> {
+    // Pauses the writes of all metrics
+    state.authorize(&headers, "*", Access::Write)?;
+    let max_pause = Duration::from_secs_f64(state.snapshots.max_pause.max(0.0));
+    let metrics_engine = state.metrics_engine.clone();
+    tokio::task::spawn_blocking(move || metrics_engine.pre_snapshot(max_pause)).await.unwrap()?;
+    Ok(Json(PreSnapshotResponse { max_pause: max_pause.as_secs_f64() }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct PreSnapshotResponse {
+    /// The time (in seconds) after which writes are resumed, if not resumed before.
+    max_pause: f64
+}
+
+/// Resumes writes after a filesystem level snapshot.
+#[utoipa::path(post, path = "/snapshots/post", responses((status = 200, description = "Success", body = PostSnapshotResponse)))]
+async fn post_snapshot(State(state): State<Arc<AppState>>,
+                       headers: HeaderMap) -> ServerResult<Response> {
+    state.authorize(&headers, "*", Access::Write)?;
+    let resumed = state.metrics_engine.post_snapshot();
+    Ok(Json(PostSnapshotResponse { resumed }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct PostSnapshotResponse {
+    resumed: bool
+}
+
+/// Returns the names of the dashboards.
+#[utoipa::path(get, path = "/dashboards", responses((status = 200, description = "Success", body = DashboardsResponse)))]
+async fn list_dashboards(State(state): State<Arc<AppState>>,
+                         headers: HeaderMap) -> ServerResult<Response> {
+    // Dashboards are shared by all users, and can show any metric
+    state.authorize(&headers, "*", Access::Read)?;
+    Ok(Json(DashboardsResponse { dashboards: state.metrics_engine.dashboard_names() }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct DashboardsResponse {
+    dashboards: Vec<String>
+}
+
+/// Returns the dashboard.
+#[utoipa::path(get, path = "/dashboards/{name}", params(("name" = String, Path, description = "The name of the dashboard.")), responses((status = 200, description = "Success", body = Dashboard)))]
+async fn get_dashboard(State(state): State<Arc<AppState>>,
+                       Path(name): Path<String>,
+                       headers: HeaderMap) -> ServerResult<Response> {
+    state.authorize(&headers, "*", Access::Read)?;
+    Ok(Json(state.metrics_engine.dashboard(&name)?).into_response())
+}
+
+/// Creates or replaces the dashboard.
+#[utoipa::path(put, path = "/dashboards/{name}", params(("name" = String, Path, description = "The name of the dashboard.")), request_body = Dashboard, responses((status = 200, description = "The dashboard was stored.")))]
+async fn put_dashboard(State(state): State<Arc<AppState>>,
+                       Path(name): Path<String>,
+                       headers: HeaderMap,
+                       Extension(request_id): Extension<RequestId>,
+                       Json(dashboard): Json<Dashboard>) -> ServerResult<Response> {
+    // Dashboards are shared by all users, and can show any metric
+    state.authorize(&headers, "*", Access::Write)?;
+    state.metrics_engine.put_dashboard(&name, dashboard)?;
+    state.audit(&headers, &request_id, AuditAction::PutDashboard, None, json!({ "dashboard": name }));
+    Ok(Json(json!({})).into_response())
+}
+
+/// Removes the dashboard.
+#[utoipa::path(delete, path = "/dashboards/{name}", params(("name" = String, Path, description = "The name of the dashboard.")), responses((status = 200, description = "The dashboard was removed.")))]
+async fn delete_dashboard(State(state): State<Arc<AppState>>,
+                          Path(name): Path<String>,
+                          headers: HeaderMap,
+                          Extension(request_id): Extension<RequestId>) -> ServerResult<Response> {
+    state.authorize(&headers, "*", Access::Write)?;
+    state.metrics_engine.remove_dashboard(&name)?;
+    state.audit(&headers, &request_id, AuditAction::RemoveDashboard, None, json!({ "dashboard": name }));
+    Ok(Json(json!({})).into_response())
+}
+
+/// Exports the definitions of all metrics and dashboards (as JSON or YAML, depending on the Accept header).
+#[utoipa::path(get, path = "/admin/schema", responses((status = 200, description = "Success", body = Schema)))]
+async fn export_schema(State(state): State<Arc<AppState>>,
+                       headers: HeaderMap) -> ServerResult<Response> {
+    // Exporting the schema requires a rule that gives read access to all metrics
+    state.authorize(&headers, "*", Access::Read)?;
+
+    let metrics_engine = state.metrics_engine.clone();
+    let schema = tokio::task::spawn_blocking(move || metrics_engine.export_schema()).await.unwrap()?;
+    Ok(encoded_response(&headers, schema))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SchemaParameters {
+    /// Removes the metrics, primary tags and dashboards that are not in the schema.
+    #[serde(default)]
+    prune: bool
+}
+
+/// Creates the metrics, primary tags and dashboards in the schema that do not exist. The configs of existing metrics are not changed.
+#[utoipa::path(put, path = "/admin/schema", params(SchemaParameters), request_body = Schema, responses((status = 200, description = "Success", body = SchemaChangesResponse)))]
+async fn apply_schema(State(state): State<Arc<AppState>>,
+                      Query(parameters): Query<SchemaParameters>,
+                      headers: HeaderMap,
+                      Extension(request_id): Extension<RequestId>,
+                      body: Bytes) -> ServerResult<Response> {
+    // Applying a schema requires a rule that gives write access to all metrics
+    state.authorize(&headers, "*", Access::Write)?;
+    let schema: Schema = decode_body(&headers, &body)?;
+
+    let metrics_engine = state.metrics_engine.clone();
+    let changes = tokio::task::spawn_blocking(move || metrics_engine.apply_schema(&schema, parameters.prune)).await.unwrap()?;
+    state.audit(&headers, &request_id, AuditAction::ApplySchema, None, json!(changes));
+    Ok(encoded_response(&headers, SchemaChangesResponse { changes }))
+}
+
+/// Returns the changes that applying the schema would make, without applying it.
+#[utoipa::path(post, path = "/admin/schema/diff", params(SchemaParameters), request_body = Schema, responses((status = 200, description = "Success", body = SchemaChangesResponse)))]
+async fn diff_schema(State(state): State<Arc<AppState>>,
+                     Query(parameters): Query<SchemaParameters>,
+                     headers: HeaderMap,
+                     body: Bytes) -> ServerResult<Response> {
+    state.authorize(&headers, "*", Access::Read)?;
+    let schema: Schema = decode_body(&headers, &body)?;
+
+    let metrics_engine = state.metrics_engine.clone();
+    let changes = tokio::task::spawn_blocking(move || metrics_engine.diff_schema(&schema, parameters.prune)).await.unwrap()?;
+    Ok(encoded_response(&headers, SchemaChangesResponse { changes }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct SchemaChangesResponse {
+    changes: SchemaChanges
+}
+
+/// Returns the administrative actions in the audit log, latest first.
+#[utoipa::path(get, path = "/admin/audit", params(AuditQuery), responses((status = 200, description = "Success", body = AuditLogResponse)))]
+async fn audit_log(State(state): State<Arc<AppState>>,
+                   Query(query): Query<AuditQuery>,
+                   headers: HeaderMap) -> ServerResult<Response> {
+    // Reading the audit log requires a rule that gives read access to all metrics
+    state.authorize(&headers, "*", Access::Read)?;
+    Ok(Json(AuditLogResponse { entries: state.metrics_engine.audit_entries(&query) }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuditLogResponse {
+    entries: Vec<AuditEntry>
+}
+
+/// Returns this document.
+#[utoipa::path(get, path = "/api-docs", responses((status = 200, description = "The OpenAPI document of the server.")))]
+async fn api_docs() -> Response {
+    Json(openapi::document()).into_response()
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputAnnotationsQuery {
+    time_range: TimeRange,
+    #[serde(default)]
+    tags: Vec<Tag>
+}
+
+/// Returns the annotations within the time range having all of the tags.
+#[utoipa::path(post, path = "/annotations/query", request_body = InputAnnotationsQuery, responses((status = 200, description = "Success", body = AnnotationsResponse)))]
+async fn query_annotations(State(state): State<Arc<AppState>>,
+                           headers: HeaderMap,
+                           Json(input_query): Json<InputAnnotationsQuery>) -> ServerResult<Response> {
+    state.authorize(&headers, "*", Access::Read)?;
+    querying::validate_time_range(&input_query.time_range)?;
+
+    let annotations = state.metrics_engine.annotations(input_query.time_range, &input_query.tags);
+    Ok(Json(AnnotationsResponse { annotations }).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+struct AnnotationsResponse {
+    annotations: Vec<Annotation>
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputMetricQuery {
+    time_range: TimeRange,
+    duration: Option<f64>,
+    expression: MetricQueryExpression,
+    output_filter: Option<FilterExpression>,
+    max_points: Option<usize>,
+    #[serde(default)]
+    downsampling: Downsampling,
+    #[serde(default)]
+    alignment: Alignment,
+    #[serde(default)]
+    join: GroupJoin,
+    relabel: Option<Relabeling>,
+    /// Overrides the read consistency of all metric queries in the expression.
+    consistency: Option<ReadConsistency>,
+    #[serde(default)]
+    include_annotations: bool,
+    #[serde(default)]
+    annotation_tags: Vec<Tag>,
+    #[serde(default)]
+    output: JsonOptions
+}
+
+impl InputMetricQuery {
+    fn metric_query(&self, mut expression: MetricQueryExpression, request_id: &RequestId) -> MetricQuery {
+        if let Some(consistency) = self.consistency {
+            expression.set_consistency(consistency);
+        }
+
+        let mut query = MetricQuery::new(self.time_range, expression);
+        query.output_filter = self.output_filter.clone();
+        query.max_points = self.max_points;
+        query.downsampling = self.downsampling;
+        query.alignment = self.alignment;
+        query.join = self.join;
+        query.relabel = self.relabel.clone();
+        query.request_id = Some(request_id.0.clone());
+        query
+    }
+
+    fn window_duration(&self) -> ServerResult<Option<Duration>> {
+        self.duration
+            .map(|duration| {
+                Duration::try_from_secs_f64(duration)
+                    .map_err(|_| MetricsEngineError::InvalidQueryInput("The window duration is not valid.".to_owned()))
+            })
+            .transpose()
+    }
+}
+
+const LONG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const LONG_POLL_MAX_TIMEOUT: f64 = 60.0;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LongPollParameters {
+    /// Waits until there is a window after this time with a value, or the timeout has passed.
+    since_time: Option<f64>,
+    /// The maximum time (in seconds) to wait (default: 30).
+    timeout: Option<f64>
+}
+
+/// Evaluates the query, waiting for new windows if long-polling is requested. The query is evaluated on a blocking thread.
+async fn query_with_long_poll(state: &AppState,
+                              query: MetricQuery,
+                              duration: Option<Duration>,
+                              long_poll: &LongPollParameters) -> ServerResult<OperationResult> {
+    let metrics_engine = state.metrics_engine.clone();
+    let (since_time, duration) = match (long_poll.since_time, duration) {
+        (Some(since_time), Some(duration)) => (since_time, duration),
+        (Some(_), None) => {
+            return Err(MetricsEngineError::InvalidQueryInput("Long-polling requires a window duration.".to_owned()));
+        }
+        (None, Some(duration)) => {
+            return tokio::task::spawn_blocking(move || metrics_engine.query_in_window(query, duration)).await.unwrap();
+        }
+        (None, None) => {
+            return tokio::task::spawn_blocking(move || metrics_engine.query(query)).await.unwrap();
+        }
+    };
+
+    let timeout = long_poll.timeout.unwrap_or(30.0);
+    if !timeout.is_finite() || timeout < 0.0 {
+        return Err(MetricsEngineError::InvalidQueryInput("The timeout is not valid.".to_owned()));
+    }
+
+    let metrics = query.expression.metrics();
+    let write_sequence = |metrics_engine: &MetricsEngine| {
+        metrics.iter().map(|metric| metrics_engine.write_sequence(metric)).sum::<u64>()
+    };
+
+    let deadline = Instant::now() + Duration::from_secs_f64(timeout.min(LONG_POLL_MAX_TIMEOUT));
+    loop {
+        let current_write_sequence = write_sequence(&metrics_engine);
+        let value = {
+            let metrics_engine = metrics_engine.clone();
+            let query = query.clone();
+            tokio::task::spawn_blocking(move || metrics_engine.query_in_window(query, duration)).await.unwrap()?
+        };
+
+        let has_new_windows = value.latest_window_time().map(|time| time > since_time).unwrap_or(false);
+        if has_new_windows || Instant::now() >= deadline {
+            return Ok(value);
+        }
+
+        // Only evaluated again once something has been written to the metrics of the query
+        while write_sequence(&metrics_engine) == current_write_sequence && Instant::now() < deadline {
+            tokio::time::sleep(LONG_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Evaluates a query expression, in windows if a duration is given. The response format is negotiated with the Accept header.
+#[utoipa::path(post, path = "/metrics/query", params(LongPollParameters), request_body = InputMetricQuery, responses((status = 200, description = "Success", body = QueryResponse)))]
+async fn metric_query(State(state): State<Arc<AppState>>,
+                      Extension(request_id): Extension<RequestId>,
+                      headers: HeaderMap,
+                      Query(long_poll): Query<LongPollParameters>,
+                      body: Bytes) -> ServerResult<Response> {
+    let input_query: InputMetricQuery = decode_body(&headers, &body)?;
+    for metric in input_query.expression.metrics() {
+        state.authorize(&headers, &metric, Access::Read)?;
+    }
+
+    let time_range = input_query.time_range;
+    querying::validate_time_range(&time_range)?;
+    let duration = input_query.window_duration()?;
+
+    let format = ResponseFormat::from_headers(&headers);
+    let annotations = if input_query.include_annotations && duration.is_some() {
+        state.authorize(&headers, "*", Access::Read)?;
+        if format == ResponseFormat::Csv {
+            return Err(MetricsEngineError::InvalidQueryInput("Annotations are not supported for CSV responses.".to_owned()));
+        }
+
+        Some(state.metrics_engine.annotations(time_range, &input_query.annotation_tags))
+    } else {
+        None
+    };
+
+    if matches!(format, ResponseFormat::Csv | ResponseFormat::JsonLines) && long_poll.since_time.is_none() {
+        return streamed_rows_response(state.metrics_engine.clone(), input_query, request_id, duration, annotations, format).await;
+    }
+
+    let query = input_query.metric_query(input_query.expression.clone(), &request_id);
+    let value = query_with_long_poll(&state, query, duration, &long_poll).await?;
+
+    match format {
+        ResponseFormat::Csv | ResponseFormat::JsonLines => operation_result_rows_response(value, annotations, format, input_query.output),
+        format => operation_result_response(value, annotations, &input_query.output, format)
+    }
+}
+
+/// How the metrics of the convenience query endpoints are aggregated.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+enum MetricAggregation {
+    #[default]
+    Average,
+    Sum,
+    Max,
+    Min,
+    /// Given as `{"Percentile": 99}`.
+    Percentile(i32),
+    Last,
+    Count,
+    Rate,
+    Numerator,
+    Denominator,
+    Datapoints,
+    /// Given as `{"RatioInterval": 0.95}`.
+    RatioInterval(f64)
+}
+
+impl MetricAggregation {
+    fn expression(&self, metric: String, query: model::Query) -> MetricQueryExpression {
+        match self {
+            MetricAggregation::Average => MetricQueryExpression::Average { metric, query },
+            MetricAggregation::Sum => MetricQueryExpression::Sum { metric, query },
+            MetricAggregation::Max => MetricQueryExpression::Max { metric, query },
+            MetricAggregation::Min => MetricQueryExpression::Min { metric, query },
+            MetricAggregation::Percentile(percentile) => MetricQueryExpression::Percentile { metric, query, percentile: *percentile },
+            MetricAggregation::Last => MetricQueryExpression::Last { metric, query },
+            MetricAggregation::Count => MetricQueryExpression::Count { metric, query },
+            MetricAggregation::Rate => MetricQueryExpression::Rate { metric, query },
+            MetricAggregation::Numerator => MetricQueryExpression::Numerator { metric, query },
+            MetricAggregation::Denominator => MetricQueryExpression::Denominator { metric, query },
+            MetricAggregation::Datapoints => MetricQueryExpression::Datapoints { metric, query },
+            MetricAggregation::RatioInterval(confidence) => MetricQueryExpression::RatioInterval { metric, query, confidence: *confidence }
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputAggregateQuery {
+    time_range: TimeRange,
+    duration: Option<f64>,
+    metric: String,
+    #[serde(default)]
+    aggregation: MetricAggregation,
+    #[serde(default)]
+    query: model::Query,
+    #[serde(default)]
+    output: JsonOptions
+}
+
+/// Aggregates a single metric, in windows if a duration is given and per group if the query has a group by.
+#[utoipa::path(post, path = "/metrics/query/aggregate", params(LongPollParameters), request_body = InputAggregateQuery, responses((status = 200, description = "Success", body = QueryResponse)))]
+async fn metric_query_aggregate(State(state): State<Arc<AppState>>,
+                                Extension(request_id): Extension<RequestId>,
+                                headers: HeaderMap,
+                                Query(long_poll): Query<LongPollParameters>,
+                                body: Bytes) -> ServerResult<Response> {
+    let input_query: InputAggregateQuery = decode_body(&headers, &body)?;
+    let expression = input_query.aggregation.expression(input_query.metric, input_query.query);
+    convenience_query(&state, request_id, &headers, &long_poll, MetricQuery::new(input_query.time_range, expression), input_query.duration, input_query.output).await
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputRatioQuery {
+    time_range: TimeRange,
+    duration: Option<f64>,
+    numerator: String,
+    denominator: String,
+    #[serde(default)]
+    aggregation: MetricAggregation,
+    #[serde(default)]
+    query: model::Query,
+    #[serde(default)]
+    output: JsonOptions
+}
+
+/// Computes the ratio between two metrics, aggregated in the same way.
+#[utoipa::path(post, path = "/metrics/query/ratio", params(LongPollParameters), request_body = InputRatioQuery, responses((status = 200, description = "Success", body = QueryResponse)))]
+async fn metric_query_ratio(State(state): State<Arc<AppState>>,
+                            Extension(request_id): Extension<RequestId>,
+                            headers: HeaderMap,
+                            Query(long_poll): Query<LongPollParameters>,
+                            body: Bytes) -> ServerResult<Response> {
+    let input_query: InputRatioQuery = decode_body(&headers, &body)?;
+    let expression = MetricQueryExpression::ratio(
+        input_query.aggregation.expression(input_query.numerator, input_query.query.clone()),
+        input_query.aggregation.expression(input_query.denominator, input_query.query)
+    );
+
+    convenience_query(&state, request_id, &headers, &long_poll, MetricQuery::new(input_query.time_range, expression), input_query.duration, input_query.output).await
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputPercentOfTotalQuery {
+    time_range: TimeRange,
+    duration: Option<f64>,
+    metric: String,
+    #[serde(default)]
+    aggregation: MetricAggregation,
+    /// Should group the metric (such as by host) for the percentages to be of interest.
+    #[serde(default)]
+    query: model::Query,
+    #[serde(default)]
+    output: JsonOptions
+}
+
+/// Computes the percentage of each group of the metric of the total over all groups.
+#[utoipa::path(post, path = "/metrics/query/percent-of-total", params(LongPollParameters), request_body = InputPercentOfTotalQuery, responses((status = 200, description = "Success", body = QueryResponse)))]
+async fn metric_query_percent_of_total(State(state): State<Arc<AppState>>,
+                                       Extension(request_id): Extension<RequestId>,
+                                       headers: HeaderMap,
+                                       Query(long_poll): Query<LongPollParameters>,
+                                       body: Bytes) -> ServerResult<Response> {
+    let input_query: InputPercentOfTotalQuery = decode_body(&headers, &body)?;
+    let expression = MetricQueryExpression::percent_of_total(input_query.aggregation.expression(input_query.metric, input_query.query));
+    convenience_query(&state, request_id, &headers, &long_poll, MetricQuery::new(input_query.time_range, expression), input_query.duration, input_query.output).await
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputDeltaQuery {
+    time_range: TimeRange,
+    duration: Option<f64>,
+    metric: String,
+    /// The length of the period (in seconds) that is compared against, the length of the time range if not given.
+    period: Option<f64>,
+    #[serde(default)]
+    aggregation: MetricAggregation,
+    #[serde(default)]
+    query: model::Query,
+    #[serde(default)]
+    output: JsonOptions
+}
+
+/// Computes the change of the metric compared to the previous period.
+#[utoipa::path(post, path = "/metrics/query/delta", params(LongPollParameters), request_body = InputDeltaQuery, responses((status = 200, description = "Success", body = QueryResponse)))]
+async fn metric_query_delta(State(state): State<Arc<AppState>>,
+                            Extension(request_id): Extension<RequestId>,
+                            headers: HeaderMap,
+                            Query(long_poll): Query<LongPollParameters>,
+                            body: Bytes) -> ServerResult<Response> {
+    let input_query: InputDeltaQuery = decode_body(&headers, &body)?;
+    let period = input_query.period.unwrap_or(input_query.time_range.end - input_query.time_range.start);
+    let expression = MetricQueryExpression::delta(input_query.aggregation.expression(input_query.metric, input_query.query), period);
+    convenience_query(&state, request_id, &headers, &long_poll, MetricQuery::new(input_query.time_range, expression), input_query.duration, input_query.output).await
+}
+
+async fn convenience_query(state: &AppState,
+                           request_id: RequestId,
+                           headers: &HeaderMap,
+                           long_poll: &LongPollParameters,
+                           mut query: MetricQuery,
+                           duration: Option<f64>,
+                           output: JsonOptions) -> ServerResult<Response> {
+    for metric in query.expression.metrics() {
+        state.authorize(headers, &metric, Access::Read)?;
+    }
+
+    querying::validate_time_range(&query.time_range)?;
+    let duration = duration
+        .map(|duration| {
+            Duration::try_from_secs_f64(duration)
+                .map_err(|_| MetricsEngineError::InvalidQueryInput("The window duration is not valid.".to_owned()))
+        })
+        .transpose()?;
+
+    query.request_id = Some(request_id.0);
+    let value = query_with_long_poll(state, query, duration, long_poll).await?;
+
+    match ResponseFormat::from_headers(headers) {
+        format @ (ResponseFormat::Csv | ResponseFormat::JsonLines) => operation_result_rows_response(value, None, format, output),
+        format => operation_result_response(value, None, &output, format)
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputBatchQueryEntry {
+    /// Identifies the result of the query in the response.
+    id: String,
+    #[serde(flatten)]
+    query: InputMetricQuery
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InputBatchQuery {
+    queries: Vec<InputBatchQueryEntry>
+}
+
+/// Evaluates multiple queries in one request, where a failing query only fails its own result.
+#[utoipa::path(post, path = "/query/batch", request_body = InputBatchQuery, responses((status = 200, description = "Success", body = BatchQueryResponse)))]
+async fn batch_query(State(state): State<Arc<AppState>>,
+                     Extension(request_id): Extension<RequestId>,
+                     headers: HeaderMap,
+                     body: Bytes) -> ServerResult<Response> {
+    let input_query: InputBatchQuery = decode_body(&headers, &body)?;
+    for entry in &input_query.queries {
+        for metric in entry.query.expression.metrics() {
+            state.authorize(&headers, &metric, Access::Read)?;
+        }
+    }
+
+    let metrics_engine = state.metrics_engine.clone();
+    let results = tokio::task::spawn_blocking(move || {
+        let mut results = Vec::new();
+        for entry in input_query.queries {
+            let input_query = entry.query;
+            let result = querying::validate_time_range(&input_query.time_range)
+                .and_then(|_| input_query.window_duration())
+                .and_then(|duration| {
+                    let query = input_query.metric_query(input_query.expression.clone(), &request_id);
+                    match duration {
+                        Some(duration) => metrics_engine.query_in_window(query, duration),
+                        None => metrics_engine.query(query)
+                    }
+                });
+
+            results.push((entry.id, result, input_query.output));
+        }
+
+        results
+    }).await.unwrap();
+
+    let results = results
+        .iter()
+        .map(|(id, result, output)| {
+            let result = match result {
+                Ok(value) => match value.error_message() {
+                    None => BatchQueryResult::Value { value: value.with_options(output) },
+                    Some(message) => BatchQueryResult::Message { message }
+                },
+                Err(err) => BatchQueryResult::Message { message: err.to_string() }
+            };
+
+            (id.as_str(), result)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    Ok(encode_response(ResponseFormat::from_headers(&headers), BatchQueryResponse { results }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct BatchQueryResponse<'a> {
+    results: BTreeMap<&'a str, BatchQueryResult<'a>>
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+enum BatchQueryResult<'a> {
+    Value { value: WithOptions<'a> },
+    Message { message: String }
+}
+
+/// Computes the result of each group separately, and sends it as an NDJSON line as soon as it is computed.
+#[utoipa::path(post, path = "/metrics/query/stream", request_body = InputMetricQuery, responses((status = 200, description = "Success", body = QueryResponse, content_type = "application/x-ndjson")))]
+async fn metric_query_stream(State(state): State<Arc<AppState>>,
+                             Extension(request_id): Extension<RequestId>,
+                             headers: HeaderMap,
+                             body: Bytes) -> ServerResult<Response> {
+    let input_query: InputMetricQuery = decode_body(&headers, &body)?;
+    for metric in input_query.expression.metrics() {
+        state.authorize(&headers, &metric, Access::Read)?;
+    }
+
+    querying::validate_time_range(&input_query.time_range)?;
+    let duration = input_query.window_duration()?;
+
+    let metrics_engine = state.metrics_engine.clone();
+    let expressions = querying::split_by_group(
+        &input_query.expression,
+        |metric, query, key| metrics_engine.group_values(metric, query, key)
+    )?.unwrap_or_else(|| vec![input_query.expression.clone()]);
+
+    let (line_sender, mut line_receiver) = tokio::sync::mpsc::channel::<String>(16);
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        for expression in expressions {
+            let query = input_query.metric_query(expression, &request_id);
+            let result = match duration {
+                Some(duration) => metrics_engine.query_in_window(query, duration),
+                None => metrics_engine.query(query)
+            };
+
+            let (line, done) = match result {
+                Ok(value) if value.error_message().is_none() => (json!({ "value": value.as_json_with(&input_query.output) }), false),
+                Ok(value) => (json!({ "message": value.error_message() }), true),
+                Err(err) => (json!({ "message": err.to_string() }), true)
+            };
+
+            // The client has gone away
+            if line_sender.blocking_send(line.to_string() + "\n").is_err() || done {
+                return;
+            }
+        }
+    });
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(line) = line_receiver.recv().await {
+            if sender.send_data(Bytes::from(line)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(([(header::CONTENT_TYPE, ResponseFormat::JsonLines.content_type())], axum::body::boxed(body)).into_response())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResponseFormat {
+    Json,
+    Csv,
+    JsonLines,
+    MessagePack,
+    Cbor,
+    Yaml
+}
+
+impl ResponseFormat {
+    fn from_headers(headers: &HeaderMap) -> ResponseFormat {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or("");
+
+        for media_type in accept.split(',') {
+            if let Some(format) = ResponseFormat::from_media_type(media_type) {
+                return format;
+            }
+        }
+
+        ResponseFormat::Json
+    }
+
+    fn from_media_type(media_type: &str) -> Option<ResponseFormat> {
+        match media_type.split(';').next().unwrap_or("").trim() {
+            "application/json" => Some(ResponseFormat::Json),
+            "text/csv" => Some(ResponseFormat::Csv),
+            "application/x-ndjson" => Some(ResponseFormat::JsonLines),
+            "application/msgpack" | "application/x-msgpack" => Some(ResponseFormat::MessagePack),
+            "application/cbor" => Some(ResponseFormat::Cbor),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => Some(ResponseFormat::Yaml),
+            _ => None
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ResponseFormat::Json => "application/json",
+            ResponseFormat::Csv => "text/csv",
+            ResponseFormat::JsonLines => "application/x-ndjson",
+            ResponseFormat::MessagePack => "application/msgpack",
+            ResponseFormat::Cbor => "application/cbor",
+            ResponseFormat::Yaml => "application/yaml"
+        }
+    }
+}
+
+fn decode_body<T: DeserializeOwned>(headers: &HeaderMap, body: &Bytes) -> ServerResult<T> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(ResponseFormat::from_media_type)
+        .unwrap_or(ResponseFormat::Json);
+
+    let decode_error = |err: String| MetricsEngineError::InvalidInput(format!("failed to decode body: {}", err));
+    match content_type {
+        ResponseFormat::MessagePack => rmp_serde::from_slice(body).map_err(|err| decode_error(err.to_string())),
+        ResponseFormat::Cbor => ciborium::de::from_reader(body.as_ref()).map_err(|err| decode_error(err.to_string())),
+        ResponseFormat::Yaml => serde_yaml::from_slice(body).map_err(|err| decode_error(err.to_string())),
+        _ => serde_json::from_slice(body).map_err(|err| decode_error(err.to_string()))
+    }
+}
+
+fn encoded_response<T: Serialize>(headers: &HeaderMap, value: T) -> Response {
+    encode_response(ResponseFormat::from_headers(headers), value)
+}
+
+/// The value is serialized directly to the format.
+fn encode_response<T: Serialize>(format: ResponseFormat, value: T) -> Response {
+    let encoded = match format {
+        ResponseFormat::MessagePack => rmp_serde::to_vec_named(&value).map_err(|err| err.to_string()),
+        ResponseFormat::Cbor => {
+            let mut buffer = Vec::new();
+            ciborium::ser::into_writer(&value, &mut buffer).map(|_| buffer).map_err(|err| err.to_string())
+        }
+        ResponseFormat::Yaml => serde_yaml::to_string(&value).map(|encoded| encoded.into_bytes()).map_err(|err| err.to_string()),
+        _ => return Json(value).into_response()
+    };
+
+    match encoded {
+        Ok(encoded) => ([(header::CONTENT_TYPE, format.content_type())], encoded).into_response(),
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to encode response.");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn operation_result_rows_response(value: OperationResult,
+                                  annotations: Option<Vec<Annotation>>,
+                                  format: ResponseFormat,
+                                  output: JsonOptions) -> ServerResult<Response> {
+    if let Some(error_message) = value.error_message() {
+        return Ok(error_message_response(error_message));
+    }
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let columns = value.columns();
+        if format == ResponseFormat::Csv && sender.send_data(Bytes::from(columns.join(",") + "\n")).await.is_err() {
+            return;
+        }
+
+        for line in row_lines(&value, &columns, format, &output).chain(annotation_lines(annotations)) {
+            // The client has gone away
+            if sender.send_data(Bytes::from(line)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(([(header::CONTENT_TYPE, format.content_type())], axum::body::boxed(body)).into_response())
+}
+
+/// Sends the rows of the query while it is evaluated, where the expression is evaluated one group at a time (if it can be
+/// split by group) such that the rows of each group are sent as soon as the group has been computed.
+async fn streamed_rows_response(metrics_engine: Arc<MetricsEngine>,
+                                input_query: InputMetricQuery,
+                                request_id: RequestId,
+                                duration: Option<Duration>,
+                                annotations: Option<Vec<Annotation>>,
+                                format: ResponseFormat) -> ServerResult<Response> {
+    let (result_sender, mut result_receiver) = tokio::sync::mpsc::channel::<ServerResult<OperationResult>>(16);
+    let output = input_query.output;
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        let expressions = if input_query.relabel.is_none() {
+            querying::split_by_group(
+                &input_query.expression,
+                |metric, query, key| metrics_engine.group_values(metric, query, key)
+            )
+        } else {
+            Ok(None)
+        };
+
+        let expressions = match expressions {
+            Ok(Some(expressions)) if !expressions.is_empty() => expressions,
+            Ok(_) => vec![input_query.expression.clone()],
+            Err(err) => {
+                let _ = result_sender.blocking_send(Err(err));
+                return;
+            }
+        };
+
+        for expression in expressions {
+            let query = input_query.metric_query(expression, &request_id);
+            let result = match duration {
+                Some(duration) => metrics_engine.query_in_window(query, duration),
+                None => metrics_engine.query(query)
+            };
+
+            // The client has gone away
+            let failed = result.is_err();
+            if result_sender.blocking_send(result).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    // The first result decides the status code and the columns of the response
+    let first_value = result_receiver.recv().await.ok_or(MetricsEngineError::UnexpectedResult)??;
+    if let Some(error_message) = first_value.error_message() {
+        return Ok(error_message_response(error_message));
+    }
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let columns = first_value.columns();
+        if format == ResponseFormat::Csv && sender.send_data(Bytes::from(columns.join(",") + "\n")).await.is_err() {
+            return;
+        }
+
+        let mut next_value = Some(first_value);
+        while let Some(value) = next_value.take() {
+            for line in row_lines(&value, &columns, format, &output) {
+                // The client has gone away
+                if sender.send_data(Bytes::from(line)).await.is_err() {
+                    return;
+                }
+            }
+
+            next_value = match result_receiver.recv().await {
+                Some(Ok(value)) => Some(value),
+                Some(Err(err)) => {
+                    // The status code has already been sent, so the response is aborted to tell the client that it failed
+                    tracing::error!(error = %err, "Failed to evaluate the rows of the query.");
+                    sender.abort();
+                    return;
+                }
+                None => None
+            };
+        }
+
+        for line in annotation_lines(annotations) {
+            if sender.send_data(Bytes::from(line)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(([(header::CONTENT_TYPE, format.content_type())], axum::body::boxed(body)).into_response())
+}
+
+fn row_lines<'a>(value: &'a OperationResult,
+                 columns: &'a [&'static str],
+                 format: ResponseFormat,
+                 output: &'a JsonOptions) -> impl Iterator<Item=String> + Send + 'a {
+    value.rows(output).map(move |row| {
+        match format {
+            ResponseFormat::Csv => csv_line(&row),
+            _ => json_line(columns, row)
+        }
+    })
+}
+
+/// The annotations are sent after the rows, as one line each.
+fn annotation_lines(annotations: Option<Vec<Annotation>>) -> impl Iterator<Item=String> + Send {
+    annotations
+        .into_iter()
+        .flatten()
+        .map(|annotation| json!({ "annotation": annotation }).to_string() + "\n")
+}
+
+fn error_message_response(error_message: String) -> Response {
+    with_response_code(Json(ErrorMessage { message: error_message }).into_response(), StatusCode::BAD_REQUEST)
+}
+
+fn csv_line(row: &[serde_json::Value]) -> String {
+    let mut line = row.iter().map(csv_field).collect::<Vec<_>>().join(",");
+    line.push('\n');
+    line
+}
+
+fn csv_field(value: &serde_json::Value) -> String {
+    let field = match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(value) => value.clone(),
+        serde_json::Value::Array(values) => values.iter().map(csv_field).collect::<Vec<_>>().join(","),
+        value => value.to_string()
+    };
+
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn json_line(columns: &[&'static str], row: Vec<serde_json::Value>) -> String {
+    let object = columns
+        .iter()
+        .map(|column| column.to_string())
+        .zip(row)
+        .collect::<serde_json::Map<_, _>>();
+
+    let mut line = serde_json::Value::Object(object).to_string();
+    line.push('\n');
+    line
+}
+
+fn operation_result_response(value: OperationResult,
+                             annotations: Option<Vec<Annotation>>,
+                             output: &JsonOptions,
+                             format: ResponseFormat) -> ServerResult<Response> {
+    if let Some(error_message) = value.error_message() {
+        return Ok(error_message_response(error_message));
+    }
+
+    Ok(
+        encode_response(
+            format,
+            QueryResponse {
+                value: value.with_options(output),
+                annotations
+            }
+        )
+    )
+}
+
+#[derive(Serialize, ToSchema)]
+struct QueryResponse<'a> {
+    value: WithOptions<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<Vec<Annotation>>
+}
+
+fn with_response_code(mut response: Response, code: StatusCode) -> Response {
+    *response.status_mut() = code;
+    response
+}
+#[cfg(test)]
+fn test_app(storage_folder: &std::path::Path, configure: impl FnOnce(&mut Config)) -> (Arc<AppState>, Router) {
+    let mut config = Config {
+        storage_folder: storage_folder.to_str().unwrap().to_owned(),
+        ..Default::default()
+    };
+    configure(&mut config);
+
+    let app_state = Arc::new(AppState::new(&config));
+    (app_state.clone(), router(app_state))
+}
+
+#[cfg(test)]
+async fn test_request(app: &Router,
+                      method: &str,
+                      uri: &str,
+                      headers: &[(&str, &str)],
+                      body: Option<serde_json::Value>) -> (StatusCode, HeaderMap, Bytes) {
+    use tower::ServiceExt;
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+
+    let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+    let response = app.clone().oneshot(request.body(body).unwrap()).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    (status, headers, hyper::body::to_bytes(response.into_body()).await.unwrap())
+}
+
+#[cfg(test)]
+fn add_test_gauge_values(app_state: &AppState, start_time: f64) {
+    app_state.metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+    app_state.metrics_engine.gauge(
+        "cpu",
+        (0..4).map(|index| {
+            let host = if index % 2 == 0 { "h1" } else { "h2" };
+            AddGaugeValue::new(start_time + index as f64, 1.0 + (index % 2) as f64, vec![Tag::from_ref("host", host)])
+        })
+    ).unwrap();
+}
+
+#[tokio::test]
+async fn test_metric_query_rows1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 10.0 },
+        "expression": { "Average": { "metric": "cpu", "query": { "group_by": "host" } } }
+    });
+
+    let (status, headers, body) = test_request(&app, "POST", "/metrics/query", &[("accept", "text/csv")], Some(query.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("text/csv", headers[header::CONTENT_TYPE]);
+    assert_eq!("group,value\nh1,1.0\nh2,2.0\n", String::from_utf8(body.to_vec()).unwrap());
+
+    let (status, headers, body) = test_request(&app, "POST", "/metrics/query", &[("accept", "application/x-ndjson")], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("application/x-ndjson", headers[header::CONTENT_TYPE]);
+    assert_eq!(
+        "{\"group\":\"h1\",\"value\":1.0}\n{\"group\":\"h2\",\"value\":2.0}\n",
+        String::from_utf8(body.to_vec()).unwrap()
+    );
+
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 10.0 },
+        "expression": { "Average": { "metric": "memory", "query": {} } }
+    });
+    let (status, _, _) = test_request(&app, "POST", "/metrics/query", &[("accept", "text/csv")], Some(query)).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[tokio::test]
+async fn test_metric_query_rows_annotations1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+    app_state.metrics_engine.add_annotation(Annotation::new(start_time + 1.0, "deploy", Vec::new())).unwrap();
+
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 4.0 },
+        "duration": 2.0,
+        "expression": { "Average": { "metric": "cpu", "query": {} } },
+        "include_annotations": true
+    });
+
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query", &[("accept", "application/x-ndjson")], Some(query.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    let lines = String::from_utf8(body.to_vec()).unwrap().lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()).collect::<Vec<_>>();
+    assert_eq!(3, lines.len());
+    assert_eq!(json!({ "time": start_time, "value": 1.5 }), lines[0]);
+    assert_eq!(json!({ "annotation": { "time": start_time + 1.0, "text": "deploy", "tags": [] } }), lines[2]);
+
+    let (status, _, _) = test_request(&app, "POST", "/metrics/query", &[("accept", "text/csv")], Some(query)).await;
+    assert_eq!(StatusCode::BAD_REQUEST, status);
+}
+
+#[tokio::test]
+async fn test_metric_query_binary1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 4.0 },
+        "duration": 2.0,
+        "expression": { "Average": { "metric": "cpu", "query": { "group_by": "host" } } },
+        "output": { "named_fields": true }
+    });
+
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query", &[], Some(query.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    let expected = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+    assert_eq!(json!({ "time": start_time, "value": 1.0 }), expected["value"][0]["values"][0]);
+
+    let (status, headers, body) = test_request(&app, "POST", "/metrics/query", &[("accept", "application/msgpack")], Some(query.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("application/msgpack", headers[header::CONTENT_TYPE]);
+    assert_eq!(expected, rmp_serde::from_slice::<serde_json::Value>(&body).unwrap());
+
+    let (status, headers, body) = test_request(&app, "POST", "/metrics/query", &[("accept", "application/cbor")], Some(query.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("application/cbor", headers[header::CONTENT_TYPE]);
+    assert_eq!(expected, ciborium::de::from_reader::<serde_json::Value, _>(body.as_ref()).unwrap());
+
+    let batch_query = json!({ "queries": [{ "id": "q1", "time_range": query["time_range"], "duration": 2.0, "expression": query["expression"], "output": query["output"] }] });
+    let (status, _, body) = test_request(&app, "POST", "/query/batch", &[("accept", "application/msgpack")], Some(batch_query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(expected["value"], rmp_serde::from_slice::<serde_json::Value>(&body).unwrap()["results"]["q1"]["value"]);
+}
+
+#[tokio::test]
+async fn test_admin_authorization1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (_, app) = test_app(
+        temp_metric_data.path(),
+        |config| {
+            config.access.api_keys.insert("teamA".to_owned(), vec![MetricAccessRule::new("teamA_*", true, true)]);
+            config.access.api_keys.insert("admin".to_owned(), vec![MetricAccessRule::new("*", true, true)]);
+        }
+    );
+
+    let time_range = json!({ "start": 1654077600.0, "end": 1654077610.0 });
+    let annotation = json!({ "time": 1654077600.0, "text": "deploy" });
+    let requests = vec![
+        ("GET", "/status", None),
+        ("GET", "/slow-queries", None),
+        ("PUT", "/annotations", Some(annotation)),
+        ("POST", "/annotations/query", Some(json!({ "time_range": time_range }))),
+        ("POST", "/snapshots/post", None),
+        ("POST", "/webhooks/test", None),
+        ("PUT", "/dashboards/d1", Some(json!({ "panels": [] }))),
+        ("GET", "/dashboards", None),
+        ("GET", "/dashboards/d1", None),
+        ("DELETE", "/dashboards/d1", None)
+    ];
+
+    for (method, uri, body) in requests {
+        let (status, _, _) = test_request(&app, method, uri, &[("x-api-key", "teamA")], body.clone()).await;
+        assert_eq!(StatusCode::FORBIDDEN, status, "{} {}", method, uri);
+
+        let (status, _, _) = test_request(&app, method, uri, &[("x-api-key", "admin")], body).await;
+        assert_eq!(StatusCode::OK, status, "{} {}", method, uri);
+    }
+
+    let (status, _, _) = test_request(&app, "POST", "/snapshots/pre", &[("x-api-key", "teamA")], None).await;
+    assert_eq!(StatusCode::FORBIDDEN, status);
+    let (status, _, _) = test_request(&app, "POST", "/snapshots", &[("x-api-key", "teamA")], None).await;
+    assert_eq!(StatusCode::FORBIDDEN, status);
+}
+
+#[tokio::test]
+async fn test_metric_query_long_poll1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let metrics_engine = app_state.metrics_engine.clone();
+    let writer = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        metrics_engine.gauge("cpu", std::iter::once(AddGaugeValue::new(start_time + 5.0, 3.0, Vec::new()))).unwrap();
+    });
+
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 10.0 },
+        "duration": 2.0,
+        "expression": { "Max": { "metric": "cpu", "query": {} } }
+    });
+
+    let request_start = Instant::now();
+    let uri = format!("/metrics/query?since_time={}&timeout=10", start_time + 2.0);
+    let (status, _, body) = test_request(&app, "POST", &uri, &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(request_start.elapsed() < Duration::from_secs(10));
+    assert_eq!(json!([start_time + 4.0, 3.0]), serde_json::from_slice::<serde_json::Value>(&body).unwrap()["value"][2]);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_unknown_metrics1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(
+        temp_metric_data.path(),
+        |config| {
+            config.templates.push(MetricTemplate::new("cpu_*", MetricType::Gauge));
+            config.strict = true;
+            config.ingestion.api_keys.insert("dropping".to_owned(), UnknownMetricMode::Drop);
+            config.ingestion.api_keys.insert("creating".to_owned(), UnknownMetricMode::AutoCreate);
+        }
+    );
+
+    let values = json!([{ "time": 1654077600.0, "value": 1.0, "tags": [] }]);
+    let (status, _, _) = test_request(&app, "PUT", "/metrics/gauge/cpu_usage", &[], Some(values.clone())).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+
+    let (status, _, body) = test_request(&app, "PUT", "/metrics/gauge/cpu_usage", &[("x-api-key", "dropping")], Some(values.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!({ "num_inserted": 0 }), serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+    assert_eq!(1, app_state.metrics_engine.dropped_values());
+
+    let (status, _, body) = test_request(&app, "PUT", "/metrics/gauge/cpu_usage", &[("x-api-key", "creating")], Some(values)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!({ "num_inserted": 1 }), serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+    assert_eq!(vec!["cpu_usage".to_owned()], app_state.metrics_engine.metric_names());
+}
+
+#[tokio::test]
+async fn test_batch_query1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let time_range = json!({ "start": start_time, "end": start_time + 4.0 });
+    let batch_query = json!({
+        "queries": [
+            { "id": "average", "time_range": time_range, "expression": { "Average": { "metric": "cpu", "query": {} } } },
+            { "id": "windows", "time_range": time_range, "duration": 2.0, "expression": { "Max": { "metric": "cpu", "query": {} } } },
+            { "id": "missing", "time_range": time_range, "expression": { "Average": { "metric": "memory", "query": {} } } }
+        ]
+    });
+
+    let (status, _, body) = test_request(&app, "POST", "/query/batch", &[], Some(batch_query)).await;
+    assert_eq!(StatusCode::OK, status);
+
+    // A failing query only fails its own result
+    let results = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["results"].clone();
+    assert_eq!(json!({ "value": 1.5 }), results["average"]);
+    assert_eq!(json!({ "value": [[start_time, 2.0], [start_time + 2.0, 2.0]] }), results["windows"]);
+    assert!(results["missing"]["message"].is_string());
+}
+
+#[tokio::test]
+async fn test_metric_query_aggregate1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let time_range = json!({ "start": start_time, "end": start_time + 4.0 });
+    let query_value = |body: Bytes| serde_json::from_slice::<serde_json::Value>(&body).unwrap()["value"].clone();
+
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query/aggregate", &[], Some(json!({ "time_range": time_range, "metric": "cpu" }))).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!(1.5), query_value(body));
+
+    let query = json!({ "time_range": time_range, "metric": "cpu", "aggregation": { "Percentile": 100 }, "query": { "group_by": "host" } });
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query/aggregate", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!([["h1", 1.0], ["h2", 2.0]]), query_value(body));
+
+    let query = json!({ "time_range": time_range, "duration": 2.0, "metric": "cpu", "aggregation": "Sum", "query": { "group_by": "host" } });
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query/aggregate", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(
+        json!([["h1", [[start_time, 1.0], [start_time + 2.0, 1.0]]], ["h2", [[start_time, 2.0], [start_time + 2.0, 2.0]]]]),
+        query_value(body)
+    );
+
+    let (status, _, _) = test_request(&app, "POST", "/metrics/query/aggregate", &[], Some(json!({ "time_range": time_range, "metric": "memory" }))).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[tokio::test]
+async fn test_metric_query_stream1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    // Each group is sent as its own line
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 4.0 },
+        "expression": { "Average": { "metric": "cpu", "query": { "group_by": "host" } } }
+    });
+    let (status, headers, body) = test_request(&app, "POST", "/metrics/query/stream", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("application/x-ndjson", headers[header::CONTENT_TYPE]);
+    let lines = String::from_utf8(body.to_vec()).unwrap().lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()).collect::<Vec<_>>();
+    assert_eq!(vec![json!({ "value": [["h1", 1.0]] }), json!({ "value": [["h2", 2.0]] })], lines);
+
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 4.0 },
+        "expression": { "Average": { "metric": "cpu", "query": {} } }
+    });
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query/stream", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("{\"value\":1.5}\n", String::from_utf8(body.to_vec()).unwrap());
+}
+
+#[tokio::test]
+async fn test_slow_queries1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |config| config.slow_queries.threshold = Some(0.0));
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let query = json!({
+        "time_range": { "start": start_time, "end": start_time + 4.0 },
+        "expression": { "Average": { "metric": "cpu", "query": {} } }
+    });
+    let (status, headers, _) = test_request(&app, "POST", "/metrics/query", &[(REQUEST_ID_HEADER, "r1")], Some(query.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("r1", headers[REQUEST_ID_HEADER]);
+
+    // Assigned if not given
+    let (status, headers, _) = test_request(&app, "POST", "/metrics/query", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(!headers[REQUEST_ID_HEADER].is_empty());
+
+    let (status, _, body) = test_request(&app, "GET", "/slow-queries?limit=10", &[], None).await;
+    assert_eq!(StatusCode::OK, status);
+    let queries = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["queries"].as_array().unwrap().clone();
+    assert_eq!(2, queries.len());
+    assert!(queries.iter().any(|query| query["request_id"] == "r1"));
+    assert!(queries.iter().all(|query| query["query"].is_object()));
+}
+
+#[tokio::test]
+async fn test_flush_metric1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let (status, _, _) = test_request(&app, "POST", "/metrics/cpu/flush", &[], None).await;
+    assert_eq!(StatusCode::OK, status);
+
+    let (status, _, _) = test_request(&app, "POST", "/metrics/memory/flush", &[], None).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[tokio::test]
+async fn test_compact1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let (status, _, body) = test_request(&app, "POST", "/admin/compact", &[], None).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!({ "num_removed_segments": 0 }), serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+
+    let (status, _, _) = test_request(&app, "POST", "/admin/compact?metric=cpu", &[], None).await;
+    assert_eq!(StatusCode::OK, status);
+
+    let (status, _, _) = test_request(&app, "POST", "/admin/compact?metric=memory", &[], None).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[tokio::test]
+async fn test_primary_tags1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+    app_state.metrics_engine.add_metric("cpu", MetricType::Gauge).unwrap();
+
+    let response_json = |body: Bytes| serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+
+    let (status, _, _) = test_request(&app, "POST", "/metrics/cpu/primary-tags", &[], Some(json!({ "tag": "host:h1" }))).await;
+    assert_eq!(StatusCode::OK, status);
+    let (status, _, _) = test_request(&app, "POST", "/metrics/primary-tag/cpu", &[], Some(json!({ "tag": "host:h2" }))).await;
+    assert_eq!(StatusCode::OK, status);
+    let (status, _, body) = test_request(&app, "GET", "/metrics/cpu/primary-tags", &[], None).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!({ "primary_tags": ["host:h1", "host:h2"] }), response_json(body));
+
+    let (_, _, body) = test_request(&app, "DELETE", "/metrics/cpu/primary-tags/host:h1", &[], None).await;
+    assert_eq!(json!({ "removed": true }), response_json(body));
+    let (_, _, body) = test_request(&app, "DELETE", "/metrics/cpu/primary-tags/host:h1", &[], None).await;
+    assert_eq!(json!({ "removed": false }), response_json(body));
+    let (_, _, body) = test_request(&app, "GET", "/metrics/cpu/primary-tags", &[], None).await;
+    assert_eq!(json!({ "primary_tags": ["host:h2"] }), response_json(body));
+
+    let (status, _, _) = test_request(&app, "POST", "/metrics/cpu/auto-primary-tags", &[], Some(json!({ "key": "host" }))).await;
+    assert_eq!(StatusCode::OK, status);
+    let (_, _, body) = test_request(&app, "GET", "/metrics/cpu/auto-primary-tags", &[], None).await;
+    assert_eq!(json!({ "keys": ["host"] }), response_json(body));
+    let (_, _, body) = test_request(&app, "DELETE", "/metrics/cpu/auto-primary-tags/host", &[], None).await;
+    assert_eq!(json!({ "removed": true }), response_json(body));
+    let (_, _, body) = test_request(&app, "GET", "/metrics/cpu/auto-primary-tags", &[], None).await;
+    assert_eq!(json!({ "keys": [] }), response_json(body));
+
+    let (status, _, _) = test_request(&app, "GET", "/metrics/memory/primary-tags", &[], None).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[tokio::test]
+async fn test_convenience_queries1() {
+    let temp_metric_data = tempfile::tempdir().unwrap();
+    let (app_state, app) = test_app(temp_metric_data.path(), |_| {});
+
+    let start_time = 1654077600.0;
+    add_test_gauge_values(&app_state, start_time);
+
+    let time_range = json!({ "start": start_time, "end": start_time + 4.0 });
+    let query_value = |body: Bytes| serde_json::from_slice::<serde_json::Value>(&body).unwrap()["value"].clone();
+
+    let query = json!({ "time_range": time_range, "numerator": "cpu", "denominator": "cpu", "aggregation": "Max" });
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query/ratio", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!(1.0), query_value(body));
+
+    let query = json!({ "time_range": time_range, "metric": "cpu", "aggregation": "Sum", "query": { "group_by": "host" } });
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query/percent-of-total", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    let value = query_value(body);
+    assert_eq!("h1", value[0][0]);
+    assert!((value[0][1].as_f64().unwrap() - 100.0 / 3.0).abs() < 1E-6);
+    assert!((value[1][1].as_f64().unwrap() - 200.0 / 3.0).abs() < 1E-6);
+
+    // Compared against the two seconds before
+    let query = json!({ "time_range": { "start": start_time + 1.5, "end": start_time + 3.5 }, "metric": "cpu", "aggregation": "Sum", "period": 2.0 });
+    let (status, _, body) = test_request(&app, "POST", "/metrics/query/delta", &[], Some(query)).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(json!(0.0), query_value(body));
+
+    let query = json!({ "time_range": time_range, "numerator": "cpu", "denominator": "memory" });
+    let (status, _, _) = test_request(&app, "POST", "/metrics/query/ratio", &[], Some(query)).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
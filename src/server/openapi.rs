@@ -0,0 +1,137 @@
+use utoipa::{Modify, OpenApi};
+use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+
+use super::*;
+
+/// The document is derived from the `utoipa::path` attributes of the handlers and the types of their requests and responses.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "MetricsDB"),
+    paths(
+        create_gauge_metric,
+        add_gauge_metric_value,
+        create_count_metric,
+        add_count_metric_value,
+        create_ratio_metric,
+        add_ratio_metric_value,
+        metric_query,
+        metric_query_stream,
+        metric_query_aggregate,
+        metric_query_ratio,
+        metric_query_percent_of_total,
+        metric_query_delta,
+        batch_query,
+        add_primary_tag_legacy,
+        add_auto_primary_tag_legacy,
+        list_primary_tags,
+        add_primary_tag,
+        remove_primary_tag,
+        list_auto_primary_tags,
+        add_auto_primary_tag,
+        remove_auto_primary_tag,
+        flush_metric,
+        delete_metric,
+        undelete_metric,
+        list_deleted_metrics,
+        write_protobuf,
+        status,
+        add_annotation,
+        query_annotations,
+        test_webhooks,
+        create_snapshot,
+        pre_snapshot,
+        post_snapshot,
+        list_dashboards,
+        get_dashboard,
+        put_dashboard,
+        delete_dashboard,
+        slow_queries,
+        compact,
+        audit_log,
+        export_schema,
+        apply_schema,
+        diff_schema,
+        api_docs
+    ),
+    components(schemas(ErrorMessage)),
+    modifiers(&ErrorResponses)
+)]
+struct ApiDoc;
+
+/// The OpenAPI document describing the routes of the server.
+pub fn document() -> serde_json::Value {
+    serde_json::to_value(ApiDoc::openapi()).unwrap()
+}
+
+/// All operations fail with an error message, with the status code given by the error.
+struct ErrorResponses;
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let error_response = ResponseBuilder::new()
+            .description("Error")
+            .content("application/json", ContentBuilder::new().schema(Some(Ref::from_schema_name("ErrorMessage"))).build())
+            .build();
+
+        for path_item in openapi.paths.paths.values_mut() {
+            let operations = [&mut path_item.get, &mut path_item.put, &mut path_item.post, &mut path_item.delete];
+            for operation in operations.into_iter().flatten() {
+                for status in ["400", "403", "404", "409", "500"] {
+                    operation.responses.responses.insert(status.to_owned(), error_response.clone().into());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_document1() {
+    let document = document();
+
+    // All references must point to a defined schema
+    fn visit(value: &serde_json::Value, schemas: &serde_json::Value) {
+        match value {
+            serde_json::Value::Object(object) => {
+                if let Some(reference) = object.get("$ref").and_then(|reference| reference.as_str()) {
+                    let name = reference.trim_start_matches("#/components/schemas/");
+                    assert!(schemas.get(name).is_some(), "undefined schema: {}", name);
+                }
+
+                for value in object.values() {
+                    visit(value, schemas);
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    visit(value, schemas);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    visit(&document, &document["components"]["schemas"]);
+    assert!(document["paths"].get("/metrics/query").is_some());
+    assert!(document["paths"].get("/metrics/gauge/{name}").is_some());
+    assert!(document["paths"].get("/metrics/query/aggregate").is_some());
+    assert!(document["paths"]["/metrics/{name}/primary-tags"].get("get").is_some());
+    assert!(document["paths"]["/metrics/{name}/primary-tags"].get("post").is_some());
+    assert_eq!(
+        "#/components/schemas/ErrorMessage",
+        document["paths"]["/status"]["get"]["responses"]["500"]["content"]["application/json"]["schema"]["$ref"]
+    );
+}
+
+#[test]
+fn test_document2() {
+    let document = document();
+    let schemas = &document["components"]["schemas"];
+
+    // The schemas follow the serde representation of the types
+    assert!(schemas["MetricQueryExpression"]["oneOf"].as_array().unwrap().len() > 10);
+    assert_eq!("string", schemas["Tag"]["type"]);
+    assert!(schemas["Query"]["properties"].get("group_by").is_some());
+    assert!(schemas["Query"]["properties"].get("consistency").is_some());
+    assert!(schemas["InputMetricQuery"]["properties"].get("join").is_some());
+    assert!(schemas["AnnotationsResponse"]["properties"].get("annotations").is_some());
+}
@@ -1,23 +1,31 @@
+use std::io::Read;
 use std::marker::PhantomData;
 use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::storage::memory_file::MemoryFile;
+use crate::storage::lock_file::LockFile;
+use crate::storage::memory_file::{MemoryAdvice, MemoryFile};
 use crate::model::{Datapoint, MetricError, MetricResult, Tags, Time};
 use crate::storage::{MetricStorage, MetricStorageConfig};
 
 const STORAGE_MAX_SIZE: usize = 8 * 1024 * 1024 * 1024;
 const INDEX_MAX_SIZE: usize = 1024 * 1024;
 const SYNC_INTERVAL: Duration = Duration::new(2, 0);
+const CHECKED_ACCESS: bool = cfg!(feature = "checked-access");
+const COMPRESSED_EXTENSION: &str = "zst";
 
 pub struct FileMetricStorage<E> {
     base_path: PathBuf,
-    metadata_file: MemoryFile,
+    metadata_file: Arc<MemoryFile>,
     segments: Vec<Segment<E>>,
     last_async: std::time::Instant,
     last_sync: std::time::Instant,
     requires_sync: bool,
+    read_only: bool,
+    pinned_size: usize,
+    _lock: Option<LockFile>,
     _phantom: PhantomData<E>,
 }
 
@@ -68,6 +76,58 @@ impl<E: Copy> FileMetricStorage<E> {
         self.segments[segment_index].block_at_ptr(index)
     }
 
+    fn load(base_path: &Path, read_only: bool) -> MetricResult<Self> {
+        let lock = if read_only {
+            None
+        } else {
+            Some(LockFile::acquire(base_path)?)
+        };
+
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(base_path).map_err(|err| MetricError::FailedToLoadMetric(base_path.to_owned(), err))?.flatten() {
+            if let Some(Component::Normal(component)) = entry.path().components().next_back() {
+                if let Some(component) = component.to_str() {
+                    if component.ends_with(".storage") {
+                        if let Some(segment_index) = component.split(".").next().and_then(|part| usize::from_str(part).ok()) {
+                            segments.push((segment_index, Segment::from_existing(base_path, segment_index, read_only)?));
+                        }
+                    }
+                }
+            }
+        }
+
+        segments.sort_by_key(|(index, _)| *index);
+        let mut segments = segments.into_iter().map(|(_, segment)| segment).collect::<Vec<_>>();
+
+        if !read_only {
+            if let Some(active_segment) = segments.last_mut() {
+                active_segment.recover_active_block()?;
+            }
+        }
+
+        let metadata_path = base_path.join("metadata");
+        let metadata_file = if read_only {
+            MemoryFile::read_only(&metadata_path, std::mem::size_of::<Metadata>())?
+        } else {
+            MemoryFile::new(&metadata_path, std::mem::size_of::<Metadata>(), false)?
+        };
+
+        Ok(
+            FileMetricStorage {
+                base_path: base_path.to_owned(),
+                metadata_file: Arc::new(metadata_file),
+                segments,
+                last_sync: std::time::Instant::now(),
+                last_async: std::time::Instant::now(),
+                requires_sync: false,
+                read_only,
+                pinned_size: 0,
+                _lock: lock,
+                _phantom: Default::default()
+            }
+        )
+    }
+
     fn try_sync_active_block(&mut self) {
         if self.requires_sync {
             if (std::time::Instant::now() - self.last_sync) >= SYNC_INTERVAL {
@@ -78,8 +138,11 @@ impl<E: Copy> FileMetricStorage<E> {
                 };
 
                 if ok {
+                    tracing::trace!(path = ?self.base_path, "synced active block");
                     self.last_sync = std::time::Instant::now();
                     self.requires_sync = false;
+                } else {
+                    tracing::warn!(path = ?self.base_path, "failed to sync active block");
                 }
             } else if (std::time::Instant::now() - self.last_async) >= SYNC_INTERVAL / 2 {
                 let ok = unsafe {
@@ -101,6 +164,7 @@ impl<E: Copy> FileMetricStorage<E> {
             unsafe { (*self.metadata()).num_segments },
         )?;
 
+        let pinned_size = self.pinned_size;
         let active_segment = self.active_segment_mut();
 
         unsafe {
@@ -119,15 +183,25 @@ impl<E: Copy> FileMetricStorage<E> {
             }
 
             result?;
+
+            if pinned_size > 0 {
+                active_segment.storage_file.unlock(active_segment.storage_file.ptr(), pinned_size);
+            }
+
+            // The sealed segment is only read by queries from now on, so no need to keep it in memory
+            active_segment.advise_blocks(0, active_segment.len().saturating_sub(1), MemoryAdvice::DontNeed);
         }
 
         self.segments.push(new_segment);
+        self.pinned_size = 0;
 
         unsafe {
             (*self.metadata_mut()).num_segments += 1;
             self.metadata_file.sync(self.metadata() as *const u8, std::mem::size_of::<Metadata>(), false)?;
         }
 
+        tracing::debug!(path = ?self.base_path, num_segments = self.segments.len(), "created segment");
+
         Ok(())
     }
 
@@ -136,9 +210,12 @@ impl<E: Copy> FileMetricStorage<E> {
             if self.segments.len() > max_segments {
                 let segment = self.segments.remove(0);
                 if let Err(err) = segment.remove() {
+                    tracing::warn!(path = ?self.base_path, error = %err, "failed to remove segment");
                     self.segments.insert(0, segment);
                     return Err(err);
                 }
+
+                tracing::debug!(path = ?self.base_path, num_segments = self.segments.len(), "removed oldest segment");
             }
         }
 
@@ -148,13 +225,18 @@ impl<E: Copy> FileMetricStorage<E> {
 
 impl<E: Copy> MetricStorage<E> for FileMetricStorage<E> {
     fn new(base_path: &Path, config: MetricStorageConfig) -> Result<Self, MetricError> {
+        let lock = LockFile::acquire(base_path)?;
+
         let mut storage = FileMetricStorage {
             base_path: base_path.to_owned(),
-            metadata_file: MemoryFile::new(&base_path.join("metadata"), std::mem::size_of::<Metadata>(), true)?,
+            metadata_file: Arc::new(MemoryFile::new(&base_path.join("metadata"), std::mem::size_of::<Metadata>(), true)?),
             segments: vec![Segment::new(base_path, 0)?],
             last_sync: std::time::Instant::now(),
             last_async: std::time::Instant::now(),
             requires_sync: false,
+            read_only: false,
+            pinned_size: 0,
+            _lock: Some(lock),
             _phantom: Default::default()
         };
 
@@ -164,35 +246,27 @@ impl<E: Copy> MetricStorage<E> for FileMetricStorage<E> {
     }
 
     fn from_existing(base_path: &Path) -> Result<Self, MetricError> {
-        let mut segments = Vec::new();
-        for entry in std::fs::read_dir(base_path).map_err(|err| MetricError::FailedToLoadMetric(err))? {
-            if let Ok(entry) = entry {
-                if let Some(Component::Normal(component)) = entry.path().components().last() {
-                    if let Some(component) = component.to_str() {
-                        if component.ends_with(".storage") {
-                            if let Some(segment_index) = component.split(".").next().map(|part| usize::from_str(part).ok()).flatten() {
-                                segments.push((segment_index, Segment::from_existing(base_path, segment_index)?));
-                            }
-                        }
-                    }
-                }
-            }
-        }
+        FileMetricStorage::load(base_path, false)
+    }
 
-        segments.sort_by_key(|(index, _)| *index);
-        let segments = segments.into_iter().map(|(_, segment)| segment).collect::<Vec<_>>();
+    fn from_existing_read_only(base_path: &Path) -> MetricResult<Self> {
+        FileMetricStorage::load(base_path, true)
+    }
 
-        Ok(
-            FileMetricStorage {
-                base_path: base_path.to_owned(),
-                metadata_file: MemoryFile::new(&base_path.join("metadata"), std::mem::size_of::<Metadata>(), false)?,
-                segments,
-                last_sync: std::time::Instant::now(),
-                last_async: std::time::Instant::now(),
-                requires_sync: false,
-                _phantom: Default::default()
-            }
-        )
+    fn snapshot(&self) -> Self {
+        let num_segments = self.segments.len();
+        FileMetricStorage {
+            base_path: self.base_path.clone(),
+            metadata_file: self.metadata_file.clone(),
+            segments: self.segments.iter().enumerate().map(|(index, segment)| segment.snapshot(index + 1 == num_segments)).collect(),
+            last_sync: std::time::Instant::now(),
+            last_async: std::time::Instant::now(),
+            requires_sync: false,
+            read_only: true,
+            pinned_size: 0,
+            _lock: None,
+            _phantom: Default::default()
+        }
     }
 
     fn segment_duration(&self) -> u64 {
@@ -242,10 +316,20 @@ impl<E: Copy> MetricStorage<E> for FileMetricStorage<E> {
     }
 
     fn active_block_datapoints_mut(&mut self, tags: Tags) -> Option<&mut [Datapoint<E>]> {
+        if self.read_only {
+            return None;
+        }
+
         self.active_segment_mut().active_block_datapoints_mut(tags)
     }
 
     fn create_block(&mut self, time: Time) -> Result<(), MetricError> {
+        if self.read_only {
+            return Err(MetricError::ReadOnly);
+        }
+
+        tracing::trace!(path = ?self.base_path, time, "creating block");
+
         if self.active_segment().len() >= self.num_blocks_per_segment() {
             self.create_segment()?;
         }
@@ -283,12 +367,16 @@ impl<E: Copy> MetricStorage<E> for FileMetricStorage<E> {
     }
 
     fn add_datapoint(&mut self, tags: Tags, datapoint: Datapoint<E>) -> Result<(), MetricError> {
+        if self.read_only {
+            return Err(MetricError::ReadOnly);
+        }
+
         let active_segment = self.active_segment_mut();
         unsafe {
             let active_block = active_segment.active_block_mut();
             let datapoint_time = (*active_block).start_time + datapoint.time_offset as Time;
 
-            let sub_block = active_segment.allocate_sub_block_for_insertion(active_block, tags)?;
+            let sub_block = active_segment.allocate_sub_block_for_insertion(active_block, tags, 1)?;
             sub_block.add_datapoint(active_block, datapoint);
             (*active_block).end_time = (*active_block).end_time.max(datapoint_time);
 
@@ -300,28 +388,285 @@ impl<E: Copy> MetricStorage<E> for FileMetricStorage<E> {
         Ok(())
     }
 
+    fn add_datapoints(&mut self, tags: Tags, datapoints: &[Datapoint<E>]) -> MetricResult<()> {
+        if self.read_only {
+            return Err(MetricError::ReadOnly);
+        }
+
+        if datapoints.is_empty() {
+            return Ok(());
+        }
+
+        let active_segment = self.active_segment_mut();
+        unsafe {
+            let active_block = active_segment.active_block_mut();
+
+            let sub_block = active_segment.allocate_sub_block_for_insertion(active_block, tags, datapoints.len() as u32)?;
+            for datapoint in datapoints {
+                let datapoint_time = (*active_block).start_time + datapoint.time_offset as Time;
+                sub_block.add_datapoint(active_block, datapoint.clone());
+                (*active_block).end_time = (*active_block).end_time.max(datapoint_time);
+            }
+
+            self.requires_sync = true;
+        }
+
+        self.try_sync_active_block();
+
+        Ok(())
+    }
+
     type BlockIterator<'a> = SubBlockDatapointsIterator<'a, E> where E: 'a;
     fn block_datapoints<'a>(&'a self, block_index: usize) -> Option<Self::BlockIterator<'a>> {
         let block_ptr = self.block_at_ptr(block_index)?;
         Some(SubBlockDatapointsIterator::new(unsafe { &*block_ptr }))
     }
 
+    fn pin_active_segment(&mut self) -> MetricResult<()> {
+        if self.read_only || !self.active_segment().has_blocks() {
+            return Ok(());
+        }
+
+        let active_segment = self.active_segment();
+        let size = unsafe { (*active_segment.header()).active_block_start + (*active_segment.active_block()).size };
+        if size > self.pinned_size {
+            active_segment.storage_file.lock(active_segment.storage_file.ptr(), size)?;
+            self.pinned_size = size;
+        }
+
+        Ok(())
+    }
+
+    fn advise_scan(&self, start_block_index: usize, end_block_index: usize) {
+        let end_block_index = end_block_index.min(self.len().saturating_sub(1));
+        if start_block_index > end_block_index || self.len() == 0 {
+            return;
+        }
+
+        let num_blocks_per_segment = self.num_blocks_per_segment();
+        let start_segment_index = start_block_index / num_blocks_per_segment;
+        let end_segment_index = end_block_index / num_blocks_per_segment;
+        for segment_index in start_segment_index..(end_segment_index + 1) {
+            let segment = &self.segments[segment_index];
+            let first_block_index = if segment_index == start_segment_index { start_block_index % num_blocks_per_segment } else { 0 };
+            let last_block_index = if segment_index == end_segment_index { end_block_index % num_blocks_per_segment } else { segment.len().saturating_sub(1) };
+
+            segment.advise_blocks(first_block_index, last_block_index, MemoryAdvice::Sequential);
+            segment.advise_blocks(first_block_index, last_block_index, MemoryAdvice::WillNeed);
+        }
+    }
+
     fn scheduled(&mut self) {
         self.try_sync_active_block();
     }
+
+    fn flush(&mut self) -> MetricResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        for segment in &mut self.segments {
+            segment.flush()?;
+        }
+
+        unsafe {
+            self.metadata_file.sync(self.metadata() as *const u8, std::mem::size_of::<Metadata>(), false)?;
+        }
+
+        self.last_sync = std::time::Instant::now();
+        self.requires_sync = false;
+        Ok(())
+    }
+
+    fn move_to_cold_tier(&mut self,
+                         hot_root: &Path,
+                         cold_root: &Path,
+                         older_than: Time,
+                         compression_level: Option<i32>) -> MetricResult<usize> {
+        if self.read_only || self.segments.len() < 2 {
+            return Ok(0);
+        }
+
+        let cold_path = match self.base_path.strip_prefix(hot_root) {
+            Ok(relative_path) => cold_root.join(relative_path),
+            Err(_) => return Ok(0)
+        };
+
+        let mut num_moved = 0;
+        for segment_index in 0..(self.segments.len() - 1) {
+            let segment = &self.segments[segment_index];
+            if segment.is_cold() {
+                continue;
+            }
+
+            // Segments are ordered by time, so no later segment can be old enough
+            match segment.time_range() {
+                Some((_, end_time)) if end_time < older_than => {}
+                _ => break
+            }
+
+            self.segments[segment_index] = segment.move_to_cold_tier(&self.base_path, &cold_path, compression_level)?;
+            num_moved += 1;
+            tracing::debug!(path = ?self.base_path, segment_index, "moved segment to the cold tier");
+        }
+
+        Ok(num_moved)
+    }
+
+    fn remove_expired_segments(&mut self, now: Time) -> MetricResult<usize> {
+        let max_segments = match self.max_segments() {
+            Some(max_segments) if !self.read_only => max_segments,
+            _ => return Ok(0)
+        };
+
+        let keep_time = (max_segments * self.num_blocks_per_segment()) as Time * self.block_duration();
+        let mut num_removed = 0;
+        while self.segments.len() > 1 {
+            match self.segments[0].time_range() {
+                Some((_, end_time)) if end_time.saturating_add(keep_time) < now => {}
+                _ => break
+            }
+
+            let segment = self.segments.remove(0);
+            if let Err(err) = segment.remove() {
+                tracing::warn!(path = ?self.base_path, error = %err, "failed to remove segment");
+                self.segments.insert(0, segment);
+                return Err(err);
+            }
+
+            num_removed += 1;
+        }
+
+        if num_removed > 0 {
+            tracing::debug!(path = ?self.base_path, num_removed, num_segments = self.segments.len(), "removed expired segments");
+        }
+
+        Ok(num_removed)
+    }
+
+    fn verify(&self) -> Vec<String> {
+        let num_blocks_per_segment = self.num_blocks_per_segment();
+
+        let mut problems = Vec::new();
+        let mut previous_end_time = None;
+        for (segment_index, segment) in self.segments.iter().enumerate() {
+            let num_sealed_blocks = if segment_index + 1 == self.segments.len() {
+                segment.len().saturating_sub(1)
+            } else {
+                segment.len()
+            };
+
+            for index in 0..num_sealed_blocks {
+                match segment.verify_block(index) {
+                    Ok((start_time, end_time)) => {
+                        if previous_end_time.map(|previous_end_time| start_time < previous_end_time).unwrap_or(false) {
+                            problems.push(format!("block {} overlaps the previous block", segment_index * num_blocks_per_segment + index));
+                        }
+
+                        previous_end_time = Some(end_time);
+                    }
+                    Err(problem) => {
+                        problems.push(format!("block {}: {}", segment_index * num_blocks_per_segment + index, problem));
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+fn remove_segment_file(path: &Path) -> std::io::Result<()> {
+    if let Ok(target) = std::fs::read_link(path) {
+        std::fs::remove_file(target)?;
+    }
+
+    std::fs::remove_file(path)
 }
 
 pub struct Segment<E> {
-    storage_file: MemoryFile,
-    index_file: MemoryFile,
+    storage_file: Arc<MemoryFile>,
+    index_file: Arc<MemoryFile>,
+    snapshot: Option<SegmentSnapshot>,
+    summary: Option<SegmentSummary>,
     _phantom: PhantomData<E>,
 }
 
+/// The blocks seen by a snapshot of a segment, where the active block (still being written to) is copied.
+struct SegmentSnapshot {
+    num_blocks: usize,
+    active_block: Option<Vec<u64>>
+}
+
+/// The number of blocks and the time range of a compressed segment, which are read without decompressing it into memory.
+#[derive(Clone, Copy)]
+struct SegmentSummary {
+    num_blocks: usize,
+    time_range: Option<(Time, Time)>
+}
+
+impl SegmentSummary {
+    fn read<E: Copy>(storage_path: &Path, index_file: &MemoryFile) -> std::io::Result<SegmentSummary> {
+        let invalid_data = || std::io::Error::from(std::io::ErrorKind::InvalidData);
+
+        let mut decoder = zstd::Decoder::new(std::fs::File::open(storage_path)?)?;
+        let header = read_value::<Header>(&mut decoder)?;
+        if header.num_blocks == 0 {
+            return Ok(SegmentSummary { num_blocks: 0, time_range: None });
+        }
+
+        let index_size = header.num_blocks.checked_mul(std::mem::size_of::<usize>()).ok_or_else(invalid_data)?;
+        if index_size > index_file.backing_size() {
+            return Err(invalid_data());
+        }
+
+        let index = index_file.ptr() as *const usize;
+        let (first_block_offset, last_block_offset) = unsafe { (*index, *index.add(header.num_blocks - 1)) };
+        if first_block_offset < std::mem::size_of::<Header>() || last_block_offset < first_block_offset {
+            return Err(invalid_data());
+        }
+
+        // The blocks are stored in order, so only skipping forward is required
+        let mut position = std::mem::size_of::<Header>();
+        let mut read_block = |offset: usize| -> std::io::Result<Block<E>> {
+            let skip = offset.checked_sub(position).ok_or_else(invalid_data)?;
+            if std::io::copy(&mut (&mut decoder).take(skip as u64), &mut std::io::sink())? != skip as u64 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            position = offset + std::mem::size_of::<Block<E>>();
+            read_value::<Block<E>>(&mut decoder)
+        };
+
+        let first_block = read_block(first_block_offset)?;
+        let end_time = if last_block_offset == first_block_offset {
+            first_block.end_time
+        } else {
+            read_block(last_block_offset)?.end_time
+        };
+
+        Ok(
+            SegmentSummary {
+                num_blocks: header.num_blocks,
+                time_range: Some((first_block.start_time, end_time))
+            }
+        )
+    }
+}
+
+fn read_value<T>(reader: &mut impl Read) -> std::io::Result<T> {
+    let mut buffer = vec![0u8; std::mem::size_of::<T>()];
+    reader.read_exact(&mut buffer)?;
+    Ok(unsafe { std::ptr::read_unaligned(buffer.as_ptr() as *const T) })
+}
+
 impl<E: Copy> Segment<E> {
     fn new(base_path: &Path, segment_index: usize) -> Result<Self, MetricError> {
         let mut segment = Segment {
-            storage_file: MemoryFile::new(&base_path.join(Path::new(&format!("{}.storage", segment_index))), STORAGE_MAX_SIZE, true)?,
-            index_file: MemoryFile::new(&base_path.join(Path::new(&format!("{}.index", segment_index))), INDEX_MAX_SIZE, true)?,
+            storage_file: Arc::new(MemoryFile::new(&base_path.join(Path::new(&format!("{}.storage", segment_index))), STORAGE_MAX_SIZE, true)?),
+            index_file: Arc::new(MemoryFile::new(&base_path.join(Path::new(&format!("{}.index", segment_index))), INDEX_MAX_SIZE, true)?),
+            snapshot: None,
+            summary: None,
             _phantom: Default::default()
         };
 
@@ -329,14 +674,50 @@ impl<E: Copy> Segment<E> {
         Ok(segment)
     }
 
-    fn from_existing(base_path: &Path, segment_index: usize) -> Result<Self, MetricError> {
-        Ok(
-            Segment {
-                storage_file: MemoryFile::new(&base_path.join(Path::new(&format!("{}.storage", segment_index))), STORAGE_MAX_SIZE, false)?,
-                index_file: MemoryFile::new(&base_path.join(Path::new(&format!("{}.index", segment_index))), INDEX_MAX_SIZE, false)?,
-                _phantom: Default::default()
-            }
-        )
+    fn from_existing(base_path: &Path, segment_index: usize, read_only: bool) -> Result<Self, MetricError> {
+        let storage_path = base_path.join(Path::new(&format!("{}.storage", segment_index)));
+        let index_path = base_path.join(Path::new(&format!("{}.index", segment_index)));
+
+        let compressed = std::fs::read_link(&storage_path)
+            .map(|target| target.extension().map(|extension| extension == COMPRESSED_EXTENSION).unwrap_or(false))
+            .unwrap_or(false);
+
+        if compressed {
+            // Compressed segments are sealed, so they are never written to and only decompressed once their blocks are read
+            let index_file = MemoryFile::read_only(&index_path, INDEX_MAX_SIZE)?;
+            let summary = SegmentSummary::read::<E>(&storage_path, &index_file)
+                .map_err(|err| MetricError::FailedToLoadMetric(storage_path.clone(), err))?;
+
+            Ok(
+                Segment {
+                    storage_file: Arc::new(MemoryFile::decompressed(&storage_path, STORAGE_MAX_SIZE)?),
+                    index_file: Arc::new(index_file),
+                    snapshot: None,
+                    summary: Some(summary),
+                    _phantom: Default::default()
+                }
+            )
+        } else if read_only {
+            Ok(
+                Segment {
+                    storage_file: Arc::new(MemoryFile::read_only(&storage_path, STORAGE_MAX_SIZE)?),
+                    index_file: Arc::new(MemoryFile::read_only(&index_path, INDEX_MAX_SIZE)?),
+                    snapshot: None,
+                    summary: None,
+                    _phantom: Default::default()
+                }
+            )
+        } else {
+            Ok(
+                Segment {
+                    storage_file: Arc::new(MemoryFile::new(&storage_path, STORAGE_MAX_SIZE, false)?),
+                    index_file: Arc::new(MemoryFile::new(&index_path, INDEX_MAX_SIZE, false)?),
+                    snapshot: None,
+                    summary: None,
+                    _phantom: Default::default()
+                }
+            )
+        }
     }
 
     fn initialize(&mut self) {
@@ -351,22 +732,23 @@ impl<E: Copy> Segment<E> {
 
     fn allocate_sub_block_for_insertion(&mut self,
                                         block_ptr: *mut Block<E>,
-                                        tags: Tags) -> MetricResult<&mut SubBlock<E>> {
+                                        tags: Tags,
+                                        num_datapoints: u32) -> MetricResult<&mut SubBlock<E>> {
         let default_capacity = 100;
         let growth_factor = 2;
 
         unsafe {
             if let Some((sub_block_index, sub_block)) = (*block_ptr).find_sub_block(tags) {
-                if sub_block.count < sub_block.capacity {
+                if sub_block.count + num_datapoints <= sub_block.capacity {
                     Ok(sub_block)
                 } else {
-                    let desired_capacity = sub_block.count * growth_factor;
-                    if let Some(increased_capacity) = (*block_ptr).try_extend(&mut self.storage_file, sub_block_index, sub_block, desired_capacity)? {
+                    let desired_capacity = (sub_block.count * growth_factor).max(sub_block.count + num_datapoints);
+                    if let Some(increased_capacity) = (*block_ptr).try_extend(&self.storage_file, sub_block_index, sub_block, desired_capacity)? {
                         let size = increased_capacity as usize * std::mem::size_of::<Datapoint<E>>();
                         (*block_ptr).size += size;
                         Ok(sub_block)
                     } else {
-                        let (new_sub_block, allocated) = (*block_ptr).allocate_sub_block(&mut self.storage_file, tags, desired_capacity)?;
+                        let (new_sub_block, allocated) = (*block_ptr).allocate_sub_block(&self.storage_file, tags, desired_capacity)?;
                         if allocated {
                             (*block_ptr).size += new_sub_block.size();
                         }
@@ -376,7 +758,7 @@ impl<E: Copy> Segment<E> {
                     }
                 }
             } else {
-                let (sub_block, allocated) = (*block_ptr).allocate_sub_block(&mut self.storage_file, tags, default_capacity)?;
+                let (sub_block, allocated) = (*block_ptr).allocate_sub_block(&self.storage_file, tags, default_capacity.max(num_datapoints))?;
                 if allocated {
                     (*block_ptr).size += sub_block.size();
                 }
@@ -387,18 +769,116 @@ impl<E: Copy> Segment<E> {
     }
 
     fn remove(&self) -> MetricResult<()> {
-        std::fs::remove_file(self.storage_file.path()).map_err(|err| MetricError::FailedToRemoveMetric(err))?;
+        remove_segment_file(self.storage_file.path()).map_err(|err| MetricError::FailedToRemoveMetric(self.storage_file.path().to_owned(), err))?;
 
         // Ok if failed, because we use the storage file to define if a segment exists or not
         #[allow(unused_must_use)] {
-            std::fs::remove_file(self.index_file.path()).map_err(|err| MetricError::FailedToRemoveMetric(err));
+            remove_segment_file(self.index_file.path()).map_err(|err| MetricError::FailedToRemoveMetric(self.index_file.path().to_owned(), err));
         }
 
         Ok(())
     }
 
+    /// Segments in the cold tier are symlinks to the files in the cold directory.
+    fn is_cold(&self) -> bool {
+        std::fs::symlink_metadata(self.storage_file.path())
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false)
+    }
+
+    /// Copies the files of the (sealed) segment to the cold directory and atomically replaces them with symlinks,
+    /// returning the segment mapped from its new location. The storage file is compressed if a level is given,
+    /// as cold segments are rarely read.
+    fn move_to_cold_tier(&self, base_path: &Path, cold_path: &Path, compression_level: Option<i32>) -> MetricResult<Segment<E>> {
+        let segment_index = self.storage_file.path()
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| usize::from_str(stem).ok())
+            .ok_or_else(|| MetricError::FailedToMoveSegment(self.storage_file.path().to_owned(), std::io::ErrorKind::InvalidInput.into()))?;
+
+        let move_file = |path: &Path, compression_level: Option<i32>| -> std::io::Result<()> {
+            std::fs::create_dir_all(cold_path)?;
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            let cold_file = match compression_level {
+                Some(compression_level) => {
+                    let cold_file = cold_path.canonicalize()?.join(format!("{}.{}", file_name, COMPRESSED_EXTENSION));
+                    let mut encoder = zstd::Encoder::new(std::fs::File::create(&cold_file)?, compression_level)?;
+                    std::io::copy(&mut std::fs::File::open(path)?, &mut encoder)?;
+                    encoder.finish()?.sync_all()?;
+                    cold_file
+                }
+                None => {
+                    let cold_file = cold_path.canonicalize()?.join(&*file_name);
+                    std::fs::copy(path, &cold_file)?;
+                    std::fs::File::open(&cold_file)?.sync_all()?;
+                    cold_file
+                }
+            };
+
+            let link_path = path.with_file_name(format!("{}.link", file_name));
+            let _ = std::fs::remove_file(&link_path);
+            std::os::unix::fs::symlink(&cold_file, &link_path)?;
+            std::fs::rename(&link_path, path)?;
+            Ok(())
+        };
+
+        // The index file is moved first, as the storage file defines if the segment exists
+        let index_path = self.index_file.path();
+        move_file(index_path, None).map_err(|err| MetricError::FailedToMoveSegment(index_path.to_owned(), err))?;
+        let storage_path = self.storage_file.path();
+        move_file(storage_path, compression_level).map_err(|err| MetricError::FailedToMoveSegment(storage_path.to_owned(), err))?;
+
+        Segment::from_existing(base_path, segment_index, false)
+    }
+
     fn len(&self) -> usize {
-        unsafe { (*self.header()).num_blocks }
+        match (self.snapshot.as_ref(), self.summary.as_ref()) {
+            (Some(snapshot), _) => snapshot.num_blocks,
+            (None, Some(summary)) => summary.num_blocks,
+            (None, None) => unsafe { (*self.header()).num_blocks }
+        }
+    }
+
+    /// A read only view of the blocks that currently exist. The active block is copied if the segment is active.
+    fn snapshot(&self, is_active: bool) -> Segment<E> {
+        let num_blocks = self.len();
+        let active_block = if is_active && num_blocks > 0 {
+            unsafe {
+                let active_block = self.active_block();
+                let size = (*active_block).size;
+                let mut buffer = vec![0u64; size.div_ceil(std::mem::size_of::<u64>())];
+                std::ptr::copy_nonoverlapping(active_block as *const u8, buffer.as_mut_ptr() as *mut u8, size);
+                Some(buffer)
+            }
+        } else {
+            self.snapshot.as_ref().and_then(|snapshot| snapshot.active_block.clone())
+        };
+
+        Segment {
+            storage_file: self.storage_file.clone(),
+            index_file: self.index_file.clone(),
+            snapshot: Some(SegmentSnapshot { num_blocks, active_block }),
+            summary: self.summary,
+            _phantom: Default::default()
+        }
+    }
+
+    fn copied_active_block(&self) -> Option<*const Block<E>> {
+        self.snapshot.as_ref()
+            .and_then(|snapshot| snapshot.active_block.as_ref())
+            .map(|active_block| active_block.as_ptr() as *const Block<E>)
+    }
+
+    fn advise_blocks(&self, first_block_index: usize, last_block_index: usize, advice: MemoryAdvice) {
+        if let (Some(first_block), Some(last_block)) = (self.block_at_ptr(first_block_index), self.block_at_ptr(last_block_index)) {
+            unsafe {
+                let start = first_block as *const u8;
+                let end = (last_block as *const u8).add((*last_block).size);
+                if end > start {
+                    self.storage_file.advise(start, end as usize - start as usize, advice);
+                }
+            }
+        }
     }
 
     fn has_blocks(&self) -> bool {
@@ -406,6 +886,10 @@ impl<E: Copy> Segment<E> {
     }
 
     fn time_range(&self) -> Option<(Time, Time)> {
+        if let Some(summary) = self.summary.as_ref() {
+            return summary.time_range;
+        }
+
         let (start_time, _) = self.block_time_range(0)?;
         let (_, end_time) = self.block_time_range(self.len() - 1)?;
         Some((start_time, end_time))
@@ -436,13 +920,46 @@ impl<E: Copy> Segment<E> {
             return None;
         }
 
+        if index + 1 == self.len() {
+            if let Some(active_block) = self.copied_active_block() {
+                return Some(active_block);
+            }
+        }
+
+        if CHECKED_ACCESS && !self.has_index_entry(index) {
+            tracing::error!(path = ?self.index_file.path(), index, "block index outside of index file");
+            return None;
+        }
+
         unsafe {
             let block_offset = *self.index().add(index);
+            if CHECKED_ACCESS && !self.is_valid_block(block_offset) {
+                tracing::error!(path = ?self.storage_file.path(), index, block_offset, "invalid block");
+                return None;
+            }
+
             Some(self.storage_file.ptr().add(block_offset) as *const Block<E>)
         }
     }
 
+    fn is_valid_block(&self, block_offset: usize) -> bool {
+        let backing_size = self.storage_file.backing_size();
+        if block_offset < std::mem::size_of::<Header>() || !fits_within(block_offset, std::mem::size_of::<Block<E>>(), backing_size) {
+            return false;
+        }
+
+        let block = unsafe { &*(self.storage_file.ptr().add(block_offset) as *const Block<E>) };
+        block.size >= std::mem::size_of::<Block<E>>()
+            && fits_within(block_offset, block.size, backing_size)
+            && block.start_time <= block.end_time
+            && std::mem::size_of::<Block<E>>() + block.next_sub_block_offset as usize <= block.size
+    }
+
     unsafe fn active_block(&self) -> *const Block<E> {
+        if let Some(active_block) = self.copied_active_block() {
+            return active_block;
+        }
+
         std::mem::transmute(self.storage_file.ptr().add((*self.header()).active_block_start))
     }
 
@@ -450,6 +967,180 @@ impl<E: Copy> Segment<E> {
         std::mem::transmute(self.storage_file.ptr_mut().add((*self.header()).active_block_start))
     }
 
+    fn has_index_entry(&self, index: usize) -> bool {
+        index
+            .checked_mul(std::mem::size_of::<usize>())
+            .map(|offset| fits_within(offset, std::mem::size_of::<usize>(), self.index_file.backing_size()))
+            .unwrap_or(false)
+    }
+
+    fn verify_block(&self, index: usize) -> Result<(Time, Time), String> {
+        if !self.has_index_entry(index) {
+            return Err("outside of the index file".to_owned());
+        }
+
+        let block_offset = unsafe { *self.index().add(index) };
+        if !self.is_valid_block(block_offset) {
+            return Err(format!("invalid block header at offset {}", block_offset));
+        }
+
+        let block = unsafe { &*(self.storage_file.ptr().add(block_offset) as *const Block<E>) };
+        let block_ptr = block as *const Block<E>;
+
+        let mut offset = std::mem::size_of::<Block<E>>();
+        for sub_block_index in 0..block.num_sub_blocks {
+            if !is_valid_sub_block(block_ptr, offset) {
+                return Err(format!("invalid sub-block {}", sub_block_index));
+            }
+
+            let sub_block = unsafe { &*((block_ptr as *const u8).add(offset) as *const SubBlock<E>) };
+            let mut previous_time_offset = 0;
+            for datapoint in sub_block.datapoints(block_ptr) {
+                if datapoint.time_offset < previous_time_offset {
+                    return Err(format!("datapoints of sub-block {} are not ordered by time", sub_block_index));
+                }
+
+                if block.start_time + datapoint.time_offset as Time > block.end_time {
+                    return Err(format!("datapoint of sub-block {} outside of the block time range", sub_block_index));
+                }
+
+                previous_time_offset = datapoint.time_offset;
+            }
+
+            offset += sub_block.size();
+        }
+
+        if offset != block.size {
+            return Err(format!("the size is {} bytes but the sub-blocks use {} bytes", block.size, offset));
+        }
+
+        Ok(block.time_range())
+    }
+
+    fn flush(&mut self) -> MetricResult<()> {
+        if self.storage_file.backing_size() > 0 {
+            self.storage_file.sync(self.storage_file.ptr(), self.storage_file.backing_size(), false)?;
+        }
+
+        if self.index_file.backing_size() > 0 {
+            self.index_file.sync(self.index_file.ptr(), self.index_file.backing_size(), false)?;
+        }
+
+        Ok(())
+    }
+
+    /// The active block might be inconsistent if a crash happened in the middle of a write.
+    /// Sub-blocks that are not consistent are discarded, or the whole block if its header is not.
+    fn recover_active_block(&mut self) -> MetricResult<()> {
+        if !self.has_blocks() {
+            return Ok(());
+        }
+
+        unsafe {
+            let file_size = self.storage_file.backing_size();
+            let header = self.header();
+            let active_block_start = (*header).active_block_start;
+            let valid_block = (*header).active_block_index + 1 == (*header).num_blocks
+                && active_block_start >= std::mem::size_of::<Header>()
+                && fits_within(active_block_start, std::mem::size_of::<Block<E>>(), file_size)
+                && (*self.active_block()).size >= std::mem::size_of::<Block<E>>()
+                && fits_within(active_block_start, (*self.active_block()).size, file_size);
+
+            if valid_block {
+                self.truncate_active_block()?;
+            } else {
+                self.discard_active_block()?;
+            }
+
+            let header_ptr = self.header() as *const u8;
+            self.storage_file.sync(header_ptr, std::mem::size_of::<Header>(), false)?;
+
+            if self.has_blocks() {
+                let active_block_ptr = self.active_block() as *const u8;
+                let active_block_size = (*self.active_block()).size;
+                self.storage_file.sync(active_block_ptr, active_block_size, false)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn truncate_active_block(&mut self) -> MetricResult<()> {
+        let block = self.active_block_mut();
+        let start_time = (*block).start_time;
+
+        let mut offset = std::mem::size_of::<Block<E>>();
+        let mut num_valid_sub_blocks = 0;
+        let mut end_time = start_time;
+        while num_valid_sub_blocks < (*block).num_sub_blocks && is_valid_sub_block(block, offset) {
+            let sub_block = &*((block as *const u8).add(offset) as *const SubBlock<E>);
+            for datapoint in sub_block.datapoints(block) {
+                end_time = end_time.max(start_time + datapoint.time_offset as Time);
+            }
+
+            offset += sub_block.size();
+            num_valid_sub_blocks += 1;
+        }
+
+        let num_discarded_sub_blocks = (*block).num_sub_blocks - num_valid_sub_blocks;
+        if num_discarded_sub_blocks > 0 || (*block).size != offset || (*block).end_time != end_time {
+            tracing::warn!(
+                path = ?self.storage_file.path(),
+                num_discarded_sub_blocks,
+                discarded_bytes = (*block).size.saturating_sub(offset),
+                "truncated inconsistent active block"
+            );
+        }
+
+        let next_sub_block_offset = u32::try_from(offset - std::mem::size_of::<Block<E>>()).map_err(|_| {
+            MetricError::CorruptedStorage(
+                self.storage_file.path().to_owned(),
+                format!("the sub-blocks of the active block use {} bytes", offset)
+            )
+        })?;
+
+        (*block).num_sub_blocks = num_valid_sub_blocks;
+        (*block).next_sub_block_offset = next_sub_block_offset;
+        (*block).size = offset;
+        (*block).end_time = end_time;
+        Ok(())
+    }
+
+    unsafe fn discard_active_block(&mut self) -> MetricResult<()> {
+        let num_blocks = (*self.header()).num_blocks;
+
+        // The previous block becomes the active block, which must be valid as it was sealed
+        if num_blocks > 1 {
+            let previous_index = num_blocks - 2;
+            if !self.has_index_entry(previous_index) || !self.is_valid_block(*self.index().add(previous_index)) {
+                return Err(
+                    MetricError::CorruptedStorage(
+                        self.storage_file.path().to_owned(),
+                        format!("the active block and the previous block {} are not valid", previous_index)
+                    )
+                );
+            }
+        }
+
+        let header = self.header_mut();
+        tracing::warn!(
+            path = ?self.storage_file.path(),
+            block_index = num_blocks - 1,
+            "discarded inconsistent active block"
+        );
+
+        (*header).num_blocks -= 1;
+        if (*header).num_blocks > 0 {
+            (*header).active_block_index = (*header).num_blocks - 1;
+            (*header).active_block_start = *self.index().add((*header).active_block_index);
+        } else {
+            (*header).active_block_index = 0;
+            (*header).active_block_start = std::mem::size_of::<Header>();
+        }
+
+        Ok(())
+    }
+
     fn active_block_time_range(&self) -> Option<(Time, Time)> {
         if !self.has_blocks() {
             return None;
@@ -562,7 +1253,7 @@ impl<E: Copy> Block<E> {
     }
 
     pub fn allocate_sub_block(&mut self,
-                              storage_file: &mut MemoryFile,
+                              storage_file: &MemoryFile,
                               tags: Tags,
                               capacity: u32) -> MetricResult<(&mut SubBlock<E>, bool)> {
         // Try using existing
@@ -594,7 +1285,7 @@ impl<E: Copy> Block<E> {
     }
 
     pub fn try_extend(&mut self,
-                      storage_file: &mut MemoryFile,
+                      storage_file: &MemoryFile,
                       index: usize,
                       sub_block: &mut SubBlock<E>,
                       new_capacity: u32) -> MetricResult<Option<u32>> {
@@ -680,6 +1371,24 @@ impl<E: Copy> Default for SubBlock<E> {
     }
 }
 
+fn fits_within(offset: usize, size: usize, limit: usize) -> bool {
+    offset.checked_add(size).map(|end| end <= limit).unwrap_or(false)
+}
+
+fn is_valid_sub_block<E: Copy>(block: *const Block<E>, offset: usize) -> bool {
+    unsafe {
+        let block_size = (*block).size;
+        if offset < std::mem::size_of::<Block<E>>() || !fits_within(offset, std::mem::size_of::<SubBlock<E>>(), block_size) {
+            return false;
+        }
+
+        let sub_block = &*((block as *const u8).add(offset) as *const SubBlock<E>);
+        sub_block.offset as usize + std::mem::size_of::<Block<E>>() == offset
+            && sub_block.count <= sub_block.capacity
+            && fits_within(offset, sub_block.size(), block_size)
+    }
+}
+
 pub struct SubBlockDatapointsIterator<'a, E: Copy> {
     iterator: SubBlockIterator<'a, E>
 }
@@ -733,6 +1442,11 @@ impl<'a, E: Copy> Iterator for SubBlockMutIterator<'a, E> {
             return None;
         }
 
+        if CHECKED_ACCESS && !is_valid_sub_block(self.block, self.offset) {
+            tracing::error!(index = self.index, offset = self.offset, "invalid sub-block");
+            return None;
+        }
+
         // Not really legal
         let block_ptr = self.block as *mut u8;
 
@@ -769,6 +1483,11 @@ impl<'a, E: Copy> Iterator for SubBlockIterator<'a, E> {
             return None;
         }
 
+        if CHECKED_ACCESS && !is_valid_sub_block(self.block as *const Block<E>, self.offset) {
+            tracing::error!(index = self.index, offset = self.offset, "invalid sub-block");
+            return None;
+        }
+
         let block_ptr = self.block as *const Block<E> as *const u8;
 
         let index = self.index;
@@ -779,3 +1498,203 @@ impl<'a, E: Copy> Iterator for SubBlockIterator<'a, E> {
         return Some((index, sub_block));
     }
 }
+
+#[test]
+fn test_snapshot1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 24 * 3600, 3600, 1)).unwrap();
+    storage.create_block_with_datapoint(1000, 1, Datapoint { time_offset: 0, value: 1.0 }).unwrap();
+    storage.create_block_with_datapoint(5000, 1, Datapoint { time_offset: 0, value: 2.0 }).unwrap();
+
+    let snapshot = storage.snapshot();
+    storage.add_datapoint(1, Datapoint { time_offset: 10, value: 3.0 }).unwrap();
+    storage.create_block_with_datapoint(9000, 1, Datapoint { time_offset: 0, value: 4.0 }).unwrap();
+
+    assert_eq!(3, storage.len());
+    assert_eq!(Some((5000, 5010)), storage.block_time_range(1));
+
+    assert_eq!(2, snapshot.len());
+    assert_eq!(Some((1000, 1000)), snapshot.block_time_range(0));
+    assert_eq!(Some((5000, 5000)), snapshot.block_time_range(1));
+}
+
+#[test]
+fn test_recover_active_block1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    {
+        let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 24 * 3600, 3600, 1)).unwrap();
+        storage.create_block_with_datapoint(1000, 1, Datapoint { time_offset: 0, value: 1.0 }).unwrap();
+        storage.add_datapoint(2, Datapoint { time_offset: 10, value: 2.0 }).unwrap();
+
+        // Simulates a torn write of the second sub-block
+        unsafe {
+            let block = storage.active_segment_mut().active_block_mut();
+            let first_sub_block = &*((block as *const u8).add(std::mem::size_of::<Block<f32>>()) as *const SubBlock<f32>);
+            let second_sub_block_offset = std::mem::size_of::<Block<f32>>() + first_sub_block.size();
+            let second_sub_block = &mut *((block as *mut u8).add(second_sub_block_offset) as *mut SubBlock<f32>);
+            second_sub_block.count = second_sub_block.capacity + 1;
+            (*block).end_time = 5000;
+        }
+    }
+
+    let mut storage = FileMetricStorage::<f32>::from_existing(temp_dir.path()).unwrap();
+    assert_eq!(1, storage.len());
+    assert_eq!(Some((1000, 1000)), storage.block_time_range(0));
+    assert_eq!(vec![1], storage.block_datapoints(0).unwrap().map(|(tags, _)| tags).collect::<Vec<_>>());
+
+    storage.add_datapoint(2, Datapoint { time_offset: 20, value: 3.0 }).unwrap();
+    assert_eq!(Some((1000, 1020)), storage.block_time_range(0));
+    assert_eq!(2, storage.block_datapoints(0).unwrap().count());
+}
+
+#[test]
+fn test_recover_active_block2() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    {
+        let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 24 * 3600, 3600, 1)).unwrap();
+        storage.create_block_with_datapoint(1000, 1, Datapoint { time_offset: 0, value: 1.0 }).unwrap();
+        storage.create_block_with_datapoint(5000, 1, Datapoint { time_offset: 0, value: 2.0 }).unwrap();
+
+        unsafe {
+            (*storage.active_segment_mut().active_block_mut()).size = usize::MAX;
+        }
+    }
+
+    let storage = FileMetricStorage::<f32>::from_existing(temp_dir.path()).unwrap();
+    assert_eq!(1, storage.len());
+    assert_eq!(Some((1000, 1000)), storage.active_block_time_range());
+}
+
+#[test]
+fn test_recover_active_block3() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    {
+        let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 24 * 3600, 3600, 1)).unwrap();
+        storage.create_block_with_datapoint(1000, 1, Datapoint { time_offset: 0, value: 1.0 }).unwrap();
+        storage.create_block_with_datapoint(5000, 1, Datapoint { time_offset: 0, value: 2.0 }).unwrap();
+
+        unsafe {
+            (*storage.active_segment_mut().active_block_mut()).size = usize::MAX;
+            *storage.active_segment_mut().index_mut() = usize::MAX - 1;
+        }
+    }
+
+    assert!(matches!(FileMetricStorage::<f32>::from_existing(temp_dir.path()), Err(MetricError::CorruptedStorage(_, _))));
+}
+
+#[test]
+fn test_valid_sub_block1() {
+    let mut block = Block::<f32>::new(1000);
+    block.size = usize::MAX;
+    assert!(!is_valid_sub_block(&block as *const Block<f32>, usize::MAX - 1));
+}
+
+#[test]
+fn test_verify1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 24 * 3600, 3600, 1)).unwrap();
+    storage.create_block_with_datapoint(1000, 1, Datapoint { time_offset: 0, value: 1.0 }).unwrap();
+    storage.add_datapoint(2, Datapoint { time_offset: 10, value: 2.0 }).unwrap();
+    storage.create_block_with_datapoint(5000, 1, Datapoint { time_offset: 0, value: 3.0 }).unwrap();
+    storage.create_block_with_datapoint(9000, 1, Datapoint { time_offset: 0, value: 4.0 }).unwrap();
+    assert_eq!(Vec::<String>::new(), storage.verify());
+
+    unsafe {
+        let block = storage.block_at_ptr(1).unwrap() as *mut Block<f32>;
+        let sub_block = &mut *((block as *mut u8).add(std::mem::size_of::<Block<f32>>()) as *mut SubBlock<f32>);
+        sub_block.datapoints_mut(block)[0].time_offset = 100;
+    }
+    assert_eq!(vec!["block 1: datapoint of sub-block 0 outside of the block time range".to_owned()], storage.verify());
+}
+
+#[cfg(feature = "checked-access")]
+#[test]
+fn test_checked_access1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 24 * 3600, 3600, 1)).unwrap();
+    storage.create_block_with_datapoint(1000, 1, Datapoint { time_offset: 0, value: 1.0 }).unwrap();
+    storage.add_datapoint(2, Datapoint { time_offset: 10, value: 2.0 }).unwrap();
+    assert_eq!(2, storage.block_datapoints(0).unwrap().count());
+
+    unsafe {
+        let block = storage.active_segment_mut().active_block_mut();
+        let sub_block = &mut *((block as *mut u8).add(std::mem::size_of::<Block<f32>>()) as *mut SubBlock<f32>);
+        sub_block.count = sub_block.capacity + 1;
+    }
+    assert_eq!(0, storage.block_datapoints(0).unwrap().count());
+
+    unsafe {
+        (*storage.active_segment_mut().active_block_mut()).size = usize::MAX;
+    }
+    assert!(storage.block_datapoints(0).is_none());
+}
+
+#[test]
+fn test_move_to_cold_tier1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let temp_cold_dir = tempfile::tempdir().unwrap();
+    let base_path = temp_dir.path().join("cpu");
+    std::fs::create_dir_all(&base_path).unwrap();
+
+    {
+        let mut storage = FileMetricStorage::<f32>::new(&base_path, MetricStorageConfig::new(None, 10, 1, 1)).unwrap();
+        for index in 0..25 {
+            storage.create_block_with_datapoint(1000 * index, 1, Datapoint { time_offset: 0, value: index as f32 }).unwrap();
+        }
+        assert_eq!(3, storage.num_segments());
+
+        assert_eq!(1, storage.move_to_cold_tier(temp_dir.path(), temp_cold_dir.path(), 15000, None).unwrap());
+        assert!(std::fs::symlink_metadata(base_path.join("0.storage")).unwrap().file_type().is_symlink());
+        assert!(temp_cold_dir.path().join("cpu").join("0.storage").exists());
+        assert!(temp_cold_dir.path().join("cpu").join("0.index").exists());
+        assert_eq!(Some((5000, 5000)), storage.block_time_range(5));
+        assert_eq!(Vec::<String>::new(), storage.verify());
+
+        // The active segment is never moved
+        assert_eq!(1, storage.move_to_cold_tier(temp_dir.path(), temp_cold_dir.path(), 100000, None).unwrap());
+        assert_eq!(0, storage.move_to_cold_tier(temp_dir.path(), temp_cold_dir.path(), 100000, None).unwrap());
+        storage.add_datapoint(1, Datapoint { time_offset: 10, value: 100.0 }).unwrap();
+    }
+
+    let storage = FileMetricStorage::<f32>::from_existing(&base_path).unwrap();
+    assert_eq!(25, storage.len());
+    assert_eq!(
+        vec![(1, vec![5.0])],
+        storage.block_datapoints(5).unwrap().map(|(tags, datapoints)| (tags, datapoints.iter().map(|datapoint| datapoint.value).collect::<Vec<_>>())).collect::<Vec<_>>()
+    );
+    assert_eq!(Some((24000, 24010)), storage.active_block_time_range());
+}
+
+#[test]
+fn test_move_to_cold_tier2() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let temp_cold_dir = tempfile::tempdir().unwrap();
+
+    {
+        let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 10, 1, 1)).unwrap();
+        for index in 0..15 {
+            storage.create_block_with_datapoint(1000 * index, 1, Datapoint { time_offset: 0, value: index as f32 }).unwrap();
+        }
+
+        assert_eq!(1, storage.move_to_cold_tier(temp_dir.path(), temp_cold_dir.path(), 15000, Some(19)).unwrap());
+        assert!(temp_cold_dir.path().join("0.storage.zst").exists());
+        assert_eq!(Vec::<String>::new(), storage.verify());
+        assert_eq!(Some((3000, 3000)), storage.block_time_range(3));
+    }
+
+    let storage = FileMetricStorage::<f32>::from_existing(temp_dir.path()).unwrap();
+    assert_eq!(15, storage.len());
+    assert_eq!(Some((0, 14000)), storage.time_range());
+    assert!(!storage.segments[0].storage_file.is_decompressed());
+
+    assert_eq!(Vec::<String>::new(), storage.verify());
+    assert!(storage.segments[0].storage_file.is_decompressed());
+    assert_eq!(
+        vec![(1, vec![3.0])],
+        storage.block_datapoints(3).unwrap().map(|(tags, datapoints)| (tags, datapoints.iter().map(|datapoint| datapoint.value).collect::<Vec<_>>())).collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::model::{MetricError, MetricResult};
+
+lazy_static! {
+    // The lock is held by the process, a storage may be opened multiple times within it
+    static ref HELD_LOCKS: Mutex<HashMap<PathBuf, (File, usize)>> = Mutex::new(HashMap::new());
+}
+
+pub struct LockFile {
+    path: PathBuf
+}
+
+impl LockFile {
+    pub fn acquire(base_path: &Path) -> MetricResult<LockFile> {
+        let base_path = std::fs::canonicalize(base_path).unwrap_or_else(|_| base_path.to_owned());
+        let path = base_path.join("lock");
+
+        let mut held_locks = HELD_LOCKS.lock().unwrap();
+        if let Some((_, count)) = held_locks.get_mut(&path) {
+            *count += 1;
+            return Ok(LockFile { path });
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(MetricError::FailedToLock)?;
+
+        let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if result != 0 {
+            let err = std::io::Error::last_os_error();
+            return if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                Err(MetricError::AlreadyLocked(base_path))
+            } else {
+                Err(MetricError::FailedToLock(err))
+            };
+        }
+
+        held_locks.insert(path.clone(), (file, 1));
+        Ok(LockFile { path })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let mut held_locks = HELD_LOCKS.lock().unwrap();
+        let released = match held_locks.get_mut(&self.path) {
+            Some((_, count)) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false
+        };
+
+        if released {
+            // Closing the file releases the lock
+            held_locks.remove(&self.path);
+        }
+    }
+}
+
+#[test]
+fn test_lock_file1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+
+    let lock1 = LockFile::acquire(temp_dir.path()).unwrap();
+    let lock2 = LockFile::acquire(temp_dir.path()).unwrap();
+
+    let file = OpenOptions::new().read(true).write(true).open(temp_dir.path().join("lock")).unwrap();
+    assert_ne!(0, unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) });
+
+    drop(lock1);
+    assert_ne!(0, unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) });
+
+    drop(lock2);
+    assert_eq!(0, unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) });
+}
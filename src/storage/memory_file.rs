@@ -1,65 +1,138 @@
 use std::ffi::{c_void};
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
+use std::io::Read;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum MemoryFileError {
-    FailedToMap(std::io::Error),
-    FailedToSync,
-    IO(std::io::Error)
+    #[error("failed to map {0:?}: {1}")]
+    FailedToMap(PathBuf, std::io::Error),
+    #[error("failed to sync {0:?}")]
+    FailedToSync(PathBuf),
+    #[error("failed to lock {0:?} in memory: {1}")]
+    FailedToLock(PathBuf, std::io::Error),
+    #[error("I/O error for {0:?}: {1}")]
+    IO(PathBuf, std::io::Error)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryAdvice {
+    Sequential,
+    WillNeed,
+    DontNeed
+}
+
+impl MemoryAdvice {
+    fn flag(&self) -> libc::c_int {
+        match self {
+            MemoryAdvice::Sequential => libc::MADV_SEQUENTIAL,
+            MemoryAdvice::WillNeed => libc::MADV_WILLNEED,
+            MemoryAdvice::DontNeed => libc::MADV_DONTNEED
+        }
+    }
 }
 
 pub struct MemoryFile {
     path: PathBuf,
     address: *mut c_void,
     size: usize,
-    backing_size: usize,
-    file: File
+    backing_size: AtomicUsize,
+    file: File,
+    decompressed: Option<Once>
 }
 
 const PAGE_SIZE: usize = 4096;
 
 impl MemoryFile {
     pub fn new(path: &Path, size: usize, create: bool) -> Result<MemoryFile, MemoryFileError> {
-        let mut file = if create {
+        let file = if create {
             OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
                 .truncate(true)
                 .open(path)
-                .map_err(|err| MemoryFileError::IO(err))?
+                .map_err(|err| MemoryFileError::IO(path.to_owned(), err))?
         } else {
             OpenOptions::new()
                 .read(true)
                 .write(true)
                 .open(path)
-                .map_err(|err| MemoryFileError::IO(err))?
+                .map_err(|err| MemoryFileError::IO(path.to_owned(), err))?
         };
 
         let backing_size = if create {
             let backing_size = PAGE_SIZE as u64;
-            file.set_len(backing_size).map_err(|err| MemoryFileError::IO(err))?;
+            file.set_len(backing_size).map_err(|err| MemoryFileError::IO(path.to_owned(), err))?;
             backing_size as usize
         } else {
-            file_size(&mut file).map_err(|err| MemoryFileError::IO(err))? as usize
+            file_size(&file).map_err(|err| MemoryFileError::IO(path.to_owned(), err))? as usize
         };
 
+        MemoryFile::map(path, size, file, backing_size, libc::MAP_SHARED)
+    }
+
+    pub fn read_only(path: &Path, size: usize) -> Result<MemoryFile, MemoryFileError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|err| MemoryFileError::IO(path.to_owned(), err))?;
+
+        let backing_size = file_size(&file).map_err(|err| MemoryFileError::IO(path.to_owned(), err))? as usize;
+
+        // A private mapping never writes back to the file, and reserving swap for the whole mapping could fail
+        MemoryFile::map(path, size, file, backing_size, libc::MAP_PRIVATE | libc::MAP_NORESERVE)
+    }
+
+    /// Maps the zstd compressed file into anonymous memory, which is decompressed on first access.
+    /// Changes are never written back to the file.
+    pub fn decompressed(path: &Path, size: usize) -> Result<MemoryFile, MemoryFileError> {
+        let file = File::open(path).map_err(|err| MemoryFileError::IO(path.to_owned(), err))?;
+
         let address = unsafe {
             libc::mmap(
                 std::ptr::null_mut(),
                 size,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0
+            )
+        };
+
+        if address == libc::MAP_FAILED {
+            return Err(MemoryFileError::FailedToMap(path.to_owned(), std::io::Error::last_os_error()));
+        }
+
+        Ok(
+            MemoryFile {
+                path: path.to_owned(),
+                address,
+                size,
+                file,
+                backing_size: AtomicUsize::new(0),
+                decompressed: Some(Once::new())
+            }
+        )
+    }
+
+    fn map(path: &Path, size: usize, file: File, backing_size: usize, flags: libc::c_int) -> Result<MemoryFile, MemoryFileError> {
+        let address = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
                 file.as_raw_fd(),
                 0
             )
         };
 
         if address == libc::MAP_FAILED {
-            return Err(MemoryFileError::FailedToMap(std::io::Error::last_os_error()));
+            return Err(MemoryFileError::FailedToMap(path.to_owned(), std::io::Error::last_os_error()));
         }
 
         Ok(
@@ -68,7 +141,8 @@ impl MemoryFile {
                 address,
                 size,
                 file,
-                backing_size
+                backing_size: AtomicUsize::new(backing_size),
+                decompressed: None
             }
         )
     }
@@ -81,28 +155,74 @@ impl MemoryFile {
         self.size
     }
 
-    pub fn try_grow_file(&mut self, amount: usize) -> Result<(), MemoryFileError> {
-        self.backing_size += amount;
-        let actual_size = file_size(&mut self.file).map_err(|err| MemoryFileError::IO(err))? as usize;
-        if self.backing_size > actual_size {
+    /// The number of bytes in use, which never exceeds the length of the file.
+    pub fn backing_size(&self) -> usize {
+        self.ensure_decompressed();
+        self.backing_size.load(Ordering::Acquire)
+    }
+
+    #[cfg(test)]
+    pub fn is_decompressed(&self) -> bool {
+        self.decompressed.as_ref().map(|decompressed| decompressed.is_completed()).unwrap_or(true)
+    }
+
+    fn ensure_decompressed(&self) {
+        if let Some(decompressed) = self.decompressed.as_ref() {
+            decompressed.call_once(|| {
+                match self.decompress() {
+                    Ok(size) => {
+                        self.backing_size.store(size, Ordering::Release);
+                    }
+                    Err(err) => {
+                        tracing::error!(path = ?self.path, error = %err, "failed to decompress");
+                    }
+                }
+            });
+        }
+    }
+
+    /// Decompresses straight into the mapping, without any intermediate buffer.
+    fn decompress(&self) -> std::io::Result<usize> {
+        let mut decoder = zstd::Decoder::new(&self.file)?;
+        let buffer = unsafe { std::slice::from_raw_parts_mut(self.address as *mut u8, self.size) };
+
+        let mut size = 0;
+        while size < buffer.len() {
+            match decoder.read(&mut buffer[size..]) {
+                Ok(0) => return Ok(size),
+                Ok(count) => size += count,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err)
+            }
+        }
+
+        if decoder.read(&mut [0u8])? > 0 {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+
+        Ok(size)
+    }
+
+    /// Changing the size only requires shared access, as the mapping itself never moves.
+    pub fn try_grow_file(&self, amount: usize) -> Result<(), MemoryFileError> {
+        let backing_size = self.backing_size.fetch_add(amount, Ordering::AcqRel) + amount;
+        let actual_size = file_size(&self.file).map_err(|err| MemoryFileError::IO(self.path.clone(), err))? as usize;
+        if backing_size > actual_size {
             let page_size = PAGE_SIZE as u64;
             self.file.set_len(
-                ((self.backing_size as u64 + page_size - 1) / page_size) * page_size
-            ).map_err(|err| MemoryFileError::IO(err))?;
+                ((backing_size as u64 + page_size - 1) / page_size) * page_size
+            ).map_err(|err| MemoryFileError::IO(self.path.clone(), err))?;
         }
 
         Ok(())
     }
 
-    pub fn shrink(&mut self, amount: usize) {
-        if amount < self.backing_size {
-            self.backing_size -= amount;
-        } else {
-            self.backing_size = 0;
-        }
+    pub fn shrink(&self, amount: usize) {
+        let backing_size = self.backing_size();
+        self.backing_size.store(backing_size.saturating_sub(amount), Ordering::Release);
     }
 
-    pub fn sync(&mut self, address: *const u8, size: usize, is_async: bool) -> Result<(), MemoryFileError> {
+    pub fn sync(&self, address: *const u8, size: usize, is_async: bool) -> Result<(), MemoryFileError> {
         unsafe {
             // The address that we invoke msync with must be aligned to pages
             let address = address as usize;
@@ -115,25 +235,71 @@ impl MemoryFile {
             if result == 0 {
                 Ok(())
             } else {
-                Err(MemoryFileError::FailedToSync)
+                Err(MemoryFileError::FailedToSync(self.path.clone()))
+            }
+        }
+    }
+
+    /// Locks the pages of the given range in memory. Locking an already locked range again is a no-op.
+    pub fn lock(&self, address: *const u8, size: usize) -> Result<(), MemoryFileError> {
+        let (page_address, size) = self.page_range(address, size);
+        if size == 0 {
+            return Ok(());
+        }
+
+        if unsafe { libc::mlock(page_address as *const _, size) } == 0 {
+            Ok(())
+        } else {
+            Err(MemoryFileError::FailedToLock(self.path.clone(), std::io::Error::last_os_error()))
+        }
+    }
+
+    pub fn unlock(&self, address: *const u8, size: usize) {
+        let (page_address, size) = self.page_range(address, size);
+        if size > 0 {
+            unsafe {
+                libc::munlock(page_address as *const _, size);
             }
         }
     }
 
+    /// Gives the kernel a hint about how the given range will be accessed. Failures are ignored as it is only a hint.
+    pub fn advise(&self, address: *const u8, size: usize, advice: MemoryAdvice) {
+        // Dropping the pages of decompressed (anonymous) memory would lose the data
+        if self.decompressed.is_some() {
+            return;
+        }
+
+        let (page_address, size) = self.page_range(address, size);
+        if size > 0 {
+            unsafe {
+                libc::madvise(page_address as *mut _, size, advice.flag());
+            }
+        }
+    }
+
+    fn page_range(&self, address: *const u8, size: usize) -> (usize, usize) {
+        let start_address = self.address as usize;
+        let end_address = (address as usize + size).min(start_address + self.size);
+
+        // The address that we invoke madvise/mlock with must be aligned to pages
+        let page_address = ((address as usize).max(start_address) / PAGE_SIZE) * PAGE_SIZE;
+        (page_address, end_address.saturating_sub(page_address))
+    }
+
     pub fn ptr(&self) -> *const u8 {
+        self.ensure_decompressed();
         self.address as *mut u8
     }
 
-    pub fn ptr_mut(&mut self) -> *mut u8 {
+    pub fn ptr_mut(&self) -> *mut u8 {
+        self.ensure_decompressed();
         self.address as *mut u8
     }
 }
 
-fn file_size(file: &mut File) -> std::io::Result<u64> {
-    let old_pos = file.stream_position()?;
-    let len = file.seek(SeekFrom::End(0))?;
-    file.seek(SeekFrom::Start(old_pos))?;
-    Ok(len)
+fn file_size(file: &File) -> std::io::Result<u64> {
+    Ok(file.metadata()?.len())
 }
 
 unsafe impl Send for MemoryFile {}
@@ -145,4 +311,51 @@ impl Drop for MemoryFile {
             libc::munmap(self.address, self.size());
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_advise1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let memory_file = MemoryFile::new(&temp_dir.path().join("data"), 1024 * 1024, true).unwrap();
+    memory_file.try_grow_file(3 * PAGE_SIZE).unwrap();
+
+    unsafe {
+        std::slice::from_raw_parts_mut(memory_file.ptr_mut(), 3 * PAGE_SIZE).fill(42);
+    }
+
+    memory_file.advise(unsafe { memory_file.ptr().add(100) }, 2 * PAGE_SIZE, MemoryAdvice::Sequential);
+    memory_file.advise(memory_file.ptr(), 3 * PAGE_SIZE, MemoryAdvice::WillNeed);
+    memory_file.advise(memory_file.ptr(), 3 * PAGE_SIZE, MemoryAdvice::DontNeed);
+    memory_file.advise(memory_file.ptr(), 2 * 1024 * 1024, MemoryAdvice::DontNeed);
+
+    // Dropping the pages of a shared mapping must not lose any data
+    let data = unsafe { std::slice::from_raw_parts(memory_file.ptr(), 3 * PAGE_SIZE) };
+    assert!(data.iter().all(|value| *value == 42));
+}
+
+#[test]
+fn test_lock1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let memory_file = MemoryFile::new(&temp_dir.path().join("data"), 1024 * 1024, true).unwrap();
+
+    memory_file.lock(memory_file.ptr(), 100).unwrap();
+    memory_file.lock(memory_file.ptr(), PAGE_SIZE).unwrap();
+    memory_file.unlock(memory_file.ptr(), PAGE_SIZE);
+}
+
+#[test]
+fn test_decompressed1() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("data.zst");
+    let data = (0..3 * PAGE_SIZE).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(&path, zstd::encode_all(&data[..], 3).unwrap()).unwrap();
+
+    let memory_file = MemoryFile::decompressed(&path, 1024 * 1024).unwrap();
+    assert!(!memory_file.is_decompressed());
+
+    assert_eq!(data.len(), memory_file.backing_size());
+    assert!(memory_file.is_decompressed());
+    assert_eq!(&data[..], unsafe { std::slice::from_raw_parts(memory_file.ptr(), data.len()) });
+
+    assert_eq!(0, MemoryFile::decompressed(&path, PAGE_SIZE).unwrap().backing_size());
+}
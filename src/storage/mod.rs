@@ -26,6 +26,10 @@ impl MetricStorageConfig {
 pub trait MetricStorage<E: Copy> {
     fn new(base_path: &Path, config: MetricStorageConfig) -> MetricResult<Self> where Self: Sized;
     fn from_existing(base_path: &Path) -> MetricResult<Self> where Self: Sized;
+    fn from_existing_read_only(base_path: &Path) -> MetricResult<Self> where Self: Sized;
+
+    /// A read only view of the storage as it is now, which can be read without blocking writes to the storage.
+    fn snapshot(&self) -> Self where Self: Sized;
 
     fn segment_duration(&self) -> u64;
     fn block_duration(&self) -> u64;
@@ -49,6 +53,14 @@ pub trait MetricStorage<E: Copy> {
     fn create_block(&mut self, time: Time) -> Result<(), MetricError>;
     fn add_datapoint(&mut self, tags: Tags, datapoint: Datapoint<E>) -> MetricResult<()>;
 
+    fn add_datapoints(&mut self, tags: Tags, datapoints: &[Datapoint<E>]) -> MetricResult<()> {
+        for datapoint in datapoints {
+            self.add_datapoint(tags, datapoint.clone())?;
+        }
+
+        Ok(())
+    }
+
     fn create_block_with_datapoint(&mut self, time: Time, tags: Tags, datapoint: Datapoint<E>) -> MetricResult<()> {
         self.create_block(time)?;
         self.add_datapoint(tags, datapoint)?;
@@ -58,8 +70,37 @@ pub trait MetricStorage<E: Copy> {
     type BlockIterator<'a>: Iterator<Item=(Tags, &'a [Datapoint<E>])> where Self: 'a, E: 'a;
     fn block_datapoints<'a>(&'a self, block_index: usize) -> Option<Self::BlockIterator<'a>>;
 
+    /// Hints that the given blocks (inclusive) are about to be scanned sequentially.
+    fn advise_scan(&self, start_block_index: usize, end_block_index: usize);
+
     fn scheduled(&mut self);
+
+    /// Synchronously writes all changes to disk.
+    fn flush(&mut self) -> MetricResult<()>;
+
+    /// Moves the sealed segments that end before the given time to the same relative location below `cold_root`,
+    /// compressed with zstd at the given level if set.
+    /// The segments are still found (through symlinks) when the storage is loaded. Returns the number of moved segments.
+    fn move_to_cold_tier(&mut self,
+                         hot_root: &Path,
+                         cold_root: &Path,
+                         older_than: Time,
+                         compression_level: Option<i32>) -> MetricResult<usize>;
+
+    /// Removes the sealed segments whose data is older than the retention relative to `now`.
+    /// These are otherwise only removed when new blocks are created. Returns the number of removed segments.
+    fn remove_expired_segments(&mut self, now: Time) -> MetricResult<usize>;
+
+    /// Keeps the active segment (which includes the active block) in memory, released when the segment is sealed.
+    fn pin_active_segment(&mut self) -> MetricResult<()>;
+
+    /// Checks the invariants of the sealed blocks, returning a description of each violation found.
+    fn verify(&self) -> Vec<String>;
 }
 
 pub mod file;
-pub mod memory_file;
+pub(crate) mod lock_file;
+pub(crate) mod memory_file;
+pub mod reader;
+
+pub use memory_file::MemoryFileError;
@@ -0,0 +1,115 @@
+use std::marker::PhantomData;
+
+use crate::model::{Datapoint, Tags, Time};
+use crate::storage::MetricStorage;
+
+/// The datapoints of a single tag set within a block.
+pub struct MetricBlock<'a, E: Copy> {
+    pub block_index: usize,
+    pub start_time: Time,
+    pub end_time: Time,
+    pub tags: Tags,
+    pub datapoints: &'a [Datapoint<E>]
+}
+
+impl<'a, E: Copy> MetricBlock<'a, E> {
+    /// The datapoints with their absolute time.
+    pub fn iter(&self) -> impl Iterator<Item=(Time, E)> + 'a {
+        let start_time = self.start_time;
+        self.datapoints.iter().map(move |datapoint| (start_time + datapoint.time_offset as Time, datapoint.value))
+    }
+}
+
+/// Read only access to the blocks of a storage, without needing to know how the blocks are laid out.
+pub struct MetricReader<'a, TStorage: MetricStorage<E>, E: Copy> {
+    storage: &'a TStorage,
+    _phantom: PhantomData<E>
+}
+
+impl<'a, TStorage: MetricStorage<E>, E: Copy + 'a> MetricReader<'a, TStorage, E> {
+    pub fn new(storage: &'a TStorage) -> MetricReader<'a, TStorage, E> {
+        MetricReader {
+            storage,
+            _phantom: Default::default()
+        }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.storage.len()
+    }
+
+    pub fn time_range(&self) -> Option<(Time, Time)> {
+        self.storage.time_range()
+    }
+
+    pub fn block(&self, block_index: usize) -> Option<impl Iterator<Item=MetricBlock<'a, E>> + 'a> {
+        let (start_time, end_time) = self.storage.block_time_range(block_index)?;
+        let iterator = self.storage.block_datapoints(block_index)?;
+        Some(
+            iterator.map(move |(tags, datapoints)| {
+                MetricBlock {
+                    block_index,
+                    start_time,
+                    end_time,
+                    tags,
+                    datapoints
+                }
+            })
+        )
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item=MetricBlock<'a, E>> + 'a {
+        let storage = self.storage;
+        (0..storage.len()).flat_map(move |block_index| MetricReader::<TStorage, E>::new(storage).block(block_index).into_iter().flatten())
+    }
+
+    /// The blocks that overlap the given time range (inclusive).
+    pub fn blocks_in_range(&self, start_time: Time, end_time: Time) -> impl Iterator<Item=MetricBlock<'a, E>> + 'a {
+        self.blocks().filter(move |block| block.end_time >= start_time && block.start_time <= end_time)
+    }
+
+    /// The datapoints in the given time range (inclusive), ordered by block but not by tags within a block.
+    pub fn datapoints_in_range(&self, start_time: Time, end_time: Time) -> impl Iterator<Item=(Time, Tags, E)> + 'a {
+        self.blocks_in_range(start_time, end_time)
+            .flat_map(move |block| {
+                let tags = block.tags;
+                block.iter()
+                    .filter(move |(time, _)| *time >= start_time && *time <= end_time)
+                    .map(move |(time, value)| (time, tags, value))
+            })
+    }
+}
+
+#[test]
+fn test_reader1() {
+    use crate::storage::file::FileMetricStorage;
+    use crate::storage::MetricStorageConfig;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut storage = FileMetricStorage::<f32>::new(temp_dir.path(), MetricStorageConfig::new(None, 24 * 3600, 3600, 1)).unwrap();
+
+    storage.create_block_with_datapoint(1000, 1, Datapoint { time_offset: 0, value: 1.0 }).unwrap();
+    storage.add_datapoint(2, Datapoint { time_offset: 10, value: 2.0 }).unwrap();
+    storage.add_datapoint(1, Datapoint { time_offset: 20, value: 3.0 }).unwrap();
+    storage.create_block_with_datapoint(5000, 1, Datapoint { time_offset: 0, value: 4.0 }).unwrap();
+
+    let reader = MetricReader::new(&storage);
+    assert_eq!(2, reader.num_blocks());
+
+    let mut blocks = reader.blocks().map(|block| (block.block_index, block.tags, block.iter().collect::<Vec<_>>())).collect::<Vec<_>>();
+    blocks.sort_by_key(|(block_index, tags, _)| (*block_index, *tags));
+    assert_eq!(
+        vec![
+            (0, 1, vec![(1000, 1.0), (1020, 3.0)]),
+            (0, 2, vec![(1010, 2.0)]),
+            (1, 1, vec![(5000, 4.0)])
+        ],
+        blocks
+    );
+
+    let mut datapoints = reader.datapoints_in_range(1005, 5000).collect::<Vec<_>>();
+    datapoints.sort_by_key(|(time, _, _)| *time);
+    assert_eq!(vec![(1010, 2, 2.0), (1020, 1, 3.0), (5000, 1, 4.0)], datapoints);
+    assert_eq!(0, reader.blocks_in_range(2000, 4000).count());
+    assert_eq!(1, reader.blocks_in_range(5000, 6000).count());
+}
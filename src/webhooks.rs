@@ -0,0 +1,140 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub max_retries: usize,
+    /// The delay (in seconds) before the first retry, doubled for each following retry.
+    pub retry_delay: f64,
+    pub max_retry_delay: f64,
+    pub timeout: f64,
+    pub max_queued_events: usize
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            urls: Vec::new(),
+            max_retries: 5,
+            retry_delay: 1.0,
+            max_retry_delay: 60.0,
+            timeout: 10.0,
+            max_queued_events: 1000
+        }
+    }
+}
+
+impl WebhookConfig {
+    fn retry_delay(&self, attempt: usize) -> Duration {
+        let delay = self.retry_delay * 2.0f64.powi(attempt.min(31) as i32);
+        Duration::from_secs_f64(delay.min(self.max_retry_delay).max(0.0))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    /// The type of the event, e.g. "alert_triggered" or "query_completed".
+    pub kind: String,
+    pub name: String,
+    pub time: f64,
+    #[serde(default)]
+    pub payload: serde_json::Value
+}
+
+impl WebhookEvent {
+    pub fn new(kind: &str, name: &str, payload: serde_json::Value) -> WebhookEvent {
+        WebhookEvent {
+            kind: kind.to_owned(),
+            name: name.to_owned(),
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
+            payload
+        }
+    }
+}
+
+/// Delivers events to the configured URLs in the background, retrying failed deliveries with backoff.
+pub struct WebhookDispatcher {
+    sender: mpsc::Sender<WebhookEvent>
+}
+
+impl WebhookDispatcher {
+    /// Must be called within a Tokio runtime.
+    pub fn spawn(config: WebhookConfig) -> WebhookDispatcher {
+        let (sender, mut receiver) = mpsc::channel::<WebhookEvent>(config.max_queued_events.max(1));
+
+        tokio::spawn(async move {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs_f64(config.timeout))
+                .build()
+                .unwrap_or_default();
+
+            while let Some(event) = receiver.recv().await {
+                for url in &config.urls {
+                    deliver(&client, &config, url, &event).await;
+                }
+            }
+        });
+
+        WebhookDispatcher {
+            sender
+        }
+    }
+
+    /// Returns false if the event was dropped because the queue is full.
+    pub fn notify(&self, event: WebhookEvent) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::warn!(error = %err, "Dropping webhook event.");
+                false
+            }
+        }
+    }
+}
+
+async fn deliver(client: &reqwest::Client, config: &WebhookConfig, url: &str, event: &WebhookEvent) {
+    let mut attempt = 0;
+    loop {
+        let error = match client.post(url).json(event).send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) if response.status().is_client_error() => {
+                tracing::warn!(url, status = %response.status(), kind = event.kind, "Webhook rejected the event.");
+                return;
+            }
+            Ok(response) => response.status().to_string(),
+            Err(err) => err.to_string()
+        };
+
+        if attempt >= config.max_retries {
+            tracing::error!(url, error, kind = event.kind, "Failed to deliver webhook event.");
+            return;
+        }
+
+        tracing::warn!(url, error, attempt, "Webhook delivery failed, retrying.");
+        tokio::time::sleep(config.retry_delay(attempt)).await;
+        attempt += 1;
+    }
+}
+
+#[test]
+fn test_retry_delay1() {
+    let config = WebhookConfig { retry_delay: 0.5, max_retry_delay: 3.0, ..Default::default() };
+    assert_eq!(Duration::from_secs_f64(0.5), config.retry_delay(0));
+    assert_eq!(Duration::from_secs_f64(1.0), config.retry_delay(1));
+    assert_eq!(Duration::from_secs_f64(2.0), config.retry_delay(2));
+    assert_eq!(Duration::from_secs_f64(3.0), config.retry_delay(3));
+    assert_eq!(Duration::from_secs_f64(3.0), config.retry_delay(100));
+}
+
+#[test]
+fn test_event_serialize1() {
+    let mut event = WebhookEvent::new("alert_triggered", "high_cpu", serde_json::json!({ "value": 0.95 }));
+    // The parsing of floats is not exact without the float_roundtrip feature of serde_json
+    event.time = 1654077600.5;
+    let content = serde_json::to_string(&event).unwrap();
+    assert_eq!(event, serde_json::from_str::<WebhookEvent>(&content).unwrap());
+}